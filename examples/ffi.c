@@ -0,0 +1,52 @@
+/* © 2018 Sebastian Reichel
+ * SPDX-License-Identifier: ISC
+ */
+
+/* Query the CFF3000 state from C, see `cff3000::ffi` for building.
+ *
+ * Usage: ffi [chip] (default /dev/gpiochip0), with LED red on line 0,
+ * LED green on line 1, button unlock on line 2 and button lock on
+ * line 3.
+ */
+
+#include <stdio.h>
+
+#include "cff3000.h"
+
+static const char *state_name(int state)
+{
+	switch (state) {
+	case CFF3000_STATE_LOCKED:
+		return "locked";
+	case CFF3000_STATE_UNLOCKED:
+		return "unlocked";
+	case CFF3000_STATE_MANUAL:
+		return "manual";
+	default:
+		return "out of range";
+	}
+}
+
+int main(int argc, char **argv)
+{
+	const char *chip = argc > 1 ? argv[1] : "/dev/gpiochip0";
+	const uint32_t pins[4] = {0, 1, 2, 3};
+	cff3000 *device;
+	int state;
+	int ret;
+
+	device = cff3000_new(chip, pins);
+	if (!device) {
+		fprintf(stderr, "failed to open %s: %s\n", chip, cff3000_last_error_message(NULL));
+		return 1;
+	}
+
+	ret = cff3000_state(device, &state);
+	if (ret == CFF3000_OK)
+		printf("%s\n", state_name(state));
+	else
+		fprintf(stderr, "failed to query state: %s\n", cff3000_last_error_message(device));
+
+	cff3000_free(device);
+	return ret;
+}
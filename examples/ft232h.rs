@@ -0,0 +1,47 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Run a CFF3000 command through an FT232H breakout.
+//!
+//! Pin mapping (`ft232h::DEFAULT_PINS`):
+//!
+//! | FT232H | CFF3000 signal |
+//! |--------|----------------|
+//! | AD4    | LED red        |
+//! | AD5    | LED green      |
+//! | AD6    | button unlock  |
+//! | AD7    | button lock    |
+//!
+//! Run with `cargo run --example ft232h --features ftdi -- check`.
+
+use cff3000::hal::{ft232h, DEFAULT_POLL_PERIOD};
+use cff3000::CFF3000;
+
+fn execute(cmd: &str) -> std::io::Result<()> {
+    let backend = ft232h::open(ft232h::DEFAULT_PINS, DEFAULT_POLL_PERIOD)?;
+    let cff3000 = CFF3000::with_backend(backend)?;
+
+    let state = match cmd {
+        "lock" => cff3000.lock_and_verify()?,
+        "unlock" => cff3000.unlock_and_verify()?,
+        "check" => cff3000.state()?,
+        _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "unsupported command")),
+    };
+
+    println!("{:?}", state);
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    if args.len() < 2 {
+        println!("missing parameter: lock, unlock, check");
+        std::process::exit(1)
+    }
+
+    if let Err(err) = execute(args[1].as_str()) {
+        println!("{}", err);
+        std::process::exit(1)
+    }
+}
@@ -0,0 +1,41 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Query the CFF3000 state on a Raspberry Pi.
+//!
+//! Wiring (BCM numbering, not physical header pins):
+//! LED red on GPIO17, LED green on GPIO27, button unlock on GPIO22 and
+//! button lock on GPIO23.
+//!
+//! Run with `cargo run --example raspberry_pi --features rppal`.
+
+use cff3000::rpi::RppalBackend;
+use cff3000::CFF3000;
+
+const BCM_LED_RED: u8 = 17;
+const BCM_LED_GREEN: u8 = 27;
+const BCM_BUTTON_UNLOCK: u8 = 22;
+const BCM_BUTTON_LOCK: u8 = 23;
+
+fn main() {
+    let backend = match RppalBackend::new([BCM_LED_RED, BCM_LED_GREEN, BCM_BUTTON_UNLOCK, BCM_BUTTON_LOCK]) {
+        Ok(backend) => backend,
+        Err(err) => {
+            println!("failed to set up GPIOs: {}", err);
+            std::process::exit(1)
+        },
+    };
+
+    let cff3000 = match CFF3000::with_backend(backend) {
+        Ok(cff3000) => cff3000,
+        Err(err) => {
+            println!("{}", err);
+            std::process::exit(1)
+        },
+    };
+
+    match cff3000.state() {
+        Ok(state) => println!("{:?}", state),
+        Err(err) => println!("{}", err),
+    }
+}
@@ -0,0 +1,59 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Run the LED pattern parser on arbitrary event lists.
+//!
+//! Input layout: two bytes merge window and one byte poll period (in
+//! ms), followed by 3 byte records: flags (bit 0: green, bit 1: on) and
+//! a big-endian u16 delay in ms since the previous event. The parser
+//! must return for every input without panicking, also with the
+//! timestamps moved to the end of the `Duration` range and with the
+//! largest merge window, and agree with its two stages; libFuzzer's
+//! timeout catches endless loops.
+//!
+//! The corpus contains the `mock::fixtures` patterns in this layout,
+//! with a few ms skew between the LEDs. Run with
+//! `cargo fuzz run parse fuzz/corpus/parse`.
+
+#![no_main]
+
+use std::time::Duration;
+
+use cff3000_parser as parser;
+use libfuzzer_sys::fuzz_target;
+use parser::{Led, LedEvent, ParseOptions};
+
+/// Bound the work per input, longer logs do not exercise new paths.
+const MAX_EVENTS: usize = 1024;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 3 {
+        return;
+    }
+    let options = ParseOptions {
+        merge_window: Duration::from_millis(u16::from_be_bytes([data[0], data[1]]) as u64),
+        poll_period: Duration::from_millis(data[2] as u64),
+    };
+
+    let mut timestamp = Duration::ZERO;
+    let events: Vec<LedEvent> = data[3..].chunks_exact(3).take(MAX_EVENTS).map(|record| {
+        timestamp += Duration::from_millis(u16::from_be_bytes([record[1], record[2]]) as u64);
+        LedEvent {
+            led: if record[0] & 1 == 0 {Led::Red} else {Led::Green},
+            on: record[0] & 2 != 0,
+            timestamp,
+        }
+    }).collect();
+
+    /* the last event at Duration::MAX */
+    let shift = Duration::MAX - timestamp;
+    let shifted: Vec<LedEvent> = events.iter().map(|event| LedEvent {timestamp: event.timestamp + shift, ..*event}).collect();
+    let widest = ParseOptions {merge_window: Duration::MAX, poll_period: Duration::MAX};
+
+    for events in &[events, shifted] {
+        for options in &[options, widest] {
+            let result = parser::parse(events, options);
+            assert_eq!(result, parser::classify(&parser::merge_events(events, options)));
+        }
+    }
+});
@@ -0,0 +1,9 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=cff3000.proto");
+    let descriptors = protox::compile(["cff3000.proto"], ["."])?;
+    tonic_build::configure().compile_fds(descriptors)?;
+    Ok(())
+}
@@ -0,0 +1,170 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! gRPC service of a CFF3000 door lock.
+//!
+//! This crate contains the async part of the `grpc` feature of the
+//! `cff3000` crate: the code generated from `cff3000.proto` and a
+//! tonic [`Server`] running on a thread of its own. The blocking
+//! operations behind it are a [`Door`], which `cff3000::grpc`
+//! implements with a `CommandQueue`. The `cff3000` crate re-exports
+//! everything, Rust clients use [`DoorLockClient`]:
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! use cff3000_grpc::proto::{LockRequest, State};
+//! use cff3000_grpc::DoorLockClient;
+//!
+//! let mut client = DoorLockClient::connect("http://door:50051").await?;
+//! let response = client.lock(LockRequest {}).await?;
+//! assert_eq!(response.into_inner().state(), State::Locked);
+//! # Ok(())
+//! # }
+//! ```
+
+/* tonic's Status is what the handlers return anyway */
+#![allow(clippy::result_large_err)]
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response};
+
+pub use tonic::{Code, Status};
+
+/// Messages and services of `cff3000.proto`.
+pub mod proto {
+    tonic::include_proto!("cff3000.v1");
+}
+
+pub use proto::door_lock_client::DoorLockClient;
+
+use proto::door_lock_server::{DoorLock, DoorLockServer};
+use proto::{GetStateRequest, LockRequest, StateChange, StateResponse, UnlockRequest, WatchStateRequest};
+
+/// RPC of a `Door` command.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Command {
+    Lock,
+    Unlock,
+    GetState,
+}
+
+/// Blocking operations behind the service.
+pub trait Door: Send + Sync + 'static {
+    /// Run `command` for the client at `peer` (if known) and return
+    /// the state the door confirmed.
+    fn execute(&self, command: Command, peer: Option<SocketAddr>) -> Result<proto::State, Status>;
+
+    /// Every change from now on, ending when the server stops
+    /// publishing them.
+    fn subscribe(&self) -> Box<dyn Iterator<Item = StateChange> + Send>;
+}
+
+struct Service {
+    door: Arc<dyn Door>,
+    buffer: usize,
+}
+
+impl Service {
+    async fn execute(&self, command: Command, peer: Option<SocketAddr>) -> Result<Response<StateResponse>, Status> {
+        let door = self.door.clone();
+        /* a command takes seconds, keep it off the runtime's thread */
+        let state = tokio::task::spawn_blocking(move || door.execute(command, peer)).await
+            .map_err(|err| Status::internal(err.to_string()))??;
+        Ok(Response::new(StateResponse {state: state.into()}))
+    }
+}
+
+/// Hand `changes` to a client through `tx`, dropping those it is not
+/// ready for and flagging the next one it gets.
+fn forward(changes: Box<dyn Iterator<Item = StateChange> + Send>, tx: mpsc::Sender<Result<StateChange, Status>>) {
+    let mut missed = false;
+    for mut change in changes {
+        change.missed_changes = missed;
+        match tx.try_send(Ok(change)) {
+            Ok(()) => missed = false,
+            Err(mpsc::error::TrySendError::Full(_)) => missed = true,
+            Err(mpsc::error::TrySendError::Closed(_)) => return,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl DoorLock for Service {
+    async fn lock(&self, request: Request<LockRequest>) -> Result<Response<StateResponse>, Status> {
+        self.execute(Command::Lock, request.remote_addr()).await
+    }
+
+    async fn unlock(&self, request: Request<UnlockRequest>) -> Result<Response<StateResponse>, Status> {
+        self.execute(Command::Unlock, request.remote_addr()).await
+    }
+
+    async fn get_state(&self, request: Request<GetStateRequest>) -> Result<Response<StateResponse>, Status> {
+        self.execute(Command::GetState, request.remote_addr()).await
+    }
+
+    type WatchStateStream = ReceiverStream<Result<StateChange, Status>>;
+
+    async fn watch_state(&self, _: Request<WatchStateRequest>) -> Result<Response<Self::WatchStateStream>, Status> {
+        let changes = self.door.subscribe();
+        let (tx, rx) = mpsc::channel(self.buffer);
+        /* not joined, it ends with the next change after the client left */
+        std::thread::Builder::new().name("cff3000-grpc-watch".to_string()).spawn(move || forward(changes, tx))
+            .map_err(|err| Status::internal(err.to_string()))?;
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+/// Running server. Dropping it stops accepting calls and waits for the
+/// running ones.
+pub struct Server {
+    addr: SocketAddr,
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Server {
+    /// Serve `door` on `listener`. Every `WatchState` call buffers up
+    /// to `buffer` changes for its client, dropping further ones.
+    pub fn start(door: Arc<dyn Door>, listener: std::net::TcpListener, buffer: usize) -> std::io::Result<Server> {
+        let addr = listener.local_addr()?;
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        listener.set_nonblocking(true)?;
+        let listener = {
+            let _context = runtime.enter();
+            tokio::net::TcpListener::from_std(listener)?
+        };
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        let service = DoorLockServer::new(Service {door, buffer: buffer.max(1)});
+        let thread = std::thread::Builder::new().name("cff3000-grpc".to_string()).spawn(move || {
+            let serving = tonic::transport::Server::builder()
+                .add_service(service)
+                .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+                    let _ = stopped.await;
+                });
+            /* errors of accepted connections end those, not the server */
+            let _ = runtime.block_on(serving);
+        })?;
+        Ok(Server {addr, shutdown: Some(shutdown), thread: Some(thread)})
+    }
+
+    /// Address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
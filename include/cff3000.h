@@ -0,0 +1,165 @@
+/* © 2018 Sebastian Reichel
+ * SPDX-License-Identifier: ISC
+ */
+
+#ifndef CFF3000_H
+#define CFF3000_H
+
+/* Generated by cbindgen from src/ffi.rs, do not edit. */
+
+#include <stdint.h>
+
+/**
+ * Success.
+ */
+#define CFF3000_OK 0
+
+/**
+ * The door is locked.
+ */
+#define CFF3000_STATE_LOCKED 0
+
+/**
+ * The door is unlocked.
+ */
+#define CFF3000_STATE_UNLOCKED 1
+
+/**
+ * The door has been locked or unlocked manually.
+ */
+#define CFF3000_STATE_MANUAL 2
+
+/**
+ * The lock did not answer the remote.
+ */
+#define CFF3000_STATE_OUT_OF_RANGE 3
+
+/**
+ * The LEDs did not show a pattern.
+ */
+#define CFF3000_ERROR_NO_RESPONSE 4
+
+/**
+ * Invalid arguments or configuration.
+ */
+#define CFF3000_ERROR_CONFIG 11
+
+/**
+ * The GPIO chip does not exist.
+ */
+#define CFF3000_ERROR_NOT_FOUND 12
+
+/**
+ * No access to the GPIO chip.
+ */
+#define CFF3000_ERROR_PERMISSION_DENIED 13
+
+/**
+ * The lines, the lock file or the device are in use.
+ */
+#define CFF3000_ERROR_BUSY 14
+
+/**
+ * The LED pattern was not understood.
+ */
+#define CFF3000_ERROR_INVALID_PATTERN 15
+
+/**
+ * Not available on this system.
+ */
+#define CFF3000_ERROR_UNSUPPORTED 17
+
+/**
+ * Any other error.
+ */
+#define CFF3000_ERROR_IO 18
+
+/**
+ * Device opened by `cff3000_new()`.
+ */
+typedef struct cff3000 cff3000;
+
+
+
+
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Open the CFF3000 on the GPIO chip `chip` (e.g. "/dev/gpiochip0")
+ * with the line offsets `pins` of LED red, LED green, button unlock
+ * and button lock. Returns NULL on failure, see
+ * `cff3000_last_error_message(NULL)`.
+ *
+ * # Safety
+ *
+ * `chip` must be a NUL-terminated string and `pins` point to 4 line
+ * offsets.
+ */
+struct cff3000 *cff3000_new(const char *chip, const uint32_t *pins);
+
+/**
+ * Press the lock button.
+ *
+ * # Safety
+ *
+ * `handle` must be NULL or returned by `cff3000_new()` and not freed.
+ */
+int cff3000_lock(struct cff3000 *handle);
+
+/**
+ * Press the unlock button.
+ *
+ * # Safety
+ *
+ * `handle` must be NULL or returned by `cff3000_new()` and not freed.
+ */
+int cff3000_unlock(struct cff3000 *handle);
+
+/**
+ * Press both buttons, the CFF3000 shows its state on the LEDs.
+ *
+ * # Safety
+ *
+ * `handle` must be NULL or returned by `cff3000_new()` and not freed.
+ */
+int cff3000_check(struct cff3000 *handle);
+
+/**
+ * Query the state and store it in `out_state`, which is left alone
+ * on failure. Takes about 10 seconds.
+ *
+ * # Safety
+ *
+ * `handle` must be NULL or returned by `cff3000_new()` and not freed,
+ * `out_state` must be NULL or point to an `int`.
+ */
+int cff3000_state(struct cff3000 *handle, int *out_state);
+
+/**
+ * Message of the last failure on `handle`, or of the last failed
+ * `cff3000_new()` of this thread for NULL. The string is empty if
+ * nothing failed and valid until the next call with `handle`.
+ *
+ * # Safety
+ *
+ * `handle` must be NULL or returned by `cff3000_new()` and not freed.
+ */
+const char *cff3000_last_error_message(const struct cff3000 *handle);
+
+/**
+ * Release the lines and free `handle`, NULL is ignored.
+ *
+ * # Safety
+ *
+ * `handle` must be NULL or returned by `cff3000_new()` and not freed.
+ */
+void cff3000_free(struct cff3000 *handle);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* CFF3000_H */
@@ -0,0 +1,666 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+#![no_std]
+#![deny(clippy::indexing_slicing, clippy::unwrap_used, clippy::expect_used, clippy::panic)]
+
+//! Interpretation of the CFF3000 LED patterns.
+//!
+//! This crate contains the hardware independent part of the `cff3000`
+//! crate: the LED event types and the classification of a captured
+//! LED pattern. It only needs `core` and `alloc`, so the same logic can
+//! run on microcontrollers sampling the LEDs directly. The `cff3000`
+//! crate re-exports everything.
+//!
+//! No function of this crate panics, whatever the events, their order
+//! or timestamps and the `ParseOptions`: a glitching LED is reported as
+//! a `ParseError`. Clippy denies indexing, `unwrap()` and `panic!()`
+//! here, and the `parse` fuzz target of the `cff3000` crate runs the
+//! parser on arbitrary input.
+
+extern crate alloc;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use alloc::vec::Vec;
+use core::time::Duration;
+
+/// LED of the CFF3000, serialized as "red" or "green".
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(rename_all = "lowercase"))]
+pub enum Led {
+    Red,
+    Green,
+}
+
+impl Led {
+    /// Bit used for this LED in event masks.
+    pub fn mask(self) -> u8 {
+        match self {
+            Led::Red => LED_RED,
+            Led::Green => LED_GREEN,
+        }
+    }
+}
+
+/// Mask bit of the red LED.
+pub const LED_RED: u8 = 0b01;
+/// Mask bit of the green LED.
+pub const LED_GREEN: u8 = 0b10;
+
+/// Level change of one LED.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(deny_unknown_fields))]
+pub struct LedEvent {
+    /// LED which changed
+    pub led: Led,
+    /// true if the LED has been switched on
+    pub on: bool,
+    /// time since the epoch of the monotonic clock of the backend, e.g.
+    /// `CLOCK_MONOTONIC` for the edge events of the kernel, serialized
+    /// as integer nanoseconds
+    ///
+    /// Deliberately not an offset from the start of the capture: the
+    /// kernel stamps the edges in its own time base, which is also the
+    /// one of the wall-clock anchors of the `cff3000` crate and keeps
+    /// the events of consecutive captures comparable. The parser only
+    /// uses the spacing of the events, offsets within a capture are the
+    /// differences to its first event.
+    #[cfg_attr(feature = "serde", serde(rename = "timestamp_ns", with = "nanos"))]
+    pub timestamp: Duration,
+}
+
+/// Serde representation of a `Duration` as integer nanoseconds, e.g.
+/// `LedEvent::timestamp` as `timestamp_ns`, which keeps the time base
+/// of the backend.
+#[cfg(feature = "serde")]
+pub mod nanos {
+    use core::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    /// Serialize `duration` as u64 nanoseconds, fails beyond 584 years.
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        match u64::try_from(duration.as_nanos()) {
+            Ok(nanos) => serializer.serialize_u64(nanos),
+            Err(_) => Err(serde::ser::Error::custom("duration beyond u64 nanoseconds")),
+        }
+    }
+
+    /// Deserialize u64 nanoseconds.
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_nanos)
+    }
+}
+
+/// Capacity of an `EventBuffer`.
+///
+/// The longest patterns, manual and out-of-range with the blinks of the
+/// second hardware revision, have about 30 events (see the fixtures of
+/// the `cff3000` crate). The capacity leaves room for contact bounce
+/// and for the remote control being used during a capture, while an
+/// `EventBuffer` stays at 2 KiB.
+pub const MAX_EVENTS: usize = 128;
+
+/// LED events of one capture, stored inline.
+///
+/// Holds up to `MAX_EVENTS` events without allocating. Pushing onto a
+/// full buffer fails instead of growing it, the capture stops there and
+/// reports itself as truncated. Dereferences to `[LedEvent]` and
+/// serializes as a sequence.
+#[derive(Clone)]
+pub struct EventBuffer {
+    events: [LedEvent; MAX_EVENTS],
+    len: usize,
+}
+
+impl EventBuffer {
+    /// Empty buffer.
+    pub const fn new() -> EventBuffer {
+        EventBuffer {events: [LedEvent {led: Led::Red, on: false, timestamp: Duration::ZERO}; MAX_EVENTS], len: 0}
+    }
+
+    /// Buffer holding `events`, `None` if they are more than
+    /// `MAX_EVENTS`.
+    pub fn from_slice(events: &[LedEvent]) -> Option<EventBuffer> {
+        let mut buffer = EventBuffer::new();
+        if buffer.extend_from_slice(events) == events.len() {Some(buffer)} else {None}
+    }
+
+    /// Append `event`, returns false without storing it if the buffer
+    /// is full.
+    pub fn push(&mut self, event: LedEvent) -> bool {
+        match self.events.get_mut(self.len) {
+            Some(slot) => {
+                *slot = event;
+                self.len += 1;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Append as many of `events` as fit, returns how many.
+    pub fn extend_from_slice(&mut self, events: &[LedEvent]) -> usize {
+        let mut count = 0;
+        for (slot, &event) in self.events.iter_mut().skip(self.len).zip(events) {
+            *slot = event;
+            count += 1;
+        }
+        self.len += count;
+        count
+    }
+
+    /// Number of events which still fit.
+    pub fn remaining(&self) -> usize {
+        MAX_EVENTS.saturating_sub(self.len)
+    }
+
+    /// Returns true if no further event fits.
+    pub fn is_full(&self) -> bool {
+        self.len == MAX_EVENTS
+    }
+
+    /// Remove all events.
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// The events, in the order they have been appended.
+    pub fn as_slice(&self) -> &[LedEvent] {
+        self.events.get(..self.len).unwrap_or(&[])
+    }
+
+    /// Like `as_slice()`, e.g. for sorting the events.
+    pub fn as_mut_slice(&mut self) -> &mut [LedEvent] {
+        self.events.get_mut(..self.len).unwrap_or(&mut [])
+    }
+}
+
+impl Default for EventBuffer {
+    fn default() -> EventBuffer {
+        EventBuffer::new()
+    }
+}
+
+impl core::ops::Deref for EventBuffer {
+    type Target = [LedEvent];
+
+    fn deref(&self) -> &[LedEvent] {
+        self.as_slice()
+    }
+}
+
+impl core::ops::DerefMut for EventBuffer {
+    fn deref_mut(&mut self) -> &mut [LedEvent] {
+        self.as_mut_slice()
+    }
+}
+
+impl<'a> IntoIterator for &'a EventBuffer {
+    type Item = &'a LedEvent;
+    type IntoIter = core::slice::Iter<'a, LedEvent>;
+
+    fn into_iter(self) -> core::slice::Iter<'a, LedEvent> {
+        self.as_slice().iter()
+    }
+}
+
+impl core::fmt::Debug for EventBuffer {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_list().entries(self.as_slice()).finish()
+    }
+}
+
+impl PartialEq for EventBuffer {
+    fn eq(&self, other: &EventBuffer) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl Eq for EventBuffer {}
+
+impl PartialEq<[LedEvent]> for EventBuffer {
+    fn eq(&self, other: &[LedEvent]) -> bool {
+        self.as_slice() == other
+    }
+}
+
+impl PartialEq<Vec<LedEvent>> for EventBuffer {
+    fn eq(&self, other: &Vec<LedEvent>) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for EventBuffer {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.as_slice())
+    }
+}
+
+#[cfg(feature = "serde")]
+struct EventsVisitor;
+
+#[cfg(feature = "serde")]
+impl<'de> serde::de::Visitor<'de> for EventsVisitor {
+    type Value = EventBuffer;
+
+    fn expecting(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "a sequence of at most {} LED events", MAX_EVENTS)
+    }
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<EventBuffer, A::Error> {
+        let mut events = EventBuffer::new();
+        while let Some(event) = seq.next_element()? {
+            if !events.push(event) {
+                return Err(serde::de::Error::invalid_length(MAX_EVENTS + 1, &self));
+            }
+        }
+        Ok(events)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for EventBuffer {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<EventBuffer, D::Error> {
+        deserializer.deserialize_seq(EventsVisitor)
+    }
+}
+
+/// State of the door, serialized as its `name()`.
+#[derive(Debug, Copy, Clone)]
+#[derive(PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(rename_all = "kebab-case"))]
+pub enum CFF3000State {
+    /// The door is locked (green LED on)
+    Locked,
+    /// The door is unlocked (red LED on)
+    Unlocked,
+    /// The door state has been changed manually (both LEDs blink synchronously)
+    Manual,
+    /// The CFA3000 is out of range (both LEDs blink alternating)
+    OutOfRange,
+}
+
+/// LED levels (bit mask of `LED_RED` and `LED_GREEN`) shown after a
+/// check press for one state.
+///
+/// Every pattern starts with both LEDs on and ends with both LEDs off.
+/// In between, `levels` is shown once or, for blinking patterns,
+/// repeated.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    pub state: CFF3000State,
+    pub levels: &'static [u8],
+    pub blinking: bool,
+}
+
+/// All known patterns. `parse()` classifies by this table and the
+/// pattern generator of the `cff3000` crate produces it, so both agree.
+pub const PATTERNS: [Pattern; 5] = [
+    Pattern {state: CFF3000State::Locked, levels: &[LED_GREEN], blinking: false},
+    Pattern {state: CFF3000State::Unlocked, levels: &[LED_RED], blinking: false},
+    Pattern {state: CFF3000State::Manual, levels: &[0b00, LED_RED | LED_GREEN], blinking: true},
+    Pattern {state: CFF3000State::OutOfRange, levels: &[LED_RED, LED_GREEN], blinking: true},
+    Pattern {state: CFF3000State::OutOfRange, levels: &[LED_GREEN, LED_RED], blinking: true},
+];
+
+impl CFF3000State {
+    /// First entry of `PATTERNS` for this state.
+    pub fn pattern(self) -> &'static Pattern {
+        match self {
+            CFF3000State::Locked => &PATTERNS[0],
+            CFF3000State::Unlocked => &PATTERNS[1],
+            CFF3000State::Manual => &PATTERNS[2],
+            CFF3000State::OutOfRange => &PATTERNS[3],
+        }
+    }
+
+    /// State with `name()`, e.g. `OutOfRange` for "out-of-range".
+    pub fn from_name(name: &str) -> Option<CFF3000State> {
+        [CFF3000State::Locked, CFF3000State::Unlocked, CFF3000State::Manual, CFF3000State::OutOfRange]
+            .iter().cloned().find(|state| state.name() == name)
+    }
+
+    /// Lowercase name, e.g. "out-of-range".
+    pub fn name(self) -> &'static str {
+        match self {
+            CFF3000State::Locked => "locked",
+            CFF3000State::Unlocked => "unlocked",
+            CFF3000State::Manual => "manual",
+            CFF3000State::OutOfRange => "out-of-range",
+        }
+    }
+}
+
+/// Tunables of the LED pattern interpretation.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// LED changes closer together than this are treated as one
+    /// simultaneous change of both LEDs (default: 50 ms)
+    pub merge_window: Duration,
+    /// Sampling period of backends without edge events (default: 0).
+    /// LED changes are seen up to one period late, so it is added to
+    /// the merge window.
+    pub poll_period: Duration,
+}
+
+/// Default `ParseOptions::merge_window`.
+pub const DEFAULT_MERGE_WINDOW: Duration = Duration::from_millis(50);
+
+impl Default for ParseOptions {
+    fn default() -> ParseOptions {
+        ParseOptions {
+            merge_window: DEFAULT_MERGE_WINDOW,
+            poll_period: Duration::from_millis(0),
+        }
+    }
+}
+
+/// Reason a LED pattern could not be interpreted.
+///
+/// The `cff3000` crate reports it as `std::io::Error` with
+/// `ErrorKind::InvalidData` wrapping this type.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// Fewer LED changes than any valid pattern has
+    NotEnoughEvents,
+    /// The pattern does not start with both LEDs on
+    InvalidFirst,
+    /// The pattern does not end with both LEDs off
+    InvalidLast,
+    /// Unexpected LED combination after the initial change
+    InvalidState,
+    /// Synchronous blinking interrupted
+    InvalidManualSubstate,
+    /// Alternating blinking interrupted
+    InvalidOutOfRangeSubstate,
+}
+
+impl core::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str(match *self {
+            ParseError::NotEnoughEvents => "did not receive enough LED change events",
+            ParseError::InvalidFirst => "first LED changes is invalid",
+            ParseError::InvalidLast => "last LED change is invalid",
+            ParseError::InvalidState => "invalid LED state",
+            ParseError::InvalidManualSubstate => "invalid manual error LED substate",
+            ParseError::InvalidOutOfRangeSubstate => "invalid out of range error LED substate",
+        })
+    }
+}
+
+impl core::error::Error for ParseError {}
+
+#[derive(Debug, Copy, Clone)]
+struct Event {
+    /// led (0 = red, 1 = green)
+    mask: u8,
+    /// timestamp
+    timestamp: Duration,
+    /// enabled = HIGH, otherwise LOW
+    state: u8,
+}
+
+impl From<LedEvent> for Event {
+    fn from(event: LedEvent) -> Event {
+        let mask = event.led.mask();
+        Event {mask, timestamp: event.timestamp, state: if event.on {mask} else {0}}
+    }
+}
+
+/// LED levels after a change, as produced by `merge_events()`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MergedEvent {
+    /// Time since the first merged event, the start of the pattern
+    pub time: Duration,
+    /// Levels of both LEDs (bit mask of `LED_RED` and `LED_GREEN`)
+    pub levels: u8,
+}
+
+/// Sort `events` by their timestamp, keeping the order of equal ones.
+/// An insertion sort, the events of a capture are sorted by LED and
+/// almost sorted overall.
+fn sort_events(events: &mut [LedEvent]) {
+    for i in 1..events.len() {
+        let mut j = i;
+        while let Some([before, after]) = j.checked_sub(1).and_then(|k| events.get_mut(k..=j)) {
+            if before.timestamp <= after.timestamp {
+                break;
+            }
+            core::mem::swap(before, after);
+            j -= 1;
+        }
+    }
+}
+
+/// Combine `events` into level changes passed to `push`, see
+/// `merge_events()`. Only unsorted input is copied, to the stack unless
+/// it is longer than an `EventBuffer`.
+fn merge<F: FnMut(MergedEvent)>(events: &[LedEvent], options: &ParseOptions, mut push: F) {
+    let window = options.merge_window.saturating_add(options.poll_period);
+
+    /* both LEDs are read from separate queues, restore chronological order */
+    let mut buffer;
+    let mut copy;
+    let events = if events.windows(2).all(|pair| match *pair {
+        [before, after] => before.timestamp <= after.timestamp,
+        _ => true,
+    }) {
+        events
+    } else if let Some(events) = EventBuffer::from_slice(events) {
+        buffer = events;
+        sort_events(&mut buffer);
+        buffer.as_slice()
+    } else {
+        copy = events.to_vec();
+        sort_events(&mut copy);
+        copy.as_slice()
+    };
+    let (first, rest) = match events.split_first() {
+        Some((&first, rest)) => (Event::from(first), rest),
+        None => return,
+    };
+
+    /* fill up event data for unchanged leds with previous information and use relative timestamps */
+    let mut levels = 0u8;
+    let mut last = None;
+    let mut flush = |group: Event| {
+        levels &= !group.mask;
+        levels |= group.state & group.mask;
+        if last != Some(levels) {
+            last = Some(levels);
+            push(MergedEvent {time: group.timestamp.saturating_sub(first.timestamp), levels});
+        }
+    };
+
+    /* combine events within the merge window */
+    let mut group = first;
+    let mut previous = first.timestamp;
+    for &e in rest {
+        let e = Event::from(e);
+        if previous.saturating_add(window) > e.timestamp {
+            group.mask |= e.mask;
+            group.state |= e.state & e.mask;
+            group.state &= e.state | !e.mask;
+        } else {
+            flush(group);
+            group = e;
+        }
+        previous = e.timestamp;
+    }
+    flush(group);
+}
+
+/// First stage of `parse()`: turn the single-LED events into a list of
+/// combined level changes.
+///
+/// `events` may be in any order, they are sorted by timestamp first.
+/// Events closer together than the merge window (plus poll period) are
+/// combined into one change, so LEDs switching together are seen as a
+/// simultaneous change of both. Changes which do not alter the levels
+/// (e.g. a glitch shorter than the merge window) are dropped, except
+/// for the first one. Earlier versions kept them, which changed the
+/// classification of repeated levels (see CHANGELOG.md).
+pub fn merge_events(events: &[LedEvent], options: &ParseOptions) -> Vec<MergedEvent> {
+    let mut merged = Vec::with_capacity(events.len());
+    merge(events, options, |change| merged.push(change));
+    merged
+}
+
+/// Second stage of `parse()`: classify the level changes produced by
+/// `merge_events()` according to `PATTERNS`.
+pub fn classify(merged: &[MergedEvent]) -> Result<CFF3000State, ParseError> {
+    /* check for obvious problems */
+    let (first, second, last) = match merged {
+        [first, second, .., last] => (first, second, last),
+        _ => return Err(ParseError::NotEnoughEvents),
+    };
+    if first.levels != 0b11 {
+        return Err(ParseError::InvalidFirst);
+    }
+    if last.levels != 0b00 {
+        return Err(ParseError::InvalidLast);
+    }
+
+    /* a single level in between means a steady pattern */
+    let blinking = merged.len() != 3;
+    let result = match PATTERNS.iter().find(|p| p.blinking == blinking && p.levels.first() == Some(&second.levels)) {
+        Some(pattern) => pattern.state,
+        None => return Err(ParseError::InvalidState),
+    };
+
+    if blinking {
+        /* pairs of subsequent changes, leaving out the first and the last change */
+        for pair in merged.windows(2).skip(1).take(merged.len() - 3) {
+            let (previous, levels) = match *pair {
+                [previous, change] => (previous.levels, change.levels),
+                _ => continue,
+            };
+            if result == CFF3000State::Manual {
+                if previous & 0b11 != !levels & 0b11 {
+                    return Err(ParseError::InvalidManualSubstate);
+                }
+            } else {
+                if levels == 0b00 || levels == 0b11 {
+                    return Err(ParseError::InvalidOutOfRangeSubstate);
+                }
+                if previous & 0b11 == !previous & 0b11 {
+                    return Err(ParseError::InvalidOutOfRangeSubstate);
+                }
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// `classify()` of level changes passed one at a time.
+///
+/// Keeps the levels of the first two and the last two changes, the
+/// blinking pattern the second one starts and its first error instead
+/// of the changes, so `parse()` merges and classifies in a single pass.
+/// `result()` is the classification of the changes pushed so far, e.g.
+/// for reading a pattern while it is shown.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Classifier {
+    len: usize,
+    first: u8,
+    second: u8,
+    previous: u8,
+    last: u8,
+    blinking: Option<CFF3000State>,
+    substate: Option<ParseError>,
+}
+
+impl Classifier {
+    /// Classifier without changes.
+    pub const fn new() -> Classifier {
+        Classifier {len: 0, first: 0, second: 0, previous: 0, last: 0, blinking: None, substate: None}
+    }
+
+    /// Append the next change.
+    pub fn push(&mut self, change: MergedEvent) {
+        match self.len {
+            0 => self.first = change.levels,
+            1 => {
+                self.second = change.levels;
+                self.blinking = PATTERNS.iter().find(|p| p.blinking && p.levels.first() == Some(&change.levels)).map(|p| p.state);
+            },
+            /* the previous change is no longer the last one */
+            _ if self.len >= 3 && self.substate.is_none() => self.substate = self.check(self.previous, self.last),
+            _ => {},
+        }
+        self.previous = self.last;
+        self.last = change.levels;
+        self.len += 1;
+    }
+
+    /// Blinking error of a change to `levels` after `previous`, neither
+    /// being the first or the last change.
+    fn check(&self, previous: u8, levels: u8) -> Option<ParseError> {
+        match self.blinking {
+            /* the result is InvalidState */
+            None => None,
+            Some(CFF3000State::Manual) if previous & 0b11 != !levels & 0b11 => Some(ParseError::InvalidManualSubstate),
+            Some(CFF3000State::Manual) => None,
+            Some(_) if levels == 0b00 || levels == 0b11 || previous & 0b11 == !previous & 0b11 => Some(ParseError::InvalidOutOfRangeSubstate),
+            Some(_) => None,
+        }
+    }
+
+    /// Number of changes pushed.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if no change has been pushed.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Same as `classify()` of the changes pushed.
+    pub fn result(&self) -> Result<CFF3000State, ParseError> {
+        if self.len <= 2 {
+            return Err(ParseError::NotEnoughEvents);
+        }
+        if self.first != 0b11 {
+            return Err(ParseError::InvalidFirst);
+        }
+        if self.last != 0b00 {
+            return Err(ParseError::InvalidLast);
+        }
+
+        let blinking = self.len != 3;
+        let result = match PATTERNS.iter().find(|p| p.blinking == blinking && p.levels.first() == Some(&self.second)) {
+            Some(pattern) => pattern.state,
+            None => return Err(ParseError::InvalidState),
+        };
+        match self.substate {
+            Some(err) if blinking => Err(err),
+            _ => Ok(result),
+        }
+    }
+}
+
+impl Default for Classifier {
+    fn default() -> Classifier {
+        Classifier::new()
+    }
+}
+
+/// Interpret the LED events captured after a button press, see
+/// `merge_events()` and `classify()`.
+///
+/// Same result as `classify(&merge_events(events, options))`, but the
+/// changes go straight into a `Classifier` instead of a list, so sorted
+/// events are read once and nothing is allocated for up to `MAX_EVENTS`
+/// events. The two stages remain for tools showing the changes, like
+/// `cff3000 replay`.
+pub fn parse(events: &[LedEvent], options: &ParseOptions) -> Result<CFF3000State, ParseError> {
+    let mut classifier = Classifier::new();
+    merge(events, options, |change| classifier.push(change));
+    classifier.result()
+}
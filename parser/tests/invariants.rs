@@ -0,0 +1,236 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Invariants of the LED pattern parser.
+//!
+//! These properties are the specification of the two parser stages:
+//!
+//! * `merge_events()` is idempotent: merging its own output again (as
+//!   events of both LEDs at every change) gives the same result.
+//! * Merged changes are strictly increasing in time and every change
+//!   alters the LED levels.
+//! * `parse()` and `merge_events()` depend only on the relative timing
+//!   of the events, so translating all timestamps (by nothing, by less
+//!   than a millisecond or by minutes) never changes the result.
+//! * A pulse shorter than the merge window, well away from any other
+//!   edge, never changes the classification.
+//! * Every pattern from `PATTERNS`, with the LEDs of a change up to a
+//!   merge window apart, is classified as its state.
+//! * The single pass of `parse()` agrees with the two stages, and a
+//!   `Classifier` with every prefix of the changes.
+//! * Nothing panics, also for timestamps in any order up to
+//!   `Duration::MAX` and merge windows up to `Duration::MAX`.
+
+use std::time::Duration;
+
+use cff3000_parser as parser;
+use parser::{classify, merge_events, parse, CFF3000State, Classifier, EventBuffer, Led, LedEvent, MergedEvent, ParseOptions, MAX_EVENTS, PATTERNS};
+use proptest::prelude::*;
+
+fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+fn options(merge_ms: u64) -> ParseOptions {
+    ParseOptions {merge_window: Duration::from_millis(merge_ms), ..ParseOptions::default()}
+}
+
+/// Events of both LEDs for every merged change.
+fn unmerge(merged: &[MergedEvent], base: Duration) -> Vec<LedEvent> {
+    let mut events = Vec::new();
+    for change in merged {
+        for &led in &[Led::Red, Led::Green] {
+            events.push(LedEvent {led, on: change.levels & led.mask() != 0, timestamp: base + change.time});
+        }
+    }
+    events
+}
+
+/// Arbitrary event logs: up to 40 edges with 0-2000 ms gaps, in
+/// nanoseconds.
+fn event_log() -> impl Strategy<Value = Vec<LedEvent>> {
+    prop::collection::vec((any::<bool>(), any::<bool>(), 0u64..2_000_000_000), 0..40).prop_map(|edges| {
+        let mut timestamp = Duration::ZERO;
+        edges.into_iter().map(|(green, on, gap_ns)| {
+            timestamp += Duration::from_nanos(gap_ns);
+            LedEvent {led: if green {Led::Green} else {Led::Red}, on, timestamp}
+        }).collect()
+    })
+}
+
+/// Event logs of any length (also beyond an `EventBuffer`), order and
+/// timestamps.
+fn hostile_log() -> impl Strategy<Value = Vec<LedEvent>> {
+    let timestamp = prop_oneof![duration(), (0u64..10_000_000_000).prop_map(|before| Duration::MAX - Duration::from_nanos(before))];
+    prop::collection::vec((any::<bool>(), any::<bool>(), timestamp), 0..MAX_EVENTS + 20).prop_map(|edges| {
+        edges.into_iter().map(|(green, on, timestamp)| LedEvent {led: if green {Led::Green} else {Led::Red}, on, timestamp}).collect()
+    })
+}
+
+/// Any `Duration`.
+fn duration() -> impl Strategy<Value = Duration> {
+    prop_oneof![Just(Duration::MAX), (any::<u64>(), 0u32..1_000_000_000).prop_map(|(secs, nanos)| Duration::new(secs, nanos))]
+}
+
+/// Translations: none, below a millisecond or up to an hour.
+fn offset() -> impl Strategy<Value = Duration> {
+    prop_oneof![Just(Duration::ZERO), (1u64..1_000_000).prop_map(Duration::from_nanos), (1u64..3_600_000_000_000).prop_map(Duration::from_nanos)]
+}
+
+/// Edges of a pattern from `PATTERNS` (index `pattern`), showing
+/// `blinks` levels of blinking patterns 500 ms apart and delaying the
+/// green edges by `skew_ms`.
+fn pattern_log(pattern: usize, blinks: usize, skew_ms: u64) -> Vec<LedEvent> {
+    let pattern = &PATTERNS[pattern];
+    let mut levels = vec![0b11];
+    let count = if pattern.blinking {blinks} else {1};
+    levels.extend((0..count).map(|i| pattern.levels[i % pattern.levels.len()]));
+    levels.push(0b00);
+
+    let mut events = Vec::new();
+    let mut previous = 0b00;
+    for (i, &level) in levels.iter().enumerate() {
+        let t = 700 + 500 * i as u64;
+        for &(led, delay) in &[(Led::Red, 0), (Led::Green, skew_ms)] {
+            if (previous ^ level) & led.mask() != 0 {
+                events.push(LedEvent {led, on: level & led.mask() != 0, timestamp: ms(t + delay)});
+            }
+        }
+        previous = level;
+    }
+    events
+}
+
+proptest! {
+    #[test]
+    fn merging_is_idempotent(events in event_log(), merge_ms in 1u64..200) {
+        let merged = merge_events(&events, &options(merge_ms));
+        prop_assert_eq!(merge_events(&unmerge(&merged, ms(10_000)), &options(merge_ms)), merged);
+    }
+
+    #[test]
+    fn merged_changes_are_increasing(events in event_log(), merge_ms in 1u64..200) {
+        let merged = merge_events(&events, &options(merge_ms));
+        for pair in merged.windows(2) {
+            prop_assert!(pair[0].time < pair[1].time);
+            prop_assert!(pair[0].levels != pair[1].levels);
+        }
+    }
+
+    #[test]
+    fn translation_does_not_change_classification(events in event_log(), shift in offset()) {
+        let shifted: Vec<LedEvent> = events.iter()
+            .map(|e| LedEvent {timestamp: e.timestamp + shift, ..*e})
+            .collect();
+        prop_assert_eq!(merge_events(&shifted, &options(50)), merge_events(&events, &options(50)));
+        prop_assert_eq!(parse(&shifted, &options(50)), parse(&events, &options(50)));
+    }
+
+    #[test]
+    fn short_glitches_are_ignored(pattern in 0..PATTERNS.len(), blinks in 2usize..10, green in any::<bool>(),
+                                  offset_ms in 100u64..350, width_ms in 0u64..50, step in 0usize..12) {
+        let mut events = pattern_log(pattern, blinks, 0);
+        let expected = parse(&events, &options(50));
+
+        /* between two changes (500 ms apart), at least 100 ms away from both */
+        let changes = if PATTERNS[pattern].blinking {blinks + 2} else {3};
+        let at = 700 + 500 * (step % (changes - 1)) as u64 + offset_ms;
+        let led = if green {Led::Green} else {Led::Red};
+        let level = events.iter().rev().find(|e| e.led == led && e.timestamp <= ms(at)).is_some_and(|e| e.on);
+        events.push(LedEvent {led, on: !level, timestamp: ms(at)});
+        events.push(LedEvent {led, on: level, timestamp: ms(at + width_ms)});
+
+        prop_assert_eq!(parse(&events, &options(50)), expected);
+    }
+
+    #[test]
+    fn single_pass_agrees(events in event_log(), merge_ms in 1u64..200) {
+        let options = options(merge_ms);
+        let merged = merge_events(&events, &options);
+        prop_assert_eq!(parse(&events, &options), classify(&merged));
+
+        let mut classifier = Classifier::new();
+        prop_assert_eq!(classifier.result(), classify(&[]));
+        for (i, &change) in merged.iter().enumerate() {
+            classifier.push(change);
+            prop_assert_eq!(classifier.result(), classify(&merged[..=i]));
+        }
+    }
+
+    #[test]
+    fn single_pass_agrees_on_patterns(pattern in 0..PATTERNS.len(), blinks in 2usize..10, skew_ms in 0u64..50, drop in any::<prop::sample::Index>()) {
+        /* with and without one of the edges */
+        let mut events = pattern_log(pattern, blinks, skew_ms);
+        prop_assert_eq!(parse(&events, &options(50)), classify(&merge_events(&events, &options(50))));
+        events.remove(drop.index(events.len()));
+        prop_assert_eq!(parse(&events, &options(50)), classify(&merge_events(&events, &options(50))));
+    }
+
+    #[test]
+    fn hostile_input_does_not_panic(events in hostile_log(), merge_window in duration(), poll_period in duration()) {
+        let options = ParseOptions {merge_window, poll_period};
+        let merged = merge_events(&events, &options);
+        prop_assert_eq!(parse(&events, &options), classify(&merged));
+
+        let mut buffer = EventBuffer::new();
+        prop_assert_eq!(buffer.extend_from_slice(&events), events.len().min(MAX_EVENTS));
+        prop_assert_eq!(EventBuffer::from_slice(&events).is_some(), events.len() <= MAX_EVENTS);
+        prop_assert_eq!(buffer.push(LedEvent {led: Led::Red, on: true, timestamp: Duration::ZERO}), events.len() < MAX_EVENTS);
+    }
+
+    #[test]
+    fn patterns_are_classified(pattern in 0..PATTERNS.len(), blinks in 2usize..10, skew_ms in 0u64..50) {
+        let events = pattern_log(pattern, blinks, skew_ms);
+        prop_assert_eq!(parse(&events, &options(50)), Ok(PATTERNS[pattern].state));
+    }
+}
+
+#[test]
+fn offsets_keep_their_precision() {
+    /* a steady pattern whose LEDs change 0.4 ms apart and which lasts
+     * three minutes, starting at the epoch and minutes later */
+    let pattern = |start: Duration| -> Vec<LedEvent> {
+        let at = |offset: Duration| start + offset;
+        vec![
+            LedEvent {led: Led::Red, on: true, timestamp: at(Duration::ZERO)},
+            LedEvent {led: Led::Green, on: true, timestamp: at(Duration::from_micros(400))},
+            LedEvent {led: Led::Red, on: false, timestamp: at(Duration::from_secs(1))},
+            LedEvent {led: Led::Green, on: false, timestamp: at(Duration::from_secs(180) + Duration::from_micros(400))},
+        ]
+    };
+    let expected = vec![
+        MergedEvent {time: Duration::ZERO, levels: 0b11},
+        MergedEvent {time: Duration::from_secs(1), levels: 0b10},
+        MergedEvent {time: Duration::from_secs(180) + Duration::from_micros(400), levels: 0b00},
+    ];
+    for &start in &[Duration::ZERO, Duration::from_nanos(1), Duration::from_secs(600) + Duration::from_nanos(999_999)] {
+        let events = pattern(start);
+        assert_eq!(merge_events(&events, &options(50)), expected, "start {:?}", start);
+        assert_eq!(parse(&events, &options(50)), Ok(CFF3000State::Locked), "start {:?}", start);
+    }
+}
+
+/// Changes which leave the levels as they are have been kept until the
+/// parser stages were split, see CHANGELOG.md.
+#[test]
+fn repeated_levels_are_dropped() {
+    let event = |led, on, at| LedEvent {led, on, timestamp: ms(at)};
+    let change = |at, levels| MergedEvent {time: ms(at), levels};
+    let cases = [
+        /* both LEDs reported on twice: 11, 11, 00 */
+        (vec![event(Led::Red, true, 700), event(Led::Green, true, 700), event(Led::Red, true, 1700), event(Led::Green, true, 1700),
+              event(Led::Red, false, 4700), event(Led::Green, false, 4700)],
+         vec![change(0, 0b11), change(1000, 0b11), change(4000, 0b00)], Err(parser::ParseError::InvalidState), Err(parser::ParseError::NotEnoughEvents)),
+        /* locked, the green LED reported on again: 11, 10, 10, 00 */
+        (vec![event(Led::Red, true, 700), event(Led::Green, true, 700), event(Led::Red, false, 1700), event(Led::Green, true, 2700),
+              event(Led::Green, false, 4700)],
+         vec![change(0, 0b11), change(1000, 0b10), change(2000, 0b10), change(4000, 0b00)], Ok(CFF3000State::OutOfRange), Ok(CFF3000State::Locked)),
+    ];
+    for (events, before, old, new) in cases {
+        /* the changes the merge used to produce, and their result */
+        assert_eq!(classify(&before), old);
+        let merged = merge_events(&events, &options(50));
+        assert_eq!(merged, before.iter().enumerate().filter(|&(i, change)| i == 0 || before[i - 1].levels != change.levels).map(|(_, &change)| change).collect::<Vec<_>>());
+        assert_eq!(parse(&events, &options(50)), new);
+    }
+}
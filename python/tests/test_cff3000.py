@@ -0,0 +1,80 @@
+# © 2018 Sebastian Reichel
+# SPDX-License-Identifier: ISC
+
+import pytest
+
+import cff3000
+from cff3000 import State, Trigger
+
+
+def device(*states):
+    """CFF3000 on a mock showing `states` (None = nothing) one press after another."""
+    mock = cff3000.MockBackend()
+    for state in states:
+        if state is None:
+            mock.push_no_response()
+        else:
+            mock.push_state(state)
+    return cff3000.CFF3000.with_backend(mock), mock
+
+
+@pytest.mark.parametrize("state", [State.LOCKED, State.UNLOCKED, State.MANUAL, State.OUT_OF_RANGE])
+def test_state(state):
+    door, _ = device(state)
+    assert door.state() == state
+
+
+def test_presses():
+    door, mock = device()
+    door.lock()
+    door.unlock()
+    assert mock.transitions() == [("lock", True), ("lock", False), ("unlock", True), ("unlock", False)]
+
+
+def test_verify():
+    door, _ = device(State.LOCKED, State.MANUAL)
+    assert door.lock_and_verify() == State.LOCKED
+    assert door.unlock_and_verify() == State.MANUAL
+
+
+def test_no_response():
+    door, mock = device(None)
+    with pytest.raises(cff3000.NoResponseError):
+        door.state()
+    assert mock.elapsed() > 8
+
+
+def test_errors():
+    assert issubclass(cff3000.NoResponseError, cff3000.CFF3000Error)
+    assert issubclass(cff3000.BusyError, cff3000.CFF3000Error)
+    with pytest.raises(cff3000.DeviceNotFoundError):
+        cff3000.CFF3000("/dev/gpiochip-missing", (0, 1, 2, 3))
+
+
+def test_watch():
+    door, _ = device(State.LOCKED, State.LOCKED, State.UNLOCKED, State.MANUAL)
+    changes = door.watch(poll_interval=60.0)
+    seen = []
+    # the watch gives up after 5 queries without a pattern
+    with pytest.raises(cff3000.NoResponseError):
+        for change in changes:
+            seen.append((change.previous, change.current, change.trigger))
+    assert seen == [
+        (None, State.LOCKED, Trigger.POLL),
+        (State.LOCKED, State.UNLOCKED, Trigger.POLL),
+        (State.UNLOCKED, State.MANUAL, Trigger.POLL),
+    ]
+
+
+def test_watch_close():
+    door, _ = device(State.LOCKED)
+    changes = door.watch(poll_interval=60.0)
+    assert next(changes).current == State.LOCKED
+    changes.close()
+    assert list(changes) == []
+
+
+def test_watch_invalid_interval():
+    door, _ = device()
+    with pytest.raises(ValueError):
+        door.watch(poll_interval=-1.0)
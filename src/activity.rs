@@ -0,0 +1,144 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! LED patterns the device has not asked for, see
+//! `CFF3000::listen_for_activity()`.
+//!
+//! The CFF3000 shows its pattern after every press, including the ones
+//! on the key remote, so a lock or unlock at the door still leaves its
+//! trace on the LED lines. The listener reads the lines while no
+//! operation is running: a burst starts with the first LED change and
+//! lasts the command capture window of `Timings`, then its events are
+//! classified with the parser and reported as
+//! `Notice::ExternalActivity` to the `CFF3000Builder::monitor()`
+//! callback.
+//!
+//! The device's own captures are never reported. Every operation of the
+//! device counts for the interlock, and a burst overlapped by one is
+//! dropped. So are the LED changes during the press and command capture
+//! window after an operation, the rest of a pattern shown for a plain
+//! `lock()` or `unlock()` which does not read the LEDs.
+//!
+//! An idle listener waits for the first LED change without a timeout,
+//! or for the `ActivityOptions::keep_alive` if one is set, and its stop
+//! token ends the wait through the `GpioBackend::waker()` (see "Idle
+//! waits" of `backend`). On backends without a waker it wakes up every
+//! second to check the token.
+
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
+
+use crate::clock::deadline;
+use crate::{parser, CFF3000, Command, EventBuffer, LedEvent, Notice, StopToken};
+
+/// Longest wait for LED changes or for the end of a running operation
+/// before the stop token is checked again, unless the backend has a
+/// waker.
+const POLL: Duration = Duration::from_millis(1000);
+
+/// Wait for the first LED change with a waker and no keep-alive, long
+/// enough to never end on an idle device.
+const IDLE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Tunables of `CFF3000::listen_for_activity_with()`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct ActivityOptions {
+    /// Longest wait for LED changes, after which the listener checks
+    /// the LED lines again, e.g. for a chip which has been removed
+    /// (default: none, a lost chip is noticed with the next change)
+    pub keep_alive: Option<Duration>,
+}
+
+/// LED changes since the first one of a burst.
+struct Burst {
+    end: Instant,
+    events: Vec<LedEvent>,
+}
+
+impl CFF3000 {
+    /// Report LED patterns shown without an operation of this device as
+    /// `Notice::ExternalActivity`, see the module documentation, until
+    /// `stop` is stopped.
+    ///
+    /// Blocks while listening, so it is usually run on a thread of its
+    /// own next to the other users of the device. Fails with
+    /// `ErrorKind::InvalidInput` without a `CFF3000Builder::monitor()`,
+    /// or with the first error reading the LED lines.
+    pub fn listen_for_activity(&self, stop: &StopToken) -> std::io::Result<()> {
+        self.listen_for_activity_with(stop, &ActivityOptions::default())
+    }
+
+    /// Like `listen_for_activity()` with `options`.
+    pub fn listen_for_activity_with(&self, stop: &StopToken, options: &ActivityOptions) -> std::io::Result<()> {
+        let monitor = self.monitor.clone().ok_or_else(|| {
+            Error::new(ErrorKind::InvalidInput, "external activity is reported to the monitor, which is not set")
+        })?;
+        let window = self.timings.capture_for(Command::Lock);
+        let settle = self.timings.press_for(Command::Lock).saturating_add(window);
+        let mut operations = 0;
+        let mut quiet_until = None;
+        let mut burst: Option<Burst> = None;
+
+        /* LED changes pending on startup are from before */
+        if let Some(started) = self.interlock.if_idle(|started| self.flush_led_events().map(|_| started)) {
+            operations = started?;
+            if operations != 0 {
+                quiet_until = Some(self.clock.now() + settle);
+            }
+        }
+
+        while !stop.is_stopped() {
+            let waker = self.backend.waker();
+            if let Some(ref waker) = waker {
+                stop.wake_on_stop(waker);
+            }
+            let idle = match (waker, options.keep_alive) {
+                (Some(_), keep_alive) => keep_alive.unwrap_or(IDLE),
+                (None, keep_alive) => keep_alive.map_or(POLL, |keep_alive| std::cmp::min(keep_alive, POLL)),
+            };
+            let timeout = burst.as_ref().map_or(idle, |burst| std::cmp::min(burst.end.saturating_duration_since(self.clock.now()), idle));
+            self.wait_for_led_events(timeout)?;
+
+            let read = self.interlock.if_idle(|started| -> std::io::Result<_> {
+                let mut events = Vec::new();
+                let mut batch = EventBuffer::new();
+                while self.read_led_events(Duration::from_millis(0), &mut batch)? != 0 {
+                    events.extend_from_slice(&batch);
+                    batch.clear();
+                }
+                Ok((started, events))
+            });
+            let (started, events) = match read {
+                Some(read) => read?,
+                None => {
+                    /* the operation's capture takes the events */
+                    burst = None;
+                    stop.wait_timeout(POLL);
+                    continue;
+                },
+            };
+            let now = self.clock.now();
+            if started != operations {
+                operations = started;
+                quiet_until = Some(deadline(now, settle));
+                burst = None;
+            }
+            if quiet_until.is_some_and(|until| now < until) {
+                continue;
+            }
+
+            if burst.is_none() && !events.is_empty() {
+                burst = Some(Burst {end: deadline(now, window), events: Vec::new()});
+            }
+            if let Some(ref mut burst) = burst {
+                burst.events.extend(events);
+            }
+            if burst.as_ref().is_some_and(|burst| now >= burst.end) {
+                let events = burst.take().map(|burst| burst.events).unwrap_or_default();
+                let classified = parser::parse(&events, &self.parse_options).ok();
+                monitor(&Notice::ExternalActivity {classified, events});
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,302 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Audit log of the operations touching the door (`audit` feature).
+//!
+//! An [`AuditLog`] registered with `CFF3000Builder::audit()` gets an
+//! [`AuditRecord`] for every operation started with
+//! `CFF3000::execute_as()` or `CFF3000::press_as()`: when it started,
+//! the command, who asked for it, the resulting state or the error code
+//! and how long it took. The initiator is a free form string given by
+//! the caller; the entry points of this crate pass their own:
+//!
+//! | Entry point | Initiator |
+//! |-------------|-----------|
+//! | `cff3000` command line tool | `cli:uid=1000`, with `,sudo-user=<name>` under sudo |
+//! | `cff3000 rpc` | `rpc:uid=1000` |
+//! | `http` | `http:<client address>` |
+//! | `mqtt` | `mqtt:<host>:<port>/<command topic>` |
+//! | `dbus` | `dbus:<unique name of the caller>` |
+//! | `socket` | `unix:uid=<user of the client>` |
+//! | `grpc` | `grpc:<client address>` |
+//! | `python`, `ffi` | `python`, `ffi` |
+//! | auto-lock of the watch loop | `auto-lock` |
+//!
+//! Commands sent with `CommandSender::send()` have an empty initiator,
+//! those merged by a coalescing `CommandQueue` all of theirs, separated
+//! by `, `. The state queries of the watch loop are not recorded.
+//!
+//! # Targets
+//!
+//! With `AuditTarget::Journal`, the records go to the journal over its
+//! native protocol, to `DEFAULT_JOURNAL_SOCKET`, with the fields
+//!
+//! | Field | |
+//! |-------|-|
+//! | `MESSAGE` | e.g. "lock by cli:uid=1000: locked after 10.2 s" |
+//! | `PRIORITY` | 5 (notice), 4 (warning) if the operation failed |
+//! | `SYSLOG_IDENTIFIER` | `cff3000` |
+//! | `CFF3000_COMMAND` | `lock`, `unlock` or `check` |
+//! | `CFF3000_INITIATOR` | see above |
+//! | `CFF3000_STATE` | state name, unset for failures and plain presses |
+//! | `CFF3000_ERROR_CODE` | `error.code` of the `cli` feature, e.g. `no-response`, only for failures |
+//! | `CFF3000_DURATION_MS` | duration of the operation |
+//! | `CFF3000_TIMESTAMP_USEC` | start of the operation, microseconds since the Unix epoch |
+//!
+//! so e.g. `journalctl CFF3000_COMMAND=unlock` lists all unlocks. With
+//! `AuditTarget::Syslog`, they go to `DEFAULT_SYSLOG_SOCKET` as
+//! `authpriv` messages with the same values as `key=value` pairs:
+//!
+//! ```text
+//! <85>cff3000[312]: command=lock initiator="cli:uid=1000" state=locked duration_ms=10204 timestamp=2026-01-02T03:04:05.678Z
+//! ```
+//!
+//! # Failures
+//!
+//! Auditing never blocks or fails an operation: records are handed to
+//! a writer thread through a bounded queue. Records which do not fit
+//! into it or cannot be written, e.g. because nothing listens on the
+//! socket, are logged as warnings and counted by `AuditLog::lost()`.
+//! Dropping the log writes the queued records first.
+
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::clock::rfc3339;
+use crate::codes::ErrorCode;
+use crate::journal;
+use crate::{CFF3000State, Command};
+
+/// Socket of the native journal protocol.
+pub const DEFAULT_JOURNAL_SOCKET: &str = journal::SOCKET;
+/// Socket of the local syslog daemon.
+pub const DEFAULT_SYSLOG_SOCKET: &str = "/dev/log";
+
+/// Records waiting for the writer thread.
+const QUEUE_CAPACITY: usize = 64;
+/// Longest wait for a full socket, per record.
+const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+/// syslog facility authpriv (10) shifted for the priority value.
+const AUTHPRIV: u8 = 10 << 3;
+
+/// Where an `AuditLog` writes, see the module documentation.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AuditTarget {
+    /// systemd journal, native protocol with structured fields
+    Journal,
+    /// syslog daemon, `key=value` pairs in the message
+    Syslog,
+}
+
+impl AuditTarget {
+    /// Default socket of the target.
+    pub fn default_socket(self) -> &'static str {
+        match self {
+            AuditTarget::Journal => DEFAULT_JOURNAL_SOCKET,
+            AuditTarget::Syslog => DEFAULT_SYSLOG_SOCKET,
+        }
+    }
+}
+
+/// Error of a failed operation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditError {
+    /// `error.code` of the `cli` feature, e.g. "no-response"
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// One operation, see the module documentation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    /// Start of the operation
+    pub timestamp: SystemTime,
+    pub command: Command,
+    /// Who asked for it, empty if not given
+    pub initiator: String,
+    /// State read by the operation, `None` for plain presses
+    pub result: Result<Option<CFF3000State>, AuditError>,
+    pub duration: Duration,
+}
+
+fn command_name(command: Command) -> &'static str {
+    match command {
+        Command::Lock => "lock",
+        Command::Unlock => "unlock",
+        Command::Check => "check",
+    }
+}
+
+impl AuditRecord {
+    /// Record of `command` started at `timestamp` by `initiator` with
+    /// `result` after `duration`.
+    pub fn new(timestamp: SystemTime, command: Command, initiator: &str, result: Result<Option<CFF3000State>, &std::io::Error>,
+               duration: Duration) -> AuditRecord {
+        AuditRecord {
+            timestamp,
+            command,
+            initiator: initiator.to_string(),
+            result: result.map_err(|err| AuditError {code: ErrorCode::of(err).name(), message: err.to_string()}),
+            duration,
+        }
+    }
+
+    /// One line summary, e.g. "unlock by http:192.0.2.7:41234: failed
+    /// after 8.0 s: did not receive enough LED change events".
+    pub fn message(&self) -> String {
+        let initiator = if self.initiator.is_empty() {"unknown initiator"} else {&self.initiator};
+        let secs = self.duration.as_secs_f64();
+        match self.result {
+            Ok(Some(state)) => format!("{} by {}: {} after {:.1} s", command_name(self.command), initiator, state.name(), secs),
+            Ok(None) => format!("{} by {}: pressed for {:.1} s", command_name(self.command), initiator, secs),
+            Err(ref err) => format!("{} by {}: failed after {:.1} s: {}", command_name(self.command), initiator, secs, err.message),
+        }
+    }
+
+    /// Journal fields in sending order, see the module documentation.
+    pub fn journal_fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![
+            ("MESSAGE", self.message()),
+            ("PRIORITY", (if self.result.is_ok() {5} else {4}).to_string()),
+            ("SYSLOG_IDENTIFIER", "cff3000".to_string()),
+            ("CFF3000_COMMAND", command_name(self.command).to_string()),
+            ("CFF3000_INITIATOR", self.initiator.clone()),
+        ];
+        match self.result {
+            Ok(Some(state)) => fields.push(("CFF3000_STATE", state.name().to_string())),
+            Ok(None) => {},
+            Err(ref err) => fields.push(("CFF3000_ERROR_CODE", err.code.to_string())),
+        }
+        let since = self.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        fields.push(("CFF3000_DURATION_MS", self.duration.as_millis().to_string()));
+        fields.push(("CFF3000_TIMESTAMP_USEC", since.as_micros().to_string()));
+        fields
+    }
+
+    /// Datagram of the native journal protocol with `journal_fields()`.
+    pub fn to_journal(&self) -> Vec<u8> {
+        journal::encode(&self.journal_fields())
+    }
+
+    /// syslog message of process `pid` with the identifier `cff3000`,
+    /// see the module documentation.
+    pub fn to_syslog(&self, pid: u32) -> String {
+        let severity = if self.result.is_ok() {5} else {4};
+        let mut out = format!("<{}>cff3000[{}]: command={} initiator=", AUTHPRIV | severity, pid, command_name(self.command));
+        quote(&mut out, &self.initiator);
+        match self.result {
+            Ok(Some(state)) => out.push_str(&format!(" state={}", state.name())),
+            Ok(None) => {},
+            Err(ref err) => {
+                out.push_str(&format!(" code={} error=", err.code));
+                quote(&mut out, &err.message);
+            },
+        }
+        out.push_str(&format!(" duration_ms={} timestamp={}", self.duration.as_millis(), rfc3339(self.timestamp)));
+        out
+    }
+}
+
+/// Append `text` in double quotes, escaping quotes and backslashes and
+/// replacing control characters, which would break the line.
+fn quote(out: &mut String, text: &str) {
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            },
+            c if c.is_control() => out.push(' '),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Writer of `AuditRecord`s to the journal or syslog, see the module
+/// documentation.
+pub struct AuditLog {
+    target: AuditTarget,
+    records: Option<SyncSender<AuditRecord>>,
+    lost: Arc<AtomicU64>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl AuditLog {
+    /// Write to the default socket of `target`.
+    pub fn new(target: AuditTarget) -> std::io::Result<AuditLog> {
+        AuditLog::with_socket(target, target.default_socket())
+    }
+
+    /// Write to the datagram socket at `path` in the format of
+    /// `target`. Fails only if the socket or the writer thread cannot
+    /// be created, a missing listener only loses the records.
+    pub fn with_socket<P: AsRef<Path>>(target: AuditTarget, path: P) -> std::io::Result<AuditLog> {
+        let socket = UnixDatagram::unbound()?;
+        socket.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let path = path.as_ref().to_path_buf();
+        let (records, queue) = mpsc::sync_channel(QUEUE_CAPACITY);
+        let lost = Arc::new(AtomicU64::new(0));
+        let thread = {
+            let lost = lost.clone();
+            std::thread::Builder::new().name("cff3000-audit".to_string()).spawn(move || {
+                for record in queue {
+                    write(&socket, &path, target, &record, &lost);
+                }
+            })?
+        };
+        Ok(AuditLog {target, records: Some(records), lost, thread: Some(thread)})
+    }
+
+    /// Target the records are formatted for.
+    pub fn target(&self) -> AuditTarget {
+        self.target
+    }
+
+    /// Queue `record` for writing, without waiting.
+    pub fn record(&self, record: AuditRecord) {
+        let result = match self.records {
+            Some(ref records) => records.try_send(record),
+            None => return,
+        };
+        if let Err(err) = result {
+            self.lost.fetch_add(1, Ordering::Relaxed);
+            let record = match err {
+                TrySendError::Full(record) | TrySendError::Disconnected(record) => record,
+            };
+            log::warn!("audit log queue is full, dropping: {}", record.message());
+        }
+    }
+
+    /// Number of records dropped or not written so far.
+    pub fn lost(&self) -> u64 {
+        self.lost.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for AuditLog {
+    fn drop(&mut self) {
+        /* the thread writes the queued records before it ends */
+        self.records.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn write(socket: &UnixDatagram, path: &Path, target: AuditTarget, record: &AuditRecord, lost: &AtomicU64) {
+    let datagram = match target {
+        AuditTarget::Journal => record.to_journal(),
+        AuditTarget::Syslog => record.to_syslog(std::process::id()).into_bytes(),
+    };
+    if let Err(err) = socket.send_to(&datagram, path) {
+        lost.fetch_add(1, Ordering::Relaxed);
+        log::warn!("cannot write audit record to {}: {}: {}", path.display(), err, record.message());
+    }
+}
@@ -0,0 +1,133 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Dry-run mode, see `CFF3000Builder::dry_run()`.
+
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::task::Waker;
+use std::time::{Duration, Instant};
+
+use crate::clock::{until, SharedClock};
+use crate::notice::{Monitor, Notice};
+use crate::ParseOptions;
+use super::{Button, EventBuffer, GpioBackend, Led, LedEvent, LineInfo, LineRole, PinAssignment, Polarities, Polarity};
+
+fn role(button: Button) -> LineRole {
+    match button {
+        Button::Unlock => LineRole::ButtonUnlock,
+        Button::Lock => LineRole::ButtonLock,
+    }
+}
+
+fn index(button: Button) -> usize {
+    match button {
+        Button::Unlock => 0,
+        Button::Lock => 1,
+    }
+}
+
+/// Backend reporting button writes as `Notice::DryRun` instead of
+/// performing them. LED reads go to `inputs`, if those could be
+/// requested.
+///
+/// It sits below `InvertingBackend`, so it sees and reports the
+/// electrical levels.
+pub(crate) struct DryRunBackend {
+    /// LED lines, or why they are not available
+    inputs: Result<Arc<dyn GpioBackend>, (ErrorKind, String)>,
+    chipdev: Option<String>,
+    pins: Option<PinAssignment>,
+    polarities: Polarities,
+    monitor: Option<Monitor>,
+    clock: SharedClock,
+    /// Time the unlock and lock buttons would have been pressed at
+    pressed: Mutex<[Option<Instant>; 2]>,
+}
+
+impl DryRunBackend {
+    /// Read the LEDs through `inputs` and report the button writes to
+    /// `monitor`, naming `chipdev` and the offsets of `pins` if known.
+    pub(crate) fn new(inputs: std::io::Result<Arc<dyn GpioBackend>>, chipdev: Option<String>, pins: Option<PinAssignment>,
+                      polarities: Polarities, monitor: Option<Monitor>, clock: SharedClock) -> DryRunBackend {
+        DryRunBackend {
+            /* the error is reported by every read, io::Error is not Clone */
+            inputs: inputs.map_err(|err| (err.kind(), err.to_string())),
+            chipdev,
+            pins,
+            polarities,
+            monitor,
+            clock,
+            pressed: Mutex::new([None; 2]),
+        }
+    }
+
+    fn inputs(&self) -> std::io::Result<&dyn GpioBackend> {
+        match self.inputs {
+            Ok(ref inputs) => Ok(&**inputs),
+            Err((kind, ref message)) => Err(Error::new(kind, format!("dry run without LED lines: {}", message))),
+        }
+    }
+}
+
+impl GpioBackend for DryRunBackend {
+    fn set_button(&self, button: Button, level: bool) -> std::io::Result<()> {
+        let role = role(button);
+        let active = level != (self.polarities.get(role) == Polarity::ActiveLow);
+        let now = self.clock.now();
+        let held = {
+            let mut pressed = self.pressed.lock().unwrap_or_else(|e| e.into_inner());
+            let since = &mut pressed[index(button)];
+            match active {
+                true => {
+                    *since = Some(now);
+                    None
+                },
+                false => since.take().map(|since| until(since, now)),
+            }
+        };
+
+        if let Some(ref monitor) = self.monitor {
+            monitor(&Notice::DryRun {
+                chipdev: self.chipdev.clone(),
+                role,
+                offset: self.pins.map(|pins| pins.get(role)),
+                level,
+                held,
+            });
+        }
+        Ok(())
+    }
+
+    fn wait_for_led_events(&self, timeout: Duration) -> std::io::Result<u8> {
+        self.inputs()?.wait_for_led_events(timeout)
+    }
+
+    fn read_led_event(&self, led: Led) -> std::io::Result<LedEvent> {
+        self.inputs()?.read_led_event(led)
+    }
+
+    fn read_led_events(&self, led: Led, events: &mut EventBuffer) -> std::io::Result<usize> {
+        self.inputs()?.read_led_events(led, events)
+    }
+
+    fn flush_led_events(&self) -> std::io::Result<()> {
+        self.inputs()?.flush_led_events()
+    }
+
+    fn waker(&self) -> Option<Waker> {
+        self.inputs().ok().and_then(|inputs| inputs.waker())
+    }
+
+    fn lost_led_events(&self) -> u32 {
+        self.inputs().map(|inputs| inputs.lost_led_events()).unwrap_or(0)
+    }
+
+    fn line_info(&self) -> std::io::Result<[LineInfo; 4]> {
+        self.inputs()?.line_info()
+    }
+
+    fn parse_options(&self) -> ParseOptions {
+        self.inputs().map(|inputs| inputs.parse_options()).unwrap_or_default()
+    }
+}
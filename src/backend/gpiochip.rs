@@ -0,0 +1,93 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+use std::time::Duration;
+
+use crate::discover;
+use super::{buttons_not_requested, describe_lines, poll_timeout_ms, Button, GpioBackend, Led, LedEvent, LineInfo};
+
+/// Backend using the Linux GPIO character device (`/dev/gpiochipN`).
+pub struct GpiochipBackend {
+    red: gpio::GpioEventHandle,
+    green: gpio::GpioEventHandle,
+    /// None for `inputs()`
+    unlock: Option<gpio::GpioHandle>,
+    lock: Option<gpio::GpioHandle>,
+    chipdev: String,
+    gpios: [u32; 4],
+}
+
+impl GpiochipBackend {
+    /// Request the lines from `chipdev`.
+    ///
+    /// `gpios` contains the line offsets for LED red, LED green, button
+    /// unlock and button lock (in this order).
+    pub fn new(chipdev: &str, gpios: [u32; 4]) -> std::io::Result<GpiochipBackend> {
+        GpiochipBackend::request(chipdev, gpios, true)
+    }
+
+    /// Request only the LED lines, leaving the button lines untouched.
+    /// `set_button()` and `line_info()` fail with `ErrorKind::Unsupported`.
+    pub(crate) fn inputs(chipdev: &str, gpios: [u32; 4]) -> std::io::Result<GpiochipBackend> {
+        GpiochipBackend::request(chipdev, gpios, false)
+    }
+
+    fn request(chipdev: &str, gpios: [u32; 4], outputs: bool) -> std::io::Result<GpiochipBackend> {
+        let chip = gpio::GpioChip::new(chipdev)?;
+        let led_red = chip.request_event("led-red", gpios[0], gpio::RequestFlags::INPUT, gpio::EventRequestFlags::BOTH_EDGES)?;
+        let led_green = chip.request_event("led-green", gpios[1], gpio::RequestFlags::INPUT, gpio::EventRequestFlags::BOTH_EDGES)?;
+        let (button_unlock, button_lock) = match outputs {
+            true => (Some(chip.request("button-unlock", gpio::RequestFlags::OUTPUT, gpios[2], 0)?),
+                Some(chip.request("button-lock", gpio::RequestFlags::OUTPUT, gpios[3], 0)?)),
+            false => (None, None),
+        };
+        Ok(GpiochipBackend {
+            red: led_red,
+            green: led_green,
+            unlock: button_unlock,
+            lock: button_lock,
+            chipdev: chipdev.to_string(),
+            gpios,
+        })
+    }
+
+    fn button(&self, button: Button) -> std::io::Result<&gpio::GpioHandle> {
+        let line = match button {
+            Button::Unlock => self.unlock.as_ref(),
+            Button::Lock => self.lock.as_ref(),
+        };
+        line.ok_or_else(buttons_not_requested)
+    }
+}
+
+impl GpioBackend for GpiochipBackend {
+    fn set_button(&self, button: Button, pressed: bool) -> std::io::Result<()> {
+        self.button(button)?.set(if pressed {1} else {0})
+    }
+
+    fn wait_for_led_events(&self, timeout: Duration) -> std::io::Result<u8> {
+        let events = gpio::wait_for_event(&[&self.red, &self.green], poll_timeout_ms(timeout))?;
+        Ok((events & 0b11) as u8)
+    }
+
+    fn read_led_event(&self, led: Led) -> std::io::Result<LedEvent> {
+        let line = match led {
+            Led::Red => &self.red,
+            Led::Green => &self.green,
+        };
+        let event = line.read()?;
+        Ok(LedEvent {led, on: event.id == gpio::EventId::RISING_EDGE, timestamp: Duration::from_nanos(event.timestamp)})
+    }
+
+    fn flush_led_events(&self) -> std::io::Result<()> {
+        self.green.flush()?;
+        self.red.flush()?;
+        Ok(())
+    }
+
+    fn line_info(&self) -> std::io::Result<[LineInfo; 4]> {
+        let chip = discover::chip(&self.chipdev)?;
+        let levels = [self.red.get()?, self.green.get()?, self.button(Button::Unlock)?.get()?, self.button(Button::Lock)?.get()?];
+        describe_lines(&chip, self.gpios, levels)
+    }
+}
@@ -0,0 +1,98 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Active-low lines, see `CFF3000Builder::polarities()`.
+
+use std::sync::Arc;
+use std::task::Waker;
+use std::time::Duration;
+
+use crate::ParseOptions;
+use super::{Button, EventBuffer, GpioBackend, Led, LedEvent, LineInfo, LineRole, Polarities, Polarity};
+
+fn role(button: Button) -> LineRole {
+    match button {
+        Button::Unlock => LineRole::ButtonUnlock,
+        Button::Lock => LineRole::ButtonLock,
+    }
+}
+
+/// Backend translating between the logical levels seen by `CFF3000`
+/// (true = pressed / lit) and the electrical levels of `inner`.
+pub(crate) struct InvertingBackend {
+    inner: Arc<dyn GpioBackend>,
+    polarities: Polarities,
+}
+
+impl InvertingBackend {
+    /// Wrap `inner` and release the active-low buttons, which the
+    /// backends request as low (i.e. pressed) outputs.
+    pub(crate) fn new(inner: Arc<dyn GpioBackend>, polarities: Polarities) -> std::io::Result<InvertingBackend> {
+        let backend = InvertingBackend {inner, polarities};
+        for &button in &[Button::Unlock, Button::Lock] {
+            if backend.inverted(role(button)) {
+                backend.set_button(button, false)?;
+            }
+        }
+        Ok(backend)
+    }
+
+    fn inverted(&self, role: LineRole) -> bool {
+        self.polarities.get(role) == Polarity::ActiveLow
+    }
+}
+
+impl GpioBackend for InvertingBackend {
+    fn set_button(&self, button: Button, pressed: bool) -> std::io::Result<()> {
+        self.inner.set_button(button, pressed != self.inverted(role(button)))
+    }
+
+    fn wait_for_led_events(&self, timeout: Duration) -> std::io::Result<u8> {
+        self.inner.wait_for_led_events(timeout)
+    }
+
+    fn read_led_event(&self, led: Led) -> std::io::Result<LedEvent> {
+        let role = match led {
+            Led::Red => LineRole::LedRed,
+            Led::Green => LineRole::LedGreen,
+        };
+        let event = self.inner.read_led_event(led)?;
+        Ok(LedEvent {on: event.on != self.inverted(role), ..event})
+    }
+
+    fn read_led_events(&self, led: Led, events: &mut EventBuffer) -> std::io::Result<usize> {
+        let role = match led {
+            Led::Red => LineRole::LedRed,
+            Led::Green => LineRole::LedGreen,
+        };
+        let start = events.len();
+        let count = self.inner.read_led_events(led, events)?;
+        if self.inverted(role) {
+            for event in &mut events[start..] {
+                event.on = !event.on;
+            }
+        }
+        Ok(count)
+    }
+
+    fn flush_led_events(&self) -> std::io::Result<()> {
+        self.inner.flush_led_events()
+    }
+
+    fn waker(&self) -> Option<Waker> {
+        self.inner.waker()
+    }
+
+    fn lost_led_events(&self) -> u32 {
+        self.inner.lost_led_events()
+    }
+
+    /// Levels stay electrical, as reported by the kernel.
+    fn line_info(&self) -> std::io::Result<[LineInfo; 4]> {
+        self.inner.line_info()
+    }
+
+    fn parse_options(&self) -> ParseOptions {
+        self.inner.parse_options()
+    }
+}
@@ -0,0 +1,89 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Records of the GPIO operations for the `log` facade.
+//!
+//! Every button level set is a `debug` record with the line, the
+//! electrical level and the time the backend took. The LED events are
+//! logged per capture, bounded, see the `eventdump` module. The records
+//! are left to the logger of the application, the library itself never
+//! writes to stdout or stderr.
+
+use std::sync::Arc;
+use std::task::Waker;
+use std::time::{Duration, Instant};
+
+use crate::notice::role_name;
+use crate::ParseOptions;
+use super::{Button, EventBuffer, GpioBackend, Led, LedEvent, LineInfo, LineRole, PinAssignment};
+
+/// Backend logging the operations on `inner`.
+pub(crate) struct LoggingBackend {
+    inner: Arc<dyn GpioBackend>,
+    /// Line offsets on the chip, `None` for custom backends
+    pins: Option<PinAssignment>,
+}
+
+impl LoggingBackend {
+    pub(crate) fn new(inner: Arc<dyn GpioBackend>, pins: Option<PinAssignment>) -> LoggingBackend {
+        LoggingBackend {inner, pins}
+    }
+
+    /// The line of `role`, e.g. "button lock (line 5)".
+    fn line(&self, role: LineRole) -> String {
+        match self.pins {
+            Some(pins) => format!("{} (line {})", role_name(role), pins.get(role)),
+            None => role_name(role).to_string(),
+        }
+    }
+}
+
+impl GpioBackend for LoggingBackend {
+    fn set_button(&self, button: Button, pressed: bool) -> std::io::Result<()> {
+        let role = match button {
+            Button::Unlock => LineRole::ButtonUnlock,
+            Button::Lock => LineRole::ButtonLock,
+        };
+        let start = Instant::now();
+        let result = self.inner.set_button(button, pressed);
+        match result {
+            Ok(()) => log::debug!("set {} to {} in {:?}", self.line(role), pressed as u8, start.elapsed()),
+            Err(ref err) => log::debug!("setting {} to {} failed after {:?}: {}", self.line(role), pressed as u8, start.elapsed(), err),
+        }
+        result
+    }
+
+    fn wait_for_led_events(&self, timeout: Duration) -> std::io::Result<u8> {
+        self.inner.wait_for_led_events(timeout)
+    }
+
+    fn read_led_event(&self, led: Led) -> std::io::Result<LedEvent> {
+        self.inner.read_led_event(led)
+    }
+
+    fn read_led_events(&self, led: Led, events: &mut EventBuffer) -> std::io::Result<usize> {
+        self.inner.read_led_events(led, events)
+    }
+
+    fn flush_led_events(&self) -> std::io::Result<()> {
+        let result = self.inner.flush_led_events();
+        log::trace!("flushed the pending LED events");
+        result
+    }
+
+    fn waker(&self) -> Option<Waker> {
+        self.inner.waker()
+    }
+
+    fn lost_led_events(&self) -> u32 {
+        self.inner.lost_led_events()
+    }
+
+    fn line_info(&self) -> std::io::Result<[LineInfo; 4]> {
+        self.inner.line_info()
+    }
+
+    fn parse_options(&self) -> ParseOptions {
+        self.inner.parse_options()
+    }
+}
@@ -0,0 +1,322 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Hardware abstraction.
+//!
+//! `CFF3000` only needs four signals: two inputs sensing the LEDs and
+//! two outputs driving the buttons. A [`GpioBackend`] provides them,
+//! with all lines already requested/configured by the backend's own
+//! constructor. [`GpiochipBackend`] is the default implementation using
+//! the Linux GPIO character device. With the `uapi-v2` feature,
+//! [`Uapi2Backend`] is used instead where the kernel supports it. On
+//! other operating systems `GpiochipBackend::new()` (and therefore
+//! `CFF3000::new()`) fails with `ErrorKind::Unsupported`, while custom
+//! backends such as the mock keep working.
+//!
+//! # Batched reads
+//!
+//! A capture waits for LED events and then reads every pending event
+//! of a LED with one `GpioBackend::read_led_events()` before it waits
+//! again, so a capture which fell behind catches up with one wait and
+//! one read per LED instead of a wait and a read per event, which
+//! keeps a loaded system ahead of the kernel event buffer during the
+//! fast blinking of the `OutOfRange` pattern. A read takes no more
+//! events than fit into the `EventBuffer` of the capture, the others
+//! stay queued. `Uapi2Backend` takes the whole kernel buffer (64
+//! events) with one read(2) and hands out the events of a LED from its
+//! queue, the mock backends return all queued events. `GpiochipBackend`
+//! still reads single events, the `gpiochip` crate reads one
+//! `gpioevent_data` per read(2). `tests/batch.rs` counts the calls: the
+//! 14 events of an out-of-range pattern read late take 2 waits and 2
+//! reads, where reading them one at a time takes 9 waits and 14 reads.
+//!
+//! # Idle waits
+//!
+//! Captures wait for LED events until the end of their window, a
+//! listener like `CFF3000::listen_for_activity()` waits without a
+//! deadline. To stop such a listener promptly, `GpioBackend::waker()`
+//! ends a running wait: `Uapi2Backend` polls an eventfd next to the LED
+//! lines and the mock backend wakes its condition variable. The
+//! listener hands the waker to its `StopToken`, so an idle listener
+//! wakes up for LED changes and its stop only. `Uapi2Backend` also
+//! wakes a wait holding its event queues when another thread needs
+//! them, e.g. for `CFF3000::state()` next to the listener. Backends
+//! without a waker, like `GpiochipBackend` whose `gpiochip` crate polls
+//! the lines itself, are waited for in slices of a second.
+
+use std::sync::Arc;
+use std::task::Waker;
+use std::time::Duration;
+
+#[cfg(feature = "config")]
+use serde::{Deserialize, Serialize};
+
+use crate::discover::LineFlags;
+use crate::ParseOptions;
+
+pub use crate::parser::{EventBuffer, Led, LedEvent, LED_GREEN, LED_RED, MAX_EVENTS};
+
+#[cfg(target_os = "linux")]
+mod gpiochip;
+#[cfg(not(target_os = "linux"))]
+#[path = "unsupported.rs"]
+mod gpiochip;
+mod dryrun;
+mod invert;
+mod logging;
+mod reopen;
+#[cfg(all(feature = "uapi-v2", target_os = "linux"))]
+mod uapi2;
+
+pub use self::gpiochip::GpiochipBackend;
+pub(crate) use self::dryrun::DryRunBackend;
+pub(crate) use self::invert::InvertingBackend;
+pub(crate) use self::logging::LoggingBackend;
+pub(crate) use self::reopen::{Opener, ReopeningBackend};
+#[cfg(all(feature = "uapi-v2", target_os = "linux"))]
+pub use self::uapi2::Uapi2Backend;
+
+/// Button of the CFF3000.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Button {
+    Unlock,
+    Lock,
+}
+
+/// Function of a line in the CFF3000 wiring.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum LineRole {
+    LedRed,
+    LedGreen,
+    ButtonUnlock,
+    ButtonLock,
+}
+
+impl LineRole {
+    /// All roles, in the order of the `gpios` array of `CFF3000::new()`.
+    pub const ALL: [LineRole; 4] = [LineRole::LedRed, LineRole::LedGreen, LineRole::ButtonUnlock, LineRole::ButtonLock];
+}
+
+/// Line offsets of the CFF3000 wiring on a GPIO chip, see
+/// `CFF3000::new_with_pins()`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "config", derive(Serialize, Deserialize), serde(deny_unknown_fields))]
+pub struct PinAssignment {
+    pub led_red: u32,
+    pub led_green: u32,
+    pub button_unlock: u32,
+    pub button_lock: u32,
+}
+
+impl PinAssignment {
+    /// Offsets in `LineRole::ALL` order, as taken by `CFF3000::new()`.
+    pub fn to_array(&self) -> [u32; 4] {
+        [self.led_red, self.led_green, self.button_unlock, self.button_lock]
+    }
+
+    /// Offset of the line with `role`.
+    pub fn get(&self, role: LineRole) -> u32 {
+        match role {
+            LineRole::LedRed => self.led_red,
+            LineRole::LedGreen => self.led_green,
+            LineRole::ButtonUnlock => self.button_unlock,
+            LineRole::ButtonLock => self.button_lock,
+        }
+    }
+}
+
+impl From<[u32; 4]> for PinAssignment {
+    fn from(gpios: [u32; 4]) -> PinAssignment {
+        PinAssignment {led_red: gpios[0], led_green: gpios[1], button_unlock: gpios[2], button_lock: gpios[3]}
+    }
+}
+
+/// Parses four comma separated offsets in `LineRole::ALL` order (red,
+/// green, unlock, lock), e.g. "2,3,4,5". Fails with
+/// `ErrorKind::InvalidInput`.
+impl std::str::FromStr for PinAssignment {
+    type Err = std::io::Error;
+
+    fn from_str(text: &str) -> std::io::Result<PinAssignment> {
+        let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidInput,
+            "expected four comma separated line offsets: red, green, unlock, lock");
+        let mut gpios = [0u32; 4];
+        let mut count = 0;
+        for item in text.split(',') {
+            if count == 4 {
+                return Err(invalid());
+            }
+            gpios[count] = item.trim().parse().map_err(|_| invalid())?;
+            count += 1;
+        }
+        if count != 4 {
+            return Err(invalid());
+        }
+        Ok(PinAssignment::from(gpios))
+    }
+}
+
+/// Electrical level of an active (lit or pressed) line.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "config", derive(Serialize, Deserialize), serde(rename_all = "kebab-case"))]
+pub enum Polarity {
+    #[default]
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// Polarity of each of the four lines, set with
+/// `CFF3000Builder::polarities()`, e.g. for LED sensing through an
+/// inverting optocoupler.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "config", derive(Serialize, Deserialize), serde(default, deny_unknown_fields))]
+pub struct Polarities {
+    pub led_red: Polarity,
+    pub led_green: Polarity,
+    pub button_unlock: Polarity,
+    pub button_lock: Polarity,
+}
+
+impl Polarities {
+    /// Polarity of the line with `role`.
+    pub fn get(&self, role: LineRole) -> Polarity {
+        match role {
+            LineRole::LedRed => self.led_red,
+            LineRole::LedGreen => self.led_green,
+            LineRole::ButtonUnlock => self.button_unlock,
+            LineRole::ButtonLock => self.button_lock,
+        }
+    }
+}
+
+/// Live state of one of the four lines, see `CFF3000::line_info()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineInfo {
+    pub role: LineRole,
+    /// Line offset on the GPIO chip
+    pub offset: u32,
+    /// Kernel line name, if any
+    pub name: Option<String>,
+    /// Line configuration as reported by the kernel
+    pub flags: LineFlags,
+    /// Current level (true = high)
+    pub level: bool,
+}
+
+/// Access to the CFF3000 signals.
+///
+/// Implementations must be shareable between threads, because button
+/// releases may be performed by the shared timer thread.
+pub trait GpioBackend: Send + Sync {
+    /// Drive `button` (true = pressed).
+    fn set_button(&self, button: Button, pressed: bool) -> std::io::Result<()>;
+
+    /// Wait up to `timeout` for LED events and return a mask of LEDs
+    /// (`LED_RED`, `LED_GREEN`) with pending events, or 0 on timeout.
+    /// The events are not consumed. A wake of the `waker()` ends the
+    /// wait early, like a timeout.
+    fn wait_for_led_events(&self, timeout: Duration) -> std::io::Result<u8>;
+
+    /// Read the next pending event of `led`. Only called after
+    /// `wait_for_led_events()` reported one.
+    fn read_led_event(&self, led: Led) -> std::io::Result<LedEvent>;
+
+    /// Append the pending events of `led` to `events`, at least one
+    /// and no more than fit, and return their number, see "Batched
+    /// reads" in the module documentation. Only called after
+    /// `wait_for_led_events()` reported one and with room in `events`.
+    /// The default reads one event with `read_led_event()`, backends
+    /// which can read several at once override it.
+    fn read_led_events(&self, led: Led, events: &mut EventBuffer) -> std::io::Result<usize> {
+        let event = self.read_led_event(led)?;
+        Ok(events.push(event) as usize)
+    }
+
+    /// Discard all pending LED events.
+    fn flush_led_events(&self) -> std::io::Result<()>;
+
+    /// Waker ending a running `wait_for_led_events()`, see "Idle
+    /// waits" in the module documentation. Backends returning `None`
+    /// (the default) cannot be interrupted, listeners then wait in
+    /// slices of a second.
+    fn waker(&self) -> Option<Waker> {
+        None
+    }
+
+    /// Number of LED events known to have been dropped (e.g. on kernel
+    /// buffer overflow) since the last `flush_led_events()`. Backends
+    /// without loss detection return 0.
+    fn lost_led_events(&self) -> u32 {
+        0
+    }
+
+    /// Describe the four lines, in `LineRole::ALL` order, with their
+    /// current levels. Backends without line introspection fail with
+    /// `ErrorKind::Unsupported`.
+    fn line_info(&self) -> std::io::Result<[LineInfo; 4]> {
+        Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "backend does not provide line information"))
+    }
+
+    /// Parser settings matching the timestamp accuracy of this backend,
+    /// used unless overridden with `CFF3000Builder::parse_options()`.
+    fn parse_options(&self) -> ParseOptions {
+        ParseOptions::default()
+    }
+}
+
+/// Convert `timeout` to poll(2) milliseconds, rounding up.
+#[cfg(target_os = "linux")]
+pub(crate) fn poll_timeout_ms(timeout: Duration) -> i32 {
+    let ms = timeout.as_secs().saturating_mul(1000) + timeout.subsec_nanos().div_ceil(1_000_000) as u64;
+    std::cmp::min(ms, i32::MAX as u64) as i32
+}
+
+/// Combine the kernel line information of `gpios` with their `levels`.
+#[cfg(target_os = "linux")]
+pub(crate) fn describe_lines(chip: &crate::discover::ChipInfo, gpios: [u32; 4], levels: [u8; 4]) -> std::io::Result<[LineInfo; 4]> {
+    let line = |i: usize| -> std::io::Result<LineInfo> {
+        let line = chip.line(gpios[i])?;
+        Ok(LineInfo {role: LineRole::ALL[i], offset: gpios[i], name: line.name, flags: line.flags, level: levels[i] != 0})
+    };
+    Ok([line(0)?, line(1)?, line(2)?, line(3)?])
+}
+
+/// Error of backends which have only requested the LED lines.
+#[cfg(target_os = "linux")]
+fn buttons_not_requested() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Unsupported, "the button lines have not been requested")
+}
+
+/// Open the best available character device backend for `chipdev`.
+#[cfg(all(feature = "uapi-v2", target_os = "linux"))]
+pub(crate) fn open_chip(chipdev: &str, gpios: [u32; 4]) -> std::io::Result<Arc<dyn GpioBackend>> {
+    match Uapi2Backend::new(chipdev, gpios) {
+        Ok(backend) => Ok(Arc::new(backend)),
+        Err(ref err) if uapi2::is_unsupported(err) => Ok(Arc::new(GpiochipBackend::new(chipdev, gpios)?)),
+        Err(err) => Err(err),
+    }
+}
+
+/// Open the best available character device backend for `chipdev`.
+#[cfg(not(all(feature = "uapi-v2", target_os = "linux")))]
+pub(crate) fn open_chip(chipdev: &str, gpios: [u32; 4]) -> std::io::Result<Arc<dyn GpioBackend>> {
+    Ok(Arc::new(GpiochipBackend::new(chipdev, gpios)?))
+}
+
+/// Like `open_chip()`, but request only the LED lines, see
+/// `GpiochipBackend::inputs()`.
+#[cfg(all(feature = "uapi-v2", target_os = "linux"))]
+pub(crate) fn open_inputs(chipdev: &str, gpios: [u32; 4]) -> std::io::Result<Arc<dyn GpioBackend>> {
+    match Uapi2Backend::inputs(chipdev, gpios) {
+        Ok(backend) => Ok(Arc::new(backend)),
+        Err(ref err) if uapi2::is_unsupported(err) => Ok(Arc::new(GpiochipBackend::inputs(chipdev, gpios)?)),
+        Err(err) => Err(err),
+    }
+}
+
+/// Like `open_chip()`, but request only the LED lines, see
+/// `GpiochipBackend::inputs()`.
+#[cfg(not(all(feature = "uapi-v2", target_os = "linux")))]
+pub(crate) fn open_inputs(chipdev: &str, gpios: [u32; 4]) -> std::io::Result<Arc<dyn GpioBackend>> {
+    Ok(Arc::new(GpiochipBackend::inputs(chipdev, gpios)?))
+}
@@ -0,0 +1,252 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Automatic reopening of a GPIO chip which went away, see
+//! `CFF3000Builder::auto_reopen()`.
+
+use std::io::Error;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::Waker;
+use std::time::Duration;
+
+use crate::clock::{deadline, SharedClock};
+use crate::discover;
+use crate::notice::{Monitor, Notice};
+use crate::{Clock, ParseOptions};
+use super::{open_chip, Button, EventBuffer, GpioBackend, Led, LedEvent, LineInfo};
+
+/// Interval for checking whether the device node is back.
+const REOPEN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Custom backend factory of `CFF3000Builder::with_opener()`.
+pub(crate) type Opener = Arc<dyn Fn() -> std::io::Result<Arc<dyn GpioBackend>> + Send + Sync>;
+
+/// Opens the handles again, returning them with the device they belong
+/// to.
+type Reopen = Box<dyn Fn() -> std::io::Result<(Arc<dyn GpioBackend>, String)> + Send + Sync>;
+
+struct Current {
+    /// None while the device is gone
+    backend: Option<Arc<dyn GpioBackend>>,
+    /// Incremented on every reopen, so concurrent failures of the same
+    /// handles trigger a single reopen
+    generation: u64,
+}
+
+/// Chip backend which requests its lines again after the device
+/// disappeared (e.g. a re-enumerated USB GPIO adapter).
+pub(crate) struct ReopeningBackend {
+    open: Reopen,
+    timeout: Duration,
+    parse_options: ParseOptions,
+    monitor: Option<Monitor>,
+    clock: SharedClock,
+    current: Mutex<Current>,
+}
+
+/// Returns true if `err` means that the device is gone.
+fn is_device_lost(err: &Error) -> bool {
+    err.raw_os_error() == Some(libc::ENODEV) || err.raw_os_error() == Some(libc::EIO)
+}
+
+impl ReopeningBackend {
+    /// Open `chipdev`, waiting up to `timeout` (on `clock`) for it to
+    /// come back whenever it is lost later.
+    pub(crate) fn new(chipdev: &str, gpios: [u32; 4], timeout: Duration, monitor: Option<Monitor>, clock: SharedClock) -> std::io::Result<ReopeningBackend> {
+        let backend = open_chip(chipdev, gpios)?;
+        /* chip label for finding the device under a different name */
+        let label = discover::chip(chipdev).ok().map(|chip| chip.label);
+        let chipdev = chipdev.to_string();
+        let open = move || {
+            let found = if Path::new(&chipdev).exists() {
+                Some(chipdev.clone())
+            } else {
+                label.as_ref().and_then(|label| {
+                    discover::chips().into_iter().find(|chip| chip.label == *label).map(|chip| chip.path)
+                })
+            };
+            match found {
+                Some(found) => Ok((open_chip(&found, gpios)?, found)),
+                None => Err(Error::new(std::io::ErrorKind::NotFound, format!("{} did not reappear", chipdev))),
+            }
+        };
+        Ok(ReopeningBackend::with_handles(backend, Box::new(open), timeout, monitor, clock))
+    }
+
+    /// Open a custom backend with `open`, calling it again whenever the
+    /// device is lost. Reconnects are reported for `name`.
+    pub(crate) fn with_opener(name: &str, open: Opener, timeout: Duration, monitor: Option<Monitor>, clock: SharedClock) -> std::io::Result<ReopeningBackend> {
+        let backend = open()?;
+        let name = name.to_string();
+        let open = move || Ok((open()?, name.clone()));
+        Ok(ReopeningBackend::with_handles(backend, Box::new(open), timeout, monitor, clock))
+    }
+
+    fn with_handles(backend: Arc<dyn GpioBackend>, open: Reopen, timeout: Duration, monitor: Option<Monitor>, clock: SharedClock) -> ReopeningBackend {
+        ReopeningBackend {
+            open,
+            timeout,
+            parse_options: backend.parse_options(),
+            monitor,
+            clock,
+            current: Mutex::new(Current {backend: Some(backend), generation: 0}),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Current> {
+        self.current.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Current handles and their generation, reopening them if a
+    /// previous reopen failed.
+    fn get(&self) -> std::io::Result<(Arc<dyn GpioBackend>, u64)> {
+        let current = self.lock();
+        match current.backend {
+            Some(ref backend) => Ok((backend.clone(), current.generation)),
+            None => {
+                let generation = current.generation;
+                drop(current);
+                self.reopen(generation)
+            },
+        }
+    }
+
+    /// Close the handles of `generation` and wait for the device to
+    /// reappear. Returns the new handles, or `err` if the device did not
+    /// come back within the timeout.
+    fn recover(&self, generation: u64, err: Error) -> std::io::Result<Arc<dyn GpioBackend>> {
+        let lost = {
+            let mut current = self.lock();
+            let lost = current.generation == generation;
+            if lost {
+                current.backend = None;
+            }
+            lost
+        };
+        match self.reopen(generation) {
+            Ok((backend, _)) => Ok(backend),
+            Err(_) if lost => Err(err),
+            Err(reopen) => Err(reopen),
+        }
+    }
+
+    /// Release the lines and request them again, e.g. after a failed
+    /// health check. Fails if the device does not come back within the
+    /// timeout.
+    pub(crate) fn reopen_now(&self) -> std::io::Result<()> {
+        let generation = {
+            let mut current = self.lock();
+            current.backend = None;
+            current.generation
+        };
+        self.reopen(generation).map(|_| ())
+    }
+
+    /// Open the handles lost in `generation` again, waiting for the
+    /// device to reappear. The lock is released while sleeping, so
+    /// handles a concurrent caller opened meanwhile are taken instead.
+    fn reopen(&self, generation: u64) -> std::io::Result<(Arc<dyn GpioBackend>, u64)> {
+        let deadline = deadline(self.clock.now(), self.timeout);
+        loop {
+            {
+                let mut current = self.lock();
+                if current.generation != generation {
+                    if let Some(ref backend) = current.backend {
+                        return Ok((backend.clone(), current.generation));
+                    }
+                }
+                match (self.open)() {
+                    Ok((backend, chipdev)) => {
+                        current.backend = Some(backend.clone());
+                        current.generation += 1;
+                        if let Some(ref monitor) = self.monitor {
+                            monitor(&Notice::Reconnected {chipdev});
+                        }
+                        return Ok((backend, current.generation));
+                    },
+                    /* the node may show up before udev applied permissions */
+                    Err(err) => if self.clock.now() >= deadline {
+                        return Err(err);
+                    },
+                }
+            }
+            self.clock.sleep(REOPEN_POLL_INTERVAL);
+        }
+    }
+
+    /// Run `op`, retrying it once on reopened handles if the device
+    /// has been lost.
+    fn retry<T, F: Fn(&dyn GpioBackend) -> std::io::Result<T>>(&self, op: F) -> std::io::Result<T> {
+        let (backend, generation) = self.get()?;
+        let err = match op(&*backend) {
+            Err(err) if is_device_lost(&err) => err,
+            result => return result,
+        };
+        drop(backend);
+        let backend = self.recover(generation, err)?;
+        op(&*backend)
+    }
+
+    /// Run `read`, failing with `ErrorKind::Interrupted` after a reopen.
+    fn read_pending<T, F>(&self, read: F) -> std::io::Result<T>
+        where F: FnOnce(&dyn GpioBackend) -> std::io::Result<T>
+    {
+        let (backend, generation) = self.get()?;
+        match read(&*backend) {
+            Err(err) if is_device_lost(&err) => {
+                drop(backend);
+                self.recover(generation, err)?;
+                Err(Error::new(std::io::ErrorKind::Interrupted, "GPIO chip reconnected, pending LED events lost"))
+            },
+            result => result,
+        }
+    }
+}
+
+impl GpioBackend for ReopeningBackend {
+    fn set_button(&self, button: Button, pressed: bool) -> std::io::Result<()> {
+        self.retry(|backend| backend.set_button(button, pressed))
+    }
+
+    fn wait_for_led_events(&self, timeout: Duration) -> std::io::Result<u8> {
+        self.retry(|backend| backend.wait_for_led_events(timeout))
+    }
+
+    /// Events pending before the device was lost are gone, so after a
+    /// reopen this fails with `ErrorKind::Interrupted` instead of being
+    /// retried.
+    fn read_led_event(&self, led: Led) -> std::io::Result<LedEvent> {
+        self.read_pending(|backend| backend.read_led_event(led))
+    }
+
+    /// Like `read_led_event()`.
+    fn read_led_events(&self, led: Led, events: &mut EventBuffer) -> std::io::Result<usize> {
+        self.read_pending(|backend| backend.read_led_events(led, events))
+    }
+
+    fn flush_led_events(&self) -> std::io::Result<()> {
+        self.retry(|backend| backend.flush_led_events())
+    }
+
+    /// The waker of the current handles. Listeners ask for it before
+    /// every wait, so they get the one of handles reopened meanwhile.
+    fn waker(&self) -> Option<Waker> {
+        self.lock().backend.as_ref().and_then(|backend| backend.waker())
+    }
+
+    fn lost_led_events(&self) -> u32 {
+        match self.lock().backend {
+            Some(ref backend) => backend.lost_led_events(),
+            None => 0,
+        }
+    }
+
+    fn line_info(&self) -> std::io::Result<[LineInfo; 4]> {
+        self.retry(|backend| backend.line_info())
+    }
+
+    fn parse_options(&self) -> ParseOptions {
+        self.parse_options
+    }
+}
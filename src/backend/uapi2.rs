@@ -0,0 +1,395 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! GPIO character device backend using the v2 uAPI (Linux 5.10+).
+//!
+//! Compared to the v1 uAPI used by `GpiochipBackend`, v2 line events
+//! carry per-line sequence numbers. Gaps in the sequence mean that the
+//! kernel event buffer overflowed and events have been dropped, which
+//! is reported through `GpioBackend::lost_led_events()` and ends up in
+//! the `StateReport` diagnostics. The ioctl encoding used here is the
+//! generic one (not MIPS, PowerPC or SPARC).
+//!
+//! The waits poll an eventfd next to the LED lines, written by the
+//! `waker()` and by threads waiting for the event queues a wait holds,
+//! see "Idle waits" of `backend`.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::task::{Wake, Waker};
+use std::time::{Duration, Instant};
+
+use crate::clock::deadline;
+use crate::discover;
+use super::{buttons_not_requested, describe_lines, poll_timeout_ms, Button, EventBuffer, GpioBackend, Led, LedEvent, LineInfo};
+
+const GPIO_V2_LINES_MAX: usize = 64;
+const GPIO_MAX_NAME_SIZE: usize = 32;
+const GPIO_V2_LINE_NUM_ATTRS_MAX: usize = 10;
+
+const GPIO_V2_LINE_FLAG_INPUT: u64 = 1 << 2;
+const GPIO_V2_LINE_FLAG_OUTPUT: u64 = 1 << 3;
+const GPIO_V2_LINE_FLAG_EDGE_RISING: u64 = 1 << 4;
+const GPIO_V2_LINE_FLAG_EDGE_FALLING: u64 = 1 << 5;
+
+const GPIO_V2_LINE_EVENT_RISING_EDGE: u32 = 1;
+
+/// Kernel event buffer size per request (two lines).
+const EVENT_BUFFER_SIZE: u32 = 64;
+/// Events read from the kernel with a single read(2), the whole buffer.
+const READ_BATCH: usize = EVENT_BUFFER_SIZE as usize;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct LineAttribute {
+    id: u32,
+    padding: u32,
+    value: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct LineConfigAttribute {
+    attr: LineAttribute,
+    mask: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct LineConfig {
+    flags: u64,
+    num_attrs: u32,
+    padding: [u32; 5],
+    attrs: [LineConfigAttribute; GPIO_V2_LINE_NUM_ATTRS_MAX],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct LineRequest {
+    offsets: [u32; GPIO_V2_LINES_MAX],
+    consumer: [u8; GPIO_MAX_NAME_SIZE],
+    config: LineConfig,
+    num_lines: u32,
+    event_buffer_size: u32,
+    padding: [u32; 5],
+    fd: i32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct LineValues {
+    bits: u64,
+    mask: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct LineEvent {
+    timestamp_ns: u64,
+    id: u32,
+    offset: u32,
+    seqno: u32,
+    line_seqno: u32,
+    padding: [u32; 6],
+}
+
+const _: () = assert!(std::mem::size_of::<LineRequest>() == 592);
+const _: () = assert!(std::mem::size_of::<LineEvent>() == 48);
+
+const fn iowr(nr: u32, size: usize) -> u32 {
+    (3 << 30) | ((size as u32) << 16) | (0xB4 << 8) | nr
+}
+
+const GPIO_V2_GET_LINE_IOCTL: u32 = iowr(0x07, std::mem::size_of::<LineRequest>());
+const GPIO_V2_LINE_GET_VALUES_IOCTL: u32 = iowr(0x0E, std::mem::size_of::<LineValues>());
+const GPIO_V2_LINE_SET_VALUES_IOCTL: u32 = iowr(0x0F, std::mem::size_of::<LineValues>());
+
+/// Request `offsets` from `chip` with `flags` for all lines.
+fn request_lines(chip: &File, consumer: &str, offsets: &[u32], flags: u64) -> std::io::Result<File> {
+    let mut req: LineRequest = unsafe { std::mem::zeroed() };
+    req.offsets[..offsets.len()].copy_from_slice(offsets);
+    let len = std::cmp::min(consumer.len(), GPIO_MAX_NAME_SIZE - 1);
+    req.consumer[..len].copy_from_slice(&consumer.as_bytes()[..len]);
+    req.config.flags = flags;
+    req.num_lines = offsets.len() as u32;
+    if flags & GPIO_V2_LINE_FLAG_INPUT != 0 {
+        req.event_buffer_size = EVENT_BUFFER_SIZE;
+    }
+
+    let ret = unsafe { libc::ioctl(chip.as_raw_fd(), GPIO_V2_GET_LINE_IOCTL as _, &mut req) };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(req.fd) })
+}
+
+/// Read the levels of both lines of `request` (bit 0 = first line).
+fn get_values(request: &File) -> std::io::Result<u64> {
+    let mut values = LineValues {bits: 0, mask: 0b11};
+    let ret = unsafe { libc::ioctl(request.as_raw_fd(), GPIO_V2_LINE_GET_VALUES_IOCTL as _, &mut values) };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(values.bits)
+}
+
+/// Returns true if `err` indicates a kernel without the v2 uAPI.
+pub(crate) fn is_unsupported(err: &Error) -> bool {
+    err.raw_os_error() == Some(libc::ENOTTY) || err.raw_os_error() == Some(libc::EINVAL)
+}
+
+struct Events {
+    red: VecDeque<LedEvent>,
+    green: VecDeque<LedEvent>,
+    /// Last line sequence number seen for red and green
+    last_seqno: [u32; 2],
+    /// Events lost since the last flush
+    lost: u32,
+    /// The eventfd ended the last poll
+    woken: bool,
+}
+
+/// Eventfd ending a poll of `Uapi2Backend::fetch()`.
+struct WakeFd(File);
+
+impl WakeFd {
+    fn new() -> std::io::Result<WakeFd> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(WakeFd(unsafe { File::from_raw_fd(fd) }))
+    }
+
+    /// Reset the counter after a wake.
+    fn drain(&self) {
+        let mut count = 0u64;
+        unsafe { libc::read(self.0.as_raw_fd(), &mut count as *mut u64 as *mut libc::c_void, 8) };
+    }
+}
+
+impl Wake for WakeFd {
+    fn wake(self: Arc<WakeFd>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<WakeFd>) {
+        /* fails only with the counter at its maximum, still readable */
+        let count = 1u64;
+        unsafe { libc::write(self.0.as_raw_fd(), &count as *const u64 as *const libc::c_void, 8) };
+    }
+}
+
+/// Backend using the v2 GPIO character device uAPI.
+pub struct Uapi2Backend {
+    leds: File,
+    /// None for `inputs()`
+    buttons: Option<File>,
+    chipdev: String,
+    gpios: [u32; 4],
+    events: Mutex<Events>,
+    wake: Arc<WakeFd>,
+}
+
+impl Uapi2Backend {
+    /// Request the lines from `chipdev`.
+    ///
+    /// `gpios` contains the line offsets for LED red, LED green, button
+    /// unlock and button lock (in this order). Fails with `ENOTTY` or
+    /// `EINVAL` on kernels without the v2 uAPI.
+    pub fn new(chipdev: &str, gpios: [u32; 4]) -> std::io::Result<Uapi2Backend> {
+        Uapi2Backend::request(chipdev, gpios, true)
+    }
+
+    /// Request only the LED lines, see `GpiochipBackend::inputs()`.
+    pub(crate) fn inputs(chipdev: &str, gpios: [u32; 4]) -> std::io::Result<Uapi2Backend> {
+        Uapi2Backend::request(chipdev, gpios, false)
+    }
+
+    fn request(chipdev: &str, gpios: [u32; 4], outputs: bool) -> std::io::Result<Uapi2Backend> {
+        let chip = OpenOptions::new().read(true).write(true).open(chipdev)?;
+        let leds = request_lines(&chip, "cff3000-leds", &gpios[..2],
+            GPIO_V2_LINE_FLAG_INPUT | GPIO_V2_LINE_FLAG_EDGE_RISING | GPIO_V2_LINE_FLAG_EDGE_FALLING)?;
+        let buttons = match outputs {
+            true => Some(request_lines(&chip, "cff3000-buttons", &gpios[2..], GPIO_V2_LINE_FLAG_OUTPUT)?),
+            false => None,
+        };
+
+        Ok(Uapi2Backend {
+            leds,
+            buttons,
+            chipdev: chipdev.to_string(),
+            gpios,
+            events: Mutex::new(Events {red: VecDeque::new(), green: VecDeque::new(), last_seqno: [0; 2], lost: 0, woken: false}),
+            wake: Arc::new(WakeFd::new()?),
+        })
+    }
+
+    fn buttons(&self) -> std::io::Result<&File> {
+        self.buttons.as_ref().ok_or_else(buttons_not_requested)
+    }
+
+    /// Lock the event queues, ending the poll of a wait holding them.
+    fn lock(&self) -> MutexGuard<'_, Events> {
+        match self.events.try_lock() {
+            Ok(events) => events,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => {
+                self.wake.wake_by_ref();
+                self.events.lock().unwrap_or_else(|e| e.into_inner())
+            },
+        }
+    }
+
+    /// Wait up to `timeout_ms` for kernel events and move them to the
+    /// per-LED queues, returning false on timeout or a wake.
+    fn fetch(&self, events: &mut Events, timeout_ms: i32) -> std::io::Result<bool> {
+        let mut fds = [
+            libc::pollfd {fd: self.leds.as_raw_fd(), events: libc::POLLIN, revents: 0},
+            libc::pollfd {fd: self.wake.0.as_raw_fd(), events: libc::POLLIN, revents: 0},
+        ];
+        let ret = unsafe { libc::poll(fds.as_mut_ptr(), 2, timeout_ms) };
+        if ret < 0 {
+            let err = Error::last_os_error();
+            return if err.kind() == ErrorKind::Interrupted {Ok(false)} else {Err(err)};
+        }
+        if fds[1].revents != 0 {
+            self.wake.drain();
+            events.woken = true;
+        }
+        if fds[0].revents == 0 {
+            return Ok(false);
+        }
+
+        let mut buf: [LineEvent; READ_BATCH] = unsafe { std::mem::zeroed() };
+        let size = std::mem::size_of::<LineEvent>();
+        let len = unsafe { libc::read(self.leds.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, size * READ_BATCH) };
+        if len < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        for event in &buf[..len as usize / size] {
+            let (led, index) = if event.offset == self.gpios[0] {(Led::Red, 0)} else {(Led::Green, 1)};
+
+            /* line sequence numbers start at 1 and increase without gaps */
+            let expected = events.last_seqno[index].wrapping_add(1);
+            let gap = event.line_seqno.wrapping_sub(expected);
+            if gap < u32::MAX / 2 {
+                events.lost = events.lost.saturating_add(gap);
+            }
+            events.last_seqno[index] = event.line_seqno;
+
+            let event = LedEvent {led, on: event.id == GPIO_V2_LINE_EVENT_RISING_EDGE, timestamp: Duration::from_nanos(event.timestamp_ns)};
+            match led {
+                Led::Red => events.red.push_back(event),
+                Led::Green => events.green.push_back(event),
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+fn pending_mask(events: &Events) -> u8 {
+    let mut mask = 0;
+    if !events.red.is_empty() {
+        mask |= Led::Red.mask();
+    }
+    if !events.green.is_empty() {
+        mask |= Led::Green.mask();
+    }
+    mask
+}
+
+impl GpioBackend for Uapi2Backend {
+    fn set_button(&self, button: Button, pressed: bool) -> std::io::Result<()> {
+        let mask = match button {
+            Button::Unlock => 0b01,
+            Button::Lock => 0b10,
+        };
+        let buttons = self.buttons()?;
+        let mut values = LineValues {bits: if pressed {mask} else {0}, mask};
+        let ret = unsafe { libc::ioctl(buttons.as_raw_fd(), GPIO_V2_LINE_SET_VALUES_IOCTL as _, &mut values) };
+        if ret < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn wait_for_led_events(&self, timeout: Duration) -> std::io::Result<u8> {
+        let deadline = deadline(Instant::now(), timeout);
+        let mut events = self.lock();
+
+        loop {
+            let mask = pending_mask(&events);
+            if mask != 0 {
+                return Ok(mask);
+            }
+
+            /* poll at least once, even for a zero timeout */
+            let now = Instant::now();
+            let remaining = if deadline > now {deadline - now} else {Duration::from_millis(0)};
+            events.woken = false;
+            self.fetch(&mut events, poll_timeout_ms(remaining))?;
+
+            if events.woken || Instant::now() >= deadline {
+                return Ok(pending_mask(&events));
+            }
+        }
+    }
+
+    fn read_led_event(&self, led: Led) -> std::io::Result<LedEvent> {
+        let mut events = self.lock();
+        let event = match led {
+            Led::Red => events.red.pop_front(),
+            Led::Green => events.green.pop_front(),
+        };
+        event.ok_or_else(|| Error::new(ErrorKind::WouldBlock, "no pending LED event"))
+    }
+
+    /// The events fetched by `wait_for_led_events()`, without another
+    /// read(2).
+    fn read_led_events(&self, led: Led, out: &mut EventBuffer) -> std::io::Result<usize> {
+        let mut events = self.lock();
+        let queue = match led {
+            Led::Red => &mut events.red,
+            Led::Green => &mut events.green,
+        };
+        if queue.is_empty() {
+            return Err(Error::new(ErrorKind::WouldBlock, "no pending LED event"));
+        }
+        let count = std::cmp::min(queue.len(), out.remaining());
+        for event in queue.drain(..count) {
+            out.push(event);
+        }
+        Ok(count)
+    }
+
+    fn flush_led_events(&self) -> std::io::Result<()> {
+        let mut events = self.lock();
+        while self.fetch(&mut events, 0)? {}
+        events.red.clear();
+        events.green.clear();
+        events.lost = 0;
+        Ok(())
+    }
+
+    fn waker(&self) -> Option<Waker> {
+        Some(Waker::from(self.wake.clone()))
+    }
+
+    fn lost_led_events(&self) -> u32 {
+        self.lock().lost
+    }
+
+    fn line_info(&self) -> std::io::Result<[LineInfo; 4]> {
+        let chip = discover::chip(&self.chipdev)?;
+        let leds = get_values(&self.leds)?;
+        let buttons = get_values(self.buttons()?)?;
+        let levels = [(leds & 1) as u8, (leds >> 1 & 1) as u8, (buttons & 1) as u8, (buttons >> 1 & 1) as u8];
+        describe_lines(&chip, self.gpios, levels)
+    }
+}
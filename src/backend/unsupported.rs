@@ -0,0 +1,47 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Placeholder for `GpiochipBackend` on non-Linux targets.
+
+use std::time::Duration;
+
+use super::{Button, GpioBackend, Led, LedEvent};
+
+enum Void {}
+
+/// Backend using the Linux GPIO character device (`/dev/gpiochipN`).
+///
+/// Not available on this target, `new()` always fails.
+pub struct GpiochipBackend {
+    void: Void,
+}
+
+impl GpiochipBackend {
+    /// Always fails with `ErrorKind::Unsupported` on this target.
+    pub fn new(_chipdev: &str, _gpios: [u32; 4]) -> std::io::Result<GpiochipBackend> {
+        Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "GPIO character devices are only supported on Linux"))
+    }
+
+    /// Always fails with `ErrorKind::Unsupported` on this target.
+    pub(crate) fn inputs(chipdev: &str, gpios: [u32; 4]) -> std::io::Result<GpiochipBackend> {
+        GpiochipBackend::new(chipdev, gpios)
+    }
+}
+
+impl GpioBackend for GpiochipBackend {
+    fn set_button(&self, _button: Button, _pressed: bool) -> std::io::Result<()> {
+        match self.void {}
+    }
+
+    fn wait_for_led_events(&self, _timeout: Duration) -> std::io::Result<u8> {
+        match self.void {}
+    }
+
+    fn read_led_event(&self, _led: Led) -> std::io::Result<LedEvent> {
+        match self.void {}
+    }
+
+    fn flush_led_events(&self) -> std::io::Result<()> {
+        match self.void {}
+    }
+}
@@ -0,0 +1,239 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Radio commands sent on the batteries of the CFF3000, see
+//! `CFF3000::battery_usage()`.
+//!
+//! Every press sends a radio command to the lock, a check as much as a
+//! lock or an unlock, and each one drains the batteries of the CFF3000
+//! a little. The device counts its presses (not those of a dry run)
+//! since the last battery change recorded with
+//! `CFF3000::reset_battery()`, or since it first ran without one. The
+//! `daemon` keeps the count in its state file, see `persist`, answers
+//! `{"cmd":"battery"}` on its Unix socket and takes new batteries with
+//! `{"cmd":"battery-reset"}`; `cff3000 battery show` and
+//! `cff3000 battery reset` send them.
+//!
+//! `BatteryOptions::budget` is a rough guess of the commands a set of
+//! batteries lasts. [`BatteryUsage`] estimates what is left of it, and
+//! at the command rate so far how long that lasts. Its `warning` is
+//! set once less than `BatteryOptions::warn_below_percent` of the
+//! budget is left, long before the CFF3000 blinks for low batteries.
+//! Usages are JSON objects for the socket and `cff3000 battery`:
+//!
+//! ```text
+//! {"since_ms":1760000000123,"replaced":true,"commands":1210,"elapsed_ms":8640000000,"budget":5000,"warning":false,"per_day":12.1,"remaining":3790,"days_left":313.2}
+//! ```
+//!
+//! `per_day` and `days_left` are left out during the first day,
+//! `days_left` also as long as there are no commands.
+
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+#[cfg(any(feature = "cli", all(feature = "unix-socket", unix)))]
+use std::time::UNIX_EPOCH;
+
+#[cfg(all(feature = "unix-socket", unix))]
+use crate::json::Value;
+use crate::CFF3000;
+
+/// Default of `BatteryOptions::budget`, commands of a fresh set of
+/// batteries.
+pub const DEFAULT_BUDGET: u64 = 5000;
+
+/// Default of `BatteryOptions::warn_below_percent`.
+pub const DEFAULT_WARN_BELOW_PERCENT: u8 = 20;
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Estimate of a `CFF3000`, see `CFF3000Builder::battery()`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct BatteryOptions {
+    /// Commands a set of batteries lasts (default: `DEFAULT_BUDGET`)
+    pub budget: u64,
+    /// Percentage of the budget left at which `BatteryUsage::warning`
+    /// is set (default: `DEFAULT_WARN_BELOW_PERCENT`)
+    pub warn_below_percent: u8,
+}
+
+impl Default for BatteryOptions {
+    fn default() -> BatteryOptions {
+        BatteryOptions {budget: DEFAULT_BUDGET, warn_below_percent: DEFAULT_WARN_BELOW_PERCENT}
+    }
+}
+
+/// Start of the count and the commands since, kept by the daemon.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BatteryMarker {
+    pub since: SystemTime,
+    /// `since` is a recorded battery change, not the first start
+    pub replaced: bool,
+    pub commands: u64,
+}
+
+/// Commands on the batteries with the estimate, see the module
+/// documentation.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BatteryUsage {
+    pub since: SystemTime,
+    /// `since` is a recorded battery change, not the first start
+    pub replaced: bool,
+    pub commands: u64,
+    /// Time from `since` to the usage
+    pub elapsed: Duration,
+    /// `BatteryOptions::budget`
+    pub budget: u64,
+    /// Less than `BatteryOptions::warn_below_percent` of the budget is
+    /// left
+    pub warning: bool,
+}
+
+impl BatteryUsage {
+    /// Usage of `marker` at `now` with the estimate of `options`.
+    pub fn new(marker: BatteryMarker, options: BatteryOptions, now: SystemTime) -> BatteryUsage {
+        let remaining = options.budget.saturating_sub(marker.commands);
+        BatteryUsage {
+            since: marker.since,
+            replaced: marker.replaced,
+            commands: marker.commands,
+            elapsed: now.duration_since(marker.since).unwrap_or_default(),
+            budget: options.budget,
+            warning: (remaining as u128) * 100 < (options.budget as u128) * (options.warn_below_percent as u128),
+        }
+    }
+
+    /// Commands per day so far, `None` during the first day.
+    pub fn per_day(&self) -> Option<f64> {
+        match self.elapsed < DAY {
+            true => None,
+            false => Some(self.commands as f64 / (self.elapsed.as_secs_f64() / DAY.as_secs_f64())),
+        }
+    }
+
+    /// Commands left of the budget.
+    pub fn remaining(&self) -> u64 {
+        self.budget.saturating_sub(self.commands)
+    }
+
+    /// Days the remaining commands last at `per_day()`, `None` during
+    /// the first day or without any commands.
+    pub fn days_left(&self) -> Option<f64> {
+        self.per_day().filter(|&per_day| per_day > 0.0).map(|per_day| self.remaining() as f64 / per_day)
+    }
+
+    /// Append the usage as a JSON object, see the module documentation.
+    #[cfg(any(feature = "cli", all(feature = "unix-socket", unix)))]
+    pub(crate) fn write_json(&self, out: &mut String) {
+        let since_epoch = self.since.duration_since(UNIX_EPOCH).unwrap_or_default();
+        out.push_str(&format!(
+            "{{\"since_ms\":{},\"replaced\":{},\"commands\":{},\"elapsed_ms\":{},\"budget\":{},\"warning\":{}",
+            since_epoch.as_millis(), self.replaced, self.commands, self.elapsed.as_millis(), self.budget, self.warning,
+        ));
+        if let Some(per_day) = self.per_day() {
+            out.push_str(&format!(",\"per_day\":{:.1}", per_day));
+        }
+        out.push_str(&format!(",\"remaining\":{}", self.remaining()));
+        if let Some(days_left) = self.days_left() {
+            out.push_str(&format!(",\"days_left\":{:.1}", days_left));
+        }
+        out.push('}');
+    }
+
+    /// Usage of a JSON object written by `write_json()`, `None` if it is
+    /// not one.
+    #[cfg(all(feature = "unix-socket", unix))]
+    pub(crate) fn from_json(value: &Value) -> Option<BatteryUsage> {
+        let number = |key: &str| match value.get(key) {
+            Some(&Value::Number(n)) if n >= 0.0 => Some(n as u64),
+            _ => None,
+        };
+        let flag = |key: &str| match value.get(key) {
+            Some(&Value::Bool(flag)) => Some(flag),
+            _ => None,
+        };
+        Some(BatteryUsage {
+            since: UNIX_EPOCH + Duration::from_millis(number("since_ms")?),
+            replaced: flag("replaced")?,
+            commands: number("commands")?,
+            elapsed: Duration::from_millis(number("elapsed_ms")?),
+            budget: number("budget")?,
+            warning: flag("warning")?,
+        })
+    }
+}
+
+/// Called after a battery change with the new marker.
+pub(crate) type ResetHook = Box<dyn Fn(BatteryMarker) + Send + Sync>;
+
+/// Commands of a device since the marker.
+pub(crate) struct BatteryCounter {
+    options: BatteryOptions,
+    marker: Mutex<BatteryMarker>,
+    on_reset: Mutex<Option<ResetHook>>,
+}
+
+impl BatteryCounter {
+    /// Counter starting at `since`, without a recorded battery change.
+    pub(crate) fn new(options: BatteryOptions, since: SystemTime) -> BatteryCounter {
+        BatteryCounter {options, marker: Mutex::new(BatteryMarker {since, replaced: false, commands: 0}), on_reset: Mutex::new(None)}
+    }
+
+    pub(crate) fn record(&self) {
+        self.marker.lock().unwrap_or_else(|e| e.into_inner()).commands += 1;
+    }
+
+    pub(crate) fn marker(&self) -> BatteryMarker {
+        *self.marker.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Continue the count of `marker`, adding the commands counted so
+    /// far.
+    pub(crate) fn restore(&self, marker: BatteryMarker) {
+        let mut current = self.marker.lock().unwrap_or_else(|e| e.into_inner());
+        *current = BatteryMarker {commands: marker.commands + current.commands, ..marker};
+    }
+
+    pub(crate) fn reset(&self, now: SystemTime) {
+        let marker = BatteryMarker {since: now, replaced: true, commands: 0};
+        *self.marker.lock().unwrap_or_else(|e| e.into_inner()) = marker;
+        if let Some(ref hook) = *self.on_reset.lock().unwrap_or_else(|e| e.into_inner()) {
+            hook(marker);
+        }
+    }
+
+    /// Call `hook` after every battery change, e.g. to write it to the
+    /// state file right away.
+    #[cfg(feature = "daemon")]
+    pub(crate) fn on_reset(&self, hook: ResetHook) {
+        *self.on_reset.lock().unwrap_or_else(|e| e.into_inner()) = Some(hook);
+    }
+
+    pub(crate) fn usage(&self, now: SystemTime) -> BatteryUsage {
+        BatteryUsage::new(self.marker(), self.options, now)
+    }
+}
+
+impl CFF3000 {
+    /// Commands sent on the batteries with the estimate of
+    /// `CFF3000Builder::battery()`, see the `battery` module.
+    pub fn battery_usage(&self) -> BatteryUsage {
+        self.battery.usage(SystemTime::now())
+    }
+
+    /// Record new batteries: the count starts again at zero, now.
+    pub fn reset_battery(&self) {
+        self.battery.reset(SystemTime::now());
+    }
+
+    /// Start of the count and the commands since, e.g. to keep them
+    /// across restarts as `persist::StateFile` does.
+    pub fn battery_marker(&self) -> BatteryMarker {
+        self.battery.marker()
+    }
+
+    /// Continue the count of `marker`, e.g. one kept across restarts.
+    /// Commands counted before are added to it.
+    pub fn restore_battery(&self, marker: BatteryMarker) {
+        self.battery.restore(marker);
+    }
+}
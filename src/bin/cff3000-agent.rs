@@ -0,0 +1,55 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Network agent exposing a locally connected CFF3000.
+//!
+//! Usage: `cff3000-agent <chipdev> <red> <green> <unlock> <lock> [listen address]`
+//!
+//! The pre-shared token is read from the `CFF3000_AGENT_TOKEN`
+//! environment variable. The default listen address is `0.0.0.0:3003`.
+
+use std::net::TcpListener;
+
+use cff3000::remote::{Agent, DEFAULT_PORT};
+use cff3000::GpiochipBackend;
+
+fn usage() -> ! {
+    println!("usage: cff3000-agent <chipdev> <red> <green> <unlock> <lock> [listen address]");
+    std::process::exit(1)
+}
+
+fn run(args: &[String]) -> std::io::Result<()> {
+    let token = match std::env::var("CFF3000_AGENT_TOKEN") {
+        Ok(ref token) if !token.is_empty() => token.clone(),
+        _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "CFF3000_AGENT_TOKEN is not set")),
+    };
+
+    let mut gpios = [0u32; 4];
+    for (gpio, arg) in gpios.iter_mut().zip(&args[2..6]) {
+        *gpio = match arg.parse() {
+            Ok(gpio) => gpio,
+            Err(_) => usage(),
+        };
+    }
+    let listen = match args.get(6) {
+        Some(addr) => addr.clone(),
+        None => format!("0.0.0.0:{}", DEFAULT_PORT),
+    };
+
+    let backend = GpiochipBackend::new(&args[1], gpios)?;
+    let listener = TcpListener::bind(&listen)?;
+    println!("cff3000-agent listening on {}", listen);
+    Agent::new(backend, &token).serve(&listener)
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 6 {
+        usage();
+    }
+
+    if let Err(err) = run(&args) {
+        println!("{}", err);
+        std::process::exit(1)
+    }
+}
@@ -0,0 +1,278 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Fake CFF3000 for development without hardware.
+//!
+//! Usage: `cff3000-sim [options] gpio-sim`
+//!    or: `cff3000-sim [options] listen [address]`
+//!
+//! The simulator watches the button lines, keeps a locked/unlocked
+//! state and answers every press with the LED pattern of the real
+//! device (a check press shows the state, lock and unlock presses
+//! change it first).
+//!
+//! * `gpio-sim` creates a chip with the kernel's gpio-sim module (needs
+//!   root and configfs at `/sys/kernel/config`) and acts as the
+//!   hardware behind it. Use `CFF3000::new()` with the printed chip and
+//!   the line offsets 0 (LED red), 1 (LED green), 2 (button unlock) and
+//!   3 (button lock). Stale chips of an interrupted run are removed on
+//!   the next start.
+//! * `listen` serves a mock backend like `cff3000-agent` does, so
+//!   `remote::RemoteBackend` connects to it (default address
+//!   `127.0.0.1:3003`, token from `CFF3000_AGENT_TOKEN` or
+//!   `cff3000-sim`).
+//!
+//! Options:
+//!
+//! * `--state locked|unlocked`: initial state (default: locked)
+//! * `--out-of-range <probability>`: answer a press with the out of
+//!   range pattern (without changing the state) with this probability
+//! * `--battery-low`: show a battery warning (three short red flashes)
+//!   before every pattern, which starts 600 ms later; the library does
+//!   not know this prefix and reports the pattern as invalid
+//! * `--seed <n>`: seed of the random timing jitter and failures
+
+use std::collections::VecDeque;
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use cff3000::mock::MockBackend;
+use cff3000::remote::{Agent, DEFAULT_PORT};
+use cff3000::testing::{generate, PatternParams};
+use cff3000::{Button, CFF3000State, Led, LedEvent};
+
+const CONFIGFS: &str = "/sys/kernel/config/gpio-sim/cff3000-sim";
+const BATTERY_LOW_FLASHES: u64 = 3;
+const BATTERY_LOW_FLASH_MS: u64 = 100;
+
+fn usage() -> ! {
+    println!("usage: cff3000-sim [--state locked|unlocked] [--out-of-range <probability>] [--battery-low] [--seed <n>] gpio-sim");
+    println!("       cff3000-sim [options] listen [address]");
+    std::process::exit(1)
+}
+
+/// Button and LED lines of the simulated hardware.
+trait Lines {
+    /// Levels of the buttons (unlock, lock)
+    fn buttons(&self) -> std::io::Result<[bool; 2]>;
+    fn set_led(&self, led: Led, on: bool) -> std::io::Result<()>;
+}
+
+/// gpio-sim chip created through configfs, removed on drop.
+struct SimChip {
+    chipdev: String,
+    /// sysfs directory with the `sim_gpioN` line attributes
+    lines: PathBuf,
+}
+
+fn read(path: &Path) -> std::io::Result<String> {
+    fs::read_to_string(path).map(|value| value.trim().to_string())
+}
+
+impl SimChip {
+    fn remove() {
+        let config = Path::new(CONFIGFS);
+        let _ = fs::write(config.join("live"), "0");
+        let _ = fs::remove_dir(config.join("gpio-bank0"));
+        let _ = fs::remove_dir(config);
+    }
+
+    fn create() -> std::io::Result<SimChip> {
+        SimChip::remove();
+        let config = Path::new(CONFIGFS);
+        fs::create_dir(config)?;
+        fs::create_dir(config.join("gpio-bank0"))?;
+        fs::write(config.join("gpio-bank0/num_lines"), "4")?;
+        fs::write(config.join("live"), "1")?;
+
+        let device = read(&config.join("dev_name"))?;
+        let chip = read(&config.join("gpio-bank0/chip_name"))?;
+        Ok(SimChip {
+            chipdev: format!("/dev/{}", chip),
+            lines: Path::new("/sys/devices/platform").join(device).join(chip),
+        })
+    }
+
+    fn line(&self, offset: u32, attribute: &str) -> PathBuf {
+        self.lines.join(format!("sim_gpio{}/{}", offset, attribute))
+    }
+}
+
+impl Drop for SimChip {
+    fn drop(&mut self) {
+        SimChip::remove();
+    }
+}
+
+impl Lines for SimChip {
+    fn buttons(&self) -> std::io::Result<[bool; 2]> {
+        let unlock = read(&self.line(2, "value"))?;
+        let lock = read(&self.line(3, "value"))?;
+        Ok([unlock == "1", lock == "1"])
+    }
+
+    fn set_led(&self, led: Led, on: bool) -> std::io::Result<()> {
+        let offset = match led {Led::Red => 0, Led::Green => 1};
+        fs::write(self.line(offset, "pull"), if on {"pull-up"} else {"pull-down"})
+    }
+}
+
+/// Mock backend side of the `listen` mode.
+struct MockLines {
+    mock: MockBackend,
+    start: Instant,
+}
+
+impl Lines for MockLines {
+    fn buttons(&self) -> std::io::Result<[bool; 2]> {
+        Ok([self.mock.is_pressed(Button::Unlock), self.mock.is_pressed(Button::Lock)])
+    }
+
+    fn set_led(&self, led: Led, on: bool) -> std::io::Result<()> {
+        self.mock.push_events(&[LedEvent {led, on, timestamp: self.start.elapsed()}]);
+        Ok(())
+    }
+}
+
+struct Options {
+    state: CFF3000State,
+    out_of_range: f64,
+    battery_low: bool,
+    seed: u64,
+}
+
+/// Apply a press of the `combo` (unlock, lock) buttons and return the
+/// LED events to show, timed from the start of the press.
+fn respond(options: &Options, state: &mut CFF3000State, combo: [bool; 2], rng: &mut u64) -> Vec<LedEvent> {
+    *rng ^= *rng << 13;
+    *rng ^= *rng >> 7;
+    *rng ^= *rng << 17;
+    let out_of_range = (*rng % 1_000_000) as f64 / 1_000_000.0 < options.out_of_range;
+
+    let shown = if out_of_range {
+        CFF3000State::OutOfRange
+    } else {
+        match combo {
+            [true, false] => *state = CFF3000State::Unlocked,
+            [false, true] => *state = CFF3000State::Locked,
+            _ => {},
+        }
+        *state
+    };
+
+    let mut params = PatternParams {jitter: Duration::from_millis(5), seed: *rng, ..PatternParams::default()};
+    let mut events = Vec::new();
+    if options.battery_low {
+        let flash = Duration::from_millis(BATTERY_LOW_FLASH_MS);
+        for i in 0..BATTERY_LOW_FLASHES {
+            let on = params.lead_in + flash * 2 * i as u32;
+            events.push(LedEvent {led: Led::Red, on: true, timestamp: on});
+            events.push(LedEvent {led: Led::Red, on: false, timestamp: on + flash});
+        }
+        params.lead_in += Duration::from_millis(2 * BATTERY_LOW_FLASHES * BATTERY_LOW_FLASH_MS);
+    }
+    events.extend(generate(shown, params));
+    println!("{} press: showing {:?}{}", match combo {
+        [true, true] => "check",
+        [true, false] => "unlock",
+        _ => "lock",
+    }, shown, if options.battery_low {" after battery warning"} else {""});
+    events
+}
+
+/// Act as the CFF3000 behind `lines` until an error occurs.
+fn simulate(lines: &dyn Lines, options: &Options) -> std::io::Result<()> {
+    let mut state = options.state;
+    let mut rng = options.seed | 1;
+    let mut press_start: Option<Instant> = None;
+    let mut combo = [false; 2];
+    let mut pending: VecDeque<(Instant, LedEvent)> = VecDeque::new();
+    /* (red, green), only changes are written so the mock sees real edges */
+    let mut leds = [false; 2];
+    let mut set = |led: Led, on: bool| -> std::io::Result<()> {
+        let level = &mut leds[match led {Led::Red => 0, Led::Green => 1}];
+        if *level != on {
+            *level = on;
+            lines.set_led(led, on)?;
+        }
+        Ok(())
+    };
+
+    lines.set_led(Led::Red, false)?;
+    lines.set_led(Led::Green, false)?;
+    loop {
+        while pending.front().is_some_and(|&(due, _)| due <= Instant::now()) {
+            let (_, event) = pending.pop_front().unwrap();
+            set(event.led, event.on)?;
+        }
+
+        let buttons = lines.buttons()?;
+        if buttons[0] || buttons[1] {
+            press_start = press_start.or_else(|| Some(Instant::now()));
+            combo = [combo[0] || buttons[0], combo[1] || buttons[1]];
+        } else if let Some(start) = press_start.take() {
+            /* a new press interrupts the running pattern */
+            let events = respond(options, &mut state, combo, &mut rng);
+            combo = [false; 2];
+            pending = events.into_iter().map(|event| (start + event.timestamp, event)).collect();
+            set(Led::Red, false)?;
+            set(Led::Green, false)?;
+        }
+        std::thread::sleep(Duration::from_millis(2));
+    }
+}
+
+fn run(args: &[String]) -> std::io::Result<()> {
+    let mut options = Options {state: CFF3000State::Locked, out_of_range: 0.0, battery_low: false, seed: 1};
+    let mut i = 1;
+    while i < args.len() && args[i].starts_with("--") {
+        let value = args.get(i + 1);
+        match (args[i].as_str(), value.map(|v| v.as_str())) {
+            ("--state", Some("locked")) => options.state = CFF3000State::Locked,
+            ("--state", Some("unlocked")) => options.state = CFF3000State::Unlocked,
+            ("--out-of-range", Some(p)) => options.out_of_range = p.parse().unwrap_or_else(|_| usage()),
+            ("--seed", Some(n)) => options.seed = n.parse().unwrap_or_else(|_| usage()),
+            ("--battery-low", _) => {
+                options.battery_low = true;
+                i += 1;
+                continue;
+            },
+            _ => usage(),
+        }
+        i += 2;
+    }
+
+    match args.get(i).map(|mode| mode.as_str()) {
+        Some("gpio-sim") if args.len() == i + 1 => {
+            let chip = SimChip::create()?;
+            println!("cff3000-sim on {} (red 0, green 1, unlock 2, lock 3)", chip.chipdev);
+            simulate(&chip, &options)
+        },
+        Some("listen") if args.len() <= i + 2 => {
+            let listen = args.get(i + 1).cloned().unwrap_or_else(|| format!("127.0.0.1:{}", DEFAULT_PORT));
+            let token = std::env::var("CFF3000_AGENT_TOKEN").ok().filter(|token| !token.is_empty()).unwrap_or_else(|| "cff3000-sim".to_string());
+            let mock = MockBackend::new();
+            let listener = TcpListener::bind(&listen)?;
+            let agent = Agent::new(mock.clone(), &token);
+            std::thread::spawn(move || {
+                if let Err(err) = agent.serve(&listener) {
+                    println!("{}", err);
+                    std::process::exit(1)
+                }
+            });
+            println!("cff3000-sim listening on {}", listen);
+            simulate(&MockLines {mock, start: Instant::now()}, &options)
+        },
+        _ => usage(),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if let Err(err) = run(&args) {
+        println!("{}", err);
+        std::process::exit(1)
+    }
+}
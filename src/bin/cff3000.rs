@@ -0,0 +1,530 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Command line interface to a CFF3000 (`cli` feature).
+//!
+//! Usage: `cff3000 [--json] [-v|-vv|-q|-qq] [--dry-run] [--config <file>] [--chip <chipdev>] [--pins <red,green,unlock,lock>] <command>`
+//!
+//! * `lock [--verify]`, `unlock [--verify]`: press the button; with
+//!   `--verify`, capture the confirmation and fail unless it shows the
+//!   new state
+//! * `check`: press both buttons, the CFF3000 shows the state on its
+//!   LEDs
+//! * `status [--cache-ttl <seconds> | --no-cache]`: query and print the
+//!   state; with `--cache-ttl`, print the cached state instead while it
+//!   is younger than `seconds`
+//! * `leds [seconds]`: show the LEDs on the terminal
+//! * `watch [--interval <seconds>] [--auto-lock-after <seconds>] [--max-events <n>]`:
+//!   query the state periodically and print every change until SIGINT
+//!   or SIGTERM (or after `n` changes), see `CFF3000::watch_with_options()`;
+//!   built with the `systemd` feature and run by a `Type=notify` unit,
+//!   it reports readiness after the first successful query and pings
+//!   the watchdog, see `cff3000::systemd`
+//! * `rpc [--interval <seconds>] [--auto-lock-after <seconds>]`: answer
+//!   JSON-RPC 2.0 requests on stdin until it is closed, see
+//!   `cff3000::cli`; the options apply to the watch loop started by
+//!   `subscribe`
+//! * `record -o <file> [--window <seconds>] [--expected <state>] [--device <text>] [--firmware <text>] [--format fixture|vcd|csv]`:
+//!   press both buttons and write the LED events as a fixture file
+//!   (the format of `cff3000::testing::Fixture`) or, with `--format
+//!   vcd` or `csv`, as a Value Change Dump or plain CSV (see
+//!   `cff3000::export`); `--expected` is needed if the pattern cannot
+//!   be interpreted
+//! * `replay <file> [--format text|vcd|csv]`: interpret a fixture file
+//!   or a CSV file of `record --format csv` without hardware and print
+//!   what the parser sees; fails with `not-confirmed` if the result
+//!   differs from the expected state of the fixture. With `--format
+//!   vcd` or `csv`, stdout only carries the events in that format,
+//!   errors go to stderr
+//! * `config show`: print the effective configuration and the source
+//!   of each value
+//! * `history [--socket <path>]`: print the last operations of a
+//!   daemon serving a Unix socket (default: `/run/cff3000.sock`), see
+//!   `cff3000::history`
+//! * `battery show [--socket <path>]`: print the commands the daemon
+//!   has sent since the last battery change, with an estimate of what
+//!   is left, see `cff3000::battery`
+//! * `battery reset [--socket <path>]`: tell the daemon the batteries
+//!   have been replaced
+//! * `completions <shell>`: print the completion script for bash, zsh,
+//!   fish, elvish or PowerShell
+//!
+//! The hidden `cff3000 --generate-man` prints the man page for
+//! packaging.
+//!
+//! The configuration is layered as documented in `cff3000::config`:
+//! `/etc/cff3000.toml` and `~/.config/cff3000/config.toml` (or the
+//! `--config` file instead), then the `CFF3000_*` environment
+//! variables, then `--chip` and `--pins`. Errors are printed to stderr.
+//! With `--json`, the result is printed as one JSON object on stdout
+//! instead; `leds` and `config show` fail with the `unsupported` error
+//! code then. Every `status` query updates the state cache in
+//! `~/.cache/cff3000` unless `--no-cache` is given, `lock` and `unlock`
+//! remove it.
+//!
+//! Log records of the library and warnings (e.g. about the state
+//! cache) go to stderr with timestamps: warnings by default, more
+//! with `-v` and `-vv`, less with `-q` and `-qq`, see
+//! `cff3000::cli::log_level()`. `RUST_LOG` overrides these flags.
+//!
+//! With `--dry-run`, the button lines are not requested and every
+//! write to them is printed to stderr instead (see
+//! `CFF3000Builder::dry_run()`), e.g. `cff3000: dry run:
+//! /dev/gpiochip2 line 5 (button lock) low after 500 ms`. The state
+//! cache is left alone, except for `status` queries.
+//!
+//! Built with the `audit` feature, `lock`, `unlock`, `check` and
+//! `status` are recorded in the audit log of the `[audit]` section of
+//! the configuration, see `cff3000::audit`. Built with the `journald`
+//! feature and started by systemd with its output going to the
+//! journal (`JOURNAL_STREAM` is set), the presses, states and failures
+//! are also sent as structured entries, see `cff3000::journald`.
+//!
+//! The exit status tells the state for `status` and the class of
+//! error for all commands, see `cff3000::cli`.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use clap::ArgMatches;
+use clap_complete::Shell;
+
+use cff3000::cli::{command, format_battery, format_config, format_history, init_logging, initiator, log_level, replay_csv_diagnostics, replay_diagnostics, stop_on_signals, write_completions, write_man_page};
+use cff3000::cli::{serve_rpc, CachedState, CliError, ErrorCode, Report, RpcOptions, StateCache, EXIT_USAGE};
+use cff3000::config::{standard_config_files, CFF3000Config, ConfigLayer, ConfigSource, ConfigSources};
+use cff3000::export::{to_csv, to_vcd};
+use cff3000::import::{from_csv, is_csv};
+#[cfg(all(feature = "journald", unix))]
+use cff3000::journald::Journal;
+#[cfg(unix)]
+use cff3000::socket::UnixClient;
+#[cfg(feature = "systemd")]
+use cff3000::systemd::Notifier;
+use cff3000::testing::Fixture;
+use cff3000::{parse_led_events, timings, Command, CFF3000, CFF3000Builder, CFF3000State, DeviceProfile, LedEvent, PinAssignment, StopToken};
+
+fn sources(args: &ArgMatches) -> std::io::Result<ConfigSources> {
+    let mut sources = ConfigSources::new();
+    match args.get_one::<PathBuf>("config") {
+        Some(path) => sources.push_file(path)?,
+        None => for path in standard_config_files() {
+            sources.push_file_if_exists(path)?;
+        },
+    }
+    sources.push(ConfigSource::Env, ConfigLayer::from_env()?);
+    sources.push(ConfigSource::CommandLine, ConfigLayer {
+        chip: args.get_one::<String>("chip").cloned(),
+        pins: args.get_one::<PinAssignment>("pins").cloned(),
+        ..ConfigLayer::default()
+    });
+    Ok(sources)
+}
+
+/// `config show`: print all entries, even if the configuration is
+/// incomplete, then fail if it is.
+fn show_config(sources: &ConfigSources) -> std::io::Result<()> {
+    print!("{}", format_config(&sources.entries()?));
+    sources.config().map(|_| ())
+}
+
+fn verify(report: &mut Report, shown: CFF3000State, expected: CFF3000State) {
+    report.state = Some(shown);
+    if shown != expected {
+        let message = format!("not confirmed, the device shows {}", shown.name());
+        report.error = Some(CliError {code: ErrorCode::NotConfirmed, message});
+    }
+}
+
+fn print(report: &Report, json: bool) {
+    if json {
+        println!("{}", report.to_json());
+    } else {
+        if let Some(summary) = report.summary() {
+            println!("{}", summary);
+        }
+        print!("{}", format_history(&report.history));
+        if let Some(ref battery) = report.battery {
+            print!("{}", format_battery(battery));
+        }
+        if let Some(ref error) = report.error {
+            eprintln!("cff3000: {}", error.message);
+        }
+    }
+    /* consumers in a pipe want every change right away */
+    let _ = std::io::stdout().flush();
+}
+
+fn watch(cff3000: &CFF3000, config: &CFF3000Config, sub: &ArgMatches, json: bool, start: Instant) -> std::io::Result<()> {
+    let mut options = config.watch_options(Duration::from_secs(*sub.get_one::<u64>("interval").unwrap()));
+    if let Some(&secs) = sub.get_one::<u64>("auto-lock-after") {
+        options = options.auto_lock_after(Duration::from_secs(secs));
+    }
+    let max_events = sub.get_one::<u64>("max-events").cloned();
+
+    let stop = StopToken::new();
+    stop_on_signals(&stop)?;
+    #[cfg(feature = "systemd")]
+    let notifier = Notifier::from_env()?;
+    #[cfg(feature = "systemd")]
+    {
+        if let Some(interval) = notifier.as_ref().and_then(Notifier::watchdog_interval) {
+            options = options.heartbeat(interval);
+        }
+    }
+    let mut events = 0;
+    let result = cff3000.watch_with_heartbeat(&options, &stop, |change| {
+        #[cfg(feature = "systemd")]
+        notify(&notifier, |notifier| {
+            let status = format!("STATUS=door {}", change.current.name());
+            match change.previous {
+                /* the lines have been acquired and read successfully */
+                None => notifier.notify(&format!("READY=1\n{}", status)),
+                Some(_) => notifier.notify(&status),
+            }
+        });
+        print(&Report::change(change, start.elapsed()), json);
+        events += 1;
+        if max_events == Some(events) {
+            stop.stop();
+        }
+    }, || {
+        #[cfg(feature = "systemd")]
+        notify(&notifier, Notifier::watchdog);
+    });
+    #[cfg(feature = "systemd")]
+    notify(&notifier, Notifier::stopping);
+    result
+}
+
+/// `rpc`: serve stdin and stdout, sharing the device with the watch
+/// loop of `subscribe`.
+fn rpc(cff3000: CFF3000, config: &CFF3000Config, sub: &ArgMatches) -> std::io::Result<()> {
+    let mut options = RpcOptions {watch: config.watch_options(Duration::from_secs(*sub.get_one::<u64>("interval").unwrap())), ..RpcOptions::default()};
+    if let Some(&secs) = sub.get_one::<u64>("auto-lock-after") {
+        options.watch = options.watch.auto_lock_after(Duration::from_secs(secs));
+    }
+    let stdin = std::io::stdin();
+    serve_rpc(Arc::new(cff3000), stdin.lock(), std::io::stdout(), &options)
+}
+
+/// Send a notification to systemd if running as a notify service, a
+/// failure is only worth a warning.
+#[cfg(feature = "systemd")]
+fn notify<F>(notifier: &Option<Notifier>, f: F)
+    where F: FnOnce(&Notifier) -> std::io::Result<()>
+{
+    if let Some(notifier) = notifier.as_ref() {
+        if let Err(err) = f(notifier) {
+            log::warn!("cannot notify systemd: {}", err);
+        }
+    }
+}
+
+fn record(cff3000: &CFF3000, config: &CFF3000Config, sub: &ArgMatches, report: &mut Report) -> std::io::Result<()> {
+    let window = sub.get_one::<u64>("window").map(|&secs| Duration::from_secs(secs)).unwrap_or(config.timings().check_capture);
+    let capture = cff3000.capture(window)?;
+    if capture.lost_events != 0 {
+        log::warn!("{} LED events lost during the capture", capture.lost_events);
+    }
+    if capture.truncated {
+        log::warn!("capture stopped after {} LED events", capture.events.len());
+    }
+
+    let options = config.parse_options().unwrap_or_else(|| config.profile.timing().parse_options());
+    let interpreted = parse_led_events(&capture.events, &options);
+    report.state = interpreted.ok();
+    let expected = match (sub.get_one::<String>("expected"), interpreted) {
+        (Some(name), _) => CFF3000State::from_name(name).unwrap(),
+        (None, Ok(state)) => state,
+        (None, Err(err)) => {
+            eprintln!("cff3000: pass --expected with the state the LEDs showed to record this capture");
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err));
+        },
+    };
+
+    let output = sub.get_one::<PathBuf>("output").unwrap();
+    match sub.get_one::<String>("format").map(String::as_str) {
+        Some("vcd") => return to_vcd(&capture.events, std::io::BufWriter::new(std::fs::File::create(output)?)),
+        Some("csv") => return to_csv(&capture.events, std::io::BufWriter::new(std::fs::File::create(output)?)),
+        _ => {},
+    }
+    let start = capture.events.iter().map(|event| event.timestamp).min().unwrap_or_default();
+    let mut fixture = Fixture::from_capture(sub.get_one::<String>("device").unwrap(), sub.get_one::<String>("firmware").unwrap(), expected, &capture.events, start);
+    fixture.profile = config.profile;
+    std::fs::write(output, fixture.to_string())
+}
+
+/// `replay --format vcd|csv`, whose stdout carries the events only.
+fn replays_events(sub: &ArgMatches) -> bool {
+    sub.get_one::<String>("format").is_some_and(|format| format != "text")
+}
+
+/// File given to `replay`.
+enum Replayed {
+    Fixture(Box<Fixture>),
+    /// Events of `record --format csv`
+    Csv(Vec<LedEvent>),
+}
+
+fn load_replay(path: &Path) -> std::io::Result<Replayed> {
+    let text = (std::fs::read_to_string(path)
+        .map_err(|err| std::io::Error::new(err.kind(), format!("{}: {}", path.display(), err))))?;
+    let loaded = match is_csv(&text) {
+        true => from_csv(text.as_bytes()).map(Replayed::Csv),
+        false => Fixture::parse(&text).map(|fixture| Replayed::Fixture(Box::new(fixture))),
+    };
+    loaded.map_err(|err| std::io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))
+}
+
+fn replay(sub: &ArgMatches, report: &mut Report, json: bool) -> std::io::Result<()> {
+    let loaded = load_replay(sub.get_one::<PathBuf>("file").unwrap())?;
+    let (events, fixture) = match loaded {
+        Replayed::Fixture(ref fixture) => (&fixture.events, Some(&**fixture)),
+        Replayed::Csv(ref events) => (events, None),
+    };
+    /* like the fixture tests, independent of the local configuration */
+    let options = fixture.map_or(DeviceProfile::Classic, |fixture| fixture.profile).timing().parse_options();
+    if replays_events(sub) {
+        if json {
+            return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "replay --format vcd and csv print the events, they have no JSON output"));
+        }
+        let stdout = std::io::stdout();
+        (match sub.get_one::<String>("format").map(String::as_str) {
+            Some("csv") => to_csv(events, stdout.lock()),
+            _ => to_vcd(events, stdout.lock()),
+        })?;
+    } else if !json {
+        let lines = match fixture {
+            Some(fixture) => replay_diagnostics(fixture, &options),
+            None => replay_csv_diagnostics(events, &options),
+        };
+        for line in lines {
+            println!("{}", line);
+        }
+    }
+
+    let state = parse_led_events(events, &options).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+    report.state = Some(state);
+    if let Some(fixture) = fixture.filter(|fixture| state != fixture.expected) {
+        let message = format!("interpreted as {}, the fixture expects {}", state.name(), fixture.expected.name());
+        report.error = Some(CliError {code: ErrorCode::NotConfirmed, message});
+    }
+    Ok(())
+}
+
+/// Ask the daemon at `--socket` for its history.
+#[cfg(unix)]
+fn history(sub: &ArgMatches, report: &mut Report) -> std::io::Result<()> {
+    let client = UnixClient::connect(sub.get_one::<PathBuf>("socket").unwrap())?;
+    report.history = client.history()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn history(_sub: &ArgMatches, _report: &mut Report) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "history needs a Unix socket"))
+}
+
+/// Ask the daemon at `--socket` for its battery usage, after recording
+/// new batteries for `battery reset`.
+#[cfg(unix)]
+fn battery(sub: &ArgMatches, report: &mut Report) -> std::io::Result<()> {
+    let (action, sub) = sub.subcommand().unwrap();
+    let client = UnixClient::connect(sub.get_one::<PathBuf>("socket").unwrap())?;
+    report.battery = Some((match action {
+        "reset" => client.reset_battery(),
+        _ => client.battery(),
+    })?);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn battery(_sub: &ArgMatches, _report: &mut Report) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "battery needs a Unix socket"))
+}
+
+/// Drop the cached state, the press may change it.
+fn invalidate(cache: Option<&StateCache>) {
+    if let Some(Err(err)) = cache.map(StateCache::invalidate) {
+        log::warn!("cannot remove the state cache: {}", err);
+    }
+}
+
+fn press(cff3000: &CFF3000, command: &str, sub: &ArgMatches, report: &mut Report) -> std::io::Result<()> {
+    let (command, expected) = match command {
+        "lock" => (Command::Lock, CFF3000State::Locked),
+        "unlock" => (Command::Unlock, CFF3000State::Unlocked),
+        _ => unreachable!("{} is no press", command),
+    };
+    match sub.get_flag("verify") {
+        true => verify(report, cff3000.execute_as(command, &initiator("cli"))?, expected),
+        false => cff3000.press_as(command, &initiator("cli"))?,
+    }
+    Ok(())
+}
+
+/// Record the commands in the configured audit log, see
+/// `cff3000::audit`.
+#[cfg(all(feature = "audit", unix))]
+fn audit(builder: CFF3000Builder, config: &CFF3000Config) -> std::io::Result<CFF3000Builder> {
+    match config.audit {
+        Some(ref audit) => Ok(builder.audit(Arc::new(audit.log()?))),
+        None => Ok(builder),
+    }
+}
+
+#[cfg(not(all(feature = "audit", unix)))]
+fn audit(builder: CFF3000Builder, config: &CFF3000Config) -> std::io::Result<CFF3000Builder> {
+    if config.audit.is_some() {
+        log::warn!("the audit log needs the audit feature, commands are not recorded");
+    }
+    Ok(builder)
+}
+
+/// Send the events of the device to the journal if the output goes
+/// there, see `cff3000::journald`.
+#[cfg(all(feature = "journald", unix))]
+fn journal(builder: CFF3000Builder) -> CFF3000Builder {
+    match std::env::var_os("JOURNAL_STREAM").map(|_| Journal::new()) {
+        Some(Ok(journal)) => builder.journal(Arc::new(journal)),
+        _ => builder,
+    }
+}
+
+/// Run the subcommand, filling in `report`.
+fn execute(args: &ArgMatches, command: &str, report: &mut Report, start: Instant) -> std::io::Result<()> {
+    if command == "leds" && args.get_flag("json") {
+        /* the terminal display would corrupt the JSON output */
+        return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "leds shows the LEDs on a terminal, it has no JSON output"));
+    }
+    if command == "config" && args.get_flag("json") {
+        return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "config show prints TOML, it has no JSON output"));
+    }
+    let sub = args.subcommand_matches(command).unwrap();
+    if command == "replay" {
+        return replay(sub, report, args.get_flag("json"));
+    }
+    if command == "history" {
+        return history(sub, report);
+    }
+    if command == "battery" {
+        return battery(sub, report);
+    }
+    let sources = sources(args)?;
+    if command == "config" {
+        return show_config(&sources);
+    }
+    let config = sources.config()?;
+
+    let cache = match command {
+        "status" if sub.get_flag("no-cache") => None,
+        "status" | "lock" | "unlock" => StateCache::for_device(&config.chip, config.pins),
+        _ => None,
+    };
+    if command == "status" {
+        /* answer from the cache without opening the device */
+        let ttl = sub.get_one::<u64>("cache-ttl").map(|&secs| Duration::from_secs(secs));
+        if let Some(cached) = ttl.and_then(|ttl| cache.as_ref().and_then(StateCache::load).filter(|cached| cached.is_fresh(ttl))) {
+            report.state = Some(cached.state);
+            report.cached = true;
+            return Ok(());
+        }
+    }
+    let dry_run = args.get_flag("dry-run");
+    let cache = if dry_run && command != "status" {None} else {cache};
+    if command == "lock" || command == "unlock" {
+        invalidate(cache.as_ref());
+    }
+    let mut builder = CFF3000Builder::from_config(&config).dry_run(dry_run);
+    if dry_run {
+        builder = builder.monitor(|notice| eprintln!("cff3000: {}", notice));
+    }
+    #[cfg(all(feature = "journald", unix))]
+    let builder = journal(builder);
+    let cff3000 = audit(builder, &config)?.build()?;
+
+    match command {
+        "lock" | "unlock" => {
+            let result = press(&cff3000, command, sub, report);
+            /* a status query may have refreshed the cache in between */
+            invalidate(cache.as_ref());
+            result?
+        },
+        "check" => cff3000.press_as(Command::Check, &initiator("cli"))?,
+        "record" => record(&cff3000, &config, sub, report)?,
+        "status" => {
+            let state = cff3000.execute_as(Command::Check, &initiator("cli"))?;
+            report.state = Some(state);
+            if let Some(Err(err)) = cache.map(|cache| cache.store(&CachedState::now(state))) {
+                log::warn!("cannot update the state cache: {}", err);
+            }
+        },
+        "leds" => {
+            let default = timings::SUGGESTED_FEEDBACK_DISPLAY.as_secs() as u8;
+            cff3000.show_leds(sub.get_one::<u8>("seconds").cloned().unwrap_or(default))?
+        },
+        "watch" => watch(&cff3000, &config, sub, args.get_flag("json"), start)?,
+        "rpc" => rpc(cff3000, &config, sub)?,
+        _ => unreachable!("unknown subcommand {}", command),
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = match command().try_get_matches() {
+        Ok(args) => args,
+        Err(err) => {
+            /* clap exits with 2, which means manual for status */
+            let _ = err.print();
+            std::process::exit(if err.use_stderr() {EXIT_USAGE} else {0})
+        },
+    };
+    let mut stdout = std::io::stdout();
+    if args.get_flag("generate-man") {
+        if let Err(err) = write_man_page(&mut stdout) {
+            eprintln!("cff3000: {}", err);
+            std::process::exit(ErrorCode::Io.exit_code())
+        }
+        return;
+    }
+    let command = match args.subcommand_name() {
+        Some(command) => command.to_string(),
+        None => {
+            let _ = cff3000::cli::command().error(clap::error::ErrorKind::MissingSubcommand, "a subcommand is required").print();
+            std::process::exit(EXIT_USAGE)
+        },
+    };
+    if command == "completions" {
+        let shell = args.subcommand_matches("completions").unwrap().get_one::<Shell>("shell").unwrap();
+        write_completions(*shell, &mut stdout);
+        return;
+    }
+
+    /* only now, clap and the early exits above print on their own */
+    if let Err(err) = init_logging(log_level(args.get_count("verbose"), args.get_count("quiet"))) {
+        eprintln!("cff3000: {}", err);
+    }
+
+    let start = Instant::now();
+    let mut report = Report::new(&command, start.elapsed());
+    if let Err(err) = execute(&args, &command, &mut report, start) {
+        report.error = Some(CliError::from(err));
+    }
+    report.duration = start.elapsed();
+
+    match command.as_str() {
+        /* watch has printed its changes already */
+        "watch" if report.error.is_none() => {},
+        /* stdout carries the protocol or the waveform */
+        "rpc" => if let Some(ref error) = report.error {
+            eprintln!("cff3000: {}", error.message);
+        },
+        "replay" if !args.get_flag("json") && args.subcommand_matches("replay").is_some_and(replays_events) => if let Some(ref error) = report.error {
+            eprintln!("cff3000: {}", error.message);
+        },
+        _ => print(&report, args.get_flag("json")),
+    }
+    std::process::exit(report.exit_code())
+}
@@ -0,0 +1,543 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Configurable construction of `CFF3000` devices.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+#[cfg(all(feature = "audit", unix))]
+use crate::audit::AuditLog;
+#[cfg(all(feature = "journald", unix))]
+use crate::journald::Journal;
+#[cfg(all(feature = "inotify", target_os = "linux"))]
+use crate::devwatch;
+use crate::interlock::{BusyPolicy, Interlock, Radio};
+use crate::battery::{BatteryCounter, BatteryOptions};
+use crate::cache::StateCache;
+use crate::door::{self, DoorInput, DoorSensor, DoorSensorOptions};
+use crate::eventdump::DEFAULT_EVENT_DUMP_LIMIT;
+use crate::group::GroupMonitor;
+use crate::health::{self, HealthOptions, Probe};
+use crate::history::{History, DEFAULT_HISTORY_CAPACITY};
+use crate::lockfile::LockFile;
+#[cfg(feature = "metrics")]
+use crate::metrics::Metrics;
+use crate::notice::Monitor;
+use crate::telemetry::{Hooks, Telemetry};
+use crate::{backend, Clock, CFF3000, DeviceProfile, GpioBackend, Notice, ParseOptions, PinAssignment, Polarities, SharedClock, SystemClock, Timings};
+
+enum Source {
+    Chip {chipdev: String, pins: PinAssignment},
+    Backend(Arc<dyn GpioBackend>),
+    Opener {name: String, open: backend::Opener},
+}
+
+/// Line of the door sensor.
+enum DoorLine {
+    /// Offset on the chip of `Source::Chip`
+    Offset(u32),
+    Input(Arc<dyn DoorInput>),
+}
+
+/// Builder for `CFF3000` with non-default settings.
+///
+/// `CFF3000::new_with_pins(chipdev, pins)` is equivalent to
+/// `CFF3000Builder::with_pins(chipdev, pins).build()`.
+pub struct CFF3000Builder {
+    source: Source,
+    label: Option<String>,
+    busy_policy: BusyPolicy,
+    /// Shared with the other devices of a `CFF3000Group`
+    radio: Option<Arc<Radio>>,
+    lockfile: Option<PathBuf>,
+    parse_options: Option<ParseOptions>,
+    profile: Option<DeviceProfile>,
+    timings: Timings,
+    polarities: Polarities,
+    reopen_timeout: Option<Duration>,
+    dry_run: bool,
+    monitor: Option<Monitor>,
+    clock: SharedClock,
+    state_cache: Option<Duration>,
+    history: usize,
+    battery: BatteryOptions,
+    door: Option<(DoorLine, DoorSensorOptions)>,
+    health: Option<HealthOptions>,
+    telemetry: Hooks,
+    event_dump: usize,
+    #[cfg(feature = "metrics")]
+    metrics: Option<Arc<Metrics>>,
+    #[cfg(all(feature = "audit", unix))]
+    audit: Option<Arc<AuditLog>>,
+    #[cfg(all(feature = "journald", unix))]
+    journal: Option<Arc<Journal>>,
+    #[cfg(all(feature = "inotify", target_os = "linux"))]
+    device_node: Option<PathBuf>,
+}
+
+impl CFF3000Builder {
+    /// Start building a device, see `CFF3000::new()` for the arguments.
+    pub fn new(chipdev: &str, gpios: [u32; 4]) -> CFF3000Builder {
+        CFF3000Builder::with_pins(chipdev, PinAssignment::from(gpios))
+    }
+
+    /// Start building a device using the lines `pins` of `chipdev`.
+    pub fn with_pins(chipdev: &str, pins: PinAssignment) -> CFF3000Builder {
+        CFF3000Builder::from_source(Source::Chip {chipdev: chipdev.to_string(), pins})
+    }
+
+    /// Start building a device using a custom GPIO backend.
+    pub fn with_backend<B: GpioBackend + 'static>(backend: B) -> CFF3000Builder {
+        CFF3000Builder::from_source(Source::Backend(Arc::new(backend)))
+    }
+
+    /// Start building a device using custom GPIO backends created by
+    /// `open`. Unlike with `with_backend()`, `auto_reopen()` applies:
+    /// `open` is called again whenever the device is lost, a reconnect
+    /// is reported for `name`.
+    pub fn with_opener<B, F>(name: &str, open: F) -> CFF3000Builder
+        where B: GpioBackend + 'static, F: Fn() -> std::io::Result<B> + Send + Sync + 'static
+    {
+        let open: backend::Opener = Arc::new(move || Ok(Arc::new(open()?) as Arc<dyn GpioBackend>));
+        CFF3000Builder::from_source(Source::Opener {name: name.to_string(), open})
+    }
+
+    fn from_source(source: Source) -> CFF3000Builder {
+        CFF3000Builder {
+            source,
+            label: None,
+            busy_policy: BusyPolicy::Wait,
+            radio: None,
+            lockfile: None,
+            parse_options: None,
+            profile: None,
+            timings: Timings::default(),
+            polarities: Polarities::default(),
+            reopen_timeout: None,
+            dry_run: false,
+            monitor: None,
+            clock: Arc::new(SystemClock),
+            state_cache: None,
+            history: DEFAULT_HISTORY_CAPACITY,
+            battery: BatteryOptions::default(),
+            door: None,
+            health: None,
+            telemetry: Hooks::default(),
+            event_dump: DEFAULT_EVENT_DUMP_LIMIT,
+            #[cfg(feature = "metrics")]
+            metrics: None,
+            #[cfg(all(feature = "audit", unix))]
+            audit: None,
+            #[cfg(all(feature = "journald", unix))]
+            journal: None,
+            #[cfg(all(feature = "inotify", target_os = "linux"))]
+            device_node: None,
+        }
+    }
+
+    /// Name the device in the spans of its operations, see `spans`
+    /// (default: the chip node of `new()`, none for custom backends).
+    pub fn label(mut self, label: &str) -> CFF3000Builder {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    /// Join the group sharing `radio` as `name`: the name labels the
+    /// device unless `label()` did, and `monitor` gets its notices after
+    /// the monitor of the device.
+    pub(crate) fn in_group(mut self, name: &str, radio: Arc<Radio>, monitor: Option<GroupMonitor>) -> CFF3000Builder {
+        self.radio = Some(radio);
+        if self.label.is_none() {
+            self.label = Some(name.to_string());
+        }
+        if let Some(monitor) = monitor {
+            let (own, name) = (self.monitor.take(), name.to_string());
+            self.monitor = Some(Arc::new(move |notice: &Notice| {
+                if let Some(ref own) = own {
+                    own(notice);
+                }
+                monitor(&name, notice);
+            }));
+        }
+        self
+    }
+
+    /// Select what happens when an operation is started while another
+    /// one is still running (default: `BusyPolicy::Wait`).
+    pub fn busy_policy(mut self, policy: BusyPolicy) -> CFF3000Builder {
+        self.busy_policy = policy;
+        self
+    }
+
+    /// Hold an exclusive `flock(2)` on `path` for the lifetime of the
+    /// device, so other processes using the same lock file fail with
+    /// `AlreadyInUse` instead of corrupting each other's captures.
+    ///
+    /// The file is created if needed and never removed. A lock left
+    /// behind by a crashed process is released by the kernel.
+    pub fn exclusive_lockfile<P: AsRef<Path>>(mut self, path: P) -> CFF3000Builder {
+        self.lockfile = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Override the LED pattern parser settings (default: provided by
+    /// the GPIO backend).
+    pub fn parse_options(mut self, options: ParseOptions) -> CFF3000Builder {
+        self.parse_options = Some(options);
+        self
+    }
+
+    /// Use the timings of a firmware generation (default:
+    /// `DeviceProfile::Classic`). This sets the press durations and
+    /// capture windows, so call it before `timings()` or the
+    /// per-operation methods to adjust single values. The merge window
+    /// of the parser becomes the larger of the profile's and the
+    /// backend's, unless set with `parse_options()`.
+    pub fn profile(mut self, profile: DeviceProfile) -> CFF3000Builder {
+        self.timings = profile.timing().timings;
+        self.profile = Some(profile);
+        self
+    }
+
+    /// Override the press durations and capture windows (default:
+    /// `Timings::default()`). This replaces the values of earlier
+    /// calls to the per-operation methods such as `lock_press()`.
+    pub fn timings(mut self, timings: Timings) -> CFF3000Builder {
+        self.timings = timings;
+        self
+    }
+
+    /// Hold the lock button for `duration` (default: `Timings::press`).
+    pub fn lock_press(mut self, duration: Duration) -> CFF3000Builder {
+        self.timings.lock_press = Some(duration);
+        self
+    }
+
+    /// Hold the unlock button for `duration` (default:
+    /// `Timings::press`).
+    pub fn unlock_press(mut self, duration: Duration) -> CFF3000Builder {
+        self.timings.unlock_press = Some(duration);
+        self
+    }
+
+    /// Hold both buttons of a check for `duration` (default:
+    /// `Timings::press`).
+    pub fn check_press(mut self, duration: Duration) -> CFF3000Builder {
+        self.timings.check_press = Some(duration);
+        self
+    }
+
+    /// Capture the confirmation of `lock_and_verify()` for `window`
+    /// (default: `Timings::command_capture`).
+    pub fn lock_capture(mut self, window: Duration) -> CFF3000Builder {
+        self.timings.lock_capture = Some(window);
+        self
+    }
+
+    /// Capture the confirmation of `unlock_and_verify()` for `window`
+    /// (default: `Timings::command_capture`), e.g. if the blinking
+    /// starts later after an unlock.
+    pub fn unlock_capture(mut self, window: Duration) -> CFF3000Builder {
+        self.timings.unlock_capture = Some(window);
+        self
+    }
+
+    /// Treat the lines marked `Polarity::ActiveLow` as lit or pressed
+    /// when low (default: all active high). This also applies to
+    /// custom backends. Active-low buttons are driven high by `build()`,
+    /// after having been low for a moment when the lines are requested.
+    pub fn polarities(mut self, polarities: Polarities) -> CFF3000Builder {
+        self.polarities = polarities;
+        self
+    }
+
+    /// Recover from the GPIO chip disappearing (e.g. a re-enumerated USB
+    /// GPIO adapter). When an operation fails with `ENODEV` or `EIO`,
+    /// the lines are released and the device node is awaited for up to
+    /// `timeout`, at the same path or at any path with the same chip
+    /// label. All four lines are then requested again and the failed
+    /// operation is retried once. A successful reopen is reported as
+    /// `Notice::Reconnected`; the capture in progress usually fails,
+    /// since the LED events pending at that time are lost.
+    ///
+    /// Only applies to devices built with `new()` or `with_opener()`,
+    /// other custom backends have to handle this themselves.
+    pub fn auto_reopen(mut self, timeout: Duration) -> CFF3000Builder {
+        self.reopen_timeout = Some(timeout);
+        self
+    }
+
+    /// Only pretend to press buttons (default: off). Every write to a
+    /// button line is reported as `Notice::DryRun` to the `monitor()`
+    /// callback instead of being performed, so `lock()`, `unlock()`,
+    /// `check()` and everything built on them never change the door.
+    ///
+    /// Devices built with `new()` request only the LED lines, the
+    /// button lines are left untouched. Queries still capture the
+    /// LEDs, which usually stay off without a press. If the LED lines
+    /// cannot be requested (e.g. because they are in use), `build()`
+    /// still succeeds and reading the LEDs fails. `auto_reopen()` is
+    /// ignored. Custom backends have requested their lines already,
+    /// their `set_button()` is never called.
+    pub fn dry_run(mut self, enabled: bool) -> CFF3000Builder {
+        self.dry_run = enabled;
+        self
+    }
+
+    /// Call `callback` for every `Notice`, e.g. to log reconnects. It
+    /// may run on any thread and should return quickly.
+    pub fn monitor<F: Fn(&Notice) + Send + Sync + 'static>(mut self, callback: F) -> CFF3000Builder {
+        self.monitor = Some(Arc::new(callback));
+        self
+    }
+
+    /// Take the time for press durations, capture windows, reopen
+    /// retries and watch intervals from `clock` (default:
+    /// `SystemClock`), e.g. a `testing::TestClock` to run them without
+    /// real waits.
+    pub fn clock<C: Clock + Send + Sync + 'static>(mut self, clock: C) -> CFF3000Builder {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Answer `CFF3000::state()` with the last verified state while it is
+    /// younger than `ttl`, instead of pressing both buttons again (default:
+    /// every call queries). See `CFF3000::cached_state()`.
+    pub fn state_cache(mut self, ttl: Duration) -> CFF3000Builder {
+        self.state_cache = Some(ttl);
+        self
+    }
+
+    /// Keep the last `capacity` operations for `CFF3000::history()`
+    /// (default: `DEFAULT_HISTORY_CAPACITY`), 0 to keep none.
+    pub fn history(mut self, capacity: usize) -> CFF3000Builder {
+        self.history = capacity;
+        self
+    }
+
+    /// Estimate the batteries of the CFF3000 with `options` for
+    /// `CFF3000::battery_usage()` (default: `BatteryOptions::default()`).
+    pub fn battery(mut self, options: BatteryOptions) -> CFF3000Builder {
+        self.battery = options;
+        self
+    }
+
+    /// Read a door sensor on the line `offset` of the chip, see the
+    /// `door` module. The line is requested by `build()` and not
+    /// reopened by `auto_reopen()`. Custom backends use `door_input()`
+    /// instead, `build()` fails with `ErrorKind::InvalidInput` for them.
+    pub fn door_sensor(mut self, offset: u32, options: DoorSensorOptions) -> CFF3000Builder {
+        self.door = Some((DoorLine::Offset(offset), options));
+        self
+    }
+
+    /// Read a door sensor on `input`, see the `door` module.
+    pub fn door_input<I: DoorInput + 'static>(mut self, input: I, options: DoorSensorOptions) -> CFF3000Builder {
+        self.door = Some((DoorLine::Input(Arc::new(input)), options));
+        self
+    }
+
+    /// Probe the lines every `HealthOptions::interval` on a thread of
+    /// the device, see the `health` module (default: off).
+    pub fn health_check(mut self, options: HealthOptions) -> CFF3000Builder {
+        self.health = Some(options);
+        self
+    }
+
+    /// Call `hook` at the end of every operation, see the `telemetry`
+    /// module. Hooks of several calls are all called, in this order.
+    pub fn telemetry<T: Telemetry + 'static>(mut self, hook: T) -> CFF3000Builder {
+        self.telemetry.add(Arc::new(hook));
+        self
+    }
+
+    /// Log the first and the last `limit` LED events of every capture
+    /// at the `trace` level and only a summary of the ones in between
+    /// (default: `DEFAULT_EVENT_DUMP_LIMIT`), see the `eventdump` module.
+    pub fn event_dump(mut self, limit: usize) -> CFF3000Builder {
+        self.event_dump = limit;
+        self
+    }
+
+    /// Record the presses and state queries in `metrics`, see the
+    /// `metrics` module. The same `Metrics` may be shared with a
+    /// scraping endpoint.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> CFF3000Builder {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Record the operations started with `CFF3000::execute_as()` and
+    /// `CFF3000::press_as()` in `log`, see the `audit` module.
+    #[cfg(all(feature = "audit", unix))]
+    pub fn audit(mut self, log: Arc<AuditLog>) -> CFF3000Builder {
+        self.audit = Some(log);
+        self
+    }
+
+    /// Send the presses, classified states and failures to `journal`,
+    /// see the `journald` module.
+    #[cfg(all(feature = "journald", unix))]
+    pub fn journal(mut self, journal: Arc<Journal>) -> CFF3000Builder {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Report the removal of the device node at `path` (usually the
+    /// `chipdev` passed to `new()`) as `Notice::DeviceLost` as soon as it
+    /// happens, instead of only failing the next operation.
+    ///
+    /// The notice is purely informational, operations are not aborted.
+    ///
+    /// # Example
+    /// ```
+    /// use cff3000::mock::MockBackend;
+    /// use cff3000::{CFF3000Builder, Notice};
+    /// use std::sync::{mpsc, Mutex};
+    /// use std::time::Duration;
+    ///
+    /// fn main() {
+    ///     let node = std::env::temp_dir().join(format!("cff3000-devwatch-{}", std::process::id()));
+    ///     std::fs::write(&node, b"").unwrap();
+    ///
+    ///     let (tx, rx) = mpsc::channel();
+    ///     let tx = Mutex::new(tx);
+    ///     let _cff3000 = CFF3000Builder::with_backend(MockBackend::new())
+    ///         .watch_device_node(&node)
+    ///         .monitor(move |notice| { let _ = tx.lock().unwrap().send(notice.clone()); })
+    ///         .build()
+    ///         .unwrap();
+    ///
+    ///     std::fs::remove_file(&node).unwrap();
+    ///     let notice = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    ///     assert_eq!(notice, Notice::DeviceLost {chipdev: node.to_string_lossy().into_owned()});
+    /// }
+    /// ```
+    #[cfg(all(feature = "inotify", target_os = "linux"))]
+    pub fn watch_device_node<P: AsRef<Path>>(mut self, path: P) -> CFF3000Builder {
+        self.device_node = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Request the GPIO lines (if not done by a custom backend) and
+    /// create the device.
+    pub fn build(self) -> std::io::Result<CFF3000> {
+        let lockfile = match self.lockfile {
+            Some(ref path) => Some(LockFile::acquire(path)?),
+            None => None,
+        };
+        let mut reopening = None;
+        let backend: Arc<dyn GpioBackend> = match self.source {
+            Source::Chip {ref chipdev, pins} if self.dry_run => {
+                let inputs = backend::open_inputs(chipdev, pins.to_array());
+                Arc::new(backend::DryRunBackend::new(inputs, Some(chipdev.clone()), Some(pins), self.polarities, self.monitor.clone(), self.clock.clone()))
+            },
+            Source::Backend(ref backend) if self.dry_run => {
+                Arc::new(backend::DryRunBackend::new(Ok(backend.clone()), None, None, self.polarities, self.monitor.clone(), self.clock.clone()))
+            },
+            Source::Opener {ref open, ..} if self.dry_run => {
+                Arc::new(backend::DryRunBackend::new(open(), None, None, self.polarities, self.monitor.clone(), self.clock.clone()))
+            },
+            Source::Chip {ref chipdev, pins} => match self.reopen_timeout {
+                Some(timeout) => {
+                    let backend = Arc::new(backend::ReopeningBackend::new(chipdev, pins.to_array(), timeout, self.monitor.clone(), self.clock.clone())?);
+                    reopening = Some(backend.clone());
+                    backend
+                },
+                None => backend::open_chip(chipdev, pins.to_array())?,
+            },
+            Source::Opener {ref name, ref open} => match self.reopen_timeout {
+                Some(timeout) => {
+                    let backend = Arc::new(backend::ReopeningBackend::with_opener(name, open.clone(), timeout, self.monitor.clone(), self.clock.clone())?);
+                    reopening = Some(backend.clone());
+                    backend
+                },
+                None => open()?,
+            },
+            Source::Backend(ref backend) => backend.clone(),
+        };
+        let pins = match self.source {
+            Source::Chip {pins, ..} => Some(pins),
+            Source::Backend(_) | Source::Opener {..} => None,
+        };
+        let backend: Arc<dyn GpioBackend> = Arc::new(backend::LoggingBackend::new(backend, pins));
+        let backend: Arc<dyn GpioBackend> = match self.polarities == Polarities::default() {
+            true => backend,
+            false => Arc::new(backend::InvertingBackend::new(backend, self.polarities)?),
+        };
+        #[cfg(all(feature = "inotify", target_os = "linux"))]
+        let device_watch = match self.device_node {
+            Some(ref path) => Some(devwatch::watch(path, self.monitor.clone())?),
+            None => None,
+        };
+        let door = match self.door {
+            Some((DoorLine::Input(ref input), options)) => Some((input.clone(), options)),
+            Some((DoorLine::Offset(offset), options)) => match self.source {
+                Source::Chip {ref chipdev, ..} => Some((door::open_line(chipdev, offset)?, options)),
+                Source::Backend(_) | Source::Opener {..} => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                    "a door sensor line needs a GPIO chip, use door_input() with custom backends")),
+            },
+            None => None,
+        };
+        let door = door.map(|(input, options)| DoorSensor::new(input, options, self.clock.clone(), self.monitor.clone()));
+        let profile = self.profile;
+        let parse_options = self.parse_options.unwrap_or_else(|| {
+            let mut options = backend.parse_options();
+            if let Some(profile) = profile {
+                /* the backend may need a wider window for its timestamp accuracy */
+                options.merge_window = options.merge_window.max(profile.timing().merge_window);
+            }
+            options
+        });
+        let interlock = Interlock::new(self.busy_policy, self.radio.clone());
+        let chipdev = match self.source {
+            Source::Chip {ref chipdev, ..} => Some(chipdev.clone()),
+            Source::Backend(_) | Source::Opener {..} => None,
+        };
+        let health = match self.health {
+            Some(options) => Some(health::start(Probe {
+                backend: backend.clone(),
+                interlock: interlock.clone(),
+                clock: self.clock.clone(),
+                chipdev: chipdev.clone(),
+                polarities: self.polarities,
+                buttons: !self.dry_run,
+                reopen: reopening,
+                monitor: self.monitor.clone(),
+                options,
+            })?),
+            None => None,
+        };
+        Ok(CFF3000 {
+            backend,
+            label: self.label.or_else(|| chipdev.clone()),
+            chipdev,
+            parse_options,
+            timings: self.timings,
+            polarities: self.polarities,
+            dry_run: self.dry_run,
+            clock: self.clock,
+            cache: self.state_cache.map(StateCache::new),
+            history: History::new(self.history),
+            battery: Arc::new(BatteryCounter::new(self.battery, SystemTime::now())),
+            door,
+            monitor: self.monitor,
+            health,
+            telemetry: self.telemetry,
+            event_dump: self.event_dump,
+            interlock,
+            #[cfg(feature = "metrics")]
+            metrics: self.metrics,
+            #[cfg(all(feature = "audit", unix))]
+            audit: self.audit,
+            #[cfg(all(feature = "journald", unix))]
+            journal: self.journal,
+            _lockfile: lockfile,
+            #[cfg(all(feature = "inotify", target_os = "linux"))]
+            _device_watch: device_watch,
+        })
+    }
+}
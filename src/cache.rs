@@ -0,0 +1,56 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Last verified state of a device, see `CFF3000Builder::state_cache()`.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::CFF3000State;
+
+/// State with its age, returned by `CFF3000::cached_state()`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CachedState {
+    pub state: CFF3000State,
+    /// Time since the state has been verified, zero for a new query
+    pub age: Duration,
+    /// Returns true if the state is taken from the cache, without
+    /// pressing any button
+    pub cached: bool,
+}
+
+/// Last state verified by a state query or a verified lock or unlock.
+pub(crate) struct StateCache {
+    ttl: Duration,
+    last: Mutex<Option<(CFF3000State, Instant)>>,
+}
+
+impl StateCache {
+    pub(crate) fn new(ttl: Duration) -> StateCache {
+        StateCache {ttl, last: Mutex::new(None)}
+    }
+
+    pub(crate) fn store(&self, state: CFF3000State, at: Instant) {
+        *self.last.lock().unwrap_or_else(|e| e.into_inner()) = Some((state, at));
+    }
+
+    /// Store `state` unless a state is stored already.
+    pub(crate) fn restore(&self, state: CFF3000State, at: Instant) {
+        self.last.lock().unwrap_or_else(|e| e.into_inner()).get_or_insert((state, at));
+    }
+
+    pub(crate) fn invalidate(&self) {
+        *self.last.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+
+    /// Last state at `now`, however old.
+    pub(crate) fn last(&self, now: Instant) -> Option<CachedState> {
+        let last = *self.last.lock().unwrap_or_else(|e| e.into_inner());
+        last.map(|(state, at)| CachedState {state, age: now.saturating_duration_since(at), cached: true})
+    }
+
+    /// Last state at `now` if it is younger than the TTL.
+    pub(crate) fn fresh(&self, now: Instant) -> Option<CachedState> {
+        self.last(now).filter(|cached| cached.age < self.ttl)
+    }
+}
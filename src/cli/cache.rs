@@ -0,0 +1,122 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Last state shown by a device, shared between invocations of the
+//! binary, see `StateCache`.
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{CFF3000State, PinAssignment};
+
+/// A state with the time it has been captured.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CachedState {
+    pub state: CFF3000State,
+    pub timestamp: SystemTime,
+}
+
+impl CachedState {
+    /// `state`, captured just now.
+    pub fn now(state: CFF3000State) -> CachedState {
+        CachedState {state, timestamp: SystemTime::now()}
+    }
+
+    /// Time since the capture, `None` if the timestamp is in the future
+    /// (e.g. after the clock has been set back).
+    pub fn age(&self) -> Option<Duration> {
+        SystemTime::now().duration_since(self.timestamp).ok()
+    }
+
+    /// Whether the capture is less than `ttl` old.
+    pub fn is_fresh(&self, ttl: Duration) -> bool {
+        self.age().is_some_and(|age| age < ttl)
+    }
+}
+
+/// Cache file of the last state of one device, used by
+/// `cff3000 status --cache-ttl`.
+///
+/// The file holds one line: the state name and the capture time in
+/// milliseconds since the Unix epoch, e.g. `locked 1760000000123`.
+/// It is replaced by renaming a temporary file, so concurrent
+/// invocations read either the old or the new state, never a mix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateCache {
+    path: PathBuf,
+}
+
+/// `$XDG_CACHE_HOME/cff3000`, or `~/.cache/cff3000` if
+/// `XDG_CACHE_HOME` is unset. `None` if neither variable is set.
+pub fn cache_dir() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME").filter(|dir| !dir.is_empty())?).join(".cache"),
+    };
+    Some(dir.join("cff3000"))
+}
+
+/// Number of `StateCache::store()` calls, part of the temporary name.
+static WRITERS: AtomicUsize = AtomicUsize::new(0);
+
+impl StateCache {
+    /// Cache in the file at `path`.
+    pub fn new<P: AsRef<Path>>(path: P) -> StateCache {
+        StateCache {path: path.as_ref().to_path_buf()}
+    }
+
+    /// Cache of the device on `chip` with `pins` in `cache_dir()`, e.g.
+    /// `~/.cache/cff3000/dev-gpiochip2_2-3-4-5.state`.
+    pub fn for_device(chip: &str, pins: PinAssignment) -> Option<StateCache> {
+        let chip: String = chip.chars().map(|c| if c.is_ascii_alphanumeric() {c} else {'-'}).collect();
+        let pins: Vec<String> = pins.to_array().iter().map(u32::to_string).collect();
+        let name = format!("{}_{}.state", chip.trim_matches('-'), pins.join("-"));
+        cache_dir().map(|dir| StateCache::new(dir.join(name)))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The cached state, `None` if there is none or the file is not
+    /// readable or corrupt.
+    pub fn load(&self) -> Option<CachedState> {
+        let text = std::fs::read_to_string(&self.path).ok()?;
+        let mut fields = text.split_whitespace();
+        let state = CFF3000State::from_name(fields.next()?)?;
+        let millis: u64 = fields.next()?.parse().ok()?;
+        if fields.next().is_some() {
+            return None;
+        }
+        Some(CachedState {state, timestamp: UNIX_EPOCH + Duration::from_millis(millis)})
+    }
+
+    /// Replace the cached state, creating the cache directory if needed.
+    pub fn store(&self, cached: &CachedState) -> std::io::Result<()> {
+        let since_epoch = cached.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let millis = since_epoch.as_secs() * 1000 + since_epoch.subsec_millis() as u64;
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        /* unique per writer, so concurrent writers do not share it */
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(format!(".{}-{}.tmp", std::process::id(), WRITERS.fetch_add(1, Ordering::Relaxed)));
+        let temporary = PathBuf::from(temporary);
+        std::fs::write(&temporary, format!("{} {}\n", cached.state.name(), millis))?;
+        std::fs::rename(&temporary, &self.path).inspect_err(|_| {
+            let _ = std::fs::remove_file(&temporary);
+        })
+    }
+
+    /// Remove the cached state, e.g. because the door has been locked
+    /// or unlocked. A missing file is not an error.
+    pub fn invalidate(&self) -> std::io::Result<()> {
+        match std::fs::remove_file(&self.path) {
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
@@ -0,0 +1,501 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Results of the `cff3000` command line tool (`cli` feature).
+//!
+//! The binary only parses its arguments, defined by [`command()`], and
+//! calls the library, every command ends with a [`Report`]. It is
+//! printed for humans by default or, with `--json`, as a single JSON
+//! object on one line of stdout:
+//!
+//! ```text
+//! {"command":"status","state":"locked","duration_ms":3120}
+//! {"command":"status","state":"locked","cached":true,"duration_ms":1}
+//! {"command":"lock","duration_ms":502}
+//! {"command":"lock","state":"unlocked","error":{"code":"not-confirmed","message":"not confirmed, the device shows unlocked"},"duration_ms":10498}
+//! {"command":"status","error":{"code":"busy","message":"Device or resource busy (os error 16)"},"duration_ms":3}
+//! {"command":"watch","state":"unlocked","previous":"locked","trigger":"poll","duration_ms":600412}
+//! {"command":"history","history":[{"timestamp_ms":1760000000123,"command":"lock","state":"locked","duration_ms":10204}],"duration_ms":4}
+//! {"command":"battery","battery":{"since_ms":1760000000123,"replaced":true,"commands":1210,"elapsed_ms":8640000000,"budget":5000,"warning":false,"per_day":12.1,"remaining":3790,"days_left":313.2},"duration_ms":3}
+//! ```
+//!
+//! `watch` prints one object per state change as it happens and only
+//! ends with a report if it fails.
+//!
+//! | Key | Type | Present |
+//! |-----|------|---------|
+//! | `command` | string: `lock`, `unlock`, `check`, `status`, `leds`, `watch`, `record`, `replay`, `config`, `history` or `battery` | always |
+//! | `state` | string: `locked`, `unlocked`, `manual` or `out-of-range` | if the command captured (or `replay` interpreted) the LED pattern |
+//! | `previous` | string, same values as `state` | `watch`, except for the first state |
+//! | `trigger` | string: `poll` or `auto-lock` | `watch` |
+//! | `cached` | `true`: `state` is from the [`StateCache`] | `status --cache-ttl` with a fresh cache |
+//! | `history` | array of the daemon's operations, see `history` | `history`, unless it failed |
+//! | `battery` | object, the daemon's commands on the batteries, see `battery` | `battery show` and `battery reset`, unless they failed |
+//! | `error.code` | string, see [`ErrorCode`] | if the command failed |
+//! | `error.message` | string for humans, may change | if the command failed |
+//! | `duration_ms` | integer, run time of the command (until the change for `watch`) | always |
+//!
+//! Keys are only ever added, never renamed or removed. Invalid command
+//! line arguments are reported by the argument parser on stderr, not as
+//! JSON. `leds` draws on the terminal and `config show` prints TOML,
+//! both fail with `unsupported` in JSON mode.
+//!
+//! # Exit status
+//!
+//! `Report::exit_code()` is the exit status of the binary, so scripts
+//! can branch on the door state without parsing output. The mapping is
+//! stable and printed by `cff3000 --help`:
+//!
+//! | Status | Meaning |
+//! |--------|---------|
+//! | 0 | success (`watch`: stopped by a signal or `--max-events`); `status`: locked |
+//! | 1 | `status`: unlocked |
+//! | 2 | `status`: manual |
+//! | 3 | `status`: out of range |
+//! | 4 | `no-response`: the LEDs did not show a pattern |
+//! | 10 | invalid command line arguments |
+//! | 11 | `config` |
+//! | 12 | `not-found` |
+//! | 13 | `permission-denied` |
+//! | 14 | `busy` |
+//! | 15 | `invalid-pattern` |
+//! | 16 | `not-confirmed` |
+//! | 17 | `unsupported` |
+//! | 18 | `io` |
+//!
+//! # JSON-RPC
+//!
+//! `cff3000 rpc` is for supervisors which spawn the tool and talk to it
+//! over a pipe: it answers JSON-RPC 2.0 requests, one per line on
+//! stdin, with one line per response on stdout until stdin is closed
+//! (see [`serve_rpc()`]). Batches are answered with one line as well.
+//!
+//! ```text
+//! → {"jsonrpc":"2.0","id":1,"method":"state"}
+//! ← {"jsonrpc":"2.0","id":1,"result":{"state":"locked"}}
+//! → {"jsonrpc":"2.0","id":2,"method":"unlock"}
+//! ← {"jsonrpc":"2.0","id":2,"error":{"code":4,"message":"did not receive enough LED change events","data":{"code":"no-response"}}}
+//! → {"jsonrpc":"2.0","id":3,"method":"subscribe"}
+//! ← {"jsonrpc":"2.0","id":3,"result":true}
+//! ← {"jsonrpc":"2.0","method":"state_changed","params":{"state":"locked","previous":null,"trigger":"poll","at_ms":1760000000123}}
+//! ```
+//!
+//! | Method | Result |
+//! |--------|--------|
+//! | `lock`, `unlock` | `{"state": ...}` confirmed by the LEDs, `not-confirmed` if it is not the new state |
+//! | `state` | `{"state": ...}` queried from the device |
+//! | `subscribe` | `true`, then a `state_changed` notification with the `StateChange` of every change seen by a watch loop like `cff3000 watch` |
+//!
+//! None of them takes parameters. The requests go through one
+//! `CommandQueue` in the order they are read, a response is written
+//! once its command is done, so they may come out of order. Failed
+//! commands answer with the exit status of the error as `error.code`
+//! and its name in `error.data.code`, plus the shown state in
+//! `error.data.state` for `not-confirmed`. If the watch loop gives up,
+//! a `subscription_failed` notification with `code` and `message`
+//! follows, another `subscribe` restarts it. Malformed lines get the
+//! standard JSON-RPC errors: -32700 for invalid JSON, -32600 for
+//! invalid requests and lines longer than `MAX_FRAME`, -32601 for
+//! unknown methods and -32602 for parameters.
+
+use std::fmt::Write;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{value_parser, Arg, ArgAction, ValueHint};
+use clap_complete::Shell;
+
+use crate::battery::BatteryUsage;
+use crate::clock::rfc3339;
+use crate::config::ConfigEntry;
+use crate::history::{self, HistoryEntry};
+use crate::json::json_string;
+use crate::parser::merge_events;
+use crate::testing::Fixture;
+use crate::{CFF3000State, DeviceProfile, LedEvent, ParseOptions, PinAssignment, StateChange, Trigger, LED_GREEN, LED_RED};
+
+mod cache;
+mod rpc;
+
+pub use self::cache::{cache_dir, CachedState, StateCache};
+pub use self::rpc::{serve_rpc, RpcOptions, MAX_FRAME};
+pub use crate::codes::{state_exit_code, ErrorCode};
+pub use crate::signals::stop_on_signals;
+
+/// Exit status for invalid command line arguments.
+pub const EXIT_USAGE: i32 = 10;
+
+/// Description of the exit status mapping for `--help`.
+pub fn exit_code_help() -> String {
+    let mut codes: Vec<(i32, String)> = vec![
+        (0, "success; status: locked".to_string()),
+        (1, "status: unlocked".to_string()),
+        (2, "status: manual".to_string()),
+        (3, "status: out-of-range".to_string()),
+        (EXIT_USAGE, "invalid command line arguments".to_string()),
+    ];
+    codes.extend(ErrorCode::ALL.iter().map(|code| (code.exit_code(), format!("{}: {}", code.name(), code.description()))));
+    codes.sort();
+
+    let mut help = String::from("Exit status:\n");
+    for (code, text) in codes {
+        let _ = writeln!(help, "  {:<3} {}", code, text);
+    }
+    help
+}
+
+/// Failure of a command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CliError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl From<std::io::Error> for CliError {
+    fn from(err: std::io::Error) -> CliError {
+        CliError {code: ErrorCode::of(&err), message: err.to_string()}
+    }
+}
+
+/// Outcome of one command, see module documentation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// Subcommand name, e.g. "status"
+    pub command: String,
+    /// State shown by the device, if the command captured it
+    pub state: Option<CFF3000State>,
+    /// State before a change reported by `watch`
+    pub previous: Option<CFF3000State>,
+    /// Cause of a change reported by `watch`
+    pub trigger: Option<Trigger>,
+    /// `state` is from the cache instead of the device
+    pub cached: bool,
+    pub error: Option<CliError>,
+    /// Operations received by `history`
+    pub history: Vec<HistoryEntry>,
+    /// Usage received by `battery`
+    pub battery: Option<BatteryUsage>,
+    pub duration: Duration,
+}
+
+impl Report {
+    /// Report of a successful `command` without a captured state.
+    pub fn new(command: &str, duration: Duration) -> Report {
+        Report {command: command.to_string(), state: None, previous: None, trigger: None, cached: false, error: None, history: Vec::new(), battery: None, duration}
+    }
+
+    /// Report of a `watch` change, `elapsed` after the start.
+    pub fn change(change: StateChange, elapsed: Duration) -> Report {
+        Report {
+            state: Some(change.current),
+            previous: change.previous,
+            trigger: Some(change.trigger),
+            ..Report::new("watch", elapsed)
+        }
+    }
+
+    /// Human readable line for the captured state, e.g. "locked" or
+    /// "unlocked -> locked (auto-lock)" for a `watch` change, "(schedule)"
+    /// for a scheduled action.
+    pub fn summary(&self) -> Option<String> {
+        let state = self.state?;
+        let mut line = match self.previous {
+            Some(previous) => format!("{} -> {}", previous.name(), state.name()),
+            None => state.name().to_string(),
+        };
+        match self.trigger {
+            Some(Trigger::Poll) | None => {},
+            Some(trigger) => line.push_str(&format!(" ({})", trigger)),
+        }
+        Some(line)
+    }
+
+    /// Exit status of the binary: the error's, the state's for
+    /// `status` and 0 otherwise.
+    pub fn exit_code(&self) -> i32 {
+        match (self.error.as_ref(), self.state) {
+            (Some(error), _) => error.code.exit_code(),
+            (None, Some(state)) if self.command == "status" => state_exit_code(state),
+            (None, _) => 0,
+        }
+    }
+
+    /// Serialize as one line of JSON, without a trailing newline.
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\"command\":");
+        json_string(&mut out, &self.command);
+        if let Some(state) = self.state {
+            out.push_str(",\"state\":");
+            json_string(&mut out, state.name());
+        }
+        if let Some(previous) = self.previous {
+            out.push_str(",\"previous\":");
+            json_string(&mut out, previous.name());
+        }
+        if let Some(trigger) = self.trigger {
+            out.push_str(",\"trigger\":");
+            json_string(&mut out, trigger.name());
+        }
+        if self.cached {
+            out.push_str(",\"cached\":true");
+        }
+        if self.command == "history" && self.error.is_none() {
+            out.push_str(",\"history\":");
+            history::write_json(&mut out, &self.history);
+        }
+        if let Some(ref battery) = self.battery {
+            out.push_str(",\"battery\":");
+            battery.write_json(&mut out);
+        }
+        if let Some(ref error) = self.error {
+            out.push_str(",\"error\":{\"code\":");
+            json_string(&mut out, error.code.name());
+            out.push_str(",\"message\":");
+            json_string(&mut out, &error.message);
+            out.push('}');
+        }
+        let ms = self.duration.as_secs() * 1000 + self.duration.subsec_millis() as u64;
+        let _ = write!(out, ",\"duration_ms\":{}}}", ms);
+        out
+    }
+}
+
+/// Initiator of the commands of this process coming in through
+/// `entry`, e.g. "cli:uid=1000" or "cli:uid=0,sudo-user=alice" run by
+/// sudo, for `CFF3000::execute_as()`.
+pub fn initiator(entry: &str) -> String {
+    #[cfg(unix)]
+    let mut initiator = format!("{}:uid={}", entry, unsafe { libc::getuid() });
+    #[cfg(not(unix))]
+    let mut initiator = entry.to_string();
+    if let Some(user) = std::env::var_os("SUDO_USER").filter(|user| !user.is_empty()) {
+        initiator.push_str(&format!(",sudo-user={}", user.to_string_lossy()));
+    }
+    initiator
+}
+
+/// Level of the log records printed for `-v` and `-q` given
+/// `verbose` and `quiet` times: warnings by default, `-v` adds info
+/// and debug records, `-vv` trace records (every GPIO operation and LED
+/// event), `-q` only prints errors and `-qq` nothing.
+pub fn log_level(verbose: u8, quiet: u8) -> log::LevelFilter {
+    const LEVELS: [log::LevelFilter; 5] = [
+        log::LevelFilter::Off, log::LevelFilter::Error, log::LevelFilter::Warn, log::LevelFilter::Debug, log::LevelFilter::Trace,
+    ];
+    let index = (2 + verbose as i32 - quiet as i32).clamp(0, LEVELS.len() as i32 - 1);
+    LEVELS[index as usize]
+}
+
+/// Print log records up to `level` with millisecond timestamps to
+/// stderr, so stdout stays clean for `--json`. `RUST_LOG` overrides
+/// the level, e.g. `RUST_LOG=cff3000::backend=trace`. Fails if a
+/// logger has been installed already.
+pub fn init_logging(level: log::LevelFilter) -> std::io::Result<()> {
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .target(env_logger::Target::Stderr)
+        .format_timestamp_millis()
+        .try_init()
+        .map_err(|err| std::io::Error::new(ErrorKind::AlreadyExists, err))
+}
+
+/// Output of `cff3000 config show`: one `key = value` line per entry,
+/// followed by its source as a TOML comment.
+pub fn format_config(entries: &[ConfigEntry]) -> String {
+    let settings: Vec<String> = entries.iter().map(|entry| format!("{} = {}", entry.key, entry.value)).collect();
+    let width = settings.iter().map(String::len).max().unwrap_or(0);
+    let mut out = String::new();
+    for (setting, entry) in settings.iter().zip(entries) {
+        let _ = writeln!(out, "{:width$}  # {}", setting, entry.source, width = width);
+    }
+    out
+}
+
+/// Output of `cff3000 history`: one line per operation with its start,
+/// command, outcome and duration, e.g.
+/// "2026-01-02T03:04:05.678Z  lock    locked  10204 ms".
+pub fn format_history(entries: &[HistoryEntry]) -> String {
+    let mut out = String::new();
+    for entry in entries {
+        let outcome = match entry.result {
+            Ok(Some(state)) => state.name().to_string(),
+            Ok(None) => "pressed".to_string(),
+            Err(ref error) => format!("{}: {}", error.code, error.message),
+        };
+        let _ = writeln!(out, "{}  {:<6}  {}  {} ms", rfc3339(entry.timestamp), history::command_name(entry.command), outcome, entry.duration.as_millis());
+    }
+    out
+}
+
+/// Output of `cff3000 battery`: the commands on the batteries with the
+/// estimate, e.g.
+/// "1210 commands since 2026-01-02T03:04:05.678Z (batteries replaced)
+/// 12.1 per day, 3790 of 5000 left, about 313 days".
+pub fn format_battery(usage: &BatteryUsage) -> String {
+    let mut out = String::new();
+    let since = if usage.replaced {"batteries replaced"} else {"first start, no battery change recorded"};
+    let _ = writeln!(out, "{} commands since {} ({})", usage.commands, rfc3339(usage.since), since);
+    if let Some(per_day) = usage.per_day() {
+        let _ = write!(out, "{:.1} per day, ", per_day);
+    }
+    let _ = write!(out, "{} of {} left", usage.remaining(), usage.budget);
+    if let Some(days_left) = usage.days_left() {
+        let _ = write!(out, ", about {:.0} days", days_left);
+    }
+    out.push('\n');
+    if usage.warning {
+        out.push_str("warning: replace the batteries soon\n");
+    }
+    out
+}
+
+/// Names of all states, the values of `record --expected`.
+const STATES: [&str; 4] = ["locked", "unlocked", "manual", "out-of-range"];
+
+fn levels_name(levels: u8) -> &'static str {
+    match levels & (LED_RED | LED_GREEN) {
+        0 => "off",
+        LED_RED => "red",
+        LED_GREEN => "green",
+        _ => "red+green",
+    }
+}
+
+/// Output of `cff3000 replay` besides the state: the size of the
+/// capture, the parser settings and the LED level changes the parser
+/// classifies, e.g. "  1410 ms  green".
+pub fn replay_diagnostics(fixture: &Fixture, options: &ParseOptions) -> Vec<String> {
+    diagnostics(&fixture.events, fixture.profile, Some(fixture.expected), options)
+}
+
+/// Like `replay_diagnostics()`, for the events of a CSV file of
+/// `import::from_csv()`, which have no profile or expected state.
+pub fn replay_csv_diagnostics(events: &[LedEvent], options: &ParseOptions) -> Vec<String> {
+    diagnostics(events, DeviceProfile::Classic, None, options)
+}
+
+fn diagnostics(events: &[LedEvent], profile: DeviceProfile, expected: Option<CFF3000State>, options: &ParseOptions) -> Vec<String> {
+    let span = match (events.first(), events.last()) {
+        (Some(first), Some(last)) => last.timestamp.saturating_sub(first.timestamp).as_millis(),
+        _ => 0,
+    };
+    let window = options.merge_window.saturating_add(options.poll_period);
+    let mut lines = vec![
+        format!("{} LED events over {} ms", events.len(), span),
+        format!("profile {}, merge window {} ms", profile.name().unwrap_or("custom"), window.as_millis()),
+    ];
+    if let Some(expected) = expected {
+        lines.push(format!("expected {}", expected.name()));
+    }
+    for change in merge_events(events, options) {
+        lines.push(format!("{:>6} ms  {}", change.time.as_millis(), levels_name(change.levels)));
+    }
+    lines
+}
+
+/// Arguments of the `cff3000` binary. Shared with `write_completions()`
+/// and `write_man_page()`, so both describe exactly what the binary
+/// accepts.
+pub fn command() -> clap::Command {
+    let verify = Arg::new("verify").long("verify").action(ArgAction::SetTrue)
+        .help("Capture the confirmation and fail unless it shows the new state");
+    let socket = Arg::new("socket").long("socket").value_name("PATH").value_parser(value_parser!(PathBuf)).default_value("/run/cff3000.sock")
+        .value_hint(ValueHint::FilePath)
+        .help("Unix socket of the daemon, see cff3000::socket");
+    clap::Command::new("cff3000")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Control a GPIO connected CFF3000 remote control")
+        /* no subcommand_required(), --generate-man has none */
+        .after_help(exit_code_help())
+        .arg(Arg::new("json").long("json").action(ArgAction::SetTrue).global(true)
+            .help("Print the result as one JSON object on stdout"))
+        .arg(Arg::new("config").long("config").value_name("FILE").value_parser(value_parser!(PathBuf)).global(true)
+            .value_hint(ValueHint::FilePath)
+            .help("TOML configuration file, instead of /etc/cff3000.toml and ~/.config/cff3000/config.toml"))
+        .arg(Arg::new("chip").long("chip").value_name("CHIPDEV").global(true)
+            .value_hint(ValueHint::FilePath)
+            .help("GPIO chip, e.g. /dev/gpiochip2 (overrides CFF3000_CHIP)"))
+        .arg(Arg::new("pins").long("pins").value_name("RED,GREEN,UNLOCK,LOCK").value_parser(value_parser!(PinAssignment)).global(true)
+            .help("Line offsets, e.g. 2,3,4,5 (overrides CFF3000_PINS)"))
+        .arg(Arg::new("verbose").short('v').long("verbose").action(ArgAction::Count).global(true)
+            .help("Log more to stderr, -vv shows every GPIO operation and LED event"))
+        .arg(Arg::new("quiet").short('q').long("quiet").action(ArgAction::Count).global(true)
+            .help("Log less to stderr, -qq only prints the result"))
+        .arg(Arg::new("dry-run").long("dry-run").action(ArgAction::SetTrue).global(true)
+            .help("Print the button writes to stderr instead of performing them, see CFF3000Builder::dry_run()"))
+        .arg(Arg::new("generate-man").long("generate-man").action(ArgAction::SetTrue).exclusive(true).hide(true)
+            .help("Print the man page in roff format"))
+        .subcommand(clap::Command::new("lock").about("Press the lock button").arg(verify.clone()))
+        .subcommand(clap::Command::new("unlock").about("Press the unlock button").arg(verify))
+        .subcommand(clap::Command::new("check").about("Press both buttons to show the state on the device"))
+        .subcommand(clap::Command::new("status").about("Query and print the state")
+            .arg(Arg::new("cache-ttl").long("cache-ttl").value_name("SECONDS").value_parser(value_parser!(u64))
+                .help("Print the cached state instead of querying the device if it is younger than this"))
+            .arg(Arg::new("no-cache").long("no-cache").action(ArgAction::SetTrue).conflicts_with("cache-ttl")
+                .help("Neither read nor update the cached state")))
+        .subcommand(clap::Command::new("leds").about("Show the LEDs on the terminal")
+            .arg(Arg::new("seconds").value_parser(value_parser!(u8))
+                .help("How long to show the LEDs (default: 10)")))
+        .subcommand(clap::Command::new("watch").about("Print every state change until interrupted")
+            .arg(Arg::new("interval").long("interval").value_name("SECONDS").value_parser(value_parser!(u64)).default_value("300")
+                .help("Time between two state queries"))
+            .arg(Arg::new("auto-lock-after").long("auto-lock-after").value_name("SECONDS").value_parser(value_parser!(u64))
+                .help("Lock the door once it has been unlocked for this long"))
+            .arg(Arg::new("max-events").long("max-events").value_name("N").value_parser(value_parser!(u64))
+                .help("Stop after N state changes")))
+        .subcommand(clap::Command::new("rpc").about("Answer JSON-RPC 2.0 requests on stdin until it is closed")
+            .arg(Arg::new("interval").long("interval").value_name("SECONDS").value_parser(value_parser!(u64)).default_value("300")
+                .help("Time between two state queries after subscribe"))
+            .arg(Arg::new("auto-lock-after").long("auto-lock-after").value_name("SECONDS").value_parser(value_parser!(u64))
+                .help("Lock the door once it has been unlocked for this long, after subscribe")))
+        .subcommand(clap::Command::new("record").about("Press both buttons and save the LED events as a fixture file")
+            .arg(Arg::new("output").short('o').long("output").value_name("FILE").value_parser(value_parser!(PathBuf)).required(true)
+                .value_hint(ValueHint::FilePath)
+                .help("Fixture file to write"))
+            .arg(Arg::new("window").long("window").value_name("SECONDS").value_parser(value_parser!(u64))
+                .help("Capture window (default: the configured check capture)"))
+            .arg(Arg::new("expected").long("expected").value_name("STATE").value_parser(STATES)
+                .help("State the LEDs showed (default: the interpreted state)"))
+            .arg(Arg::new("device").long("device").value_name("TEXT").default_value("unknown")
+                .help("Hardware description for the fixture header"))
+            .arg(Arg::new("firmware").long("firmware").value_name("TEXT").default_value("unknown")
+                .help("Firmware notes for the fixture header"))
+            .arg(Arg::new("format").long("format").value_name("FORMAT").value_parser(["fixture", "vcd", "csv"]).default_value("fixture")
+                .help("Write a fixture file, a Value Change Dump for waveform viewers or plain CSV, see cff3000::export")))
+        .subcommand(clap::Command::new("replay").about("Interpret a fixture file offline")
+            .arg(Arg::new("file").value_name("FILE").value_parser(value_parser!(PathBuf)).required(true)
+                .value_hint(ValueHint::FilePath)
+                .help("Fixture file or CSV file of record --format csv"))
+            .arg(Arg::new("format").long("format").value_name("FORMAT").value_parser(["text", "vcd", "csv"]).default_value("text")
+                .help("Print what the parser sees or, instead of any other output, the events as a Value Change Dump or CSV")))
+        .subcommand(clap::Command::new("history").about("Print the last operations of a running daemon")
+            .arg(socket.clone()))
+        .subcommand(clap::Command::new("battery").about("Commands on the batteries of a running daemon").subcommand_required(true)
+            .subcommand(clap::Command::new("show").about("Print the commands since the last battery change with the estimate")
+                .arg(socket.clone()))
+            .subcommand(clap::Command::new("reset").about("Record new batteries")
+                .arg(socket)))
+        .subcommand(clap::Command::new("config").about("Inspect the configuration").subcommand_required(true)
+            .subcommand(clap::Command::new("show").about("Print the effective configuration and the source of each value")))
+        .subcommand(clap::Command::new("completions").about("Print a shell completion script")
+            .arg(Arg::new("shell").required(true).value_parser(value_parser!(Shell))
+                .help("Shell to generate the script for")))
+}
+
+/// Write the completion script of the `cff3000` binary for `shell`.
+///
+/// Generated from `command()`, it completes the subcommands, the flags
+/// and the values of arguments with a fixed set of choices.
+pub fn write_completions(shell: Shell, out: &mut dyn std::io::Write) {
+    let mut command = command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, name, out);
+}
+
+/// Write the man page of the `cff3000` binary in roff format, printed
+/// by the hidden `--generate-man` flag for packaging.
+pub fn write_man_page(out: &mut dyn std::io::Write) -> std::io::Result<()> {
+    clap_mangen::Man::new(command()).render(out)
+}
@@ -0,0 +1,327 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! JSON-RPC 2.0 over stdio (`cff3000 rpc`), see the module
+//! documentation of `cli`.
+
+use std::io::{BufRead, Read, Write};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::json::{json_string, json_value, parse, Value};
+use crate::{Command, CommandQueue, CommandSender, CFF3000, CFF3000State, QueueOptions, StateChange, StopToken, WatchOptions};
+use super::ErrorCode;
+
+/// Longest accepted line in bytes.
+pub const MAX_FRAME: usize = 64 * 1024;
+
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+
+/// Configuration of `serve_rpc()`.
+#[derive(Debug, Clone)]
+pub struct RpcOptions {
+    /// Queue of the requests, its depth bounds the requests waiting
+    /// for the device
+    pub queue: QueueOptions,
+    /// Watch loop started by the first `subscribe`
+    pub watch: WatchOptions,
+}
+
+impl Default for RpcOptions {
+    /// Default queue, polling every 300 seconds like `cff3000 watch`.
+    fn default() -> RpcOptions {
+        RpcOptions {queue: QueueOptions::default(), watch: WatchOptions::new(Duration::from_secs(300))}
+    }
+}
+
+/// Lines written by the reader, the responders and the watch loop.
+type Output = Arc<Mutex<Box<dyn Write + Send>>>;
+
+fn send(output: &Output, line: &str) {
+    let mut output = output.lock().unwrap_or_else(|e| e.into_inner());
+    /* a supervisor which is gone closes stdin as well */
+    let _ = output.write_all(line.as_bytes()).and_then(|_| output.write_all(b"\n")).and_then(|_| output.flush());
+}
+
+fn success(id: &Value, result: &str) -> String {
+    let mut out = String::from("{\"jsonrpc\":\"2.0\",\"id\":");
+    json_value(&mut out, id);
+    out.push_str(",\"result\":");
+    out.push_str(result);
+    out.push('}');
+    out
+}
+
+/// Error response, `data` is a JSON object or empty.
+fn failure(id: &Value, code: i32, message: &str, data: &str) -> String {
+    let mut out = String::from("{\"jsonrpc\":\"2.0\",\"id\":");
+    json_value(&mut out, id);
+    out.push_str(&format!(",\"error\":{{\"code\":{},\"message\":", code));
+    json_string(&mut out, message);
+    if !data.is_empty() {
+        out.push_str(",\"data\":");
+        out.push_str(data);
+    }
+    out.push_str("}}");
+    out
+}
+
+fn state_object(state: CFF3000State) -> String {
+    let mut out = String::from("{\"state\":");
+    json_string(&mut out, state.name());
+    out.push('}');
+    out
+}
+
+/// Error of a command, with the error code of the tool as JSON-RPC
+/// code and its name in `data.code`.
+fn command_failure(id: &Value, code: ErrorCode, message: &str, state: Option<CFF3000State>) -> String {
+    let mut data = String::from("{\"code\":");
+    json_string(&mut data, code.name());
+    if let Some(state) = state {
+        data.push_str(",\"state\":");
+        json_string(&mut data, state.name());
+    }
+    data.push('}');
+    failure(id, code.exit_code(), message, &data)
+}
+
+/// Notification of a change seen by the watch loop.
+fn change_notification(change: StateChange) -> String {
+    let mut out = String::from("{\"jsonrpc\":\"2.0\",\"method\":\"state_changed\",\"params\":");
+    change.write_json(&mut out);
+    out.push('}');
+    out
+}
+
+/// Notification of a watch loop which gave up.
+fn watch_failure_notification(err: &std::io::Error) -> String {
+    let mut out = String::from("{\"jsonrpc\":\"2.0\",\"method\":\"subscription_failed\",\"params\":{\"code\":");
+    json_string(&mut out, ErrorCode::of(err).name());
+    out.push_str(",\"message\":");
+    json_string(&mut out, &err.to_string());
+    out.push_str("}}");
+    out
+}
+
+/// Response to a request, once it is known.
+enum Answer {
+    /// Nothing, the request is a notification
+    Silent,
+    Ready(String),
+    /// Waiting for the queue: id, expected state and result
+    Queued(Option<Value>, Option<CFF3000State>, mpsc::Receiver<std::io::Result<CFF3000State>>),
+}
+
+impl Answer {
+    /// Wait for the response.
+    fn resolve(self) -> Option<String> {
+        match self {
+            Answer::Silent => None,
+            Answer::Ready(response) => Some(response),
+            Answer::Queued(id, expected, result) => {
+                let id = id?;
+                Some(match result.recv() {
+                    Ok(Ok(state)) => match expected {
+                        Some(expected) if expected != state => {
+                            let message = format!("not confirmed, the device shows {}", state.name());
+                            command_failure(&id, ErrorCode::NotConfirmed, &message, Some(state))
+                        },
+                        _ => success(&id, &state_object(state)),
+                    },
+                    Ok(Err(err)) => command_failure(&id, ErrorCode::of(&err), &err.to_string(), None),
+                    Err(_) => command_failure(&id, ErrorCode::Io, "command queue has been shut down", None),
+                })
+            },
+        }
+    }
+}
+
+/// State of one `serve_rpc()` call.
+struct Session {
+    device: Arc<CFF3000>,
+    sender: CommandSender,
+    output: Output,
+    watch: WatchOptions,
+    stop: StopToken,
+    watcher: Option<JoinHandle<()>>,
+    /// See `super::initiator()`
+    initiator: String,
+}
+
+impl Session {
+    /// Start the watch loop unless it is running.
+    fn subscribe(&mut self) -> std::io::Result<()> {
+        if self.watcher.as_ref().is_some_and(|watcher| !watcher.is_finished()) {
+            return Ok(());
+        }
+        let (device, output, options, stop) = (self.device.clone(), self.output.clone(), self.watch, self.stop.clone());
+        self.watcher = Some(std::thread::Builder::new().name("cff3000-rpc-watch".to_string()).spawn(move || {
+            let result = device.watch_with_options(&options, &stop, |change| send(&output, &change_notification(change)));
+            if let Err(err) = result {
+                send(&output, &watch_failure_notification(&err));
+            }
+        })?);
+        Ok(())
+    }
+
+    /// Answer one request object.
+    fn call(&mut self, request: &Value) -> Answer {
+        let invalid = |message: &str| Answer::Ready(failure(&Value::Null, INVALID_REQUEST, message, ""));
+        if !matches!(*request, Value::Object(_)) {
+            return invalid("request is not an object");
+        }
+        let id = match request.get("id") {
+            None => None,
+            Some(id @ &Value::Null) | Some(id @ &Value::Number(_)) | Some(id @ &Value::String(_)) => Some(id.clone()),
+            Some(_) => return invalid("id must be a string, a number or null"),
+        };
+        /* valid requests without id are notifications, they get no response */
+        let notification = id.is_none();
+        let reply = |response: String| match notification {
+            true => Answer::Silent,
+            false => Answer::Ready(response),
+        };
+        let reply_id = id.clone().unwrap_or(Value::Null);
+        if request.get("jsonrpc").and_then(Value::as_str) != Some("2.0") {
+            return Answer::Ready(failure(&reply_id, INVALID_REQUEST, "jsonrpc must be \"2.0\"", ""));
+        }
+        let method = match request.get("method").and_then(Value::as_str) {
+            Some(method) => method,
+            None => return Answer::Ready(failure(&reply_id, INVALID_REQUEST, "method must be a string", "")),
+        };
+        let (command, expected) = match method {
+            "lock" => (Some(Command::Lock), Some(CFF3000State::Locked)),
+            "unlock" => (Some(Command::Unlock), Some(CFF3000State::Unlocked)),
+            "state" => (Some(Command::Check), None),
+            "subscribe" => (None, None),
+            _ => return reply(failure(&reply_id, METHOD_NOT_FOUND, &format!("unknown method {}", method), "")),
+        };
+        match request.get("params") {
+            None => {},
+            Some(Value::Array(params)) if params.is_empty() => {},
+            Some(Value::Object(params)) if params.is_empty() => {},
+            Some(&Value::Array(_)) | Some(&Value::Object(_)) => {
+                return reply(failure(&reply_id, INVALID_PARAMS, &format!("{} takes no parameters", method), ""));
+            },
+            Some(_) => return Answer::Ready(failure(&reply_id, INVALID_REQUEST, "params must be an array or an object", "")),
+        }
+        match command {
+            Some(command) => Answer::Queued(id, expected, self.sender.send_as(command, &self.initiator)),
+            None => match self.subscribe() {
+                Ok(()) => reply(success(&reply_id, "true")),
+                Err(err) => reply(command_failure(&reply_id, ErrorCode::of(&err), &err.to_string(), None)),
+            },
+        }
+    }
+
+    /// Answer one line, a request or a batch of them. Returns the
+    /// answers and whether they form a batch.
+    fn answer(&mut self, line: &str) -> (Vec<Answer>, bool) {
+        match parse(line) {
+            None => (vec![Answer::Ready(failure(&Value::Null, PARSE_ERROR, "invalid JSON", ""))], false),
+            Some(Value::Array(ref requests)) if requests.is_empty() => {
+                (vec![Answer::Ready(failure(&Value::Null, INVALID_REQUEST, "empty batch", ""))], false)
+            },
+            Some(Value::Array(requests)) => (requests.iter().map(|request| self.call(request)).collect(), true),
+            Some(request) => (vec![self.call(&request)], false),
+        }
+    }
+}
+
+/// Write the responses of `answers`, waiting for the queued ones.
+fn respond(output: &Output, answers: Vec<Answer>, batch: bool) {
+    let responses: Vec<String> = answers.into_iter().filter_map(Answer::resolve).collect();
+    match (batch, responses.as_slice()) {
+        (_, []) => {},
+        (true, _) => send(output, &format!("[{}]", responses.join(","))),
+        (false, [response, ..]) => send(output, response),
+    }
+}
+
+/// Read the next line into `frame`, `None` at the end of `input`. A
+/// line longer than `MAX_FRAME` is skipped, `Some(false)` tells so.
+fn read_frame<R: BufRead>(input: &mut R, frame: &mut Vec<u8>) -> std::io::Result<Option<bool>> {
+    frame.clear();
+    if input.by_ref().take(MAX_FRAME as u64 + 1).read_until(b'\n', frame)? == 0 {
+        return Ok(None);
+    }
+    if frame.len() <= MAX_FRAME || frame.ends_with(b"\n") {
+        return Ok(Some(true));
+    }
+    let mut rest = Vec::new();
+    loop {
+        rest.clear();
+        if input.by_ref().take(MAX_FRAME as u64).read_until(b'\n', &mut rest)? == 0 || rest.ends_with(b"\n") {
+            return Ok(Some(false));
+        }
+    }
+}
+
+/// Answer the JSON-RPC requests on the lines of `input` on `output`
+/// until `input` ends, then wait for the pending responses and stop
+/// the watch loop. Commands go through a `CommandQueue` in the order
+/// they are read, responses are written as they are done, so a `state`
+/// can be answered before a `lock` sent afterwards fails busy. Invalid
+/// lines are answered with a JSON-RPC error, only a failure to read
+/// `input` ends with an error.
+pub fn serve_rpc<R, W>(cff3000: Arc<CFF3000>, mut input: R, output: W, options: &RpcOptions) -> std::io::Result<()>
+    where R: BufRead, W: Write + Send + 'static
+{
+    let queue = CommandQueue::with_options(cff3000.clone(), options.queue)?;
+    let output: Output = Arc::new(Mutex::new(Box::new(output)));
+    let mut session = Session {
+        device: cff3000,
+        sender: queue.sender(),
+        output: output.clone(),
+        watch: options.watch,
+        stop: StopToken::new(),
+        watcher: None,
+        initiator: super::initiator("rpc"),
+    };
+    let mut responders: Vec<JoinHandle<()>> = Vec::new();
+    let mut frame = Vec::new();
+    let result = loop {
+        let complete = match read_frame(&mut input, &mut frame) {
+            Ok(Some(complete)) => complete,
+            Ok(None) => break Ok(()),
+            Err(err) => break Err(err),
+        };
+        if !complete {
+            send(&output, &failure(&Value::Null, INVALID_REQUEST, &format!("request longer than {} bytes", MAX_FRAME), ""));
+            continue;
+        }
+        let line = match std::str::from_utf8(&frame) {
+            Ok(line) if line.trim().is_empty() => continue,
+            Ok(line) => line,
+            Err(_) => {
+                send(&output, &failure(&Value::Null, PARSE_ERROR, "request is not UTF-8", ""));
+                continue;
+            },
+        };
+        let (answers, batch) = session.answer(line);
+        if answers.iter().all(|answer| !matches!(*answer, Answer::Queued(..))) {
+            respond(&output, answers, batch);
+            continue;
+        }
+        responders.retain(|responder| !responder.is_finished());
+        let output = output.clone();
+        match std::thread::Builder::new().name("cff3000-rpc".to_string()).spawn(move || respond(&output, answers, batch)) {
+            Ok(responder) => responders.push(responder),
+            Err(err) => break Err(err),
+        }
+    };
+
+    for responder in responders {
+        let _ = responder.join();
+    }
+    session.stop.stop();
+    if let Some(watcher) = session.watcher.take() {
+        let _ = watcher.join();
+    }
+    result
+}
@@ -0,0 +1,108 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Time source abstraction.
+//!
+//! All timing of a `CFF3000` (press durations, capture windows, reopen
+//! retries and the watch loop's intervals) goes through its `Clock`,
+//! set with `CFF3000Builder::clock()`. Only the backends' LED event
+//! timestamps and waits use the real time of the hardware.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+#[cfg(any(feature = "cli", feature = "webhook", all(feature = "audit", unix)))]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::StopToken;
+
+/// Source of the current time and of sleeps.
+pub trait Clock {
+    /// Current point in time.
+    fn now(&self) -> Instant;
+
+    /// Block the calling thread for `duration` (default:
+    /// `std::thread::sleep()`).
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration)
+    }
+
+    /// Block for `duration` or until `stop` is stopped, returning true
+    /// if stopped (default: `StopToken::wait_timeout()`).
+    fn sleep_or_stop(&self, duration: Duration, stop: &StopToken) -> bool {
+        stop.wait_timeout(duration)
+    }
+}
+
+/// Clock shared by a device and its components.
+pub type SharedClock = Arc<dyn Clock + Send + Sync>;
+
+/// `Clock` backed by `std::time::Instant::now()`.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for &C {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        (**self).sleep(duration)
+    }
+
+    fn sleep_or_stop(&self, duration: Duration, stop: &StopToken) -> bool {
+        (**self).sleep_or_stop(duration, stop)
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        (**self).sleep(duration)
+    }
+
+    fn sleep_or_stop(&self, duration: Duration, stop: &StopToken) -> bool {
+        (**self).sleep_or_stop(duration, stop)
+    }
+}
+
+/// Time left from `now` until `deadline` (zero if passed).
+pub(crate) fn until(now: Instant, deadline: Instant) -> Duration {
+    deadline.saturating_duration_since(now)
+}
+
+/// `now + timeout`, for timeouts too large for an `Instant` (e.g.
+/// `Duration::MAX` from a configuration) about 30 years from `now`.
+pub(crate) fn deadline(now: Instant, timeout: Duration) -> Instant {
+    now.checked_add(timeout).or_else(|| now.checked_add(FAR_FUTURE)).unwrap_or(now)
+}
+
+/// Cap of `deadline()`.
+const FAR_FUTURE: Duration = Duration::from_secs(30 * 365 * 86400);
+
+/// `time` as RFC 3339 in UTC with milliseconds, e.g.
+/// "2026-01-02T03:04:05.678Z".
+#[cfg(any(feature = "cli", feature = "webhook", all(feature = "audit", unix)))]
+pub(crate) fn rfc3339(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let (days, secs) = ((since.as_secs() / 86400) as i64, since.as_secs() % 86400);
+    /* civil date of the day number, see Howard Hinnant's days_from_civil */
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 {mp + 3} else {mp - 9};
+    let year = yoe + era * 400 + if month <= 2 {1} else {0};
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day, secs / 3600, secs / 60 % 60, secs % 60, since.subsec_millis())
+}
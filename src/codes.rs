@@ -0,0 +1,124 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Stable numbers of states and errors, shared by the command line tool
+//! and the C interface.
+
+use std::io::ErrorKind;
+
+use crate::query;
+use crate::{AlreadyInUse, CFF3000State, ParseError};
+
+/// Stable class of an error, the `error.code` of a `Report`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    /// "config": missing or invalid configuration
+    Config,
+    /// "not-found": the GPIO chip does not exist
+    NotFound,
+    /// "permission-denied": no access to the GPIO chip
+    PermissionDenied,
+    /// "busy": the lines, the lock file or the device are in use
+    Busy,
+    /// "no-response": the LEDs did not show a pattern
+    NoResponse,
+    /// "invalid-pattern": the LED pattern was not understood
+    InvalidPattern,
+    /// "not-confirmed": `--verify` saw a different state
+    NotConfirmed,
+    /// "unsupported": not available on this system
+    Unsupported,
+    /// "io": any other error
+    Io,
+}
+
+impl ErrorCode {
+    /// All codes.
+    pub const ALL: [ErrorCode; 9] = [
+        ErrorCode::NoResponse,
+        ErrorCode::Config,
+        ErrorCode::NotFound,
+        ErrorCode::PermissionDenied,
+        ErrorCode::Busy,
+        ErrorCode::InvalidPattern,
+        ErrorCode::NotConfirmed,
+        ErrorCode::Unsupported,
+        ErrorCode::Io,
+    ];
+
+    /// Class of `err`.
+    pub fn of(err: &std::io::Error) -> ErrorCode {
+        let inner = err.get_ref();
+        if let Some(err) = query::parse_error(err) {
+            return match *err {
+                ParseError::NotEnoughEvents => ErrorCode::NoResponse,
+                _ => ErrorCode::InvalidPattern,
+            };
+        }
+        if inner.is_some_and(|inner| inner.is::<AlreadyInUse>()) {
+            return ErrorCode::Busy;
+        }
+        match err.kind() {
+            ErrorKind::InvalidInput | ErrorKind::InvalidData => ErrorCode::Config,
+            ErrorKind::NotFound => ErrorCode::NotFound,
+            ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+            ErrorKind::WouldBlock | ErrorKind::ResourceBusy => ErrorCode::Busy,
+            ErrorKind::Unsupported => ErrorCode::Unsupported,
+            _ => ErrorCode::Io,
+        }
+    }
+
+    /// Name used in the JSON output, e.g. "not-found".
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorCode::Config => "config",
+            ErrorCode::NotFound => "not-found",
+            ErrorCode::PermissionDenied => "permission-denied",
+            ErrorCode::Busy => "busy",
+            ErrorCode::NoResponse => "no-response",
+            ErrorCode::InvalidPattern => "invalid-pattern",
+            ErrorCode::NotConfirmed => "not-confirmed",
+            ErrorCode::Unsupported => "unsupported",
+            ErrorCode::Io => "io",
+        }
+    }
+
+    /// Exit status of the binary, see module documentation.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorCode::NoResponse => 4,
+            ErrorCode::Config => 11,
+            ErrorCode::NotFound => 12,
+            ErrorCode::PermissionDenied => 13,
+            ErrorCode::Busy => 14,
+            ErrorCode::InvalidPattern => 15,
+            ErrorCode::NotConfirmed => 16,
+            ErrorCode::Unsupported => 17,
+            ErrorCode::Io => 18,
+        }
+    }
+
+    pub(crate) fn description(self) -> &'static str {
+        match self {
+            ErrorCode::Config => "missing or invalid configuration",
+            ErrorCode::NotFound => "the GPIO chip does not exist",
+            ErrorCode::PermissionDenied => "no access to the GPIO chip",
+            ErrorCode::Busy => "the lines, the lock file or the device are in use",
+            ErrorCode::NoResponse => "the LEDs did not show a pattern",
+            ErrorCode::InvalidPattern => "the LED pattern was not understood",
+            ErrorCode::NotConfirmed => "--verify saw a different state",
+            ErrorCode::Unsupported => "not available on this system",
+            ErrorCode::Io => "any other error",
+        }
+    }
+}
+
+/// Exit status of `status` for `state`.
+pub fn state_exit_code(state: CFF3000State) -> i32 {
+    match state {
+        CFF3000State::Locked => 0,
+        CFF3000State::Unlocked => 1,
+        CFF3000State::Manual => 2,
+        CFF3000State::OutOfRange => 3,
+    }
+}
@@ -0,0 +1,107 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Configuration from environment variables.
+
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+
+use crate::{BusyPolicy, DeviceProfile, PinAssignment, Polarities, Polarity};
+use super::ConfigLayer;
+
+/// Names accepted by `CFF3000_ACTIVE_LOW`, same as the `pins` keys.
+const LINES: [&str; 4] = ["led_red", "led_green", "button_unlock", "button_lock"];
+
+fn invalid(name: &str, value: &str, expected: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, format!("{}: invalid value {:?} ({})", name, value, expected))
+}
+
+fn number<T: std::str::FromStr>(name: &str, value: &str) -> std::io::Result<T> {
+    value.trim().parse().map_err(|_| invalid(name, value, "expected a non-negative integer"))
+}
+
+fn pins(name: &str, value: &str) -> std::io::Result<PinAssignment> {
+    value.parse().map_err(|err: Error| invalid(name, value, &err.to_string()))
+}
+
+fn active_low(name: &str, value: &str) -> std::io::Result<Polarities> {
+    let mut polarities = Polarities::default();
+    for line in value.split(',').map(str::trim).filter(|line| !line.is_empty()) {
+        let polarity = match LINES.iter().position(|&known| known == line) {
+            Some(0) => &mut polarities.led_red,
+            Some(1) => &mut polarities.led_green,
+            Some(2) => &mut polarities.button_unlock,
+            Some(3) => &mut polarities.button_lock,
+            _ => return Err(invalid(name, value, "expected a comma separated list of led_red, led_green, button_unlock, button_lock")),
+        };
+        *polarity = Polarity::ActiveLow;
+    }
+    Ok(polarities)
+}
+
+fn busy_policy(name: &str, value: &str) -> std::io::Result<BusyPolicy> {
+    match value.trim() {
+        "wait" => Ok(BusyPolicy::Wait),
+        "fail-fast" => Ok(BusyPolicy::FailFast),
+        _ => Err(invalid(name, value, "expected wait or fail-fast")),
+    }
+}
+
+fn profile(name: &str, value: &str) -> std::io::Result<DeviceProfile> {
+    DeviceProfile::from_name(value.trim()).ok_or_else(|| invalid(name, value, "expected classic or rev2"))
+}
+
+/// Apply variable `name` to `layer`. Unknown names are ignored.
+fn apply(layer: &mut ConfigLayer, name: &str, value: &str) -> std::io::Result<()> {
+    if value.is_empty() {
+        return Ok(());
+    }
+    match name {
+        "CFF3000_CHIP" => layer.chip = Some(value.to_string()),
+        "CFF3000_PINS" => layer.pins = Some(pins(name, value)?),
+        "CFF3000_ACTIVE_LOW" => layer.polarities = Some(active_low(name, value)?),
+        "CFF3000_PROFILE" => layer.profile = Some(profile(name, value)?),
+        "CFF3000_PRESS_MS" => layer.timings.press_ms = Some(number(name, value)?),
+        "CFF3000_CHECK_CAPTURE_MS" => layer.timings.check_capture_ms = Some(number(name, value)?),
+        "CFF3000_COMMAND_CAPTURE_MS" => layer.timings.command_capture_ms = Some(number(name, value)?),
+        "CFF3000_LOCK_PRESS_MS" => layer.timings.lock_press_ms = Some(number(name, value)?),
+        "CFF3000_UNLOCK_PRESS_MS" => layer.timings.unlock_press_ms = Some(number(name, value)?),
+        "CFF3000_CHECK_PRESS_MS" => layer.timings.check_press_ms = Some(number(name, value)?),
+        "CFF3000_LOCK_CAPTURE_MS" => layer.timings.lock_capture_ms = Some(number(name, value)?),
+        "CFF3000_UNLOCK_CAPTURE_MS" => layer.timings.unlock_capture_ms = Some(number(name, value)?),
+        "CFF3000_MERGE_WINDOW_MS" => layer.timings.merge_window_ms = Some(number(name, value)?),
+        "CFF3000_POLL_PERIOD_MS" => layer.timings.poll_period_ms = Some(number(name, value)?),
+        "CFF3000_AUTO_REOPEN_MS" => layer.retry.auto_reopen_ms = Some(number(name, value)?),
+        "CFF3000_MAX_CONSECUTIVE_ERRORS" => layer.retry.max_consecutive_errors = Some(number(name, value)?),
+        "CFF3000_MIN_INTERVAL_MS" => layer.rate_limit.min_interval_ms = Some(number(name, value)?),
+        "CFF3000_JITTER_MS" => layer.rate_limit.jitter_ms = Some(number(name, value)?),
+        "CFF3000_BUSY_POLICY" => layer.busy_policy = Some(busy_policy(name, value)?),
+        "CFF3000_LOCKFILE" => layer.lockfile = Some(PathBuf::from(value)),
+        "CFF3000_STATE_FILE" => layer.state_file = Some(PathBuf::from(value)),
+        _ => {},
+    }
+    Ok(())
+}
+
+pub(super) fn from_vars<I: IntoIterator<Item = (String, String)>>(vars: I) -> std::io::Result<ConfigLayer> {
+    let mut layer = ConfigLayer::default();
+    for (name, value) in vars {
+        apply(&mut layer, &name, &value)?;
+    }
+    Ok(layer)
+}
+
+pub(super) fn from_env() -> std::io::Result<ConfigLayer> {
+    let mut vars = Vec::new();
+    for (name, value) in std::env::vars_os() {
+        let name = match name.into_string() {
+            Ok(ref name) if name.starts_with("CFF3000_") => name.clone(),
+            _ => continue,
+        };
+        match value.into_string() {
+            Ok(value) => vars.push((name, value)),
+            Err(value) => return Err(invalid(&name, &value.to_string_lossy(), "not valid UTF-8")),
+        }
+    }
+    from_vars(vars)
+}
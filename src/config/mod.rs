@@ -0,0 +1,1021 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Device configuration files (`config` feature).
+//!
+//! A [`CFF3000Config`] holds everything that differs between
+//! installations: the GPIO chip, the pin assignment and polarities, the
+//! device profile, the timings, the retry and rate limit settings of the watch loop,
+//! the MQTT broker connection, the webhook, the audit log, the
+//! scheduled actions, the battery estimate and the health check. It
+//! is usually read from a TOML file. Unknown keys are rejected, so a
+//! mistyped pin name fails instead of being ignored. Only `chip` and
+//! `pins` are required, all durations are given in milliseconds.
+//!
+//! # MQTT
+//!
+//! The optional `[mqtt]` section describes the broker connection of
+//! the `mqtt` module, see [`MqttConfig`]:
+//!
+//! ```toml
+//! [mqtt]
+//! host = "mqtt.example.org"
+//! client_id = "frontdoor"
+//! username = "cff3000"
+//! password = "secret"
+//! keep_alive_ms = 60000
+//!
+//! [mqtt.tls]
+//! ca_cert = "/etc/cff3000/ca.pem"
+//! client_cert = "/etc/cff3000/client.pem"
+//! client_key = "/etc/cff3000/client.key"
+//! ```
+//!
+//! # Webhook
+//!
+//! The optional `[webhook]` section configures the notifier of the
+//! `webhook` module, see [`WebhookConfig`]:
+//!
+//! ```toml
+//! [webhook]
+//! url = "https://ntfy.example.org/frontdoor"
+//! token = "secret"
+//! device_name = "front door"
+//! retries = 3
+//!
+//! [webhook.headers]
+//! Title = "Front door"
+//! ```
+//!
+//! # Audit
+//!
+//! The optional `[audit]` section selects the target of the `audit`
+//! log, see [`AuditConfig`]:
+//!
+//! ```toml
+//! [audit]
+//! target = "syslog"
+//! ```
+//!
+//! The sections are accepted without the `mqtt`, `webhook` and `audit`
+//! features, so one file serves all builds.
+//!
+//! # Schedule
+//!
+//! Every `[[schedule]]` section adds an action at a local time, run by
+//! the `daemon`, see [`ScheduleConfig`] and the `schedule` module:
+//!
+//! ```toml
+//! [[schedule]]
+//! at = "22:30"
+//! action = "ensure_locked"
+//! days = ["sun", "mon", "tue", "wed", "thu"]
+//!
+//! [[schedule]]
+//! at = "7:00"
+//! action = "check"
+//! grace_ms = 3600000
+//! ```
+//!
+//! # Battery
+//!
+//! The optional `[battery]` section sets the estimate of the `battery`
+//! module, see [`BatteryConfig`]:
+//!
+//! ```toml
+//! [battery]
+//! budget = 8000
+//! warn_below_percent = 25
+//! ```
+//!
+//! # Health check
+//!
+//! The optional `[health]` section starts the probe of the `health`
+//! module, see [`HealthConfig`]. An empty section probes with the
+//! defaults:
+//!
+//! ```toml
+//! [health]
+//! interval_ms = 30000
+//! stuck_after_ms = 120000
+//! reopen = true
+//! ```
+//!
+//! # Groups
+//!
+//! A [`GroupConfig`] describes the devices of a `CFF3000Group`, each in
+//! a `[devices.<name>]` table with the settings above, and the
+//! `spacing_ms` between their operations:
+//!
+//! ```toml
+//! spacing_ms = 2000
+//!
+//! [devices.frontdoor]
+//! chip = "/dev/gpiochip0"
+//! pins = {led_red = 2, led_green = 3, button_unlock = 4, button_lock = 5}
+//!
+//! [devices.garage]
+//! chip = "/dev/gpiochip0"
+//! pins = {led_red = 6, led_green = 7, button_unlock = 8, button_lock = 9}
+//! profile = "rev2"
+//! ```
+//!
+//! # Example
+//! ```
+//! use cff3000::config::CFF3000Config;
+//! use cff3000::{BusyPolicy, Command, DeviceProfile, Polarity};
+//! use std::time::Duration;
+//!
+//! fn main() {
+//!     let config = CFF3000Config::from_toml_str(r#"
+//!         chip = "/dev/gpiochip2"
+//!         profile = "rev2"
+//!         busy_policy = "fail-fast"
+//!
+//!         [pins]
+//!         led_red = 2
+//!         led_green = 3
+//!         button_unlock = 4
+//!         button_lock = 5
+//!
+//!         [polarities]
+//!         led_red = "active-low"
+//!
+//!         [timings]
+//!         press_ms = 700
+//!         unlock_press_ms = 1200
+//!         merge_window_ms = 80
+//!
+//!         [retry]
+//!         auto_reopen_ms = 30000
+//!
+//!         [rate_limit]
+//!         min_interval_ms = 60000
+//!     "#).unwrap();
+//!
+//!     assert_eq!(config.pins.to_array(), [2, 3, 4, 5]);
+//!     assert_eq!(config.polarities.led_red, Polarity::ActiveLow);
+//!     assert_eq!(config.busy_policy, BusyPolicy::FailFast);
+//!     assert_eq!(config.profile, DeviceProfile::Rev2);
+//!     assert_eq!(config.timings().check_capture, Duration::from_secs(9));
+//!     assert_eq!(config.timings().press, Duration::from_millis(700));
+//!     assert_eq!(config.timings().press_for(Command::Unlock), Duration::from_millis(1200));
+//!     assert_eq!(config.watch_options(Duration::from_secs(300)).min_interval, Duration::from_secs(60));
+//! }
+//! ```
+//!
+//! # Environment
+//!
+//! `CFF3000Config::from_env()` reads the same settings from these
+//! variables, e.g. for containers. Empty variables count as unset.
+//!
+//! | Variable | Setting |
+//! |----------|---------|
+//! | `CFF3000_CHIP` | `chip` (required) |
+//! | `CFF3000_PINS` | `pins` as `red,green,unlock,lock`, e.g. `2,3,4,5` (required) |
+//! | `CFF3000_ACTIVE_LOW` | active-low lines, e.g. `led_red,led_green` |
+//! | `CFF3000_PROFILE` | `profile`: `classic` or `rev2` |
+//! | `CFF3000_PRESS_MS` | `timings.press_ms` |
+//! | `CFF3000_CHECK_CAPTURE_MS` | `timings.check_capture_ms` |
+//! | `CFF3000_COMMAND_CAPTURE_MS` | `timings.command_capture_ms` |
+//! | `CFF3000_LOCK_PRESS_MS` | `timings.lock_press_ms` |
+//! | `CFF3000_UNLOCK_PRESS_MS` | `timings.unlock_press_ms` |
+//! | `CFF3000_CHECK_PRESS_MS` | `timings.check_press_ms` |
+//! | `CFF3000_LOCK_CAPTURE_MS` | `timings.lock_capture_ms` |
+//! | `CFF3000_UNLOCK_CAPTURE_MS` | `timings.unlock_capture_ms` |
+//! | `CFF3000_MERGE_WINDOW_MS` | `timings.merge_window_ms` |
+//! | `CFF3000_POLL_PERIOD_MS` | `timings.poll_period_ms` |
+//! | `CFF3000_AUTO_REOPEN_MS` | `retry.auto_reopen_ms` |
+//! | `CFF3000_MAX_CONSECUTIVE_ERRORS` | `retry.max_consecutive_errors` |
+//! | `CFF3000_MIN_INTERVAL_MS` | `rate_limit.min_interval_ms` |
+//! | `CFF3000_JITTER_MS` | `rate_limit.jitter_ms` |
+//! | `CFF3000_BUSY_POLICY` | `busy_policy`: `wait` or `fail-fast` |
+//! | `CFF3000_LOCKFILE` | `lockfile` |
+//! | `CFF3000_STATE_FILE` | `state_file` |
+//!
+//! Invalid values fail with `ErrorKind::InvalidInput`, naming the
+//! variable and its value. `CFF3000Config::merged()` combines a file,
+//! the environment and overrides (e.g. command line options).
+//!
+//! # Layering
+//!
+//! [`ConfigSources`] stacks any number of partial [`ConfigLayer`]s and
+//! reports the origin of each effective value. The `cff3000` binary
+//! uses, in increasing precedence:
+//!
+//! 1. built-in defaults and the values of the device profile
+//! 2. [`SYSTEM_CONFIG_FILE`] (`/etc/cff3000.toml`)
+//! 3. [`user_config_file()`] (`~/.config/cff3000/config.toml`)
+//! 4. the environment variables above
+//! 5. command line options
+//!
+//! An explicit configuration file replaces 2. and 3. Every file may
+//! leave out any setting, only the merged result needs `chip` and
+//! `pins`. Errors in a file name it, the line and the key, e.g.
+//! `/etc/cff3000.toml:9: timings.press_ms: invalid type: string "x",
+//! expected u64`.
+
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+#[cfg(all(feature = "audit", unix))]
+use crate::audit::{self, AuditLog};
+use crate::battery::BatteryOptions;
+use crate::group::{CFF3000Group, GroupOptions};
+use crate::health::HealthOptions;
+#[cfg(feature = "mqtt")]
+use crate::mqtt::{self, MqttOptions};
+use crate::schedule::{ScheduleAction, ScheduleEntry, TimeOfDay, Weekday};
+#[cfg(feature = "webhook")]
+use crate::webhook::WebhookOptions;
+use crate::{BusyPolicy, CFF3000, CFF3000Builder, DeviceProfile, ParseOptions, PinAssignment, Polarities, Timings, WatchOptions};
+
+mod env;
+mod sources;
+mod validate;
+
+pub use self::sources::{standard_config_files, user_config_file, ConfigEntry, ConfigSource, ConfigSources, SYSTEM_CONFIG_FILE};
+pub use self::validate::{ConfigIssue, Severity};
+
+/// Configuration of a `CFF3000`, see module documentation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CFF3000Config {
+    /// GPIO chip device, e.g. "/dev/gpiochip0"
+    pub chip: String,
+    pub pins: PinAssignment,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub polarities: Polarities,
+    /// Firmware generation, the `timings` override its values
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub profile: DeviceProfile,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub timings: TimingConfig,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub retry: RetryConfig,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub busy_policy: BusyPolicy,
+    /// See `CFF3000Builder::exclusive_lockfile()`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lockfile: Option<PathBuf>,
+    /// Last state and operation counters kept by the `daemon` across
+    /// restarts, see `persist::StateFile`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state_file: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub battery: BatteryConfig,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mqtt: Option<MqttConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<WebhookConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audit: Option<AuditConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health: Option<HealthConfig>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schedule: Vec<ScheduleConfig>,
+}
+
+/// Timing overrides; unset values are taken from the profile.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimingConfig {
+    /// `Timings::press`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub press_ms: Option<u64>,
+    /// `Timings::check_capture`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub check_capture_ms: Option<u64>,
+    /// `Timings::command_capture`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command_capture_ms: Option<u64>,
+    /// `Timings::lock_press`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lock_press_ms: Option<u64>,
+    /// `Timings::unlock_press`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unlock_press_ms: Option<u64>,
+    /// `Timings::check_press`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub check_press_ms: Option<u64>,
+    /// `Timings::lock_capture`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lock_capture_ms: Option<u64>,
+    /// `Timings::unlock_capture`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unlock_capture_ms: Option<u64>,
+    /// `ParseOptions::merge_window`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub merge_window_ms: Option<u64>,
+    /// `ParseOptions::poll_period`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub poll_period_ms: Option<u64>,
+}
+
+/// Error recovery settings.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Timeout of `CFF3000Builder::auto_reopen()` (unset = disabled)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auto_reopen_ms: Option<u64>,
+    /// `WatchOptions::max_consecutive_errors`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_consecutive_errors: Option<u32>,
+}
+
+/// Watch loop rate limit settings.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// `WatchOptions::min_interval`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_interval_ms: Option<u64>,
+    /// `WatchOptions::jitter`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jitter_ms: Option<u64>,
+}
+
+/// Battery estimate, see `cff3000::battery::BatteryOptions`. Unset
+/// values keep the defaults.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatteryConfig {
+    /// `BatteryOptions::budget`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub budget: Option<u64>,
+    /// `BatteryOptions::warn_below_percent`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warn_below_percent: Option<u8>,
+}
+
+impl BatteryConfig {
+    /// Estimate with these settings.
+    pub fn options(&self) -> BatteryOptions {
+        let defaults = BatteryOptions::default();
+        BatteryOptions {
+            budget: self.budget.unwrap_or(defaults.budget),
+            warn_below_percent: self.warn_below_percent.unwrap_or(defaults.warn_below_percent),
+        }
+    }
+}
+
+/// Health check, see `cff3000::health::HealthOptions`. Unset values
+/// keep the defaults.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// `HealthOptions::interval`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interval_ms: Option<u64>,
+    /// `HealthOptions::stuck_after`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stuck_after_ms: Option<u64>,
+    /// `HealthOptions::reopen`, needs `retry.auto_reopen_ms`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reopen: Option<bool>,
+}
+
+impl HealthConfig {
+    /// Health check with these settings.
+    pub fn options(&self) -> HealthOptions {
+        let defaults = HealthOptions::default();
+        HealthOptions {
+            interval: ms(self.interval_ms, defaults.interval),
+            stuck_after: ms(self.stuck_after_ms, defaults.stuck_after),
+            reopen: self.reopen.unwrap_or(defaults.reopen),
+        }
+    }
+}
+
+/// MQTT broker connection, see `cff3000::mqtt::MqttOptions`. Unset
+/// values keep the defaults of `MqttOptions::new()`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    pub host: String,
+    /// Defaults to 1883, or 8883 with TLS
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// Only sent with `username`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// `MqttOptions::keep_alive`, 0 or whole seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_alive_ms: Option<u64>,
+    /// `MqttOptions::change_topic`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub change_topic: Option<String>,
+    /// Connect with TLS
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<MqttTlsConfig>,
+}
+
+/// TLS settings of the broker connection, see
+/// `cff3000::mqtt::TlsConfig`.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttTlsConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_cert: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_cert: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_key: Option<PathBuf>,
+    #[serde(skip_serializing_if = "is_default")]
+    pub insecure_skip_verify: bool,
+}
+
+impl MqttConfig {
+    /// Connection to `host` with the default settings.
+    pub fn new(host: &str) -> MqttConfig {
+        MqttConfig {host: host.to_string(), port: None, client_id: None, username: None, password: None, keep_alive_ms: None, change_topic: None, tls: None}
+    }
+
+    /// Publisher options with these settings and the default topics.
+    #[cfg(feature = "mqtt")]
+    pub fn options(&self) -> MqttOptions {
+        let mut options = MqttOptions::new(&self.host);
+        options.port = match (self.port, &self.tls) {
+            (Some(port), _) => port,
+            (None, &Some(_)) => mqtt::DEFAULT_TLS_PORT,
+            (None, &None) => mqtt::DEFAULT_PORT,
+        };
+        if let Some(ref id) = self.client_id {
+            options.client_id = id.clone();
+        }
+        if let Some(ref user) = self.username {
+            options.credentials = Some((user.clone(), self.password.clone().unwrap_or_default()));
+        }
+        options.keep_alive = ms(self.keep_alive_ms, options.keep_alive);
+        options.change_topic = self.change_topic.clone();
+        options.tls = self.tls.as_ref().map(|tls| mqtt::TlsConfig {
+            ca_cert: tls.ca_cert.clone(),
+            client_cert: tls.client_cert.clone(),
+            client_key: tls.client_key.clone(),
+            insecure_skip_verify: tls.insecure_skip_verify,
+        });
+        options
+    }
+}
+
+/// Webhook notifications, see `cff3000::webhook::WebhookOptions`.
+/// Unset values keep the defaults of `WebhookOptions::new()`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Sent as bearer token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u32>,
+}
+
+impl WebhookConfig {
+    /// Notifications to `url` with the default settings.
+    pub fn new(url: &str) -> WebhookConfig {
+        WebhookConfig {
+            url: url.to_string(),
+            token: None,
+            headers: BTreeMap::new(),
+            template: None,
+            device_name: None,
+            timeout_ms: None,
+            retries: None,
+        }
+    }
+
+    /// Notifier options with these settings.
+    #[cfg(feature = "webhook")]
+    pub fn options(&self) -> WebhookOptions {
+        let mut options = WebhookOptions::new(&self.url);
+        options.token = self.token.clone();
+        options.headers = self.headers.iter().map(|(name, value)| (name.clone(), value.clone())).collect();
+        if let Some(ref template) = self.template {
+            options.template = template.clone();
+        }
+        if let Some(ref name) = self.device_name {
+            options.device_name = name.clone();
+        }
+        options.timeout = ms(self.timeout_ms, options.timeout);
+        options.retries = self.retries.unwrap_or(options.retries);
+        options
+    }
+}
+
+/// Where the audit log goes, see `cff3000::audit::AuditTarget`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditTarget {
+    Journal,
+    Syslog,
+}
+
+/// Audit log, see `cff3000::audit::AuditLog`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuditConfig {
+    pub target: AuditTarget,
+    /// Socket instead of the default one of the target
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socket: Option<PathBuf>,
+}
+
+impl AuditConfig {
+    /// Audit log writing to `target`.
+    pub fn new(target: AuditTarget) -> AuditConfig {
+        AuditConfig {target, socket: None}
+    }
+
+    /// Start the audit log with these settings.
+    #[cfg(all(feature = "audit", unix))]
+    pub fn log(&self) -> std::io::Result<AuditLog> {
+        let target = match self.target {
+            AuditTarget::Journal => audit::AuditTarget::Journal,
+            AuditTarget::Syslog => audit::AuditTarget::Syslog,
+        };
+        match self.socket {
+            Some(ref path) => AuditLog::with_socket(target, path),
+            None => AuditLog::new(target),
+        }
+    }
+}
+
+/// Scheduled action, see `cff3000::schedule::ScheduleEntry`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleConfig {
+    /// Local time, e.g. "22:30"
+    pub at: TimeOfDay,
+    pub action: ScheduleAction,
+    /// Every day if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<Weekday>,
+    /// `ScheduleEntry::grace`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grace_ms: Option<u64>,
+}
+
+impl ScheduleConfig {
+    /// `action` at `at` every day with the default grace time.
+    pub fn new(at: TimeOfDay, action: ScheduleAction) -> ScheduleConfig {
+        ScheduleConfig {at, action, days: Vec::new(), grace_ms: None}
+    }
+
+    /// Schedule entry with these settings.
+    pub fn entry(&self) -> ScheduleEntry {
+        let mut entry = ScheduleEntry::new(self.at, self.action);
+        entry.days = self.days.clone();
+        entry.grace = ms(self.grace_ms, entry.grace);
+        entry
+    }
+}
+
+/// Partial configuration, one layer of `CFF3000Config::merged()`.
+/// Unset (`None`) values are taken from the layer below.
+///
+/// Same TOML format as `CFF3000Config`, without required keys.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigLayer {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pins: Option<PinAssignment>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub polarities: Option<Polarities>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<DeviceProfile>,
+    #[serde(skip_serializing_if = "is_default")]
+    pub timings: TimingConfig,
+    #[serde(skip_serializing_if = "is_default")]
+    pub retry: RetryConfig,
+    #[serde(skip_serializing_if = "is_default")]
+    pub rate_limit: RateLimitConfig,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub busy_policy: Option<BusyPolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lockfile: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_file: Option<PathBuf>,
+    #[serde(skip_serializing_if = "is_default")]
+    pub battery: BatteryConfig,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mqtt: Option<MqttConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<WebhookConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audit: Option<AuditConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health: Option<HealthConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<Vec<ScheduleConfig>>,
+}
+
+impl ConfigLayer {
+    /// Layer from the `CFF3000_*` environment variables, see module
+    /// documentation.
+    pub fn from_env() -> std::io::Result<ConfigLayer> {
+        env::from_env()
+    }
+
+    /// Parse a partial TOML document, see `CFF3000Config::from_toml_str()`.
+    pub fn from_toml_str(text: &str) -> std::io::Result<ConfigLayer> {
+        from_toml(text, None)
+    }
+
+    /// Read a partial TOML file, see `CFF3000Config::from_toml_file()`.
+    pub fn from_toml_file<P: AsRef<Path>>(path: P) -> std::io::Result<ConfigLayer> {
+        from_toml_file(path.as_ref())
+    }
+
+    /// Like `from_env()`, for `(name, value)` pairs instead of the
+    /// process environment. Names not listed in the module
+    /// documentation are ignored.
+    pub fn from_vars<I: IntoIterator<Item = (String, String)>>(vars: I) -> std::io::Result<ConfigLayer> {
+        env::from_vars(vars)
+    }
+
+    /// Combine with `top`, whose values take precedence.
+    pub fn merge(self, top: ConfigLayer) -> ConfigLayer {
+        ConfigLayer {
+            chip: top.chip.or(self.chip),
+            pins: top.pins.or(self.pins),
+            polarities: top.polarities.or(self.polarities),
+            profile: top.profile.or(self.profile),
+            timings: TimingConfig {
+                press_ms: top.timings.press_ms.or(self.timings.press_ms),
+                check_capture_ms: top.timings.check_capture_ms.or(self.timings.check_capture_ms),
+                command_capture_ms: top.timings.command_capture_ms.or(self.timings.command_capture_ms),
+                lock_press_ms: top.timings.lock_press_ms.or(self.timings.lock_press_ms),
+                unlock_press_ms: top.timings.unlock_press_ms.or(self.timings.unlock_press_ms),
+                check_press_ms: top.timings.check_press_ms.or(self.timings.check_press_ms),
+                lock_capture_ms: top.timings.lock_capture_ms.or(self.timings.lock_capture_ms),
+                unlock_capture_ms: top.timings.unlock_capture_ms.or(self.timings.unlock_capture_ms),
+                merge_window_ms: top.timings.merge_window_ms.or(self.timings.merge_window_ms),
+                poll_period_ms: top.timings.poll_period_ms.or(self.timings.poll_period_ms),
+            },
+            retry: RetryConfig {
+                auto_reopen_ms: top.retry.auto_reopen_ms.or(self.retry.auto_reopen_ms),
+                max_consecutive_errors: top.retry.max_consecutive_errors.or(self.retry.max_consecutive_errors),
+            },
+            rate_limit: RateLimitConfig {
+                min_interval_ms: top.rate_limit.min_interval_ms.or(self.rate_limit.min_interval_ms),
+                jitter_ms: top.rate_limit.jitter_ms.or(self.rate_limit.jitter_ms),
+            },
+            busy_policy: top.busy_policy.or(self.busy_policy),
+            lockfile: top.lockfile.or(self.lockfile),
+            state_file: top.state_file.or(self.state_file),
+            battery: BatteryConfig {
+                budget: top.battery.budget.or(self.battery.budget),
+                warn_below_percent: top.battery.warn_below_percent.or(self.battery.warn_below_percent),
+            },
+            mqtt: top.mqtt.or(self.mqtt),
+            webhook: top.webhook.or(self.webhook),
+            audit: top.audit.or(self.audit),
+            health: top.health.or(self.health),
+            schedule: top.schedule.or(self.schedule),
+        }
+    }
+
+    /// Complete configuration, failing with `ErrorKind::InvalidInput`
+    /// if `chip` or `pins` is unset.
+    pub fn into_config(self) -> std::io::Result<CFF3000Config> {
+        let missing = |key: &str, var: &str| {
+            Error::new(ErrorKind::InvalidInput, format!("{} is not configured (set it in the configuration file or {})", key, var))
+        };
+        Ok(CFF3000Config {
+            chip: self.chip.ok_or_else(|| missing("chip", "CFF3000_CHIP"))?,
+            pins: self.pins.ok_or_else(|| missing("pins", "CFF3000_PINS"))?,
+            polarities: self.polarities.unwrap_or_default(),
+            profile: self.profile.unwrap_or_default(),
+            timings: self.timings,
+            retry: self.retry,
+            rate_limit: self.rate_limit,
+            busy_policy: self.busy_policy.unwrap_or_default(),
+            lockfile: self.lockfile,
+            state_file: self.state_file,
+            battery: self.battery,
+            mqtt: self.mqtt,
+            webhook: self.webhook,
+            audit: self.audit,
+            health: self.health,
+            schedule: self.schedule.unwrap_or_default(),
+        })
+    }
+}
+
+impl From<CFF3000Config> for ConfigLayer {
+    fn from(config: CFF3000Config) -> ConfigLayer {
+        ConfigLayer {
+            chip: Some(config.chip),
+            pins: Some(config.pins),
+            polarities: Some(config.polarities),
+            profile: Some(config.profile),
+            timings: config.timings,
+            retry: config.retry,
+            rate_limit: config.rate_limit,
+            busy_policy: Some(config.busy_policy),
+            lockfile: config.lockfile,
+            state_file: config.state_file,
+            battery: config.battery,
+            mqtt: config.mqtt,
+            webhook: config.webhook,
+            audit: config.audit,
+            health: config.health,
+            schedule: Some(config.schedule),
+        }
+    }
+}
+
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+fn ms(value: Option<u64>, default: Duration) -> Duration {
+    value.map(Duration::from_millis).unwrap_or(default)
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs().saturating_mul(1000).saturating_add(duration.subsec_millis() as u64)
+}
+
+/// Line number and key, e.g. (9, "timings.press_ms"), of the TOML
+/// element at byte `offset` of `text`.
+fn locate(text: &str, offset: usize) -> (usize, String) {
+    let before = &text[..offset];
+    let start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    let header = |line: &str| line.trim_start_matches('[').split(']').next().unwrap_or("").trim().to_string();
+
+    let mut table = String::new();
+    for line in before[..start].lines().map(str::trim).filter(|line| line.starts_with('[')) {
+        table = header(line);
+    }
+    let line = text[start..].lines().next().unwrap_or("").trim();
+    let key = if line.starts_with('[') {
+        header(line)
+    } else {
+        let name = line.split('=').next().unwrap_or("").trim().trim_matches('"');
+        if table.is_empty() {name.to_string()} else {format!("{}.{}", table, name)}
+    };
+    (before.matches('\n').count() + 1, key)
+}
+
+/// Parse `text`, read from `path` if given. Errors fail with
+/// `ErrorKind::InvalidData` as "file:line: key: message".
+fn from_toml<T: DeserializeOwned>(text: &str, path: Option<&Path>) -> std::io::Result<T> {
+    toml::from_str(text).map_err(|err| {
+        let mut message = String::new();
+        if let Some(path) = path {
+            message.push_str(&format!("{}:", path.display()));
+        }
+        match err.span().filter(|span| !span.is_empty() || span.start != 0) {
+            Some(span) => {
+                let (line, key) = locate(text, span.start);
+                let location = if path.is_some() {format!("{}", line)} else {format!("line {}", line)};
+                message.push_str(&location);
+                if !key.is_empty() {
+                    message.push_str(&format!(": {}", key));
+                }
+                message.push_str(": ");
+            },
+            None if path.is_some() => message.push(' '),
+            None => {},
+        }
+        message.push_str(&err.message().replace('\n', ", "));
+        Error::new(ErrorKind::InvalidData, message)
+    })
+}
+
+fn from_toml_file<T: DeserializeOwned>(path: &Path) -> std::io::Result<T> {
+    let text = std::fs::read_to_string(path).map_err(|err| Error::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+    from_toml(&text, Some(path))
+}
+
+impl CFF3000Config {
+    /// Configuration with default settings for `pins` on `chip`.
+    pub fn new(chip: &str, pins: PinAssignment) -> CFF3000Config {
+        CFF3000Config {
+            chip: chip.to_string(),
+            pins,
+            polarities: Polarities::default(),
+            profile: DeviceProfile::default(),
+            timings: TimingConfig::default(),
+            retry: RetryConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            busy_policy: BusyPolicy::default(),
+            lockfile: None,
+            state_file: None,
+            battery: BatteryConfig::default(),
+            mqtt: None,
+            webhook: None,
+            audit: None,
+            health: None,
+            schedule: Vec::new(),
+        }
+    }
+
+    /// Parse a TOML document. Syntax errors, unknown keys and
+    /// missing required keys fail with `ErrorKind::InvalidData`,
+    /// naming the line and key, e.g. "line 9: timings.press_ms: ...".
+    pub fn from_toml_str(text: &str) -> std::io::Result<CFF3000Config> {
+        from_toml(text, None)
+    }
+
+    /// Read a TOML file, see `from_toml_str()`. Errors name the file,
+    /// e.g. "/etc/cff3000.toml:9: timings.press_ms: ...".
+    pub fn from_toml_file<P: AsRef<Path>>(path: P) -> std::io::Result<CFF3000Config> {
+        from_toml_file(path.as_ref())
+    }
+
+    /// Read the `CFF3000_*` environment variables, see module
+    /// documentation. `CFF3000_CHIP` and `CFF3000_PINS` are required.
+    pub fn from_env() -> std::io::Result<CFF3000Config> {
+        ConfigLayer::from_env()?.into_config()
+    }
+
+    /// Combine an optional configuration `file`, the `env` layer and
+    /// `overrides`, in increasing precedence.
+    ///
+    /// Polarities, the MQTT connection, the webhook, the audit log, the
+    /// health check and the schedule are taken as a whole from the
+    /// topmost layer setting them, all other values individually.
+    pub fn merged(file: Option<CFF3000Config>, env: ConfigLayer, overrides: ConfigLayer) -> std::io::Result<CFF3000Config> {
+        let base = file.map(ConfigLayer::from).unwrap_or_default();
+        base.merge(env).merge(overrides).into_config()
+    }
+
+    /// Check the settings without accessing the hardware: distinct
+    /// pins, durations within sane bounds and a merge window smaller
+    /// than the blink period of the LEDs.
+    pub fn validate_offline(&self) -> Vec<ConfigIssue> {
+        validate::offline(self)
+    }
+
+    /// Like `validate_offline()`, and also check that the chip exists,
+    /// has all configured lines and that none of them is in use, and
+    /// that the lock file directory exists. All problems are reported,
+    /// not only the first one.
+    ///
+    /// Call this before creating the device, its own lines are in use
+    /// afterwards.
+    pub fn validate(&self) -> Vec<ConfigIssue> {
+        validate::all(self)
+    }
+
+    /// Serialize to TOML, leaving out settings which have their default.
+    pub fn to_toml_string(&self) -> std::io::Result<String> {
+        toml::to_string(self).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+    }
+
+    /// Press durations and capture windows of the profile with the
+    /// configured overrides.
+    pub fn timings(&self) -> Timings {
+        let defaults = self.profile.timing().timings;
+        Timings {
+            press: ms(self.timings.press_ms, defaults.press),
+            check_capture: ms(self.timings.check_capture_ms, defaults.check_capture),
+            command_capture: ms(self.timings.command_capture_ms, defaults.command_capture),
+            lock_press: self.timings.lock_press_ms.map(Duration::from_millis).or(defaults.lock_press),
+            unlock_press: self.timings.unlock_press_ms.map(Duration::from_millis).or(defaults.unlock_press),
+            check_press: self.timings.check_press_ms.map(Duration::from_millis).or(defaults.check_press),
+            lock_capture: self.timings.lock_capture_ms.map(Duration::from_millis).or(defaults.lock_capture),
+            unlock_capture: self.timings.unlock_capture_ms.map(Duration::from_millis).or(defaults.unlock_capture),
+        }
+    }
+
+    /// Parser settings, if any are configured. Unset values of a
+    /// partial override come from the profile.
+    pub fn parse_options(&self) -> Option<ParseOptions> {
+        if self.timings.merge_window_ms.is_none() && self.timings.poll_period_ms.is_none() {
+            return None;
+        }
+        let defaults = self.profile.timing().parse_options();
+        Some(ParseOptions {
+            merge_window: ms(self.timings.merge_window_ms, defaults.merge_window),
+            poll_period: ms(self.timings.poll_period_ms, defaults.poll_period),
+        })
+    }
+
+    /// Watch loop options for `poll_interval` with the configured
+    /// retry and rate limit settings.
+    pub fn watch_options(&self, poll_interval: Duration) -> WatchOptions {
+        let mut options = WatchOptions::new(poll_interval);
+        options.min_interval = ms(self.rate_limit.min_interval_ms, options.min_interval);
+        options.jitter = ms(self.rate_limit.jitter_ms, options.jitter);
+        if let Some(max) = self.retry.max_consecutive_errors {
+            options.max_consecutive_errors = max;
+        }
+        options
+    }
+}
+
+impl CFF3000Builder {
+    /// Start building a device from `config`, e.g. to add a monitor
+    /// before calling `build()`.
+    pub fn from_config(config: &CFF3000Config) -> CFF3000Builder {
+        let mut builder = CFF3000Builder::with_pins(&config.chip, config.pins)
+            .busy_policy(config.busy_policy)
+            .profile(config.profile)
+            .timings(config.timings())
+            .polarities(config.polarities);
+        if let Some(options) = config.parse_options() {
+            builder = builder.parse_options(options);
+        }
+        if let Some(timeout) = config.retry.auto_reopen_ms {
+            builder = builder.auto_reopen(Duration::from_millis(timeout));
+        }
+        if let Some(ref path) = config.lockfile {
+            builder = builder.exclusive_lockfile(path);
+        }
+        if let Some(ref health) = config.health {
+            builder = builder.health_check(health.options());
+        }
+        builder.battery(config.battery.options())
+    }
+}
+
+impl CFF3000 {
+    /// Create a device as described by `config`.
+    pub fn from_config(config: &CFF3000Config) -> std::io::Result<CFF3000> {
+        CFF3000Builder::from_config(config).build()
+    }
+}
+
+/// Devices of a `CFF3000Group`, see module documentation.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GroupConfig {
+    /// `GroupOptions::spacing`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spacing_ms: Option<u64>,
+    pub devices: BTreeMap<String, CFF3000Config>,
+}
+
+impl GroupConfig {
+    /// Parse a TOML document, see `CFF3000Config::from_toml_str()`.
+    pub fn from_toml_str(text: &str) -> std::io::Result<GroupConfig> {
+        from_toml(text, None)
+    }
+
+    /// Read a TOML file, see `CFF3000Config::from_toml_file()`.
+    pub fn from_toml_file<P: AsRef<Path>>(path: P) -> std::io::Result<GroupConfig> {
+        from_toml_file(path.as_ref())
+    }
+
+    /// Serialize to TOML, leaving out settings which have their default.
+    pub fn to_toml_string(&self) -> std::io::Result<String> {
+        toml::to_string(self).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+    }
+
+    /// Options of the group.
+    pub fn options(&self) -> GroupOptions {
+        GroupOptions {spacing: ms(self.spacing_ms, GroupOptions::default().spacing)}
+    }
+
+    /// `CFF3000Config::validate_offline()` of every device, with the
+    /// fields under `devices.<name>`, and also check the names and that
+    /// no line of a chip belongs to two devices.
+    pub fn validate_offline(&self) -> Vec<ConfigIssue> {
+        validate::group_offline(self)
+    }
+
+    /// Like `validate_offline()` with `CFF3000Config::validate()`.
+    pub fn validate(&self) -> Vec<ConfigIssue> {
+        validate::group_all(self)
+    }
+}
+
+impl CFF3000Group {
+    /// Create the devices of `config` like `CFF3000::from_config()`.
+    ///
+    /// For a `monitor()`, `add()` the `CFF3000Builder::from_config()` of
+    /// every device to a group with `GroupConfig::options()` instead.
+    pub fn from_config(config: &GroupConfig) -> std::io::Result<CFF3000Group> {
+        let mut group = CFF3000Group::new(config.options());
+        for (name, device) in &config.devices {
+            group.add(name, CFF3000Builder::from_config(device))?;
+        }
+        Ok(group)
+    }
+}
@@ -0,0 +1,190 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Layered configuration with the origin of every value, see
+//! `ConfigSources`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::battery::BatteryOptions;
+use crate::{DeviceProfile, WatchOptions};
+use super::{millis, BatteryConfig, CFF3000Config, ConfigLayer, RateLimitConfig, RetryConfig, TimingConfig};
+
+/// System wide configuration file.
+pub const SYSTEM_CONFIG_FILE: &str = "/etc/cff3000.toml";
+
+/// Origin of a configuration value, see `ConfigSources::entries()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    /// Built-in default or a value of the device profile
+    Default,
+    /// Configuration file
+    File(PathBuf),
+    /// `CFF3000_*` environment variables
+    Env,
+    /// Command line options
+    CommandLine,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigSource::Default => f.write_str("default"),
+            ConfigSource::File(ref path) => write!(f, "{}", path.display()),
+            ConfigSource::Env => f.write_str("environment"),
+            ConfigSource::CommandLine => f.write_str("command line"),
+        }
+    }
+}
+
+/// Effective value of one setting, see `ConfigSources::entries()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigEntry {
+    /// Setting as in the TOML file, e.g. "timings.press_ms"
+    pub key: String,
+    /// Value in TOML syntax, e.g. `500` or `"/dev/gpiochip2"`
+    pub value: String,
+    /// Topmost layer setting the value
+    pub source: ConfigSource,
+}
+
+/// Configuration layers with their origin, lowest precedence first.
+///
+/// Like `CFF3000Config::merged()`, but for any number of layers and
+/// keeping track of where each value came from, e.g. to explain the
+/// effective configuration to the user.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigSources {
+    layers: Vec<(ConfigSource, ConfigLayer)>,
+}
+
+/// User configuration file: `$XDG_CONFIG_HOME/cff3000/config.toml`,
+/// or `~/.config/cff3000/config.toml` if `XDG_CONFIG_HOME` is unset.
+/// `None` if neither variable is set.
+pub fn user_config_file() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME").filter(|dir| !dir.is_empty())?).join(".config"),
+    };
+    Some(dir.join("cff3000").join("config.toml"))
+}
+
+/// `SYSTEM_CONFIG_FILE` and `user_config_file()`, lowest precedence
+/// first.
+pub fn standard_config_files() -> Vec<PathBuf> {
+    let mut files = vec![PathBuf::from(SYSTEM_CONFIG_FILE)];
+    files.extend(user_config_file());
+    files
+}
+
+/// Built-in values of all settings with a default for `profile`.
+fn defaults(profile: DeviceProfile) -> ConfigLayer {
+    let timing = profile.timing();
+    let watch = WatchOptions::new(Duration::from_secs(0));
+    let battery = BatteryOptions::default();
+    ConfigLayer {
+        polarities: Some(Default::default()),
+        profile: Some(profile),
+        timings: TimingConfig {
+            press_ms: Some(millis(timing.timings.press)),
+            check_capture_ms: Some(millis(timing.timings.check_capture)),
+            command_capture_ms: Some(millis(timing.timings.command_capture)),
+            lock_press_ms: timing.timings.lock_press.map(millis),
+            unlock_press_ms: timing.timings.unlock_press.map(millis),
+            check_press_ms: timing.timings.check_press.map(millis),
+            lock_capture_ms: timing.timings.lock_capture.map(millis),
+            unlock_capture_ms: timing.timings.unlock_capture.map(millis),
+            merge_window_ms: Some(millis(timing.merge_window)),
+            poll_period_ms: Some(0),
+        },
+        retry: RetryConfig {
+            auto_reopen_ms: None,
+            max_consecutive_errors: Some(watch.max_consecutive_errors),
+        },
+        rate_limit: RateLimitConfig {
+            min_interval_ms: Some(millis(watch.min_interval)),
+            jitter_ms: Some(millis(watch.jitter)),
+        },
+        busy_policy: Some(Default::default()),
+        battery: BatteryConfig {
+            budget: Some(battery.budget),
+            warn_below_percent: Some(battery.warn_below_percent),
+        },
+        ..ConfigLayer::default()
+    }
+}
+
+/// Add the settings of `layer` to `entries` as dotted keys.
+fn flatten(entries: &mut BTreeMap<String, (String, ConfigSource)>, prefix: &str, table: toml::Table, source: &ConfigSource) {
+    for (key, value) in table {
+        let key = if prefix.is_empty() {key} else {format!("{}.{}", prefix, key)};
+        match value {
+            toml::Value::Table(table) => flatten(entries, &key, table, source),
+            /* shown by `cff3000 config show` */
+            _ if key == "mqtt.password" || key == "webhook.token" || key.eq_ignore_ascii_case("webhook.headers.authorization") => {
+                entries.insert(key, ("\"<redacted>\"".to_string(), source.clone()));
+            },
+            value => {entries.insert(key, (value.to_string(), source.clone()));},
+        }
+    }
+}
+
+impl ConfigSources {
+    pub fn new() -> ConfigSources {
+        ConfigSources::default()
+    }
+
+    /// Add `layer` on top of the existing layers.
+    pub fn push(&mut self, source: ConfigSource, layer: ConfigLayer) {
+        self.layers.push((source, layer));
+    }
+
+    /// Add the configuration file at `path`, see
+    /// `ConfigLayer::from_toml_file()`.
+    pub fn push_file<P: AsRef<Path>>(&mut self, path: P) -> std::io::Result<()> {
+        let path = path.as_ref();
+        let layer = ConfigLayer::from_toml_file(path)?;
+        self.push(ConfigSource::File(path.to_path_buf()), layer);
+        Ok(())
+    }
+
+    /// Like `push_file()`, but a missing file is skipped. Returns
+    /// whether the file exists.
+    pub fn push_file_if_exists<P: AsRef<Path>>(&mut self, path: P) -> std::io::Result<bool> {
+        match self.push_file(path) {
+            Ok(()) => Ok(true),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// All layers merged, see `ConfigLayer::merge()`.
+    pub fn layer(&self) -> ConfigLayer {
+        self.layers.iter().fold(ConfigLayer::default(), |base, (_, layer)| base.merge(layer.clone()))
+    }
+
+    /// Complete configuration, failing like `ConfigLayer::into_config()`.
+    pub fn config(&self) -> std::io::Result<CFF3000Config> {
+        self.layer().into_config()
+    }
+
+    /// Effective value and origin of every setting, sorted by key.
+    ///
+    /// Settings left unset by all layers are reported with their
+    /// built-in or profile value and `ConfigSource::Default`. `chip`
+    /// and `pins` are missing if they are not configured.
+    pub fn entries(&self) -> std::io::Result<Vec<ConfigEntry>> {
+        let profile = self.layer().profile.unwrap_or_default();
+        let mut entries = BTreeMap::new();
+        let defaults = (ConfigSource::Default, defaults(profile));
+        for (source, layer) in Some(&defaults).into_iter().chain(&self.layers) {
+            let table = toml::Table::try_from(layer).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+            flatten(&mut entries, "", table, source);
+        }
+        Ok(entries.into_iter().map(|(key, (value, source))| ConfigEntry {key, value, source}).collect())
+    }
+}
@@ -0,0 +1,292 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Configuration checks, see `CFF3000Config::validate()`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::discover;
+use crate::group;
+use super::{millis, CFF3000Config, GroupConfig};
+
+/// Presses shorter than this are often not registered.
+const MIN_PRESS_MS: u64 = 100;
+/// Presses longer than this keep the lines busy for no benefit.
+const MAX_PRESS_MS: u64 = 5000;
+/// Capture windows longer than this block operations for no benefit.
+const MAX_CAPTURE_MS: u64 = 60000;
+
+/// How serious a `ConfigIssue` is.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// Probably unintended, but the device can be used
+    Warning,
+    /// The device cannot be created or will not work
+    Error,
+}
+
+/// Problem found by `CFF3000Config::validate()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigIssue {
+    /// Affected setting as in the TOML file, e.g. "pins.button_lock"
+    pub field: String,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}: {}: {}", severity, self.field, self.message)
+    }
+}
+
+struct Issues(Vec<ConfigIssue>);
+
+impl Issues {
+    fn push(&mut self, field: &str, severity: Severity, message: String) {
+        self.0.push(ConfigIssue {field: field.to_string(), severity, message});
+    }
+}
+
+/// Pin fields in `LineRole::ALL` order.
+const PINS: [&str; 4] = ["pins.led_red", "pins.led_green", "pins.button_unlock", "pins.button_lock"];
+
+fn check_durations(config: &CFF3000Config, issues: &mut Issues) {
+    let timings = &config.timings;
+    let profile = config.profile.timing();
+    let pattern_length_ms = millis(profile.pattern_length);
+    let blink_period_ms = millis(profile.blink_period);
+
+    let presses = [
+        ("timings.press_ms", timings.press_ms),
+        ("timings.lock_press_ms", timings.lock_press_ms),
+        ("timings.unlock_press_ms", timings.unlock_press_ms),
+        ("timings.check_press_ms", timings.check_press_ms),
+    ];
+    for &(field, value) in &presses {
+        match value {
+            Some(0) => issues.push(field, Severity::Error, "must not be zero".to_string()),
+            Some(ms) if ms < MIN_PRESS_MS => issues.push(field, Severity::Warning,
+                format!("{} ms is shorter than {} ms and may not be registered", ms, MIN_PRESS_MS)),
+            Some(ms) if ms > MAX_PRESS_MS => issues.push(field, Severity::Warning,
+                format!("{} ms is longer than {} ms", ms, MAX_PRESS_MS)),
+            _ => {},
+        }
+    }
+
+    let captures = [
+        ("timings.check_capture_ms", timings.check_capture_ms),
+        ("timings.command_capture_ms", timings.command_capture_ms),
+        ("timings.lock_capture_ms", timings.lock_capture_ms),
+        ("timings.unlock_capture_ms", timings.unlock_capture_ms),
+    ];
+    for &(field, value) in &captures {
+        match value {
+            Some(0) => issues.push(field, Severity::Error, "must not be zero".to_string()),
+            Some(ms) if ms < pattern_length_ms => issues.push(field, Severity::Warning,
+                format!("{} ms is shorter than the LED patterns ({} ms), they will be cut off", ms, pattern_length_ms)),
+            Some(ms) if ms > MAX_CAPTURE_MS => issues.push(field, Severity::Warning,
+                format!("{} ms is longer than {} ms", ms, MAX_CAPTURE_MS)),
+            _ => {},
+        }
+    }
+
+    /* both are added up by the parser */
+    let options = config.parse_options().unwrap_or_else(|| profile.parse_options());
+    let window_ms = millis(options.merge_window.saturating_add(options.poll_period));
+    if window_ms >= blink_period_ms {
+        let field = match timings.merge_window_ms {
+            Some(_) => "timings.merge_window_ms",
+            None => "timings.poll_period_ms",
+        };
+        issues.push(field, Severity::Error, format!(
+            "merge window plus poll period ({} ms) must be smaller than the blink period ({} ms)", window_ms, blink_period_ms));
+    } else if timings.merge_window_ms == Some(0) {
+        issues.push("timings.merge_window_ms", Severity::Warning,
+            "LED changes are never simultaneous to the nanosecond, patterns will not be recognized".to_string());
+    }
+
+    if config.retry.auto_reopen_ms == Some(0) {
+        issues.push("retry.auto_reopen_ms", Severity::Warning, "a timeout of zero never waits for the chip to come back".to_string());
+    }
+    if config.retry.max_consecutive_errors == Some(0) {
+        issues.push("retry.max_consecutive_errors", Severity::Warning, "0 retries forever".to_string());
+    }
+}
+
+fn check_pins(config: &CFF3000Config, issues: &mut Issues) {
+    let pins = config.pins.to_array();
+    for (i, (&key, &pin)) in PINS.iter().zip(&pins).enumerate() {
+        if let Some((first, _)) = PINS.iter().zip(&pins).take(i).find(|&(_, &other)| other == pin) {
+            issues.push(key, Severity::Error, format!("line {} is already used for {}", pin, first));
+        }
+    }
+}
+
+/// The directory of the file `key`, if set, must exist.
+fn check_directory(key: &str, path: Option<&PathBuf>, issues: &mut Issues) {
+    let dir = match path.and_then(|path| path.parent()) {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => return,
+    };
+    if !dir.is_dir() {
+        issues.push(key, Severity::Error, format!("directory {} does not exist", dir.display()));
+    }
+}
+
+fn check_chip(config: &CFF3000Config, issues: &mut Issues) {
+    let chip = match discover::chip(&config.chip) {
+        Ok(chip) => chip,
+        Err(err) => return issues.push("chip", Severity::Error, format!("cannot open {}: {}", config.chip, err)),
+    };
+
+    for (&key, pin) in PINS.iter().zip(config.pins.to_array()) {
+        if pin >= chip.lines {
+            issues.push(key, Severity::Error, format!("line {} does not exist, {} has {} lines", pin, chip.label, chip.lines));
+            continue;
+        }
+        match chip.line(pin) {
+            Ok(ref line) if line.flags.used => {
+                let consumer = line.consumer.as_ref().map_or(String::new(), |consumer| format!(" by \"{}\"", consumer));
+                issues.push(key, Severity::Error, format!("line {} is in use{}", pin, consumer));
+            },
+            Ok(_) => {},
+            Err(err) => issues.push(key, Severity::Error, format!("cannot read line {}: {}", pin, err)),
+        }
+    }
+}
+
+fn check_mqtt(config: &CFF3000Config, issues: &mut Issues) {
+    let mqtt = match config.mqtt {
+        Some(ref mqtt) => mqtt,
+        None => return,
+    };
+    if mqtt.host.is_empty() {
+        issues.push("mqtt.host", Severity::Error, "must not be empty".to_string());
+    }
+    if mqtt.password.is_some() && mqtt.username.is_none() {
+        issues.push("mqtt.password", Severity::Error, "is only used with mqtt.username".to_string());
+    }
+    match mqtt.keep_alive_ms {
+        Some(ms) if ms % 1000 != 0 => issues.push("mqtt.keep_alive_ms", Severity::Error,
+            format!("{} ms is not a whole number of seconds", ms)),
+        _ => {},
+    }
+    if let Some(ref tls) = mqtt.tls {
+        if tls.insecure_skip_verify {
+            issues.push("mqtt.tls.insecure_skip_verify", Severity::Warning,
+                "the broker certificate is not verified, anyone on the path can impersonate it".to_string());
+        } else if tls.ca_cert.is_none() {
+            issues.push("mqtt.tls.ca_cert", Severity::Error, "is required unless mqtt.tls.insecure_skip_verify is set".to_string());
+        }
+        match (&tls.client_cert, &tls.client_key) {
+            (&Some(_), &None) => issues.push("mqtt.tls.client_key", Severity::Error, "is required with mqtt.tls.client_cert".to_string()),
+            (&None, &Some(_)) => issues.push("mqtt.tls.client_cert", Severity::Error, "is required with mqtt.tls.client_key".to_string()),
+            _ => {},
+        }
+    }
+}
+
+fn check_webhook(config: &CFF3000Config, issues: &mut Issues) {
+    let webhook = match config.webhook {
+        Some(ref webhook) => webhook,
+        None => return,
+    };
+    if webhook.url.starts_with("http://") {
+        if webhook.token.is_some() {
+            issues.push("webhook.token", Severity::Warning, "is sent unencrypted, webhook.url is not https://".to_string());
+        }
+    } else if !webhook.url.starts_with("https://") {
+        issues.push("webhook.url", Severity::Error, format!("{} is not an http:// or https:// URL", webhook.url));
+    }
+    if webhook.timeout_ms == Some(0) {
+        issues.push("webhook.timeout_ms", Severity::Error, "must not be 0".to_string());
+    }
+}
+
+fn check_battery(config: &CFF3000Config, issues: &mut Issues) {
+    if config.battery.budget == Some(0) {
+        issues.push("battery.budget", Severity::Error, "must not be 0".to_string());
+    }
+    match config.battery.warn_below_percent {
+        Some(percent) if percent > 100 => issues.push("battery.warn_below_percent", Severity::Error,
+            format!("{} is more than 100 percent", percent)),
+        _ => {},
+    }
+}
+
+fn check_health(config: &CFF3000Config, issues: &mut Issues) {
+    let health = match config.health {
+        Some(ref health) => health,
+        None => return,
+    };
+    if health.interval_ms == Some(0) {
+        issues.push("health.interval_ms", Severity::Error, "must not be 0".to_string());
+    }
+    if health.stuck_after_ms == Some(0) {
+        issues.push("health.stuck_after_ms", Severity::Error, "must not be 0".to_string());
+    }
+    if health.reopen == Some(true) && config.retry.auto_reopen_ms.is_none() {
+        issues.push("health.reopen", Severity::Warning, "has no effect without retry.auto_reopen_ms".to_string());
+    }
+}
+
+pub(super) fn offline(config: &CFF3000Config) -> Vec<ConfigIssue> {
+    let mut issues = Issues(Vec::new());
+    check_pins(config, &mut issues);
+    check_durations(config, &mut issues);
+    check_mqtt(config, &mut issues);
+    check_webhook(config, &mut issues);
+    check_battery(config, &mut issues);
+    check_health(config, &mut issues);
+    issues.0
+}
+
+pub(super) fn all(config: &CFF3000Config) -> Vec<ConfigIssue> {
+    let mut issues = Issues(offline(config));
+    check_directory("lockfile", config.lockfile.as_ref(), &mut issues);
+    check_directory("state_file", config.state_file.as_ref(), &mut issues);
+    check_chip(config, &mut issues);
+    issues.0
+}
+
+/// Issues of the devices of `config` found by `check`, and the lines
+/// shared between devices.
+fn group(config: &GroupConfig, check: fn(&CFF3000Config) -> Vec<ConfigIssue>) -> Vec<ConfigIssue> {
+    let mut issues = Issues(Vec::new());
+    if config.devices.is_empty() {
+        issues.push("devices", Severity::Error, "the group has no devices".to_string());
+    }
+    let mut lines: BTreeMap<(&str, u32), String> = BTreeMap::new();
+    for (name, device) in &config.devices {
+        let prefix = format!("devices.{}", name);
+        if let Err(err) = group::check_name(name) {
+            issues.push(&prefix, Severity::Error, err.to_string());
+        }
+        for issue in check(device) {
+            issues.0.push(ConfigIssue {field: format!("{}.{}", prefix, issue.field), ..issue});
+        }
+        for (&line, pin) in device.pins.to_array().iter().zip(&PINS) {
+            let field = format!("{}.{}", prefix, pin);
+            match lines.get(&(device.chip.as_str(), line)) {
+                Some(first) => issues.push(&field, Severity::Error, format!("line {} of {} is already used for {}", line, device.chip, first)),
+                None => {lines.insert((device.chip.as_str(), line), field);},
+            }
+        }
+    }
+    issues.0
+}
+
+pub(super) fn group_offline(config: &GroupConfig) -> Vec<ConfigIssue> {
+    group(config, offline)
+}
+
+pub(super) fn group_all(config: &GroupConfig) -> Vec<ConfigIssue> {
+    group(config, all)
+}
@@ -0,0 +1,75 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Operations shared by local devices, clients of a daemon and other
+//! locks.
+
+use crate::{CFF3000, CFF3000State, Command};
+
+/// Verified commands of a lock, implemented by `CFF3000` and by clients
+/// talking to a process owning one, e.g. `socket::UnixClient`, so
+/// application code does not need to know which one it uses.
+///
+/// Other hardware implements it as well to be driven by the same code
+/// and by `CommandQueue::for_lock()`, `http::serve_lock()` and the
+/// publishers of `mqtt`. Its states are mapped to `CFF3000State`, e.g.
+/// `OutOfRange` for a lock which cannot tell, and its errors to
+/// `std::io::Error`, with `ErrorKind::WouldBlock` while the lock is
+/// busy so the integrations report `busy`. A fake for tests is
+/// `testing::FakeLock`.
+///
+/// # Example
+/// ```
+/// use cff3000::{CFF3000State, LockControl};
+///
+/// fn lock_door<L: LockControl>(door: &L) -> std::io::Result<bool> {
+///     Ok(door.lock_and_verify()? == CFF3000State::Locked)
+/// }
+/// # fn main() {}
+/// ```
+pub trait LockControl {
+    /// Query the current state, see `CFF3000::state()`.
+    fn state(&self) -> std::io::Result<CFF3000State>;
+
+    /// Lock and return the confirmed state, see
+    /// `CFF3000::lock_and_verify()`.
+    fn lock_and_verify(&self) -> std::io::Result<CFF3000State>;
+
+    /// Unlock and return the confirmed state, see
+    /// `CFF3000::unlock_and_verify()`.
+    fn unlock_and_verify(&self) -> std::io::Result<CFF3000State>;
+
+    /// Run `command` with the method above matching it.
+    fn execute(&self, command: Command) -> std::io::Result<CFF3000State> {
+        match command {
+            Command::Lock => self.lock_and_verify(),
+            Command::Unlock => self.unlock_and_verify(),
+            Command::Check => self.state(),
+        }
+    }
+
+    /// Run `command` like `execute()` for `initiator`, see `audit`.
+    /// Locks without an audit log ignore the initiator.
+    fn execute_as(&self, command: Command, initiator: &str) -> std::io::Result<CFF3000State> {
+        let _ = initiator;
+        self.execute(command)
+    }
+}
+
+impl LockControl for CFF3000 {
+    fn state(&self) -> std::io::Result<CFF3000State> {
+        CFF3000::state(self)
+    }
+
+    fn lock_and_verify(&self) -> std::io::Result<CFF3000State> {
+        CFF3000::lock_and_verify(self)
+    }
+
+    fn unlock_and_verify(&self) -> std::io::Result<CFF3000State> {
+        CFF3000::unlock_and_verify(self)
+    }
+
+    fn execute_as(&self, command: Command, initiator: &str) -> std::io::Result<CFF3000State> {
+        CFF3000::execute_as(self, command, initiator)
+    }
+}
@@ -0,0 +1,491 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Service running a device with its integrations (`daemon` feature).
+//!
+//! A [`CFF3000Daemon`] watches the state of the door like
+//! `CFF3000::watch_with_options()`, passes every change to the
+//! integrations set up in its [`DaemonConfig`] and serves their
+//! commands until it is stopped:
+//!
+//! | Integration | Feature | Setting | |
+//! |-------------|---------|---------|-|
+//! | MQTT | `mqtt` | `DaemonConfig::mqtt` | state, availability and commands, see `mqtt::MqttPublisher` |
+//! | HTTP | `http` | `DaemonConfig::http` | see `http::serve_with_options()` |
+//! | Unix socket | `unix-socket` | `DaemonConfig::socket` | see `socket::serve_with_options()` |
+//! | Webhook | `webhook` | `DaemonConfig::webhook` | see `webhook::WebhookNotifier` |
+//! | systemd | `systemd` | | `READY=1` after the first state, `STATUS=`, the watchdog and `STOPPING=1` in a `Type=notify` unit |
+//! | Schedule | | `DaemonConfig::schedule` | actions at local times, see `schedule::Scheduler` |
+//!
+//! The MQTT commands and the scheduled actions share one
+//! `CommandQueue`. The results of the scheduled actions are passed on
+//! like the changes found by the watch loop, with `Trigger::Schedule`,
+//! and the next query finding the same state is not passed on again.
+//!
+//! With `DaemonConfig::state_file`, the last state, the counters of
+//! the metrics and the battery count are kept across restarts, see the
+//! `persist` module.
+//!
+//! `DaemonConfig::new()` takes the `[mqtt]`, `[webhook]` and
+//! `[[schedule]]` sections and the `state_file` of the configuration
+//! file, the servers are set up by the program:
+//!
+//! ```no_run
+//! use cff3000::config::CFF3000Config;
+//! use cff3000::daemon::{CFF3000Daemon, DaemonConfig};
+//! use cff3000::socket::SocketOptions;
+//!
+//! # fn main() -> std::io::Result<()> {
+//! let text = std::fs::read_to_string("/etc/cff3000.toml")?;
+//! let mut config = DaemonConfig::new(CFF3000Config::from_toml_str(&text)?);
+//! config.socket = Some(("/run/cff3000.sock".into(), SocketOptions::default()));
+//! CFF3000Daemon::from_config(config)?.run()
+//! # }
+//! ```
+//!
+//! # Shutdown
+//!
+//! `run()` returns after SIGINT or SIGTERM (with
+//! `DaemonConfig::signals`), once its `stop_token()` is stopped or on
+//! the first fatal error: an integration which cannot be started or
+//! the watch loop giving up after `WatchOptions::max_consecutive_errors`
+//! failed queries. Either way it tears everything down in this order:
+//!
+//! 1. The watch loop ends, a running state query releases its buttons,
+//!    and the scheduler finishes the action it is running.
+//! 2. The servers stop accepting connections and finish the commands
+//!    they have queued, like the queue of the MQTT commands and the
+//!    scheduled actions. MQTT commands arriving from now on fail.
+//! 3. The GPIO lines are released, after the request threads of the
+//!    servers have let go of the device, for at most 10 seconds. The
+//!    state file is written before.
+//! 4. MQTT publishes `offline` and disconnects, the webhook sends the
+//!    waiting notifications.
+//!
+//! `run()` returns the first error, that of the watch loop or failing
+//! to start, otherwise a failure to publish `offline`.
+//!
+//! # Tracing
+//!
+//! With the `tracing` feature the operations of the daemon's device
+//! are spans (see `spans`), which go to the subscriber set by the
+//! program, e.g. one of `tracing-subscriber` printing how long each
+//! span took, or an OpenTelemetry layer:
+//!
+//! ```ignore
+//! use cff3000::config::CFF3000Config;
+//! use cff3000::daemon::{CFF3000Daemon, DaemonConfig};
+//! use tracing_subscriber::fmt::format::FmtSpan;
+//!
+//! fn main() -> std::io::Result<()> {
+//!     tracing_subscriber::fmt().with_span_events(FmtSpan::CLOSE).init();
+//!     let text = std::fs::read_to_string("/etc/cff3000.toml")?;
+//!     CFF3000Daemon::from_config(DaemonConfig::new(CFF3000Config::from_toml_str(&text)?))?.run()
+//! }
+//! ```
+//!
+//! A state query then ends with lines like
+//!
+//! ```text
+//! INFO state{device="/dev/gpiochip2" press_ms=500}:capture{device="/dev/gpiochip2" events=14}: close time.busy=12.1µs time.idle=8.00s
+//! INFO state{device="/dev/gpiochip2" press_ms=500}:parse{device="/dev/gpiochip2" events=14 state="locked"}: close time.busy=48.3µs time.idle=2.10µs
+//! INFO state{device="/dev/gpiochip2" press_ms=500 events=14 state="locked"}: close time.busy=1.02ms time.idle=11.0s
+//! ```
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::battery::BatteryCounter;
+use crate::config::CFF3000Config;
+#[cfg(feature = "http")]
+use crate::http::{self, HttpOptions, HttpServer};
+#[cfg(feature = "metrics")]
+use crate::metrics::Metrics;
+#[cfg(feature = "mqtt")]
+use crate::mqtt::{MqttOptions, MqttPublisher};
+use crate::persist::{LastState, Snapshot, StateFile};
+use crate::schedule::{ScheduleEntry, ScheduledRun, Scheduler};
+use crate::signals::stop_on_signals;
+#[cfg(all(feature = "unix-socket", unix))]
+use crate::socket::{self, SocketOptions, UnixServer};
+#[cfg(all(feature = "systemd", unix))]
+use crate::systemd::Notifier;
+#[cfg(feature = "webhook")]
+use crate::webhook::{WebhookNotifier, WebhookOptions};
+use crate::{CFF3000, CFF3000Builder, CFF3000State, CommandQueue, QueueOptions, Shutdown, StateChange, StopToken, Trigger, WatchOptions};
+
+/// Time between two state queries of `DaemonConfig::new()`, like
+/// `cff3000 watch`.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Longest wait for the threads of the servers still using the device.
+const RELEASE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a `CFF3000Daemon` runs, see the module documentation.
+#[derive(Debug, Clone)]
+pub struct DaemonConfig {
+    /// Device of `CFF3000Daemon::from_config()`
+    pub device: CFF3000Config,
+    /// State queries of the watch loop
+    pub watch: WatchOptions,
+    /// Queue of the MQTT commands and the scheduled actions
+    pub queue: QueueOptions,
+    /// Stop on SIGINT and SIGTERM instead of terminating the process
+    pub signals: bool,
+    /// Broker to publish the state to and take commands from
+    #[cfg(feature = "mqtt")]
+    pub mqtt: Option<MqttOptions>,
+    /// Address and options of the HTTP server
+    #[cfg(feature = "http")]
+    pub http: Option<(String, HttpOptions)>,
+    /// Path and options of the Unix socket server
+    #[cfg(all(feature = "unix-socket", unix))]
+    pub socket: Option<(PathBuf, SocketOptions)>,
+    /// Notifications of the state changes
+    #[cfg(feature = "webhook")]
+    pub webhook: Option<WebhookOptions>,
+    /// Actions at local times
+    pub schedule: Vec<ScheduleEntry>,
+    /// File keeping the last state and the counters across restarts
+    pub state_file: Option<PathBuf>,
+}
+
+impl DaemonConfig {
+    /// Daemon for `device`, querying every `DEFAULT_POLL_INTERVAL` with
+    /// its rate limit and retry settings, handling signals and with the
+    /// MQTT, webhook and schedule settings of its sections and its state
+    /// file. The servers are off.
+    pub fn new(device: CFF3000Config) -> DaemonConfig {
+        DaemonConfig {
+            watch: device.watch_options(DEFAULT_POLL_INTERVAL),
+            queue: QueueOptions::default(),
+            signals: true,
+            #[cfg(feature = "mqtt")]
+            mqtt: device.mqtt.as_ref().map(|mqtt| mqtt.options()),
+            #[cfg(feature = "http")]
+            http: None,
+            #[cfg(all(feature = "unix-socket", unix))]
+            socket: None,
+            #[cfg(feature = "webhook")]
+            webhook: device.webhook.as_ref().map(|webhook| webhook.options()),
+            schedule: device.schedule.iter().map(|entry| entry.entry()).collect(),
+            state_file: device.state_file.clone(),
+            device,
+        }
+    }
+}
+
+/// Device with its integrations, see the module documentation.
+pub struct CFF3000Daemon {
+    device: Arc<CFF3000>,
+    config: DaemonConfig,
+    stop: StopToken,
+}
+
+impl CFF3000Daemon {
+    /// Open the device of `config.device`, with the audit log of its
+    /// `[audit]` section (`audit` feature).
+    pub fn from_config(config: DaemonConfig) -> std::io::Result<CFF3000Daemon> {
+        let builder = CFF3000Builder::from_config(&config.device);
+        #[cfg(all(feature = "audit", unix))]
+        let builder = match config.device.audit {
+            Some(ref audit) => builder.audit(Arc::new(audit.log()?)),
+            None => builder,
+        };
+        Ok(CFF3000Daemon::new(builder.build()?, config))
+    }
+
+    /// Run `device` instead of the one of `config.device`, e.g. one
+    /// built with a monitor.
+    pub fn new(device: CFF3000, config: DaemonConfig) -> CFF3000Daemon {
+        CFF3000Daemon {device: Arc::new(device), config, stop: StopToken::new()}
+    }
+
+    /// Token ending `run()` when stopped, e.g. from another thread.
+    pub fn stop_token(&self) -> StopToken {
+        self.stop.clone()
+    }
+
+    /// Start the integrations, watch the device until stopped or a
+    /// fatal error and tear everything down, see the module
+    /// documentation.
+    pub fn run(self) -> std::io::Result<()> {
+        let CFF3000Daemon {device, config, stop} = self;
+        if config.signals {
+            stop_on_signals(&stop)?;
+        }
+        let persist = config.state_file.as_ref().map(|path| Persist::load(StateFile::new(path), &device));
+        let mut services = Services {persist, ..Services::default()};
+        let started = services.start(&device, &config);
+        let DaemonConfig {watch, schedule, ..} = config;
+        let services = Arc::new(services);
+        let result = started.and_then(|_| Services::schedule(&services, schedule)).and_then(|scheduler| {
+            let result = services.watch(&device, &watch, &stop);
+            /* lets go of the services once its action is done */
+            drop(scheduler);
+            result
+        });
+        /* also ends the signal thread */
+        stop.stop();
+        let stopped = match Arc::try_unwrap(services) {
+            Ok(services) => services.stop(device),
+            /* the scheduler has been stopped, so this does not happen */
+            Err(_) => Err(std::io::Error::other("the integrations are still in use")),
+        };
+        result.and(stopped)
+    }
+}
+
+/// Running integrations.
+#[derive(Default)]
+struct Services {
+    /// Last state passed on
+    last: Mutex<Option<CFF3000State>>,
+    /// Queue of the MQTT commands and the scheduled actions
+    queue: Option<CommandQueue>,
+    persist: Option<Arc<Persist>>,
+    #[cfg(all(feature = "systemd", unix))]
+    notifier: Option<Notifier>,
+    #[cfg(feature = "webhook")]
+    webhook: Option<WebhookNotifier>,
+    #[cfg(feature = "mqtt")]
+    mqtt: Option<MqttPublisher>,
+    #[cfg(feature = "http")]
+    http: Option<HttpServer>,
+    #[cfg(all(feature = "unix-socket", unix))]
+    socket: Option<UnixServer>,
+}
+
+impl Services {
+    /// Start the integrations of `config`, those started before a
+    /// failure are kept for `stop()`.
+    fn start(&mut self, device: &Arc<CFF3000>, config: &DaemonConfig) -> std::io::Result<()> {
+        #[cfg(all(feature = "systemd", unix))]
+        {
+            self.notifier = Notifier::from_env()?;
+        }
+        #[cfg(feature = "webhook")]
+        {
+            if let Some(ref options) = config.webhook {
+                self.webhook = Some(WebhookNotifier::new(options.clone())?);
+            }
+        }
+        #[cfg(feature = "mqtt")]
+        let commands = config.mqtt.is_some();
+        #[cfg(not(feature = "mqtt"))]
+        let commands = false;
+        if commands || !config.schedule.is_empty() {
+            self.queue = Some(CommandQueue::with_options(device.clone(), config.queue)?);
+        }
+        #[cfg(feature = "mqtt")]
+        {
+            if let (Some(options), Some(queue)) = (config.mqtt.as_ref(), self.queue.as_ref()) {
+                self.mqtt = Some(MqttPublisher::with_commands(options.clone(), queue.sender())?);
+            }
+        }
+        #[cfg(feature = "http")]
+        {
+            if let Some((ref addr, ref options)) = config.http {
+                self.http = Some(http::serve_with_options(device.clone(), addr.as_str(), options.clone())?);
+            }
+        }
+        #[cfg(all(feature = "unix-socket", unix))]
+        {
+            if let Some((ref path, ref options)) = config.socket {
+                self.socket = Some(socket::serve_with_options(device.clone(), path, options.clone())?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Start the scheduler of `entries`, if any, passing its results on
+    /// to `services`.
+    fn schedule(services: &Arc<Services>, entries: Vec<ScheduleEntry>) -> std::io::Result<Option<Scheduler>> {
+        let sender = match services.queue {
+            Some(ref queue) if !entries.is_empty() => queue.sender(),
+            _ => return Ok(None),
+        };
+        let services = services.clone();
+        Scheduler::start(entries, sender, move |run: ScheduledRun| {
+            if let Ok(current) = run.result {
+                services.publish(StateChange {previous: None, current, trigger: Trigger::Schedule, at: SystemTime::now()});
+            }
+        }).map(Some)
+    }
+
+    /// Run the watch loop, with the watchdog of systemd if enabled.
+    fn watch(&self, device: &CFF3000, options: &WatchOptions, stop: &StopToken) -> std::io::Result<()> {
+        #[cfg(all(feature = "systemd", unix))]
+        let options = &match self.notifier.as_ref().and_then(Notifier::watchdog_interval) {
+            Some(interval) => options.heartbeat(interval),
+            None => *options,
+        };
+        device.watch_with_heartbeat(options, stop, |change| self.publish(change), || {
+            #[cfg(all(feature = "systemd", unix))]
+            {
+                if let Some(ref notifier) = self.notifier {
+                    let _ = notifier.watchdog();
+                }
+            }
+        })
+    }
+
+    /// Pass `change` on with the last state passed on as `previous`.
+    fn publish(&self, change: StateChange) {
+        let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        /* the watch loop does not know the results of the scheduled actions */
+        if change.trigger == Trigger::Poll && *last == Some(change.current) {
+            return;
+        }
+        let change = StateChange {previous: *last, ..change};
+        *last = Some(change.current);
+        if let Some(ref persist) = self.persist {
+            persist.store(Some(LastState {state: change.current, timestamp: SystemTime::now()}));
+        }
+        #[cfg(all(feature = "systemd", unix))]
+        {
+            if let Some(ref notifier) = self.notifier {
+                let status = format!("STATUS=door {}", change.current.name());
+                let _ = match change.previous {
+                    None => notifier.notify(&format!("READY=1\n{}", status)),
+                    Some(_) => notifier.notify(&status),
+                };
+            }
+        }
+        #[cfg(feature = "mqtt")]
+        {
+            if let Some(ref mqtt) = self.mqtt {
+                /* a failure is published again with the next change or reconnect */
+                let _ = mqtt.publish_change(&change);
+            }
+        }
+        #[cfg(feature = "http")]
+        {
+            if let Some(ref http) = self.http {
+                http.publish(change);
+            }
+        }
+        #[cfg(feature = "webhook")]
+        {
+            if let Some(ref webhook) = self.webhook {
+                webhook.notify(change);
+            }
+        }
+    }
+
+    /// Tear down in the order of the module documentation.
+    fn stop(self, device: Arc<CFF3000>) -> std::io::Result<()> {
+        #[cfg(all(feature = "systemd", unix))]
+        {
+            if let Some(ref notifier) = self.notifier {
+                let _ = notifier.stopping();
+            }
+        }
+        /* dropping a server finishes its queued commands */
+        #[cfg(all(feature = "unix-socket", unix))]
+        drop(self.socket);
+        #[cfg(feature = "http")]
+        drop(self.http);
+        if let Some(queue) = self.queue {
+            queue.shutdown(Shutdown::Drain);
+        }
+        if let Some(ref persist) = self.persist {
+            /* verified by a command after the last state passed on */
+            let last = device.last_state().and_then(|cached| {
+                SystemTime::now().checked_sub(cached.age).map(|timestamp| LastState {state: cached.state, timestamp})
+            });
+            persist.store(last);
+        }
+        release(device);
+        #[cfg(feature = "mqtt")]
+        let result = self.mqtt.map_or(Ok(()), MqttPublisher::shutdown);
+        #[cfg(not(feature = "mqtt"))]
+        let result = Ok(());
+        #[cfg(feature = "webhook")]
+        drop(self.webhook);
+        result
+    }
+}
+
+/// State file with the snapshot written last.
+struct Persist {
+    file: StateFile,
+    snapshot: Mutex<Snapshot>,
+    battery: Arc<BatteryCounter>,
+    #[cfg(feature = "metrics")]
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl Persist {
+    /// Load `file`, priming the state cache, the metrics and the battery
+    /// count of `device`. Battery changes are written right away.
+    fn load(file: StateFile, device: &CFF3000) -> Arc<Persist> {
+        let snapshot = file.load().unwrap_or_default();
+        if let Some(marker) = snapshot.battery {
+            device.restore_battery(marker);
+        }
+        if let Some(last) = snapshot.last {
+            if let Some(age) = last.age() {
+                device.restore_cached_state(last.state, age);
+            }
+        }
+        #[cfg(feature = "metrics")]
+        {
+            if let Some(metrics) = device.metrics() {
+                metrics.add_counters(&snapshot.counters);
+            }
+        }
+        let persist = Arc::new(Persist {
+            file,
+            snapshot: Mutex::new(snapshot),
+            battery: device.battery.clone(),
+            #[cfg(feature = "metrics")]
+            metrics: device.metrics().cloned(),
+        });
+        /* the counter keeps its hook, a strong reference would be a cycle */
+        let weak = Arc::downgrade(&persist);
+        device.battery.on_reset(Box::new(move |_| {
+            if let Some(persist) = weak.upgrade() {
+                persist.store(None);
+            }
+        }));
+        persist
+    }
+
+    /// Write the snapshot with `last`, unless an older state, the
+    /// current counters and the battery count.
+    fn store(&self, last: Option<LastState>) {
+        let mut snapshot = self.snapshot.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(last) = last {
+            if snapshot.last.is_none_or(|stored| stored.timestamp <= last.timestamp) {
+                snapshot.last = Some(last);
+            }
+        }
+        snapshot.battery = Some(self.battery.marker());
+        #[cfg(feature = "metrics")]
+        {
+            if let Some(ref metrics) = self.metrics {
+                snapshot.counters = metrics.counters();
+            }
+        }
+        /* a failure is written again with the next state */
+        let _ = self.file.store(&snapshot);
+    }
+}
+
+/// Drop `device` once no other thread uses it, so its lines are
+/// released, or after `RELEASE_TIMEOUT`.
+fn release(mut device: Arc<CFF3000>) {
+    let deadline = Instant::now() + RELEASE_TIMEOUT;
+    while Instant::now() < deadline {
+        match Arc::try_unwrap(device) {
+            Ok(device) => {
+                drop(device);
+                return;
+            },
+            Err(shared) => device = shared,
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
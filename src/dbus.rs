@@ -0,0 +1,360 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! D-Bus service (`dbus` feature).
+//!
+//! A [`DbusService`] serves the door as the object [`OBJECT_PATH`]
+//! with the interface [`INTERFACE`], described by [`INTERFACE_XML`]
+//! (`dbus/org.cff3000.Lock1.xml`):
+//!
+//! * `Lock()` and `Unlock()` press the button and wait for the
+//!   confirmation,
+//! * `Check() -> s` queries the door and returns the state name,
+//! * the `State` property holds the last known state name, `unknown`
+//!   before the first one,
+//! * the `StateChanged(s)` signal and `PropertiesChanged` announce
+//!   every change, from the calls and from `publish()`, e.g. fed by
+//!   `publish_changes()`.
+//!
+//! The calls go through a `CommandQueue`, so concurrent callers wait
+//! for each other up to the queue depth. Failures are returned as
+//! distinct D-Bus errors, e.g. for polkit rules or scripts:
+//! [`ERROR_BUSY`] when the queue or the device is busy,
+//! [`ERROR_OUT_OF_RANGE`] when the lock did not answer the remote (also
+//! from `Check()`), [`ERROR_NOT_RESPONDING`] when the remote showed no
+//! LED pattern and [`ERROR_FAILED`] for everything else.
+//!
+//! # System bus
+//!
+//! `DbusService::system()` owns [`BUS_NAME`] on the system bus, which
+//! needs the policy `dbus/org.cff3000.Lock1.conf` installed in
+//! `/usr/share/dbus-1/system.d`. It allows only root and the `cff3000`
+//! group to lock and unlock. With the service running:
+//!
+//! ```sh
+//! busctl introspect org.cff3000.Lock1 /org/cff3000/Lock1
+//! busctl call org.cff3000.Lock1 /org/cff3000/Lock1 org.cff3000.Lock1 Check
+//! busctl get-property org.cff3000.Lock1 /org/cff3000/Lock1 org.cff3000.Lock1 State
+//! busctl call org.cff3000.Lock1 /org/cff3000/Lock1 org.cff3000.Lock1 Lock
+//! busctl monitor org.cff3000.Lock1
+//! ```
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+use zbus::blocking::{Connection, MessageIterator};
+use zbus::blocking::connection::Builder;
+use zbus::fdo;
+use zbus::message::Type as MessageType;
+use zbus::names::BusName;
+use zbus::zvariant::Value;
+use zbus::Message;
+
+use crate::query;
+use crate::{AlreadyInUse, Command, CommandSender, CFF3000, CFF3000State, ParseError, StopToken, WatchOptions};
+
+/// Well-known name on the system bus.
+pub const BUS_NAME: &str = "org.cff3000.Lock1";
+/// Path of the lock object.
+pub const OBJECT_PATH: &str = "/org/cff3000/Lock1";
+/// Interface of the lock object.
+pub const INTERFACE: &str = "org.cff3000.Lock1";
+/// Introspection data of `INTERFACE`.
+pub const INTERFACE_XML: &str = include_str!("../dbus/org.cff3000.Lock1.xml");
+
+/// Another operation is in progress or queued up to the queue depth.
+pub const ERROR_BUSY: &str = "org.cff3000.Lock1.Error.Busy";
+/// The lock did not answer the remote.
+pub const ERROR_OUT_OF_RANGE: &str = "org.cff3000.Lock1.Error.OutOfRange";
+/// The remote did not show an LED pattern, e.g. because of an empty
+/// battery.
+pub const ERROR_NOT_RESPONDING: &str = "org.cff3000.Lock1.Error.NotResponding";
+/// Any other failure, the message tells the details.
+pub const ERROR_FAILED: &str = "org.cff3000.Lock1.Error.Failed";
+
+/// `State` before the first state is known.
+const UNKNOWN: &str = "unknown";
+
+const PROPERTIES: &str = "org.freedesktop.DBus.Properties";
+const INTROSPECTABLE: &str = "org.freedesktop.DBus.Introspectable";
+const PEER: &str = "org.freedesktop.DBus.Peer";
+
+/// Standard interfaces of the lock object.
+const STANDARD_XML: &str = r#"  <interface name="org.freedesktop.DBus.Peer">
+    <method name="Ping"/>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect">
+      <arg name="xml" type="s" direction="out"/>
+    </method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Properties">
+    <method name="Get">
+      <arg name="interface" type="s" direction="in"/>
+      <arg name="property" type="s" direction="in"/>
+      <arg name="value" type="v" direction="out"/>
+    </method>
+    <method name="GetAll">
+      <arg name="interface" type="s" direction="in"/>
+      <arg name="properties" type="a{sv}" direction="out"/>
+    </method>
+    <method name="Set">
+      <arg name="interface" type="s" direction="in"/>
+      <arg name="property" type="s" direction="in"/>
+      <arg name="value" type="v" direction="in"/>
+    </method>
+    <signal name="PropertiesChanged">
+      <arg name="interface" type="s"/>
+      <arg name="changed" type="a{sv}"/>
+      <arg name="invalidated" type="as"/>
+    </signal>
+  </interface>
+"#;
+
+fn io_error(err: zbus::Error) -> Error {
+    match err {
+        zbus::Error::InputOutput(err) => Error::new(err.kind(), err.to_string()),
+        zbus::Error::NameTaken => Error::new(ErrorKind::AddrInUse, format!("{} is already owned by another process", BUS_NAME)),
+        err => Error::other(err),
+    }
+}
+
+/// D-Bus error name of a failed command.
+fn error_name(err: &Error) -> &'static str {
+    let inner = err.get_ref();
+    if let Some(&ParseError::NotEnoughEvents) = query::parse_error(err) {
+        return ERROR_NOT_RESPONDING;
+    }
+    if inner.is_some_and(|inner| inner.is::<AlreadyInUse>()) {
+        return ERROR_BUSY;
+    }
+    match err.kind() {
+        ErrorKind::WouldBlock | ErrorKind::ResourceBusy => ERROR_BUSY,
+        _ => ERROR_FAILED,
+    }
+}
+
+/// Reply to a `command` which ended in `result`: the state name or
+/// the error name and message.
+fn outcome(command: Command, result: std::io::Result<CFF3000State>) -> Result<CFF3000State, (&'static str, String)> {
+    let (method, expected) = match command {
+        Command::Lock => ("Lock", Some(CFF3000State::Locked)),
+        Command::Unlock => ("Unlock", Some(CFF3000State::Unlocked)),
+        Command::Check => ("Check", None),
+    };
+    match result {
+        Ok(CFF3000State::OutOfRange) => Err((ERROR_OUT_OF_RANGE, "the lock is out of range of the remote".to_string())),
+        Ok(state) if expected.is_some_and(|expected| expected != state) => {
+            Err((ERROR_FAILED, format!("the door is {} after {}", state.name(), method)))
+        },
+        Ok(state) => Ok(state),
+        Err(err) => Err((error_name(&err), err.to_string())),
+    }
+}
+
+struct Shared {
+    connection: Connection,
+    sender: CommandSender,
+    state: Mutex<Option<CFF3000State>>,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Option<CFF3000State>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn state_name(&self) -> &'static str {
+        self.lock().map_or(UNKNOWN, CFF3000State::name)
+    }
+
+    /// Remember `state` and announce it if it changed.
+    fn publish(&self, state: CFF3000State) -> std::io::Result<()> {
+        let mut current = self.lock();
+        if *current == Some(state) {
+            return Ok(());
+        }
+        *current = Some(state);
+        /* under the lock, so the signals keep the order of the changes */
+        let changed: HashMap<&str, Value> = Some(("State", Value::from(state.name()))).into_iter().collect();
+        self.connection.emit_signal(None::<BusName>, OBJECT_PATH, INTERFACE, "StateChanged", &state.name()).map_err(io_error)?;
+        self.connection.emit_signal(None::<BusName>, OBJECT_PATH, PROPERTIES, "PropertiesChanged", &(INTERFACE, changed, Vec::<&str>::new()))
+            .map_err(io_error)
+    }
+
+    /// Answer the method `call`.
+    fn dispatch(shared: &Arc<Shared>, call: Message) -> zbus::Result<()> {
+        let header = call.header();
+        let path = header.path().map(|path| path.as_str()).unwrap_or("");
+        let interface = header.interface().map(|interface| interface.as_str());
+        let member = header.member().map(|member| member.as_str()).unwrap_or("");
+        let connection = &shared.connection;
+
+        if path != OBJECT_PATH {
+            return match (interface, member) {
+                (Some(INTROSPECTABLE), "Introspect") if is_parent(path) => connection.reply(&call, &parent_xml(path)),
+                (Some(PEER), "Ping") => connection.reply(&call, &()),
+                _ => connection.reply_dbus_error(&header, fdo::Error::UnknownObject(format!("no object at {}", path))),
+            };
+        }
+
+        let command = match (interface.unwrap_or(INTERFACE), member) {
+            (INTERFACE, "Lock") => Command::Lock,
+            (INTERFACE, "Unlock") => Command::Unlock,
+            (INTERFACE, "Check") => Command::Check,
+            (INTROSPECTABLE, "Introspect") => return connection.reply(&call, &object_xml()),
+            (PEER, "Ping") => return connection.reply(&call, &()),
+            (PROPERTIES, "Get") => {
+                return match call.body().deserialize::<(String, String)>()? {
+                    (ref iface, ref name) if iface == INTERFACE && name == "State" => {
+                        connection.reply(&call, &Value::from(shared.state_name()))
+                    },
+                    (_, name) => connection.reply_dbus_error(&header, fdo::Error::UnknownProperty(format!("no property {}", name))),
+                };
+            },
+            (PROPERTIES, "GetAll") => {
+                let iface: String = call.body().deserialize()?;
+                let mut properties: HashMap<&str, Value> = HashMap::new();
+                if iface == INTERFACE {
+                    properties.insert("State", Value::from(shared.state_name()));
+                }
+                return connection.reply(&call, &properties);
+            },
+            (PROPERTIES, "Set") => {
+                return connection.reply_dbus_error(&header, fdo::Error::PropertyReadOnly("State is read-only".to_string()));
+            },
+            (INTERFACE, _) | (INTROSPECTABLE, _) | (PEER, _) | (PROPERTIES, _) => {
+                return connection.reply_dbus_error(&header, fdo::Error::UnknownMethod(format!("no method {}", member)));
+            },
+            (interface, _) => {
+                return connection.reply_dbus_error(&header, fdo::Error::UnknownInterface(format!("no interface {}", interface)));
+            },
+        };
+
+        /* calls take seconds, answer them from their own thread */
+        let initiator = header.sender().map_or_else(|| "dbus".to_string(), |sender| format!("dbus:{}", sender));
+        let reply = shared.sender.send_as(command, &initiator);
+        let shared = shared.clone();
+        let spawned = std::thread::Builder::new().name("cff3000-dbus-call".to_string()).spawn(move || {
+            let result = reply.recv().unwrap_or_else(|_| Err(Error::new(ErrorKind::BrokenPipe, "command queue has been shut down")));
+            match outcome(command, result) {
+                Ok(state) => {
+                    let _ = shared.publish(state);
+                    let _ = match command {
+                        Command::Check => shared.connection.reply(&call, &state.name()),
+                        _ => shared.connection.reply(&call, &()),
+                    };
+                },
+                Err((name, message)) => {let _ = shared.connection.reply_error(&call, name, &message);},
+            }
+        });
+        match spawned {
+            Ok(_) => Ok(()),
+            Err(err) => Err(zbus::Error::InputOutput(Arc::new(err))),
+        }
+    }
+}
+
+/// Whether `path` is an ancestor of `OBJECT_PATH`.
+fn is_parent(path: &str) -> bool {
+    path == "/" || OBJECT_PATH.strip_prefix(path).is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Introspection data of the ancestor `path`, naming its child.
+fn parent_xml(path: &str) -> String {
+    let rest = OBJECT_PATH[path.len()..].trim_start_matches('/');
+    let child = rest.split('/').next().unwrap_or(rest);
+    format!("{}<node>\n  <node name=\"{}\"/>\n</node>\n", &INTERFACE_XML[..INTERFACE_XML.find("<node>").unwrap_or(0)], child)
+}
+
+/// Introspection data of the lock object.
+fn object_xml() -> String {
+    let end = INTERFACE_XML.rfind("</node>").unwrap_or(INTERFACE_XML.len());
+    format!("{}{}{}", &INTERFACE_XML[..end], STANDARD_XML, &INTERFACE_XML[end..])
+}
+
+/// Answer the method calls of `messages` until the connection is
+/// closed or the service is gone.
+fn serve(shared: &Weak<Shared>, messages: MessageIterator) {
+    for message in messages {
+        let message = match message {
+            Ok(message) => message,
+            Err(_) => continue,
+        };
+        if message.message_type() != MessageType::MethodCall {
+            continue;
+        }
+        match shared.upgrade() {
+            Some(shared) => {let _ = Shared::dispatch(&shared, message);},
+            None => return,
+        }
+    }
+}
+
+/// The lock on D-Bus, see the module documentation.
+pub struct DbusService {
+    shared: Arc<Shared>,
+    /// Owns `BUS_NAME`
+    named: bool,
+}
+
+impl DbusService {
+    /// Own `BUS_NAME` on the system bus and serve the lock there,
+    /// executing the calls through `sender`. Fails with
+    /// `ErrorKind::AddrInUse` if another process owns the name.
+    pub fn system(sender: CommandSender) -> std::io::Result<DbusService> {
+        let connection = Builder::system().and_then(Builder::build).map_err(io_error)?;
+        /* only take the name once the calls are read */
+        let mut service = DbusService::new(connection, sender)?;
+        service.shared.connection.request_name(BUS_NAME).map_err(io_error)?;
+        service.named = true;
+        Ok(service)
+    }
+
+    /// Serve the lock on `connection`, e.g. a session bus or a
+    /// peer-to-peer connection, executing the calls through `sender`.
+    /// The connection must not use the zbus `ObjectServer`, which would
+    /// answer the calls itself, and calls received before are lost.
+    pub fn new(connection: Connection, sender: CommandSender) -> std::io::Result<DbusService> {
+        /* zbus drops the messages arriving while nobody reads them */
+        let messages = MessageIterator::from(&connection);
+        let shared = Arc::new(Shared {connection, sender, state: Mutex::new(None)});
+        let weak = Arc::downgrade(&shared);
+        /* not joined, it ends with the connection */
+        std::thread::Builder::new().name("cff3000-dbus".to_string()).spawn(move || serve(&weak, messages))?;
+        Ok(DbusService {shared, named: false})
+    }
+
+    /// Remember `state` as `State` and announce it if it changed.
+    pub fn publish(&self, state: CFF3000State) -> std::io::Result<()> {
+        self.shared.publish(state)
+    }
+
+    /// The last known state.
+    pub fn state(&self) -> Option<CFF3000State> {
+        *self.shared.lock()
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.shared.connection
+    }
+}
+
+impl Drop for DbusService {
+    fn drop(&mut self) {
+        if self.named {
+            let _ = self.shared.connection.release_name(BUS_NAME);
+        }
+        let _ = self.shared.connection.clone().close();
+    }
+}
+
+/// Watch `cff3000` like `CFF3000::watch_with_options()` and publish
+/// every state change to `service` until `stop` is stopped.
+///
+/// Publishing errors do not end the watch loop.
+pub fn publish_changes(cff3000: &CFF3000, options: &WatchOptions, stop: &StopToken, service: &DbusService) -> std::io::Result<()> {
+    cff3000.watch_with_options(options, stop, |change| {
+        let _ = service.publish(change.current);
+    })
+}
@@ -0,0 +1,110 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Prompt detection of a removed device node, see
+//! `CFF3000Builder::watch_device_node()`.
+//!
+//! The parent directory of the node is watched with inotify, so the
+//! removal is noticed right away instead of at the next failing
+//! operation. Every removal is reported, so a node which comes back
+//! and disappears again is reported again.
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{Error, ErrorKind};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use crate::notice::{Monitor, Notice};
+
+/// Maximum time between two checks of the stop flag.
+const STOP_POLL_MS: i32 = 200;
+
+/// Watcher thread, stopped on drop.
+pub(crate) struct DeviceWatch {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Start watching `path`, calling `monitor` with `Notice::DeviceLost`
+/// whenever it is removed.
+pub(crate) fn watch(path: &Path, monitor: Option<Monitor>) -> std::io::Result<DeviceWatch> {
+    let name = match path.file_name() {
+        Some(name) => name.as_bytes().to_vec(),
+        None => return Err(Error::new(ErrorKind::InvalidInput, "device node path has no file name")),
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let dir = CString::new(dir.as_os_str().as_bytes()).map_err(|_| Error::new(ErrorKind::InvalidInput, "device node path contains NUL"))?;
+
+    let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    let inotify = unsafe { File::from_raw_fd(fd) };
+    if unsafe { libc::inotify_add_watch(fd, dir.as_ptr(), libc::IN_DELETE | libc::IN_MOVED_FROM) } < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread_stop = stop.clone();
+    let chipdev = path.to_string_lossy().into_owned();
+    let thread = (std::thread::Builder::new()
+        .name("cff3000-devwatch".to_string())
+        .spawn(move || run(&inotify, &name, &chipdev, monitor, &thread_stop)))?;
+
+    Ok(DeviceWatch {stop, thread: Some(thread)})
+}
+
+fn run(inotify: &File, name: &[u8], chipdev: &str, monitor: Option<Monitor>, stop: &AtomicBool) {
+    let header = std::mem::size_of::<libc::inotify_event>();
+    let mut buf = [0u8; 4096];
+
+    while !stop.load(Ordering::SeqCst) {
+        let mut fd = libc::pollfd {fd: inotify.as_raw_fd(), events: libc::POLLIN, revents: 0};
+        let ret = unsafe { libc::poll(&mut fd, 1, STOP_POLL_MS) };
+        if ret < 0 && Error::last_os_error().kind() != ErrorKind::Interrupted {
+            return;
+        }
+        if ret <= 0 {
+            continue;
+        }
+
+        let len = unsafe { libc::read(inotify.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if len <= 0 {
+            continue;
+        }
+
+        /* records are a fixed header followed by a NUL padded name */
+        let mut pos = 0;
+        while pos + header <= len as usize {
+            let event: libc::inotify_event = unsafe { std::ptr::read_unaligned(buf[pos..].as_ptr() as *const _) };
+            let end = std::cmp::min(pos + header + event.len as usize, len as usize);
+            let entry = &buf[pos + header..end];
+            let entry = &entry[..entry.iter().position(|&b| b == 0).unwrap_or(entry.len())];
+            pos = end;
+
+            if entry != name {
+                continue;
+            }
+            if let Some(ref monitor) = monitor {
+                monitor(&Notice::DeviceLost {chipdev: chipdev.to_string()});
+            }
+        }
+    }
+}
+
+impl Drop for DeviceWatch {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,170 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Discovery of GPIO chips and lines.
+//!
+//! Helps finding the `chipdev` and line offsets for a new installation
+//! without using the gpiochip crate directly:
+//!
+//! ```no_run
+//! for chip in cff3000::discover::chips() {
+//!     println!("{}: {} ({} lines)", chip.path, chip.label, chip.lines);
+//!     for line in chip.lines().unwrap() {
+//!         println!("  {:3} {:?} {:?}", line.offset, line.name, line.consumer);
+//!     }
+//! }
+//! ```
+//!
+//! On other operating systems than Linux no chips are found.
+
+/// GPIO chip found by `chips()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChipInfo {
+    /// Character device (e.g. "/dev/gpiochip0")
+    pub path: String,
+    /// Kernel name (e.g. "gpiochip0")
+    pub name: String,
+    /// Driver provided label (e.g. "pinctrl-bcm2835")
+    pub label: String,
+    /// Number of lines
+    pub lines: u32,
+}
+
+/// Configuration of a line.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct LineFlags {
+    /// Requested by the kernel or another process
+    pub used: bool,
+    /// Configured as output
+    pub output: bool,
+    pub active_low: bool,
+    pub open_drain: bool,
+    pub open_source: bool,
+}
+
+/// Line of a GPIO chip.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    /// Line offset, as used for `CFF3000::new()`
+    pub offset: u32,
+    /// Name from the device tree or ACPI tables, if any
+    pub name: Option<String>,
+    /// Consumer label of the current user, if any
+    pub consumer: Option<String>,
+    pub flags: LineFlags,
+}
+
+/// List all GPIO chips (`/dev/gpiochip*`) which can be opened, sorted
+/// by path.
+pub fn chips() -> Vec<ChipInfo> {
+    let mut paths: Vec<String> = match std::fs::read_dir("/dev") {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with("gpiochip"))
+            .map(|entry| entry.path().to_string_lossy().into_owned())
+            .collect(),
+        Err(_) => return Vec::new(),
+    };
+    paths.sort();
+    paths.iter().filter_map(|path| chip(path).ok()).collect()
+}
+
+/// Information about the GPIO chip at `path`.
+///
+/// Fails with `ErrorKind::Unsupported` on other operating systems than
+/// Linux.
+pub fn chip(path: &str) -> std::io::Result<ChipInfo> {
+    sys::chip(path)
+}
+
+impl ChipInfo {
+    /// Read the current configuration of all lines of this chip.
+    pub fn lines(&self) -> std::io::Result<Vec<Line>> {
+        let file = std::fs::File::open(&self.path)?;
+        (0..self.lines).map(|offset| sys::line_info(&file, offset)).collect()
+    }
+
+    /// Read the current configuration of line `offset`.
+    pub fn line(&self, offset: u32) -> std::io::Result<Line> {
+        let file = std::fs::File::open(&self.path)?;
+        sys::line_info(&file, offset)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::fs::File;
+    use std::io::Error;
+    use std::os::unix::io::AsRawFd;
+
+    use super::{ChipInfo, Line, LineFlags};
+
+    /// `struct gpioline_info` of the v1 uAPI.
+    #[repr(C)]
+    struct RawLineInfo {
+        line_offset: u32,
+        flags: u32,
+        name: [u8; 32],
+        consumer: [u8; 32],
+    }
+
+    const GPIOLINE_FLAG_KERNEL: u32 = 1 << 0;
+    const GPIOLINE_FLAG_IS_OUT: u32 = 1 << 1;
+    const GPIOLINE_FLAG_ACTIVE_LOW: u32 = 1 << 2;
+    const GPIOLINE_FLAG_OPEN_DRAIN: u32 = 1 << 3;
+    const GPIOLINE_FLAG_OPEN_SOURCE: u32 = 1 << 4;
+
+    /// `_IOWR(0xB4, 0x02, struct gpioline_info)`
+    const GPIO_GET_LINEINFO_IOCTL: u32 = (3 << 30) | ((std::mem::size_of::<RawLineInfo>() as u32) << 16) | (0xB4 << 8) | 0x02;
+
+    pub fn chip(path: &str) -> std::io::Result<ChipInfo> {
+        let chip = gpio::GpioChip::new(path)?;
+        Ok(ChipInfo {path: path.to_string(), name: chip.name, label: chip.label, lines: chip.lines})
+    }
+
+    fn c_string(buf: &[u8]) -> Option<String> {
+        let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+        if len == 0 {
+            None
+        } else {
+            Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+        }
+    }
+
+    pub fn line_info(chip: &File, offset: u32) -> std::io::Result<Line> {
+        let mut info = RawLineInfo {line_offset: offset, flags: 0, name: [0; 32], consumer: [0; 32]};
+        let ret = unsafe { libc::ioctl(chip.as_raw_fd(), GPIO_GET_LINEINFO_IOCTL as _, &mut info) };
+        if ret < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(Line {
+            offset,
+            name: c_string(&info.name),
+            consumer: c_string(&info.consumer),
+            flags: LineFlags {
+                used: info.flags & GPIOLINE_FLAG_KERNEL != 0,
+                output: info.flags & GPIOLINE_FLAG_IS_OUT != 0,
+                active_low: info.flags & GPIOLINE_FLAG_ACTIVE_LOW != 0,
+                open_drain: info.flags & GPIOLINE_FLAG_OPEN_DRAIN != 0,
+                open_source: info.flags & GPIOLINE_FLAG_OPEN_SOURCE != 0,
+            },
+        })
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::fs::File;
+    use std::io::{Error, ErrorKind};
+
+    use super::{ChipInfo, Line};
+
+    pub fn chip(_path: &str) -> std::io::Result<ChipInfo> {
+        Err(Error::new(ErrorKind::Unsupported, "GPIO character devices are only supported on Linux"))
+    }
+
+    pub fn line_info(_chip: &File, _offset: u32) -> std::io::Result<Line> {
+        Err(Error::new(ErrorKind::Unsupported, "GPIO character devices are only supported on Linux"))
+    }
+}
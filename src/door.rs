@@ -0,0 +1,276 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Door sensor, e.g. a reed switch in the door frame, see
+//! `CFF3000Builder::door_sensor()`.
+//!
+//! The CFF3000 only knows where its bolt is, not whether the door is
+//! shut: locking an open door throws the bolt into the air and shows
+//! `Locked` all the same. A fifth input line tells the two apart.
+//! `CFF3000::door_closed()` reads it, `CFF3000::watch_composite()`
+//! reports the changes of the lock and of the door as a
+//! [`CompositeChange`], and `DoorSensorOptions::open_door` lets lock
+//! presses fail with [`DoorOpen`] or warn while the door is open. This
+//! covers `lock()`, `lock_and_verify()` and everything built on them,
+//! such as the auto-lock of the watch loop and the `ensure_locked`
+//! actions of `schedule`.
+//!
+//! The line is read when needed, there are no edge events. A read is
+//! repeated after `DoorSensorOptions::debounce` until two reads in a
+//! row agree, so a bouncing contact is not taken for a change.
+
+use std::cell::RefCell;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::notice::Monitor;
+use crate::{CFF3000, CFF3000State, Notice, Polarity, SharedClock, StateChange, StopToken, Trigger, WatchOptions};
+
+/// Default of `DoorSensorOptions::debounce`.
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(50);
+
+/// Reads of a bouncing line before `CFF3000::door_closed()` gives up.
+const MAX_READS: u32 = 10;
+
+/// Input line of a door sensor.
+pub trait DoorInput: Send + Sync {
+    /// Current electrical level of the line (true = high).
+    fn level(&self) -> std::io::Result<bool>;
+}
+
+/// Door sensor on a line of the Linux GPIO character device.
+#[cfg(target_os = "linux")]
+pub struct GpiochipDoorInput {
+    line: gpio::GpioHandle,
+}
+
+#[cfg(target_os = "linux")]
+impl GpiochipDoorInput {
+    /// Request the line `offset` of `chipdev` as an input.
+    pub fn new(chipdev: &str, offset: u32) -> std::io::Result<GpiochipDoorInput> {
+        let chip = gpio::GpioChip::new(chipdev)?;
+        Ok(GpiochipDoorInput {line: chip.request("door-sensor", gpio::RequestFlags::INPUT, offset, 0)?})
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl DoorInput for GpiochipDoorInput {
+    fn level(&self) -> std::io::Result<bool> {
+        Ok(self.line.get()? != 0)
+    }
+}
+
+/// Request the line `offset` of `chipdev` for `CFF3000Builder::door_sensor()`.
+#[cfg(target_os = "linux")]
+pub(crate) fn open_line(chipdev: &str, offset: u32) -> std::io::Result<Arc<dyn DoorInput>> {
+    Ok(Arc::new(GpiochipDoorInput::new(chipdev, offset)?))
+}
+
+/// Request the line `offset` of `chipdev` for `CFF3000Builder::door_sensor()`.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn open_line(_chipdev: &str, _offset: u32) -> std::io::Result<Arc<dyn DoorInput>> {
+    Err(Error::new(ErrorKind::Unsupported, "GPIO character devices are only supported on Linux"))
+}
+
+/// What a lock press does while the door is open.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub enum OpenDoorPolicy {
+    /// Lock anyway
+    #[default]
+    Allow,
+    /// Lock anyway and report `Notice::DoorOpen` to the
+    /// `CFF3000Builder::monitor()` callback
+    Warn,
+    /// Fail with `ErrorKind::PermissionDenied` and `DoorOpen`, without
+    /// pressing the button. A sensor which cannot be read fails the
+    /// press as well.
+    Refuse,
+}
+
+/// Configuration of a door sensor.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct DoorSensorOptions {
+    /// Level of the line while the door is closed, `ActiveLow` for a
+    /// switch pulling the line down (default: `ActiveHigh`)
+    pub polarity: Polarity,
+    /// Time between two reads which have to agree, zero to take the
+    /// first read (default: `DEFAULT_DEBOUNCE`)
+    pub debounce: Duration,
+    /// Lock presses while the door is open (default:
+    /// `OpenDoorPolicy::Allow`)
+    pub open_door: OpenDoorPolicy,
+}
+
+impl Default for DoorSensorOptions {
+    fn default() -> DoorSensorOptions {
+        DoorSensorOptions {polarity: Polarity::ActiveHigh, debounce: DEFAULT_DEBOUNCE, open_door: OpenDoorPolicy::Allow}
+    }
+}
+
+/// Error of a lock press refused by `OpenDoorPolicy::Refuse`, wrapped
+/// in an `std::io::Error` of kind `PermissionDenied`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DoorOpen;
+
+impl std::fmt::Display for DoorOpen {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("not locking, the door is open")
+    }
+}
+
+impl std::error::Error for DoorOpen {}
+
+/// State of the lock and of the door.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CompositeStatus {
+    pub lock: CFF3000State,
+    pub door_open: bool,
+}
+
+/// Reason a `CompositeChange` has been reported.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CompositeTrigger {
+    /// A `StateChange` of the lock with its trigger
+    Lock(Trigger),
+    /// The door has been opened or closed, the lock is as before
+    Door,
+}
+
+/// A change of the lock or of the door, see
+/// `CFF3000::watch_composite()`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CompositeChange {
+    /// Previously reported status, `None` for the first one
+    pub previous: Option<CompositeStatus>,
+    pub current: CompositeStatus,
+    pub trigger: CompositeTrigger,
+}
+
+/// Door sensor of a device.
+pub(crate) struct DoorSensor {
+    input: Arc<dyn DoorInput>,
+    options: DoorSensorOptions,
+    clock: SharedClock,
+    monitor: Option<Monitor>,
+}
+
+impl DoorSensor {
+    pub(crate) fn new(input: Arc<dyn DoorInput>, options: DoorSensorOptions, clock: SharedClock, monitor: Option<Monitor>) -> DoorSensor {
+        DoorSensor {input, options, clock, monitor}
+    }
+
+    /// Returns true if the debounced line shows a closed door.
+    pub(crate) fn closed(&self) -> std::io::Result<bool> {
+        let closed = |level: bool| level == (self.options.polarity == Polarity::ActiveHigh);
+        let mut level = self.input.level()?;
+        if self.options.debounce == Duration::from_millis(0) {
+            return Ok(closed(level));
+        }
+        for _ in 1..MAX_READS {
+            self.clock.sleep(self.options.debounce);
+            let next = self.input.level()?;
+            if next == level {
+                return Ok(closed(level));
+            }
+            level = next;
+        }
+        Err(Error::new(ErrorKind::InvalidData, "the door sensor does not settle"))
+    }
+
+    /// Apply `DoorSensorOptions::open_door` before a lock press.
+    pub(crate) fn check_lock(&self) -> std::io::Result<()> {
+        match self.options.open_door {
+            OpenDoorPolicy::Allow => Ok(()),
+            OpenDoorPolicy::Warn => {
+                if let (Ok(false), Some(monitor)) = (self.closed(), self.monitor.as_ref()) {
+                    monitor(&Notice::DoorOpen);
+                }
+                Ok(())
+            },
+            OpenDoorPolicy::Refuse => match self.closed()? {
+                true => Ok(()),
+                false => Err(Error::new(ErrorKind::PermissionDenied, DoorOpen)),
+            },
+        }
+    }
+}
+
+fn no_sensor() -> Error {
+    Error::new(ErrorKind::Unsupported, "the device has no door sensor")
+}
+
+/// Last reported status of `watch_composite()` and its callback.
+struct Composite<F> {
+    f: F,
+    last: Option<CompositeStatus>,
+    door_open: bool,
+}
+
+impl<F: FnMut(CompositeChange)> Composite<F> {
+    fn report(&mut self, current: CompositeStatus, trigger: CompositeTrigger) {
+        let previous = self.last.replace(current);
+        (self.f)(CompositeChange {previous, current, trigger});
+    }
+
+    fn lock_changed(&mut self, change: StateChange, door: &DoorSensor) {
+        if let Ok(closed) = door.closed() {
+            self.door_open = !closed;
+        }
+        let current = CompositeStatus {lock: change.current, door_open: self.door_open};
+        self.report(current, CompositeTrigger::Lock(change.trigger));
+    }
+
+    fn sample(&mut self, door: &DoorSensor) {
+        /* a failed read keeps the last level */
+        let door_open = match door.closed() {
+            Ok(closed) => !closed,
+            Err(_) => return,
+        };
+        if door_open == self.door_open {
+            return;
+        }
+        self.door_open = door_open;
+        /* reported once the lock is known */
+        if let Some(last) = self.last {
+            self.report(CompositeStatus {door_open, ..last}, CompositeTrigger::Door);
+        }
+    }
+}
+
+impl CFF3000 {
+    fn door(&self) -> std::io::Result<&DoorSensor> {
+        self.door.as_ref().ok_or_else(no_sensor)
+    }
+
+    /// Returns true if the door sensor shows a closed door. Fails with
+    /// `ErrorKind::Unsupported` without a sensor.
+    pub fn door_closed(&self) -> std::io::Result<bool> {
+        self.door().and_then(DoorSensor::closed)
+    }
+
+    /// Query the state and read the door sensor.
+    pub fn composite_status(&self) -> std::io::Result<CompositeStatus> {
+        let door = self.door()?;
+        let lock = self.state()?;
+        Ok(CompositeStatus {lock, door_open: !door.closed()?})
+    }
+
+    /// Like `watch_with_options()`, also reading the door sensor every
+    /// `door_interval` between the queries and reporting the changes of
+    /// either as a `CompositeChange` to `f`.
+    ///
+    /// The lock triggers a change as with `watch_with_options()`, the
+    /// door once the first state is known. Failed reads of the sensor
+    /// keep the last level. Fails with `ErrorKind::Unsupported` without
+    /// a sensor, or with the error of the first read.
+    pub fn watch_composite<F>(&self, options: &WatchOptions, door_interval: Duration, stop: &StopToken, f: F) -> std::io::Result<()>
+        where F: FnMut(CompositeChange)
+    {
+        let door = self.door()?;
+        let composite = RefCell::new(Composite {f, last: None, door_open: !door.closed()?});
+        let mut options = *options;
+        options.heartbeat = Some(options.heartbeat.map_or(door_interval, |every| std::cmp::min(every, door_interval)));
+        self.watch_with_heartbeat(&options, stop, |change| composite.borrow_mut().lock_changed(change, door), || composite.borrow_mut().sample(door))
+    }
+}
@@ -0,0 +1,152 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Bounded trace of the LED events of a capture.
+//!
+//! With `trace` records of the `log` facade enabled for this module,
+//! every capture of a state query logs its LED events: the LED, the
+//! edge, the raw timestamp of the backend and the time since the first
+//! event of the capture, e.g. "event 1: LED red on at 700000000 ns
+//! (+0ns)". Blinking patterns like `OutOfRange` can produce hundreds of
+//! events per capture, so only the first and the last
+//! `CFF3000Builder::event_dump()` events are logged verbatim and the
+//! ones in between as a single line, e.g. "events 9 to 188 suppressed
+//! (90 red, 90 green over 8.99s)".
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::Duration;
+
+use crate::{Led, LedEvent};
+
+/// Default number of events logged verbatim at each end of a capture.
+pub const DEFAULT_EVENT_DUMP_LIMIT: usize = 8;
+
+/// Events of a capture skipped by `EventDump`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Suppressed {
+    /// Index of the first skipped event, counted from 1
+    pub first: usize,
+    pub red: usize,
+    pub green: usize,
+    /// Time between the first and the last skipped event
+    pub span: Duration,
+}
+
+impl Suppressed {
+    /// Number of skipped events.
+    pub fn count(&self) -> usize {
+        self.red + self.green
+    }
+}
+
+impl fmt::Display for Suppressed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (match self.count() {
+            1 => write!(f, "event {} suppressed", self.first),
+            count => write!(f, "events {} to {} suppressed", self.first, self.first + count - 1),
+        })?;
+        write!(f, " ({} red, {} green over {:?})", self.red, self.green, self.span)
+    }
+}
+
+/// One line of the dump, "event 1: LED red on at 700000000 ns (+0ns)".
+/// `index` counts from 1 and `first` is the timestamp of the first
+/// event of the capture.
+pub fn format_event(index: usize, event: &LedEvent, first: Duration) -> String {
+    let led = match event.led {
+        Led::Red => "red",
+        Led::Green => "green",
+    };
+    let edge = if event.on { "on" } else { "off" };
+    format!("event {}: LED {} {} at {} ns (+{:?})", index, led, edge, event.timestamp.as_nanos(), event.timestamp.saturating_sub(first))
+}
+
+/// Dump of one capture.
+///
+/// `push()` formats the first `limit` events right away, the following
+/// ones are kept until `finish()` knows which are the last `limit`.
+#[derive(Debug)]
+pub struct EventDump {
+    limit: usize,
+    /// Events pushed so far
+    count: usize,
+    /// Timestamp of the first event
+    first: Option<Duration>,
+    tail: VecDeque<LedEvent>,
+    suppressed: Option<(Suppressed, Duration)>,
+}
+
+impl EventDump {
+    pub fn new(limit: usize) -> EventDump {
+        EventDump {limit, count: 0, first: None, tail: VecDeque::with_capacity(limit), suppressed: None}
+    }
+
+    /// Start the dump of a capture, `None` unless `trace` records of
+    /// this module are enabled.
+    pub(crate) fn start(limit: usize) -> Option<EventDump> {
+        match log::log_enabled!(log::Level::Trace) {
+            true => Some(EventDump::new(limit)),
+            false => None,
+        }
+    }
+
+    /// Like `push()`, but log the line.
+    pub(crate) fn log(&mut self, event: LedEvent) {
+        if let Some(line) = self.push(event) {
+            log::trace!("{}", line);
+        }
+    }
+
+    /// Like `finish()`, but log the lines.
+    pub(crate) fn log_finish(self) {
+        for line in self.finish() {
+            log::trace!("{}", line);
+        }
+    }
+
+    /// Add the next event, returning its line if it is one of the
+    /// first `limit`.
+    pub fn push(&mut self, event: LedEvent) -> Option<String> {
+        self.count += 1;
+        let first = *self.first.get_or_insert(event.timestamp);
+        if self.count <= self.limit {
+            return Some(format_event(self.count, &event, first));
+        }
+        self.tail.push_back(event);
+        if self.tail.len() > self.limit {
+            if let Some(skipped) = self.tail.pop_front() {
+                self.suppress(skipped);
+            }
+        }
+        None
+    }
+
+    /// Count `event`, which dropped out of the tail.
+    fn suppress(&mut self, event: LedEvent) {
+        let index = self.count - self.tail.len();
+        let empty = Suppressed {first: index, red: 0, green: 0, span: Duration::from_millis(0)};
+        let &mut (ref mut suppressed, start) = self.suppressed.get_or_insert((empty, event.timestamp));
+        match event.led {
+            Led::Red => suppressed.red += 1,
+            Led::Green => suppressed.green += 1,
+        }
+        suppressed.span = event.timestamp.saturating_sub(start);
+    }
+
+    /// Events skipped so far.
+    pub fn suppressed(&self) -> Option<Suppressed> {
+        self.suppressed.map(|(suppressed, _)| suppressed)
+    }
+
+    /// The summary of the skipped events, if any, and the lines of the
+    /// last `limit` events.
+    pub fn finish(self) -> Vec<String> {
+        let first = self.first.unwrap_or_default();
+        let start = self.count - self.tail.len() + 1;
+        let summary = self.suppressed().map(|suppressed| suppressed.to_string());
+        summary.into_iter()
+            .chain(self.tail.iter().enumerate().map(|(i, event)| format_event(start + i, event, first)))
+            .collect()
+    }
+}
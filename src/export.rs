@@ -0,0 +1,122 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! LED event logs in the formats of other tools.
+//!
+//! [`to_vcd()`] writes a capture as a Value Change Dump (IEEE 1364) for
+//! waveform viewers like GTKWave or PulseView, with one 1 bit wire per
+//! LED in a `cff3000` scope and the timescale 1 ns of the timestamps:
+//!
+//! ```text
+//! $version cff3000 LED events $end
+//! $timescale 1ns $end
+//! $scope module cff3000 $end
+//! $var wire 1 r red $end
+//! $var wire 1 g green $end
+//! $upscope $end
+//! $enddefinitions $end
+//! #0
+//! $dumpvars
+//! 1r
+//! 0g
+//! $end
+//! #200000000
+//! 1g
+//! ```
+//!
+//! The first event is at time 0. A wire starts with the opposite of its
+//! first event, or 0 without events. An empty log has the definitions
+//! only. `cff3000 record --format vcd` and `cff3000 replay --format vcd`
+//! write this format.
+//!
+//! [`to_csv()`] writes a plain CSV file for spreadsheets or pandas, one
+//! row per event with the microseconds since the first event, the LED
+//! and its new state:
+//!
+//! ```text
+//! timestamp_us,led,state
+//! 0,green,on
+//! 1890,red,on
+//! 309042,red,off
+//! ```
+//!
+//! `import::from_csv()` reads it back and `cff3000 replay` accepts it
+//! like a fixture file, `cff3000 record --format csv` writes it.
+
+use std::io::Write;
+use std::time::Duration;
+
+use crate::{Led, LedEvent};
+
+/// Header row of `to_csv()`.
+pub const CSV_HEADER: &str = "timestamp_us,led,state";
+
+fn led_name(led: Led) -> &'static str {
+    match led {
+        Led::Red => "red",
+        Led::Green => "green",
+    }
+}
+
+fn vcd_id(led: Led) -> char {
+    match led {
+        Led::Red => 'r',
+        Led::Green => 'g',
+    }
+}
+
+/// Write `events` as a Value Change Dump to `w`, see the module
+/// documentation. The events may be in any order.
+pub fn to_vcd<W: Write>(events: &[LedEvent], mut w: W) -> std::io::Result<()> {
+    w.write_all(b"$version cff3000 LED events $end\n")?;
+    w.write_all(b"$timescale 1ns $end\n")?;
+    w.write_all(b"$scope module cff3000 $end\n")?;
+    w.write_all(b"$var wire 1 r red $end\n")?;
+    w.write_all(b"$var wire 1 g green $end\n")?;
+    w.write_all(b"$upscope $end\n")?;
+    w.write_all(b"$enddefinitions $end\n")?;
+    let mut events = events.to_vec();
+    /* stable, events of one timestamp keep the order they were read in */
+    events.sort_by_key(|event| event.timestamp);
+    let start = match events.first() {
+        Some(first) => first.timestamp,
+        None => return w.flush(),
+    };
+    /* the level at time 0 after the events at time 0 */
+    let initial = |led: Led| {
+        let mut changes = events.iter().filter(|event| event.led == led);
+        match changes.clone().take_while(|event| event.timestamp == start).last() {
+            Some(event) => event.on,
+            None => changes.next().is_some_and(|event| !event.on),
+        }
+    };
+
+    w.write_all(b"#0\n$dumpvars\n")?;
+    for &led in &[Led::Red, Led::Green] {
+        writeln!(w, "{}{}", initial(led) as u8, vcd_id(led))?;
+    }
+    w.write_all(b"$end\n")?;
+    let mut time = start;
+    for event in events.iter().filter(|event| event.timestamp != start) {
+        if event.timestamp != time {
+            time = event.timestamp;
+            writeln!(w, "#{}", (time - start).as_nanos())?;
+        }
+        writeln!(w, "{}{}", event.on as u8, vcd_id(event.led))?;
+    }
+    w.flush()
+}
+
+/// Write `events` as CSV to `w`, see the module documentation. The rows
+/// are sorted by time, timestamps are truncated to microseconds.
+pub fn to_csv<W: Write>(events: &[LedEvent], mut w: W) -> std::io::Result<()> {
+    writeln!(w, "{}", CSV_HEADER)?;
+    let mut events = events.to_vec();
+    events.sort_by_key(|event| event.timestamp);
+    let start = events.first().map_or(Duration::ZERO, |event| event.timestamp);
+    for event in &events {
+        let state = if event.on { "on" } else { "off" };
+        writeln!(w, "{},{},{}", (event.timestamp - start).as_micros(), led_name(event.led), state)?;
+    }
+    w.flush()
+}
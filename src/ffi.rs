@@ -0,0 +1,205 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! C interface (`ffi` feature).
+//!
+//! The functions below make up `libcff3000`, declared by
+//! `include/cff3000.h` (generated by cbindgen from this file, see
+//! `cbindgen.toml`). Build the shared library with
+//!
+//! ```sh
+//! cargo rustc --release --features ffi --crate-type cdylib
+//! cbindgen --config cbindgen.toml --output include/cff3000.h
+//! cc -Iinclude examples/ffi.c -Ltarget/release -lcff3000 -o ffi
+//! ```
+//!
+//! Every function but `cff3000_new()` and `cff3000_free()` returns
+//! `CFF3000_OK` or one of the `CFF3000_ERROR_*` codes, which are the
+//! exit status numbers of the `cff3000` tool (see `cli`). States are
+//! given as the `CFF3000_STATE_*` numbers, again those of the tool.
+//! After a failure, `cff3000_last_error_message()` describes it.
+//!
+//! A handle may be used by several threads, the calls wait for each
+//! other like those of `CFF3000`. The message of the last failure
+//! belongs to the handle though, so read it from the failing thread
+//! before another call on the handle.
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Mutex;
+
+use crate::codes::{state_exit_code, ErrorCode};
+use crate::{CFF3000, CFF3000Builder, Command};
+
+/// Success.
+pub const CFF3000_OK: c_int = 0;
+/// The door is locked.
+pub const CFF3000_STATE_LOCKED: c_int = 0;
+/// The door is unlocked.
+pub const CFF3000_STATE_UNLOCKED: c_int = 1;
+/// The door has been locked or unlocked manually.
+pub const CFF3000_STATE_MANUAL: c_int = 2;
+/// The lock did not answer the remote.
+pub const CFF3000_STATE_OUT_OF_RANGE: c_int = 3;
+/// The LEDs did not show a pattern.
+pub const CFF3000_ERROR_NO_RESPONSE: c_int = 4;
+/// Invalid arguments or configuration.
+pub const CFF3000_ERROR_CONFIG: c_int = 11;
+/// The GPIO chip does not exist.
+pub const CFF3000_ERROR_NOT_FOUND: c_int = 12;
+/// No access to the GPIO chip.
+pub const CFF3000_ERROR_PERMISSION_DENIED: c_int = 13;
+/// The lines, the lock file or the device are in use.
+pub const CFF3000_ERROR_BUSY: c_int = 14;
+/// The LED pattern was not understood.
+pub const CFF3000_ERROR_INVALID_PATTERN: c_int = 15;
+/// Not available on this system.
+pub const CFF3000_ERROR_UNSUPPORTED: c_int = 17;
+/// Any other error.
+pub const CFF3000_ERROR_IO: c_int = 18;
+
+/// Device opened by `cff3000_new()`.
+pub struct Handle {
+    device: CFF3000,
+    error: Mutex<CString>,
+}
+
+thread_local! {
+    /// Message of the last failed `cff3000_new()` of the thread.
+    static NEW_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn message(text: &str) -> CString {
+    CString::new(text.replace('\0', " ")).unwrap_or_default()
+}
+
+/// Wrap `device` in a handle for C code, e.g. one built with
+/// `CFF3000Builder` options the C interface does not offer. Release it
+/// with `cff3000_free()`.
+pub fn into_handle(device: CFF3000) -> *mut Handle {
+    Box::into_raw(Box::new(Handle {device, error: Mutex::new(CString::default())}))
+}
+
+/// Run `f` on the device of `handle`, recording a failure.
+fn call<F>(handle: *mut Handle, f: F) -> c_int
+    where F: FnOnce(&CFF3000) -> std::io::Result<c_int>
+{
+    let handle = match unsafe { handle.as_ref() } {
+        Some(handle) => handle,
+        None => return CFF3000_ERROR_CONFIG,
+    };
+    let (code, text) = match catch_unwind(AssertUnwindSafe(|| f(&handle.device))) {
+        Ok(Ok(code)) => return code,
+        Ok(Err(err)) => (ErrorCode::of(&err).exit_code(), err.to_string()),
+        Err(_) => (CFF3000_ERROR_IO, "internal error (panic)".to_string()),
+    };
+    *handle.error.lock().unwrap_or_else(|e| e.into_inner()) = message(&text);
+    code
+}
+
+/// Open the CFF3000 on the GPIO chip `chip` (e.g. "/dev/gpiochip0")
+/// with the line offsets `pins` of LED red, LED green, button unlock
+/// and button lock. Returns NULL on failure, see
+/// `cff3000_last_error_message(NULL)`.
+///
+/// # Safety
+///
+/// `chip` must be a NUL-terminated string and `pins` point to 4 line
+/// offsets.
+#[no_mangle]
+pub unsafe extern "C" fn cff3000_new(chip: *const c_char, pins: *const u32) -> *mut Handle {
+    let opened = catch_unwind(|| {
+        if chip.is_null() || pins.is_null() {
+            return Err("chip and pins must not be NULL".to_string());
+        }
+        let chip = CStr::from_ptr(chip).to_str().map_err(|_| "chip is not UTF-8".to_string())?;
+        let pins = std::slice::from_raw_parts(pins, 4);
+        CFF3000Builder::new(chip, [pins[0], pins[1], pins[2], pins[3]]).build().map_err(|err| err.to_string())
+    });
+    let text = match opened {
+        Ok(Ok(device)) => return into_handle(device),
+        Ok(Err(text)) => text,
+        Err(_) => "internal error (panic)".to_string(),
+    };
+    NEW_ERROR.with(|error| *error.borrow_mut() = message(&text));
+    std::ptr::null_mut()
+}
+
+/// Press the lock button.
+///
+/// # Safety
+///
+/// `handle` must be NULL or returned by `cff3000_new()` and not freed.
+#[no_mangle]
+pub unsafe extern "C" fn cff3000_lock(handle: *mut Handle) -> c_int {
+    call(handle, |device| device.press_as(Command::Lock, "ffi").map(|_| CFF3000_OK))
+}
+
+/// Press the unlock button.
+///
+/// # Safety
+///
+/// `handle` must be NULL or returned by `cff3000_new()` and not freed.
+#[no_mangle]
+pub unsafe extern "C" fn cff3000_unlock(handle: *mut Handle) -> c_int {
+    call(handle, |device| device.press_as(Command::Unlock, "ffi").map(|_| CFF3000_OK))
+}
+
+/// Press both buttons, the CFF3000 shows its state on the LEDs.
+///
+/// # Safety
+///
+/// `handle` must be NULL or returned by `cff3000_new()` and not freed.
+#[no_mangle]
+pub unsafe extern "C" fn cff3000_check(handle: *mut Handle) -> c_int {
+    call(handle, |device| device.press_as(Command::Check, "ffi").map(|_| CFF3000_OK))
+}
+
+/// Query the state and store it in `out_state`, which is left alone
+/// on failure. Takes about 10 seconds.
+///
+/// # Safety
+///
+/// `handle` must be NULL or returned by `cff3000_new()` and not freed,
+/// `out_state` must be NULL or point to an `int`.
+#[no_mangle]
+pub unsafe extern "C" fn cff3000_state(handle: *mut Handle, out_state: *mut c_int) -> c_int {
+    if out_state.is_null() {
+        return CFF3000_ERROR_CONFIG;
+    }
+    call(handle, |device| {
+        let state = device.execute_as(Command::Check, "ffi")?;
+        *out_state = state_exit_code(state);
+        Ok(CFF3000_OK)
+    })
+}
+
+/// Message of the last failure on `handle`, or of the last failed
+/// `cff3000_new()` of this thread for NULL. The string is empty if
+/// nothing failed and valid until the next call with `handle`.
+///
+/// # Safety
+///
+/// `handle` must be NULL or returned by `cff3000_new()` and not freed.
+#[no_mangle]
+pub unsafe extern "C" fn cff3000_last_error_message(handle: *const Handle) -> *const c_char {
+    match handle.as_ref() {
+        Some(handle) => handle.error.lock().unwrap_or_else(|e| e.into_inner()).as_ptr(),
+        /* the thread's string stays allocated until it is replaced */
+        None => NEW_ERROR.with(|error| error.borrow().as_ptr()),
+    }
+}
+
+/// Release the lines and free `handle`, NULL is ignored.
+///
+/// # Safety
+///
+/// `handle` must be NULL or returned by `cff3000_new()` and not freed.
+#[no_mangle]
+pub unsafe extern "C" fn cff3000_free(handle: *mut Handle) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
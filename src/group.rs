@@ -0,0 +1,199 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Several CFF3000s driven as one.
+//!
+//! A [`CFF3000Group`] owns named devices, e.g. the front door, the back
+//! door and the garage wired to different lines of one GPIO chip. Their
+//! remote controls interfere when sending at the same time, so the
+//! operations of all devices of a group run one after another with
+//! `GroupOptions::spacing` in between. This holds for every operation,
+//! also for those of the integrations and of the devices returned by
+//! `get()`, not only for the `*_all()` methods.
+//!
+//! The `*_all()` methods run on every device in the order of the names
+//! and return one result per device, a failing device does not keep
+//! the others from running.
+//!
+//! Names end up in MQTT topics and HTTP routes, so they consist of ASCII
+//! letters, digits, `-` and `_`. The integrations namespace by them:
+//! `http::serve_group()` serves `/frontdoor/state` and so on, and
+//! `mqtt::GroupPublisher` publishes to `cff3000/frontdoor/state`, see
+//! `MqttOptions::for_device()`. The `monitor()` of a group gets the
+//! notices of every device with its name.
+//!
+//! With the `config` feature, `CFF3000Group::from_config()` creates the
+//! devices of a `config::GroupConfig`:
+//!
+//! ```toml
+//! spacing_ms = 2000
+//!
+//! [devices.frontdoor]
+//! chip = "/dev/gpiochip0"
+//! pins = {led_red = 2, led_green = 3, button_unlock = 4, button_lock = 5}
+//!
+//! [devices.garage]
+//! chip = "/dev/gpiochip0"
+//! pins = {led_red = 6, led_green = 7, button_unlock = 8, button_lock = 9}
+//! ```
+
+use std::collections::btree_map::{self, BTreeMap};
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::interlock::Radio;
+use crate::{CFF3000, CFF3000Builder, CFF3000State, Notice, StateChange, StopToken, WatchOptions};
+
+/// Default `GroupOptions::spacing`.
+pub const DEFAULT_SPACING: Duration = Duration::from_millis(1000);
+
+/// Tunables of a `CFF3000Group`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GroupOptions {
+    /// Time between the end of an operation of the group and the start
+    /// of the next one (default: 1 s)
+    pub spacing: Duration,
+}
+
+impl Default for GroupOptions {
+    fn default() -> GroupOptions {
+        GroupOptions {spacing: DEFAULT_SPACING}
+    }
+}
+
+/// Callback of `CFF3000Group::monitor()`.
+pub(crate) type GroupMonitor = Arc<dyn Fn(&str, &Notice) + Send + Sync>;
+
+/// Named devices sharing a radio, see the module documentation.
+pub struct CFF3000Group {
+    radio: Arc<Radio>,
+    monitor: Option<GroupMonitor>,
+    devices: BTreeMap<String, Arc<CFF3000>>,
+}
+
+/// Check that `name` can name a device of a group.
+pub(crate) fn check_name(name: &str) -> std::io::Result<()> {
+    if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("invalid device name \"{}\", use letters, digits, - and _", name)));
+    }
+    Ok(())
+}
+
+impl CFF3000Group {
+    /// Group without devices.
+    pub fn new(options: GroupOptions) -> CFF3000Group {
+        CFF3000Group {radio: Radio::new(options.spacing), monitor: None, devices: BTreeMap::new()}
+    }
+
+    /// Pass the notices of the devices added afterwards to `callback`
+    /// with their name, after their own `CFF3000Builder::monitor()`.
+    pub fn monitor<F: Fn(&str, &Notice) + Send + Sync + 'static>(mut self, callback: F) -> CFF3000Group {
+        self.monitor = Some(Arc::new(callback));
+        self
+    }
+
+    /// Build the device `name` of `builder` into the group. It is
+    /// labeled with the name unless `CFF3000Builder::label()` says
+    /// otherwise.
+    ///
+    /// Fails with `ErrorKind::InvalidInput` for names which are not
+    /// allowed, see the module documentation, with
+    /// `ErrorKind::AlreadyExists` if there is a device of that name, or
+    /// like `CFF3000Builder::build()`.
+    pub fn add(&mut self, name: &str, builder: CFF3000Builder) -> std::io::Result<&Arc<CFF3000>> {
+        check_name(name)?;
+        let entry = match self.devices.entry(name.to_string()) {
+            btree_map::Entry::Vacant(entry) => entry,
+            btree_map::Entry::Occupied(_) => return Err(Error::new(ErrorKind::AlreadyExists, format!("the group already has a device \"{}\"", name))),
+        };
+        let device = builder.in_group(name, self.radio.clone(), self.monitor.clone()).build()?;
+        Ok(entry.insert(Arc::new(device)))
+    }
+
+    /// Device `name`, if there is one.
+    pub fn get(&self, name: &str) -> Option<&Arc<CFF3000>> {
+        self.devices.get(name)
+    }
+
+    /// Names and devices, ordered by name.
+    pub fn iter(&self) -> btree_map::Iter<'_, String, Arc<CFF3000>> {
+        self.devices.iter()
+    }
+
+    /// Names of the devices, in order.
+    pub fn names(&self) -> btree_map::Keys<'_, String, Arc<CFF3000>> {
+        self.devices.keys()
+    }
+
+    /// Number of devices.
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    /// Returns true if the group has no devices.
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// Result of `f` for every device, one after another.
+    fn each<T, F: Fn(&CFF3000) -> std::io::Result<T>>(&self, f: F) -> Vec<(String, std::io::Result<T>)> {
+        self.devices.iter().map(|(name, device)| (name.clone(), f(device))).collect()
+    }
+
+    /// `CFF3000::state()` of every device.
+    pub fn state_all(&self) -> Vec<(String, std::io::Result<CFF3000State>)> {
+        self.each(|device| device.state())
+    }
+
+    /// `CFF3000::lock_and_verify()` of every device.
+    pub fn lock_all(&self) -> Vec<(String, std::io::Result<CFF3000State>)> {
+        self.each(|device| device.lock_and_verify())
+    }
+
+    /// Query every device and lock those which are not locked, also
+    /// after a failed query, like the `ensure_locked` action of
+    /// `schedule`. The devices already locked only see the query.
+    pub fn ensure_all_locked(&self) -> Vec<(String, std::io::Result<CFF3000State>)> {
+        self.each(|device| match device.state() {
+            Ok(CFF3000State::Locked) => Ok(CFF3000State::Locked),
+            _ => device.lock_and_verify(),
+        })
+    }
+
+    /// Watch every device like `CFF3000::watch_with_options()`, each on
+    /// a thread of its own, and invoke `f` with the name for every
+    /// change until `stop` is stopped.
+    ///
+    /// The polls of the devices take turns like any other operation of
+    /// the group. A watch loop failing stops `stop`, so the others end
+    /// as well, and the error of the first device failing in name order
+    /// is returned.
+    pub fn watch_all<F>(&self, options: &WatchOptions, stop: &StopToken, f: F) -> std::io::Result<()>
+        where F: Fn(&str, StateChange) + Sync
+    {
+        let f = &f;
+        std::thread::scope(|scope| {
+            let mut loops = Vec::with_capacity(self.devices.len());
+            for (name, device) in &self.devices {
+                let watch = std::thread::Builder::new().name(format!("cff3000-watch-{}", name)).spawn_scoped(scope, move || {
+                    let result = device.watch_with_options(options, stop, |change| f(name, change));
+                    if result.is_err() {
+                        stop.stop();
+                    }
+                    result
+                });
+                match watch {
+                    Ok(watch) => loops.push(watch),
+                    Err(err) => {
+                        stop.stop();
+                        loops.into_iter().for_each(|watch| {let _ = watch.join();});
+                        return Err(err);
+                    },
+                }
+            }
+            loops.into_iter().map(|watch| watch.join().unwrap_or_else(|_| Err(Error::other("watch loop panicked"))))
+                .fold(Ok(()), Result::and)
+        })
+    }
+}
@@ -0,0 +1,214 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! gRPC service (`grpc` feature).
+//!
+//! `serve()` starts a [`GrpcServer`] implementing the `DoorLock`
+//! service of `grpc/cff3000.proto`:
+//!
+//! | RPC | Result |
+//! |-----|--------|
+//! | `Lock`, `Unlock` | the confirmed state, like `lock_and_verify()` |
+//! | `GetState` | the queried state |
+//! | `WatchState` | stream of the changes given to `GrpcServer::publish()` |
+//!
+//! The calls go through a `CommandQueue` of the server like those of
+//! the `http` module. `State` has a value for every `CFF3000State` and
+//! `STATE_UNKNOWN`, which is only used for the `previous` state of the
+//! first change. Failures use these status codes:
+//!
+//! | Code | Cause |
+//! |------|-------|
+//! | `UNAVAILABLE` | queue or device busy (`busy`) |
+//! | `DEADLINE_EXCEEDED` | no LED pattern (`no-response`) |
+//! | `DATA_LOSS` | a pattern which could not be read (`invalid-pattern`) |
+//! | `FAILED_PRECONDITION` | `Lock` or `Unlock` not confirmed (`not-confirmed`) |
+//! | `INTERNAL` | everything else (`io`) |
+//!
+//! Every `WatchState` call gets the changes through a buffer of
+//! `GrpcOptions::stream_buffer` changes. A client not keeping up loses
+//! the changes not fitting into it instead of slowing down the watch
+//! loop, the next change it gets has `missed_changes` set.
+//!
+//! The service runs on a tokio runtime of its own, so the rest of the
+//! crate stays blocking. Rust clients use the generated
+//! [`DoorLockClient`] in their async code:
+//!
+//! ```no_run
+//! use cff3000::grpc::proto::GetStateRequest;
+//! use cff3000::grpc::DoorLockClient;
+//! # fn main() {}
+//! # fn run(runtime: &tokio::runtime::Runtime) -> Result<(), Box<dyn std::error::Error>> {
+//! let mut client = runtime.block_on(DoorLockClient::connect("http://door:50051"))?;
+//! let response = runtime.block_on(client.get_state(GetStateRequest {}))?;
+//! println!("{:?}", response.into_inner().state());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io::{Error, ErrorKind};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::sync::Arc;
+
+use cff3000_grpc::Door;
+use crate::query;
+use crate::{AlreadyInUse, ChangeBroadcast, Command, CommandQueue, CommandSender, CFF3000, CFF3000State, ParseError, QueueOptions, StateChange, StopToken, Trigger, WatchOptions};
+
+pub use cff3000_grpc::{proto, Code, DoorLockClient, Status};
+
+/// Server configuration.
+#[derive(Debug, Clone)]
+pub struct GrpcOptions {
+    /// Queue of the commands, its depth bounds the calls waiting for
+    /// the device
+    pub queue: QueueOptions,
+    /// Changes buffered for every `WatchState` client
+    pub stream_buffer: usize,
+}
+
+impl Default for GrpcOptions {
+    fn default() -> GrpcOptions {
+        GrpcOptions {queue: QueueOptions::default(), stream_buffer: 16}
+    }
+}
+
+/// `State` of `state`.
+pub fn to_proto(state: CFF3000State) -> proto::State {
+    match state {
+        CFF3000State::Locked => proto::State::Locked,
+        CFF3000State::Unlocked => proto::State::Unlocked,
+        CFF3000State::Manual => proto::State::Manual,
+        CFF3000State::OutOfRange => proto::State::OutOfRange,
+    }
+}
+
+/// `CFF3000State` of `state`, `None` for `STATE_UNKNOWN`.
+pub fn from_proto(state: proto::State) -> Option<CFF3000State> {
+    match state {
+        proto::State::Unknown => None,
+        proto::State::Locked => Some(CFF3000State::Locked),
+        proto::State::Unlocked => Some(CFF3000State::Unlocked),
+        proto::State::Manual => Some(CFF3000State::Manual),
+        proto::State::OutOfRange => Some(CFF3000State::OutOfRange),
+    }
+}
+
+/// Message of a `StateChange`.
+fn change_message(change: StateChange) -> proto::StateChange {
+    let trigger = match change.trigger {
+        Trigger::Poll => proto::Trigger::Poll,
+        Trigger::AutoLock => proto::Trigger::AutoLock,
+        Trigger::Schedule => proto::Trigger::Schedule,
+        Trigger::Command => proto::Trigger::Command,
+        Trigger::External => proto::Trigger::External,
+    };
+    proto::StateChange {
+        previous: change.previous.map_or(proto::State::Unknown, to_proto) as i32,
+        current: to_proto(change.current) as i32,
+        trigger: trigger as i32,
+        missed_changes: false,
+    }
+}
+
+/// Status of a failed command, see the module documentation.
+fn error_status(err: &Error) -> Status {
+    let inner = err.get_ref();
+    let code = match query::parse_error(err) {
+        Some(&ParseError::NotEnoughEvents) => Code::DeadlineExceeded,
+        Some(_) => Code::DataLoss,
+        None if inner.is_some_and(|inner| inner.is::<AlreadyInUse>()) => Code::Unavailable,
+        None => match err.kind() {
+            ErrorKind::WouldBlock | ErrorKind::ResourceBusy => Code::Unavailable,
+            _ => Code::Internal,
+        },
+    };
+    Status::new(code, err.to_string())
+}
+
+/// The device behind the service.
+struct QueuedDoor {
+    sender: CommandSender,
+    changes: ChangeBroadcast,
+}
+
+impl Door for QueuedDoor {
+    fn execute(&self, command: cff3000_grpc::Command, peer: Option<SocketAddr>) -> Result<proto::State, Status> {
+        let (command, expected) = match command {
+            cff3000_grpc::Command::Lock => (Command::Lock, Some(CFF3000State::Locked)),
+            cff3000_grpc::Command::Unlock => (Command::Unlock, Some(CFF3000State::Unlocked)),
+            cff3000_grpc::Command::GetState => (Command::Check, None),
+        };
+        let initiator = peer.map_or_else(|| "grpc".to_string(), |peer| format!("grpc:{}", peer));
+        match self.sender.send_as(command, &initiator).recv() {
+            Ok(Ok(current)) => match expected {
+                Some(expected) if expected != current => {
+                    Err(Status::failed_precondition(format!("the door is {} instead of {}", current.name(), expected.name())))
+                },
+                _ => Ok(to_proto(current)),
+            },
+            Ok(Err(err)) => Err(error_status(&err)),
+            Err(_) => Err(Status::unavailable("command queue has been shut down")),
+        }
+    }
+
+    fn subscribe(&self) -> Box<dyn Iterator<Item = proto::StateChange> + Send> {
+        Box::new(self.changes.subscribe().into_iter().map(change_message))
+    }
+}
+
+/// Running server, see the module documentation. Dropping it ends the
+/// `WatchState` streams, stops accepting calls and waits for the queued
+/// commands.
+pub struct GrpcServer {
+    server: cff3000_grpc::Server,
+    changes: ChangeBroadcast,
+    _queue: CommandQueue,
+}
+
+impl GrpcServer {
+    /// Address the server is listening on, e.g. to find the port chosen
+    /// for port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.server.local_addr()
+    }
+
+    /// Send `change` to every `WatchState` client.
+    pub fn publish(&self, change: StateChange) {
+        self.changes.send(change);
+    }
+
+    /// Number of `WatchState` clients, including those which left since
+    /// the last change.
+    pub fn watch_clients(&self) -> usize {
+        self.changes.subscribers()
+    }
+}
+
+impl Drop for GrpcServer {
+    fn drop(&mut self) {
+        /* the server waits for the streams to end */
+        self.changes.close();
+    }
+}
+
+/// Serve `cff3000` on `addr` with default options.
+pub fn serve<A: ToSocketAddrs>(cff3000: Arc<CFF3000>, addr: A) -> std::io::Result<GrpcServer> {
+    serve_with_options(cff3000, addr, GrpcOptions::default())
+}
+
+/// Serve `cff3000` on `addr` with `options`. Fails if the address
+/// cannot be bound.
+pub fn serve_with_options<A: ToSocketAddrs>(cff3000: Arc<CFF3000>, addr: A, options: GrpcOptions) -> std::io::Result<GrpcServer> {
+    let listener = TcpListener::bind(addr)?;
+    let queue = CommandQueue::with_options(cff3000, options.queue)?;
+    let changes = ChangeBroadcast::new();
+    let door = QueuedDoor {sender: queue.sender(), changes: changes.clone()};
+    let server = cff3000_grpc::Server::start(Arc::new(door), listener, options.stream_buffer)?;
+    Ok(GrpcServer {server, changes, _queue: queue})
+}
+
+/// Watch `cff3000` like `CFF3000::watch_with_options()` and publish
+/// every state change to `server` until `stop` is stopped.
+pub fn publish_changes(cff3000: &CFF3000, options: &WatchOptions, stop: &StopToken, server: &GrpcServer) -> std::io::Result<()> {
+    cff3000.watch_with_options(options, stop, |change| server.publish(change))
+}
@@ -0,0 +1,227 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Backend for I2C GPIO expanders (MCP23017, PCF8574).
+//!
+//! All four signals are connected to one expander, addressed by their
+//! bit number on the expander (MCP23017: 0-7 = GPA0-7, 8-15 = GPB0-7).
+//! The LED inputs are polled like with `HalBackend`. If the expander's
+//! interrupt output is connected, pass it as `int` and the LED port is
+//! only read over I2C after the expander signalled a change.
+//!
+//! # Timing resolution
+//!
+//! Reading the inputs takes about 5 bytes on the bus, i.e. ~0.1 ms at
+//! 400 kHz or ~0.5 ms at 100 kHz. Edge timestamps are therefore late by
+//! up to one poll period plus one transfer. With the default poll period
+//! of 5 ms this stays far below the default merge window of 50 ms and the
+//! 500 ms LED blink period, so the default `ParseOptions` (with the poll
+//! period added to the merge window) classify reliably.
+//!
+//! The PCF8574 has quasi-bidirectional ports: a high output is only a
+//! weak pull-up, so the button driver stage must not need more than
+//! ~100 µA of input current.
+
+use std::convert::Infallible;
+use std::time::Duration;
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{ErrorType, InputPin};
+use embedded_hal::i2c::I2c;
+
+use super::{pin_error, Lines, Poller};
+use crate::{Button, Clock, GpioBackend, Led, LedEvent, ParseOptions, SystemClock};
+
+const MCP23017_IODIRA: u8 = 0x00;
+const MCP23017_GPINTENA: u8 = 0x04;
+const MCP23017_IOCON: u8 = 0x0A;
+const MCP23017_GPIOA: u8 = 0x12;
+const MCP23017_OLATA: u8 = 0x14;
+/// IOCON: INTA and INTB both signal changes of either port
+const MCP23017_IOCON_MIRROR: u8 = 0x40;
+
+/// Supported expander chips.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Expander {
+    /// Microchip MCP23017, 16 bit (default address 0x20)
+    Mcp23017 {address: u8},
+    /// NXP/TI PCF8574, 8 bit (default address 0x20, PCF8574A: 0x38)
+    Pcf8574 {address: u8},
+}
+
+impl Expander {
+    fn width(&self) -> u8 {
+        match *self {
+            Expander::Mcp23017 {..} => 16,
+            Expander::Pcf8574 {..} => 8,
+        }
+    }
+}
+
+/// Placeholder for an unconnected interrupt line; always reports a
+/// pending interrupt, so the inputs are read on every poll.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct NoInterrupt;
+
+impl ErrorType for NoInterrupt {
+    type Error = Infallible;
+}
+
+impl InputPin for NoInterrupt {
+    fn is_high(&mut self) -> Result<bool, Infallible> {
+        Ok(false)
+    }
+
+    fn is_low(&mut self) -> Result<bool, Infallible> {
+        Ok(true)
+    }
+}
+
+struct ExpanderLines<B, N> {
+    bus: B,
+    chip: Expander,
+    /// Bit numbers of LED red, LED green, button unlock, button lock
+    bits: [u8; 4],
+    /// Active-low interrupt output of the expander
+    int: N,
+    /// Output latch shadow
+    outputs: u16,
+}
+
+impl<B: I2c, N> ExpanderLines<B, N> {
+    fn input_mask(&self) -> u16 {
+        (1 << self.bits[0]) | (1 << self.bits[1])
+    }
+
+    fn configure(&mut self) -> std::io::Result<()> {
+        match self.chip {
+            Expander::Mcp23017 {address} => {
+                let inputs = !((1u16 << self.bits[2]) | (1 << self.bits[3]));
+                let leds = self.input_mask();
+                self.bus.write(address, &[MCP23017_IOCON, MCP23017_IOCON_MIRROR]).map_err(pin_error)?;
+                /* latch low before switching the buttons to outputs */
+                self.bus.write(address, &[MCP23017_OLATA, 0, 0]).map_err(pin_error)?;
+                self.bus.write(address, &[MCP23017_IODIRA, inputs as u8, (inputs >> 8) as u8]).map_err(pin_error)?;
+                /* interrupt on any change of the LED inputs */
+                self.bus.write(address, &[MCP23017_GPINTENA, leds as u8, (leds >> 8) as u8]).map_err(pin_error)?;
+                self.outputs = 0;
+            },
+            Expander::Pcf8574 {..} => {
+                /* buttons low, everything else high (= input) */
+                self.outputs = 0xff & !((1 << self.bits[2]) | (1 << self.bits[3]));
+                self.write_outputs()?;
+            },
+        }
+        Ok(())
+    }
+
+    fn write_outputs(&mut self) -> std::io::Result<()> {
+        let outputs = self.outputs;
+        let result = match self.chip {
+            Expander::Mcp23017 {address} => self.bus.write(address, &[MCP23017_OLATA, outputs as u8, (outputs >> 8) as u8]),
+            Expander::Pcf8574 {address} => self.bus.write(address, &[outputs as u8]),
+        };
+        result.map_err(pin_error)
+    }
+
+    fn read_inputs(&mut self) -> std::io::Result<u16> {
+        match self.chip {
+            Expander::Mcp23017 {address} => {
+                let mut buf = [0u8; 2];
+                self.bus.write_read(address, &[MCP23017_GPIOA], &mut buf).map_err(pin_error)?;
+                Ok(buf[0] as u16 | (buf[1] as u16) << 8)
+            },
+            Expander::Pcf8574 {address} => {
+                let mut buf = [0u8; 1];
+                self.bus.read(address, &mut buf).map_err(pin_error)?;
+                Ok(buf[0] as u16)
+            },
+        }
+    }
+}
+
+impl<B: I2c, N: InputPin> Lines for ExpanderLines<B, N> {
+    fn read_leds(&mut self) -> std::io::Result<[bool; 2]> {
+        /* reading the port also clears the expander interrupt */
+        let inputs = self.read_inputs()?;
+        Ok([inputs & (1 << self.bits[0]) != 0, inputs & (1 << self.bits[1]) != 0])
+    }
+
+    fn set_button(&mut self, button: Button, pressed: bool) -> std::io::Result<()> {
+        let bit = match button {
+            Button::Unlock => self.bits[2],
+            Button::Lock => self.bits[3],
+        };
+        if pressed {
+            self.outputs |= 1 << bit;
+        } else {
+            self.outputs &= !(1 << bit);
+        }
+        self.write_outputs()
+    }
+
+    fn may_have_changed(&mut self) -> std::io::Result<bool> {
+        self.int.is_low().map_err(pin_error)
+    }
+}
+
+/// Backend for a CFF3000 connected through an I2C GPIO expander.
+pub struct ExpanderBackend<B, D, N = NoInterrupt, C = SystemClock> {
+    poller: Poller<ExpanderLines<B, N>, D, C>,
+}
+
+impl<B: I2c, D: DelayNs> ExpanderBackend<B, D, NoInterrupt, SystemClock> {
+    /// Configure `chip` on `bus` and poll the LED inputs every
+    /// `poll_period` (see `hal::DEFAULT_POLL_PERIOD`).
+    ///
+    /// `bits` contains the expander bit numbers for LED red, LED green,
+    /// button unlock and button lock (in this order).
+    pub fn new(bus: B, chip: Expander, bits: [u8; 4], delay: D, poll_period: Duration) -> std::io::Result<ExpanderBackend<B, D, NoInterrupt, SystemClock>> {
+        ExpanderBackend::with_interrupt(bus, chip, bits, NoInterrupt, delay, poll_period, SystemClock)
+    }
+}
+
+impl<B: I2c, D: DelayNs, N: InputPin, C: Clock> ExpanderBackend<B, D, N, C> {
+    /// Like `new()`, but only read the inputs while the expander's
+    /// active-low interrupt output `int` is asserted, and take timestamps
+    /// and deadlines from `clock`.
+    pub fn with_interrupt(bus: B, chip: Expander, bits: [u8; 4], int: N, delay: D, poll_period: Duration, clock: C) -> std::io::Result<ExpanderBackend<B, D, N, C>> {
+        let width = chip.width();
+        if bits.iter().any(|&bit| bit >= width) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "expander bit number out of range"));
+        }
+        for i in 0..bits.len() {
+            if bits[i + 1..].contains(&bits[i]) {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "expander bit used twice"));
+            }
+        }
+
+        let mut lines = ExpanderLines {bus, chip, bits, int, outputs: 0};
+        lines.configure()?;
+        Ok(ExpanderBackend {poller: Poller::new(lines, delay, poll_period, clock)?})
+    }
+}
+
+impl<B, D, N, C> GpioBackend for ExpanderBackend<B, D, N, C>
+    where B: I2c + Send, D: DelayNs + Send, N: InputPin + Send, C: Clock + Send + Sync
+{
+    fn set_button(&self, button: Button, pressed: bool) -> std::io::Result<()> {
+        self.poller.set_button(button, pressed)
+    }
+
+    fn wait_for_led_events(&self, timeout: Duration) -> std::io::Result<u8> {
+        self.poller.wait_for_led_events(timeout)
+    }
+
+    fn read_led_event(&self, led: Led) -> std::io::Result<LedEvent> {
+        self.poller.read_led_event(led)
+    }
+
+    fn flush_led_events(&self) -> std::io::Result<()> {
+        self.poller.flush_led_events()
+    }
+
+    fn parse_options(&self) -> ParseOptions {
+        self.poller.parse_options()
+    }
+}
@@ -0,0 +1,84 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! FT232H USB breakout backend for desktop development.
+//!
+//! The four signals are connected to the ADBUS pins of an FT232H (AD4 to
+//! AD7 are used by default, AD0 to AD3 are left free for the MPSSE
+//! clock/data functions). The pins are driven through `ftdi-embedded-hal`
+//! and the LEDs are polled like with `HalBackend`, with timestamps taken
+//! on the host when a change is sampled.
+//!
+//! # Jitter
+//!
+//! Every sample is a USB round trip, which usually takes 0.5-2 ms and
+//! occasionally more on a busy host. Expect timestamps to be late by up
+//! to one poll period plus one round trip (typically < 10 ms with the
+//! default poll period), which is covered by the default merge window.
+
+use std::time::Duration;
+
+use ftdi_embedded_hal::{FtHal, InputPin, OutputPin};
+
+use crate::hal::{pin_error, HalBackend, StdDelay};
+
+/// USB vendor ID of FTDI.
+pub const FTDI_VID: u16 = 0x0403;
+/// USB product ID of the FT232H.
+pub const FT232H_PID: u16 = 0x6014;
+
+/// Default ADBUS pins for LED red, LED green, button unlock and button lock.
+pub const DEFAULT_PINS: [u8; 4] = [4, 5, 6, 7];
+
+/// `HalBackend` over FT232H pins.
+pub type Ft232hBackend = HalBackend<InputPin<ftdi::Device>, OutputPin<ftdi::Device>, StdDelay>;
+
+fn pin_number_error() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, "FT232H ADBUS pin number must be 0-7")
+}
+
+fn input(hal: &FtHal<ftdi::Device>, pin: u8) -> std::io::Result<InputPin<ftdi::Device>> {
+    let result = match pin {
+        0 => hal.adi0(),
+        1 => hal.adi1(),
+        2 => hal.adi2(),
+        3 => hal.adi3(),
+        4 => hal.adi4(),
+        5 => hal.adi5(),
+        6 => hal.adi6(),
+        7 => hal.adi7(),
+        _ => return Err(pin_number_error()),
+    };
+    result.map_err(pin_error)
+}
+
+fn output(hal: &FtHal<ftdi::Device>, pin: u8) -> std::io::Result<OutputPin<ftdi::Device>> {
+    let result = match pin {
+        0 => hal.ad0(),
+        1 => hal.ad1(),
+        2 => hal.ad2(),
+        3 => hal.ad3(),
+        4 => hal.ad4(),
+        5 => hal.ad5(),
+        6 => hal.ad6(),
+        7 => hal.ad7(),
+        _ => return Err(pin_number_error()),
+    };
+    result.map_err(pin_error)
+}
+
+/// Open the first FT232H and set up `pins` (ADBUS numbers for LED red,
+/// LED green, button unlock and button lock), polling every `poll_period`.
+pub fn open(pins: [u8; 4], poll_period: Duration) -> std::io::Result<Ft232hBackend> {
+    let device = (ftdi::find_by_vid_pid(FTDI_VID, FT232H_PID)
+        .interface(ftdi::Interface::A)
+        .open()
+        .map_err(std::io::Error::other))?;
+    let hal = FtHal::init_freq(device, 100_000).map_err(pin_error)?;
+
+    let red = input(&hal, pins[0])?;
+    let green = input(&hal, pins[1])?;
+    let unlock = output(&hal, pins[2])?;
+    let lock = output(&hal, pins[3])?;
+    HalBackend::new(red, green, unlock, lock, StdDelay, poll_period)
+}
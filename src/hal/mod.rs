@@ -0,0 +1,253 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Backend for `embedded-hal` digital pins.
+//!
+//! embedded-hal has no edge events, so the LED inputs are sampled every
+//! poll period while the driver waits for LED events, and an event is
+//! synthesized for every level change. Timestamps are taken when the
+//! change is sampled, i.e. up to one poll period late. The backend
+//! reports its poll period in `ParseOptions`, so the parser widens its
+//! merge window accordingly.
+//!
+//! Sampling only happens while the driver waits for events (which is
+//! most of the capture window); keep the poll period well below the
+//! 500 ms LED blink period, e.g. at the default of 5 ms.
+
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{InputPin, OutputPin};
+
+use crate::clock::deadline;
+use crate::{Button, Clock, GpioBackend, Led, LedEvent, ParseOptions, SystemClock, LED_GREEN, LED_RED};
+
+#[cfg(feature = "i2c-expander")]
+pub mod expander;
+#[cfg(feature = "ftdi")]
+pub mod ft232h;
+
+/// Default sampling period of the LED inputs.
+pub const DEFAULT_POLL_PERIOD: Duration = Duration::from_millis(5);
+
+/// `DelayNs` implementation using `std::thread::sleep()`.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct StdDelay;
+
+impl DelayNs for StdDelay {
+    fn delay_ns(&mut self, ns: u32) {
+        std::thread::sleep(Duration::from_nanos(ns as u64));
+    }
+}
+
+pub(crate) fn pin_error<E: std::fmt::Debug>(err: E) -> Error {
+    Error::other(format!("GPIO pin error: {:?}", err))
+}
+
+/// Signals sampled by a `Poller`.
+pub(crate) trait Lines {
+    /// Current levels of LED red and green.
+    fn read_leds(&mut self) -> std::io::Result<[bool; 2]>;
+
+    /// Drive `button` (true = pressed).
+    fn set_button(&mut self, button: Button, pressed: bool) -> std::io::Result<()>;
+
+    /// Cheap check whether `read_leds()` may return new levels, e.g.
+    /// based on an interrupt line.
+    fn may_have_changed(&mut self) -> std::io::Result<bool> {
+        Ok(true)
+    }
+}
+
+struct PollState<L, D> {
+    lines: L,
+    delay: D,
+    /// Last sampled levels of red and green
+    levels: [bool; 2],
+    pending: [VecDeque<LedEvent>; 2],
+}
+
+impl<L: Lines, D> PollState<L, D> {
+    /// Sample both inputs and queue events for changed levels.
+    fn sample(&mut self, timestamp: Duration) -> std::io::Result<()> {
+        if !self.lines.may_have_changed()? {
+            return Ok(());
+        }
+        let levels = self.lines.read_leds()?;
+
+        for (i, &led) in [Led::Red, Led::Green].iter().enumerate() {
+            if self.levels[i] != levels[i] {
+                self.levels[i] = levels[i];
+                self.pending[i].push_back(LedEvent {led, on: levels[i], timestamp});
+            }
+        }
+        Ok(())
+    }
+
+    fn pending_mask(&self) -> u8 {
+        let mut mask = 0;
+        if !self.pending[0].is_empty() {
+            mask |= LED_RED;
+        }
+        if !self.pending[1].is_empty() {
+            mask |= LED_GREEN;
+        }
+        mask
+    }
+}
+
+/// Polling implementation of the `GpioBackend` operations shared by the
+/// embedded-hal based backends.
+pub(crate) struct Poller<L, D, C> {
+    state: Mutex<PollState<L, D>>,
+    poll_period: Duration,
+    clock: C,
+    start: Instant,
+}
+
+impl<L: Lines, D: DelayNs, C: Clock> Poller<L, D, C> {
+    pub(crate) fn new(mut lines: L, delay: D, poll_period: Duration, clock: C) -> std::io::Result<Poller<L, D, C>> {
+        if poll_period == Duration::from_millis(0) {
+            return Err(Error::new(ErrorKind::InvalidInput, "poll period must not be zero"));
+        }
+
+        lines.set_button(Button::Unlock, false)?;
+        lines.set_button(Button::Lock, false)?;
+        let levels = lines.read_leds()?;
+
+        let start = clock.now();
+        Ok(Poller {
+            state: Mutex::new(PollState {lines, delay, levels, pending: [VecDeque::new(), VecDeque::new()]}),
+            poll_period,
+            clock,
+            start,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PollState<L, D>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn timestamp(&self) -> Duration {
+        self.clock.now().saturating_duration_since(self.start)
+    }
+
+    pub(crate) fn set_button(&self, button: Button, pressed: bool) -> std::io::Result<()> {
+        self.lock().lines.set_button(button, pressed)
+    }
+
+    pub(crate) fn wait_for_led_events(&self, timeout: Duration) -> std::io::Result<u8> {
+        let deadline = deadline(self.clock.now(), timeout);
+        let mut state = self.lock();
+
+        loop {
+            state.sample(self.timestamp())?;
+            let mask = state.pending_mask();
+            let now = self.clock.now();
+            if mask != 0 || now >= deadline {
+                return Ok(mask);
+            }
+
+            let sleep = std::cmp::min(self.poll_period, deadline - now);
+            state.delay.delay_us(std::cmp::max(sleep.as_micros() as u32, 1));
+        }
+    }
+
+    pub(crate) fn read_led_event(&self, led: Led) -> std::io::Result<LedEvent> {
+        let index = match led {
+            Led::Red => 0,
+            Led::Green => 1,
+        };
+        self.lock().pending[index].pop_front().ok_or_else(|| Error::new(ErrorKind::WouldBlock, "no pending LED event"))
+    }
+
+    pub(crate) fn flush_led_events(&self) -> std::io::Result<()> {
+        let mut state = self.lock();
+        state.sample(self.timestamp())?;
+        state.pending[0].clear();
+        state.pending[1].clear();
+        Ok(())
+    }
+
+    pub(crate) fn parse_options(&self) -> ParseOptions {
+        ParseOptions {poll_period: self.poll_period, ..ParseOptions::default()}
+    }
+}
+
+struct Pins<I, O> {
+    red: I,
+    green: I,
+    unlock: O,
+    lock: O,
+}
+
+impl<I: InputPin, O: OutputPin> Lines for Pins<I, O> {
+    fn read_leds(&mut self) -> std::io::Result<[bool; 2]> {
+        let red = self.red.is_high().map_err(pin_error)?;
+        let green = self.green.is_high().map_err(pin_error)?;
+        Ok([red, green])
+    }
+
+    fn set_button(&mut self, button: Button, pressed: bool) -> std::io::Result<()> {
+        let pin = match button {
+            Button::Unlock => &mut self.unlock,
+            Button::Lock => &mut self.lock,
+        };
+        let result = if pressed {pin.set_high()} else {pin.set_low()};
+        result.map_err(pin_error)
+    }
+}
+
+/// Backend polling two embedded-hal `InputPin`s and driving two
+/// `OutputPin`s, with `D` providing the delay between samples and `C`
+/// the time base for timestamps and deadlines.
+pub struct HalBackend<I, O, D, C = SystemClock> {
+    poller: Poller<Pins<I, O>, D, C>,
+}
+
+impl<I, O, D> HalBackend<I, O, D, SystemClock>
+    where I: InputPin, O: OutputPin, D: DelayNs
+{
+    /// Set up the pins for LED red, LED green, button unlock and button
+    /// lock, sampling the LEDs every `poll_period`.
+    pub fn new(red: I, green: I, unlock: O, lock: O, delay: D, poll_period: Duration) -> std::io::Result<HalBackend<I, O, D, SystemClock>> {
+        HalBackend::with_clock(red, green, unlock, lock, delay, poll_period, SystemClock)
+    }
+}
+
+impl<I, O, D, C> HalBackend<I, O, D, C>
+    where I: InputPin, O: OutputPin, D: DelayNs, C: Clock
+{
+    /// Like `new()`, but with timestamps and deadlines taken from `clock`.
+    pub fn with_clock(red: I, green: I, unlock: O, lock: O, delay: D, poll_period: Duration, clock: C) -> std::io::Result<HalBackend<I, O, D, C>> {
+        let pins = Pins {red, green, unlock, lock};
+        Ok(HalBackend {poller: Poller::new(pins, delay, poll_period, clock)?})
+    }
+}
+
+impl<I, O, D, C> GpioBackend for HalBackend<I, O, D, C>
+    where I: InputPin + Send, O: OutputPin + Send, D: DelayNs + Send, C: Clock + Send + Sync
+{
+    fn set_button(&self, button: Button, pressed: bool) -> std::io::Result<()> {
+        self.poller.set_button(button, pressed)
+    }
+
+    fn wait_for_led_events(&self, timeout: Duration) -> std::io::Result<u8> {
+        self.poller.wait_for_led_events(timeout)
+    }
+
+    fn read_led_event(&self, led: Led) -> std::io::Result<LedEvent> {
+        self.poller.read_led_event(led)
+    }
+
+    fn flush_led_events(&self) -> std::io::Result<()> {
+        self.poller.flush_led_events()
+    }
+
+    fn parse_options(&self) -> ParseOptions {
+        self.poller.parse_options()
+    }
+}
@@ -0,0 +1,552 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Periodic probe of the GPIO lines, see
+//! `CFF3000Builder::health_check()`.
+//!
+//! A half dead USB GPIO adapter may keep answering line reads while its
+//! events never arrive, which usually shows only when the next capture
+//! comes back empty. The health check probes the lines every
+//! `HealthOptions::interval` on a thread of its own, never while an
+//! operation runs:
+//!
+//! - the device node of the chip still exists (devices built with
+//!   `new()`),
+//! - all four lines are still requested, the LEDs as inputs and the
+//!   buttons as outputs (the buttons are left alone in a dry run),
+//! - no LED has been lit for `HealthOptions::stuck_after` without an
+//!   operation, which the CFF3000 never does, and
+//! - an LED changing its level between two probes did so with an event.
+//!
+//! Backends without line information only get the first check. Probes
+//! finding problems are reported as `Notice::HealthDegraded` to the
+//! `CFF3000Builder::monitor()` callback whenever the problems change,
+//! the first probe without problems after them as
+//! `Notice::HealthRestored`. `CFF3000::health()` returns the last
+//! result, which `/healthz` of the `http` module answers with. With
+//! `HealthOptions::reopen` a failed probe releases the lines and
+//! requests them again as `CFF3000Builder::auto_reopen()` does.
+//!
+//! # Reports
+//!
+//! `CFF3000::health_check()` answers right away, with or without the
+//! periodic probe: it checks the chip and the lines like a probe does,
+//! looks up the last state query and the last error in `history`, and
+//! adds the LED problems of the last probe and whether its thread still
+//! runs. `HealthReport::with_queue()` adds the depth of a
+//! `CommandQueue`. Problems with the lines make the report
+//! `HealthVerdict::Unhealthy`, a failed last operation, a stopped probe
+//! or a full queue `HealthVerdict::Degraded`. `/healthz` answers with
+//! the report, which also serializes with serde (`config` feature):
+//!
+//! ```text
+//! {"verdict":"degraded","reasons":["the last operation failed: did not receive enough LED change events"],"chip_reachable":true,"lines_held":true,"last_state_age_ms":310204,"last_error":{"code":"no-response","message":"did not receive enough LED change events"},"watchdog_alive":true,"queue_depth":0}
+//! ```
+//!
+//! Values a device cannot tell are left out, e.g. `chip_reachable` for
+//! custom backends or `last_state_age_ms` before the first state query.
+
+use std::fmt;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+#[cfg(feature = "config")]
+use serde::{Serialize, Serializer};
+
+use crate::backend::ReopeningBackend;
+use crate::discover::LineFlags;
+use crate::history::HistoryError;
+use crate::interlock::Interlock;
+#[cfg(feature = "http")]
+use crate::json::json_string;
+use crate::notice::{self, Monitor};
+use crate::{CFF3000, CommandSender, GpioBackend, LineInfo, LineRole, Notice, Polarities, Polarity, SharedClock, StopToken, LED_GREEN, LED_RED};
+
+/// Default of `HealthOptions::interval`.
+pub const DEFAULT_HEALTH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Default of `HealthOptions::stuck_after`, far longer than any pattern
+/// of the CFF3000.
+pub const DEFAULT_STUCK_AFTER: Duration = Duration::from_secs(120);
+
+/// Configuration of the health check, see `CFF3000Builder::health_check()`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct HealthOptions {
+    /// Time between two probes (default: `DEFAULT_HEALTH_INTERVAL`)
+    pub interval: Duration,
+    /// Time an LED may be lit without an operation (default:
+    /// `DEFAULT_STUCK_AFTER`)
+    pub stuck_after: Duration,
+    /// Release and request the lines again after a failed probe, only
+    /// for devices built with `CFF3000Builder::auto_reopen()` (default:
+    /// off)
+    pub reopen: bool,
+}
+
+impl Default for HealthOptions {
+    fn default() -> HealthOptions {
+        HealthOptions {interval: DEFAULT_HEALTH_INTERVAL, stuck_after: DEFAULT_STUCK_AFTER, reopen: false}
+    }
+}
+
+/// Problem found by a probe.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HealthProblem {
+    /// The device node `chipdev` is gone
+    ChipMissing {chipdev: String},
+    /// The lines could not be read
+    Unreadable {message: String},
+    /// The line of `role` is not requested the way the device requested
+    /// it, `flags` are the ones reported by the kernel
+    LineFlags {role: LineRole, flags: LineFlags},
+    /// The LED of `role` has been lit for `lit` without an operation
+    Stuck {role: LineRole, lit: Duration},
+    /// The LED of `role` changed its level without an event
+    MissedEvent {role: LineRole},
+    /// The last operation of the device failed with `error`
+    OperationFailed {error: HistoryError},
+    /// The thread of `CFF3000Builder::health_check()` is gone
+    WatchdogStopped,
+    /// The command queue holds `depth` commands and takes no more
+    QueueFull {depth: usize},
+}
+
+impl HealthProblem {
+    /// The problem without its duration, to tell new problems from
+    /// lasting ones.
+    fn kind(&self) -> HealthProblem {
+        match *self {
+            HealthProblem::Stuck {role, ..} => HealthProblem::Stuck {role, lit: Duration::from_secs(0)},
+            ref problem => problem.clone(),
+        }
+    }
+
+    /// Verdict of a report with only this problem.
+    pub fn verdict(&self) -> HealthVerdict {
+        match *self {
+            HealthProblem::OperationFailed {..} | HealthProblem::WatchdogStopped | HealthProblem::QueueFull {..} => HealthVerdict::Degraded,
+            _ => HealthVerdict::Unhealthy,
+        }
+    }
+}
+
+/// One line for logs, e.g. "LED red lit for 130 s without an
+/// operation".
+impl fmt::Display for HealthProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HealthProblem::ChipMissing {ref chipdev} => write!(f, "{} is missing", chipdev),
+            HealthProblem::Unreadable {ref message} => write!(f, "cannot read the lines: {}", message),
+            HealthProblem::LineFlags {role, flags} if !flags.used => write!(f, "{} is not requested", notice::role_name(role)),
+            HealthProblem::LineFlags {role, flags} => {
+                write!(f, "{} is requested as an {}", notice::role_name(role), if flags.output {"output"} else {"input"})
+            },
+            HealthProblem::Stuck {role, lit} => write!(f, "{} lit for {} s without an operation", notice::role_name(role), lit.as_secs()),
+            HealthProblem::MissedEvent {role} => write!(f, "{} changed without an event", notice::role_name(role)),
+            HealthProblem::OperationFailed {ref error} => write!(f, "the last operation failed: {}", error.message),
+            HealthProblem::WatchdogStopped => f.write_str("the health check is not running"),
+            HealthProblem::QueueFull {depth} => write!(f, "the command queue is full with {} commands", depth),
+        }
+    }
+}
+
+/// Result of a probe, see `CFF3000::health()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthStatus {
+    /// Time of the probe
+    pub checked: SystemTime,
+    /// Empty if nothing is wrong
+    pub problems: Vec<HealthProblem>,
+}
+
+impl HealthStatus {
+    /// Returns true if the probe found no problem.
+    pub fn healthy(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Overall result of `CFF3000::health_check()`, ordered from best to
+/// worst.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "config", derive(Serialize), serde(rename_all = "lowercase"))]
+pub enum HealthVerdict {
+    Healthy,
+    /// The lines work, but the device may not
+    Degraded,
+    /// The lines are gone or do not work
+    Unhealthy,
+}
+
+impl HealthVerdict {
+    /// Name as in the JSON reports, e.g. "degraded".
+    pub fn name(self) -> &'static str {
+        match self {
+            HealthVerdict::Healthy => "healthy",
+            HealthVerdict::Degraded => "degraded",
+            HealthVerdict::Unhealthy => "unhealthy",
+        }
+    }
+}
+
+/// Answer of `CFF3000::health_check()`, see the module documentation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "config", derive(Serialize))]
+pub struct HealthReport {
+    /// Worst verdict of the `reasons`, `Healthy` without any
+    pub verdict: HealthVerdict,
+    /// Problems found, serialized as their messages
+    #[cfg_attr(feature = "config", serde(serialize_with = "messages"))]
+    pub reasons: Vec<HealthProblem>,
+    /// The device node exists, `None` for custom backends
+    #[cfg_attr(feature = "config", serde(skip_serializing_if = "Option::is_none"))]
+    pub chip_reachable: Option<bool>,
+    /// All four lines are requested the way the device requested them,
+    /// `None` for backends without line information
+    #[cfg_attr(feature = "config", serde(skip_serializing_if = "Option::is_none"))]
+    pub lines_held: Option<bool>,
+    /// Time since the end of the last state query that returned a
+    /// state, `None` without one in the history
+    #[cfg_attr(feature = "config", serde(rename = "last_state_age_ms", serialize_with = "millis", skip_serializing_if = "Option::is_none"))]
+    pub last_state_age: Option<Duration>,
+    /// Error of the last failed operation in the history, even if
+    /// others succeeded since
+    #[cfg_attr(feature = "config", serde(skip_serializing_if = "Option::is_none"))]
+    pub last_error: Option<HistoryError>,
+    /// The thread of `CFF3000Builder::health_check()` runs, `None`
+    /// without one
+    #[cfg_attr(feature = "config", serde(skip_serializing_if = "Option::is_none"))]
+    pub watchdog_alive: Option<bool>,
+    /// Commands waiting in the queue of `with_queue()`
+    #[cfg_attr(feature = "config", serde(skip_serializing_if = "Option::is_none"))]
+    pub queue_depth: Option<usize>,
+}
+
+#[cfg(feature = "config")]
+fn messages<S: Serializer>(reasons: &[HealthProblem], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(reasons.iter().map(|reason| reason.to_string()))
+}
+
+#[cfg(feature = "config")]
+fn millis<S: Serializer>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+    match *duration {
+        Some(duration) => serializer.serialize_some(&(duration.as_millis() as u64)),
+        None => serializer.serialize_none(),
+    }
+}
+
+impl HealthReport {
+    /// Report without any findings, for locks other than a `CFF3000`.
+    #[cfg(feature = "http")]
+    pub(crate) fn unknown() -> HealthReport {
+        HealthReport {
+            verdict: HealthVerdict::Healthy,
+            reasons: Vec::new(),
+            chip_reachable: None,
+            lines_held: None,
+            last_state_age: None,
+            last_error: None,
+            watchdog_alive: None,
+            queue_depth: None,
+        }
+    }
+
+    /// Add the depth of the queue of `sender`, e.g. the one the device
+    /// is used through. A full queue degrades the report.
+    pub fn with_queue(mut self, sender: &CommandSender) -> HealthReport {
+        let depth = sender.pending();
+        self.queue_depth = Some(depth);
+        if sender.is_full() {
+            self.add(HealthProblem::QueueFull {depth});
+        }
+        self
+    }
+
+    fn add(&mut self, reason: HealthProblem) {
+        self.verdict = std::cmp::max(self.verdict, reason.verdict());
+        self.reasons.push(reason);
+    }
+
+    /// Append the report as a JSON object, see the module documentation.
+    #[cfg(feature = "http")]
+    pub(crate) fn write_json(&self, out: &mut String) {
+        out.push_str("{\"verdict\":");
+        json_string(out, self.verdict.name());
+        out.push_str(",\"reasons\":[");
+        for (i, reason) in self.reasons.iter().enumerate() {
+            if i != 0 {
+                out.push(',');
+            }
+            json_string(out, &reason.to_string());
+        }
+        out.push(']');
+        if let Some(reachable) = self.chip_reachable {
+            out.push_str(&format!(",\"chip_reachable\":{}", reachable));
+        }
+        if let Some(held) = self.lines_held {
+            out.push_str(&format!(",\"lines_held\":{}", held));
+        }
+        if let Some(age) = self.last_state_age {
+            out.push_str(&format!(",\"last_state_age_ms\":{}", age.as_millis()));
+        }
+        if let Some(ref error) = self.last_error {
+            out.push_str(",\"last_error\":{\"code\":");
+            json_string(out, error.code);
+            out.push_str(",\"message\":");
+            json_string(out, &error.message);
+            out.push('}');
+        }
+        if let Some(alive) = self.watchdog_alive {
+            out.push_str(&format!(",\"watchdog_alive\":{}", alive));
+        }
+        if let Some(depth) = self.queue_depth {
+            out.push_str(&format!(",\"queue_depth\":{}", depth));
+        }
+        out.push('}');
+    }
+}
+
+/// State shared by a device and its health check thread.
+#[derive(Default)]
+pub(crate) struct Shared {
+    /// LED events read or flushed by the device
+    events: AtomicUsize,
+    status: Mutex<Option<HealthStatus>>,
+}
+
+impl Shared {
+    pub(crate) fn note_events(&self) {
+        self.events.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// What the health check probes and where it reports.
+pub(crate) struct Probe {
+    pub(crate) backend: Arc<dyn GpioBackend>,
+    pub(crate) interlock: Arc<Interlock>,
+    pub(crate) clock: SharedClock,
+    /// Device node of a chip
+    pub(crate) chipdev: Option<String>,
+    pub(crate) polarities: Polarities,
+    /// The button lines are requested, i.e. no dry run
+    pub(crate) buttons: bool,
+    pub(crate) reopen: Option<Arc<ReopeningBackend>>,
+    pub(crate) monitor: Option<Monitor>,
+    pub(crate) options: HealthOptions,
+}
+
+/// LEDs seen by the last probe.
+#[derive(Default)]
+struct Seen {
+    operations: Option<usize>,
+    events: usize,
+    levels: [Option<bool>; 2],
+    lit_since: [Option<Instant>; 2],
+}
+
+/// Add `ChipMissing` if the device node `chipdev` is gone, returns
+/// whether it exists.
+fn check_chip(chipdev: &str, problems: &mut Vec<HealthProblem>) -> bool {
+    let exists = Path::new(chipdev).exists();
+    if !exists {
+        problems.push(HealthProblem::ChipMissing {chipdev: chipdev.to_string()});
+    }
+    exists
+}
+
+/// Add `LineFlags` for every line not requested as the device does,
+/// the button lines only if `buttons` are requested. Returns whether
+/// all are.
+fn check_flags(lines: &[LineInfo; 4], buttons: bool, problems: &mut Vec<HealthProblem>) -> bool {
+    let found = problems.len();
+    for line in lines.iter() {
+        let output = match line.role {
+            LineRole::LedRed | LineRole::LedGreen => false,
+            LineRole::ButtonUnlock | LineRole::ButtonLock if buttons => true,
+            LineRole::ButtonUnlock | LineRole::ButtonLock => continue,
+        };
+        if !line.flags.used || line.flags.output != output {
+            problems.push(HealthProblem::LineFlags {role: line.role, flags: line.flags});
+        }
+    }
+    problems.len() == found
+}
+
+/// Health check thread, stopped on drop.
+pub(crate) struct HealthCheck {
+    shared: Arc<Shared>,
+    stop: StopToken,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Start probing as `probe` says.
+pub(crate) fn start(probe: Probe) -> std::io::Result<HealthCheck> {
+    let shared = Arc::new(Shared::default());
+    let stop = StopToken::new();
+    let (thread_shared, thread_stop) = (shared.clone(), stop.clone());
+    let thread = (std::thread::Builder::new()
+        .name("cff3000-health".to_string())
+        .spawn(move || probe.run(&thread_shared, &thread_stop)))?;
+    Ok(HealthCheck {shared, stop, thread: Some(thread)})
+}
+
+impl Probe {
+    fn run(&self, shared: &Shared, stop: &StopToken) {
+        let mut seen = Seen::default();
+        let mut reported: Vec<HealthProblem> = Vec::new();
+        while !stop.wait_timeout(self.options.interval) {
+            let problems = match self.interlock.if_idle(|operations| self.probe(operations, shared, &mut seen)) {
+                Some(problems) => problems,
+                /* the running operation reads the lines itself */
+                None => continue,
+            };
+            *shared.status.lock().unwrap_or_else(|e| e.into_inner()) = Some(HealthStatus {checked: SystemTime::now(), problems: problems.clone()});
+
+            let kinds: Vec<HealthProblem> = problems.iter().map(HealthProblem::kind).collect();
+            if kinds != reported {
+                if let Some(ref monitor) = self.monitor {
+                    match problems.is_empty() {
+                        true => monitor(&Notice::HealthRestored),
+                        false => monitor(&Notice::HealthDegraded {problems}),
+                    }
+                }
+                reported = kinds;
+            }
+        }
+    }
+
+    /// Check everything while no operation runs, see the module
+    /// documentation.
+    fn probe(&self, operations: usize, shared: &Shared, seen: &mut Seen) -> Vec<HealthProblem> {
+        let mut problems = Vec::new();
+        if let Some(ref chipdev) = self.chipdev {
+            check_chip(chipdev, &mut problems);
+        }
+        match self.backend.line_info() {
+            Ok(lines) => {
+                check_flags(&lines, self.buttons, &mut problems);
+                self.check_leds(&lines, operations, shared.events.load(Ordering::Relaxed), seen, &mut problems);
+            },
+            Err(ref err) if err.kind() == std::io::ErrorKind::Unsupported => {},
+            Err(err) => problems.push(HealthProblem::Unreadable {message: err.to_string()}),
+        }
+
+        if let (false, true, Some(reopen)) = (problems.is_empty(), self.options.reopen, self.reopen.as_ref()) {
+            /* reported by the backend as Notice::Reconnected, new lines start over */
+            if reopen.reopen_now().is_ok() {
+                *seen = Seen::default();
+            }
+        }
+        problems
+    }
+
+    fn check_leds(&self, lines: &[LineInfo; 4], operations: usize, events: usize, seen: &mut Seen, problems: &mut Vec<HealthProblem>) {
+        let now = self.clock.now();
+        /* a level changed by an operation or an event read since needs no event now */
+        let quiet = seen.operations == Some(operations) && seen.events == events;
+        let pending = self.backend.wait_for_led_events(Duration::from_millis(0)).unwrap_or(LED_RED | LED_GREEN);
+        for (i, &(role, mask)) in [(LineRole::LedRed, LED_RED), (LineRole::LedGreen, LED_GREEN)].iter().enumerate() {
+            let level = match lines.iter().find(|line| line.role == role) {
+                Some(line) => line.level,
+                None => continue,
+            };
+            if quiet && seen.levels[i].is_some_and(|last| last != level) && pending & mask == 0 {
+                problems.push(HealthProblem::MissedEvent {role});
+            }
+            let lit = level == (self.polarities.get(role) == Polarity::ActiveHigh);
+            seen.levels[i] = Some(level);
+            seen.lit_since[i] = match (lit, seen.lit_since[i]) {
+                (true, Some(since)) if quiet => Some(since),
+                (true, _) => Some(now),
+                (false, _) => None,
+            };
+            if let Some(lit) = seen.lit_since[i].map(|since| now.saturating_duration_since(since)) {
+                if lit >= self.options.stuck_after {
+                    problems.push(HealthProblem::Stuck {role, lit});
+                }
+            }
+        }
+        seen.operations = Some(operations);
+        seen.events = events;
+    }
+}
+
+impl HealthCheck {
+    /// The thread has not ended, e.g. by a panic of the monitor.
+    fn alive(&self) -> bool {
+        self.thread.as_ref().is_some_and(|thread| !thread.is_finished())
+    }
+}
+
+impl Drop for HealthCheck {
+    fn drop(&mut self) {
+        self.stop.stop();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl CFF3000 {
+    /// Result of the last probe of `CFF3000Builder::health_check()`,
+    /// `None` without one or before its first probe.
+    pub fn health(&self) -> Option<HealthStatus> {
+        self.health.as_ref().and_then(|health| health.shared.status.lock().unwrap_or_else(|e| e.into_inner()).clone())
+    }
+
+    /// Check the device now, see the module documentation.
+    ///
+    /// Never presses a button and never waits for a running operation,
+    /// so it is quick enough for liveness probes.
+    pub fn health_check(&self) -> HealthReport {
+        let mut problems = Vec::new();
+        let chip_reachable = self.chipdev.as_ref().map(|chipdev| check_chip(chipdev, &mut problems));
+        let lines_held = match self.backend.line_info() {
+            Ok(lines) => Some(check_flags(&lines, !self.dry_run, &mut problems)),
+            Err(ref err) if err.kind() == std::io::ErrorKind::Unsupported => None,
+            Err(err) => {
+                problems.push(HealthProblem::Unreadable {message: err.to_string()});
+                Some(false)
+            },
+        };
+        let watchdog_alive = self.health.as_ref().map(HealthCheck::alive);
+        if watchdog_alive == Some(false) {
+            problems.push(HealthProblem::WatchdogStopped);
+        }
+        /* the LEDs need two probes in a row, the lines are checked above */
+        if let Some(status) = self.health() {
+            problems.extend(status.problems.into_iter().filter(|problem| matches!(*problem, HealthProblem::Stuck {..} | HealthProblem::MissedEvent {..})));
+        }
+        let last = self.history.find_last(|_| true);
+        if let Some(Err(error)) = last.map(|entry| entry.result) {
+            problems.push(HealthProblem::OperationFailed {error});
+        }
+
+        let now = SystemTime::now();
+        let mut report = HealthReport {
+            verdict: HealthVerdict::Healthy,
+            reasons: Vec::new(),
+            chip_reachable,
+            lines_held,
+            last_state_age: self.history.find_last(|entry| matches!(entry.result, Ok(Some(_))))
+                .map(|entry| now.duration_since(entry.timestamp + entry.duration).unwrap_or_default()),
+            last_error: self.history.find_last(|entry| entry.result.is_err()).and_then(|entry| entry.result.err()),
+            watchdog_alive,
+            queue_depth: None,
+        };
+        for problem in problems {
+            report.add(problem);
+        }
+        report
+    }
+
+    /// Count LED events read or flushed for the health check.
+    pub(crate) fn note_led_events(&self) {
+        if let Some(ref health) = self.health {
+            health.shared.note_events();
+        }
+    }
+}
@@ -0,0 +1,206 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Recent operations of a device, see `CFF3000::history()`.
+//!
+//! Every lock, unlock and check is recorded once it is done, whoever
+//! started it: the library calls, the integrations, the queue and the
+//! watch loop all end up in the same presses and state queries. The
+//! device keeps the last `CFF3000Builder::history()` operations,
+//! `DEFAULT_HISTORY_CAPACITY` by default, the oldest are dropped. A
+//! state query dropped before its capture is complete, e.g. by stopping
+//! the watch loop, is not recorded.
+//!
+//! With the `http` feature the history is served on `/history`, with
+//! `unix-socket` it is answered to `{"cmd":"history"}`, and
+//! `cff3000 history` prints the one of a daemon. Entries are JSON
+//! objects, oldest first:
+//!
+//! ```text
+//! {"timestamp_ms":1760000000123,"command":"lock","state":"locked","duration_ms":10204}
+//! {"timestamp_ms":1760000300456,"command":"check","error":{"code":"no-response","message":"did not receive enough LED change events"},"duration_ms":8012}
+//! ```
+//!
+//! `state` is left out for plain presses like `CFF3000::lock()`, which
+//! do not read the LEDs.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+#[cfg(any(feature = "cli", feature = "config", feature = "http", all(feature = "unix-socket", unix)))]
+use std::time::UNIX_EPOCH;
+
+#[cfg(feature = "config")]
+use serde::ser::SerializeStruct;
+#[cfg(feature = "config")]
+use serde::{Serialize, Serializer};
+
+use crate::codes::ErrorCode;
+#[cfg(any(feature = "cli", feature = "http", all(feature = "unix-socket", unix)))]
+use crate::json::json_string;
+#[cfg(all(feature = "unix-socket", unix))]
+use crate::json::Value;
+use crate::{CFF3000State, Command};
+
+/// Operations kept by a device without `CFF3000Builder::history()`, a
+/// few days of the watch loop's queries.
+pub const DEFAULT_HISTORY_CAPACITY: usize = 1000;
+
+/// Error of a failed operation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "config", derive(Serialize))]
+pub struct HistoryError {
+    /// `error.code` of the `cli` feature, e.g. "no-response"
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// One operation, see the module documentation. Serialized like the
+/// JSON objects there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    /// Start of the operation
+    pub timestamp: SystemTime,
+    pub command: Command,
+    /// State read by the operation, `None` for plain presses
+    pub result: Result<Option<CFF3000State>, HistoryError>,
+    pub duration: Duration,
+}
+
+#[cfg(any(feature = "cli", feature = "http", all(feature = "unix-socket", unix)))]
+pub(crate) fn command_name(command: Command) -> &'static str {
+    match command {
+        Command::Lock => "lock",
+        Command::Unlock => "unlock",
+        Command::Check => "check",
+    }
+}
+
+impl HistoryEntry {
+    /// Entry of `command` started at `timestamp` with `result` after
+    /// `duration`.
+    pub(crate) fn new(timestamp: SystemTime, command: Command, result: Result<Option<CFF3000State>, &std::io::Error>, duration: Duration) -> HistoryEntry {
+        HistoryEntry {
+            timestamp,
+            command,
+            result: result.map_err(|err| HistoryError {code: ErrorCode::of(err).name(), message: err.to_string()}),
+            duration,
+        }
+    }
+
+    /// Append the entry as a JSON object, see the module documentation.
+    #[cfg(any(feature = "cli", feature = "http", all(feature = "unix-socket", unix)))]
+    pub(crate) fn write_json(&self, out: &mut String) {
+        let since_epoch = self.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        out.push_str(&format!("{{\"timestamp_ms\":{},\"command\":", since_epoch.as_millis()));
+        json_string(out, command_name(self.command));
+        match self.result {
+            Ok(Some(state)) => {
+                out.push_str(",\"state\":");
+                json_string(out, state.name());
+            },
+            Ok(None) => {},
+            Err(ref error) => {
+                out.push_str(",\"error\":{\"code\":");
+                json_string(out, error.code);
+                out.push_str(",\"message\":");
+                json_string(out, &error.message);
+                out.push('}');
+            },
+        }
+        out.push_str(&format!(",\"duration_ms\":{}}}", self.duration.as_millis()));
+    }
+
+    /// Entry of a JSON object written by `write_json()`, `None` if it is
+    /// not one. Unknown error codes are taken as "io".
+    #[cfg(all(feature = "unix-socket", unix))]
+    pub(crate) fn from_json(value: &Value) -> Option<HistoryEntry> {
+        let number = |key: &str| match value.get(key) {
+            Some(&Value::Number(n)) if n >= 0.0 => Some(n as u64),
+            _ => None,
+        };
+        let command = value.get("command").and_then(Value::as_str)?;
+        let command = [Command::Lock, Command::Unlock, Command::Check].iter().cloned().find(|&c| command_name(c) == command)?;
+        let result = match (value.get("state"), value.get("error")) {
+            (Some(state), None) => Ok(Some(CFF3000State::from_name(state.as_str()?)?)),
+            (None, Some(error)) => {
+                let code = error.get("code").and_then(Value::as_str)?;
+                Err(HistoryError {
+                    code: ErrorCode::ALL.iter().map(|code| code.name()).find(|&name| name == code).unwrap_or("io"),
+                    message: error.get("message").and_then(Value::as_str)?.to_string(),
+                })
+            },
+            (None, None) => Ok(None),
+            (Some(_), Some(_)) => return None,
+        };
+        Some(HistoryEntry {
+            timestamp: UNIX_EPOCH + Duration::from_millis(number("timestamp_ms")?),
+            command,
+            result,
+            duration: Duration::from_millis(number("duration_ms")?),
+        })
+    }
+}
+
+#[cfg(feature = "config")]
+impl Serialize for HistoryEntry {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let since_epoch = self.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut entry = serializer.serialize_struct("HistoryEntry", 4)?;
+        entry.serialize_field("timestamp_ms", &(since_epoch.as_millis() as u64))?;
+        entry.serialize_field("command", &self.command)?;
+        match self.result {
+            Ok(Some(state)) => entry.serialize_field("state", &state)?,
+            Ok(None) => entry.skip_field("state")?,
+            Err(ref error) => entry.serialize_field("error", error)?,
+        }
+        entry.serialize_field("duration_ms", &(self.duration.as_millis() as u64))?;
+        entry.end()
+    }
+}
+
+/// Append `entries` as a JSON array.
+#[cfg(any(feature = "cli", feature = "http", all(feature = "unix-socket", unix)))]
+pub(crate) fn write_json(out: &mut String, entries: &[HistoryEntry]) {
+    out.push('[');
+    for (i, entry) in entries.iter().enumerate() {
+        if i != 0 {
+            out.push(',');
+        }
+        entry.write_json(out);
+    }
+    out.push(']');
+}
+
+/// Ring buffer of the last operations of a device.
+pub(crate) struct History {
+    capacity: usize,
+    entries: Mutex<VecDeque<HistoryEntry>>,
+}
+
+impl History {
+    pub(crate) fn new(capacity: usize) -> History {
+        History {capacity, entries: Mutex::new(VecDeque::new())}
+    }
+
+    pub(crate) fn record(&self, entry: HistoryEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// All entries, oldest first.
+    pub(crate) fn entries(&self) -> Vec<HistoryEntry> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).iter().cloned().collect()
+    }
+
+    /// The newest entry matching `predicate`.
+    pub(crate) fn find_last<P: Fn(&HistoryEntry) -> bool>(&self, predicate: P) -> Option<HistoryEntry> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).iter().rev().find(|entry| predicate(entry)).cloned()
+    }
+}
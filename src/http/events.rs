@@ -0,0 +1,53 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Server-Sent Events of `GET /events`.
+
+use std::io::Write;
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::RecvTimeoutError;
+
+use crate::StateChange;
+
+use super::Shared;
+
+fn event(change: StateChange) -> String {
+    let mut data = String::new();
+    change.write_json(&mut data);
+    format!("event: state\ndata: {}\n\n", data)
+}
+
+/// Stream the changes published to `shared` to `stream` until the
+/// client goes away or the server is dropped.
+pub(super) fn stream(shared: &Shared, mut stream: TcpStream) {
+    let (changes, last) = {
+        let last = shared.last_change.lock().unwrap_or_else(|e| e.into_inner());
+        (shared.changes.subscribe(), *last)
+    };
+
+    let mut head = String::from("HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n");
+    if let Some(ref origin) = shared.options.allow_origin {
+        head.push_str(&format!("Access-Control-Allow-Origin: {}\r\n", origin));
+    }
+    head.push_str("\r\nretry: 5000\n\n");
+    if let Some(change) = last {
+        head.push_str(&event(change));
+    }
+    if stream.write_all(head.as_bytes()).is_err() {
+        return;
+    }
+
+    /* a client which went away makes a write fail, dropping `changes`
+     * then unsubscribes it */
+    loop {
+        let written = match changes.recv_timeout(shared.options.keep_alive) {
+            Ok(change) => stream.write_all(event(change).as_bytes()),
+            Err(RecvTimeoutError::Timeout) => stream.write_all(b": keep-alive\n\n"),
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if written.is_err() {
+            return;
+        }
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+}
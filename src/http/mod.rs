@@ -0,0 +1,747 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Embedded HTTP status and control server (`http` feature).
+//!
+//! `serve()` starts an [`HttpServer`] answering HTTP/1.1 requests with
+//! JSON bodies:
+//!
+//! | Request | Response |
+//! |---------|----------|
+//! | `GET /state` | `{"state":"locked","cached":false,"age_ms":0}` |
+//! | `POST /lock`, `POST /unlock` | `202 Accepted`, `{"queued":true}` |
+//! | `POST /lock?verify=true`, `POST /unlock?verify=true` | like `/state` |
+//! | `GET /healthz` | `{"verdict":"healthy",...}`, see `health` |
+//! | `GET /events` | Server-Sent Events, see below |
+//! | `GET /history` | `{"history":[...]}`, see `history` |
+//! | `GET /metrics` | Prometheus metrics, see `metrics` (`metrics` feature) |
+//!
+//! The commands go through a `CommandQueue` of the server, so a lock
+//! taking ten seconds never blocks `/healthz` or another client, which
+//! all get their own thread. Without `verify=true`, `/lock` and
+//! `/unlock` answer as soon as the command is queued. A verified
+//! command seeing another state than requested fails with
+//! `not-confirmed`.
+//!
+//! `/state` queries the device, or with `HttpOptions::cache_ttl`
+//! returns the last state seen by any request while it is younger than
+//! that. `/healthz` answers with the report of `CFF3000::health_check()`
+//! and the depth of the server's queue, without pressing a button. It
+//! fails with `503` only for `unhealthy` reports, a `degraded` device
+//! still answers with `200`. `/metrics` is only served for devices
+//! built with `CFF3000Builder::metrics()`.
+//!
+//! Failures answer with an error status and the body
+//! `{"error":{"code":"busy","message":"command queue is full"}}`, the
+//! codes being those of the `cli` module: `busy` (503), `no-response`
+//! (504), `not-confirmed` (502), `invalid-pattern` (502), `io` (500).
+//!
+//! # Events
+//!
+//! `GET /events` streams `text/event-stream` with one `state` event per
+//! change given to `HttpServer::publish()`, e.g. from
+//! `publish_changes()`, starting with the last one:
+//!
+//! ```text
+//! event: state
+//! data: {"state":"locked","previous":"unlocked","trigger":"poll","at_ms":1760000000123}
+//! ```
+//!
+//! A comment is sent every `HttpOptions::keep_alive`, so proxies keep
+//! the connection open and a client which went away is noticed and
+//! unsubscribed. Every client gets the full stream through a
+//! `ChangeBroadcast`. `examples/events.html` shows the state live.
+//!
+//! # Authentication
+//!
+//! With `HttpOptions::token` set, every request except `/healthz` needs
+//! the header `Authorization: Bearer <token>` and is answered with
+//! `401` otherwise. As browsers cannot add the header to an
+//! `EventSource`, `/events` also accepts `?access_token=<token>`. The
+//! server speaks plain HTTP, so keep it on a trusted network or behind
+//! a TLS terminating proxy:
+//!
+//! ```sh
+//! curl -X POST -H 'Authorization: Bearer secret' 'http://door:8080/lock?verify=true'
+//! ```
+//!
+//! # Groups
+//!
+//! `serve_group()` serves the devices of a `CFF3000Group` below their
+//! names, e.g. `GET /garage/state` or `GET /frontdoor/events`, each with
+//! a command queue and `/events` stream of its own, and lists the names
+//! at `GET /devices`:
+//!
+//! ```text
+//! {"devices":["backdoor","frontdoor","garage"]}
+//! ```
+//!
+//! Changes are published with `HttpServer::publish_to()`, e.g. from
+//! `publish_group_changes()`. The token protects every route except the
+//! `/healthz` of the devices.
+//!
+//! # Other locks
+//!
+//! `serve_lock()` serves any `LockControl`, e.g. a door with another
+//! mechanism. It answers `/state`, `/lock`, `/unlock` and `/events`
+//! like above, `/healthz` only with the depth of the queue, and has
+//! neither `/history` nor `/metrics`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::TryRecvError;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::group::CFF3000Group;
+use crate::health::{HealthReport, HealthVerdict};
+use crate::history;
+use crate::json::json_string;
+use crate::query;
+use crate::{AlreadyInUse, ChangeBroadcast, Command, CommandQueue, CommandSender, CFF3000, CFF3000State, LockControl, ParseError, QueueOptions, StateChange,
+     StopToken, WatchOptions};
+
+mod events;
+
+/// Longest accepted request line plus headers.
+const MAX_HEAD: usize = 8192;
+/// Longest request body, which is read and ignored.
+const MAX_BODY: u64 = 8192;
+
+/// Server configuration.
+#[derive(Debug, Clone)]
+pub struct HttpOptions {
+    /// Bearer token required by every request except `/healthz`,
+    /// `None` to accept everybody
+    pub token: Option<String>,
+    /// Answer `/state` from the last seen state while it is younger,
+    /// `None` to query the device every time
+    pub cache_ttl: Option<Duration>,
+    /// Queue of the commands, its depth bounds the requests waiting for
+    /// the device
+    pub queue: QueueOptions,
+    /// Timeout for reading a request and writing the response
+    pub io_timeout: Duration,
+    /// Time between two keep-alive comments of `/events`
+    pub keep_alive: Duration,
+    /// Value of `Access-Control-Allow-Origin`, e.g. for a page served
+    /// from elsewhere, `None` to leave it out
+    pub allow_origin: Option<String>,
+}
+
+impl Default for HttpOptions {
+    fn default() -> HttpOptions {
+        HttpOptions {
+            token: None,
+            cache_ttl: None,
+            queue: QueueOptions::default(),
+            io_timeout: Duration::from_secs(10),
+            keep_alive: Duration::from_secs(15),
+            allow_origin: None,
+        }
+    }
+}
+
+/// Parsed request, only what the router needs.
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    headers: HashMap<String, String>,
+}
+
+/// Response with a JSON body.
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: String,
+}
+
+impl Response {
+    fn json(status: u16, body: String) -> Response {
+        Response {status, content_type: "application/json", headers: Vec::new(), body}
+    }
+
+    fn error(status: u16, code: &str, message: &str) -> Response {
+        let mut body = String::from("{\"error\":{\"code\":");
+        json_string(&mut body, code);
+        body.push_str(",\"message\":");
+        json_string(&mut body, message);
+        body.push_str("}}");
+        Response::json(status, body)
+    }
+
+    fn header(mut self, name: &'static str, value: &str) -> Response {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
+
+/// Status and error code of a failed command, see module documentation.
+fn error_response(err: &Error) -> Response {
+    let inner = err.get_ref();
+    let (status, code) = match query::parse_error(err) {
+        Some(&ParseError::NotEnoughEvents) => (504, "no-response"),
+        Some(_) => (502, "invalid-pattern"),
+        None if inner.is_some_and(|inner| inner.is::<AlreadyInUse>()) => (503, "busy"),
+        None => match err.kind() {
+            ErrorKind::WouldBlock | ErrorKind::ResourceBusy => (503, "busy"),
+            _ => (500, "io"),
+        },
+    };
+    Response::error(status, code, &err.to_string())
+}
+
+/// Decode `%XX` escapes and `+` of a query component.
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = |b: u8| (b as char).to_digit(16);
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                    (Some(high), Some(low)) => {
+                        out.push((high * 16 + low) as u8);
+                        i += 3;
+                        continue;
+                    },
+                    _ => out.push(b'%'),
+                }
+            },
+            b'+' => out.push(b' '),
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Read the request head from `stream` and skip its body.
+fn read_request<R: BufRead>(stream: &mut R) -> Result<Request, Response> {
+    let mut head = Vec::new();
+    loop {
+        let start = head.len();
+        let read = ((&mut *stream).take((MAX_HEAD + 1 - start) as u64).read_until(b'\n', &mut head)
+            .map_err(|err| Response::error(400, "io", &err.to_string())))?;
+        if head.len() > MAX_HEAD {
+            return Err(Response::error(431, "io", "request head too large"));
+        }
+        if read == 0 {
+            return Err(Response::error(400, "io", "incomplete request"));
+        }
+        if head[start..] == b"\r\n"[..] || head[start..] == b"\n"[..] {
+            break;
+        }
+    }
+
+    let head = String::from_utf8_lossy(&head).into_owned();
+    let mut lines = head.lines();
+    let mut parts = lines.next().unwrap_or("").split(' ');
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => (method, target),
+        _ => return Err(Response::error(400, "io", "malformed request line")),
+    };
+    let mut headers = HashMap::new();
+    for line in lines.take_while(|line| !line.is_empty()) {
+        match line.find(':') {
+            Some(colon) => {headers.insert(line[..colon].trim().to_ascii_lowercase(), line[colon + 1..].trim().to_string());},
+            None => return Err(Response::error(400, "io", "malformed header")),
+        }
+    }
+
+    let length = match headers.get("content-length").map(|length| length.parse::<u64>()) {
+        Some(Ok(length)) => length,
+        Some(Err(_)) => return Err(Response::error(400, "io", "malformed Content-Length")),
+        None => 0,
+    };
+    if length > MAX_BODY {
+        return Err(Response::error(413, "io", "request body too large"));
+    }
+    if headers.contains_key("transfer-encoding") {
+        return Err(Response::error(400, "io", "chunked requests are not supported"));
+    }
+    let _ = std::io::copy(&mut (&mut *stream).take(length), &mut std::io::sink());
+
+    let (path, query) = match target.find('?') {
+        Some(mark) => (&target[..mark], &target[mark + 1..]),
+        None => (target, ""),
+    };
+    let query = query.split('&').filter(|pair| !pair.is_empty()).map(|pair| match pair.find('=') {
+        Some(eq) => (percent_decode(&pair[..eq]), percent_decode(&pair[eq + 1..])),
+        None => (percent_decode(pair), String::new()),
+    }).collect();
+    Ok(Request {method: method.to_string(), path: path.to_string(), query, headers})
+}
+
+fn write_response<W: Write>(stream: &mut W, response: &Response) -> std::io::Result<()> {
+    let mut head = format!("HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+                           response.status, reason(response.status), response.content_type, response.body.len() + 1);
+    for &(name, ref value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(response.body.as_bytes())?;
+    stream.write_all(b"\n")?;
+    stream.flush()
+}
+
+/// Compare without stopping at the first difference, so the time taken
+/// does not tell how much of a guessed token is right.
+fn same_token(given: &str, token: &str) -> bool {
+    let (given, token) = (given.as_bytes(), token.as_bytes());
+    let difference = given.iter().zip(token).fold(0u8, |acc, (a, b)| acc | (a ^ b));
+    difference == 0 && given.len() == token.len()
+}
+
+/// Returns true if `request` carries the token of `options`, if any.
+fn authorized(options: &HttpOptions, request: &Request) -> bool {
+    let token = match options.token {
+        Some(ref token) => token,
+        None => return true,
+    };
+    /* EventSource cannot send headers */
+    let query = match request.path.as_str() {
+        "/events" => request.query.get("access_token").map(String::as_str),
+        _ => None,
+    };
+    match request.headers.get("authorization").and_then(|value| value.strip_prefix("Bearer ")).or(query) {
+        Some(given) => same_token(given.trim(), token),
+        None => false,
+    }
+}
+
+fn unauthorized() -> Response {
+    Response::error(401, "permission-denied", "missing or wrong bearer token").header("WWW-Authenticate", "Bearer realm=\"cff3000\"")
+}
+
+struct Shared {
+    /// `None` for other locks, see `serve_lock()`
+    device: Option<Arc<CFF3000>>,
+    sender: CommandSender,
+    options: HttpOptions,
+    /// Last state seen by any request
+    last: Mutex<Option<(CFF3000State, Instant)>>,
+    /// Changes published to the server
+    changes: ChangeBroadcast,
+    /// Last change published, the first event of every stream
+    last_change: Mutex<Option<StateChange>>,
+}
+
+impl Shared {
+    fn remember(&self, state: CFF3000State) {
+        *self.last.lock().unwrap_or_else(|e| e.into_inner()) = Some((state, Instant::now()));
+    }
+
+    /// Send `command` for `initiator` and wait for its result.
+    fn execute(&self, command: Command, initiator: &str) -> std::io::Result<CFF3000State> {
+        let result = self.sender.send_as(command, initiator).recv()
+            .unwrap_or_else(|_| Err(Error::new(ErrorKind::BrokenPipe, "command queue has been shut down")));
+        if let Ok(state) = result {
+            self.remember(state);
+        }
+        result
+    }
+
+    fn state(&self, initiator: &str) -> Response {
+        if let Some(ttl) = self.options.cache_ttl {
+            let last = *self.last.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some((state, at)) = last.filter(|&(_, at)| at.elapsed() < ttl) {
+                return Response::json(200, state_json(state, true, at.elapsed()));
+            }
+        }
+        match self.execute(Command::Check, initiator) {
+            Ok(state) => Response::json(200, state_json(state, false, Duration::from_millis(0))),
+            Err(err) => error_response(&err),
+        }
+    }
+
+    fn command(self: &Arc<Shared>, command: Command, expected: CFF3000State, verify: bool, initiator: &str) -> Response {
+        if !verify {
+            let reply = self.sender.send_as(command, initiator);
+            match reply.try_recv() {
+                /* a full or closed queue */
+                Ok(Err(ref err)) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::BrokenPipe => {
+                    return error_response(err);
+                },
+                Ok(Ok(state)) => self.remember(state),
+                Ok(Err(_)) | Err(TryRecvError::Disconnected) => {},
+                Err(TryRecvError::Empty) => {
+                    let shared = self.clone();
+                    let _ = std::thread::Builder::new().name("cff3000-http-result".to_string()).spawn(move || {
+                        if let Ok(Ok(state)) = reply.recv() {
+                            shared.remember(state);
+                        }
+                    });
+                },
+            }
+            return Response::json(202, "{\"queued\":true}".to_string());
+        }
+        match self.execute(command, initiator) {
+            Ok(state) if state == expected => Response::json(200, state_json(state, false, Duration::from_millis(0))),
+            Ok(state) => Response::error(502, "not-confirmed", &format!("the door is {} instead of {}", state.name(), expected.name())),
+            Err(err) => error_response(&err),
+        }
+    }
+
+    fn health(&self) -> Response {
+        let report = self.device.as_ref().map_or_else(HealthReport::unknown, |device| device.health_check()).with_queue(&self.sender);
+        let mut body = String::new();
+        report.write_json(&mut body);
+        Response::json(if report.verdict == HealthVerdict::Unhealthy {503} else {200}, body)
+    }
+
+    fn history(&self, device: &CFF3000) -> Response {
+        let mut body = String::from("{\"history\":");
+        history::write_json(&mut body, &device.history());
+        body.push('}');
+        Response::json(200, body)
+    }
+
+    #[cfg(feature = "metrics")]
+    fn metrics(&self, device: &CFF3000) -> Response {
+        let body = device.metrics().map_or_else(String::new, |metrics| metrics.encode());
+        Response {content_type: "text/plain; version=0.0.4", ..Response::json(200, body.trim_end().to_string())}
+    }
+
+    /// Response refusing `request`, `None` if it may go ahead.
+    fn refusal(&self, request: &Request) -> Option<Response> {
+        let allowed = match request.path.as_str() {
+            "/state" | "/healthz" | "/events" => "GET",
+            "/history" if self.device.is_some() => "GET",
+            #[cfg(feature = "metrics")]
+            "/metrics" if self.device.as_ref().is_some_and(|device| device.metrics().is_some()) => "GET",
+            "/lock" | "/unlock" => "POST",
+            _ => return Some(Response::error(404, "not-found", "no such resource")),
+        };
+        if request.path != "/healthz" && !authorized(&self.options, request) {
+            return Some(unauthorized());
+        }
+        if request.method != allowed {
+            return Some(Response::error(405, "unsupported", "method not allowed").header("Allow", allowed));
+        }
+        None
+    }
+
+    /// Answer `request` of the client `initiator`, see `audit`.
+    fn route(self: &Arc<Shared>, request: &Request, initiator: &str) -> Response {
+        if let Some(refusal) = self.refusal(request) {
+            return refusal;
+        }
+        let verify = match request.query.get("verify").map(String::as_str) {
+            None | Some("false") | Some("0") => false,
+            Some("true") | Some("1") | Some("") => true,
+            Some(_) => return Response::error(400, "config", "verify must be true or false"),
+        };
+        match request.path.as_str() {
+            "/state" => self.state(initiator),
+            "/lock" => self.command(Command::Lock, CFF3000State::Locked, verify, initiator),
+            "/unlock" => self.command(Command::Unlock, CFF3000State::Unlocked, verify, initiator),
+            "/healthz" => self.health(),
+            path => match self.device {
+                Some(ref device) if path == "/history" => self.history(device),
+                #[cfg(feature = "metrics")]
+                Some(ref device) => self.metrics(device),
+                _ => Response::error(404, "not-found", "no such resource"),
+            },
+        }
+    }
+}
+
+/// Devices of a server, see `serve()` and `serve_group()`.
+struct Routes {
+    options: HttpOptions,
+    /// Device of `serve()`, served at the root
+    single: Option<Arc<Shared>>,
+    /// Devices of `serve_group()` by name
+    devices: BTreeMap<String, Arc<Shared>>,
+}
+
+impl Routes {
+    /// Device answering `request`, whose path becomes the one below the
+    /// device, or the response if it is none.
+    fn resolve(&self, request: &mut Request) -> Result<&Arc<Shared>, Response> {
+        if let Some(ref single) = self.single {
+            return Ok(single);
+        }
+        if request.path == "/devices" {
+            return Err(self.list(request));
+        }
+        let target = match request.path.strip_prefix('/') {
+            Some(target) => target,
+            None => return Err(Response::error(400, "io", "malformed request target")),
+        };
+        let (name, path) = match target.find('/') {
+            Some(slash) => (&target[..slash], target[slash..].to_string()),
+            None => (target, String::new()),
+        };
+        match self.devices.get(name) {
+            Some(shared) => {
+                request.path = path;
+                Ok(shared)
+            },
+            None => Err(Response::error(404, "not-found", "no such resource")),
+        }
+    }
+
+    /// Answer `GET /devices`.
+    fn list(&self, request: &Request) -> Response {
+        if !authorized(&self.options, request) {
+            return unauthorized();
+        }
+        if request.method != "GET" {
+            return Response::error(405, "unsupported", "method not allowed").header("Allow", "GET");
+        }
+        let mut body = String::from("{\"devices\":[");
+        for (i, name) in self.devices.keys().enumerate() {
+            if i != 0 {
+                body.push(',');
+            }
+            json_string(&mut body, name);
+        }
+        body.push_str("]}");
+        Response::json(200, body)
+    }
+
+    fn handle(&self, stream: TcpStream) {
+        let _ = stream.set_read_timeout(Some(self.options.io_timeout));
+        let _ = stream.set_write_timeout(Some(self.options.io_timeout));
+        let mut reader = BufReader::new(match stream.try_clone() {
+            Ok(reader) => reader,
+            Err(_) => return,
+        });
+        let request = read_request(&mut reader).and_then(|mut request| self.resolve(&mut request).map(|shared| (shared, request)));
+        let mut response = match request {
+            Ok((shared, ref request)) if request.path == "/events" => match shared.refusal(request) {
+                Some(refusal) => refusal,
+                None => return events::stream(shared, stream),
+            },
+            Ok((shared, request)) => {
+                let initiator = stream.peer_addr().map_or_else(|_| "http".to_string(), |peer| format!("http:{}", peer));
+                shared.route(&request, &initiator)
+            },
+            Err(response) => response,
+        };
+        if let Some(ref origin) = self.options.allow_origin {
+            response = response.header("Access-Control-Allow-Origin", origin);
+        }
+        let mut stream = stream;
+        let _ = write_response(&mut stream, &response);
+        let _ = stream.shutdown(Shutdown::Write);
+    }
+}
+
+fn state_json(state: CFF3000State, cached: bool, age: Duration) -> String {
+    let mut out = String::from("{\"state\":");
+    json_string(&mut out, state.name());
+    out.push_str(&format!(",\"cached\":{},\"age_ms\":{}}}", cached, age.as_millis()));
+    out
+}
+
+/// Running server, see the module documentation. Dropping it stops
+/// accepting connections and waits for the queued commands.
+pub struct HttpServer {
+    routes: Arc<Routes>,
+    addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    acceptor: Option<JoinHandle<()>>,
+    _queues: Vec<CommandQueue>,
+}
+
+impl HttpServer {
+    /// Address the server is listening on, e.g. to find the port chosen
+    /// for port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Send `change` to every `/events` client and remember its state
+    /// like a state seen by a request. Servers of `serve_group()` ignore
+    /// it, see `publish_to()`.
+    pub fn publish(&self, change: StateChange) {
+        if let Some(ref single) = self.routes.single {
+            single.publish(change);
+        }
+    }
+
+    /// Like `publish()` for the device `name` of `serve_group()`.
+    /// Returns false if there is no such device.
+    pub fn publish_to(&self, name: &str, change: StateChange) -> bool {
+        match self.routes.devices.get(name) {
+            Some(shared) => {
+                shared.publish(change);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Number of connected `/events` clients, including those which
+    /// left since the last change.
+    pub fn event_clients(&self) -> usize {
+        self.routes.single.iter().chain(self.routes.devices.values()).map(|shared| shared.changes.subscribers()).sum()
+    }
+}
+
+impl Shared {
+    fn publish(&self, change: StateChange) {
+        self.remember(change.current);
+        let mut last = self.last_change.lock().unwrap_or_else(|e| e.into_inner());
+        /* under the lock, so a new client never misses or repeats it */
+        *last = Some(change);
+        self.changes.send(change);
+    }
+}
+
+impl Drop for HttpServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        /* ends the event streams */
+        for shared in self.routes.single.iter().chain(self.routes.devices.values()) {
+            shared.changes.close();
+        }
+        /* wake up accept() */
+        let _ = TcpStream::connect_timeout(&self.addr, Duration::from_secs(1));
+        if let Some(acceptor) = self.acceptor.take() {
+            let _ = acceptor.join();
+        }
+    }
+}
+
+/// Serve `cff3000` on `addr` with default options.
+pub fn serve<A: ToSocketAddrs>(cff3000: Arc<CFF3000>, addr: A) -> std::io::Result<HttpServer> {
+    serve_with_options(cff3000, addr, HttpOptions::default())
+}
+
+/// Serve `cff3000` on `addr` with `options`. Fails if the address
+/// cannot be bound.
+///
+/// With the `systemd` feature, a socket passed by systemd is served
+/// instead of `addr`, see `systemd`.
+pub fn serve_with_options<A: ToSocketAddrs>(cff3000: Arc<CFF3000>, addr: A, options: HttpOptions) -> std::io::Result<HttpServer> {
+    let (listener, addr) = bind(addr)?;
+    let (shared, queue) = device(cff3000.clone(), Some(cff3000), &options)?;
+    start(listener, addr, Routes {options, single: Some(shared), devices: BTreeMap::new()}, vec![queue])
+}
+
+/// Serve the devices of `group` on `addr` with `options`, see "Groups".
+/// Every device gets a queue of `options.queue`.
+pub fn serve_group<A: ToSocketAddrs>(group: &CFF3000Group, addr: A, options: HttpOptions) -> std::io::Result<HttpServer> {
+    let (listener, addr) = bind(addr)?;
+    let mut devices = BTreeMap::new();
+    let mut queues = Vec::with_capacity(group.len());
+    for (name, cff3000) in group.iter() {
+        let (shared, queue) = device(cff3000.clone(), Some(cff3000.clone()), &options)?;
+        devices.insert(name.clone(), shared);
+        queues.push(queue);
+    }
+    start(listener, addr, Routes {options, single: None, devices}, queues)
+}
+
+/// Serve `lock` on `addr` with `options`, see "Other locks".
+pub fn serve_lock<L, A>(lock: Arc<L>, addr: A, options: HttpOptions) -> std::io::Result<HttpServer>
+    where L: LockControl + Send + Sync + 'static, A: ToSocketAddrs
+{
+    let (listener, addr) = bind(addr)?;
+    let (shared, queue) = device(lock, None, &options)?;
+    start(listener, addr, Routes {options, single: Some(shared), devices: BTreeMap::new()}, vec![queue])
+}
+
+/// Listener of `addr` or of systemd, and the address clients reach it
+/// at.
+fn bind<A: ToSocketAddrs>(addr: A) -> std::io::Result<(TcpListener, SocketAddr)> {
+    #[cfg(all(feature = "systemd", unix))]
+    let passed = crate::systemd::tcp_listener()?;
+    #[cfg(not(all(feature = "systemd", unix)))]
+    let passed = None;
+    let listener = match passed {
+        Some(listener) => listener,
+        None => TcpListener::bind(addr)?,
+    };
+    let mut addr = listener.local_addr()?;
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr {
+            SocketAddr::V4(_) => std::net::Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => std::net::Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    Ok((listener, addr))
+}
+
+/// State of a served lock, the same `device` if it is one, and its
+/// queue.
+fn device<L>(lock: Arc<L>, device: Option<Arc<CFF3000>>, options: &HttpOptions) -> std::io::Result<(Arc<Shared>, CommandQueue)>
+    where L: LockControl + Send + Sync + 'static
+{
+    let queue = CommandQueue::for_lock(lock, options.queue)?;
+    let shared = Arc::new(Shared {
+        device,
+        sender: queue.sender(),
+        options: options.clone(),
+        last: Mutex::new(None),
+        changes: ChangeBroadcast::new(),
+        last_change: Mutex::new(None),
+    });
+    Ok((shared, queue))
+}
+
+fn start(listener: TcpListener, addr: SocketAddr, routes: Routes, queues: Vec<CommandQueue>) -> std::io::Result<HttpServer> {
+    let routes = Arc::new(routes);
+    let stopped = Arc::new(AtomicBool::new(false));
+
+    let (stop, server) = (stopped.clone(), routes.clone());
+    let acceptor = std::thread::Builder::new().name("cff3000-http".to_string()).spawn(move || {
+        for stream in listener.incoming() {
+            if stop.load(Ordering::SeqCst) {
+                return;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let routes = routes.clone();
+            /* not joined, a request ends with its command or io_timeout */
+            let _ = std::thread::Builder::new().name("cff3000-http-request".to_string()).spawn(move || routes.handle(stream));
+        }
+    })?;
+    Ok(HttpServer {routes: server, addr, stopped, acceptor: Some(acceptor), _queues: queues})
+}
+
+/// Watch `cff3000` like `CFF3000::watch_with_options()` and publish
+/// every state change to `server` until `stop` is stopped.
+pub fn publish_changes(cff3000: &CFF3000, options: &WatchOptions, stop: &StopToken, server: &HttpServer) -> std::io::Result<()> {
+    cff3000.watch_with_options(options, stop, |change| server.publish(change))
+}
+
+/// Watch the devices of `group` like `CFF3000Group::watch_all()` and
+/// publish every change to the device of `server` with its name until
+/// `stop` is stopped.
+pub fn publish_group_changes(group: &CFF3000Group, options: &WatchOptions, stop: &StopToken, server: &HttpServer) -> std::io::Result<()> {
+    group.watch_all(options, stop, |name, change| {
+        server.publish_to(name, change);
+    })
+}
@@ -0,0 +1,79 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! LED event logs written by other tools or by `export`.
+//!
+//! [`from_csv()`] reads the CSV format of `export::to_csv()`, e.g. after
+//! editing a capture in a spreadsheet. Rows are checked in order and
+//! the first invalid one is reported with its line number, the header
+//! being line 1.
+
+use std::io::{BufRead, Error, ErrorKind};
+use std::time::Duration;
+
+use crate::export::CSV_HEADER;
+use crate::{Led, LedEvent};
+
+fn invalid(line: usize, msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("line {}: {}", line, msg))
+}
+
+/// Returns true if `text` starts with the header of `export::to_csv()`.
+pub fn is_csv(text: &str) -> bool {
+    text.lines().map(str::trim).find(|line| !line.is_empty()) == Some(CSV_HEADER)
+}
+
+/// Read the events of a CSV file, see the module documentation.
+///
+/// Timestamps are microseconds and must not decrease from row to row,
+/// `led` is `red` or `green` and `state` is `on` or `off`. Empty lines
+/// are ignored.
+pub fn from_csv<R: BufRead>(r: R) -> std::io::Result<Vec<LedEvent>> {
+    let mut events: Vec<LedEvent> = Vec::new();
+    let mut header = false;
+
+    for (i, line) in r.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        let n = i + 1;
+        if line.is_empty() {
+            continue;
+        }
+        if !header {
+            if line != CSV_HEADER {
+                return Err(invalid(n, &format!("expected column header \"{}\"", CSV_HEADER)));
+            }
+            header = true;
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+        let (micros, led, on) = match *fields.as_slice() {
+            [micros, led, on] => (micros, led, on),
+            _ => return Err(invalid(n, "expected 3 columns")),
+        };
+        let micros: u64 = micros.parse().map_err(|_| invalid(n, "invalid timestamp"))?;
+        let timestamp = Duration::from_micros(micros);
+        let led = match led {
+            "red" => Led::Red,
+            "green" => Led::Green,
+            _ => return Err(invalid(n, &format!("unknown LED \"{}\"", led))),
+        };
+        let on = match on {
+            "on" => true,
+            "off" => false,
+            _ => return Err(invalid(n, &format!("unknown state \"{}\", expected on or off", on))),
+        };
+        if let Some(last) = events.last() {
+            if timestamp < last.timestamp {
+                return Err(invalid(n, &format!("timestamp {} before the one of the previous row", micros)));
+            }
+        }
+        events.push(LedEvent {led, on, timestamp});
+    }
+
+    match header {
+        true => Ok(events),
+        false => Err(Error::new(ErrorKind::InvalidData, "empty file, expected a column header")),
+    }
+}
@@ -0,0 +1,131 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Mutual exclusion of GPIO operations on one device.
+//!
+//! Button sequences and LED captures of one `CFF3000` must never
+//! overlap, otherwise presses get merged and captures steal each
+//! other's events. Every public operation holds an [`OperationGuard`]
+//! until its lines are released again.
+//!
+//! The devices of a `CFF3000Group` also share a [`Radio`]: their
+//! remotes interfere when sending at the same time, so an operation of
+//! one waits for those of the others and a gap after them.
+
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "config")]
+use serde::{Deserialize, Serialize};
+
+/// Behavior of an operation started while another one is running.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "config", derive(Serialize, Deserialize), serde(rename_all = "kebab-case"))]
+pub enum BusyPolicy {
+    /// Block until the running operation has finished.
+    #[default]
+    Wait,
+    /// Fail immediately with `ErrorKind::WouldBlock`.
+    FailFast,
+}
+
+pub(crate) struct Interlock {
+    policy: BusyPolicy,
+    busy: Mutex<bool>,
+    idle: Condvar,
+    /// Number of operations started, changed with `busy` locked
+    operations: AtomicUsize,
+    radio: Option<Arc<Radio>>,
+}
+
+impl Interlock {
+    pub(crate) fn new(policy: BusyPolicy, radio: Option<Arc<Radio>>) -> Arc<Interlock> {
+        Arc::new(Interlock {policy, busy: Mutex::new(false), idle: Condvar::new(), operations: AtomicUsize::new(0), radio})
+    }
+
+    /// Start an operation according to the busy policy.
+    pub(crate) fn acquire(this: &Arc<Interlock>) -> std::io::Result<OperationGuard> {
+        let mut busy = this.busy.lock().unwrap_or_else(|e| e.into_inner());
+
+        while *busy {
+            if this.policy == BusyPolicy::FailFast {
+                return Err(Error::new(ErrorKind::WouldBlock, "CFF3000 is busy with another operation"));
+            }
+            busy = this.idle.wait(busy).unwrap_or_else(|e| e.into_inner());
+        }
+
+        *busy = true;
+        this.operations.fetch_add(1, Ordering::Relaxed);
+        /* released before `busy` by dropping the guard */
+        let held = Held(this.clone());
+        if let Some(ref radio) = this.radio {
+            drop(busy);
+            radio.acquire();
+        }
+        Ok(OperationGuard(Arc::new(held)))
+    }
+
+    /// Run `f` with the number of operations started so far if no
+    /// operation is running, `None` otherwise. No operation starts
+    /// while `f` runs.
+    pub(crate) fn if_idle<T, F: FnOnce(usize) -> T>(&self, f: F) -> Option<T> {
+        let busy = self.busy.lock().unwrap_or_else(|e| e.into_inner());
+        match *busy {
+            true => None,
+            false => Some(f(self.operations.load(Ordering::Relaxed))),
+        }
+    }
+}
+
+/// Running operation, which ends once all clones are dropped.
+#[derive(Clone)]
+pub(crate) struct OperationGuard(#[allow(dead_code)] Arc<Held>);
+
+pub(crate) struct Held(Arc<Interlock>);
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        if let Some(ref radio) = self.0.radio {
+            radio.release();
+        }
+        *self.0.busy.lock().unwrap_or_else(|e| e.into_inner()) = false;
+        self.0.idle.notify_one();
+    }
+}
+
+/// Operations of the devices sharing a radio, one at a time and
+/// `spacing` apart.
+pub(crate) struct Radio {
+    spacing: Duration,
+    /// Whether an operation is running, and the end of the last one
+    state: Mutex<(bool, Option<Instant>)>,
+    idle: Condvar,
+}
+
+impl Radio {
+    pub(crate) fn new(spacing: Duration) -> Arc<Radio> {
+        Arc::new(Radio {spacing, state: Mutex::new((false, None)), idle: Condvar::new()})
+    }
+
+    /// Wait for the running operation and the spacing after it. Always
+    /// waits, the busy policy is about the operations of one device.
+    fn acquire(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            let wait = state.1.map_or(Duration::from_millis(0), |end| (end + self.spacing).saturating_duration_since(Instant::now()));
+            match *state {
+                (false, _) if wait == Duration::from_millis(0) => break,
+                (false, _) => state = self.idle.wait_timeout(state, wait).unwrap_or_else(|e| e.into_inner()).0,
+                (true, _) => state = self.idle.wait(state).unwrap_or_else(|e| e.into_inner()),
+            }
+        }
+        state.0 = true;
+    }
+
+    fn release(&self) {
+        *self.state.lock().unwrap_or_else(|e| e.into_inner()) = (false, Some(Instant::now()));
+        self.idle.notify_all();
+    }
+}
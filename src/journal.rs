@@ -0,0 +1,27 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Native protocol of the systemd journal, used by `audit` and
+//! `journald`.
+
+/// Socket of the native journal protocol.
+pub(crate) const SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Datagram with `fields`: `KEY=value` lines, with values containing a
+/// newline sent as the key, a newline, their length as 64 bit little
+/// endian number, the value and a newline.
+pub(crate) fn encode(fields: &[(&str, String)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for &(key, ref value) in fields {
+        out.extend_from_slice(key.as_bytes());
+        if value.contains('\n') {
+            out.push(b'\n');
+            out.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            out.push(b'=');
+        }
+        out.extend_from_slice(value.as_bytes());
+        out.push(b'\n');
+    }
+    out
+}
@@ -0,0 +1,133 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Structured journal entries for the events of the device (`journald`
+//! feature).
+//!
+//! A [`Journal`] registered with `CFF3000Builder::journal()` gets an
+//! entry for every significant event of the device, sent over the
+//! native protocol of the systemd journal to `DEFAULT_SOCKET`:
+//!
+//! | Event | `CFF3000_EVENT` | `PRIORITY` | |
+//! |-------|-----------------|------------|-|
+//! | buttons pressed | `command` | 6 (info) | by every press and state query |
+//! | state classified | `state` | 6 (info) | with `CFF3000_STATE` |
+//! | failure | `failure` | 4 (warning) | with `CFF3000_ERROR_CODE` |
+//!
+//! A `lock_and_verify()` thus sends `command` and then `state` or
+//! `failure`, a refused operation, e.g. one finding the device busy,
+//! only `failure`. Every entry also has
+//!
+//! | Field | |
+//! |-------|-|
+//! | `MESSAGE` | e.g. "check: out-of-range" or "unlock failed: did not receive enough LED change events" |
+//! | `SYSLOG_IDENTIFIER` | `cff3000` |
+//! | `CFF3000_COMMAND` | `lock`, `unlock` or `check` |
+//!
+//! `CFF3000_STATE` is the state name and `CFF3000_ERROR_CODE` the
+//! `error.code` of the `cli` feature, so e.g.
+//!
+//! ```sh
+//! journalctl -t cff3000 CFF3000_STATE=out-of-range
+//! journalctl -t cff3000 CFF3000_ERROR_CODE=no-response
+//! ```
+//!
+//! list the times the transmitter was out of range or did not answer.
+//! `cff3000` registers a `Journal` when started by systemd with its
+//! output going to the journal.
+//!
+//! Unlike the `audit` log, the entries are diagnostics: sending never
+//! blocks, and entries which cannot be sent, e.g. in a container
+//! without a journal socket, are dropped silently and only counted by
+//! `Journal::dropped()`.
+
+use std::io::Error;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::codes::ErrorCode;
+use crate::journal;
+use crate::{CFF3000State, Command};
+
+/// Socket of the native journal protocol.
+pub const DEFAULT_SOCKET: &str = journal::SOCKET;
+
+fn command_name(command: Command) -> &'static str {
+    match command {
+        Command::Lock => "lock",
+        Command::Unlock => "unlock",
+        Command::Check => "check",
+    }
+}
+
+/// Event of the device, see the module documentation.
+#[derive(Debug)]
+pub enum JournalEvent<'a> {
+    /// The buttons of the command have been pressed
+    Command(Command),
+    /// A state query of the command classified the LED pattern
+    State(Command, CFF3000State),
+    /// A press or state query of the command failed
+    Failure(Command, &'a Error),
+}
+
+impl<'a> JournalEvent<'a> {
+    /// Journal fields in sending order, see the module documentation.
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        let (event, command, priority, message) = match *self {
+            JournalEvent::Command(command) => ("command", command, 6, format!("{}: buttons pressed", command_name(command))),
+            JournalEvent::State(command, state) => ("state", command, 6, format!("{}: {}", command_name(command), state.name())),
+            JournalEvent::Failure(command, err) => ("failure", command, 4, format!("{} failed: {}", command_name(command), err)),
+        };
+        let mut fields = vec![
+            ("MESSAGE", message),
+            ("PRIORITY", priority.to_string()),
+            ("SYSLOG_IDENTIFIER", "cff3000".to_string()),
+            ("CFF3000_EVENT", event.to_string()),
+            ("CFF3000_COMMAND", command_name(command).to_string()),
+        ];
+        match *self {
+            JournalEvent::Command(_) => {},
+            JournalEvent::State(_, state) => fields.push(("CFF3000_STATE", state.name().to_string())),
+            JournalEvent::Failure(_, err) => fields.push(("CFF3000_ERROR_CODE", ErrorCode::of(err).name().to_string())),
+        }
+        fields
+    }
+}
+
+/// Sender of `JournalEvent`s to the journal, see the module
+/// documentation.
+#[derive(Debug)]
+pub struct Journal {
+    socket: UnixDatagram,
+    path: PathBuf,
+    dropped: AtomicU64,
+}
+
+impl Journal {
+    /// Send to `DEFAULT_SOCKET`.
+    pub fn new() -> std::io::Result<Journal> {
+        Journal::with_socket(DEFAULT_SOCKET)
+    }
+
+    /// Send to the datagram socket at `path`. Fails only if the socket
+    /// cannot be created, a missing listener only drops the entries.
+    pub fn with_socket<P: AsRef<Path>>(path: P) -> std::io::Result<Journal> {
+        let socket = UnixDatagram::unbound()?;
+        socket.set_nonblocking(true)?;
+        Ok(Journal {socket, path: path.as_ref().to_path_buf(), dropped: AtomicU64::new(0)})
+    }
+
+    /// Send the entry of `event`, without waiting.
+    pub fn send(&self, event: &JournalEvent) {
+        if self.socket.send_to(&journal::encode(&event.fields()), &self.path).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of entries which could not be sent so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
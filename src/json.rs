@@ -0,0 +1,226 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! JSON helpers shared by the `cli`, `http`, `mqtt`, `unix-socket`
+//! and `webhook` features: output for all of them, a small parser for
+//! the requests of `unix-socket` and `cli::rpc`.
+
+use std::fmt::Write;
+
+/// Append `text` as a JSON string.
+pub(crate) fn json_string(out: &mut String, text: &str) {
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            },
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Append `value` as JSON, integral numbers without a fraction.
+#[cfg(feature = "cli")]
+pub(crate) fn json_value(out: &mut String, value: &Value) {
+    match *value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if b {"true"} else {"false"}),
+        Value::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => {
+            let _ = write!(out, "{}", n as i64);
+        },
+        Value::Number(n) if n.is_finite() => {
+            let _ = write!(out, "{}", n);
+        },
+        Value::Number(_) => out.push_str("null"),
+        Value::String(ref text) => json_string(out, text),
+        Value::Array(ref items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                json_value(out, item);
+            }
+            out.push(']');
+        },
+        Value::Object(ref members) => {
+            out.push('{');
+            for (i, (name, member)) in members.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                json_string(out, name);
+                out.push(':');
+                json_value(out, member);
+            }
+            out.push('}');
+        },
+    }
+}
+
+/// Parsed JSON value.
+#[cfg(any(feature = "cli", all(feature = "unix-socket", unix)))]
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+#[cfg(any(feature = "cli", all(feature = "unix-socket", unix)))]
+impl Value {
+    /// Member `key` of an object.
+    pub(crate) fn get(&self, key: &str) -> Option<&Value> {
+        match *self {
+            Value::Object(ref members) => members.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::String(ref text) => Some(text),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(any(feature = "cli", all(feature = "unix-socket", unix)))]
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+#[cfg(any(feature = "cli", all(feature = "unix-socket", unix)))]
+impl<'a> Parser<'a> {
+    fn skip_space(&mut self) {
+        while self.pos < self.text.len() && (self.text[self.pos] as char).is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, byte: u8) -> Option<()> {
+        self.skip_space();
+        match self.text.get(self.pos) {
+            Some(&b) if b == byte => {
+                self.pos += 1;
+                Some(())
+            },
+            _ => None,
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> Option<Value> {
+        match self.text[self.pos..].starts_with(word.as_bytes()) {
+            true => {
+                self.pos += word.len();
+                Some(value)
+            },
+            false => None,
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.eat(b'"')?;
+        let mut out = Vec::new();
+        loop {
+            let byte = *self.text.get(self.pos)?;
+            self.pos += 1;
+            match byte {
+                b'"' => return String::from_utf8(out).ok(),
+                b'\\' => {
+                    let escape = *self.text.get(self.pos)?;
+                    self.pos += 1;
+                    let c = match escape {
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'u' => {
+                            let hex = self.text.get(self.pos..self.pos + 4)?;
+                            self.pos += 4;
+                            let code = std::str::from_utf8(hex).ok().and_then(|hex| u32::from_str_radix(hex, 16).ok())?;
+                            std::char::from_u32(code)?
+                        },
+                        b => b as char,
+                    };
+                    let mut buf = [0; 4];
+                    out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                },
+                b => out.push(b),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Option<Value> {
+        self.skip_space();
+        match *self.text.get(self.pos)? {
+            b'{' => self.object(),
+            b'[' => self.array(),
+            b'"' => self.string().map(Value::String),
+            b't' => self.literal("true", Value::Bool(true)),
+            b'f' => self.literal("false", Value::Bool(false)),
+            b'n' => self.literal("null", Value::Null),
+            _ => {
+                let start = self.pos;
+                while self.text.get(self.pos).is_some_and(|&b| b == b'-' || b == b'+' || b == b'.' || b == b'e' || b == b'E' || b.is_ascii_digit()) {
+                    self.pos += 1;
+                }
+                std::str::from_utf8(&self.text[start..self.pos]).ok().and_then(|number| number.parse().ok()).map(Value::Number)
+            },
+        }
+    }
+
+    fn array(&mut self) -> Option<Value> {
+        self.eat(b'[')?;
+        let mut items = Vec::new();
+        if self.eat(b']').is_some() {
+            return Some(Value::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            if self.eat(b']').is_some() {
+                return Some(Value::Array(items));
+            }
+            self.eat(b',')?;
+        }
+    }
+
+    fn object(&mut self) -> Option<Value> {
+        self.eat(b'{')?;
+        let mut members = Vec::new();
+        if self.eat(b'}').is_some() {
+            return Some(Value::Object(members));
+        }
+        loop {
+            self.skip_space();
+            let name = self.string()?;
+            self.eat(b':')?;
+            members.push((name, self.value()?));
+            if self.eat(b'}').is_some() {
+                return Some(Value::Object(members));
+            }
+            self.eat(b',')?;
+        }
+    }
+}
+
+/// Parse `text` as one JSON value, `None` if it is not valid JSON.
+#[cfg(any(feature = "cli", all(feature = "unix-socket", unix)))]
+pub(crate) fn parse(text: &str) -> Option<Value> {
+    let mut parser = Parser {text: text.as_bytes(), pos: 0};
+    let value = parser.value();
+    parser.skip_space();
+    value.filter(|_| parser.pos == parser.text.len())
+}
@@ -8,72 +8,226 @@
 //! CFF3000 remote control.
 //!
 //! # Example
-//! ```
-//! extern crate cff3000;
-//! use cff3000::CFF3000;
+//! ```no_run
+//! use cff3000::{timings, CFF3000};
 //! use std::io::{Error,ErrorKind};
-//! 
+//!
 //! fn execute(cmd: &str) -> std::io::Result<()> {
-//!     let cff3000 = try!(CFF3000::new("/dev/gpiochip2", [2,3,4,5]));
-//!     let duration: u8;
-//! 
+//!     let cff3000 = CFF3000::new("/dev/gpiochip2", [2,3,4,5])?;
+//!     let duration;
+//!
 //!     match cmd {
-//!         "lock" => {try!(cff3000.lock()); duration = 10;},
-//!         "unlock" => {try!(cff3000.unlock()); duration = 10;},
-//!         "check" => {try!(cff3000.check()); duration = 8;},
+//!         "lock" => {cff3000.lock()?; duration = timings::SUGGESTED_FEEDBACK_DISPLAY;},
+//!         "unlock" => {cff3000.unlock()?; duration = timings::SUGGESTED_FEEDBACK_DISPLAY;},
+//!         "check" => {cff3000.check()?; duration = timings::SUGGESTED_CHECK_FEEDBACK_DISPLAY;},
 //!         _ => return Err(Error::new(ErrorKind::Other, "unsupported command")),
 //!     }
-//! 
-//!     try!(cff3000.show_leds(duration));
+//!
+//!     cff3000.show_leds(duration.as_secs() as u8)?;
 //!     Ok(())
 //! }
-//! 
+//!
 //! fn main() {
 //!     let args: Vec<String> = std::env::args().collect();
-//! 
+//!
 //!     if args.len() < 2 {
 //!         println!("missing parameter: lock, unlock, check");
 //!         std::process::exit(1)
 //!     }
-//! 
+//!
 //!     match execute(args[1].as_str()) {
 //!         Err(err) => println!("{}", err.to_string()),
 //!         Ok(()) => {},
 //!     }
 //! }
 //! ```
+//!
+//! # Panics
+//!
+//! The public API does not panic on any input or hardware behavior.
+//! Invalid arguments and configurations, LED patterns which cannot be
+//! interpreted, malformed frames of remote agents and durations too
+//! large to add to an `Instant` are returned as `std::io::Error` or
+//! reported as issues. The parser, which sees whatever the LEDs do, is
+//! built with Clippy denying indexing, `unwrap()` and `panic!()` and is
+//! fuzzed by `fuzz/fuzz_targets/parse.rs`. Panics of callbacks passed
+//! in, e.g. to `CFF3000Builder::monitor()`, are not caught.
+
+/// LED pattern interpretation (`no_std`), see the `cff3000-parser` crate.
+pub use cff3000_parser as parser;
 
-extern crate gpiochip as gpio;
 use std::io::Write;
-
+use std::sync::Arc;
+
+pub mod activity;
+#[cfg(all(feature = "audit", unix))]
+pub mod audit;
+mod backend;
+pub mod battery;
+mod builder;
+mod cache;
+#[cfg(feature = "cli")]
+pub mod cli;
+mod clock;
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+mod codes;
+#[cfg(feature = "config")]
+pub mod config;
+mod control;
+#[cfg(feature = "daemon")]
+pub mod daemon;
+#[cfg(feature = "dbus")]
+pub mod dbus;
+#[cfg(all(feature = "inotify", target_os = "linux"))]
+mod devwatch;
+pub mod discover;
+pub mod door;
+pub mod eventdump;
+pub mod export;
+#[cfg(feature = "embedded-hal")]
+pub mod hal;
+pub mod history;
+pub mod import;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod group;
+pub mod health;
+#[cfg(feature = "http")]
+pub mod http;
+mod interlock;
+#[cfg(all(any(feature = "audit", feature = "journald"), unix))]
+mod journal;
+#[cfg(all(feature = "journald", unix))]
+pub mod journald;
+#[cfg(any(feature = "cli", feature = "http", feature = "mqtt", all(feature = "unix-socket", unix), feature = "webhook"))]
+mod json;
+mod lockfile;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod mock;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+mod notice;
+#[cfg(feature = "daemon")]
+pub mod persist;
+#[cfg(all(feature = "sysfs", target_os = "linux"))]
+pub mod sysfs;
+mod press;
+#[cfg(feature = "python")]
+pub mod python;
+mod query;
+mod queue;
+#[cfg(feature = "remote")]
+pub mod remote;
+#[cfg(all(feature = "rppal", target_os = "linux"))]
+pub mod rpi;
+pub mod schedule;
+pub mod schema;
+mod selftest;
+#[cfg(any(feature = "cli", feature = "daemon"))]
+mod signals;
+#[cfg(all(feature = "unix-socket", unix))]
+pub mod socket;
+pub mod spans;
+#[cfg(all(feature = "systemd", unix))]
+pub mod systemd;
+pub mod telemetry;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod timings;
+pub mod wallclock;
+mod watch;
+#[cfg(feature = "webhook")]
+pub mod webhook;
+
+pub use backend::{Button, EventBuffer, GpioBackend, GpiochipBackend, Led, LedEvent, LineInfo, LineRole, PinAssignment, Polarities, Polarity, LED_GREEN, LED_RED, MAX_EVENTS};
+#[cfg(all(feature = "uapi-v2", target_os = "linux"))]
+pub use backend::Uapi2Backend;
+pub use builder::CFF3000Builder;
+pub use cache::CachedState;
+pub use clock::{Clock, SharedClock, SystemClock};
+pub use control::LockControl;
+pub use group::{CFF3000Group, GroupOptions};
+pub use interlock::BusyPolicy;
+pub use lockfile::AlreadyInUse;
+pub use notice::Notice;
+pub use parser::{CFF3000State, ParseError, ParseOptions};
+pub use parser::parse as parse_led_events;
+pub use press::PressGuard;
+pub use query::{Capture, CaptureError, StateQuery, StateReport};
+pub use queue::{Command, CommandQueue, CommandSender, QueueOptions, Shutdown};
+pub use selftest::{SelfTestItem, SelfTestReport, SelfTestResult};
+pub use timings::{DeviceProfile, TimingProfile, Timings};
+pub use wallclock::{WallAnchor, WallTime};
+pub use watch::{ChangeBroadcast, StateChange, StopToken, Trigger, WatchOptions};
+
+/// GPIO connected CFF3000.
+///
+/// All operations take `&self`, so a device can be shared between
+/// threads. Operations never overlap: an operation started while
+/// another one is running waits for it or fails, depending on the
+/// `BusyPolicy` selected with `CFF3000Builder`.
 pub struct CFF3000 {
-    red: gpio::GpioEventHandle,
-    green: gpio::GpioEventHandle,
-    unlock: gpio::GpioHandle,
-    lock: gpio::GpioHandle,
+    backend: Arc<dyn GpioBackend>,
+    /// Device node of `new()`, `None` for custom backends
+    chipdev: Option<String>,
+    /// Name in the spans, see `CFF3000Builder::label()`
+    label: Option<String>,
+    interlock: Arc<interlock::Interlock>,
+    parse_options: ParseOptions,
+    timings: Timings,
+    /// Of the lines, whose `LineInfo::level` stays electrical
+    polarities: Polarities,
+    dry_run: bool,
+    clock: SharedClock,
+    cache: Option<cache::StateCache>,
+    history: history::History,
+    battery: Arc<battery::BatteryCounter>,
+    door: Option<door::DoorSensor>,
+    monitor: Option<notice::Monitor>,
+    health: Option<health::HealthCheck>,
+    telemetry: telemetry::Hooks,
+    /// Events logged at each end of a capture, see `eventdump`
+    event_dump: usize,
+    #[cfg(feature = "metrics")]
+    metrics: Option<Arc<metrics::Metrics>>,
+    #[cfg(all(feature = "audit", unix))]
+    audit: Option<Arc<audit::AuditLog>>,
+    #[cfg(all(feature = "journald", unix))]
+    journal: Option<Arc<journald::Journal>>,
+    _lockfile: Option<lockfile::LockFile>,
+    #[cfg(all(feature = "inotify", target_os = "linux"))]
+    _device_watch: Option<devwatch::DeviceWatch>,
 }
 
-#[derive(Debug, Copy, Clone)]
-struct Event {
-    /// led (0 = red, 1 = green)
-    mask: u8,
-    /// timestamp (in ms)
-    timestamp: u64,
-    /// enabled = HIGH, otherwise LOW
-    state: u8,
+/// Button(s) pressed by an operation.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Buttons {
+    Lock,
+    Unlock,
+    Both,
 }
 
-#[derive(Debug)]
-#[derive(PartialEq)]
-pub enum CFF3000State {
-    /// The door is locked (green LED on)
-    Locked,
-    /// The door is unlocked (red LED on)
-    Unlocked,
-    /// The door state has been changed manually (both LEDs blink synchronously)
-    Manual,
-    /// The CFA3000 is out of range (both LEDs blink alternating)
-    OutOfRange,
+impl Buttons {
+    /// Button(s) pressed by `command`.
+    fn of(command: Command) -> Buttons {
+        match command {
+            Command::Lock => Buttons::Lock,
+            Command::Unlock => Buttons::Unlock,
+            Command::Check => Buttons::Both,
+        }
+    }
+
+    /// Operation selecting the per-operation timings.
+    fn command(self) -> Command {
+        match self {
+            Buttons::Lock => Command::Lock,
+            Buttons::Unlock => Command::Unlock,
+            Buttons::Both => Command::Check,
+        }
+    }
 }
 
 impl CFF3000 {
@@ -83,14 +237,39 @@ impl CFF3000 {
     /// device. `chipdev` should be something like "/dev/gpiochip0"
     /// and `gpios` should be an array containing the line offsets
     /// for LED red, LED green, button unlock and button lock (in
-    /// this order)
+    /// this order). `new_with_pins()` names them instead.
     pub fn new(chipdev: &str, gpios: [u32; 4]) -> std::io::Result<CFF3000> {
-        let chip = try!(gpio::GpioChip::new(chipdev));
-        let led_red = try!(chip.request_event("led-red", gpios[0], gpio::RequestFlags::INPUT, gpio::EventRequestFlags::BOTH_EDGES));
-        let led_green = try!(chip.request_event("led-green", gpios[1], gpio::RequestFlags::INPUT, gpio::EventRequestFlags::BOTH_EDGES));
-        let button_unlock = try!(chip.request("button-unlock", gpio::RequestFlags::OUTPUT, gpios[2], 0));
-        let button_lock = try!(chip.request("button-lock", gpio::RequestFlags::OUTPUT, gpios[3], 0));
-        Ok(CFF3000 {red: led_red, green: led_green, unlock: button_unlock, lock: button_lock})
+        CFF3000::new_with_pins(chipdev, PinAssignment::from(gpios))
+    }
+
+    /// Create new CFF3000 device using the lines `pins` of `chipdev`,
+    /// see `new()`.
+    ///
+    /// # Example
+    /// ```no_run
+    /// extern crate cff3000;
+    /// use cff3000::{CFF3000, PinAssignment};
+    ///
+    /// fn main() {
+    ///     let pins = PinAssignment {led_red: 2, led_green: 3, button_unlock: 4, button_lock: 5};
+    ///     let cff3000 = CFF3000::new_with_pins("/dev/gpiochip2", pins).unwrap();
+    ///     cff3000.lock().unwrap();
+    /// }
+    /// ```
+    pub fn new_with_pins(chipdev: &str, pins: PinAssignment) -> std::io::Result<CFF3000> {
+        CFF3000Builder::with_pins(chipdev, pins).build()
+    }
+
+    /// Create new CFF3000 device using a custom GPIO backend.
+    ///
+    /// Use `CFF3000Builder::with_backend()` for non-default settings.
+    pub fn with_backend<B: GpioBackend + 'static>(backend: B) -> std::io::Result<CFF3000> {
+        CFF3000Builder::with_backend(backend).build()
+    }
+
+    /// Start an operation, see `BusyPolicy`.
+    fn acquire(&self) -> std::io::Result<interlock::OperationGuard> {
+        interlock::Interlock::acquire(&self.interlock)
     }
 
     fn print_leds(red: bool, green: bool) -> std::io::Result<()> {
@@ -103,7 +282,7 @@ impl CFF3000 {
         } else {
             print!("\r\x1b[31m ◯ \x1b[32m◯ \x1b[0m ")
         }
-        try!(std::io::stdout().flush());
+        std::io::stdout().flush()?;
         Ok(())
     }
 
@@ -113,171 +292,358 @@ impl CFF3000 {
     /// UTF-8 symbols. The output will be refreshed for `duration` seconds
     /// using the rollback character.
     pub fn show_leds(&self, duration: u8) -> std::io::Result<()> {
+        let _busy = self.acquire()?;
         let mut r = false;
         let mut g = false;
-        let start = std::time::Instant::now();
+        let end = self.clock.now() + std::time::Duration::from_secs(duration as u64);
 
-        try!(CFF3000::print_leds(r, g));
+        CFF3000::print_leds(r, g)?;
 
-        while start.elapsed().as_secs() < duration as u64 {
-            let events = try!(gpio::wait_for_event(&[&self.red, &self.green], 1000));
+        while self.clock.now() < end {
+            let events = self.backend.wait_for_led_events(end.saturating_duration_since(self.clock.now()))?;
             if events == 0 {
                 continue;
             }
 
-            if events & 0x1 != 0 {
-                r = try!(self.red.read()).id == gpio::EventId::RISING_EDGE;
+            if events & LED_RED != 0 {
+                r = self.backend.read_led_event(Led::Red)?.on;
             }
-            if events & 0x2 != 0 {
-                g = try!(self.green.read()).id == gpio::EventId::RISING_EDGE;
+            if events & LED_GREEN != 0 {
+                g = self.backend.read_led_event(Led::Green)?.on;
             }
 
-            try!(CFF3000::print_leds(r, g));
+            CFF3000::print_leds(r, g)?;
         }
 
-        println!("");
+        println!();
 
         Ok(())
     }
 
+    fn press(&self, buttons: Buttons, busy: interlock::OperationGuard) -> std::io::Result<PressGuard> {
+        let lines = match buttons {
+            Buttons::Lock => vec![Button::Lock],
+            Buttons::Unlock => vec![Button::Unlock],
+            Buttons::Both => vec![Button::Lock, Button::Unlock],
+        };
+        if let (Buttons::Lock, Some(door)) = (buttons, self.door.as_ref()) {
+            door.check_lock()?;
+        }
+        if buttons != Buttons::Both {
+            self.invalidate_cached_state();
+        }
+        let guard = PressGuard::press(self.backend.clone(), lines, self.timings.press_for(buttons.command()), self.clock.clone(), busy);
+        if guard.is_ok() && !self.dry_run {
+            self.battery.record();
+        }
+        #[cfg(all(feature = "journald", unix))]
+        {
+            if guard.is_ok() {
+                self.send_journal(journald::JournalEvent::Command(buttons.command()));
+            }
+        }
+        guard
+    }
+
+    /// Press the lock button without blocking.
+    ///
+    /// The button stays pressed until the returned guard is released or
+    /// dropped, but never for less than the press duration. The device
+    /// is busy until the button has actually been released.
+    pub fn begin_lock_press(&self) -> std::io::Result<PressGuard> {
+        self.press(Buttons::Lock, self.acquire()?)
+    }
+
+    /// Press the unlock button without blocking.
+    ///
+    /// See `begin_lock_press()`.
+    pub fn begin_unlock_press(&self) -> std::io::Result<PressGuard> {
+        self.press(Buttons::Unlock, self.acquire()?)
+    }
+
+    /// Press both buttons to query state without blocking.
+    ///
+    /// See `begin_lock_press()`.
+    pub fn begin_check_press(&self) -> std::io::Result<PressGuard> {
+        self.press(Buttons::Both, self.acquire()?)
+    }
+
+    fn wait_and_release(&self, guard: PressGuard) -> std::io::Result<()> {
+        self.clock.sleep(guard.remaining());
+        guard.release()
+    }
+
+    fn press_and_release(&self, buttons: Buttons) -> std::io::Result<()> {
+        let mut span = spans::OperationSpan::new(match buttons {
+            Buttons::Lock => spans::Operation::Lock,
+            Buttons::Unlock => spans::Operation::Unlock,
+            Buttons::Both => spans::Operation::Check,
+        }, self.label.as_deref());
+        span.record_press(self.timings.press_for(buttons.command()));
+        let started = (std::time::SystemTime::now(), self.clock.now());
+        let result = span.in_scope(|| self.acquire().and_then(|busy| self.press(buttons, busy)).and_then(|guard| self.wait_and_release(guard)));
+        span.record_result(&result, None);
+        let duration = clock::until(started.1, self.clock.now());
+        self.history.record(history::HistoryEntry::new(started.0, buttons.command(), result.as_ref().map(|_| None), duration));
+        self.telemetry.operation(buttons.command(), &result, None, &[], duration);
+        #[cfg(feature = "metrics")]
+        {
+            if let Some(ref metrics) = self.metrics {
+                metrics.record_press(buttons.command(), &result);
+            }
+        }
+        #[cfg(all(feature = "journald", unix))]
+        {
+            if let Err(ref err) = result {
+                self.send_journal(journald::JournalEvent::Failure(buttons.command(), err));
+            }
+        }
+        result
+    }
+
     /// Press and release lock button.
     pub fn lock(&self) -> std::io::Result<()> {
-        try!(self.lock.set(1));
-        std::thread::sleep(std::time::Duration::from_millis(500));
-        try!(self.lock.set(0));
-        Ok(())
+        self.press_and_release(Buttons::Lock)
     }
 
     /// Press and release unlock button.
     pub fn unlock(&self) -> std::io::Result<()> {
-        try!(self.unlock.set(1));
-        std::thread::sleep(std::time::Duration::from_millis(500));
-        try!(self.unlock.set(0));
-        Ok(())
+        self.press_and_release(Buttons::Unlock)
     }
 
     /// Press and release both buttons to query state.
     pub fn check(&self) -> std::io::Result<()> {
-        try!(self.lock.set(1));
-        try!(self.unlock.set(1));
-        std::thread::sleep(std::time::Duration::from_millis(500));
-        try!(self.unlock.set(0));
-        try!(self.lock.set(0));
-        Ok(())
+        self.press_and_release(Buttons::Both)
+    }
+
+    /// Run `command` like `LockControl::execute()` and record it in the
+    /// audit log with `initiator`, see the `audit` module. Without
+    /// the `audit` feature or a log, only the command is run.
+    pub fn execute_as(&self, command: Command, initiator: &str) -> std::io::Result<CFF3000State> {
+        self.audited(command, initiator, || LockControl::execute(self, command), |&state| Some(state))
+    }
+
+    /// Press the button(s) of `command` like `lock()`, `unlock()` or
+    /// `check()` and record it like `execute_as()`.
+    pub fn press_as(&self, command: Command, initiator: &str) -> std::io::Result<()> {
+        self.audited(command, initiator, || self.press_and_release(Buttons::of(command)), |_| None)
+    }
+
+    /// Run `f` for `command` and record its result, with the state given
+    /// by `state`, like `execute_as()`.
+    #[cfg_attr(not(all(feature = "audit", unix)), allow(unused_variables))]
+    pub(crate) fn audited<T, F, S>(&self, command: Command, initiator: &str, f: F, state: S) -> std::io::Result<T>
+        where F: FnOnce() -> std::io::Result<T>, S: Fn(&T) -> Option<CFF3000State>
+    {
+        #[cfg(all(feature = "audit", unix))]
+        let started = (std::time::SystemTime::now(), self.clock.now());
+        let result = f();
+        #[cfg(all(feature = "audit", unix))]
+        {
+            if let Some(ref log) = self.audit {
+                let duration = clock::until(started.1, self.clock.now());
+                log.record(audit::AuditRecord::new(started.0, command, initiator, result.as_ref().map(state), duration));
+            }
+        }
+        result
+    }
+
+    /// Audit log registered with `CFF3000Builder::audit()`.
+    #[cfg(all(feature = "audit", unix))]
+    pub fn audit_log(&self) -> Option<&Arc<audit::AuditLog>> {
+        self.audit.as_ref()
+    }
+
+    /// Journal registered with `CFF3000Builder::journal()`.
+    #[cfg(all(feature = "journald", unix))]
+    pub fn journal(&self) -> Option<&Arc<journald::Journal>> {
+        self.journal.as_ref()
+    }
+
+    /// Send `event` to the journal, if any.
+    #[cfg(all(feature = "journald", unix))]
+    fn send_journal(&self, event: journald::JournalEvent) {
+        if let Some(ref journal) = self.journal {
+            journal.send(&event);
+        }
+    }
+
+    /// The last operations, oldest first, see the `history` module.
+    pub fn history(&self) -> Vec<history::HistoryEntry> {
+        self.history.entries()
+    }
+
+    /// Metrics registered with `CFF3000Builder::metrics()`.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> Option<&Arc<metrics::Metrics>> {
+        self.metrics.as_ref()
+    }
+
+    /// Returns true if button presses are only reported, see
+    /// `CFF3000Builder::dry_run()`.
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Describe the four lines (offset, kernel name, flags and current
+    /// level), in `LineRole::ALL` order. The values are read from the
+    /// hardware on every call, so e.g. a stuck button line is visible.
+    pub fn line_info(&self) -> std::io::Result<[LineInfo; 4]> {
+        self.backend.line_info()
     }
 
     /// Flush LED events
     pub fn flush_led_events(&self) -> std::io::Result<()> {
-        try!(self.green.flush());
-        try!(self.red.flush());
-        Ok(())
+        self.note_led_events();
+        self.backend.flush_led_events()
     }
 
     /// Query CFA3000 state and interpret the following
     /// LED pattern. This function blocks for 8 seconds
-    /// to capture the LED blink pattern.
+    /// (see `Timings`) to capture the LED blink pattern.
+    ///
+    /// With `CFF3000Builder::state_cache()`, a state verified within
+    /// the TTL is returned without pressing any button, see
+    /// `cached_state()`.
     pub fn state(&self) -> std::io::Result<CFF3000State> {
-        try!(self.flush_led_events());
-        try!(self.check());
-
-        let mut eventlog: std::vec::Vec<Event> = std::vec::Vec::new();
-        let start = std::time::Instant::now();
+        self.cached_state().map(|cached| cached.state)
+    }
 
-        print!("waiting for led events... ");
-        try!(std::io::stdout().flush());
+    /// Returns true if the door is locked, see `state()`.
+    pub fn is_locked(&self) -> std::io::Result<bool> {
+        self.state().map(|state| state == CFF3000State::Locked)
+    }
 
-        while start.elapsed().as_secs() < 8 {
-            let events = try!(gpio::wait_for_event(&[&self.red, &self.green], 1000));
-            if events == 0 {
-                continue;
+    /// Like `state()`, but also return how old the state is.
+    ///
+    /// The cached state is used if it is younger than the TTL given to
+    /// `CFF3000Builder::state_cache()` and no LED events are pending,
+    /// since they mean the lock has been used with its remote control.
+    /// Otherwise the state is queried, with an age of zero.
+    pub fn cached_state(&self) -> std::io::Result<CachedState> {
+        if let Some(ref cache) = self.cache {
+            if self.wait_for_led_events(std::time::Duration::from_millis(0))? != 0 {
+                cache.invalidate();
+            } else if let Some(cached) = cache.fresh(self.clock.now()) {
+                return Ok(cached);
             }
+        }
+        self.state_report().map(|report| CachedState {state: report.state, age: std::time::Duration::from_millis(0), cached: false})
+    }
 
-            if events & 0x1 != 0 {
-                let event = try!(self.red.read());
-                let state: u8 = if event.id == gpio::EventId::RISING_EDGE {1} else {0};
-                eventlog.push(Event {mask: 0b01, timestamp: event.timestamp/1000/1000, state: state << 0});
-            }
-            if events & 0x2 != 0 {
-                let event = try!(self.green.read());
-                let state: u8 = if event.id == gpio::EventId::RISING_EDGE {1} else {0};
-                eventlog.push(Event {mask: 0b10, timestamp: event.timestamp/1000/1000, state: state << 1});
-            }
+    /// Last state verified by a state query or `lock_and_verify()` /
+    /// `unlock_and_verify()`, however old, without pressing any button.
+    /// Always `None` without `CFF3000Builder::state_cache()`.
+    pub fn last_state(&self) -> Option<CachedState> {
+        self.cache.as_ref().and_then(|cache| cache.last(self.clock.now()))
+    }
+
+    /// Put `state`, verified `age` ago, into an empty cache, e.g. one
+    /// kept across restarts with `persist::StateFile`. Does nothing
+    /// without `CFF3000Builder::state_cache()` or if a state is cached
+    /// already.
+    pub fn restore_cached_state(&self, state: CFF3000State, age: std::time::Duration) {
+        if let (Some(cache), Some(at)) = (self.cache.as_ref(), self.clock.now().checked_sub(age)) {
+            cache.restore(state, at);
         }
+    }
 
-        /* combine events within 50ms */
-        let mut simple_eventlog: std::vec::Vec<Event> = std::vec::Vec::new();
-        simple_eventlog.push(eventlog[0]);
-        for i in 1..eventlog.len() {
-            if eventlog[i-1].timestamp > eventlog[i].timestamp - 50 {
-                let pos = simple_eventlog.len()-1;
-                simple_eventlog[pos].mask |= eventlog[i].mask;
-                simple_eventlog[pos].state |= eventlog[i].state & eventlog[i].mask;
-                simple_eventlog[pos].state &= eventlog[i].state | !eventlog[i].mask;
-            } else {
-                simple_eventlog.push(eventlog[i]);
-            }
+    /// Forget the cached state, so the next `state()` queries it. Done
+    /// on every press of the lock or unlock button.
+    pub fn invalidate_cached_state(&self) {
+        if let Some(ref cache) = self.cache {
+            cache.invalidate();
         }
+    }
 
-        /* fill up event data for unchanged leds with previous information and use relative timestamps */
-        let mut state = 0u8;
-        let offset = simple_eventlog[0].timestamp;
-        for e in &mut simple_eventlog {
-            state &= !e.mask;
-            state |= e.state & e.mask;
+    /// Like `state()`, but always query and also return the captured
+    /// LED events and capture diagnostics.
+    pub fn state_report(&self) -> std::io::Result<StateReport> {
+        self.query(Buttons::Both)
+    }
 
-            if e.mask != 0b11 {
-                e.state &= e.mask;
-                e.state |= state;
+    /// Press both buttons like `state()` and return the LED events
+    /// captured during `window`, without interpreting them. Used to
+    /// record patterns `state()` rejects, see `testing::Fixture`.
+    pub fn capture(&self, window: std::time::Duration) -> std::io::Result<Capture> {
+        let mut query = StateQuery::begin(self, Buttons::Both, window, self.clock.clone())?;
+        loop {
+            if let std::task::Poll::Ready(result) = query.poll_capture() {
+                return result;
             }
-
-            e.mask = 0b11;
-            e.timestamp -= offset;
+            query.wait()?;
         }
+    }
 
-        /* check for obvious problems */
-        if simple_eventlog.len() <= 2 {
-            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "did not receive enough LED change events"));
-        }
-        if simple_eventlog.first().unwrap().state != 0b11 {
-            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "first LED changes is invalid"));
-        }
-        if simple_eventlog.last().unwrap().state != 0b00 {
-            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "last LED change is invalid"));
-        }
+    /// Press and release lock button and interpret the
+    /// confirmation LED pattern. This function blocks for
+    /// 10 seconds (see `Timings`) to capture the LED blink pattern.
+    pub fn lock_and_verify(&self) -> std::io::Result<CFF3000State> {
+        self.query(Buttons::Lock).map(|report| report.state)
+    }
 
-        let result: CFF3000State;
-        if simple_eventlog.len() == 3 {
-            if simple_eventlog[1].state == 0b10 {
-                result = CFF3000State::Locked;
-            } else if simple_eventlog[1].state == 0b01 {
-                result = CFF3000State::Unlocked;
-            } else {
-                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid LED state"));
-            }
-        } else {
-            result = match simple_eventlog[1].state {
-                0b00 => CFF3000State::Manual,
-                0b01 => CFF3000State::OutOfRange,
-                0b10 => CFF3000State::OutOfRange,
-                _ => {return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid LED state"))},
-            };
-
-            for i in 2..simple_eventlog.len()-1 {
-                if result == CFF3000State::Manual {
-                    if simple_eventlog[i-1].state & 0b11 != !simple_eventlog[i].state & 0b11 {
-                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid manual error LED substate"));
-                    }
-                } else {
-                    if simple_eventlog[i].state == 0b00 || simple_eventlog[i].state == 0b11 {
-                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid out of range error LED substate"));
-                    }
-                    if simple_eventlog[i-1].state & 0b11 == !simple_eventlog[i-1].state & 0b11 {
-                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid out of range error LED substate"));
-                    }
+    /// Press and release unlock button and interpret the
+    /// confirmation LED pattern. This function blocks for
+    /// 10 seconds (see `Timings`) to capture the LED blink pattern.
+    pub fn unlock_and_verify(&self) -> std::io::Result<CFF3000State> {
+        self.query(Buttons::Unlock).map(|report| report.state)
+    }
+
+    fn query(&self, buttons: Buttons) -> std::io::Result<StateReport> {
+        let mut span = spans::OperationSpan::new(match buttons {
+            Buttons::Lock => spans::Operation::LockAndVerify,
+            Buttons::Unlock => spans::Operation::UnlockAndVerify,
+            Buttons::Both => spans::Operation::State,
+        }, self.label.as_deref());
+        span.record_press(self.timings.press_for(buttons.command()));
+        let result = span.in_scope(|| {
+            let capture = self.timings.capture_for(buttons.command());
+            let mut query = StateQuery::begin(self, buttons, capture, self.clock.clone())?;
+
+            log::debug!("waiting {:?} for LED events", capture);
+
+            loop {
+                if let std::task::Poll::Ready(result) = query.poll_report() {
+                    return result;
                 }
+                query.wait()?;
+            }
+        });
+        if let Ok(ref report) = result {
+            span.record_events(report.events.len());
+        }
+        span.record_result(&result, result.as_ref().ok().map(|report| report.state));
+        result
+    }
+
+    /// Wait up to `timeout` for LED events without reading them.
+    fn wait_for_led_events(&self, timeout: std::time::Duration) -> std::io::Result<u8> {
+        self.backend.wait_for_led_events(timeout)
+    }
+
+    /// Wait up to `timeout` and append the pending LED events to
+    /// `eventlog` as far as they fit, returning the number of events
+    /// read.
+    fn read_led_events(&self, timeout: std::time::Duration, eventlog: &mut EventBuffer) -> std::io::Result<usize> {
+        let events = self.wait_for_led_events(timeout)?;
+        let start = eventlog.len();
+
+        for &led in &[Led::Red, Led::Green] {
+            if events & led.mask() != 0 && !eventlog.is_full() {
+                self.backend.read_led_events(led, eventlog)?;
             }
         }
+        let count = eventlog.len() - start;
+        if count != 0 {
+            /* the events of both LEDs in the order they happened */
+            eventlog[start..].sort_by_key(|event| event.timestamp);
+            self.note_led_events();
+        }
+
+        Ok(count)
+    }
 
-        Ok(result)
+    fn parse_eventlog(events: &[LedEvent], options: &ParseOptions) -> std::io::Result<CFF3000State> {
+        parser::parse(events, options).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
     }
 }
@@ -0,0 +1,82 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Cross-process exclusive access via an advisory lock file.
+//!
+//! The lock is an `flock(2)` on a user supplied file. The kernel drops
+//! it when the owning process exits, so a lock file left behind by a
+//! crashed process is simply taken over. The owner's PID is written
+//! into the file for diagnostics only.
+
+use std::fs::File;
+use std::io::Error;
+#[cfg(unix)]
+use std::io::{Read, Seek, SeekFrom, Write};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Error payload returned when another process holds the lock file.
+///
+/// The returned `std::io::Error` has `ErrorKind::Other` and wraps this
+/// type, use `err.get_ref().and_then(|e| e.downcast_ref::<AlreadyInUse>())`
+/// to access it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlreadyInUse {
+    /// Path of the lock file
+    pub path: PathBuf,
+    /// PID recorded by the current owner, if readable
+    pub pid: Option<u32>,
+}
+
+impl std::fmt::Display for AlreadyInUse {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.pid {
+            Some(pid) => write!(f, "CFF3000 already in use by process {} (lock file {})", pid, self.path.display()),
+            None => write!(f, "CFF3000 already in use (lock file {})", self.path.display()),
+        }
+    }
+}
+
+impl std::error::Error for AlreadyInUse {}
+
+/// Held lock, released when dropped.
+#[derive(Debug)]
+pub(crate) struct LockFile {
+    _file: File,
+}
+
+impl LockFile {
+    #[cfg(not(unix))]
+    pub(crate) fn acquire(_path: &Path) -> std::io::Result<LockFile> {
+        Err(Error::new(std::io::ErrorKind::Unsupported, "lock files are only supported on Unix"))
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn acquire(path: &Path) -> std::io::Result<LockFile> {
+        let mut file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+
+        let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if ret != 0 {
+            let err = Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(err);
+            }
+
+            let mut content = String::new();
+            let pid = match file.read_to_string(&mut content) {
+                Ok(_) => content.trim().parse().ok(),
+                Err(_) => None,
+            };
+            return Err(Error::other(AlreadyInUse {path: path.to_path_buf(), pid}));
+        }
+
+        /* replace the PID of a previous (possibly crashed) owner */
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        writeln!(file, "{}", std::process::id())?;
+        file.flush()?;
+
+        Ok(LockFile {_file: file})
+    }
+}
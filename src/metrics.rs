@@ -0,0 +1,409 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Prometheus metrics (`metrics` feature).
+//!
+//! A [`Metrics`] registered with `CFF3000Builder::metrics()` records the
+//! operations of the device, `encode()` renders them in the Prometheus
+//! text format. With the `http` feature they are served on `/metrics`.
+//!
+//! | Name | Type | Labels | |
+//! |------|------|--------|-|
+//! | `cff3000_commands_total` | counter | `command` | presses and state queries started |
+//! | `cff3000_command_failures_total` | counter | `command`, `code` | failed presses and state queries |
+//! | `cff3000_state` | gauge | | last state read: 0 locked, 1 unlocked, 2 manual, 3 out-of-range |
+//! | `cff3000_query_duration_seconds` | histogram | `command` | duration of completed state queries including the press |
+//! | `cff3000_led_feedback_latency_seconds` | histogram | | time from releasing the buttons until the first LED event is read |
+//! | `cff3000_last_success_timestamp_seconds` | gauge | | Unix time of the last successful state query |
+//!
+//! `command` is "lock", "unlock" or "check". `lock()` counts as "lock"
+//! like `lock_and_verify()`, `state()` and the queries of `watch()` as
+//! "check". `code` is one of the `error.code` values of the `cli`
+//! feature, e.g. "busy" or "no-response". `cff3000_state` and the
+//! timestamp have no sample before the first successful query.
+//!
+//! # Snapshots
+//!
+//! `Metrics::snapshot()` returns the same numbers as plain data, e.g.
+//! for Graphite, the InfluxDB line protocol or a JSON file. `encode()`
+//! is rendered from it. With the `config` feature the snapshot
+//! serializes with serde, durations in milliseconds, the state by its
+//! name and `last_success` in milliseconds since the Unix epoch:
+//!
+//! ```json
+//! {"commands":{"check":2,"lock":1,"unlock":1},"successes":{"check":1,"lock":1,"unlock":1},
+//!  "failures":{"check":{"no-response":1}},"last_durations_ms":{"check":8500,"lock":10500},
+//!  "state":"locked","uptime_ms":35200,"last_success_ms":1700000000000,"query_duration":{...},
+//!  "feedback_latency":{"bounds":[0.01,...],"counts":[0,...],"sum":0.4,"count":2}}
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io::{Error, ErrorKind};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "config")]
+use serde::{Serialize, Serializer};
+
+#[cfg(feature = "daemon")]
+use crate::persist::Counters;
+use crate::query;
+use crate::{AlreadyInUse, CFF3000State, Command, ParseError};
+
+/// Upper bounds of the `cff3000_query_duration_seconds` buckets.
+pub const QUERY_DURATION_BUCKETS: [f64; 10] = [2.0, 4.0, 6.0, 8.0, 9.0, 10.0, 11.0, 12.0, 15.0, 20.0];
+/// Upper bounds of the `cff3000_led_feedback_latency_seconds` buckets.
+pub const FEEDBACK_LATENCY_BUCKETS: [f64; 9] = [0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+const COMMANDS: [Command; 3] = [Command::Lock, Command::Unlock, Command::Check];
+
+fn command_name(command: Command) -> &'static str {
+    match command {
+        Command::Lock => "lock",
+        Command::Unlock => "unlock",
+        Command::Check => "check",
+    }
+}
+
+/// Values of the `code` label.
+#[cfg(feature = "daemon")]
+const CODES: [&str; 8] = ["no-response", "invalid-pattern", "busy", "config", "not-found", "permission-denied", "unsupported", "io"];
+
+/// `error.code` of `err`, see `cli::ErrorCode`.
+fn error_code(err: &Error) -> &'static str {
+    let inner = err.get_ref();
+    match query::parse_error(err) {
+        Some(&ParseError::NotEnoughEvents) => return "no-response",
+        Some(_) => return "invalid-pattern",
+        None => {},
+    }
+    if inner.is_some_and(|inner| inner.is::<AlreadyInUse>()) {
+        return "busy";
+    }
+    match err.kind() {
+        ErrorKind::InvalidInput | ErrorKind::InvalidData => "config",
+        ErrorKind::NotFound => "not-found",
+        ErrorKind::PermissionDenied => "permission-denied",
+        ErrorKind::WouldBlock | ErrorKind::ResourceBusy => "busy",
+        ErrorKind::Unsupported => "unsupported",
+        _ => "io",
+    }
+}
+
+fn state_value(state: CFF3000State) -> u8 {
+    match state {
+        CFF3000State::Locked => 0,
+        CFF3000State::Unlocked => 1,
+        CFF3000State::Manual => 2,
+        CFF3000State::OutOfRange => 3,
+    }
+}
+
+/// Histogram of a `MetricsSnapshot`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "config", derive(Serialize))]
+pub struct Histogram {
+    /// Upper bounds of the buckets in seconds, e.g.
+    /// `QUERY_DURATION_BUCKETS`
+    pub bounds: &'static [f64],
+    /// Observations per bucket, not cumulative. Those above the last
+    /// bound are only in `count`.
+    pub counts: Vec<u64>,
+    /// Sum of the observations in seconds
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {bounds, counts: vec![0; bounds.len()], sum: 0.0, count: 0}
+    }
+
+    fn observe(&mut self, value: Duration) {
+        let seconds = value.as_secs_f64();
+        if let Some(bucket) = self.bounds.iter().position(|&bound| seconds <= bound) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn encode(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{{}le=\"{}\"}} {}", name, labels, bound, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{{}le=\"+Inf\"}} {}", name, labels, self.count);
+        let labels = labels.trim_end_matches(',');
+        let labels = if labels.is_empty() {String::new()} else {format!("{{{}}}", labels)};
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, self.count);
+    }
+}
+
+struct Values {
+    commands: [u64; 3],
+    failures: BTreeMap<(&'static str, &'static str), u64>,
+    state: Option<CFF3000State>,
+    query_duration: BTreeMap<&'static str, Histogram>,
+    last_duration: BTreeMap<&'static str, Duration>,
+    feedback_latency: Histogram,
+    last_success: Option<SystemTime>,
+}
+
+/// Numbers of a `Metrics` at one point in time, see the module
+/// documentation. Commands are named "lock", "unlock" and "check",
+/// codes as the `error.code` values of the `cli` feature.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "config", derive(Serialize))]
+pub struct MetricsSnapshot {
+    /// Presses and state queries started, for every command
+    pub commands: BTreeMap<&'static str, u64>,
+    /// Started ones which did not fail, for every command
+    pub successes: BTreeMap<&'static str, u64>,
+    /// Failures by command and code, without commands that never failed
+    pub failures: BTreeMap<&'static str, BTreeMap<&'static str, u64>>,
+    /// Duration of the last completed state query of each command,
+    /// including the press
+    #[cfg_attr(feature = "config", serde(rename = "last_durations_ms", serialize_with = "millis_map"))]
+    pub last_durations: BTreeMap<&'static str, Duration>,
+    /// Last state read, `None` before the first successful query
+    #[cfg_attr(feature = "config", serde(serialize_with = "state_name", skip_serializing_if = "Option::is_none"))]
+    pub state: Option<CFF3000State>,
+    /// Time since `Metrics::new()`
+    #[cfg_attr(feature = "config", serde(rename = "uptime_ms", serialize_with = "millis"))]
+    pub uptime: Duration,
+    /// Time of the last successful state query
+    #[cfg_attr(feature = "config", serde(rename = "last_success_ms", serialize_with = "unix_millis", skip_serializing_if = "Option::is_none"))]
+    pub last_success: Option<SystemTime>,
+    /// Durations of the completed state queries, for the commands with
+    /// at least one
+    pub query_duration: BTreeMap<&'static str, Histogram>,
+    /// Time from releasing the buttons until the first LED event is read
+    pub feedback_latency: Histogram,
+}
+
+#[cfg(feature = "config")]
+fn millis<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_millis() as u64)
+}
+
+#[cfg(feature = "config")]
+fn millis_map<S: Serializer>(durations: &BTreeMap<&'static str, Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(durations.iter().map(|(command, duration)| (command, duration.as_millis() as u64)))
+}
+
+#[cfg(feature = "config")]
+fn state_name<S: Serializer>(state: &Option<CFF3000State>, serializer: S) -> Result<S::Ok, S::Error> {
+    match *state {
+        Some(state) => serializer.serialize_some(state.name()),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(feature = "config")]
+fn unix_millis<S: Serializer>(time: &Option<SystemTime>, serializer: S) -> Result<S::Ok, S::Error> {
+    match *time {
+        Some(time) => serializer.serialize_some(&(time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64)),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Operation metrics of one device, see the module documentation.
+///
+/// # Example
+/// ```
+/// use cff3000::metrics::Metrics;
+/// use cff3000::mock::MockBackend;
+/// use cff3000::CFF3000Builder;
+/// use std::sync::Arc;
+/// use std::time::Duration;
+///
+/// fn main() {
+///     let metrics = Arc::new(Metrics::new());
+///     let cff3000 = CFF3000Builder::with_backend(MockBackend::new())
+///         .lock_press(Duration::from_millis(10))
+///         .metrics(metrics.clone())
+///         .build()
+///         .unwrap();
+///     cff3000.lock().unwrap();
+///     assert!(metrics.encode().contains("cff3000_commands_total{command=\"lock\"} 1\n"));
+/// }
+/// ```
+pub struct Metrics {
+    values: Mutex<Values>,
+    started: Instant,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    /// Metrics without any recorded operation.
+    pub fn new() -> Metrics {
+        Metrics {
+            values: Mutex::new(Values {
+                commands: [0; 3],
+                failures: BTreeMap::new(),
+                state: None,
+                query_duration: BTreeMap::new(),
+                last_duration: BTreeMap::new(),
+                feedback_latency: Histogram::new(&FEEDBACK_LATENCY_BUCKETS),
+                last_success: None,
+            }),
+            started: Instant::now(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Values> {
+        self.values.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn count(values: &mut Values, command: Command, err: Option<&Error>) {
+        values.commands[COMMANDS.iter().position(|&c| c == command).unwrap_or(0)] += 1;
+        if let Some(err) = err {
+            *values.failures.entry((command_name(command), error_code(err))).or_insert(0) += 1;
+        }
+    }
+
+    /// Record a press without reading the LEDs, e.g. `lock()`.
+    pub(crate) fn record_press(&self, command: Command, result: &std::io::Result<()>) {
+        Metrics::count(&mut self.lock(), command, result.as_ref().err());
+    }
+
+    /// Record a state query which failed before pressing the buttons.
+    pub(crate) fn record_refused(&self, command: Command, err: &Error) {
+        Metrics::count(&mut self.lock(), command, Some(err));
+    }
+
+    /// Record a completed state query taking `duration`, whose first
+    /// LED event has been read `latency` after the release.
+    pub(crate) fn record_query(&self, command: Command, result: Result<CFF3000State, &Error>, duration: Duration, latency: Option<Duration>) {
+        let mut values = self.lock();
+        Metrics::count(&mut values, command, result.err());
+        values.query_duration.entry(command_name(command)).or_insert_with(|| Histogram::new(&QUERY_DURATION_BUCKETS)).observe(duration);
+        values.last_duration.insert(command_name(command), duration);
+        if let Some(latency) = latency {
+            values.feedback_latency.observe(latency);
+        }
+        if let Ok(state) = result {
+            values.state = Some(state);
+            values.last_success = Some(SystemTime::now());
+        }
+    }
+
+    /// Values of the counters, e.g. to keep them across restarts with
+    /// `persist::StateFile`.
+    #[cfg(feature = "daemon")]
+    pub fn counters(&self) -> Counters {
+        let values = self.lock();
+        let mut counters = Counters::default();
+        for (&command, &count) in COMMANDS.iter().zip(&values.commands) {
+            counters.commands.insert(command_name(command).to_string(), count);
+        }
+        for (&(command, code), &count) in &values.failures {
+            counters.failures.entry(command.to_string()).or_insert_with(BTreeMap::new).insert(code.to_string(), count);
+        }
+        counters
+    }
+
+    /// Add `counters`, e.g. those of a previous run. Unknown commands
+    /// and codes are ignored.
+    #[cfg(feature = "daemon")]
+    pub fn add_counters(&self, counters: &Counters) {
+        let mut values = self.lock();
+        for (i, &command) in COMMANDS.iter().enumerate() {
+            values.commands[i] += counters.commands.get(command_name(command)).cloned().unwrap_or(0);
+            for &code in &CODES {
+                if let Some(&count) = counters.failures.get(command_name(command)).and_then(|codes| codes.get(code)) {
+                    *values.failures.entry((command_name(command), code)).or_insert(0) += count;
+                }
+            }
+        }
+    }
+
+    /// The current numbers, see `MetricsSnapshot`.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let values = self.lock();
+        let mut failures = BTreeMap::new();
+        for (&(command, code), &count) in &values.failures {
+            failures.entry(command).or_insert_with(BTreeMap::new).insert(code, count);
+        }
+        let mut commands = BTreeMap::new();
+        let mut successes = BTreeMap::new();
+        for (&command, &count) in COMMANDS.iter().zip(&values.commands) {
+            let failed: u64 = failures.get(command_name(command)).map_or(0, |codes: &BTreeMap<&str, u64>| codes.values().sum());
+            commands.insert(command_name(command), count);
+            successes.insert(command_name(command), count.saturating_sub(failed));
+        }
+        MetricsSnapshot {
+            commands,
+            successes,
+            failures,
+            last_durations: values.last_duration.clone(),
+            state: values.state,
+            uptime: self.started.elapsed(),
+            last_success: values.last_success,
+            query_duration: values.query_duration.clone(),
+            feedback_latency: values.feedback_latency.clone(),
+        }
+    }
+
+    /// The metrics in the Prometheus text exposition format 0.0.4, see
+    /// `MetricsSnapshot::encode()`.
+    pub fn encode(&self) -> String {
+        self.snapshot().encode()
+    }
+}
+
+impl MetricsSnapshot {
+    /// The numbers in the Prometheus text exposition format 0.0.4.
+    pub fn encode(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP cff3000_commands_total Button presses and state queries started.\n");
+        out.push_str("# TYPE cff3000_commands_total counter\n");
+        for &command in &COMMANDS {
+            let count = self.commands.get(command_name(command)).cloned().unwrap_or(0);
+            let _ = writeln!(out, "cff3000_commands_total{{command=\"{}\"}} {}", command_name(command), count);
+        }
+
+        out.push_str("# HELP cff3000_command_failures_total Failed button presses and state queries.\n");
+        out.push_str("# TYPE cff3000_command_failures_total counter\n");
+        for (command, codes) in &self.failures {
+            for (code, count) in codes {
+                let _ = writeln!(out, "cff3000_command_failures_total{{command=\"{}\",code=\"{}\"}} {}", command, code, count);
+            }
+        }
+
+        out.push_str("# HELP cff3000_state Last state read: 0 locked, 1 unlocked, 2 manual, 3 out-of-range.\n");
+        out.push_str("# TYPE cff3000_state gauge\n");
+        if let Some(state) = self.state {
+            let _ = writeln!(out, "cff3000_state {}", state_value(state));
+        }
+
+        out.push_str("# HELP cff3000_query_duration_seconds Duration of completed state queries including the press.\n");
+        out.push_str("# TYPE cff3000_query_duration_seconds histogram\n");
+        for (command, histogram) in &self.query_duration {
+            histogram.encode(&mut out, "cff3000_query_duration_seconds", &format!("command=\"{}\",", command));
+        }
+
+        out.push_str("# HELP cff3000_led_feedback_latency_seconds Time from releasing the buttons until the first LED event is read.\n");
+        out.push_str("# TYPE cff3000_led_feedback_latency_seconds histogram\n");
+        self.feedback_latency.encode(&mut out, "cff3000_led_feedback_latency_seconds", "");
+
+        out.push_str("# HELP cff3000_last_success_timestamp_seconds Unix time of the last successful state query.\n");
+        out.push_str("# TYPE cff3000_last_success_timestamp_seconds gauge\n");
+        if let Some(time) = self.last_success {
+            let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            let _ = writeln!(out, "cff3000_last_success_timestamp_seconds {}.{:03}", since.as_secs(), since.subsec_millis());
+        }
+
+        out
+    }
+}
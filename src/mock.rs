@@ -0,0 +1,397 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Mock GPIO backend for tests without hardware.
+//!
+//! [`MockBackend`] records every button transition and, once a press has
+//! been released, plays back the LED [`Script`] registered for that kind
+//! of press. Scripted events carry virtual timestamps and are available
+//! immediately, so the capture and parser see exactly what real hardware
+//! would have produced.
+//!
+//! # Example
+//! ```
+//! use cff3000::mock::{fixtures, MockBackend};
+//! use cff3000::{CFF3000, CFF3000State, Command};
+//!
+//! fn main() {
+//!     let mock = MockBackend::new();
+//!     mock.respond(Command::Check, fixtures::locked());
+//!
+//!     let cff3000 = CFF3000::with_backend(mock.clone()).unwrap();
+//!     assert_eq!(cff3000.state().unwrap(), CFF3000State::Locked);
+//!     assert_eq!(mock.transitions().len(), 4);
+//! }
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::task::{Wake, Waker};
+use std::time::{Duration, Instant};
+
+use crate::clock::deadline;
+use crate::discover::LineFlags;
+use crate::{Button, Command, EventBuffer, GpioBackend, Led, LedEvent, LineInfo, LineRole, LED_GREEN, LED_RED};
+
+/// Virtual time between mock creation and the first event timestamp,
+/// keeping timestamps clear of zero.
+const TIMESTAMP_BASE: Duration = Duration::from_secs(1);
+
+/// Recorded change of a button output.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Transition {
+    /// Button which changed
+    pub button: Button,
+    /// New level (true = pressed)
+    pub pressed: bool,
+    /// Time since the mock (or the clock of a `testing::Replay`) has
+    /// been created
+    pub at: Duration,
+}
+
+/// LED pattern as a list of combined LED levels.
+///
+/// Each step sets both LEDs (`LED_RED` / `LED_GREEN` bits) at an offset
+/// relative to the release of the button(s). Both LEDs start off.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Script {
+    steps: Vec<(Duration, u8)>,
+}
+
+impl Script {
+    /// Build a script from `(offset in ms, LED levels)` steps.
+    pub fn from_levels(steps: &[(u64, u8)]) -> Script {
+        Script {steps: steps.iter().map(|&(ms, levels)| (Duration::from_millis(ms), levels)).collect()}
+    }
+
+    /// Convert to per-LED events starting at `base`.
+    fn events(&self, base: Duration) -> Vec<LedEvent> {
+        let mut levels = 0u8;
+        let mut events = Vec::new();
+
+        for &(offset, next) in &self.steps {
+            let timestamp = base.saturating_add(offset);
+            for &led in &[Led::Red, Led::Green] {
+                let mask = led.mask();
+                if (levels ^ next) & mask != 0 {
+                    events.push(LedEvent {led, on: next & mask != 0, timestamp});
+                }
+            }
+            levels = next;
+        }
+
+        events
+    }
+}
+
+struct Inner {
+    transitions: Vec<Transition>,
+    pressed: [bool; 2],
+    press_combo: [bool; 2],
+    responses: HashMap<Command, Script>,
+    red: VecDeque<LedEvent>,
+    green: VecDeque<LedEvent>,
+    /// LED levels of the last events read
+    leds: u8,
+    /// The waker has been woken since the last wait
+    woken: bool,
+}
+
+struct Shared {
+    start: Instant,
+    inner: Mutex<Inner>,
+    events: Condvar,
+}
+
+/// `GpioBackend::waker()`, ending a wait of `wait_for_led_events()`.
+impl Wake for Shared {
+    fn wake(self: Arc<Shared>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Shared>) {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner()).woken = true;
+        self.events.notify_all();
+    }
+}
+
+/// Scriptable `GpioBackend`.
+///
+/// Clones share their state, so a test can keep one clone for
+/// inspection after handing another one to `CFF3000::with_backend()`.
+#[derive(Clone)]
+pub struct MockBackend {
+    shared: Arc<Shared>,
+}
+
+fn index(button: Button) -> usize {
+    match button {
+        Button::Unlock => 0,
+        Button::Lock => 1,
+    }
+}
+
+impl Default for MockBackend {
+    fn default() -> MockBackend {
+        MockBackend::new()
+    }
+}
+
+impl MockBackend {
+    /// Create a mock without any responses.
+    pub fn new() -> MockBackend {
+        MockBackend {
+            shared: Arc::new(Shared {
+                start: Instant::now(),
+                inner: Mutex::new(Inner {
+                    transitions: Vec::new(),
+                    pressed: [false; 2],
+                    press_combo: [false; 2],
+                    responses: HashMap::new(),
+                    red: VecDeque::new(),
+                    green: VecDeque::new(),
+                    leds: 0,
+                    woken: false,
+                }),
+                events: Condvar::new(),
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.shared.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Virtual timestamp for the current point in time.
+    fn now(&self) -> Duration {
+        self.shared.start.elapsed() + TIMESTAMP_BASE
+    }
+
+    /// Play `script` whenever a press of kind `command` is released.
+    /// `Command::Check` refers to both buttons being pressed together.
+    pub fn respond(&self, command: Command, script: Script) {
+        self.lock().responses.insert(command, script);
+    }
+
+    /// Queue LED events as if the LEDs changed right now, e.g. to
+    /// simulate activity not caused by a button press.
+    pub fn push_script(&self, script: &Script) {
+        let events = script.events(self.now());
+        self.push_events(&events);
+    }
+
+    /// Queue raw LED events.
+    pub fn push_events(&self, events: &[LedEvent]) {
+        let mut inner = self.lock();
+        for &event in events {
+            match event.led {
+                Led::Red => inner.red.push_back(event),
+                Led::Green => inner.green.push_back(event),
+            }
+        }
+        self.shared.events.notify_all();
+    }
+
+    /// All button transitions recorded so far.
+    pub fn transitions(&self) -> Vec<Transition> {
+        self.lock().transitions.clone()
+    }
+
+    /// Forget the recorded button transitions.
+    pub fn clear_transitions(&self) {
+        self.lock().transitions.clear();
+    }
+
+    /// Current level of `button` (true = pressed).
+    pub fn is_pressed(&self, button: Button) -> bool {
+        self.lock().pressed[index(button)]
+    }
+}
+
+impl GpioBackend for MockBackend {
+    fn set_button(&self, button: Button, pressed: bool) -> std::io::Result<()> {
+        let at = self.shared.start.elapsed();
+        let timestamp = self.now();
+        let mut inner = self.lock();
+        let i = index(button);
+
+        if inner.pressed[i] != pressed {
+            inner.transitions.push(Transition {button, pressed, at});
+        }
+        inner.pressed[i] = pressed;
+
+        if pressed {
+            inner.press_combo[i] = true;
+            return Ok(());
+        }
+
+        if inner.pressed[0] || inner.pressed[1] {
+            return Ok(());
+        }
+
+        /* all buttons released, respond to the completed press */
+        let command = match inner.press_combo {
+            [true, true] => Some(Command::Check),
+            [false, true] => Some(Command::Lock),
+            [true, false] => Some(Command::Unlock),
+            [false, false] => None,
+        };
+        inner.press_combo = [false; 2];
+
+        let events = match command.and_then(|c| inner.responses.get(&c)) {
+            Some(script) => script.events(timestamp),
+            None => return Ok(()),
+        };
+        drop(inner);
+        self.push_events(&events);
+        Ok(())
+    }
+
+    fn wait_for_led_events(&self, timeout: Duration) -> std::io::Result<u8> {
+        let deadline = deadline(Instant::now(), timeout);
+        let mut inner = self.lock();
+
+        loop {
+            let mut mask = 0;
+            if !inner.red.is_empty() {
+                mask |= LED_RED;
+            }
+            if !inner.green.is_empty() {
+                mask |= LED_GREEN;
+            }
+
+            let now = Instant::now();
+            if mask != 0 || now >= deadline || std::mem::take(&mut inner.woken) {
+                return Ok(mask);
+            }
+
+            inner = match self.shared.events.wait_timeout(inner, deadline - now) {
+                Ok((inner, _)) => inner,
+                Err(poisoned) => poisoned.into_inner().0,
+            };
+        }
+    }
+
+    fn read_led_event(&self, led: Led) -> std::io::Result<LedEvent> {
+        let mut inner = self.lock();
+        let event = match led {
+            Led::Red => inner.red.pop_front(),
+            Led::Green => inner.green.pop_front(),
+        };
+        if let Some(event) = event {
+            inner.leds = if event.on {inner.leds | led.mask()} else {inner.leds & !led.mask()};
+        }
+        event.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::WouldBlock, "no pending LED event"))
+    }
+
+    fn read_led_events(&self, led: Led, events: &mut EventBuffer) -> std::io::Result<usize> {
+        let mut inner = self.lock();
+        let queue = match led {
+            Led::Red => &mut inner.red,
+            Led::Green => &mut inner.green,
+        };
+        if queue.is_empty() {
+            return Err(std::io::Error::new(std::io::ErrorKind::WouldBlock, "no pending LED event"));
+        }
+        let count = std::cmp::min(queue.len(), events.remaining());
+        let mut last = None;
+        for event in queue.drain(..count) {
+            events.push(event);
+            last = Some(event.on);
+        }
+        match last {
+            Some(true) => inner.leds |= led.mask(),
+            Some(false) => inner.leds &= !led.mask(),
+            None => {},
+        }
+        Ok(count)
+    }
+
+    fn flush_led_events(&self) -> std::io::Result<()> {
+        let mut inner = self.lock();
+        inner.red.clear();
+        inner.green.clear();
+        Ok(())
+    }
+
+    fn waker(&self) -> Option<Waker> {
+        Some(Waker::from(self.shared.clone()))
+    }
+
+    /// Lines 0 to 3 with the button levels and the LED levels of the
+    /// last events read.
+    fn line_info(&self) -> std::io::Result<[LineInfo; 4]> {
+        let inner = self.lock();
+        let levels = [inner.leds & LED_RED != 0, inner.leds & LED_GREEN != 0, inner.pressed[0], inner.pressed[1]];
+        let line = |i: usize| {
+            let output = i >= 2;
+            LineInfo {
+                role: LineRole::ALL[i],
+                offset: i as u32,
+                name: None,
+                flags: LineFlags {used: true, output, ..LineFlags::default()},
+                level: levels[i],
+            }
+        };
+        Ok([line(0), line(1), line(2), line(3)])
+    }
+}
+
+/// Scripts for the documented LED patterns and common failures.
+pub mod fixtures {
+    use super::Script;
+
+    /// Both LEDs on, then green (locked), then off.
+    pub fn locked() -> Script {
+        Script::from_levels(&[(200, 0b11), (1200, 0b10), (4200, 0b00)])
+    }
+
+    /// Both LEDs on, then red (unlocked), then off.
+    pub fn unlocked() -> Script {
+        Script::from_levels(&[(200, 0b11), (1200, 0b01), (4200, 0b00)])
+    }
+
+    /// Both LEDs on, then blinking synchronously.
+    pub fn manual() -> Script {
+        Script::from_levels(&[
+            (200, 0b11), (1200, 0b00), (1700, 0b11), (2200, 0b00),
+            (2700, 0b11), (3200, 0b00), (3700, 0b11), (4200, 0b00),
+        ])
+    }
+
+    /// Both LEDs on, then blinking alternating.
+    pub fn out_of_range() -> Script {
+        Script::from_levels(&[
+            (200, 0b11), (1200, 0b01), (1700, 0b10), (2200, 0b01),
+            (2700, 0b10), (3200, 0b01), (3700, 0b10), (4200, 0b00),
+        ])
+    }
+
+    /// No LED reaction at all (e.g. empty battery).
+    pub fn no_response() -> Script {
+        Script::default()
+    }
+
+    /// LEDs switched on and off once, too few changes to classify.
+    pub fn too_short() -> Script {
+        Script::from_levels(&[(200, 0b11), (1200, 0b00)])
+    }
+
+    /// Pattern not starting with both LEDs on.
+    pub fn invalid_first() -> Script {
+        Script::from_levels(&[(200, 0b10), (1200, 0b11), (4200, 0b00)])
+    }
+
+    /// Pattern not ending with both LEDs off.
+    pub fn invalid_last() -> Script {
+        Script::from_levels(&[(200, 0b11), (1200, 0b10), (4200, 0b01)])
+    }
+
+    /// Synchronous blinking interrupted by a single LED.
+    pub fn invalid_manual() -> Script {
+        Script::from_levels(&[
+            (200, 0b11), (1200, 0b00), (1700, 0b11), (2200, 0b10),
+            (2700, 0b11), (3200, 0b00),
+        ])
+    }
+}
@@ -0,0 +1,823 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! State publishing over MQTT (`mqtt` feature).
+//!
+//! An [`MqttPublisher`] keeps a connection to a broker and publishes
+//! the door state as a retained message with the stable state names
+//! (`locked`, `unlocked`, `manual`, `out-of-range`), e.g. for Home
+//! Assistant. `publish_changes()` feeds it from the watch loop.
+//!
+//! With `MqttOptions::change_topic` set, `MqttPublisher::publish_change()`
+//! also publishes every change there as the JSON object of
+//! `StateChange`, retained, for clients which want to know the previous
+//! state, the trigger or the time:
+//!
+//! ```text
+//! {"state":"locked","previous":"unlocked","trigger":"auto-lock","at_ms":1760000000123}
+//! ```
+//!
+//! # Commands
+//!
+//! Created with `MqttPublisher::with_commands()`, it also subscribes to
+//! the command topic and accepts the payloads `LOCK`, `UNLOCK` and
+//! `CHECK` (surrounding whitespace is ignored). Commands in
+//! `allowed_commands` are sent to a `CommandQueue`, its depth bounds
+//! the number of commands waiting while an operation is in flight. The
+//! resulting state is published to the state topic like any other.
+//! Malformed, disallowed and retained payloads (which the broker would
+//! replay on every connect) are logged as warnings and ignored, as are
+//! commands the queue rejects or fails. Any `LockControl` can be driven
+//! like this through `CommandQueue::for_lock()`, other locks then
+//! publish their changes with `MqttPublisher::publish_change()` instead
+//! of `publish_changes()`.
+//!
+//! # Conventions
+//!
+//! `MqttOptions::convention` selects how the door is announced, only
+//! one at a time so they do not fight over topics:
+//!
+//! * `Convention::Plain` uses the state, command and availability
+//!   topics of the options and announces nothing.
+//! * `Convention::HomeAssistant` additionally announces a lock entity
+//!   as described by `discovery_config()` after every connect,
+//!   retained, so Home Assistant shows the door without any YAML. The
+//!   config is removed on drop if `Discovery::remove_on_shutdown` is
+//!   set, otherwise the entity stays and shows as unavailable.
+//! * `Convention::Homie` implements the Homie 4.0 convention instead,
+//!   see [`Homie`]. Its topics replace those of the options.
+//!
+//! # Availability
+//!
+//! The availability topic is set to `online` (retained) after every
+//! connect, before the state, and to `offline` on
+//! `MqttPublisher::shutdown()` or when the publisher is dropped. The
+//! broker sets it to `offline` as the Last Will if the connection
+//! breaks without that, e.g. because the process died. So `offline`
+//! means nobody watches the door, while a retained state next to
+//! `online` is current. With Homie these are the `$state` values
+//! `ready`, `disconnected` and `lost`.
+//!
+//! # TLS and authentication
+//!
+//! `MqttOptions::credentials` are sent with every connect and
+//! `MqttOptions::tls` encrypts the connection (`mqtt-tls` feature),
+//! usually with the certificate of a private CA, see [`TlsConfig`].
+//! Failed connects are logged as warnings telling TLS handshake
+//! failures, rejected credentials and unreachable brokers apart.
+//!
+//! # Connection loss
+//!
+//! A connection thread reconnects on its own, waiting `min_backoff`
+//! after the first failure and doubling the wait up to `max_backoff`
+//! after every further one. After each connect it publishes the
+//! availability and the last state again, so the broker never keeps a
+//! stale state. States published while disconnected are not queued,
+//! only the last one is sent after the reconnect. Changes published
+//! while disconnected are dropped.
+//!
+//! # Groups
+//!
+//! A [`GroupPublisher`] connects one publisher per device of a
+//! `CFF3000Group`, with the options of `MqttOptions::for_device()`: the
+//! topics of the device `garage` are `cff3000/garage/state` and so on,
+//! and each device is announced on its own. `publish_group_changes()`
+//! feeds them from the watch loops of the group.
+
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, Weak};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use rumqttc::{Client, ConnectReturnCode, Connection, ConnectionError, Event, LastWill, Outgoing, Packet, Publish, QoS, Transport};
+use rumqttc::MqttOptions as ClientOptions;
+
+use crate::json::json_string;
+#[cfg(feature = "mqtt-tls")]
+use rumqttc::TlsConfiguration;
+use crate::{Command, CommandQueue, CommandSender, CFF3000, CFF3000Group, CFF3000State, QueueOptions, StateChange, StopToken, WatchOptions};
+
+#[cfg(feature = "mqtt-tls")]
+mod tls;
+
+/// Default TCP port of MQTT brokers.
+pub const DEFAULT_PORT: u16 = 1883;
+/// Default TCP port of MQTT brokers with TLS.
+pub const DEFAULT_TLS_PORT: u16 = 8883;
+/// Default topic of the state messages.
+pub const DEFAULT_STATE_TOPIC: &str = "cff3000/frontdoor/state";
+/// Default topic of the commands, see `MqttPublisher::with_commands()`.
+pub const DEFAULT_COMMAND_TOPIC: &str = "cff3000/frontdoor/set";
+/// Default topic of the availability messages.
+pub const DEFAULT_AVAILABILITY_TOPIC: &str = "cff3000/frontdoor/availability";
+/// Default Home Assistant discovery prefix.
+pub const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+/// Default Homie base topic.
+pub const DEFAULT_HOMIE_BASE: &str = "homie";
+/// Availability payload while the publisher is connected.
+pub const ONLINE: &str = "online";
+/// Availability payload after a disconnect, also the Last Will.
+pub const OFFLINE: &str = "offline";
+
+/// Requests queued for the connection thread, room for the Homie
+/// announcement.
+const QUEUE_CAPACITY: usize = 32;
+/// Time for sending the disconnect when the publisher is dropped.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Broker and topics of an `MqttPublisher`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MqttOptions {
+    pub host: String,
+    pub port: u16,
+    /// MQTT client identifier, unique per broker
+    pub client_id: String,
+    /// User name and password, if the broker requires them
+    pub credentials: Option<(String, String)>,
+    /// Connect with TLS instead of plain TCP, usually on port 8883
+    pub tls: Option<TlsConfig>,
+    pub state_topic: String,
+    pub availability_topic: String,
+    pub command_topic: String,
+    /// Topic of the changes of `MqttPublisher::publish_change()`, none
+    /// by default. Used with every convention.
+    pub change_topic: Option<String>,
+    /// Commands accepted on `command_topic`, others are ignored
+    pub allowed_commands: Vec<Command>,
+    /// How the lock is announced
+    pub convention: Convention,
+    /// Ping interval in whole seconds (0 = none), the broker publishes
+    /// the Last Will after 1.5 times this long without a message
+    pub keep_alive: Duration,
+    /// Wait after the first failed connection attempt
+    pub min_backoff: Duration,
+    /// Longest wait between two connection attempts
+    pub max_backoff: Duration,
+}
+
+impl MqttOptions {
+    /// Options for the broker at `host` on `DEFAULT_PORT` with the
+    /// default topics, all commands allowed and `Convention::Plain`, pinging
+    /// every 30 seconds and retrying after 1 to 60 seconds.
+    pub fn new(host: &str) -> MqttOptions {
+        MqttOptions {
+            host: host.to_string(),
+            port: DEFAULT_PORT,
+            client_id: "cff3000".to_string(),
+            credentials: None,
+            tls: None,
+            state_topic: DEFAULT_STATE_TOPIC.to_string(),
+            availability_topic: DEFAULT_AVAILABILITY_TOPIC.to_string(),
+            command_topic: DEFAULT_COMMAND_TOPIC.to_string(),
+            change_topic: None,
+            allowed_commands: vec![Command::Lock, Command::Unlock, Command::Check],
+            convention: Convention::Plain,
+            keep_alive: Duration::from_secs(30),
+            min_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+
+    /// Options of the device `name` of a group: the level before the
+    /// last of every topic becomes `name` (`cff3000/frontdoor/state`
+    /// becomes `cff3000/garage/state`, `state` becomes `garage/state`)
+    /// and `name` is appended to the client identifier. With
+    /// `Convention::HomeAssistant` it is the node id and the name,
+    /// with `Convention::Homie` the device id (lower case, `_` as `-`)
+    /// and the name.
+    pub fn for_device(&self, name: &str) -> MqttOptions {
+        let convention = match self.convention {
+            Convention::Plain => Convention::Plain,
+            Convention::HomeAssistant(ref discovery) => Convention::HomeAssistant(Discovery {node_id: name.to_string(), name: name.to_string(), ..discovery.clone()}),
+            Convention::Homie(ref homie) => {
+                let device_id = name.to_ascii_lowercase().replace('_', "-");
+                Convention::Homie(Homie {device_id, name: name.to_string(), ..homie.clone()})
+            },
+        };
+        MqttOptions {
+            client_id: format!("{}-{}", self.client_id, name),
+            state_topic: device_topic(&self.state_topic, name),
+            availability_topic: device_topic(&self.availability_topic, name),
+            command_topic: device_topic(&self.command_topic, name),
+            change_topic: self.change_topic.as_ref().map(|topic| device_topic(topic, name)),
+            convention,
+            ..self.clone()
+        }
+    }
+
+    /// Client options, failing if the TLS files cannot be used or TLS
+    /// is not supported.
+    fn client_options(&self) -> std::io::Result<ClientOptions> {
+        if self.keep_alive.subsec_nanos() != 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "MQTT keep alive must be a whole number of seconds"));
+        }
+        let mut options = ClientOptions::new(self.client_id.clone(), self.host.clone(), self.port);
+        options.set_keep_alive(self.keep_alive);
+        let will = match self.convention {
+            Convention::Homie(ref homie) => LastWill::new(homie.topic("$state"), "lost", QoS::AtLeastOnce, true),
+            _ => LastWill::new(self.availability_topic.clone(), OFFLINE, QoS::AtLeastOnce, true),
+        };
+        options.set_last_will(will);
+        if let Some((ref user, ref password)) = self.credentials {
+            options.set_credentials(user.clone(), password.clone());
+        }
+        if let Some(ref config) = self.tls {
+            options.set_transport(transport(config)?);
+        }
+        Ok(options)
+    }
+
+    fn state_topic(&self) -> String {
+        match self.convention {
+            Convention::Homie(ref homie) => homie.topic("lock/state"),
+            _ => self.state_topic.clone(),
+        }
+    }
+
+    fn command_topic(&self) -> String {
+        match self.convention {
+            Convention::Homie(ref homie) => homie.topic("lock/state/set"),
+            _ => self.command_topic.clone(),
+        }
+    }
+
+    /// Topic and payload announcing that the publisher is connected
+    /// (`online`) or not.
+    fn availability(&self, online: bool) -> (String, &'static str) {
+        match self.convention {
+            Convention::Homie(ref homie) => (homie.topic("$state"), if online {"ready"} else {"disconnected"}),
+            _ => (self.availability_topic.clone(), if online {ONLINE} else {OFFLINE}),
+        }
+    }
+}
+
+/// `topic` with `name` as the level before the last.
+fn device_topic(topic: &str, name: &str) -> String {
+    match topic.rfind('/') {
+        Some(last) => match topic[..last].rfind('/') {
+            Some(device) => format!("{}/{}{}", &topic[..device], name, &topic[last..]),
+            None => format!("{}{}", name, &topic[last..]),
+        },
+        None => format!("{}/{}", name, topic),
+    }
+}
+
+/// TLS settings of the broker connection (`mqtt-tls` feature). All
+/// files are PEM encoded.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TlsConfig {
+    /// CA certificates to verify the broker with, required unless
+    /// `insecure_skip_verify` is set
+    pub ca_cert: Option<PathBuf>,
+    /// Certificate chain for client authentication, with `client_key`
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    /// Accept any broker certificate, for testing only: the connection
+    /// is encrypted, but not protected against impersonation
+    pub insecure_skip_verify: bool,
+}
+
+impl TlsConfig {
+    /// Verify the broker with the CA certificates in `ca_cert`.
+    pub fn new<P: Into<PathBuf>>(ca_cert: P) -> TlsConfig {
+        TlsConfig {ca_cert: Some(ca_cert.into()), ..TlsConfig::default()}
+    }
+}
+
+#[cfg(feature = "mqtt-tls")]
+fn transport(config: &TlsConfig) -> std::io::Result<Transport> {
+    Ok(Transport::Tls(TlsConfiguration::Rustls(tls::client_config(config)?)))
+}
+
+#[cfg(not(feature = "mqtt-tls"))]
+fn transport(_: &TlsConfig) -> std::io::Result<Transport> {
+    Err(Error::new(ErrorKind::Unsupported, "MQTT over TLS needs the mqtt-tls feature"))
+}
+
+/// Log message of a failed connect to the broker of `options`.
+fn describe(options: &MqttOptions, err: &ConnectionError) -> String {
+    let broker = format!("{}:{}", options.host, options.port);
+    match *err {
+        #[cfg(feature = "mqtt-tls")]
+        ConnectionError::Tls(ref err) => format!("TLS handshake with MQTT broker {} failed: {}", broker, err),
+        ConnectionError::ConnectionRefused(ConnectReturnCode::BadUserNamePassword) => {
+            format!("MQTT broker {} rejected the user name or password", broker)
+        },
+        ConnectionError::ConnectionRefused(ConnectReturnCode::NotAuthorized) => {
+            format!("MQTT broker {} refused the connection: not authorized", broker)
+        },
+        ConnectionError::ConnectionRefused(code) => format!("MQTT broker {} refused the connection: {:?}", broker, code),
+        ConnectionError::Io(ref err) => format!("cannot connect to MQTT broker {}: {}", broker, err),
+        ref err => format!("connection to MQTT broker {} failed: {}", broker, err),
+    }
+}
+
+/// How an `MqttPublisher` announces the lock, see the module
+/// documentation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Convention {
+    /// Only the topics of `MqttOptions`
+    Plain,
+    /// Home Assistant MQTT discovery
+    HomeAssistant(Discovery),
+    /// Homie 4.0 device
+    Homie(Homie),
+}
+
+/// Home Assistant MQTT discovery of the lock, see `discovery_config()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Discovery {
+    /// Discovery prefix configured in Home Assistant
+    pub prefix: String,
+    /// Part of the config topic and the unique id, only ASCII letters,
+    /// digits, `_` and `-`
+    pub node_id: String,
+    /// Device name shown in Home Assistant
+    pub name: String,
+    /// Area Home Assistant suggests for the device
+    pub suggested_area: Option<String>,
+    /// Remove the entity when the publisher is dropped
+    pub remove_on_shutdown: bool,
+}
+
+impl Discovery {
+    /// Discovery of the lock `node_id` named `name` under
+    /// `DEFAULT_DISCOVERY_PREFIX`, without area and kept on shutdown.
+    pub fn new(node_id: &str, name: &str) -> Discovery {
+        Discovery {
+            prefix: DEFAULT_DISCOVERY_PREFIX.to_string(),
+            node_id: node_id.to_string(),
+            name: name.to_string(),
+            suggested_area: None,
+            remove_on_shutdown: false,
+        }
+    }
+
+    /// Topic of the config, `<prefix>/lock/<node_id>/config`.
+    pub fn topic(&self) -> String {
+        format!("{}/lock/{}/config", self.prefix, self.node_id)
+    }
+}
+
+/// Home Assistant discovery config of a lock entity using the topics of
+/// `options` and the device of `discovery`, e.g. (wrapped here)
+///
+/// ```json
+/// {"name":null,"unique_id":"cff3000_frontdoor",
+///  "state_topic":"cff3000/frontdoor/state","command_topic":"cff3000/frontdoor/set",
+///  "availability_topic":"cff3000/frontdoor/availability",
+///  "payload_available":"online","payload_not_available":"offline",
+///  "payload_lock":"LOCK","payload_unlock":"UNLOCK",
+///  "state_locked":"locked","state_unlocked":"unlocked","state_jammed":"out-of-range",
+///  "value_template":"{{ 'None' if value == 'manual' else value }}",
+///  "optimistic":false,"retain":false,"qos":1,
+///  "device":{"identifiers":["cff3000_frontdoor"],"name":"Front door",
+///            "manufacturer":"ABUS","model":"ABUS CFF3000","suggested_area":"Hallway"}}
+/// ```
+///
+/// `out-of-range` shows as jammed and `manual`, which does not tell
+/// whether the door is locked, as unknown. Commands are not retained,
+/// retained ones would be ignored.
+pub fn discovery_config(options: &MqttOptions, discovery: &Discovery) -> String {
+    fn field(out: &mut String, key: &str, value: &str) {
+        out.push(',');
+        json_string(out, key);
+        out.push(':');
+        json_string(out, value);
+    }
+
+    let mut out = String::from("{\"name\":null");
+    field(&mut out, "unique_id", &discovery.node_id);
+    field(&mut out, "state_topic", &options.state_topic);
+    field(&mut out, "command_topic", &options.command_topic);
+    field(&mut out, "availability_topic", &options.availability_topic);
+    field(&mut out, "payload_available", ONLINE);
+    field(&mut out, "payload_not_available", OFFLINE);
+    field(&mut out, "payload_lock", "LOCK");
+    field(&mut out, "payload_unlock", "UNLOCK");
+    field(&mut out, "state_locked", CFF3000State::Locked.name());
+    field(&mut out, "state_unlocked", CFF3000State::Unlocked.name());
+    field(&mut out, "state_jammed", CFF3000State::OutOfRange.name());
+    field(&mut out, "value_template", &format!("{{{{ 'None' if value == '{}' else value }}}}", CFF3000State::Manual.name()));
+    out.push_str(",\"optimistic\":false,\"retain\":false,\"qos\":1,\"device\":{\"identifiers\":[");
+    json_string(&mut out, &discovery.node_id);
+    out.push_str("],\"name\":");
+    json_string(&mut out, &discovery.name);
+    field(&mut out, "manufacturer", "ABUS");
+    field(&mut out, "model", "ABUS CFF3000");
+    if let Some(ref area) = discovery.suggested_area {
+        field(&mut out, "suggested_area", area);
+    }
+    out.push_str("}}");
+    out
+}
+
+/// Homie 4.0 device of the lock.
+///
+/// The device `<base>/<device_id>` has one node `lock` with the
+/// properties
+///
+/// * `state`: enum of the state names, settable with the commands
+///   `LOCK`, `UNLOCK` and `CHECK` or with `locked` and `unlocked`,
+/// * `battery-low`: boolean, not settable.
+///
+/// The parser does not detect battery warnings, `battery-low` is only
+/// published once set with `MqttPublisher::publish_battery_low()`.
+///
+/// After every connect the device goes through `$state` `init`, the
+/// attributes and property values, and `ready`. It is `disconnected`
+/// after a clean shutdown and `lost` (the Last Will) otherwise.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Homie {
+    /// Base topic, `DEFAULT_HOMIE_BASE` by convention
+    pub base: String,
+    /// Device ID, only lower case ASCII letters, digits and `-`
+    pub device_id: String,
+    /// Device name shown by controllers
+    pub name: String,
+}
+
+impl Homie {
+    /// Device `device_id` named `name` under `DEFAULT_HOMIE_BASE`.
+    pub fn new(device_id: &str, name: &str) -> Homie {
+        Homie {base: DEFAULT_HOMIE_BASE.to_string(), device_id: device_id.to_string(), name: name.to_string()}
+    }
+
+    /// Topic `path` of the device, e.g. `homie/frontdoor/$state`.
+    pub fn topic(&self, path: &str) -> String {
+        format!("{}/{}/{}", self.base, self.device_id, path)
+    }
+
+    /// Retained attributes announcing the device, in publishing order
+    /// and without `$state`.
+    pub fn attributes(&self) -> Vec<(String, String)> {
+        let states = [CFF3000State::Locked, CFF3000State::Unlocked, CFF3000State::Manual, CFF3000State::OutOfRange];
+        let format: Vec<&str> = states.iter().map(|state| state.name()).collect();
+        [
+            ("$homie", "4.0.0"),
+            ("$name", &self.name),
+            ("$extensions", ""),
+            ("$nodes", "lock"),
+            ("lock/$name", "Lock"),
+            ("lock/$type", "ABUS CFF3000"),
+            ("lock/$properties", "state,battery-low"),
+            ("lock/state/$name", "State"),
+            ("lock/state/$datatype", "enum"),
+            ("lock/state/$format", &format.join(",")),
+            ("lock/state/$settable", "true"),
+            ("lock/battery-low/$name", "Battery low"),
+            ("lock/battery-low/$datatype", "boolean"),
+        ].iter().map(|&(path, value)| (self.topic(path), value.to_string())).collect()
+    }
+}
+
+/// Command named by an MQTT `payload`: `LOCK`, `UNLOCK` or `CHECK`,
+/// with surrounding whitespace ignored.
+pub fn parse_command(payload: &[u8]) -> Option<Command> {
+    match std::str::from_utf8(payload).map(str::trim) {
+        Ok("LOCK") => Some(Command::Lock),
+        Ok("UNLOCK") => Some(Command::Unlock),
+        Ok("CHECK") => Some(Command::Check),
+        _ => None,
+    }
+}
+
+/// Result of a queued command.
+type Reply = mpsc::Receiver<std::io::Result<CFF3000State>>;
+
+struct Status {
+    connected: bool,
+    /// Last published state, sent again after every connect
+    current: Option<CFF3000State>,
+    /// Last published battery warning, Homie only
+    battery_low: Option<bool>,
+}
+
+struct Shared {
+    options: MqttOptions,
+    client: Client,
+    status: Mutex<Status>,
+    stop: StopToken,
+    /// Queue of the accepted commands, None without commands
+    commands: Option<CommandSender>,
+    /// Replies for the results thread
+    replies: Mutex<Option<mpsc::Sender<Reply>>>,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Status> {
+        self.status.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn send(&self, topic: &str, payload: &str) -> std::io::Result<()> {
+        self.client.try_publish(topic, QoS::AtLeastOnce, true, payload)
+            .map_err(Error::other)
+    }
+
+    fn publish(&self, state: CFF3000State) -> std::io::Result<()> {
+        let mut status = self.lock();
+        status.current = Some(state);
+        match status.connected {
+            true => self.send(&self.options.state_topic(), state.name()),
+            false => Ok(()),
+        }
+    }
+
+    fn publish_change(&self, change: &StateChange) -> std::io::Result<()> {
+        let result = self.publish(change.current);
+        match self.options.change_topic {
+            Some(ref topic) if self.lock().connected => {
+                let mut json = String::new();
+                change.write_json(&mut json);
+                result.and(self.send(topic, &json))
+            },
+            _ => result,
+        }
+    }
+
+    fn publish_battery_low(&self, low: bool) -> std::io::Result<()> {
+        let mut status = self.lock();
+        status.battery_low = Some(low);
+        match self.options.convention {
+            Convention::Homie(ref homie) if status.connected => self.send(&homie.topic("lock/battery-low"), &low.to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Announce the lock and its state after a connect.
+    fn announce(&self, status: &Status) {
+        let options = &self.options;
+        let state = status.current.map(|state| (options.state_topic(), state.name()));
+        match options.convention {
+            Convention::Homie(ref homie) => {
+                /* ready only once all values are known */
+                let _ = self.send(&homie.topic("$state"), "init");
+                for (topic, value) in homie.attributes() {
+                    let _ = self.send(&topic, &value);
+                }
+                if let Some((ref topic, name)) = state {
+                    let _ = self.send(topic, name);
+                }
+                if let Some(low) = status.battery_low {
+                    let _ = self.send(&homie.topic("lock/battery-low"), &low.to_string());
+                }
+                let _ = self.send(&homie.topic("$state"), "ready");
+            },
+            ref convention => {
+                if let Convention::HomeAssistant(ref discovery) = *convention {
+                    let _ = self.send(&discovery.topic(), &discovery_config(options, discovery));
+                }
+                let _ = self.send(&options.availability_topic, ONLINE);
+                if let Some((ref topic, name)) = state {
+                    let _ = self.send(topic, name);
+                }
+            },
+        }
+        if self.commands.is_some() {
+            let _ = self.client.try_subscribe(options.command_topic(), QoS::AtLeastOnce);
+        }
+    }
+
+    /// Queue the command of `message`, if it is acceptable.
+    fn command(&self, message: &Publish) {
+        let sender = match self.commands {
+            Some(ref sender) if message.topic == self.options.command_topic() => sender,
+            _ => return,
+        };
+        let payload = String::from_utf8_lossy(&message.payload);
+        let parsed = match (&self.options.convention, &*message.payload) {
+            (&Convention::Homie(_), b"locked") => Some(Command::Lock),
+            (&Convention::Homie(_), b"unlocked") => Some(Command::Unlock),
+            (_, payload) => parse_command(payload),
+        };
+        let command = match parsed {
+            _ if message.retain => return log::warn!("ignoring retained MQTT command {:?}", payload),
+            None => return log::warn!("ignoring malformed MQTT command {:?}", payload),
+            Some(command) if !self.options.allowed_commands.contains(&command) => {
+                return log::warn!("ignoring MQTT command {:?}, it is not allowed", payload)
+            },
+            Some(command) => command,
+        };
+        let initiator = format!("mqtt:{}:{}/{}", self.options.host, self.options.port, message.topic);
+        let reply = sender.send_as(command, &initiator);
+        if let Some(ref replies) = *self.replies.lock().unwrap_or_else(|e| e.into_inner()) {
+            let _ = replies.send(reply);
+        }
+    }
+}
+
+/// Connection to an MQTT broker publishing the door state, see the
+/// module documentation.
+pub struct MqttPublisher {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MqttPublisher {
+    /// Start connecting to the broker of `options` in the background.
+    /// Fails only if the TLS settings cannot be used or the connection
+    /// thread cannot be started, an unreachable broker is retried
+    /// forever.
+    pub fn new(options: MqttOptions) -> std::io::Result<MqttPublisher> {
+        MqttPublisher::start(options, None)
+    }
+
+    /// Like `new()`, but also execute the commands received on the
+    /// command topic through `sender` and publish their results, see
+    /// the module documentation.
+    pub fn with_commands(options: MqttOptions, sender: CommandSender) -> std::io::Result<MqttPublisher> {
+        MqttPublisher::start(options, Some(sender))
+    }
+
+    fn start(options: MqttOptions, commands: Option<CommandSender>) -> std::io::Result<MqttPublisher> {
+        let (client, connection) = Client::new(options.client_options()?, QUEUE_CAPACITY);
+        let (replies, results) = mpsc::channel();
+        let shared = Arc::new(Shared {
+            options,
+            client,
+            status: Mutex::new(Status {connected: false, current: None, battery_low: None}),
+            stop: StopToken::new(),
+            replies: Mutex::new(commands.as_ref().map(|_| replies)),
+            commands,
+        });
+        if shared.commands.is_some() {
+            /* not joined, a reply may take a whole queue of commands */
+            let shared = Arc::downgrade(&shared);
+            std::thread::Builder::new().name("cff3000-mqtt-results".to_string()).spawn(move || publish_results(&shared, results))?;
+        }
+        let thread = {
+            let shared = shared.clone();
+            std::thread::Builder::new().name("cff3000-mqtt".to_string()).spawn(move || run(&shared, connection))?
+        };
+        Ok(MqttPublisher {shared, thread: Some(thread)})
+    }
+
+    /// Publish `state` as retained message. While disconnected it is
+    /// only remembered and published after the reconnect. Fails if the
+    /// request queue is full.
+    pub fn publish(&self, state: CFF3000State) -> std::io::Result<()> {
+        self.shared.publish(state)
+    }
+
+    /// Publish the state of `change` like `publish()` and the change to
+    /// `MqttOptions::change_topic`, if set and connected, see the module
+    /// documentation.
+    pub fn publish_change(&self, change: &StateChange) -> std::io::Result<()> {
+        self.shared.publish_change(change)
+    }
+
+    /// Publish whether the battery is low, like `publish()`. Only
+    /// `Convention::Homie` has a property for it, the others only
+    /// remember it.
+    pub fn publish_battery_low(&self, low: bool) -> std::io::Result<()> {
+        self.shared.publish_battery_low(low)
+    }
+
+    /// Returns true while the broker has accepted the connection.
+    pub fn is_connected(&self) -> bool {
+        self.shared.lock().connected
+    }
+
+    /// Publish `offline` (Homie: `disconnected`), disconnect and stop
+    /// the connection thread, like dropping the publisher. Fails if the
+    /// messages cannot be queued, the broker then publishes the Last
+    /// Will once the keep alive runs out.
+    pub fn shutdown(mut self) -> std::io::Result<()> {
+        self.close()
+    }
+
+    fn close(&mut self) -> std::io::Result<()> {
+        let mut result = Ok(());
+        /* only once, also if shutdown() failed */
+        if std::mem::replace(&mut self.shared.lock().connected, false) {
+            if let Convention::HomeAssistant(ref discovery) = self.shared.options.convention {
+                if discovery.remove_on_shutdown {
+                    let _ = self.shared.send(&discovery.topic(), "");
+                }
+            }
+            let (topic, payload) = self.shared.options.availability(false);
+            result = self.shared.send(&topic, payload)
+                .and_then(|_| self.shared.client.try_disconnect().map_err(Error::other));
+        }
+        /* after the requests, the thread sends them before it stops */
+        self.shared.stop.stop();
+        self.shared.replies.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        result
+    }
+}
+
+impl Drop for MqttPublisher {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+/// Drive `connection` until the publisher disconnects or is dropped.
+fn run(shared: &Shared, mut connection: Connection) {
+    let mut backoff = shared.options.min_backoff;
+    /* fails once all clients are gone, but `shared` holds one */
+    while let Ok(event) = connection.recv() {
+        match event {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                backoff = shared.options.min_backoff;
+                let mut status = shared.lock();
+                status.connected = true;
+                shared.announce(&status);
+            },
+            Ok(Event::Incoming(Packet::Publish(ref message))) => shared.command(message),
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                /* the packet is only queued, flush it until the broker hangs up */
+                let _ = connection.recv_timeout(DISCONNECT_TIMEOUT);
+                return;
+            },
+            Ok(_) => {},
+            Err(err) => {
+                log::warn!("{}", describe(&shared.options, &err));
+                shared.lock().connected = false;
+                if shared.stop.wait_timeout(backoff) {
+                    return;
+                }
+                backoff = std::cmp::min(backoff * 2, shared.options.max_backoff);
+            },
+        }
+    }
+}
+
+/// Publish the states of `results` until the publisher is gone.
+fn publish_results(shared: &Weak<Shared>, results: mpsc::Receiver<Reply>) {
+    for reply in results {
+        match reply.recv() {
+            Ok(Ok(state)) => match shared.upgrade() {
+                Some(shared) => {let _ = shared.publish(state);},
+                None => return,
+            },
+            Ok(Err(err)) => log::warn!("MQTT command failed: {}", err),
+            Err(_) => {},
+        }
+    }
+}
+
+/// Watch `cff3000` like `CFF3000::watch_with_options()` and publish
+/// every state change to `publisher` until `stop` is stopped.
+///
+/// Publishing errors do not end the watch loop, the next change or
+/// reconnect publishes the state again.
+pub fn publish_changes(cff3000: &CFF3000, options: &WatchOptions, stop: &StopToken, publisher: &MqttPublisher) -> std::io::Result<()> {
+    cff3000.watch_with_options(options, stop, |change| {
+        let _ = publisher.publish_change(&change);
+    })
+}
+
+/// Publishers of the devices of a `CFF3000Group`, see "Groups".
+pub struct GroupPublisher {
+    publishers: BTreeMap<String, MqttPublisher>,
+    /* dropped after the publishers sending to them */
+    _queues: Vec<CommandQueue>,
+}
+
+impl GroupPublisher {
+    /// Start a publisher for every device of `group` with the options
+    /// of `MqttOptions::for_device()`, like `MqttPublisher::new()`.
+    pub fn new(group: &CFF3000Group, options: &MqttOptions) -> std::io::Result<GroupPublisher> {
+        let mut publishers = BTreeMap::new();
+        for name in group.names() {
+            publishers.insert(name.clone(), MqttPublisher::new(options.for_device(name))?);
+        }
+        Ok(GroupPublisher {publishers, _queues: Vec::new()})
+    }
+
+    /// Like `new()`, but also execute the commands of every device
+    /// through a `CommandQueue` of `queue` like
+    /// `MqttPublisher::with_commands()`.
+    pub fn with_commands(group: &CFF3000Group, options: &MqttOptions, queue: QueueOptions) -> std::io::Result<GroupPublisher> {
+        let mut publishers = BTreeMap::new();
+        let mut queues = Vec::with_capacity(group.len());
+        for (name, cff3000) in group.iter() {
+            let commands = CommandQueue::with_options(cff3000.clone(), queue)?;
+            publishers.insert(name.clone(), MqttPublisher::with_commands(options.for_device(name), commands.sender())?);
+            queues.push(commands);
+        }
+        Ok(GroupPublisher {publishers, _queues: queues})
+    }
+
+    /// Publisher of the device `name`, if there is one.
+    pub fn get(&self, name: &str) -> Option<&MqttPublisher> {
+        self.publishers.get(name)
+    }
+}
+
+/// Watch the devices of `group` like `CFF3000Group::watch_all()` and
+/// publish every change with the publisher of its device until `stop`
+/// is stopped, like `publish_changes()`.
+pub fn publish_group_changes(group: &CFF3000Group, options: &WatchOptions, stop: &StopToken, publisher: &GroupPublisher) -> std::io::Result<()> {
+    group.watch_all(options, stop, |name, change| {
+        if let Some(publisher) = publisher.get(name) {
+            let _ = publisher.publish_change(&change);
+        }
+    })
+}
@@ -0,0 +1,100 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! rustls client configuration of `TlsConfig` (`mqtt-tls` feature).
+
+use std::io::{BufReader, Error, ErrorKind};
+use std::path::Path;
+use std::sync::Arc;
+
+use rumqttc::tokio_rustls::rustls;
+use self::rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use self::rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms};
+use self::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use self::rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+
+use super::TlsConfig;
+
+/// Error about the file at `path`.
+fn file_error(path: &Path, kind: ErrorKind, message: &str) -> Error {
+    Error::new(kind, format!("{}: {}", path.display(), message))
+}
+
+fn certificates(path: &Path) -> std::io::Result<Vec<CertificateDer<'static>>> {
+    let file = std::fs::File::open(path).map_err(|err| file_error(path, err.kind(), &err.to_string()))?;
+    let certs: Vec<_> = (rustls_pemfile::certs(&mut BufReader::new(file)).collect::<Result<_, _>>()
+        .map_err(|err| file_error(path, ErrorKind::InvalidData, &err.to_string())))?;
+    match certs.is_empty() {
+        true => Err(file_error(path, ErrorKind::InvalidData, "no PEM certificate found")),
+        false => Ok(certs),
+    }
+}
+
+fn private_key(path: &Path) -> std::io::Result<PrivateKeyDer<'static>> {
+    let file = std::fs::File::open(path).map_err(|err| file_error(path, err.kind(), &err.to_string()))?;
+    match rustls_pemfile::private_key(&mut BufReader::new(file)) {
+        Ok(Some(key)) => Ok(key),
+        Ok(None) => Err(file_error(path, ErrorKind::InvalidData, "no PEM private key found")),
+        Err(err) => Err(file_error(path, ErrorKind::InvalidData, &err.to_string())),
+    }
+}
+
+/// Accepts every server certificate, see `TlsConfig::insecure_skip_verify`.
+/// The handshake signatures are still checked, only the chain and the
+/// name are not.
+#[derive(Debug)]
+struct SkipVerification(WebPkiSupportedAlgorithms);
+
+impl ServerCertVerifier for SkipVerification {
+    fn verify_server_cert(&self, _: &CertificateDer<'_>, _: &[CertificateDer<'_>], _: &ServerName<'_>, _: &[u8], _: UnixTime)
+                          -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+                              -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+                              -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_schemes()
+    }
+}
+
+/// Client configuration of `tls`, reading the certificates and the key.
+/// Unreadable files fail with their error kind, files without usable
+/// PEM data with `ErrorKind::InvalidData`, both naming the file.
+pub(super) fn client_config(tls: &TlsConfig) -> std::io::Result<Arc<ClientConfig>> {
+    let builder = match (tls.insecure_skip_verify, tls.ca_cert.as_ref()) {
+        (true, _) => {
+            let verifier = SkipVerification(ring::default_provider().signature_verification_algorithms);
+            ClientConfig::builder().dangerous().with_custom_certificate_verifier(Arc::new(verifier))
+        },
+        (false, Some(path)) => {
+            let mut roots = RootCertStore::empty();
+            let (_, ignored) = roots.add_parsable_certificates(certificates(path)?);
+            if roots.is_empty() {
+                return Err(file_error(path, ErrorKind::InvalidData, &format!("none of {} certificates is usable", ignored)));
+            }
+            ClientConfig::builder().with_root_certificates(roots)
+        },
+        (false, None) => {
+            return Err(Error::new(ErrorKind::InvalidInput, "TLS needs a CA certificate unless verification is skipped"));
+        },
+    };
+
+    let config = match (tls.client_cert.as_ref(), tls.client_key.as_ref()) {
+        (Some(cert), Some(key)) => {
+            let (certs, key) = (certificates(cert)?, private_key(key)?);
+            builder.with_client_auth_cert(certs, key).map_err(|err| file_error(cert, ErrorKind::InvalidData, &err.to_string()))?
+        },
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err(Error::new(ErrorKind::InvalidInput, "TLS client authentication needs both a certificate and a key")),
+    };
+    Ok(Arc::new(config))
+}
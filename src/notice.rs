@@ -0,0 +1,95 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Out-of-band notices for the monitor callback.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::health::HealthProblem;
+use crate::{CFF3000State, LedEvent, LineRole};
+
+/// Noteworthy event which is not the result of an operation, reported
+/// to the callback registered with `CFF3000Builder::monitor()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Notice {
+    /// The GPIO chip disappeared and has been reopened at `chipdev`
+    /// (see `CFF3000Builder::auto_reopen()`)
+    Reconnected {chipdev: String},
+    /// The device node `chipdev` has been removed (see
+    /// `CFF3000Builder::watch_device_node()`)
+    DeviceLost {chipdev: String},
+    /// A button line would have been set to the electrical `level`
+    /// (true = high), but has not been touched (see
+    /// `CFF3000Builder::dry_run()`). `chipdev` and `offset` are unknown
+    /// for custom backends. `held` is how long the button would have
+    /// been pressed, for the write releasing it.
+    DryRun {chipdev: Option<String>, role: LineRole, offset: Option<u32>, level: bool, held: Option<Duration>},
+    /// The lock button is pressed while the door sensor shows an open
+    /// door (see `door::OpenDoorPolicy::Warn`)
+    DoorOpen,
+    /// The LEDs have shown a pattern no operation of the device has
+    /// started, e.g. after a press on the key remote (see
+    /// `CFF3000::listen_for_activity()`). `classified` is the state the
+    /// parser reads from `events`, if any.
+    ExternalActivity {classified: Option<CFF3000State>, events: Vec<LedEvent>},
+    /// A probe of the health check found `problems`, reported when they
+    /// change (see `CFF3000Builder::health_check()`)
+    HealthDegraded {problems: Vec<HealthProblem>},
+    /// A probe found nothing wrong after `HealthDegraded`
+    HealthRestored,
+}
+
+/// Name of the line with `role` for messages, e.g. "LED red".
+pub(crate) fn role_name(role: LineRole) -> &'static str {
+    match role {
+        LineRole::LedRed => "LED red",
+        LineRole::LedGreen => "LED green",
+        LineRole::ButtonUnlock => "button unlock",
+        LineRole::ButtonLock => "button lock",
+    }
+}
+
+/// One line for logs, e.g. "dry run: /dev/gpiochip2 line 5 (button
+/// lock) low after 500 ms".
+impl fmt::Display for Notice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Notice::Reconnected {ref chipdev} => write!(f, "reconnected to {}", chipdev),
+            Notice::DeviceLost {ref chipdev} => write!(f, "{} has been removed", chipdev),
+            Notice::DryRun {ref chipdev, role, offset, level, held} => {
+                f.write_str("dry run: ")?;
+                if let Some(ref chipdev) = *chipdev {
+                    write!(f, "{} ", chipdev)?;
+                }
+                let role = role_name(role);
+                match offset {
+                    Some(offset) => write!(f, "line {} ({})", offset, role)?,
+                    None => f.write_str(role)?,
+                }
+                f.write_str(if level {" high"} else {" low"})?;
+                match held {
+                    Some(held) => write!(f, " after {} ms", held.as_millis()),
+                    None => Ok(()),
+                }
+            },
+            Notice::DoorOpen => f.write_str("locking while the door is open"),
+            Notice::ExternalActivity {classified, ref events} => match classified {
+                Some(state) => write!(f, "external activity: {} ({} LED events)", state.name(), events.len()),
+                None => write!(f, "external activity: {} LED events, no known pattern", events.len()),
+            },
+            Notice::HealthDegraded {ref problems} => {
+                f.write_str("health check failed: ")?;
+                for (i, problem) in problems.iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 {""} else {"; "}, problem)?;
+                }
+                Ok(())
+            },
+            Notice::HealthRestored => f.write_str("health check passed again"),
+        }
+    }
+}
+
+/// Monitor callback shared with the components emitting notices.
+pub(crate) type Monitor = Arc<dyn Fn(&Notice) + Send + Sync>;
@@ -0,0 +1,199 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Last state and operation counters kept across restarts of the
+//! `daemon` (`daemon` feature).
+//!
+//! A [`StateFile`] holds a [`Snapshot`]: the last verified state with
+//! the time of its verification, the counters of the `metrics` and the
+//! commands sent on the batteries (see `battery`). The daemon loads it
+//! on startup to prime the state cache of the device (see
+//! `CFF3000Builder::state_cache()`), the metrics and the battery count,
+//! and replaces it with every state it passes on, after a battery
+//! change and when it stops. The path is the `state_file` setting of
+//! the configuration file.
+//!
+//! The file is TOML:
+//!
+//! ```toml
+//! schema = 1
+//!
+//! [last]
+//! state = "locked"
+//! timestamp_ms = 1760000000123
+//!
+//! [battery]
+//! since_ms = 1750000000456
+//! replaced = true
+//! commands = 1210
+//!
+//! [commands]
+//! check = 12
+//! lock = 3
+//!
+//! [failures.check]
+//! no-response = 2
+//! ```
+//!
+//! Unknown keys are ignored, so new settings can be added without
+//! breaking older daemons. `schema` only changes with an incompatible
+//! format, files of other schemas are read as missing. Files of older
+//! daemons name it `version`, which is still read. A missing,
+//! unreadable or corrupt file is not an error: the daemon starts
+//! without a state, as if it has never run.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::battery::BatteryMarker;
+use crate::CFF3000State;
+
+/// `schema` of the files written and read by `StateFile`.
+pub const STATE_FILE_SCHEMA: u32 = 1;
+
+/// A state with the time it has been verified.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LastState {
+    pub state: CFF3000State,
+    pub timestamp: SystemTime,
+}
+
+impl LastState {
+    /// Time since the verification, `None` if the timestamp is in the
+    /// future (e.g. after the clock has been set back).
+    pub fn age(&self) -> Option<Duration> {
+        SystemTime::now().duration_since(self.timestamp).ok()
+    }
+}
+
+/// Values of the counters of `metrics::Metrics`.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Counters {
+    /// `cff3000_commands_total` by command, e.g. "lock"
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub commands: BTreeMap<String, u64>,
+    /// `cff3000_command_failures_total` by command and code, e.g.
+    /// "check" and "no-response"
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub failures: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+/// Contents of a `StateFile`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Snapshot {
+    pub last: Option<LastState>,
+    pub counters: Counters,
+    pub battery: Option<BatteryMarker>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FileLast {
+    state: String,
+    timestamp_ms: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FileBattery {
+    since_ms: u64,
+    #[serde(default, skip_serializing_if = "is_false")]
+    replaced: bool,
+    commands: u64,
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+#[derive(Serialize, Deserialize)]
+struct File {
+    #[serde(alias = "version")]
+    schema: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last: Option<FileLast>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    battery: Option<FileBattery>,
+    #[serde(flatten)]
+    counters: Counters,
+}
+
+/// Milliseconds since the epoch of `time`, 0 before it.
+fn millis(time: SystemTime) -> u64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    since_epoch.as_secs() * 1000 + since_epoch.subsec_millis() as u64
+}
+
+/// Number of `StateFile::store()` calls, part of the temporary name.
+static WRITERS: AtomicUsize = AtomicUsize::new(0);
+
+/// State file of the daemon, see the module documentation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateFile {
+    path: PathBuf,
+}
+
+impl StateFile {
+    /// State file at `path`.
+    pub fn new<P: AsRef<Path>>(path: P) -> StateFile {
+        StateFile {path: path.as_ref().to_path_buf()}
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The stored snapshot, `None` if there is none or the file is not
+    /// readable, corrupt or of another schema.
+    pub fn load(&self) -> Option<Snapshot> {
+        let text = std::fs::read_to_string(&self.path).ok()?;
+        let file: File = toml::from_str(&text).ok()?;
+        if file.schema != STATE_FILE_SCHEMA {
+            return None;
+        }
+        let last = match file.last {
+            Some(last) => Some(LastState {
+                state: CFF3000State::from_name(&last.state)?,
+                timestamp: UNIX_EPOCH + Duration::from_millis(last.timestamp_ms),
+            }),
+            None => None,
+        };
+        let battery = file.battery.map(|battery| BatteryMarker {
+            since: UNIX_EPOCH + Duration::from_millis(battery.since_ms),
+            replaced: battery.replaced,
+            commands: battery.commands,
+        });
+        Some(Snapshot {last, counters: file.counters, battery})
+    }
+
+    /// Replace the stored snapshot. The directory must exist.
+    ///
+    /// The new file is written and synced under a temporary name and
+    /// renamed, so a crash leaves either the old or the new snapshot.
+    pub fn store(&self, snapshot: &Snapshot) -> std::io::Result<()> {
+        let last = snapshot.last.map(|last| FileLast {state: last.state.name().to_string(), timestamp_ms: millis(last.timestamp)});
+        let battery = snapshot.battery.map(|battery| FileBattery {
+            since_ms: millis(battery.since),
+            replaced: battery.replaced,
+            commands: battery.commands,
+        });
+        let file = File {schema: STATE_FILE_SCHEMA, last, battery, counters: snapshot.counters.clone()};
+        let text = toml::to_string(&file).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+
+        /* unique per writer, so concurrent writers do not share it */
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(format!(".{}-{}.tmp", std::process::id(), WRITERS.fetch_add(1, Ordering::Relaxed)));
+        let temporary = PathBuf::from(temporary);
+        let written = std::fs::File::create(&temporary).and_then(|mut out| {
+            out.write_all(text.as_bytes())?;
+            out.sync_all()
+        });
+        written.and_then(|_| std::fs::rename(&temporary, &self.path)).inspect_err(|_| {
+            let _ = std::fs::remove_file(&temporary);
+        })
+    }
+}
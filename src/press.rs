@@ -0,0 +1,183 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Two-phase button presses.
+//!
+//! A [`PressGuard`] keeps one or more button lines asserted without
+//! blocking the calling thread. Releasing (or dropping) the guard before
+//! the minimum press duration has elapsed hands the release over to a
+//! shared timer thread, so the remote control always sees a complete
+//! press and the lines are always released again.
+
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::clock::{deadline, until, Clock, SharedClock};
+use crate::interlock::OperationGuard;
+use crate::{Button, GpioBackend};
+
+/// Pressed button(s), released on `release()` or drop.
+pub struct PressGuard {
+    backend: Arc<dyn GpioBackend>,
+    lines: Vec<Button>,
+    deadline: Instant,
+    clock: SharedClock,
+    busy: Option<OperationGuard>,
+}
+
+impl PressGuard {
+    /// Assert all `lines` (in order) for at least `duration` on `clock`,
+    /// keeping the device `busy` until they have been released.
+    pub(crate) fn press(backend: Arc<dyn GpioBackend>, lines: Vec<Button>, duration: Duration, clock: SharedClock, busy: OperationGuard) -> std::io::Result<PressGuard> {
+        for i in 0..lines.len() {
+            if let Err(err) = backend.set_button(lines[i], true) {
+                let _ = release_lines(&*backend, &lines[..i]);
+                return Err(err);
+            }
+        }
+        let deadline = deadline(clock.now(), duration);
+        Ok(PressGuard {backend, lines, deadline, clock, busy: Some(busy)})
+    }
+
+    /// Point in time (of the device's clock) at which the minimum press
+    /// duration has elapsed.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Time left until the minimum press duration has elapsed.
+    pub fn remaining(&self) -> Duration {
+        until(self.clock.now(), self.deadline)
+    }
+
+    /// Returns true once the buttons can be released immediately.
+    pub fn is_due(&self) -> bool {
+        self.clock.now() >= self.deadline
+    }
+
+    /// Release the button(s).
+    ///
+    /// This never blocks. If the minimum press duration has not yet
+    /// elapsed, the release is scheduled on the shared timer thread and
+    /// errors from setting the lines can no longer be reported.
+    pub fn release(mut self) -> std::io::Result<()> {
+        let lines = std::mem::take(&mut self.lines);
+        finish(self.backend.clone(), lines, self.remaining(), &*self.clock, self.busy.take())
+    }
+}
+
+impl Drop for PressGuard {
+    fn drop(&mut self) {
+        let lines = std::mem::take(&mut self.lines);
+        let remaining = self.remaining();
+        let _ = finish(self.backend.clone(), lines, remaining, &*self.clock, self.busy.take());
+    }
+}
+
+/// Release `lines` now, or after `remaining` if that is not zero.
+/// `busy` is dropped once the lines have been released.
+///
+/// The timer thread works on real time, so a release deferred on a
+/// virtual clock happens after the same amount of real time.
+fn finish(backend: Arc<dyn GpioBackend>, lines: Vec<Button>, remaining: Duration, clock: &dyn Clock, busy: Option<OperationGuard>) -> std::io::Result<()> {
+    if lines.is_empty() {
+        return Ok(());
+    }
+
+    if remaining == Duration::from_millis(0) {
+        return release_lines(&*backend, &lines);
+    }
+
+    match schedule(Release {deadline: deadline(Instant::now(), remaining), backend, lines, _busy: busy}) {
+        Ok(()) => Ok(()),
+        Err(release) => {
+            /* no timer thread available, keep the guarantee by blocking */
+            clock.sleep(remaining);
+            release_lines(&*release.backend, &release.lines)
+        },
+    }
+}
+
+/// Release `lines` in reverse order, trying every line even if one fails.
+fn release_lines(backend: &dyn GpioBackend, lines: &[Button]) -> std::io::Result<()> {
+    let mut result = Ok(());
+    for &line in lines.iter().rev() {
+        if let Err(err) = backend.set_button(line, false) {
+            if result.is_ok() {
+                result = Err(err);
+            }
+        }
+    }
+    result
+}
+
+struct Release {
+    deadline: Instant,
+    backend: Arc<dyn GpioBackend>,
+    lines: Vec<Button>,
+    _busy: Option<OperationGuard>,
+}
+
+static TIMER: OnceLock<Option<Mutex<mpsc::Sender<Release>>>> = OnceLock::new();
+
+/// Hand `release` over to the timer thread, which is started on first use.
+fn schedule(release: Release) -> Result<(), Release> {
+    let timer = TIMER.get_or_init(|| {
+        let (tx, rx) = mpsc::channel();
+        match std::thread::Builder::new().name("cff3000-release".to_string()).spawn(move || timer_thread(rx)) {
+            Ok(_) => Some(Mutex::new(tx)),
+            Err(_) => None,
+        }
+    });
+
+    let sender = match *timer {
+        Some(ref sender) => sender,
+        None => return Err(release),
+    };
+
+    let sender = match sender.lock() {
+        Ok(sender) => sender,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+
+    sender.send(release).map_err(|err| err.0)
+}
+
+fn timer_thread(rx: mpsc::Receiver<Release>) {
+    let mut pending: Vec<Release> = Vec::new();
+
+    loop {
+        let now = Instant::now();
+        let mut i = 0;
+        while i < pending.len() {
+            if pending[i].deadline <= now {
+                let release = pending.swap_remove(i);
+                let _ = release_lines(&*release.backend, &release.lines);
+            } else {
+                i += 1;
+            }
+        }
+
+        let next = pending.iter().map(|r| r.deadline).min();
+        let received = match next {
+            Some(deadline) => rx.recv_timeout(deadline - now),
+            None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+
+        match received {
+            Ok(release) => pending.push(release),
+            Err(mpsc::RecvTimeoutError::Timeout) => {},
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                /* the sender lives in a static, but release anything left just in case */
+                for release in pending.drain(..) {
+                    let now = Instant::now();
+                    if release.deadline > now {
+                        std::thread::sleep(release.deadline - now);
+                    }
+                    let _ = release_lines(&*release.backend, &release.lines);
+                }
+                return;
+            },
+        }
+    }
+}
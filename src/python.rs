@@ -0,0 +1,350 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Python module (`python` feature).
+//!
+//! The `cff3000` extension module wraps `CFF3000` for Python code,
+//! built with maturin from `python/pyproject.toml`:
+//!
+//! ```sh
+//! cd python && maturin develop
+//! pytest tests
+//! ```
+//!
+//! ```python
+//! import cff3000
+//!
+//! door = cff3000.CFF3000("/dev/gpiochip0", (17, 27, 22, 23))
+//! if door.state() == cff3000.State.UNLOCKED:
+//!     door.lock()
+//! for change in door.watch(poll_interval=60.0):
+//!     print(change.previous, "->", change.current)
+//! ```
+//!
+//! The calls block like those of `CFF3000`, without holding the GIL,
+//! so other Python threads keep running. `watch()` returns a generator
+//! of `StateChange`s watched by a thread of its own, which stops once
+//! the generator is closed or collected.
+//!
+//! Failures raise a subclass of `cff3000.CFF3000Error` for the
+//! `cli::ErrorCode`s, e.g. `NoResponseError` if the LEDs did not show a
+//! pattern:
+//!
+//! | Exception | Code |
+//! |-----------|------|
+//! | `ConfigError` | `config` |
+//! | `DeviceNotFoundError` | `not-found` |
+//! | `PermissionDeniedError` | `permission-denied` |
+//! | `BusyError` | `busy` |
+//! | `NoResponseError` | `no-response` |
+//! | `InvalidPatternError` | `invalid-pattern` |
+//! | `UnsupportedError` | `unsupported` |
+//! | `GpioError` | `io` |
+//!
+//! `cff3000.MockBackend` is a `testing::Replay` for tests without
+//! hardware: every press plays the next queued state, on virtual time.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+
+use crate::codes::ErrorCode;
+use crate::testing::{generate, PatternParams, Replay};
+use crate::{Button, CFF3000, CFF3000Builder, CFF3000State, Command, StopToken, WatchOptions};
+
+create_exception!(cff3000, CFF3000Error, PyException, "Base class of the errors of the cff3000 module.");
+create_exception!(cff3000, ConfigError, CFF3000Error, "Missing or invalid configuration.");
+create_exception!(cff3000, DeviceNotFoundError, CFF3000Error, "The GPIO chip does not exist.");
+create_exception!(cff3000, PermissionDeniedError, CFF3000Error, "No access to the GPIO chip.");
+create_exception!(cff3000, BusyError, CFF3000Error, "The lines, the lock file or the device are in use.");
+create_exception!(cff3000, NoResponseError, CFF3000Error, "The LEDs did not show a pattern.");
+create_exception!(cff3000, InvalidPatternError, CFF3000Error, "The LED pattern was not understood.");
+create_exception!(cff3000, UnsupportedError, CFF3000Error, "Not available on this system.");
+create_exception!(cff3000, GpioError, CFF3000Error, "Any other error.");
+
+/// Exception of `err`, see the module documentation.
+fn to_py_err(err: std::io::Error) -> PyErr {
+    let message = err.to_string();
+    match ErrorCode::of(&err) {
+        ErrorCode::Config => ConfigError::new_err(message),
+        ErrorCode::NotFound => DeviceNotFoundError::new_err(message),
+        ErrorCode::PermissionDenied => PermissionDeniedError::new_err(message),
+        ErrorCode::Busy => BusyError::new_err(message),
+        ErrorCode::NoResponse => NoResponseError::new_err(message),
+        ErrorCode::InvalidPattern => InvalidPatternError::new_err(message),
+        /* only given by the cli for --verify */
+        ErrorCode::NotConfirmed => CFF3000Error::new_err(message),
+        ErrorCode::Unsupported => UnsupportedError::new_err(message),
+        ErrorCode::Io => GpioError::new_err(message),
+    }
+}
+
+/// `CFF3000State` in Python.
+#[pyclass(name = "State", eq, eq_int, frozen, rename_all = "SCREAMING_SNAKE_CASE")]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum State {
+    Locked,
+    Unlocked,
+    Manual,
+    OutOfRange,
+}
+
+impl From<CFF3000State> for State {
+    fn from(state: CFF3000State) -> State {
+        match state {
+            CFF3000State::Locked => State::Locked,
+            CFF3000State::Unlocked => State::Unlocked,
+            CFF3000State::Manual => State::Manual,
+            CFF3000State::OutOfRange => State::OutOfRange,
+        }
+    }
+}
+
+impl From<State> for CFF3000State {
+    fn from(state: State) -> CFF3000State {
+        match state {
+            State::Locked => CFF3000State::Locked,
+            State::Unlocked => CFF3000State::Unlocked,
+            State::Manual => CFF3000State::Manual,
+            State::OutOfRange => CFF3000State::OutOfRange,
+        }
+    }
+}
+
+/// `Trigger` in Python.
+#[pyclass(name = "Trigger", eq, eq_int, frozen, rename_all = "SCREAMING_SNAKE_CASE")]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PyTrigger {
+    Poll,
+    AutoLock,
+    Schedule,
+    Command,
+    External,
+}
+
+/// `StateChange` in Python.
+#[pyclass(name = "StateChange", frozen, get_all)]
+#[derive(Debug, Copy, Clone)]
+pub struct Change {
+    previous: Option<State>,
+    current: State,
+    trigger: PyTrigger,
+}
+
+#[pymethods]
+impl Change {
+    fn __repr__(&self) -> String {
+        format!("StateChange(previous={:?}, current={:?}, trigger={:?})", self.previous, self.current, self.trigger)
+    }
+}
+
+impl From<crate::StateChange> for Change {
+    fn from(change: crate::StateChange) -> Change {
+        Change {
+            previous: change.previous.map(State::from),
+            current: change.current.into(),
+            trigger: match change.trigger {
+                crate::Trigger::Poll => PyTrigger::Poll,
+                crate::Trigger::AutoLock => PyTrigger::AutoLock,
+                crate::Trigger::Schedule => PyTrigger::Schedule,
+                crate::Trigger::Command => PyTrigger::Command,
+                crate::Trigger::External => PyTrigger::External,
+            },
+        }
+    }
+}
+
+/// `testing::Replay` in Python, see the module documentation.
+#[pyclass(name = "MockBackend", frozen)]
+pub struct Mock {
+    replay: Replay,
+}
+
+#[pymethods]
+impl Mock {
+    #[new]
+    fn new() -> Mock {
+        Mock {replay: Replay::new()}
+    }
+
+    /// Show `state` after the next press.
+    fn push_state(&self, state: State) {
+        self.replay.push_capture(generate(state.into(), PatternParams::default()));
+    }
+
+    /// Show nothing after the next press.
+    fn push_no_response(&self) {
+        self.replay.push_capture(Vec::new());
+    }
+
+    /// Button transitions so far as `(button, pressed)`, the button
+    /// being "lock" or "unlock".
+    fn transitions(&self) -> Vec<(&'static str, bool)> {
+        self.replay.transitions().iter().map(|transition| {
+            let button = match transition.button {
+                Button::Lock => "lock",
+                Button::Unlock => "unlock",
+            };
+            (button, transition.pressed)
+        }).collect()
+    }
+
+    /// Virtual time in seconds.
+    fn elapsed(&self) -> f64 {
+        self.replay.elapsed().as_secs_f64()
+    }
+}
+
+/// Duration of `seconds` given to `name`.
+fn duration(name: &str, seconds: f64) -> PyResult<Duration> {
+    Duration::try_from_secs_f64(seconds).map_err(|_| PyValueError::new_err(format!("invalid {} {}", name, seconds)))
+}
+
+/// `CFF3000` in Python.
+#[pyclass(name = "CFF3000", frozen)]
+pub struct Device {
+    device: Arc<CFF3000>,
+}
+
+#[pymethods]
+impl Device {
+    /// Open the CFF3000 on `chip` with the line offsets `pins` of LED
+    /// red, LED green, button unlock and button lock.
+    #[new]
+    fn new(py: Python<'_>, chip: &str, pins: [u32; 4]) -> PyResult<Device> {
+        let device = py.detach(|| CFF3000::new(chip, pins)).map_err(to_py_err)?;
+        Ok(Device {device: Arc::new(device)})
+    }
+
+    /// CFF3000 on `mock`, using its virtual time.
+    #[staticmethod]
+    fn with_backend(mock: &Mock) -> PyResult<Device> {
+        let device = CFF3000Builder::with_backend(mock.replay.clone()).clock(mock.replay.clock()).build().map_err(to_py_err)?;
+        Ok(Device {device: Arc::new(device)})
+    }
+
+    /// Press the lock button.
+    fn lock(&self, py: Python<'_>) -> PyResult<()> {
+        py.detach(|| self.device.press_as(Command::Lock, "python")).map_err(to_py_err)
+    }
+
+    /// Press the unlock button.
+    fn unlock(&self, py: Python<'_>) -> PyResult<()> {
+        py.detach(|| self.device.press_as(Command::Unlock, "python")).map_err(to_py_err)
+    }
+
+    /// Press both buttons, the CFF3000 shows its state on the LEDs.
+    fn check(&self, py: Python<'_>) -> PyResult<()> {
+        py.detach(|| self.device.press_as(Command::Check, "python")).map_err(to_py_err)
+    }
+
+    /// Query the state.
+    fn state(&self, py: Python<'_>) -> PyResult<State> {
+        py.detach(|| self.device.execute_as(Command::Check, "python")).map(State::from).map_err(to_py_err)
+    }
+
+    /// Lock and return the state confirmed by the LEDs.
+    fn lock_and_verify(&self, py: Python<'_>) -> PyResult<State> {
+        py.detach(|| self.device.execute_as(Command::Lock, "python")).map(State::from).map_err(to_py_err)
+    }
+
+    /// Unlock and return the state confirmed by the LEDs.
+    fn unlock_and_verify(&self, py: Python<'_>) -> PyResult<State> {
+        py.detach(|| self.device.execute_as(Command::Unlock, "python")).map(State::from).map_err(to_py_err)
+    }
+
+    /// Generator of the state changes, queried every `poll_interval`
+    /// seconds like `CFF3000::watch_with_options()`, locking once the
+    /// door has been unlocked for `auto_lock_after` seconds. Raises the
+    /// error that ended the watch.
+    #[pyo3(signature = (poll_interval = 30.0, auto_lock_after = None))]
+    fn watch(&self, poll_interval: f64, auto_lock_after: Option<f64>) -> PyResult<Watch> {
+        let mut options = WatchOptions::new(duration("poll_interval", poll_interval)?);
+        if let Some(seconds) = auto_lock_after {
+            options = options.auto_lock_after(duration("auto_lock_after", seconds)?);
+        }
+        let (sender, changes) = mpsc::channel();
+        let stop = StopToken::new();
+        let device = self.device.clone();
+        let token = stop.clone();
+        let spawned = std::thread::Builder::new().name("cff3000-watch".to_string()).spawn(move || {
+            let result = device.watch_with_options(&options, &token, |change| {
+                let _ = sender.send(Ok(change));
+            });
+            if let Err(err) = result {
+                let _ = sender.send(Err(err));
+            }
+        });
+        spawned.map_err(to_py_err)?;
+        Ok(Watch {changes: std::sync::Mutex::new(changes), stop, closed: AtomicBool::new(false)})
+    }
+}
+
+/// Generator returned by `CFF3000.watch()`.
+#[pyclass(name = "Watch", frozen)]
+pub struct Watch {
+    changes: std::sync::Mutex<mpsc::Receiver<std::io::Result<crate::StateChange>>>,
+    stop: StopToken,
+    closed: AtomicBool,
+}
+
+#[pymethods]
+impl Watch {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&self, py: Python<'_>) -> PyResult<Option<Change>> {
+        let mut changes = self.changes.lock().unwrap_or_else(|e| e.into_inner());
+        while !self.closed.load(Ordering::SeqCst) {
+            let receiver = &mut *changes;
+            /* wake up now and then for Ctrl-C */
+            match py.detach(move || receiver.recv_timeout(Duration::from_millis(100))) {
+                Ok(Ok(change)) => return Ok(Some(change.into())),
+                Ok(Err(err)) => return Err(to_py_err(err)),
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(None),
+                Err(mpsc::RecvTimeoutError::Timeout) => py.check_signals()?,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Stop watching and end the generator, like `generator.close()`.
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.stop.stop();
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        self.stop.stop();
+    }
+}
+
+/// The `cff3000` Python module.
+#[pymodule]
+fn cff3000(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = m.py();
+    m.add_class::<Device>()?;
+    m.add_class::<Mock>()?;
+    m.add_class::<State>()?;
+    m.add_class::<PyTrigger>()?;
+    m.add_class::<Change>()?;
+    m.add_class::<Watch>()?;
+    m.add("CFF3000Error", py.get_type::<CFF3000Error>())?;
+    m.add("ConfigError", py.get_type::<ConfigError>())?;
+    m.add("DeviceNotFoundError", py.get_type::<DeviceNotFoundError>())?;
+    m.add("PermissionDeniedError", py.get_type::<PermissionDeniedError>())?;
+    m.add("BusyError", py.get_type::<BusyError>())?;
+    m.add("NoResponseError", py.get_type::<NoResponseError>())?;
+    m.add("InvalidPatternError", py.get_type::<InvalidPatternError>())?;
+    m.add("UnsupportedError", py.get_type::<UnsupportedError>())?;
+    m.add("GpioError", py.get_type::<GpioError>())?;
+    Ok(())
+}
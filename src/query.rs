@@ -0,0 +1,456 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Incremental state query.
+//!
+//! [`StateQuery`] splits `CFF3000::state()` into a state machine which
+//! never sleeps. Each call to `poll()` does whatever is due at that
+//! point in time (releasing the buttons, reading pending LED events,
+//! classifying the captured pattern) and returns immediately.
+
+use std::task::Poll;
+use std::time::{Duration, Instant, SystemTime};
+
+#[cfg(feature = "config")]
+use serde::{Deserialize, Serialize};
+
+use crate::clock::{deadline, Clock, SharedClock};
+use crate::eventdump::EventDump;
+use crate::history::HistoryEntry;
+use crate::interlock::OperationGuard;
+#[cfg(all(feature = "journald", unix))]
+use crate::journald::JournalEvent;
+use crate::spans::{Operation, OperationSpan};
+use crate::wallclock::{Anchoring, WallAnchor};
+use crate::{Buttons, CFF3000, CFF3000State, EventBuffer, ParseError, PressGuard, StopToken, MAX_EVENTS};
+
+/// Longest wait of `wait_or_stop()` on backends without a waker
+const STOP_SLICE: Duration = Duration::from_secs(1);
+
+/// Result of a state query including what has been captured.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "config", derive(Serialize, Deserialize), serde(deny_unknown_fields))]
+pub struct StateReport {
+    /// Interpreted state
+    pub state: CFF3000State,
+    /// LED events captured after the button press, with the timestamps
+    /// of the backend
+    pub events: EventBuffer,
+    /// Number of LED events the backend detected as lost during the
+    /// capture (always 0 for backends without loss detection)
+    pub lost_events: u32,
+    /// The capture stopped early with `MAX_EVENTS` events since more
+    /// kept coming, the pattern is incomplete
+    #[cfg_attr(feature = "config", serde(default, skip_serializing_if = "std::ops::Not::not"))]
+    pub truncated: bool,
+    /// Wall-clock time of the event timestamps, see `wallclock`. `None`
+    /// for reports of other sources, e.g. replayed files.
+    #[cfg_attr(feature = "config", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub anchor: Option<WallAnchor>,
+}
+
+impl StateReport {
+    /// Wall-clock time of the first LED event, when the device started
+    /// to show the state, `None` without an anchor.
+    pub fn shown_at(&self) -> Option<SystemTime> {
+        match (self.anchor, self.events.iter().map(|event| event.timestamp).min()) {
+            (Some(anchor), Some(first)) => Some(anchor.wall_time(first)),
+            _ => None,
+        }
+    }
+
+    /// Human readable notes about the capture quality, e.g.
+    /// "3 events lost during capture".
+    pub fn diagnostics(&self) -> Vec<String> {
+        capture_notes(self.lost_events, self.truncated)
+    }
+}
+
+/// LED events captured after a press, not yet interpreted, see
+/// `CFF3000::capture()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capture {
+    /// LED events in the order they have been read
+    pub events: EventBuffer,
+    /// See `StateReport::lost_events`
+    pub lost_events: u32,
+    /// See `StateReport::truncated`
+    pub truncated: bool,
+    /// See `StateReport::anchor`, `None` without events
+    pub anchor: Option<WallAnchor>,
+}
+
+fn capture_notes(lost: u32, truncated: bool) -> Vec<String> {
+    let mut notes = Vec::new();
+    if lost != 0 {
+        notes.push(format!("{} event{} lost during capture", lost, if lost == 1 {""} else {"s"}));
+    }
+    if truncated {
+        notes.push(format!("capture stopped after {} events", MAX_EVENTS));
+    }
+    notes
+}
+
+/// Pattern error of a capture with notes about its quality, see
+/// `StateReport::diagnostics()`.
+///
+/// Wrapped by the `std::io::Error` of a failed query when events were
+/// lost or the capture was truncated, its `source()` is the
+/// `ParseError`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureError {
+    pub error: ParseError,
+    pub notes: Vec<String>,
+}
+
+impl std::fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} ({})", self.error, self.notes.join(", "))
+    }
+}
+
+impl std::error::Error for CaptureError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// `ParseError` wrapped by `err`, directly or by a `CaptureError`.
+pub(crate) fn parse_error(err: &std::io::Error) -> Option<&ParseError> {
+    let inner = err.get_ref()?;
+    inner.downcast_ref::<ParseError>().or_else(|| inner.downcast_ref::<CaptureError>().map(|err| &err.error))
+}
+
+enum Phase {
+    Pressing(PressGuard),
+    Capturing,
+    Done,
+}
+
+/// State query driven by repeated calls to `poll()`.
+///
+/// The device is busy until the query has completed or been dropped.
+pub struct StateQuery<'a, C: Clock = SharedClock> {
+    device: &'a CFF3000,
+    _busy: OperationGuard,
+    clock: C,
+    phase: Phase,
+    buttons: Buttons,
+    started: (SystemTime, Instant),
+    /* when the first LED event has been read */
+    #[cfg(feature = "metrics")]
+    first_event: Option<Instant>,
+    press_end: Instant,
+    capture: Duration,
+    capture_end: Instant,
+    /* span of the capture window, see `spans` */
+    capture_span: Option<OperationSpan>,
+    /* trace of the captured events, see `eventdump` */
+    dump: Option<EventDump>,
+    anchoring: Anchoring,
+    eventlog: EventBuffer,
+    /* events arrived with `eventlog` full */
+    truncated: bool,
+}
+
+impl<'a> StateQuery<'a, SharedClock> {
+    /// Flush stale LED events and press both buttons to start a query.
+    /// Deadlines are computed from the device's clock.
+    pub fn start(device: &'a CFF3000) -> std::io::Result<StateQuery<'a, SharedClock>> {
+        StateQuery::start_with_clock(device, device.clock.clone())
+    }
+}
+
+impl<'a, C: Clock> StateQuery<'a, C> {
+    /// Like `start()`, but all deadlines are computed from `clock`.
+    pub fn start_with_clock(device: &'a CFF3000, clock: C) -> std::io::Result<StateQuery<'a, C>> {
+        StateQuery::begin(device, Buttons::Both, device.timings.check_capture, clock)
+    }
+
+    pub(crate) fn begin(device: &'a CFF3000, buttons: Buttons, capture: Duration, clock: C) -> std::io::Result<StateQuery<'a, C>> {
+        let timestamp = SystemTime::now();
+        let result = StateQuery::press(device, buttons, capture, clock, timestamp);
+        if let Err(ref err) = result {
+            device.history.record(HistoryEntry::new(timestamp, buttons.command(), Err(err), Duration::from_millis(0)));
+            device.telemetry.error(buttons.command(), err, &[], Duration::from_millis(0));
+        }
+        #[cfg(feature = "metrics")]
+        {
+            if let (Some(metrics), Err(err)) = (device.metrics.as_ref(), result.as_ref()) {
+                metrics.record_refused(buttons.command(), err);
+            }
+        }
+        #[cfg(all(feature = "journald", unix))]
+        {
+            if let Err(ref err) = result {
+                device.send_journal(JournalEvent::Failure(buttons.command(), err));
+            }
+        }
+        result
+    }
+
+    fn press(device: &'a CFF3000, buttons: Buttons, capture: Duration, clock: C, timestamp: SystemTime) -> std::io::Result<StateQuery<'a, C>> {
+        let busy = device.acquire()?;
+        device.flush_led_events()?;
+        let guard = device.press(buttons, busy.clone())?;
+        let now = clock.now();
+
+        Ok(StateQuery {
+            device,
+            _busy: busy,
+            buttons,
+            started: (timestamp, now),
+            #[cfg(feature = "metrics")]
+            first_event: None,
+            press_end: deadline(now, device.timings.press_for(buttons.command())),
+            capture,
+            capture_end: now,
+            capture_span: None,
+            dump: None,
+            anchoring: Anchoring::new(SystemTime::now(), now),
+            clock,
+            phase: Phase::Pressing(guard),
+            eventlog: EventBuffer::new(),
+            truncated: false,
+        })
+    }
+
+    /// Advance the query without blocking.
+    ///
+    /// Returns `Poll::Pending` until the capture window has passed and
+    /// then the classified state. Polling again after that returns an
+    /// error.
+    pub fn poll(&mut self) -> Poll<std::io::Result<CFF3000State>> {
+        self.poll_report().map(|result| result.map(|report| report.state))
+    }
+
+    /// Like `poll()`, but complete with a `StateReport`.
+    ///
+    /// If the pattern cannot be interpreted, the error message mentions
+    /// events the backend detected as lost and a truncated capture.
+    pub fn poll_report(&mut self) -> Poll<std::io::Result<StateReport>> {
+        let completed = matches!(self.phase, Phase::Done);
+        /* kept for the telemetry hooks, the parser takes the capture */
+        let mut events = EventBuffer::new();
+        let result = match self.poll_capture() {
+            Poll::Ready(result) => result.and_then(|capture| {
+                if !self.device.telemetry.is_empty() {
+                    events = capture.events.clone();
+                }
+                self.interpret(capture)
+            }),
+            Poll::Pending => return Poll::Pending,
+        };
+        if let (false, Some(cache), Ok(report)) = (completed, self.device.cache.as_ref(), result.as_ref()) {
+            cache.store(report.state, self.device.clock.now());
+        }
+        if !completed {
+            let duration = self.clock.now().saturating_duration_since(self.started.1);
+            let state = result.as_ref().map(|report| Some(report.state));
+            self.device.history.record(HistoryEntry::new(self.started.0, self.buttons.command(), state, duration));
+            let state = result.as_ref().ok().map(|report| report.state);
+            self.device.telemetry.operation(self.buttons.command(), &result, state, &events, duration);
+        }
+        #[cfg(feature = "metrics")]
+        {
+            if !completed {
+                self.record(&result);
+            }
+        }
+        #[cfg(all(feature = "journald", unix))]
+        {
+            if !completed {
+                self.device.send_journal(match result {
+                    Ok(ref report) => JournalEvent::State(self.buttons.command(), report.state),
+                    Err(ref err) => JournalEvent::Failure(self.buttons.command(), err),
+                });
+            }
+        }
+        Poll::Ready(result)
+    }
+
+    fn interpret(&self, capture: Capture) -> std::io::Result<StateReport> {
+        let Capture {events, lost_events, truncated, anchor} = capture;
+        let mut span = OperationSpan::new(Operation::Parse, self.device.label.as_deref());
+        span.record_events(events.len());
+        let result = span.in_scope(|| CFF3000::parse_eventlog(&events, &self.device.parse_options));
+        span.record_result(&result, result.as_ref().ok().cloned());
+        match result {
+            Ok(state) => Ok(StateReport {state, events, lost_events, truncated, anchor}),
+            Err(err) => {
+                let notes = capture_notes(lost_events, truncated);
+                match parse_error(&err) {
+                    Some(&error) if !notes.is_empty() => Err(std::io::Error::new(err.kind(), CaptureError {error, notes})),
+                    _ => Err(err),
+                }
+            },
+        }
+    }
+
+    /// Pass the outcome to the device's `Metrics`, if any.
+    #[cfg(feature = "metrics")]
+    fn record(&self, result: &std::io::Result<StateReport>) {
+        if let Some(ref metrics) = self.device.metrics {
+            let released = self.capture_end.checked_sub(self.capture).unwrap_or(self.capture_end);
+            let latency = self.first_event.map(|first| first.saturating_duration_since(released));
+            let duration = self.clock.now().saturating_duration_since(self.started.1);
+            metrics.record_query(self.buttons.command(), result.as_ref().map(|report| report.state), duration, latency);
+        }
+    }
+
+    /// Like `poll_report()`, but complete with the captured events
+    /// without interpreting them, so patterns `poll()` rejects can be
+    /// inspected.
+    ///
+    /// The events are kept in an `EventBuffer`. Once it is full, LED
+    /// events still arriving end the capture before its window has
+    /// passed, flagged as `truncated` and leaving them for the flush of
+    /// the next operation.
+    pub fn poll_capture(&mut self) -> Poll<std::io::Result<Capture>> {
+        let now = self.clock.now();
+
+        match std::mem::replace(&mut self.phase, Phase::Done) {
+            Phase::Pressing(guard) if now < self.press_end => {
+                self.phase = Phase::Pressing(guard);
+                return Poll::Pending;
+            },
+            Phase::Pressing(guard) => {
+                self.capture_end = deadline(now, self.capture);
+                self.capture_span = Some(OperationSpan::new(Operation::Capture, self.device.label.as_deref()));
+                self.dump = EventDump::start(self.device.event_dump);
+                if let Err(err) = guard.release() {
+                    return Poll::Ready(Err(err));
+                }
+                self.phase = Phase::Capturing;
+            },
+            Phase::Capturing => self.phase = Phase::Capturing,
+            Phase::Done => return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "state query already completed"))),
+        }
+
+        loop {
+            let outcome = if self.eventlog.is_full() {
+                let truncated = &mut self.truncated;
+                self.device.wait_for_led_events(Duration::from_millis(0)).map(|pending| {
+                    *truncated = pending != 0;
+                    0
+                })
+            } else {
+                self.device.read_led_events(Duration::from_millis(0), &mut self.eventlog)
+            };
+            match outcome {
+                Ok(0) => break,
+                Ok(count) => {
+                    let read = self.clock.now();
+                    let new = self.eventlog.len().saturating_sub(count);
+                    for &event in self.eventlog.iter().skip(new) {
+                        self.anchoring.note(event.timestamp, read);
+                    }
+                    if let Some(ref mut dump) = self.dump {
+                        for &event in self.eventlog.iter().skip(new) {
+                            dump.log(event);
+                        }
+                    }
+                    #[cfg(feature = "metrics")]
+                    {
+                        self.first_event = self.first_event.or(Some(read));
+                    }
+                    continue;
+                },
+                Err(err) => {
+                    self.phase = Phase::Done;
+                    let result = Err(err);
+                    if let Some(mut span) = self.capture_span.take() {
+                        span.record_result(&result, None);
+                    }
+                    if let Some(dump) = self.dump.take() {
+                        dump.log_finish();
+                    }
+                    return Poll::Ready(result);
+                },
+            }
+        }
+
+        if !self.truncated && self.clock.now() < self.capture_end {
+            return Poll::Pending;
+        }
+
+        self.phase = Phase::Done;
+        if let Some(mut span) = self.capture_span.take() {
+            span.record_events(self.eventlog.len());
+        }
+        if let Some(dump) = self.dump.take() {
+            dump.log_finish();
+        }
+        let events = std::mem::take(&mut self.eventlog);
+        Poll::Ready(Ok(Capture {events, lost_events: self.device.backend.lost_led_events(), truncated: self.truncated, anchor: self.anchoring.anchor()}))
+    }
+
+    /// Point in time at which `poll()` will make progress without new
+    /// LED events, or `None` once the query is complete.
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.phase {
+            Phase::Pressing(_) => Some(self.press_end),
+            Phase::Capturing => Some(self.capture_end),
+            Phase::Done => None,
+        }
+    }
+
+    /// Returns true while the buttons are still pressed.
+    pub fn is_pressing(&self) -> bool {
+        matches!(self.phase, Phase::Pressing(_))
+    }
+
+    /// Block until `poll()` can make progress.
+    ///
+    /// This is used by the blocking front-ends and waits for LED events
+    /// (or the next deadline), so it must not be called from
+    /// cooperative schedulers.
+    pub fn wait(&self) -> std::io::Result<()> {
+        let timeout = match self.timeout() {
+            Some(timeout) => timeout,
+            None => return Ok(()),
+        };
+
+        if self.is_pressing() {
+            self.clock.sleep(timeout);
+        } else {
+            self.device.wait_for_led_events(timeout)?;
+        }
+        Ok(())
+    }
+
+    /// Like `wait()`, returning early once `stop` is stopped. Backends
+    /// without a `waker()` are waited for in slices of a second.
+    pub(crate) fn wait_or_stop(&self, stop: &StopToken) -> std::io::Result<()> {
+        let timeout = match self.timeout() {
+            Some(timeout) => timeout,
+            None => return Ok(()),
+        };
+
+        if self.is_pressing() {
+            self.clock.sleep_or_stop(timeout, stop);
+            return Ok(());
+        }
+        let timeout = match self.device.backend.waker() {
+            Some(waker) => {
+                stop.wake_on_stop(&waker);
+                timeout
+            },
+            None => std::cmp::min(timeout, STOP_SLICE),
+        };
+        if !stop.is_stopped() {
+            self.device.wait_for_led_events(timeout)?;
+        }
+        Ok(())
+    }
+
+    /// Time until the deadline, `None` if passed or complete.
+    fn timeout(&self) -> Option<Duration> {
+        let deadline = self.deadline()?;
+        let now = self.clock.now();
+        match deadline > now {
+            true => Some(deadline - now),
+            false => None,
+        }
+    }
+}
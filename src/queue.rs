@@ -0,0 +1,288 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Serialized command execution.
+//!
+//! A [`CommandQueue`] owns a worker thread which executes commands from
+//! any number of [`CommandSender`]s strictly one after another, so that
+//! button presses and LED captures of different producers never overlap.
+
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard};
+
+#[cfg(feature = "config")]
+use serde::{Deserialize, Serialize};
+
+use crate::{AlreadyInUse, CaptureError, CFF3000, CFF3000State, LockControl, ParseError};
+
+/// Command executed by the queue worker, serialized as "lock", "unlock"
+/// or "check".
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "config", derive(Serialize, Deserialize), serde(rename_all = "lowercase"))]
+pub enum Command {
+    /// Press lock and verify the result (`CFF3000::lock_and_verify()`)
+    Lock,
+    /// Press unlock and verify the result (`CFF3000::unlock_and_verify()`)
+    Unlock,
+    /// Query the current state (`CFF3000::state()`)
+    Check,
+}
+
+/// Command queue configuration.
+#[derive(Debug, Copy, Clone)]
+pub struct QueueOptions {
+    /// Maximum number of pending commands (not counting the one being
+    /// executed). Commands sent to a full queue fail immediately.
+    pub depth: usize,
+    /// Merge a command into the last pending one if both are identical.
+    /// All senders of the merged commands receive the same result.
+    pub coalesce: bool,
+}
+
+impl Default for QueueOptions {
+    fn default() -> QueueOptions {
+        QueueOptions {depth: 16, coalesce: true}
+    }
+}
+
+/// What happens to pending commands on shutdown.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Shutdown {
+    /// Execute all pending commands before stopping the worker.
+    Drain,
+    /// Fail all pending commands with `ErrorKind::BrokenPipe`, like
+    /// the commands sent after the shutdown.
+    Cancel,
+}
+
+type Reply = mpsc::Sender<std::io::Result<CFF3000State>>;
+
+struct Pending {
+    command: Command,
+    /// Distinct initiators of the merged commands, see `audit`
+    initiators: Vec<String>,
+    replies: Vec<Reply>,
+}
+
+struct State {
+    pending: VecDeque<Pending>,
+    shutdown: Option<Shutdown>,
+}
+
+struct Shared {
+    options: QueueOptions,
+    state: Mutex<State>,
+    wakeup: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// Worker thread executing commands one at a time.
+///
+/// Dropping the queue is equivalent to `shutdown(Shutdown::Drain)`.
+pub struct CommandQueue {
+    shared: Arc<Shared>,
+    worker: Option<std::thread::JoinHandle<()>>,
+}
+
+/// Cloneable handle to submit commands to a `CommandQueue`.
+#[derive(Clone)]
+pub struct CommandSender {
+    shared: Arc<Shared>,
+}
+
+impl CommandQueue {
+    /// Start a worker for `device` using default options.
+    ///
+    /// `device` can be passed by value or as an `Arc<CFF3000>` to keep
+    /// using it elsewhere.
+    pub fn new<D: Into<Arc<CFF3000>>>(device: D) -> std::io::Result<CommandQueue> {
+        CommandQueue::with_options(device, QueueOptions::default())
+    }
+
+    /// Start a worker for `device` using `options`.
+    pub fn with_options<D: Into<Arc<CFF3000>>>(device: D, options: QueueOptions) -> std::io::Result<CommandQueue> {
+        CommandQueue::for_lock(device.into(), options)
+    }
+
+    /// Start a worker for any `LockControl` using `options`, e.g. a
+    /// client of a daemon or another kind of lock.
+    pub fn for_lock<L: LockControl + Send + Sync + ?Sized + 'static>(device: Arc<L>, options: QueueOptions) -> std::io::Result<CommandQueue> {
+        let shared = Arc::new(Shared {
+            options,
+            state: Mutex::new(State {pending: VecDeque::new(), shutdown: None}),
+            wakeup: Condvar::new(),
+        });
+
+        let worker_shared = shared.clone();
+        let worker = (std::thread::Builder::new()
+            .name("cff3000-queue".to_string())
+            .spawn(move || worker(&*device, &worker_shared)))?;
+
+        Ok(CommandQueue {shared, worker: Some(worker)})
+    }
+
+    /// Get a new handle for submitting commands.
+    pub fn sender(&self) -> CommandSender {
+        CommandSender {shared: self.shared.clone()}
+    }
+
+    /// Stop the worker.
+    ///
+    /// A command already being executed is always completed. Pending
+    /// commands are executed or cancelled according to `mode`, after
+    /// which this function returns. Commands sent afterwards fail
+    /// with `ErrorKind::BrokenPipe`.
+    pub fn shutdown(mut self, mode: Shutdown) {
+        self.stop(mode);
+    }
+
+    fn stop(&mut self, mode: Shutdown) {
+        let cancelled = {
+            let mut state = self.shared.lock();
+            if state.shutdown.is_none() {
+                state.shutdown = Some(mode);
+            }
+            if mode == Shutdown::Cancel {
+                state.pending.drain(..).collect()
+            } else {
+                Vec::new()
+            }
+        };
+        self.shared.wakeup.notify_all();
+
+        for pending in cancelled {
+            for reply in pending.replies {
+                let _ = reply.send(Err(Error::new(ErrorKind::BrokenPipe, "command cancelled by queue shutdown")));
+            }
+        }
+
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for CommandQueue {
+    fn drop(&mut self) {
+        self.stop(Shutdown::Drain);
+    }
+}
+
+impl CommandSender {
+    /// Enqueue `command`.
+    ///
+    /// The result is delivered through the returned receiver once the
+    /// command has been executed. If the queue is full or shut down,
+    /// the error is delivered immediately. A command whose execution
+    /// panics fails with `ErrorKind::BrokenPipe`, the worker continues
+    /// with the next one.
+    pub fn send(&self, command: Command) -> mpsc::Receiver<std::io::Result<CFF3000State>> {
+        self.send_as(command, "")
+    }
+
+    /// Like `send()`, executing the command with
+    /// `LockControl::execute_as()` so that it is recorded with `initiator`.
+    pub fn send_as(&self, command: Command, initiator: &str) -> mpsc::Receiver<std::io::Result<CFF3000State>> {
+        let (tx, rx) = mpsc::channel();
+
+        {
+            let mut state = self.shared.lock();
+
+            if state.shutdown.is_some() {
+                let _ = tx.send(Err(Error::new(ErrorKind::BrokenPipe, "command queue has been shut down")));
+                return rx;
+            }
+
+            if self.shared.options.coalesce {
+                if let Some(last) = state.pending.back_mut() {
+                    if last.command == command {
+                        if !initiator.is_empty() && !last.initiators.iter().any(|known| known == initiator) {
+                            last.initiators.push(initiator.to_string());
+                        }
+                        last.replies.push(tx);
+                        return rx;
+                    }
+                }
+            }
+
+            if state.pending.len() >= self.shared.options.depth {
+                let _ = tx.send(Err(Error::new(ErrorKind::WouldBlock, "command queue is full")));
+                return rx;
+            }
+
+            let initiators = if initiator.is_empty() {Vec::new()} else {vec![initiator.to_string()]};
+            state.pending.push_back(Pending {command, initiators, replies: vec![tx]});
+        }
+
+        self.shared.wakeup.notify_one();
+        rx
+    }
+
+    /// Number of commands waiting for execution.
+    pub fn pending(&self) -> usize {
+        self.shared.lock().pending.len()
+    }
+
+    /// Commands sent now fail as the queue is full.
+    pub(crate) fn is_full(&self) -> bool {
+        self.pending() >= self.shared.options.depth
+    }
+}
+
+/// Copy of `err` for every sender, keeping a `ParseError`,
+/// `CaptureError` or `AlreadyInUse` it wraps.
+fn copy_error(err: &Error) -> Error {
+    let inner = err.get_ref();
+    if let Some(capture) = inner.and_then(|inner| inner.downcast_ref::<CaptureError>()) {
+        return Error::new(err.kind(), capture.clone());
+    }
+    if let Some(&parse) = inner.and_then(|inner| inner.downcast_ref::<ParseError>()) {
+        return Error::new(err.kind(), parse);
+    }
+    if let Some(in_use) = inner.and_then(|inner| inner.downcast_ref::<AlreadyInUse>()) {
+        return Error::new(err.kind(), in_use.clone());
+    }
+    Error::new(err.kind(), err.to_string())
+}
+
+fn worker<L: LockControl + ?Sized>(device: &L, shared: &Shared) {
+    loop {
+        let next = {
+            let mut state = shared.lock();
+            loop {
+                if let Some(pending) = state.pending.pop_front() {
+                    break pending;
+                }
+                if state.shutdown.is_some() {
+                    return;
+                }
+                state = match shared.wakeup.wait(state) {
+                    Ok(state) => state,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+            }
+        };
+
+        /* a panicking command must neither leave its senders nor the
+         * pending commands waiting forever */
+        let executed = panic::catch_unwind(AssertUnwindSafe(|| device.execute_as(next.command, &next.initiators.join(", "))));
+        let result = executed.unwrap_or_else(|_| Err(Error::new(ErrorKind::BrokenPipe, "command execution panicked")));
+        for reply in next.replies {
+            let copy = match result {
+                Ok(state) => Ok(state),
+                Err(ref err) => Err(copy_error(err)),
+            };
+            let _ = reply.send(copy);
+        }
+    }
+}
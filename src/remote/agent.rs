@@ -0,0 +1,163 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+use std::io::{Error, ErrorKind};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::{is_timeout, token_matches, FrameReader, Message, HEARTBEAT_TIMEOUT, PROTOCOL_VERSION};
+use crate::{Button, GpioBackend, Led};
+
+/// Network agent exposing a local `GpioBackend`, see module documentation.
+pub struct Agent {
+    backend: Arc<dyn GpioBackend>,
+    token: Vec<u8>,
+}
+
+/// State of one client connection shared with its event pump.
+struct Session {
+    backend: Arc<dyn GpioBackend>,
+    /// Serializes writes; held by the pump from reading an event until
+    /// it has been sent, so a flush cannot overtake an event in flight
+    writer: Mutex<TcpStream>,
+    closed: AtomicBool,
+}
+
+impl Session {
+    fn send(&self, message: &Message) -> std::io::Result<()> {
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        message.write_to(&mut *writer)
+    }
+
+    /// Stream LED events to the client until the session is closed.
+    fn pump(&self) {
+        while !self.closed.load(Ordering::SeqCst) {
+            let mask = match self.backend.wait_for_led_events(Duration::from_millis(200)) {
+                Ok(mask) => mask,
+                Err(_) => break,
+            };
+
+            let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+            for &led in &[Led::Red, Led::Green] {
+                if mask & led.mask() == 0 {
+                    continue;
+                }
+                let sent = self.backend.read_led_event(led)
+                    .and_then(|event| Message::Event(event).write_to(&mut *writer));
+                if sent.is_err() {
+                    self.closed.store(true, Ordering::SeqCst);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn handle(&self, request: Message) -> std::io::Result<()> {
+        let reply = match request {
+            Message::SetButton {button, pressed} => match self.backend.set_button(button, pressed) {
+                Ok(()) => Message::Ok,
+                Err(err) => Message::Error {kind: err.kind(), message: err.to_string()},
+            },
+            Message::Flush => {
+                /* block the pump, so no pre-flush event follows the reply */
+                let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+                let reply = match self.backend.flush_led_events() {
+                    Ok(()) => Message::Flushed,
+                    Err(err) => Message::Error {kind: err.kind(), message: err.to_string()},
+                };
+                return reply.write_to(&mut *writer);
+            },
+            Message::Ping => Message::Pong,
+            _ => Message::Error {kind: ErrorKind::InvalidInput, message: "unexpected request".to_string()},
+        };
+        self.send(&reply)
+    }
+}
+
+impl Agent {
+    /// Serve `backend` to clients presenting `token`.
+    pub fn new<B: GpioBackend + 'static>(backend: B, token: &str) -> Agent {
+        Agent::with_shared(Arc::new(backend), token)
+    }
+
+    /// Like `new()`, for a backend shared with other code.
+    pub fn with_shared(backend: Arc<dyn GpioBackend>, token: &str) -> Agent {
+        Agent {backend, token: token.as_bytes().to_vec()}
+    }
+
+    /// Accept and serve clients on `listener`, one at a time, forever.
+    ///
+    /// Errors of individual connections (including authentication
+    /// failures) end that connection only.
+    pub fn serve(&self, listener: &TcpListener) -> std::io::Result<()> {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let _ = self.handle_client(stream);
+                },
+                Err(ref err) if err.kind() == ErrorKind::Interrupted || err.kind() == ErrorKind::ConnectionAborted => {},
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Serve a single client connection until it disconnects.
+    ///
+    /// Both buttons are released before this function returns.
+    pub fn handle_client(&self, stream: TcpStream) -> std::io::Result<()> {
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(HEARTBEAT_TIMEOUT))?;
+        let mut reader = stream.try_clone()?;
+        let mut frames = FrameReader::default();
+
+        let session = Arc::new(Session {
+            backend: self.backend.clone(),
+            writer: Mutex::new(stream),
+            closed: AtomicBool::new(false),
+        });
+
+        match frames.read(&mut reader)? {
+            Message::Hello {version, ref token} if version == PROTOCOL_VERSION && token_matches(&self.token, token) => {},
+            Message::Hello {version, ..} if version != PROTOCOL_VERSION => {
+                let _ = session.send(&Message::Error {kind: ErrorKind::InvalidData, message: "unsupported protocol version".to_string()});
+                return Err(Error::new(ErrorKind::InvalidData, "client uses unsupported protocol version"));
+            },
+            _ => {
+                let _ = session.send(&Message::Error {kind: ErrorKind::PermissionDenied, message: "invalid token".to_string()});
+                return Err(Error::new(ErrorKind::PermissionDenied, "client presented invalid token"));
+            },
+        }
+
+        self.backend.flush_led_events()?;
+        session.send(&Message::Ok)?;
+
+        let pump_session = session.clone();
+        let pump = (std::thread::Builder::new()
+            .name("cff3000-agent-pump".to_string())
+            .spawn(move || pump_session.pump()))?;
+
+        let result = loop {
+            if session.closed.load(Ordering::SeqCst) {
+                break Err(Error::new(ErrorKind::BrokenPipe, "client connection lost"));
+            }
+            let request = match frames.read(&mut reader) {
+                Ok(request) => request,
+                Err(ref err) if is_timeout(err) => break Err(Error::new(ErrorKind::TimedOut, "client heartbeat timed out")),
+                Err(ref err) if err.kind() == ErrorKind::UnexpectedEof => break Ok(()),
+                Err(err) => break Err(err),
+            };
+            if let Err(err) = session.handle(request) {
+                break Err(err);
+            }
+        };
+
+        session.closed.store(true, Ordering::SeqCst);
+        let _ = pump.join();
+        let _ = self.backend.set_button(Button::Unlock, false);
+        let _ = self.backend.set_button(Button::Lock, false);
+        result
+    }
+}
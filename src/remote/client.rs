@@ -0,0 +1,281 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use super::{is_timeout, FrameReader, Message, HEARTBEAT_INTERVAL, HEARTBEAT_TIMEOUT, PROTOCOL_VERSION};
+use crate::clock::deadline;
+use crate::{Button, GpioBackend, Led, LedEvent, LED_GREEN, LED_RED};
+
+struct Queues {
+    red: VecDeque<LedEvent>,
+    green: VecDeque<LedEvent>,
+    /// Agent and local timestamp of the first event of the connection
+    anchor: Option<(Duration, Duration)>,
+    /// Reader thread of the current connection is running
+    connected: bool,
+}
+
+/// State shared with the reader thread.
+struct Shared {
+    start: Instant,
+    queues: Mutex<Queues>,
+    events: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queues> {
+        self.queues.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn local_now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Translate `event` to the local timebase and queue it.
+    fn push(&self, mut event: LedEvent) {
+        let local = self.local_now();
+        let mut queues = self.lock();
+        let (agent_base, local_base) = *queues.anchor.get_or_insert((event.timestamp, local));
+        event.timestamp = local_base.saturating_add(event.timestamp.saturating_sub(agent_base));
+        match event.led {
+            Led::Red => queues.red.push_back(event),
+            Led::Green => queues.green.push_back(event),
+        }
+        drop(queues);
+        self.events.notify_all();
+    }
+
+    fn clear(&self) {
+        let mut queues = self.lock();
+        queues.red.clear();
+        queues.green.clear();
+    }
+}
+
+struct Connection {
+    writer: Arc<Mutex<TcpStream>>,
+    replies: mpsc::Receiver<Message>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = writer.shutdown(Shutdown::Both);
+    }
+}
+
+/// `GpioBackend` talking to a remote `Agent`, see module documentation.
+pub struct RemoteBackend {
+    addr: String,
+    token: Vec<u8>,
+    shared: Arc<Shared>,
+    connection: Mutex<Option<Connection>>,
+}
+
+/// Read frames until the connection fails, dispatching events and
+/// forwarding replies.
+fn reader(mut stream: TcpStream, mut frames: FrameReader, writer: &Mutex<TcpStream>, shared: &Shared, replies: &mpsc::Sender<Message>) {
+    let mut last_seen = Instant::now();
+    let mut last_ping = Instant::now();
+
+    loop {
+        /* ping regularly, the agent only sees our requests otherwise */
+        if last_ping.elapsed() >= HEARTBEAT_INTERVAL {
+            let mut writer = writer.lock().unwrap_or_else(|e| e.into_inner());
+            if Message::Ping.write_to(&mut *writer).is_err() {
+                break;
+            }
+            last_ping = Instant::now();
+        }
+
+        let message = match frames.read(&mut stream) {
+            Ok(message) => message,
+            Err(ref err) if is_timeout(err) => {
+                if last_seen.elapsed() >= HEARTBEAT_TIMEOUT {
+                    break;
+                }
+                continue;
+            },
+            Err(_) => break,
+        };
+        last_seen = Instant::now();
+
+        match message {
+            Message::Event(event) => shared.push(event),
+            Message::Pong => {},
+            Message::Flushed => {
+                /* ordered with the event stream, so clear right here */
+                shared.clear();
+                let _ = replies.send(Message::Ok);
+            },
+            reply => {
+                let _ = replies.send(reply);
+            },
+        }
+    }
+
+    let _ = stream.shutdown(Shutdown::Both);
+    shared.lock().connected = false;
+    shared.events.notify_all();
+}
+
+impl RemoteBackend {
+    /// Connect to the agent at `addr` (e.g. "hallway-pi:3003") and
+    /// authenticate with `token`.
+    pub fn connect(addr: &str, token: &str) -> std::io::Result<RemoteBackend> {
+        let backend = RemoteBackend {
+            addr: addr.to_string(),
+            token: token.as_bytes().to_vec(),
+            shared: Arc::new(Shared {
+                start: Instant::now(),
+                queues: Mutex::new(Queues {red: VecDeque::new(), green: VecDeque::new(), anchor: None, connected: false}),
+                events: Condvar::new(),
+            }),
+            connection: Mutex::new(None),
+        };
+        {
+            let mut connection = backend.connection.lock().unwrap_or_else(|e| e.into_inner());
+            *connection = Some(backend.open()?);
+        }
+        Ok(backend)
+    }
+
+    fn open(&self) -> std::io::Result<Connection> {
+        let addrs = self.addr.to_socket_addrs()?;
+        let mut last_err = Error::new(ErrorKind::NotFound, "agent address did not resolve");
+        let mut stream = None;
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, HEARTBEAT_TIMEOUT) {
+                Ok(s) => {
+                    stream = Some(s);
+                    break;
+                },
+                Err(err) => last_err = err,
+            }
+        }
+        let mut stream = match stream {
+            Some(stream) => stream,
+            None => return Err(last_err),
+        };
+
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(HEARTBEAT_TIMEOUT))?;
+        (Message::Hello {version: PROTOCOL_VERSION, token: self.token.clone()}.write_to(&mut stream))?;
+        let mut frames = FrameReader::default();
+        match frames.read(&mut stream)? {
+            Message::Ok => {},
+            Message::Error {kind, message} => return Err(Error::new(kind, format!("agent refused connection: {}", message))),
+            _ => return Err(Error::new(ErrorKind::InvalidData, "unexpected reply from agent")),
+        }
+        stream.set_read_timeout(Some(HEARTBEAT_INTERVAL))?;
+
+        {
+            let mut queues = self.shared.lock();
+            queues.red.clear();
+            queues.green.clear();
+            queues.anchor = None;
+            queues.connected = true;
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let read_stream = stream.try_clone()?;
+        let writer = Arc::new(Mutex::new(stream));
+        let ping_writer = writer.clone();
+        let shared = self.shared.clone();
+        let spawned = std::thread::Builder::new()
+            .name("cff3000-remote".to_string())
+            .spawn(move || reader(read_stream, frames, &ping_writer, &shared, &tx));
+        if let Err(err) = spawned {
+            self.shared.lock().connected = false;
+            return Err(err);
+        }
+
+        Ok(Connection {writer, replies: rx})
+    }
+
+    /// Send `request` and wait for its reply, reconnecting first if the
+    /// connection has been lost.
+    fn request(&self, request: &Message) -> std::io::Result<()> {
+        let mut connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        if connection.is_none() || !self.shared.lock().connected {
+            *connection = None;
+            *connection = Some(self.open()?);
+        }
+
+        let reply = {
+            let conn = connection.as_ref().unwrap();
+            let sent = {
+                let mut writer = conn.writer.lock().unwrap_or_else(|e| e.into_inner());
+                request.write_to(&mut *writer)
+            };
+            match sent {
+                Ok(()) => conn.replies.recv_timeout(HEARTBEAT_TIMEOUT).ok(),
+                Err(_) => None,
+            }
+        };
+
+        match reply {
+            Some(Message::Ok) => Ok(()),
+            Some(Message::Error {kind, message}) => Err(Error::new(kind, message)),
+            Some(_) => Err(Error::new(ErrorKind::InvalidData, "unexpected reply from agent")),
+            None => {
+                *connection = None;
+                Err(Error::new(ErrorKind::ConnectionAborted, "connection to agent lost"))
+            },
+        }
+    }
+}
+
+impl GpioBackend for RemoteBackend {
+    fn set_button(&self, button: Button, pressed: bool) -> std::io::Result<()> {
+        self.request(&Message::SetButton {button, pressed})
+    }
+
+    fn wait_for_led_events(&self, timeout: Duration) -> std::io::Result<u8> {
+        let deadline = deadline(Instant::now(), timeout);
+        let mut queues = self.shared.lock();
+
+        loop {
+            let mut mask = 0;
+            if !queues.red.is_empty() {
+                mask |= LED_RED;
+            }
+            if !queues.green.is_empty() {
+                mask |= LED_GREEN;
+            }
+            if mask != 0 {
+                return Ok(mask);
+            }
+            if !queues.connected {
+                return Err(Error::new(ErrorKind::ConnectionAborted, "connection to agent lost"));
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(0);
+            }
+            queues = match self.shared.events.wait_timeout(queues, deadline - now) {
+                Ok((queues, _)) => queues,
+                Err(poisoned) => poisoned.into_inner().0,
+            };
+        }
+    }
+
+    fn read_led_event(&self, led: Led) -> std::io::Result<LedEvent> {
+        let mut queues = self.shared.lock();
+        let event = match led {
+            Led::Red => queues.red.pop_front(),
+            Led::Green => queues.green.pop_front(),
+        };
+        event.ok_or_else(|| Error::new(ErrorKind::WouldBlock, "no pending LED event"))
+    }
+
+    fn flush_led_events(&self) -> std::io::Result<()> {
+        self.request(&Message::Flush)
+    }
+}
@@ -0,0 +1,247 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! GPIO access over the network.
+//!
+//! An [`Agent`] runs next to the hardware (e.g. the `cff3000-agent`
+//! binary on a Pi Zero) and exposes a local `GpioBackend` over TCP.
+//! [`RemoteBackend`] implements `GpioBackend` on top of that connection,
+//! so the normal `CFF3000` API works unchanged on another machine.
+//!
+//! # Protocol
+//!
+//! Every message is a frame consisting of a big-endian `u32` length
+//! followed by that many bytes: one message type byte and its payload.
+//! The client authenticates with a pre-shared token in its first
+//! message; the agent closes the connection if it does not match. LED
+//! events are streamed by the agent as they happen, interleaved with
+//! the replies to the client's requests. Only one client is served at
+//! a time. The connection is not encrypted, use a VPN or SSH tunnel on
+//! untrusted networks.
+//!
+//! # Connection loss
+//!
+//! The client pings the agent every `HEARTBEAT_INTERVAL` and both sides
+//! drop the connection after `HEARTBEAT_TIMEOUT` of silence. The agent releases
+//! both buttons whenever a client disconnects, so a button is never left
+//! pressed. `RemoteBackend` reconnects on the next button or flush
+//! request (i.e. at the start of the next operation); waiting for LED
+//! events on a lost connection fails with `ErrorKind::ConnectionAborted`,
+//! so a capture in progress is reported as failed.
+//!
+//! # Timestamps
+//!
+//! Event timestamps are taken by the agent's backend on the agent's
+//! clock. The client anchors the first event of each connection to its
+//! local receipt time and shifts all later events of that connection by
+//! the same offset, so the intervals between events keep the agent's
+//! precision while the network latency does not distort the pattern.
+
+use std::io::{Error, ErrorKind, Read, Write};
+use std::time::Duration;
+
+use crate::{Button, Led, LedEvent};
+
+mod agent;
+mod client;
+
+pub use self::agent::Agent;
+pub use self::client::RemoteBackend;
+
+/// Default TCP port of the agent.
+pub const DEFAULT_PORT: u16 = 3003;
+
+/// Time between two pings of the client.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// Silence after which a connection is considered lost.
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(15);
+
+const PROTOCOL_VERSION: u8 = 1;
+const MAX_FRAME_LEN: usize = 1024;
+
+const MSG_HELLO: u8 = 0x01;
+const MSG_SET_BUTTON: u8 = 0x02;
+const MSG_FLUSH: u8 = 0x03;
+const MSG_PING: u8 = 0x04;
+const MSG_OK: u8 = 0x80;
+const MSG_ERROR: u8 = 0x81;
+const MSG_FLUSHED: u8 = 0x83;
+const MSG_PONG: u8 = 0x84;
+const MSG_EVENT: u8 = 0x90;
+
+/// Protocol message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Message {
+    /// Client greeting with protocol version and token
+    Hello {version: u8, token: Vec<u8>},
+    /// Drive a button
+    SetButton {button: Button, pressed: bool},
+    /// Discard pending LED events
+    Flush,
+    /// Liveness check
+    Ping,
+    /// Request succeeded
+    Ok,
+    /// Request failed
+    Error {kind: ErrorKind, message: String},
+    /// Flush completed, all following events are new
+    Flushed,
+    /// Reply to `Ping`
+    Pong,
+    /// LED event (agent timebase)
+    Event(LedEvent),
+}
+
+fn protocol_error(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("remote protocol error: {}", msg))
+}
+
+/// Error kinds transported over the wire; others become `Other`.
+const ERROR_KINDS: [ErrorKind; 8] = [
+    ErrorKind::Other,
+    ErrorKind::NotFound,
+    ErrorKind::PermissionDenied,
+    ErrorKind::InvalidInput,
+    ErrorKind::InvalidData,
+    ErrorKind::WouldBlock,
+    ErrorKind::TimedOut,
+    ErrorKind::Unsupported,
+];
+
+impl Message {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; 4];
+        match *self {
+            Message::Hello {version, ref token} => {
+                buf.push(MSG_HELLO);
+                buf.push(version);
+                buf.extend_from_slice(token);
+            },
+            Message::SetButton {button, pressed} => {
+                buf.push(MSG_SET_BUTTON);
+                buf.push(match button {Button::Unlock => 0, Button::Lock => 1});
+                buf.push(pressed as u8);
+            },
+            Message::Flush => buf.push(MSG_FLUSH),
+            Message::Ping => buf.push(MSG_PING),
+            Message::Ok => buf.push(MSG_OK),
+            Message::Error {kind, ref message} => {
+                buf.push(MSG_ERROR);
+                buf.push(ERROR_KINDS.iter().position(|&k| k == kind).unwrap_or(0) as u8);
+                let message = message.as_bytes();
+                buf.extend_from_slice(&message[..std::cmp::min(message.len(), MAX_FRAME_LEN - 2)]);
+            },
+            Message::Flushed => buf.push(MSG_FLUSHED),
+            Message::Pong => buf.push(MSG_PONG),
+            Message::Event(event) => {
+                buf.push(MSG_EVENT);
+                buf.push(match event.led {Led::Red => 0, Led::Green => 1});
+                buf.push(event.on as u8);
+                /* nanoseconds, saturated at u64::MAX (584 years) */
+                let nanos = u64::try_from(event.timestamp.as_nanos()).unwrap_or(u64::MAX);
+                buf.extend_from_slice(&nanos.to_be_bytes());
+            },
+        }
+        let len = (buf.len() - 4) as u32;
+        buf[..4].copy_from_slice(&len.to_be_bytes());
+        buf
+    }
+
+    fn decode(frame: &[u8]) -> std::io::Result<Message> {
+        let (&kind, payload) = match frame.split_first() {
+            Some(split) => split,
+            None => return Err(protocol_error("empty frame")),
+        };
+
+        let message = match (kind, payload) {
+            (MSG_HELLO, &[version, ref token @ ..]) => Message::Hello {version, token: token.to_vec()},
+            (MSG_SET_BUTTON, &[button, pressed]) => Message::SetButton {
+                button: match button {
+                    0 => Button::Unlock,
+                    1 => Button::Lock,
+                    _ => return Err(protocol_error("invalid button")),
+                },
+                pressed: pressed != 0,
+            },
+            (MSG_FLUSH, []) => Message::Flush,
+            (MSG_PING, []) => Message::Ping,
+            (MSG_OK, []) => Message::Ok,
+            (MSG_ERROR, &[kind, ref message @ ..]) => Message::Error {
+                kind: ERROR_KINDS.get(kind as usize).cloned().unwrap_or(ErrorKind::Other),
+                message: String::from_utf8_lossy(message).into_owned(),
+            },
+            (MSG_FLUSHED, []) => Message::Flushed,
+            (MSG_PONG, []) => Message::Pong,
+            (MSG_EVENT, &[led, on, t0, t1, t2, t3, t4, t5, t6, t7]) => Message::Event(LedEvent {
+                led: match led {
+                    0 => Led::Red,
+                    1 => Led::Green,
+                    _ => return Err(protocol_error("invalid LED")),
+                },
+                on: on != 0,
+                timestamp: Duration::from_nanos(u64::from_be_bytes([t0, t1, t2, t3, t4, t5, t6, t7])),
+            }),
+            _ => return Err(protocol_error("unexpected message")),
+        };
+        Ok(message)
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        w.write_all(&self.encode())
+    }
+}
+
+/// Incremental frame parser, keeping partial frames across read
+/// timeouts.
+#[derive(Default)]
+struct FrameReader {
+    buf: Vec<u8>,
+}
+
+impl FrameReader {
+    /// Read the next message. A read timeout is returned as error
+    /// without losing already received data.
+    fn read<R: Read>(&mut self, r: &mut R) -> std::io::Result<Message> {
+        loop {
+            if let Some(message) = self.take()? {
+                return Ok(message);
+            }
+            let mut chunk = [0u8; 256];
+            let len = r.read(&mut chunk)?;
+            if len == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed"));
+            }
+            self.buf.extend_from_slice(&chunk[..len]);
+        }
+    }
+
+    fn take(&mut self) -> std::io::Result<Option<Message>> {
+        let (len, rest) = match *self.buf.as_slice() {
+            [a, b, c, d, ref rest @ ..] => (u32::from_be_bytes([a, b, c, d]) as usize, rest),
+            _ => return Ok(None),
+        };
+        if len > MAX_FRAME_LEN {
+            return Err(protocol_error("frame too long"));
+        }
+        let message = match rest.get(..len) {
+            Some(frame) => Message::decode(frame),
+            None => return Ok(None),
+        };
+        self.buf.drain(..4 + len);
+        message.map(Some)
+    }
+}
+
+/// Constant time comparison, so the token cannot be guessed byte by
+/// byte from reply timings.
+fn token_matches(expected: &[u8], received: &[u8]) -> bool {
+    if expected.len() != received.len() {
+        return false;
+    }
+    expected.iter().zip(received).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Returns true if `err` is a read timeout.
+fn is_timeout(err: &Error) -> bool {
+    err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut
+}
@@ -0,0 +1,169 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Raspberry Pi backend using the `rppal` crate.
+//!
+//! Pins are addressed by their BCM GPIO number (not the physical
+//! header pin). LED edges are delivered by rppal's interrupt thread and
+//! timestamped when the callback runs, so they include the callback
+//! latency (typically well below 1 ms), which the default merge window
+//! easily absorbs.
+
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use rppal::gpio::{Gpio, InputPin, Level, OutputPin, Trigger};
+
+use crate::clock::deadline;
+use crate::{Button, GpioBackend, Led, LedEvent, LED_GREEN, LED_RED};
+
+struct Queues {
+    red: VecDeque<LedEvent>,
+    green: VecDeque<LedEvent>,
+}
+
+/// State shared with the interrupt callbacks.
+struct Shared {
+    start: Instant,
+    queues: Mutex<Queues>,
+    events: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queues> {
+        self.queues.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn push(&self, led: Led, level: Level) {
+        let event = LedEvent {led, on: level == Level::High, timestamp: self.start.elapsed()};
+        {
+            let mut queues = self.lock();
+            match led {
+                Led::Red => queues.red.push_back(event),
+                Led::Green => queues.green.push_back(event),
+            }
+        }
+        self.events.notify_all();
+    }
+}
+
+fn rppal_error(err: rppal::gpio::Error) -> Error {
+    Error::other(err)
+}
+
+/// Backend using rppal's `Gpio` peripheral access.
+pub struct RppalBackend {
+    shared: Arc<Shared>,
+    /* kept to keep the interrupts registered */
+    _red: Mutex<InputPin>,
+    _green: Mutex<InputPin>,
+    unlock: Mutex<OutputPin>,
+    lock: Mutex<OutputPin>,
+}
+
+impl RppalBackend {
+    /// Configure the pins.
+    ///
+    /// `pins` contains the BCM GPIO numbers for LED red, LED green, button
+    /// unlock and button lock (in this order).
+    pub fn new(pins: [u8; 4]) -> std::io::Result<RppalBackend> {
+        let gpio = Gpio::new().map_err(rppal_error)?;
+        RppalBackend::with_gpio(&gpio, pins)
+    }
+
+    /// Like `new()`, but using a `Gpio` instance owned by the application.
+    pub fn with_gpio(gpio: &Gpio, pins: [u8; 4]) -> std::io::Result<RppalBackend> {
+        let shared = Arc::new(Shared {
+            start: Instant::now(),
+            queues: Mutex::new(Queues {red: VecDeque::new(), green: VecDeque::new()}),
+            events: Condvar::new(),
+        });
+
+        let unlock = gpio.get(pins[2]).map_err(rppal_error)?.into_output_low();
+        let lock = gpio.get(pins[3]).map_err(rppal_error)?.into_output_low();
+
+        let mut inputs = Vec::new();
+        for &(pin, led) in &[(pins[0], Led::Red), (pins[1], Led::Green)] {
+            let mut input = gpio.get(pin).map_err(rppal_error)?.into_input();
+            let callback_shared = shared.clone();
+            input.set_async_interrupt(Trigger::Both, move |level| callback_shared.push(led, level)).map_err(rppal_error)?;
+            inputs.push(input);
+        }
+        let green = inputs.pop().unwrap();
+        let red = inputs.pop().unwrap();
+
+        Ok(RppalBackend {
+            shared,
+            _red: Mutex::new(red),
+            _green: Mutex::new(green),
+            unlock: Mutex::new(unlock),
+            lock: Mutex::new(lock),
+        })
+    }
+}
+
+impl Drop for RppalBackend {
+    fn drop(&mut self) {
+        let _ = self.set_button(Button::Unlock, false);
+        let _ = self.set_button(Button::Lock, false);
+    }
+}
+
+impl GpioBackend for RppalBackend {
+    fn set_button(&self, button: Button, pressed: bool) -> std::io::Result<()> {
+        let pin = match button {
+            Button::Unlock => &self.unlock,
+            Button::Lock => &self.lock,
+        };
+        let mut pin = pin.lock().unwrap_or_else(|e| e.into_inner());
+        if pressed {
+            pin.set_high();
+        } else {
+            pin.set_low();
+        }
+        Ok(())
+    }
+
+    fn wait_for_led_events(&self, timeout: Duration) -> std::io::Result<u8> {
+        let deadline = deadline(Instant::now(), timeout);
+        let mut queues = self.shared.lock();
+
+        loop {
+            let mut mask = 0;
+            if !queues.red.is_empty() {
+                mask |= LED_RED;
+            }
+            if !queues.green.is_empty() {
+                mask |= LED_GREEN;
+            }
+
+            let now = Instant::now();
+            if mask != 0 || now >= deadline {
+                return Ok(mask);
+            }
+
+            queues = match self.shared.events.wait_timeout(queues, deadline - now) {
+                Ok((queues, _)) => queues,
+                Err(poisoned) => poisoned.into_inner().0,
+            };
+        }
+    }
+
+    fn read_led_event(&self, led: Led) -> std::io::Result<LedEvent> {
+        let mut queues = self.shared.lock();
+        let event = match led {
+            Led::Red => queues.red.pop_front(),
+            Led::Green => queues.green.pop_front(),
+        };
+        event.ok_or_else(|| Error::new(ErrorKind::WouldBlock, "no pending LED event"))
+    }
+
+    fn flush_led_events(&self) -> std::io::Result<()> {
+        let mut queues = self.shared.lock();
+        queues.red.clear();
+        queues.green.clear();
+        Ok(())
+    }
+}
@@ -0,0 +1,385 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Commands at fixed times of the day, e.g. locking every night.
+//!
+//! A [`Scheduler`] executes the actions of its [`ScheduleEntry`]s at
+//! their local time through a `CommandSender`, so they are serialized
+//! with the commands of all other producers of the queue, and reports
+//! every result. `CFF3000Daemon` runs the entries of the `[[schedule]]`
+//! configuration sections this way.
+//!
+//! ```no_run
+//! use cff3000::schedule::{ScheduleAction, ScheduleEntry, Scheduler, Weekday};
+//! use cff3000::{CFF3000, CommandQueue};
+//!
+//! # fn main() -> std::io::Result<()> {
+//! let queue = CommandQueue::new(CFF3000::new("/dev/gpiochip2", [2, 3, 4, 5])?)?;
+//! let mut night = ScheduleEntry::new("22:30".parse()?, ScheduleAction::EnsureLocked);
+//! night.days = vec![Weekday::Sun, Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu];
+//! let _scheduler = Scheduler::start(vec![night], queue.sender(), |run| {
+//!     println!("{} at {}: {:?}", run.entry.action.name(), run.entry.at, run.result);
+//! })?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! # Local time
+//!
+//! The times are evaluated in the local time zone of the process
+//! (`TZ` or `/etc/localtime`), so an entry keeps its wall clock time
+//! across daylight saving time changes:
+//!
+//! - a time skipped when the clocks go forward runs that much later,
+//!   e.g. 02:30 at 03:30
+//! - a time repeated when the clocks go back runs once, the first time
+//!
+//! # Missed times
+//!
+//! An action runs if the scheduler notices its time within
+//! `ScheduleEntry::grace`, e.g. when it is started shortly after it, once
+//! the system has resumed from suspend or after the wall clock has been
+//! set forward. Of several missed times only the latest runs, older ones
+//! and those missed by more than the grace time are skipped.
+
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "config")]
+use serde::{Deserialize, Serialize};
+
+use crate::timings::DEFAULT_SCHEDULE_GRACE;
+use crate::{CFF3000State, Command, CommandSender, StopToken};
+
+const DAY: i64 = 24 * 60 * 60;
+
+/// Longest time between two evaluations, so that a changed wall clock
+/// is noticed within a minute.
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+/// Day of the week, e.g. "mon" in configuration files.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "config", derive(Serialize, Deserialize), serde(rename_all = "lowercase"))]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl Weekday {
+    /// All days, starting with Monday.
+    pub const ALL: [Weekday; 7] = [Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri, Weekday::Sat, Weekday::Sun];
+
+    /// Local day of the week of `time`.
+    pub fn of(time: SystemTime) -> Weekday {
+        weekday(local(seconds(time)).div_euclid(DAY))
+    }
+
+    /// Lower case name, e.g. "mon".
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Weekday::Mon => "mon",
+            Weekday::Tue => "tue",
+            Weekday::Wed => "wed",
+            Weekday::Thu => "thu",
+            Weekday::Fri => "fri",
+            Weekday::Sat => "sat",
+            Weekday::Sun => "sun",
+        }
+    }
+}
+
+/// Local wall clock time with minute resolution, written as "22:30".
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "config", derive(Serialize, Deserialize), serde(try_from = "String", into = "String"))]
+pub struct TimeOfDay {
+    hour: u8,
+    minute: u8,
+}
+
+impl TimeOfDay {
+    /// Fails with `ErrorKind::InvalidInput` unless `hour` is below 24
+    /// and `minute` below 60.
+    pub fn new(hour: u8, minute: u8) -> std::io::Result<TimeOfDay> {
+        if hour >= 24 || minute >= 60 {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("{}:{:02} is no time of the day", hour, minute)));
+        }
+        Ok(TimeOfDay {hour, minute})
+    }
+
+    /// Local time of the day of `time`, rounded down to the minute.
+    pub fn of(time: SystemTime) -> TimeOfDay {
+        let seconds = local(seconds(time)).rem_euclid(DAY);
+        TimeOfDay {hour: (seconds / 3600) as u8, minute: (seconds / 60 % 60) as u8}
+    }
+
+    pub fn hour(&self) -> u8 {
+        self.hour
+    }
+
+    pub fn minute(&self) -> u8 {
+        self.minute
+    }
+
+    fn seconds(&self) -> i64 {
+        self.hour as i64 * 3600 + self.minute as i64 * 60
+    }
+}
+
+/// Parses "HH:MM" with 24 hours, e.g. "7:05" or "22:30". Fails with
+/// `ErrorKind::InvalidInput`.
+impl std::str::FromStr for TimeOfDay {
+    type Err = Error;
+
+    fn from_str(text: &str) -> std::io::Result<TimeOfDay> {
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("expected a time like \"22:30\", not {:?}", text));
+        let (hour, minute) = text.split_once(':').ok_or_else(invalid)?;
+        if minute.len() != 2 || !hour.chars().chain(minute.chars()).all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        TimeOfDay::new(hour.parse().map_err(|_| invalid())?, minute.parse().map_err(|_| invalid())?)
+    }
+}
+
+impl std::fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:02}:{:02}", self.hour, self.minute)
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = Error;
+
+    fn try_from(text: String) -> std::io::Result<TimeOfDay> {
+        text.parse()
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(time: TimeOfDay) -> String {
+        time.to_string()
+    }
+}
+
+/// What a `ScheduleEntry` does.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "config", derive(Serialize, Deserialize), serde(rename_all = "snake_case"))]
+pub enum ScheduleAction {
+    /// Query the state and lock the door unless it is locked
+    /// (`Command::Check`, then `Command::Lock` if needed)
+    EnsureLocked,
+    /// Query the state and unlock the door unless it is unlocked
+    EnsureUnlocked,
+    /// Only query the state, e.g. to report it at a fixed time
+    Check,
+}
+
+impl ScheduleAction {
+    /// Name in configuration files, e.g. "ensure_locked".
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ScheduleAction::EnsureLocked => "ensure_locked",
+            ScheduleAction::EnsureUnlocked => "ensure_unlocked",
+            ScheduleAction::Check => "check",
+        }
+    }
+}
+
+/// Action at a local time on some days of the week.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduleEntry {
+    pub at: TimeOfDay,
+    pub action: ScheduleAction,
+    /// Days to run on, every day if empty
+    pub days: Vec<Weekday>,
+    /// Longest delay after which a missed time still runs, see the
+    /// module documentation
+    pub grace: Duration,
+}
+
+impl ScheduleEntry {
+    /// Run `action` at `at` every day, with `DEFAULT_SCHEDULE_GRACE`.
+    pub fn new(at: TimeOfDay, action: ScheduleAction) -> ScheduleEntry {
+        ScheduleEntry {at, action, days: Vec::new(), grace: DEFAULT_SCHEDULE_GRACE}
+    }
+
+    /// Returns true if the entry runs on `day`.
+    pub fn runs_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    /// Times the entry is due after `after` until and including
+    /// `until`, in order, see the module documentation for daylight
+    /// saving time.
+    pub fn slots(&self, after: SystemTime, until: SystemTime) -> Vec<SystemTime> {
+        let (after, until) = (seconds(after), seconds(until));
+        if until <= after {
+            return Vec::new();
+        }
+        /* a skipped time shifts into the next day at most */
+        (local(after).div_euclid(DAY) - 1..local(until).div_euclid(DAY) + 1)
+            .filter(|&day| self.runs_on(weekday(day)))
+            .map(|day| instant(day * DAY + self.at.seconds()))
+            .filter(|&slot| slot > after && slot <= until)
+            .map(time)
+            .collect()
+    }
+
+    /// Initiator of the commands for the `audit` log, e.g.
+    /// "schedule:ensure_locked@22:30".
+    pub fn initiator(&self) -> String {
+        format!("schedule:{}@{}", self.action.name(), self.at)
+    }
+}
+
+/// Outcome of a scheduled action.
+#[derive(Debug)]
+pub struct ScheduledRun {
+    pub entry: ScheduleEntry,
+    /// Time the entry was due
+    pub slot: SystemTime,
+    /// State after the action, or the error of its last command
+    pub result: std::io::Result<CFF3000State>,
+}
+
+/// Thread running schedule entries, see the module documentation.
+///
+/// Dropping the scheduler stops it, after the action being executed.
+pub struct Scheduler {
+    stop: StopToken,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Scheduler {
+    /// Run `entries` through `sender` and pass every result to
+    /// `report`, on the thread of the scheduler.
+    pub fn start<F>(entries: Vec<ScheduleEntry>, sender: CommandSender, mut report: F) -> std::io::Result<Scheduler>
+        where F: FnMut(ScheduledRun) + Send + 'static
+    {
+        let stop = StopToken::new();
+        let thread_stop = stop.clone();
+        let thread = (std::thread::Builder::new()
+            .name("cff3000-schedule".to_string())
+            .spawn(move || run(&entries, &sender, &thread_stop, &mut report)))?;
+        Ok(Scheduler {stop, thread: Some(thread)})
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.stop.stop();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run<F: FnMut(ScheduledRun)>(entries: &[ScheduleEntry], sender: &CommandSender, stop: &StopToken, report: &mut F) {
+    /* catch up on the times missed within their grace before the start */
+    let grace = entries.iter().map(|entry| entry.grace).max().unwrap_or_default();
+    let mut last = SystemTime::now().checked_sub(grace).unwrap_or(UNIX_EPOCH);
+    while !stop.is_stopped() {
+        let now = SystemTime::now();
+        let mut due: Vec<(SystemTime, &ScheduleEntry)> = entries.iter()
+            .filter_map(|entry| {
+                let slot = *entry.slots(last, now).last()?;
+                /* whole seconds, so that waking up late by a fraction is no miss even with no grace */
+                let late = seconds(now) - seconds(slot);
+                if late <= entry.grace.as_secs() as i64 {Some((slot, entry))} else {None}
+            })
+            .collect();
+        due.sort_by_key(|&(slot, _)| slot);
+        for (slot, entry) in due {
+            if stop.is_stopped() {
+                return;
+            }
+            report(ScheduledRun {entry: entry.clone(), slot, result: execute(entry, sender)});
+        }
+        /* a wall clock set back does not repeat the times already run */
+        if now > last {
+            last = now;
+        }
+
+        let horizon = now + MAX_WAIT;
+        let next = entries.iter().filter_map(|entry| entry.slots(now, horizon).first().cloned()).min().unwrap_or(horizon);
+        if stop.wait_timeout(next.duration_since(SystemTime::now()).unwrap_or_default()) {
+            return;
+        }
+    }
+}
+
+fn execute(entry: &ScheduleEntry, sender: &CommandSender) -> std::io::Result<CFF3000State> {
+    let initiator = entry.initiator();
+    let command = |command| {
+        sender.send_as(command, &initiator).recv()
+            .unwrap_or_else(|_| Err(Error::new(ErrorKind::BrokenPipe, "command queue has been shut down")))
+    };
+    let (wanted, press) = match entry.action {
+        ScheduleAction::EnsureLocked => (CFF3000State::Locked, Command::Lock),
+        ScheduleAction::EnsureUnlocked => (CFF3000State::Unlocked, Command::Unlock),
+        ScheduleAction::Check => return command(Command::Check),
+    };
+    match command(Command::Check) {
+        Ok(state) if state == wanted => Ok(state),
+        /* also after a failed query, e.g. a missed LED pattern */
+        _ => command(press),
+    }
+}
+
+/// Seconds since the epoch of `time`, rounded down.
+fn seconds(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as i64,
+        Err(before) => -(before.duration().as_secs() as i64) - if before.duration().subsec_nanos() > 0 {1} else {0},
+    }
+}
+
+fn time(seconds: i64) -> SystemTime {
+    if seconds >= 0 {
+        UNIX_EPOCH + Duration::from_secs(seconds as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(seconds.unsigned_abs())
+    }
+}
+
+/// Day of the week of the day number (1970-01-01 was a Thursday).
+fn weekday(day: i64) -> Weekday {
+    Weekday::ALL[(day + 3).rem_euclid(7) as usize]
+}
+
+/// Offset of the local time zone from UTC at `seconds`, in seconds.
+#[cfg(unix)]
+fn utc_offset(seconds: i64) -> i64 {
+    let time = seconds as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return 0;
+    }
+    tm.tm_gmtoff as i64
+}
+
+/// Local time is UTC without `localtime_r()`.
+#[cfg(not(unix))]
+fn utc_offset(_seconds: i64) -> i64 {
+    0
+}
+
+/// Local wall clock time of `seconds` as seconds since the local epoch.
+fn local(seconds: i64) -> i64 {
+    seconds + utc_offset(seconds)
+}
+
+/// Earliest instant showing the local wall clock time `local`, or for a
+/// time skipped by a change of the offset, the instant it would have
+/// had with the offset before.
+fn instant(local: i64) -> i64 {
+    let before = local - utc_offset(local - DAY);
+    let after = local - utc_offset(local + DAY);
+    [before, after].iter().cloned().filter(|&seconds| self::local(seconds) == local).min().unwrap_or(before)
+}
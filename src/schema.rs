@@ -0,0 +1,55 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! JSON representation of the values the integrations emit.
+//!
+//! The HTTP server, the MQTT bridge, `cff3000 --json` and the files of
+//! the daemon hand the same values to other programs, so they share one
+//! representation. `LedEvent`, `CFF3000State`, `Command`, `StateReport`,
+//! `StateChange`, `HealthReport`, `HistoryEntry` and `MetricsSnapshot`
+//! serialize with serde (`config` feature) to the objects the
+//! hand-written writers of the integrations produce:
+//!
+//! - keys are snake_case,
+//! - durations and times are integer milliseconds with the unit in the
+//!   key, e.g. `duration_ms`, `timestamp_ms` (since the Unix epoch) or
+//!   `last_state_age_ms`; only the monotonic timestamps of the LED
+//!   events are nanoseconds, `timestamp_ns` like in the fixture files,
+//!   in the time base of the backend rather than since the capture
+//!   start (see `LedEvent::timestamp`),
+//!   and the `wall` time of a `WallAnchor` is an RFC 3339 string with
+//!   the `chrono` feature (see `wallclock`),
+//! - enums are their stable names: "locked", "unlocked", "manual" and
+//!   "out-of-range" for the states (`CFF3000State::name()`), "lock",
+//!   "unlock" and "check" for the commands, "red" and "green" for the
+//!   LEDs, "poll", "auto-lock", "schedule", "command" and "external"
+//!   for the triggers of the changes (`Trigger::name()`),
+//! - errors are objects with the `code` of the `cli` feature and the
+//!   message, e.g. `{"code":"no-response","message":"..."}`,
+//! - values a device cannot tell are left out instead of `null`, and
+//!   the `truncated` flag of a state query only appears when set.
+//!
+//! A state query:
+//!
+//! ```text
+//! {"state":"locked","events":[{"led":"red","on":true,"timestamp_ns":700000000},{"led":"green","on":true,"timestamp_ns":700000000}],"lost_events":0,"anchor":{"wall":1760000000123,"timestamp_ns":700000000}}
+//! ```
+//!
+//! A change, as sent by the Server-Sent Events of `http`, the
+//! `change_topic` of `mqtt`, the `{{change}}` of `webhook` and the
+//! notifications of `cli::serve_rpc()`, `previous` being `null` for the
+//! first observation:
+//!
+//! ```text
+//! {"state":"locked","previous":"unlocked","trigger":"auto-lock","at_ms":1760000000123}
+//! ```
+//!
+//! `LedEvent`, `StateReport` and `StateChange` also deserialize, e.g. for tools reading
+//! dumps back, and reject unknown keys, so a misspelled key is an error
+//! instead of a default. Persisted formats carry a `schema` number which
+//! changes with incompatible versions, like `schema = 1` of the state
+//! file of `persist`. The other objects are described by the modules
+//! emitting them, see `health`, `history` and `metrics`.
+//!
+//! The shapes are covered by snapshot tests, changing one breaks the
+//! integrations reading them.
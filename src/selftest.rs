@@ -0,0 +1,169 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! End to end check of the wiring.
+
+use std::time::Duration;
+
+use crate::clock::deadline;
+use crate::interlock::OperationGuard;
+use crate::{Button, Buttons, Clock, CFF3000, EventBuffer, Led, LedEvent, LineRole, Polarity};
+
+/// Length of the button pulses used for the read back test, far below
+/// the press duration registered by the CFF3000.
+const READBACK_PULSE: Duration = Duration::from_millis(5);
+
+/// Outcome of a single self-test step.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SelfTestResult {
+    Pass,
+    /// The step failed for the given reason
+    Fail(String),
+    /// The step could not be performed, e.g. because the backend does
+    /// not provide line levels
+    Skipped(String),
+}
+
+/// Self-test step with its result.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTestItem {
+    /// Stable identifier, e.g. "button-lock-readback"
+    pub name: &'static str,
+    pub result: SelfTestResult,
+    /// Time the step took, or for passed LED activity steps the time
+    /// from the check press to the first change of that LED
+    pub duration: Duration,
+}
+
+/// Result of `CFF3000::self_test()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTestReport {
+    pub items: Vec<SelfTestItem>,
+    /// LED events captured after the check press
+    pub events: Vec<LedEvent>,
+}
+
+impl SelfTestReport {
+    /// Returns true if no step failed (skipped steps are not failures).
+    pub fn passed(&self) -> bool {
+        !self.items.iter().any(|item| matches!(item.result, SelfTestResult::Fail(_)))
+    }
+}
+
+impl CFF3000 {
+    /// Exercise the setup end to end and report every step.
+    ///
+    /// 1. Both button outputs are pulsed for a few milliseconds and read
+    ///    back (skipped if the backend cannot report line levels).
+    /// 2. A check press is performed and the LED pattern is captured
+    ///    like `state()` does; both LEDs must change at least once,
+    ///    otherwise the input is stuck or not connected.
+    /// 3. The captured pattern must be a valid CFF3000 state.
+    ///
+    /// Errors of individual steps are reported as failed items; only
+    /// errors preventing the test from running at all (e.g. the device
+    /// being busy) are returned as `Err`. Both buttons are released
+    /// before this function returns. Takes about ten seconds.
+    pub fn self_test(&self) -> std::io::Result<SelfTestReport> {
+        let busy = self.acquire()?;
+        let mut items = Vec::new();
+
+        for &(name, button, role) in &[
+            ("button-unlock-readback", Button::Unlock, LineRole::ButtonUnlock),
+            ("button-lock-readback", Button::Lock, LineRole::ButtonLock),
+        ] {
+            let start = self.clock.now();
+            let result = self.readback(button, role);
+            items.push(SelfTestItem {name, result, duration: self.clock.now().saturating_duration_since(start)});
+        }
+
+        let mut events = Vec::new();
+        let mut first_change = [None; 2];
+        let start = self.clock.now();
+        let capture = self.capture_check(busy, &mut events, &mut first_change);
+        let elapsed = self.clock.now().saturating_duration_since(start);
+
+        for &(name, index) in &[("led-red-activity", 0), ("led-green-activity", 1)] {
+            let (result, duration) = match (first_change[index], &capture) {
+                (Some(after), _) => (SelfTestResult::Pass, after),
+                (None, Err(err)) => (SelfTestResult::Fail(format!("capture failed: {}", err)), elapsed),
+                (None, Ok(())) => (SelfTestResult::Fail("no LED change after check press".to_string()), elapsed),
+            };
+            items.push(SelfTestItem {name, result, duration});
+        }
+
+        let start = self.clock.now();
+        let result = match capture {
+            Ok(()) => match CFF3000::parse_eventlog(&events, &self.parse_options) {
+                Ok(_) => SelfTestResult::Pass,
+                Err(err) => SelfTestResult::Fail(err.to_string()),
+            },
+            Err(_) => SelfTestResult::Skipped("capture failed".to_string()),
+        };
+        items.push(SelfTestItem {name: "led-pattern", result, duration: self.clock.now().saturating_duration_since(start)});
+
+        let _ = self.backend.set_button(Button::Unlock, false);
+        let _ = self.backend.set_button(Button::Lock, false);
+        Ok(SelfTestReport {items, events})
+    }
+
+    /// Pulse `button` and check that its line follows.
+    fn readback(&self, button: Button, role: LineRole) -> SelfTestResult {
+        /* line levels are electrical, active-low buttons are pressed while low */
+        let active_high = self.polarities.get(role) == Polarity::ActiveHigh;
+        let level = |pressed: bool| -> std::io::Result<bool> {
+            self.backend.set_button(button, pressed)?;
+            let lines = self.backend.line_info()?;
+            Ok(lines.iter().any(|line| line.role == role && line.level == (pressed == active_high)))
+        };
+
+        let start = self.clock.now();
+        let pressed = level(true);
+        let remaining = READBACK_PULSE.checked_sub(self.clock.now().saturating_duration_since(start)).unwrap_or_default();
+        self.clock.sleep(remaining);
+        let released = level(false);
+
+        match (pressed, released) {
+            (Err(ref err), _) | (_, Err(ref err)) if err.kind() == std::io::ErrorKind::Unsupported => {
+                let _ = self.backend.set_button(button, false);
+                SelfTestResult::Skipped(err.to_string())
+            },
+            (Err(err), _) | (_, Err(err)) => {
+                let _ = self.backend.set_button(button, false);
+                SelfTestResult::Fail(err.to_string())
+            },
+            (Ok(false), _) => SelfTestResult::Fail(format!("line stays {} while pressed", if active_high {"low"} else {"high"})),
+            (_, Ok(false)) => SelfTestResult::Fail(format!("line stays {} while released", if active_high {"high"} else {"low"})),
+            (Ok(true), Ok(true)) => SelfTestResult::Pass,
+        }
+    }
+
+    /// Press both buttons and capture the LED events like `state()`,
+    /// noting when each LED (red, green) changed first after the press.
+    fn capture_check(&self, busy: OperationGuard, events: &mut Vec<LedEvent>, first_change: &mut [Option<Duration>; 2]) -> std::io::Result<()> {
+        self.flush_led_events()?;
+        let start = self.clock.now();
+        let guard = self.press(Buttons::Both, busy)?;
+        self.clock.sleep(guard.remaining());
+        guard.release()?;
+
+        let capture_end = deadline(self.clock.now(), self.timings.check_capture);
+        loop {
+            let now = self.clock.now();
+            if now >= capture_end {
+                break;
+            }
+            let mut batch = EventBuffer::new();
+            self.read_led_events(capture_end - now, &mut batch)?;
+            events.extend_from_slice(&batch);
+            for event in &batch {
+                let index = match event.led {Led::Red => 0, Led::Green => 1};
+                if first_change[index].is_none() {
+                    first_change[index] = Some(self.clock.now().saturating_duration_since(start));
+                }
+            }
+        }
+        events.sort_by_key(|event| event.timestamp);
+        Ok(())
+    }
+}
@@ -0,0 +1,46 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! SIGINT and SIGTERM as `StopToken`, for `cli` and `daemon`.
+
+#[cfg(unix)]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(unix)]
+use std::time::Duration;
+
+use crate::StopToken;
+
+#[cfg(unix)]
+static SIGNALLED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_signal(_: libc::c_int) {
+    SIGNALLED.store(true, Ordering::SeqCst);
+}
+
+/// Stop `stop` on SIGINT or SIGTERM instead of terminating the process,
+/// so a running watch loop returns and the device releases its lines.
+/// Does nothing on other platforms.
+pub fn stop_on_signals(stop: &StopToken) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        for &signal in &[libc::SIGINT, libc::SIGTERM] {
+            let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        /* the handler may only set a flag, forward it from a thread */
+        let stop = stop.clone();
+        std::thread::Builder::new().name("cff3000-signals".to_string()).spawn(move || {
+            while !stop.wait_timeout(Duration::from_millis(100)) {
+                if SIGNALLED.load(Ordering::SeqCst) {
+                    stop.stop();
+                }
+            }
+        })?;
+    }
+    #[cfg(not(unix))]
+    let _ = stop;
+    Ok(())
+}
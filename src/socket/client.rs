@@ -0,0 +1,142 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Client side of the socket protocol.
+
+use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::battery::BatteryUsage;
+use crate::history::HistoryEntry;
+use crate::{CFF3000State, Command, LockControl};
+use super::{code_error, command_name, parse, Value};
+
+/// Default time a request may take, long enough for a verified command
+/// waiting behind another one.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// `LockControl` of the device of a `UnixServer`.
+///
+/// Requests share one connection, which is opened again by the request
+/// after a failed one. Failures are reported like those of a local
+/// device, e.g. a pattern which could not be read as
+/// `ParseError::NotEnoughEvents`; a request taking longer than the
+/// timeout fails with `ErrorKind::TimedOut`.
+pub struct UnixClient {
+    path: PathBuf,
+    timeout: Duration,
+    connection: Mutex<Option<BufReader<UnixStream>>>,
+}
+
+impl UnixClient {
+    /// Connect to the server at `path` with `DEFAULT_TIMEOUT`.
+    pub fn connect<P: AsRef<Path>>(path: P) -> std::io::Result<UnixClient> {
+        UnixClient::connect_with_timeout(path, DEFAULT_TIMEOUT)
+    }
+
+    /// Connect to the server at `path`, giving every request `timeout`.
+    pub fn connect_with_timeout<P: AsRef<Path>>(path: P, timeout: Duration) -> std::io::Result<UnixClient> {
+        let client = UnixClient {path: path.as_ref().to_path_buf(), timeout, connection: Mutex::new(None)};
+        let connection = client.open()?;
+        *client.lock() = Some(connection);
+        Ok(client)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<BufReader<UnixStream>>> {
+        self.connection.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn open(&self) -> std::io::Result<BufReader<UnixStream>> {
+        let stream = UnixStream::connect(&self.path)?;
+        /* the server answers "timeout" by itself first */
+        stream.set_read_timeout(Some(self.timeout.saturating_add(Duration::from_secs(1))))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        Ok(BufReader::new(stream))
+    }
+
+    /// Send `command` and wait for its result.
+    pub fn request(&self, command: Command) -> std::io::Result<CFF3000State> {
+        let request = format!("{{\"cmd\":\"{}\",\"timeout_ms\":{}}}\n", command_name(command), self.timeout.as_millis());
+        self.send(&request, |response| response.get("state").and_then(Value::as_str).and_then(CFF3000State::from_name))
+    }
+
+    /// The operations of the server's device, oldest first, see
+    /// `history`.
+    pub fn history(&self) -> std::io::Result<Vec<HistoryEntry>> {
+        self.send("{\"cmd\":\"history\"}\n", |response| match response.get("history") {
+            Some(Value::Array(entries)) => entries.iter().map(HistoryEntry::from_json).collect(),
+            _ => None,
+        })
+    }
+
+    /// The commands on the batteries of the server's device, see
+    /// `battery`.
+    pub fn battery(&self) -> std::io::Result<BatteryUsage> {
+        self.send("{\"cmd\":\"battery\"}\n", |response| response.get("battery").and_then(BatteryUsage::from_json))
+    }
+
+    /// Record new batteries in the server's device, returning the new
+    /// usage.
+    pub fn reset_battery(&self) -> std::io::Result<BatteryUsage> {
+        self.send("{\"cmd\":\"battery-reset\"}\n", |response| response.get("battery").and_then(BatteryUsage::from_json))
+    }
+
+    /// Send the request line `request`, `answer` reads a successful
+    /// response.
+    fn send<T, F: Fn(&Value) -> Option<T>>(&self, request: &str, answer: F) -> std::io::Result<T> {
+        let mut connection = self.lock();
+        if connection.is_none() {
+            *connection = Some(self.open()?);
+        }
+        match self.exchange(connection.as_mut().unwrap(), request, answer) {
+            Ok(answer) => answer,
+            Err(err) => {
+                /* a late answer would be taken for the next request's */
+                *connection = None;
+                Err(err)
+            },
+        }
+    }
+
+    /// Send `request`, failing on transport errors and returning the
+    /// server's answer otherwise.
+    fn exchange<T, F: Fn(&Value) -> Option<T>>(&self, connection: &mut BufReader<UnixStream>, request: &str, answer: F) -> std::io::Result<std::io::Result<T>> {
+        connection.get_mut().write_all(request.as_bytes())?;
+        let mut line = String::new();
+        if connection.read_line(&mut line)? == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "the server closed the connection"));
+        }
+        let invalid = || Error::new(ErrorKind::InvalidData, format!("invalid response {}", line.trim_end()));
+        let response = parse(&line).ok_or_else(invalid)?;
+        match response.get("ok") {
+            Some(&Value::Bool(true)) => match answer(&response) {
+                Some(answer) => Ok(Ok(answer)),
+                None => Err(invalid()),
+            },
+            Some(&Value::Bool(false)) => {
+                let error = response.get("error").ok_or_else(invalid)?;
+                let code = error.get("code").and_then(Value::as_str).unwrap_or("io");
+                let message = error.get("message").and_then(Value::as_str).unwrap_or(code);
+                Ok(Err(code_error(code, message)))
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl LockControl for UnixClient {
+    fn state(&self) -> std::io::Result<CFF3000State> {
+        self.request(Command::Check)
+    }
+
+    fn lock_and_verify(&self) -> std::io::Result<CFF3000State> {
+        self.request(Command::Lock)
+    }
+
+    fn unlock_and_verify(&self) -> std::io::Result<CFF3000State> {
+        self.request(Command::Unlock)
+    }
+}
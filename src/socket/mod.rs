@@ -0,0 +1,133 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Control over a Unix domain socket (`unix-socket` feature).
+//!
+//! A privileged daemon holding the GPIO lines runs a [`UnixServer`],
+//! unprivileged processes use a [`UnixClient`], which implements
+//! `LockControl` like `CFF3000` itself.
+//!
+//! # Protocol
+//!
+//! Requests and responses are JSON objects on a line of their own. A
+//! connection may carry any number of requests, which are answered in
+//! order:
+//!
+//! ```text
+//! → {"cmd":"lock"}
+//! ← {"ok":true,"state":"locked"}
+//! → {"cmd":"check","timeout_ms":5000}
+//! ← {"ok":false,"error":{"code":"no-response","message":"did not receive enough LED change events"}}
+//! ```
+//!
+//! `cmd` is "lock", "unlock" (both verified) or "check". The server
+//! waits `timeout_ms`, at most `SocketOptions::request_timeout`, for
+//! the result and then answers with the code `timeout`; the command
+//! itself stays queued. The other codes are those of the `cli` module:
+//! `config` for malformed requests, `permission-denied` for commands
+//! refused by the `PeerPolicy`, `busy` for a full queue, `no-response`,
+//! `invalid-pattern` and `io`. Requests longer than
+//! `MAX_REQUEST` bytes end the connection.
+//!
+//! `{"cmd":"history"}` is answered with the operations of the device,
+//! see `history`:
+//!
+//! ```text
+//! → {"cmd":"history"}
+//! ← {"ok":true,"history":[{"timestamp_ms":1760000000123,"command":"lock","state":"locked","duration_ms":10204}]}
+//! ```
+//!
+//! `{"cmd":"battery"}` is answered with the commands on the batteries,
+//! `{"cmd":"battery-reset"}` records new ones first, see `battery`:
+//!
+//! ```text
+//! → {"cmd":"battery-reset"}
+//! ← {"ok":true,"battery":{"since_ms":1760000000123,"replaced":true,"commands":0,"elapsed_ms":0,"budget":5000,"warning":false,"remaining":5000}}
+//! ```
+//!
+//! ```sh
+//! echo '{"cmd":"check"}' | socat - UNIX-CONNECT:/run/cff3000.sock
+//! ```
+//!
+//! # Permissions
+//!
+//! Without a policy, everybody able to connect can lock and unlock, so
+//! access is granted through the socket file: the server creates it
+//! with `SocketOptions::mode` (default `0660`) and
+//! `SocketOptions::group`, e.g. a `door` group of the allowed users.
+//! Keep the parent directory writable only by the daemon. A socket
+//! created by systemd (`systemd` feature) has the permissions of its
+//! unit instead.
+//!
+//! On a machine shared with other users, `SocketOptions::policy` checks
+//! the credentials of every connection (`SO_PEERCRED`, `getpeereid()`
+//! elsewhere) against a [`PeerPolicy`]: its `control_uids` and
+//! `control_gids` may send all commands, its `state_uids` and
+//! `state_gids` only `check`, `history` and `battery`. A group matches the primary group of the
+//! peer and, on Linux, the supplementary ones it had when connecting.
+//! Other commands are answered with `permission-denied`:
+//!
+//! ```text
+//! → {"cmd":"unlock"}
+//! ← {"ok":false,"error":{"code":"permission-denied","message":"uid 1001 may not unlock"}}
+//! ```
+//!
+//! With the `audit` feature, accepted and refused commands are recorded
+//! with the initiator `unix:uid=<uid of the peer>`.
+
+use std::io::{Error, ErrorKind};
+
+use crate::query;
+use crate::{AlreadyInUse, Command, ParseError};
+
+use crate::json::{parse, Value};
+
+mod client;
+mod server;
+
+pub use self::client::{UnixClient, DEFAULT_TIMEOUT};
+pub use self::server::{serve, serve_with_options, PeerCredentials, PeerPolicy, SocketOptions, UnixServer};
+
+/// Longest accepted request line in bytes.
+pub const MAX_REQUEST: usize = 1024;
+
+fn command_name(command: Command) -> &'static str {
+    match command {
+        Command::Lock => "lock",
+        Command::Unlock => "unlock",
+        Command::Check => "check",
+    }
+}
+
+/// Wire code of `err`, see the module documentation.
+fn error_code(err: &Error) -> &'static str {
+    let inner = err.get_ref();
+    match query::parse_error(err) {
+        Some(&ParseError::NotEnoughEvents) => return "no-response",
+        Some(_) => return "invalid-pattern",
+        None => {},
+    }
+    if inner.is_some_and(|inner| inner.is::<AlreadyInUse>()) {
+        return "busy";
+    }
+    match err.kind() {
+        ErrorKind::WouldBlock | ErrorKind::ResourceBusy => "busy",
+        ErrorKind::TimedOut => "timeout",
+        ErrorKind::InvalidInput => "config",
+        ErrorKind::PermissionDenied => "permission-denied",
+        _ => "io",
+    }
+}
+
+/// Error received with `code`, classified like the server's original.
+fn code_error(code: &str, message: &str) -> Error {
+    match code {
+        "no-response" => Error::new(ErrorKind::InvalidData, ParseError::NotEnoughEvents),
+        "invalid-pattern" => Error::new(ErrorKind::InvalidData, message.to_string()),
+        "busy" => Error::new(ErrorKind::WouldBlock, message.to_string()),
+        "timeout" => Error::new(ErrorKind::TimedOut, message.to_string()),
+        "config" => Error::new(ErrorKind::InvalidInput, message.to_string()),
+        "permission-denied" => Error::new(ErrorKind::PermissionDenied, message.to_string()),
+        _ => Error::other(message.to_string()),
+    }
+}
@@ -0,0 +1,406 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Daemon side of the socket protocol.
+
+use std::ffi::CString;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Weak};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::history;
+use crate::json::json_string;
+use crate::{Command, CommandQueue, CommandSender, CFF3000, CFF3000State, QueueOptions};
+use super::{command_name, error_code, parse, Value, MAX_REQUEST};
+
+/// Server configuration.
+#[derive(Debug, Clone)]
+pub struct SocketOptions {
+    /// Permissions of the socket file, connecting needs write access
+    pub mode: u32,
+    /// Group of the socket file, `None` to keep the daemon's
+    pub group: Option<u32>,
+    /// Longest wait for the result of a request, also the limit of its
+    /// `timeout_ms`
+    pub request_timeout: Duration,
+    /// Connections without a request for this long are closed
+    pub idle_timeout: Duration,
+    /// Queue of the commands, its depth bounds the requests waiting for
+    /// the device
+    pub queue: QueueOptions,
+    /// Peers allowed to send commands, `None` for everybody able to
+    /// connect
+    pub policy: Option<PeerPolicy>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> SocketOptions {
+        SocketOptions {
+            mode: 0o660,
+            group: None,
+            request_timeout: Duration::from_secs(60),
+            idle_timeout: Duration::from_secs(300),
+            queue: QueueOptions::default(),
+            policy: None,
+        }
+    }
+}
+
+/// User and groups of the process at the other end of a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    /// Primary group first, then on Linux the supplementary ones
+    pub gids: Vec<u32>,
+}
+
+impl PeerCredentials {
+    /// Credentials of the peer of `stream`, as of its `connect()`.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn of(stream: &UnixStream) -> std::io::Result<PeerCredentials> {
+        let mut cred = libc::ucred {pid: 0, uid: 0, gid: 0};
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        if unsafe { libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED, &mut cred as *mut _ as *mut libc::c_void, &mut len) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        let mut gids = vec![cred.gid];
+        /* SO_PEERGROUPS needs Linux 4.13, older kernels only give the primary group */
+        for gid in peer_groups(stream).unwrap_or_default() {
+            if !gids.contains(&gid) {
+                gids.push(gid);
+            }
+        }
+        Ok(PeerCredentials {uid: cred.uid, gids})
+    }
+
+    /// Credentials of the peer of `stream`, as of its `connect()`.
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn of(stream: &UnixStream) -> std::io::Result<PeerCredentials> {
+        let (mut uid, mut gid) = (0, 0);
+        match unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } {
+            0 => Ok(PeerCredentials {uid, gids: vec![gid]}),
+            _ => Err(Error::last_os_error()),
+        }
+    }
+}
+
+/// Supplementary groups of the peer of `stream`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_groups(stream: &UnixStream) -> std::io::Result<Vec<u32>> {
+    let mut gids: Vec<libc::gid_t> = vec![0; 32];
+    loop {
+        let mut len = (gids.len() * std::mem::size_of::<libc::gid_t>()) as libc::socklen_t;
+        if unsafe { libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERGROUPS, gids.as_mut_ptr() as *mut libc::c_void, &mut len) } == 0 {
+            gids.truncate(len as usize / std::mem::size_of::<libc::gid_t>());
+            return Ok(gids);
+        }
+        let err = Error::last_os_error();
+        /* len is the size needed */
+        match err.raw_os_error() {
+            Some(libc::ERANGE) if len as usize > gids.len() * std::mem::size_of::<libc::gid_t>() =>
+                gids.resize(len as usize / std::mem::size_of::<libc::gid_t>(), 0),
+            _ => return Err(err),
+        }
+    }
+}
+
+/// Peers allowed to send commands, by user or by any of their groups,
+/// see the module documentation.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerPolicy {
+    /// Users allowed to lock, unlock and check
+    pub control_uids: Vec<u32>,
+    /// Groups allowed to lock, unlock and check
+    pub control_gids: Vec<u32>,
+    /// Users allowed to check only
+    pub state_uids: Vec<u32>,
+    /// Groups allowed to check only
+    pub state_gids: Vec<u32>,
+}
+
+impl PeerPolicy {
+    /// Returns true if `peer` may send `command`.
+    pub fn allows(&self, peer: &PeerCredentials, command: Command) -> bool {
+        let member = |uids: &[u32], gids: &[u32]| uids.contains(&peer.uid) || peer.gids.iter().any(|gid| gids.contains(gid));
+        member(&self.control_uids, &self.control_gids) || (command == Command::Check && member(&self.state_uids, &self.state_gids))
+    }
+}
+
+fn ok_response(state: CFF3000State) -> String {
+    let mut out = String::from("{\"ok\":true,\"state\":");
+    json_string(&mut out, state.name());
+    out.push('}');
+    out
+}
+
+fn error_response(code: &str, message: &str) -> String {
+    let mut out = String::from("{\"ok\":false,\"error\":{\"code\":");
+    json_string(&mut out, code);
+    out.push_str(",\"message\":");
+    json_string(&mut out, message);
+    out.push_str("}}");
+    out
+}
+
+/// Answer `{"cmd":"history"}` of the client `peer`, allowed to the
+/// peers which may check.
+fn history_response(client: &Client, peer: Option<&PeerCredentials>) -> String {
+    if let Some(ref policy) = client.options.policy {
+        if !peer.is_some_and(|peer| policy.allows(peer, Command::Check)) {
+            let message = match peer {
+                Some(peer) => format!("uid {} may not read the history", peer.uid),
+                None => "peer credentials unavailable".to_string(),
+            };
+            return error_response("permission-denied", &message);
+        }
+    }
+    match client.device.upgrade() {
+        Some(device) => {
+            let mut out = String::from("{\"ok\":true,\"history\":");
+            history::write_json(&mut out, &device.history());
+            out.push('}');
+            out
+        },
+        None => error_response("io", "the device has been closed"),
+    }
+}
+
+/// Answer `{"cmd":"battery"}` of the client `peer`, allowed to the
+/// peers which may check, and `{"cmd":"battery-reset"}`, allowed to the
+/// peers which may lock.
+fn battery_response(client: &Client, peer: Option<&PeerCredentials>, reset: bool) -> String {
+    if let Some(ref policy) = client.options.policy {
+        let command = if reset {Command::Lock} else {Command::Check};
+        if !peer.is_some_and(|peer| policy.allows(peer, command)) {
+            let message = match (peer, reset) {
+                (Some(peer), true) => format!("uid {} may not record a battery change", peer.uid),
+                (Some(peer), false) => format!("uid {} may not read the battery usage", peer.uid),
+                (None, _) => "peer credentials unavailable".to_string(),
+            };
+            return error_response("permission-denied", &message);
+        }
+    }
+    match client.device.upgrade() {
+        Some(device) => {
+            if reset {
+                device.reset_battery();
+            }
+            let mut out = String::from("{\"ok\":true,\"battery\":");
+            device.battery_usage().write_json(&mut out);
+            out.push('}');
+            out
+        },
+        None => error_response("io", "the device has been closed"),
+    }
+}
+
+/// Answer one request line of the client `peer`, recorded in the audit
+/// log as `initiator`.
+fn answer(client: &Client, peer: Option<&PeerCredentials>, initiator: &str, line: &str) -> String {
+    let options = &*client.options;
+    let request = match parse(line) {
+        Some(request @ Value::Object(_)) => request,
+        _ => return error_response("config", "request is not a JSON object"),
+    };
+    let command = match request.get("cmd").and_then(Value::as_str) {
+        Some("history") => return history_response(client, peer),
+        Some("battery") => return battery_response(client, peer, false),
+        Some("battery-reset") => return battery_response(client, peer, true),
+        Some(name) => match [Command::Lock, Command::Unlock, Command::Check].iter().find(|&&command| command_name(command) == name) {
+            Some(&command) => command,
+            None => return error_response("config", &format!("unknown cmd {}", name)),
+        },
+        None => return error_response("config", "missing cmd"),
+    };
+    let timeout = match request.get("timeout_ms") {
+        None => options.request_timeout,
+        Some(&Value::Number(ms)) if ms >= 0.0 => std::cmp::min(Duration::from_millis(ms as u64), options.request_timeout),
+        Some(_) => return error_response("config", "timeout_ms must be a non-negative number"),
+    };
+    if let Some(ref policy) = options.policy {
+        if !peer.is_some_and(|peer| policy.allows(peer, command)) {
+            let message = match peer {
+                Some(peer) => format!("uid {} may not {}", peer.uid, command_name(command)),
+                None => "peer credentials unavailable".to_string(),
+            };
+            let err = Error::new(ErrorKind::PermissionDenied, message);
+            let response = error_response(error_code(&err), &err.to_string());
+            if let Some(device) = client.device.upgrade() {
+                let _ = device.audited(command, initiator, || Err::<(), _>(err), |_| None);
+            }
+            return response;
+        }
+    }
+    match client.sender.send_as(command, initiator).recv_timeout(timeout) {
+        Ok(Ok(state)) => ok_response(state),
+        Ok(Err(err)) => error_response(error_code(&err), &err.to_string()),
+        Err(RecvTimeoutError::Timeout) => error_response("timeout", &format!("no result within {} ms", timeout.as_millis())),
+        Err(RecvTimeoutError::Disconnected) => error_response("io", "command queue has been shut down"),
+    }
+}
+
+/// What the connections share.
+#[derive(Clone)]
+struct Client {
+    /// Only for the audit log, the history and the battery, idle
+    /// connections keep no device open
+    device: Weak<CFF3000>,
+    sender: CommandSender,
+    options: Arc<SocketOptions>,
+}
+
+/// Serve the requests of one client until it disconnects or idles.
+fn handle(client: &Client, stream: UnixStream) {
+    let _ = stream.set_read_timeout(Some(client.options.idle_timeout));
+    let _ = stream.set_write_timeout(Some(client.options.request_timeout));
+    let mut reader = BufReader::new(match stream.try_clone() {
+        Ok(reader) => reader,
+        Err(_) => return,
+    });
+    let peer = PeerCredentials::of(&stream).ok();
+    let initiator = match peer {
+        Some(ref peer) => format!("unix:uid={}", peer.uid),
+        None => "unix".to_string(),
+    };
+    let mut writer = stream;
+    loop {
+        let mut line = String::new();
+        match reader.by_ref().take(MAX_REQUEST as u64 + 1).read_line(&mut line) {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {},
+        }
+        let too_long = line.len() > MAX_REQUEST;
+        let mut response = match too_long {
+            true => error_response("config", "request too long"),
+            false => answer(client, peer.as_ref(), &initiator, &line),
+        };
+        response.push('\n');
+        if writer.write_all(response.as_bytes()).is_err() || too_long {
+            return;
+        }
+    }
+}
+
+/// Remove a socket file left behind by a server which is gone, fail
+/// with `ErrorKind::AddrInUse` for a live one or another kind of file.
+fn remove_stale(path: &Path) -> std::io::Result<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(Error::new(ErrorKind::AddrInUse, format!("{} exists and is not a socket", path.display())));
+    }
+    match UnixStream::connect(path) {
+        Ok(_) => Err(Error::new(ErrorKind::AddrInUse, format!("{} is served by another process", path.display()))),
+        Err(_) => std::fs::remove_file(path),
+    }
+}
+
+fn set_group(path: &Path, gid: u32) -> std::io::Result<()> {
+    let cpath = CString::new(path.as_os_str().as_bytes()).map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+    match unsafe { libc::chown(cpath.as_ptr(), !0, gid) } {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error()),
+    }
+}
+
+/// Running server, see the module documentation. Dropping it stops
+/// accepting connections, removes the socket file (unless systemd owns
+/// it) and waits for the queued commands.
+pub struct UnixServer {
+    path: PathBuf,
+    /// false for a socket passed by systemd, which keeps its file
+    owns_file: bool,
+    stopped: Arc<AtomicBool>,
+    acceptor: Option<JoinHandle<()>>,
+    _queue: CommandQueue,
+}
+
+impl UnixServer {
+    /// Path of the socket file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for UnixServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        /* wake up accept() */
+        let _ = UnixStream::connect(&self.path);
+        if let Some(acceptor) = self.acceptor.take() {
+            let _ = acceptor.join();
+        }
+        if self.owns_file {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Serve `cff3000` on the socket `path` with default options.
+pub fn serve<P: AsRef<Path>>(cff3000: Arc<CFF3000>, path: P) -> std::io::Result<UnixServer> {
+    serve_with_options(cff3000, path, SocketOptions::default())
+}
+
+/// Bind the socket `path` with the permissions of `options`.
+fn bind(path: &Path, options: &SocketOptions) -> std::io::Result<UnixListener> {
+    remove_stale(path)?;
+    let listener = UnixListener::bind(path)?;
+    let configured = std::fs::set_permissions(path, std::fs::Permissions::from_mode(options.mode))
+        .and_then(|_| options.group.map_or(Ok(()), |gid| set_group(path, gid)));
+    if let Err(err) = configured {
+        let _ = std::fs::remove_file(path);
+        return Err(err);
+    }
+    Ok(listener)
+}
+
+/// Serve `cff3000` on the socket `path` with `options`. A socket file
+/// left behind by a crashed server is replaced, other existing files
+/// are not.
+///
+/// With the `systemd` feature, a socket passed by systemd is served
+/// instead of `path`, see `systemd`.
+pub fn serve_with_options<P: AsRef<Path>>(cff3000: Arc<CFF3000>, path: P, options: SocketOptions) -> std::io::Result<UnixServer> {
+    #[cfg(feature = "systemd")]
+    let passed = crate::systemd::unix_listener()?;
+    #[cfg(not(feature = "systemd"))]
+    let passed: Option<UnixListener> = None;
+    let (listener, path, owns_file) = match passed {
+        Some(listener) => {
+            let bound = listener.local_addr()?.as_pathname().map(Path::to_path_buf);
+            (listener, bound.unwrap_or_else(|| path.as_ref().to_path_buf()), false)
+        },
+        None => (bind(path.as_ref(), &options)?, path.as_ref().to_path_buf(), true),
+    };
+    let queue = CommandQueue::with_options(cff3000.clone(), options.queue)?;
+    let client = Client {device: Arc::downgrade(&cff3000), sender: queue.sender(), options: Arc::new(options)};
+    let stopped = Arc::new(AtomicBool::new(false));
+
+    let stop = stopped.clone();
+    let acceptor = std::thread::Builder::new().name("cff3000-socket".to_string()).spawn(move || {
+        for stream in listener.incoming() {
+            if stop.load(Ordering::SeqCst) {
+                return;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let client = client.clone();
+            /* not joined, a connection ends with its client or idle_timeout */
+            let _ = std::thread::Builder::new().name("cff3000-socket-client".to_string()).spawn(move || handle(&client, stream));
+        }
+    })?;
+    Ok(UnixServer {path, owns_file, stopped, acceptor: Some(acceptor), _queue: queue})
+}
@@ -0,0 +1,162 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Spans of the operations of a device.
+//!
+//! With the `tracing` feature every `lock()`, `unlock()`, `check()` and
+//! state query is a `tracing` span at the `INFO` level, named after
+//! the operation ("lock", "unlock", "check", "state", "lock_and_verify"
+//! or "unlock_and_verify"). The capture of a state query and the
+//! classification of the captured pattern are spans of their own
+//! ("capture" and "parse") within it, so the time of a query shows as
+//! the press, the capture window and the parser. The spans carry the
+//! fields
+//!
+//! - `device`: `CFF3000Builder::label()`, by default the chip node,
+//! - `press_ms`: the press duration of the operation,
+//! - `events`: the LED events captured,
+//! - `state`: the classified state, e.g. "locked", and
+//! - `error`: the code of a failure, e.g. "no-response".
+//!
+//! Without the feature a span ends as a `debug` record of the `log`
+//! facade with the same fields and the time it took, e.g. "state on
+//! /dev/gpiochip2 took 11.02s (press_ms=500, events=14,
+//! state=locked)". See the `daemon` module for wiring a subscriber.
+
+#[cfg(not(feature = "tracing"))]
+use std::time::Instant;
+use std::time::Duration;
+
+use crate::codes::ErrorCode;
+use crate::CFF3000State;
+
+/// Operation of a span, see the module documentation.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Operation {
+    Lock,
+    Unlock,
+    Check,
+    State,
+    LockAndVerify,
+    UnlockAndVerify,
+    Capture,
+    Parse,
+}
+
+impl Operation {
+    #[cfg(not(feature = "tracing"))]
+    fn name(self) -> &'static str {
+        match self {
+            Operation::Lock => "lock",
+            Operation::Unlock => "unlock",
+            Operation::Check => "check",
+            Operation::State => "state",
+            Operation::LockAndVerify => "lock_and_verify",
+            Operation::UnlockAndVerify => "unlock_and_verify",
+            Operation::Capture => "capture",
+            Operation::Parse => "parse",
+        }
+    }
+}
+
+/// Span of one operation, closed on drop.
+pub(crate) struct OperationSpan {
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+    #[cfg(not(feature = "tracing"))]
+    operation: Operation,
+    #[cfg(not(feature = "tracing"))]
+    label: Option<String>,
+    #[cfg(not(feature = "tracing"))]
+    started: Instant,
+    #[cfg(not(feature = "tracing"))]
+    fields: Vec<(&'static str, String)>,
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! operation_span {
+    ($name:expr, $label:expr) => {
+        tracing::info_span!(
+            $name,
+            device = $label,
+            press_ms = tracing::field::Empty,
+            events = tracing::field::Empty,
+            state = tracing::field::Empty,
+            error = tracing::field::Empty,
+        )
+    };
+}
+
+impl OperationSpan {
+    /// Open the span of `operation` on the device `label`, within the
+    /// span entered on this thread, if any.
+    #[cfg(feature = "tracing")]
+    pub(crate) fn new(operation: Operation, label: Option<&str>) -> OperationSpan {
+        let span = match operation {
+            Operation::Lock => operation_span!("lock", label),
+            Operation::Unlock => operation_span!("unlock", label),
+            Operation::Check => operation_span!("check", label),
+            Operation::State => operation_span!("state", label),
+            Operation::LockAndVerify => operation_span!("lock_and_verify", label),
+            Operation::UnlockAndVerify => operation_span!("unlock_and_verify", label),
+            Operation::Capture => operation_span!("capture", label),
+            Operation::Parse => operation_span!("parse", label),
+        };
+        OperationSpan {span}
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    pub(crate) fn new(operation: Operation, label: Option<&str>) -> OperationSpan {
+        OperationSpan {operation, label: label.map(str::to_string), started: Instant::now(), fields: Vec::new()}
+    }
+
+    /// Run `f` within the span, so spans opened by it are nested.
+    pub(crate) fn in_scope<T, F: FnOnce() -> T>(&self, f: F) -> T {
+        #[cfg(feature = "tracing")]
+        let _entered = self.span.enter();
+        f()
+    }
+
+    #[cfg(feature = "tracing")]
+    fn record(&mut self, field: &'static str, value: &dyn tracing::Value) {
+        self.span.record(field, value);
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    fn record(&mut self, field: &'static str, value: &dyn ToString) {
+        self.fields.retain(|&(name, _)| name != field);
+        self.fields.push((field, value.to_string()));
+    }
+
+    pub(crate) fn record_press(&mut self, duration: Duration) {
+        self.record("press_ms", &(duration.as_millis() as u64));
+    }
+
+    pub(crate) fn record_events(&mut self, events: usize) {
+        self.record("events", &(events as u64));
+    }
+
+    /// Record the state or the error code of `result`.
+    pub(crate) fn record_result<T>(&mut self, result: &std::io::Result<T>, state: Option<CFF3000State>) {
+        match *result {
+            Ok(_) => {
+                if let Some(state) = state {
+                    self.record("state", &state.name());
+                }
+            },
+            Err(ref err) => self.record("error", &ErrorCode::of(err).name()),
+        }
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+impl Drop for OperationSpan {
+    fn drop(&mut self) {
+        let on = self.label.as_ref().map_or_else(String::new, |label| format!(" on {}", label));
+        let fields: Vec<String> = self.fields.iter().map(|&(name, ref value)| format!("{}={}", name, value)).collect();
+        match fields.is_empty() {
+            true => log::debug!("{}{} took {:?}", self.operation.name(), on, self.started.elapsed()),
+            false => log::debug!("{}{} took {:?} ({})", self.operation.name(), on, self.started.elapsed(), fields.join(", ")),
+        }
+    }
+}
@@ -0,0 +1,266 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Legacy sysfs GPIO backend (`/sys/class/gpio`).
+//!
+//! For kernels without GPIO character devices. GPIOs are addressed by
+//! their global sysfs number, exported on construction and unexported
+//! again on drop (unless they had already been exported before).
+//!
+//! # Timing accuracy
+//!
+//! The sysfs interface does not timestamp edges. This backend waits for
+//! edges with poll(2) on the `value` files and timestamps each change
+//! when it reads the new level, so timestamps include the wakeup and
+//! scheduling latency of the process (usually a few ms, more on a
+//! loaded system). Two edges of the same LED arriving before the value
+//! has been read are seen as a single (or no) change. To compensate,
+//! the default merge window is raised to 100 ms, which is still far
+//! below the LED blink period.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::backend::poll_timeout_ms;
+use crate::clock::deadline;
+use crate::{Button, GpioBackend, Led, LedEvent, ParseOptions};
+
+const SYSFS_GPIO: &str = "/sys/class/gpio";
+
+/// Merge window used with sysfs timestamps, see module documentation.
+pub const SYSFS_MERGE_WINDOW: Duration = Duration::from_millis(100);
+
+/// Exported GPIO, unexported on drop if exported by us.
+struct Export {
+    gpio: u32,
+    exported: bool,
+}
+
+impl Export {
+    fn new(gpio: u32) -> std::io::Result<Export> {
+        let path = format!("{}/gpio{}", SYSFS_GPIO, gpio);
+        if std::path::Path::new(&path).exists() {
+            return Ok(Export {gpio, exported: false});
+        }
+
+        write_attr(&format!("{}/export", SYSFS_GPIO), &gpio.to_string())?;
+        let export = Export {gpio, exported: true};
+
+        /* udev may still be adjusting permissions of the new files */
+        let direction = format!("{}/direction", path);
+        for _ in 0..50 {
+            if OpenOptions::new().write(true).open(&direction).is_ok() {
+                return Ok(export);
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        Err(Error::new(ErrorKind::PermissionDenied, format!("gpio{} exported, but not accessible", gpio)))
+    }
+
+    fn attr(&self, name: &str) -> String {
+        format!("{}/gpio{}/{}", SYSFS_GPIO, self.gpio, name)
+    }
+}
+
+impl Drop for Export {
+    fn drop(&mut self) {
+        if self.exported {
+            let _ = write_attr(&format!("{}/unexport", SYSFS_GPIO), &self.gpio.to_string());
+        }
+    }
+}
+
+fn write_attr(path: &str, value: &str) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.write_all(value.as_bytes())
+}
+
+fn read_level(file: &File) -> std::io::Result<bool> {
+    let mut buf = [0u8; 2];
+    let len = file.read_at(&mut buf, 0)?;
+    match buf[..len].first() {
+        Some(&b'1') => Ok(true),
+        Some(&b'0') => Ok(false),
+        _ => Err(Error::new(ErrorKind::InvalidData, "unexpected sysfs GPIO value")),
+    }
+}
+
+struct Output {
+    value: File,
+    _export: Export,
+}
+
+impl Output {
+    fn new(gpio: u32) -> std::io::Result<Output> {
+        let export = Export::new(gpio)?;
+        /* "low" configures the direction and level atomically */
+        write_attr(&export.attr("direction"), "low")?;
+        let value = OpenOptions::new().write(true).open(export.attr("value"))?;
+        Ok(Output {value, _export: export})
+    }
+
+    fn set(&self, level: bool) -> std::io::Result<()> {
+        self.value.write_at(if level {b"1"} else {b"0"}, 0)?;
+        Ok(())
+    }
+}
+
+struct InputState {
+    level: bool,
+    pending: std::collections::VecDeque<LedEvent>,
+}
+
+struct Input {
+    led: Led,
+    value: File,
+    state: Mutex<InputState>,
+    _export: Export,
+}
+
+impl Input {
+    fn new(gpio: u32, led: Led) -> std::io::Result<Input> {
+        let export = Export::new(gpio)?;
+        write_attr(&export.attr("direction"), "in")?;
+        write_attr(&export.attr("edge"), "both")?;
+        let value = File::open(export.attr("value"))?;
+        /* reading clears the initial poll condition */
+        let level = read_level(&value)?;
+        Ok(Input {led, value, state: Mutex::new(InputState {level, pending: Default::default()}), _export: export})
+    }
+
+    /// Read the current level and queue an event if it changed.
+    fn update(&self, timestamp: Duration) -> std::io::Result<()> {
+        let level = read_level(&self.value)?;
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if level != state.level {
+            state.level = level;
+            state.pending.push_back(LedEvent {led: self.led, on: level, timestamp});
+        }
+        Ok(())
+    }
+
+    fn has_pending(&self) -> bool {
+        !self.state.lock().unwrap_or_else(|e| e.into_inner()).pending.is_empty()
+    }
+}
+
+/// Backend using the sysfs GPIO interface.
+pub struct SysfsBackend {
+    red: Input,
+    green: Input,
+    unlock: Output,
+    lock: Output,
+    start: Instant,
+}
+
+impl SysfsBackend {
+    /// Export and configure the GPIOs.
+    ///
+    /// `gpios` contains the global sysfs GPIO numbers for LED red, LED
+    /// green, button unlock and button lock (in this order).
+    pub fn new(gpios: [u32; 4]) -> std::io::Result<SysfsBackend> {
+        let red = Input::new(gpios[0], Led::Red)?;
+        let green = Input::new(gpios[1], Led::Green)?;
+        let unlock = Output::new(gpios[2])?;
+        let lock = Output::new(gpios[3])?;
+        Ok(SysfsBackend {red, green, unlock, lock, start: Instant::now()})
+    }
+
+    fn input(&self, led: Led) -> &Input {
+        match led {
+            Led::Red => &self.red,
+            Led::Green => &self.green,
+        }
+    }
+
+    fn pending_mask(&self) -> u8 {
+        let mut mask = 0;
+        for input in &[&self.red, &self.green] {
+            if input.has_pending() {
+                mask |= input.led.mask();
+            }
+        }
+        mask
+    }
+
+    fn timestamp(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+impl Drop for SysfsBackend {
+    fn drop(&mut self) {
+        let _ = self.unlock.set(false);
+        let _ = self.lock.set(false);
+    }
+}
+
+impl GpioBackend for SysfsBackend {
+    fn set_button(&self, button: Button, pressed: bool) -> std::io::Result<()> {
+        match button {
+            Button::Unlock => self.unlock.set(pressed),
+            Button::Lock => self.lock.set(pressed),
+        }
+    }
+
+    fn wait_for_led_events(&self, timeout: Duration) -> std::io::Result<u8> {
+        let deadline = deadline(Instant::now(), timeout);
+
+        loop {
+            let mask = self.pending_mask();
+            if mask != 0 {
+                return Ok(mask);
+            }
+
+            /* poll at least once, even for a zero timeout */
+            let now = Instant::now();
+            let remaining = if deadline > now {deadline - now} else {Duration::from_millis(0)};
+            let mut fds = [
+                libc::pollfd {fd: self.red.value.as_raw_fd(), events: libc::POLLPRI | libc::POLLERR, revents: 0},
+                libc::pollfd {fd: self.green.value.as_raw_fd(), events: libc::POLLPRI | libc::POLLERR, revents: 0},
+            ];
+            let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, poll_timeout_ms(remaining)) };
+            if ret < 0 {
+                let err = Error::last_os_error();
+                if err.kind() != ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+
+            let timestamp = self.timestamp();
+            for (fd, input) in fds.iter().zip(&[&self.red, &self.green]) {
+                if fd.revents != 0 {
+                    input.update(timestamp)?;
+                }
+            }
+
+            if Instant::now() >= deadline {
+                return Ok(self.pending_mask());
+            }
+        }
+    }
+
+    fn read_led_event(&self, led: Led) -> std::io::Result<LedEvent> {
+        let mut state = self.input(led).state.lock().unwrap_or_else(|e| e.into_inner());
+        state.pending.pop_front().ok_or_else(|| Error::new(ErrorKind::WouldBlock, "no pending LED event"))
+    }
+
+    fn flush_led_events(&self) -> std::io::Result<()> {
+        for input in &[&self.red, &self.green] {
+            let level = read_level(&input.value)?;
+            let mut state = input.state.lock().unwrap_or_else(|e| e.into_inner());
+            state.level = level;
+            state.pending.clear();
+        }
+        Ok(())
+    }
+
+    fn parse_options(&self) -> ParseOptions {
+        ParseOptions {merge_window: SYSFS_MERGE_WINDOW, ..ParseOptions::default()}
+    }
+}
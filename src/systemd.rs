@@ -0,0 +1,259 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! systemd service notifications (`systemd` feature).
+//!
+//! A daemon started by a unit with `Type=notify` tells systemd with
+//! [`Notifier::ready()`] that it has acquired its GPIO lines and read
+//! the lock once, so units ordered after it do not start too early. With
+//! `WatchdogSec=` set, systemd restarts the daemon unless
+//! [`Notifier::watchdog()`] is called regularly; calling it as the
+//! heartbeat of `CFF3000::watch_with_heartbeat()` with
+//! `WatchOptions::heartbeat` set to [`Notifier::watchdog_interval()`]
+//! restarts a daemon whose monitor loop got stuck.
+//!
+//! ```ini
+//! [Service]
+//! Type=notify
+//! ExecStart=/usr/bin/cff3000 watch
+//! WatchdogSec=60
+//! ```
+//!
+//! The `sd_notify` protocol is spoken directly: datagrams to the socket
+//! in `NOTIFY_SOCKET`, no libsystemd needed. Outside of systemd the
+//! variable is not set and `Notifier::from_env()` returns `None`.
+//!
+//! # Socket activation
+//!
+//! With a `.socket` unit, systemd owns the listening socket, starts the
+//! daemon on the first connection and keeps accepting connections
+//! while it restarts. The servers of the `unix-socket` and `http`
+//! features look for sockets passed this way (`LISTEN_FDS`) before
+//! binding their own: `socket::serve()` takes the Unix stream socket,
+//! leaving its file and permissions to systemd (`SocketMode=`,
+//! `SocketGroup=`), `http::serve()` the TCP socket, ignoring the
+//! address given to them. Without passed sockets, they bind as usual.
+//!
+//! ```ini
+//! [Socket]
+//! ListenStream=/run/cff3000.sock
+//! SocketMode=0660
+//! SocketGroup=door
+//! ```
+//!
+//! A server fails with `ErrorKind::InvalidInput` if a passed socket is
+//! not a listening stream socket or more than one socket of its kind
+//! has been passed, so a wrong unit does not go unnoticed.
+
+use std::io::{Error, ErrorKind};
+use std::net::TcpListener;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Destination of the notifications.
+#[derive(Debug, Clone)]
+enum Address {
+    Path(PathBuf),
+    /// Linux abstract socket, given as `@name`
+    #[cfg(target_os = "linux")]
+    Abstract(Vec<u8>),
+}
+
+/// Sender of notifications to the service manager.
+#[derive(Debug)]
+pub struct Notifier {
+    socket: UnixDatagram,
+    address: Address,
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    /// Notifier for the service manager of this process, `None` if
+    /// `NOTIFY_SOCKET` is not set. The watchdog is enabled if
+    /// `WATCHDOG_USEC` is set and `WATCHDOG_PID`, if set, is this
+    /// process.
+    pub fn from_env() -> std::io::Result<Option<Notifier>> {
+        let socket = match std::env::var_os("NOTIFY_SOCKET") {
+            Some(socket) => socket,
+            None => return Ok(None),
+        };
+        let mut notifier = Notifier::connect(socket)?;
+        let for_us = std::env::var("WATCHDOG_PID").ok().is_none_or(|pid| pid.trim() == std::process::id().to_string());
+        notifier.watchdog = match std::env::var("WATCHDOG_USEC") {
+            Ok(ref usec) if for_us => match usec.trim().parse() {
+                Ok(0) => None,
+                Ok(usec) => Some(Duration::from_micros(usec)),
+                Err(_) => return Err(Error::new(ErrorKind::InvalidInput, format!("invalid WATCHDOG_USEC {}", usec))),
+            },
+            _ => None,
+        };
+        Ok(Some(notifier))
+    }
+
+    /// Notifier sending to `socket`, a path or an abstract address
+    /// starting with `@`, without watchdog.
+    pub fn connect<S: Into<std::ffi::OsString>>(socket: S) -> std::io::Result<Notifier> {
+        let socket = socket.into();
+        let address = match socket.to_str().and_then(|socket| socket.strip_prefix('@')) {
+            #[cfg(target_os = "linux")]
+            Some(name) => Address::Abstract(name.as_bytes().to_vec()),
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => return Err(Error::new(ErrorKind::Unsupported, "abstract notification sockets need Linux")),
+            None if socket.is_empty() => return Err(Error::new(ErrorKind::InvalidInput, "empty NOTIFY_SOCKET")),
+            None => Address::Path(PathBuf::from(socket)),
+        };
+        Ok(Notifier {socket: UnixDatagram::unbound()?, address, watchdog: None})
+    }
+
+    /// Send `state`, newline separated `VARIABLE=value` assignments.
+    pub fn notify(&self, state: &str) -> std::io::Result<()> {
+        let sent = match self.address {
+            Address::Path(ref path) => self.socket.send_to(state.as_bytes(), path),
+            #[cfg(target_os = "linux")]
+            Address::Abstract(ref name) => {
+                use std::os::linux::net::SocketAddrExt;
+                let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                self.socket.send_to_addr(state.as_bytes(), &address)
+            },
+        };
+        match sent? {
+            n if n == state.len() => Ok(()),
+            _ => Err(Error::new(ErrorKind::WriteZero, "notification truncated")),
+        }
+    }
+
+    /// Startup is complete (`READY=1`).
+    pub fn ready(&self) -> std::io::Result<()> {
+        self.notify("READY=1")
+    }
+
+    /// The daemon is alive (`WATCHDOG=1`).
+    pub fn watchdog(&self) -> std::io::Result<()> {
+        self.notify("WATCHDOG=1")
+    }
+
+    /// Shutdown has begun (`STOPPING=1`).
+    pub fn stopping(&self) -> std::io::Result<()> {
+        self.notify("STOPPING=1")
+    }
+
+    /// Status line shown by `systemctl status` (`STATUS=`).
+    pub fn status(&self, text: &str) -> std::io::Result<()> {
+        /* a newline would start another assignment */
+        self.notify(&format!("STATUS={}", text.replace('\n', " ")))
+    }
+
+    /// Timeout of the watchdog, `None` if it is not enabled.
+    pub fn watchdog_timeout(&self) -> Option<Duration> {
+        self.watchdog
+    }
+
+    /// Interval to call `watchdog()` at: half the timeout, as systemd
+    /// recommends.
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog.map(|timeout| timeout / 2)
+    }
+}
+
+/// First file descriptor passed by systemd.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Sockets passed by systemd and not yet taken by a server.
+static PASSED: Mutex<Vec<OwnedFd>> = Mutex::new(Vec::new());
+
+/// Add the sockets passed to this process to `passed` and remove the
+/// variables describing them, so children do not take them for theirs.
+fn take_passed(passed: &mut Vec<OwnedFd>) -> std::io::Result<()> {
+    let count = match std::env::var("LISTEN_FDS") {
+        Ok(count) => count,
+        Err(_) => return Ok(()),
+    };
+    let pid = std::env::var("LISTEN_PID").ok();
+    for name in &["LISTEN_FDS", "LISTEN_PID", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+    if pid.is_none_or(|pid| pid.trim() != std::process::id().to_string()) {
+        return Ok(());
+    }
+    let count: RawFd = count.trim().parse().map_err(|_| Error::new(ErrorKind::InvalidInput, format!("invalid LISTEN_FDS {}", count)))?;
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        /* systemd hands them over to this process */
+        passed.push(unsafe { OwnedFd::from_raw_fd(fd) });
+    }
+    Ok(())
+}
+
+/// Address family of a listening stream socket.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Family {
+    Unix,
+    Inet,
+}
+
+fn socket_option(fd: RawFd, option: libc::c_int) -> std::io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    match unsafe { libc::getsockopt(fd, libc::SOL_SOCKET, option, &mut value as *mut _ as *mut libc::c_void, &mut len) } {
+        0 => Ok(value),
+        _ => Err(Error::last_os_error()),
+    }
+}
+
+/// Family of `fd`, `None` if it is not a listening stream socket.
+fn listener_family(fd: RawFd) -> Option<Family> {
+    if socket_option(fd, libc::SO_TYPE).ok()? != libc::SOCK_STREAM || socket_option(fd, libc::SO_ACCEPTCONN).ok()? == 0 {
+        return None;
+    }
+    let mut address: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    if unsafe { libc::getsockname(fd, &mut address as *mut _ as *mut libc::sockaddr, &mut len) } != 0 {
+        return None;
+    }
+    match address.ss_family as libc::c_int {
+        libc::AF_UNIX => Some(Family::Unix),
+        libc::AF_INET | libc::AF_INET6 => Some(Family::Inet),
+        _ => None,
+    }
+}
+
+/// Take the passed socket of `family`, see the module documentation.
+fn take_listener(family: Family, kind: &str) -> std::io::Result<Option<OwnedFd>> {
+    let mut passed = PASSED.lock().unwrap_or_else(|e| e.into_inner());
+    take_passed(&mut passed)?;
+    let mut matching = Vec::new();
+    for (i, fd) in passed.iter().enumerate() {
+        match listener_family(fd.as_raw_fd()) {
+            Some(found) if found == family => matching.push(i),
+            Some(_) => {},
+            None => {
+                let message = format!("descriptor {} passed by systemd is not a listening stream socket", fd.as_raw_fd());
+                passed.clear();
+                return Err(Error::new(ErrorKind::InvalidInput, message));
+            },
+        }
+    }
+    match matching.len() {
+        0 => Ok(None),
+        1 => Ok(Some(passed.remove(matching[0]))),
+        n => {
+            passed.clear();
+            Err(Error::new(ErrorKind::InvalidInput, format!("{} {} sockets passed by systemd, expected one", n, kind)))
+        },
+    }
+}
+
+/// The Unix stream socket passed by systemd, `None` without.
+pub fn unix_listener() -> std::io::Result<Option<UnixListener>> {
+    take_listener(Family::Unix, "Unix stream").map(|fd| fd.map(UnixListener::from))
+}
+
+/// The TCP socket passed by systemd, `None` without.
+pub fn tcp_listener() -> std::io::Result<Option<TcpListener>> {
+    take_listener(Family::Inet, "TCP").map(|fd| fd.map(TcpListener::from))
+}
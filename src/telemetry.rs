@@ -0,0 +1,99 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Hooks for the operations of a device, see
+//! `CFF3000Builder::telemetry()`.
+//!
+//! A [`Telemetry`] hook is called once at the end of every operation,
+//! wherever it has been started, at the points covered by the spans of
+//! `spans`:
+//!
+//! - `on_command()` after a plain press like `CFF3000::lock()`,
+//! - `on_state()` after a state query or a verified command, and
+//! - `on_error()` after an operation failed, with the LED events
+//!   captured until then, e.g. for an error report service.
+//!
+//! All methods do nothing by default. A device calls its hooks in the
+//! order they have been added, on the thread running the operation and
+//! while the device is still busy, so they should return quickly. A
+//! panicking hook is logged as a `warn` record of the `log` facade and
+//! the operation goes on as if it had returned.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::codes::ErrorCode;
+use crate::{CFF3000State, Command, LedEvent};
+
+/// Failed operation passed to `Telemetry::on_error()`.
+#[derive(Debug)]
+pub struct TelemetryError<'a> {
+    pub command: Command,
+    /// `error.code` of the `cli` feature, e.g. "no-response"
+    pub code: &'static str,
+    pub error: &'a std::io::Error,
+    /// LED events captured before the failure, empty for plain presses
+    pub events: &'a [LedEvent],
+    pub duration: Duration,
+}
+
+/// Hook for the operations of a device, see the module documentation.
+pub trait Telemetry: Send + Sync {
+    /// A plain press of `command` has been released after `duration`.
+    fn on_command(&self, command: Command, duration: Duration) {
+        let _ = (command, duration);
+    }
+
+    /// `command` read `state` after `duration`.
+    fn on_state(&self, command: Command, state: CFF3000State, duration: Duration) {
+        let _ = (command, state, duration);
+    }
+
+    /// An operation failed.
+    fn on_error(&self, error: &TelemetryError) {
+        let _ = error;
+    }
+}
+
+/// Hooks of a device.
+#[derive(Clone, Default)]
+pub(crate) struct Hooks(Vec<Arc<dyn Telemetry>>);
+
+impl Hooks {
+    pub(crate) fn add(&mut self, hook: Arc<dyn Telemetry>) {
+        self.0.push(hook);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Call every hook with `f`, catching their panics.
+    fn each<F: Fn(&dyn Telemetry)>(&self, f: F) {
+        for hook in &self.0 {
+            if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| f(&**hook))) {
+                let message = panic.downcast_ref::<&str>().map(|message| message.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_string());
+                log::warn!("telemetry hook panicked: {}", message);
+            }
+        }
+    }
+
+    /// Report the end of `command`, which read `state` if it is a state
+    /// query, see `Telemetry`.
+    pub(crate) fn operation<T>(&self, command: Command, result: &std::io::Result<T>, state: Option<CFF3000State>, events: &[LedEvent], duration: Duration) {
+        match (result, state) {
+            (&Ok(_), Some(state)) => self.each(|hook| hook.on_state(command, state, duration)),
+            (&Ok(_), None) => self.each(|hook| hook.on_command(command, duration)),
+            (Err(error), _) => self.error(command, error, events, duration),
+        }
+    }
+
+    /// Report the failure of `command`.
+    pub(crate) fn error(&self, command: Command, error: &std::io::Error, events: &[LedEvent], duration: Duration) {
+        let error = TelemetryError {command, code: ErrorCode::of(error).name(), error, events, duration};
+        self.each(|hook| hook.on_error(&error));
+    }
+}
@@ -0,0 +1,750 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Deterministic replay of captured LED events.
+//!
+//! [`TestClock`] is a virtual clock: time only passes when a test
+//! advances it or something sleeps on it, and sleeping returns
+//! immediately. Set as the device's clock with
+//! `CFF3000Builder::clock()`, all press durations, capture windows,
+//! retries and watch intervals run without real waits.
+//!
+//! [`Replay`] is a `GpioBackend` on such a clock. Each button press
+//! starts the next of its captures: the events of that capture become
+//! readable once the virtual time has advanced past their timestamp
+//! (relative to the press), exactly as if real hardware produced them.
+//! Waiting for LED events advances the virtual time to the next event,
+//! so a device using the replay's clock completes even blocking
+//! operations like `state()` instantly. Events outside the capture
+//! window are cut off like they would be in a live query.
+//!
+//! [`generate()`] produces realistic captures for every state from the
+//! pattern table used by the parser, with tunable timing and injected
+//! faults. [`Replay::with_captures()`] queues such captures by state and
+//! [`device()`] builds a device on the replay.
+//!
+//! [`Fixture`] is a capture recorded on real hardware together with the
+//! state the device showed, stored as a small text file. The fixtures in
+//! `tests/fixtures/` are checked by `cargo test --features testing`.
+//!
+//! [`FakeLock`] is a `LockControl` without any hardware for testing
+//! code written against the trait, e.g. automations driving several
+//! kinds of locks.
+//!
+//! Unlike `mock::MockBackend`, which plays back LED level scripts on the
+//! system clock, replay works on `LedEvent` level, so captured logs
+//! (e.g. from `StateReport::events`) can be fed back unchanged.
+//!
+//! # Example
+//! ```
+//! use std::time::Duration;
+//!
+//! use cff3000::testing::{self, Replay};
+//! use cff3000::{CFF3000State, Led, LedEvent};
+//!
+//! fn main() {
+//!     let ms = Duration::from_millis;
+//!     let replay = Replay::new();
+//!     replay.push_capture(vec![
+//!         LedEvent {led: Led::Red, on: true, timestamp: ms(700)},
+//!         LedEvent {led: Led::Green, on: true, timestamp: ms(700)},
+//!         LedEvent {led: Led::Red, on: false, timestamp: ms(1700)},
+//!         LedEvent {led: Led::Green, on: false, timestamp: ms(4700)},
+//!     ]);
+//!
+//!     let cff3000 = testing::device(&replay).build().unwrap();
+//!     assert_eq!(cff3000.state().unwrap(), CFF3000State::Locked);
+//!     assert_eq!(replay.elapsed().as_secs(), 8);
+//! }
+//! ```
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+
+use crate::mock::Transition;
+use crate::{Button, Clock, Command, CFF3000, CFF3000Builder, CFF3000State, DeviceProfile, EventBuffer, GpioBackend, Led, LedEvent, LockControl, StateQuery, StateReport, StopToken, TimingProfile, LED_GREEN, LED_RED};
+
+struct TimeInner {
+    base: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+/// Virtual `Clock` for deterministic tests, see module documentation.
+///
+/// `sleep()` advances the clock by the slept duration and returns
+/// immediately. Clones share their time.
+#[derive(Clone)]
+pub struct TestClock {
+    inner: Arc<TimeInner>,
+}
+
+impl Default for TestClock {
+    fn default() -> TestClock {
+        TestClock::new()
+    }
+}
+
+impl TestClock {
+    /// Create a clock at virtual time zero.
+    pub fn new() -> TestClock {
+        TestClock {inner: Arc::new(TimeInner {base: Instant::now(), elapsed: Mutex::new(Duration::from_millis(0))})}
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Duration> {
+        self.inner.elapsed.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Virtual time since the clock has been created.
+    pub fn elapsed(&self) -> Duration {
+        *self.lock()
+    }
+
+    /// Advance the virtual time by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.lock() += duration;
+    }
+
+    /// Virtual time of `instant` (a value returned by `now()`).
+    pub fn elapsed_at(&self, instant: Instant) -> Duration {
+        instant.saturating_duration_since(self.inner.base)
+    }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> Instant {
+        self.inner.base + self.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+
+    /// Stopping is only noticed before and after the sleep, since
+    /// nothing can happen while the virtual time jumps ahead.
+    fn sleep_or_stop(&self, duration: Duration, stop: &StopToken) -> bool {
+        if stop.is_stopped() {
+            return true;
+        }
+        self.advance(duration);
+        stop.is_stopped()
+    }
+}
+
+/// Virtual time of a `Replay`.
+pub type ReplayClock = TestClock;
+
+struct Inner {
+    /// Captures not yet started by a press
+    captures: VecDeque<Vec<LedEvent>>,
+    /// Events of the running capture, in timestamp order, with their
+    /// virtual due time
+    scheduled: VecDeque<(Duration, LedEvent)>,
+    red: VecDeque<LedEvent>,
+    green: VecDeque<LedEvent>,
+    pressed: [bool; 2],
+    transitions: Vec<Transition>,
+}
+
+/// Replaying `GpioBackend` with virtual time, see module documentation.
+///
+/// Clones share their state.
+#[derive(Clone)]
+pub struct Replay {
+    clock: TestClock,
+    inner: Arc<Mutex<Inner>>,
+}
+
+fn nanos(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000_000 + duration.subsec_nanos() as u64
+}
+
+impl Inner {
+    /// Make all scheduled events due at `now` readable.
+    fn deliver(&mut self, now: Duration) {
+        while self.scheduled.front().is_some_and(|&(due, _)| due <= now) {
+            let (due, mut event) = self.scheduled.pop_front().unwrap();
+            event.timestamp = due;
+            match event.led {
+                Led::Red => self.red.push_back(event),
+                Led::Green => self.green.push_back(event),
+            }
+        }
+    }
+
+    fn pending(&self) -> u8 {
+        let mut mask = 0;
+        if !self.red.is_empty() {
+            mask |= Led::Red.mask();
+        }
+        if !self.green.is_empty() {
+            mask |= Led::Green.mask();
+        }
+        mask
+    }
+}
+
+impl Default for Replay {
+    fn default() -> Replay {
+        Replay::new()
+    }
+}
+
+impl Replay {
+    /// Create a replay without captures on a new `TestClock`; presses
+    /// then produce no events.
+    pub fn new() -> Replay {
+        Replay::with_clock(TestClock::new())
+    }
+
+    /// Create a replay without captures on `clock`.
+    pub fn with_clock(clock: TestClock) -> Replay {
+        Replay {
+            clock,
+            inner: Arc::new(Mutex::new(Inner {
+                captures: VecDeque::new(),
+                scheduled: VecDeque::new(),
+                red: VecDeque::new(),
+                green: VecDeque::new(),
+                pressed: [false; 2],
+                transitions: Vec::new(),
+            })),
+        }
+    }
+
+    /// Create a replay on a new `TestClock` with one capture per entry:
+    /// the default generated pattern of the state, or no response for
+    /// `None`.
+    pub fn with_captures(captures: &[Option<CFF3000State>]) -> Replay {
+        let replay = Replay::new();
+        for &state in captures {
+            replay.push_capture(state.map_or_else(Vec::new, |state| generate(state, PatternParams::default())));
+        }
+        replay
+    }
+
+    /// Lock the state with all events due by now delivered.
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.deliver(self.clock.elapsed());
+        inner
+    }
+
+    /// Queue `events` for the next press. Timestamps are the time after
+    /// the start of the press; the order does not matter.
+    pub fn push_capture(&self, mut events: Vec<LedEvent>) {
+        events.sort_by_key(|event| event.timestamp);
+        self.lock().captures.push_back(events);
+    }
+
+    /// Virtual time of this replay, for `CFF3000Builder::clock()` or
+    /// `StateQuery::start_with_clock()`.
+    pub fn clock(&self) -> TestClock {
+        self.clock.clone()
+    }
+
+    /// Virtual time since the replay's clock has been created.
+    pub fn elapsed(&self) -> Duration {
+        self.clock.elapsed()
+    }
+
+    /// Advance the virtual time by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        self.clock.advance(duration);
+    }
+
+    /// All button transitions so far, at virtual time.
+    pub fn transitions(&self) -> Vec<Transition> {
+        self.lock().transitions.clone()
+    }
+
+    /// Run a state query (as `CFF3000::state_report()`) on virtual time.
+    ///
+    /// Time advances straight to the next event or deadline, so the
+    /// query completes without waiting, whichever clock `device` uses.
+    /// With a different clock, the button release may be deferred to
+    /// the shared timer thread in real time, which delays the next
+    /// operation on `device` but does not affect the capture.
+    pub fn run(&self, device: &CFF3000) -> std::io::Result<StateReport> {
+        let mut query = StateQuery::start_with_clock(device, self.clock())?;
+        loop {
+            if let Poll::Ready(result) = query.poll_report() {
+                return result;
+            }
+
+            let inner = self.lock();
+            let now = self.clock.elapsed();
+            let deadline = query.deadline().map(|deadline| self.clock.elapsed_at(deadline));
+            let next_event = inner.scheduled.front().map(|&(due, _)| due);
+            let next = match (deadline, next_event) {
+                (Some(deadline), Some(event)) => std::cmp::min(deadline, event),
+                (Some(deadline), None) => deadline,
+                (None, _) => continue,
+            };
+            drop(inner);
+            self.clock.advance(next.saturating_sub(now));
+        }
+    }
+}
+
+/// Builder of a device on `replay`, using its clock.
+pub fn device(replay: &Replay) -> CFF3000Builder {
+    CFF3000Builder::with_backend(replay.clone()).clock(replay.clock())
+}
+
+fn index(button: Button) -> usize {
+    match button {
+        Button::Unlock => 0,
+        Button::Lock => 1,
+    }
+}
+
+impl GpioBackend for Replay {
+    fn set_button(&self, button: Button, pressed: bool) -> std::io::Result<()> {
+        let mut inner = self.lock();
+        let now = self.clock.elapsed();
+        let idle = !inner.pressed[0] && !inner.pressed[1];
+        if inner.pressed[index(button)] != pressed {
+            inner.transitions.push(Transition {button, pressed, at: now});
+        }
+        inner.pressed[index(button)] = pressed;
+
+        /* a new press starts the next capture */
+        if pressed && idle {
+            let events = inner.captures.pop_front().unwrap_or_default();
+            inner.scheduled = events.into_iter().map(|event| (now.saturating_add(event.timestamp), event)).collect();
+            inner.deliver(now);
+        }
+        Ok(())
+    }
+
+    /// Never blocks: if no event is pending, the virtual time advances
+    /// to the next scheduled event, or by `timeout` if that comes first.
+    fn wait_for_led_events(&self, timeout: Duration) -> std::io::Result<u8> {
+        let inner = self.lock();
+        if inner.pending() != 0 || timeout == Duration::from_millis(0) {
+            return Ok(inner.pending());
+        }
+
+        let now = self.clock.elapsed();
+        let step = match inner.scheduled.front() {
+            Some(&(due, _)) => std::cmp::min(due.saturating_sub(now), timeout),
+            None => timeout,
+        };
+        drop(inner);
+        self.clock.advance(step);
+        Ok(self.lock().pending())
+    }
+
+    fn read_led_event(&self, led: Led) -> std::io::Result<LedEvent> {
+        let mut inner = self.lock();
+        let event = match led {
+            Led::Red => inner.red.pop_front(),
+            Led::Green => inner.green.pop_front(),
+        };
+        event.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::WouldBlock, "no pending LED event"))
+    }
+
+    fn read_led_events(&self, led: Led, events: &mut EventBuffer) -> std::io::Result<usize> {
+        let mut inner = self.lock();
+        let queue = match led {
+            Led::Red => &mut inner.red,
+            Led::Green => &mut inner.green,
+        };
+        if queue.is_empty() {
+            return Err(std::io::Error::new(std::io::ErrorKind::WouldBlock, "no pending LED event"));
+        }
+        let count = std::cmp::min(queue.len(), events.remaining());
+        for event in queue.drain(..count) {
+            events.push(event);
+        }
+        Ok(count)
+    }
+
+    fn flush_led_events(&self) -> std::io::Result<()> {
+        let mut inner = self.lock();
+        inner.red.clear();
+        inner.green.clear();
+        Ok(())
+    }
+}
+
+/// Timing and faults of a generated pattern, see `generate()`.
+///
+/// The defaults match the timing of a real CFF3000 and produce the
+/// same patterns as the `mock::fixtures` scripts.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PatternParams {
+    /// Time from the start of the press to both LEDs switching on
+    pub lead_in: Duration,
+    /// Time both LEDs stay on at the start
+    pub intro: Duration,
+    /// Time the level of a steady pattern (locked, unlocked) is shown
+    pub hold: Duration,
+    /// Time between two level changes of a blinking pattern
+    pub blink_period: Duration,
+    /// Number of levels shown by a blinking pattern
+    pub blinks: u32,
+    /// Delay of every green edge relative to the red one
+    pub skew: Duration,
+    /// Maximum random delay added to every edge
+    pub jitter: Duration,
+    /// Number of short pulses injected at random times
+    pub glitches: u32,
+    /// Width of the injected pulses
+    pub glitch_width: Duration,
+    /// Number of edges removed at random
+    pub dropped_edges: u32,
+    /// Seed of the random generator, equal seeds give equal patterns
+    pub seed: u64,
+}
+
+impl Default for PatternParams {
+    fn default() -> PatternParams {
+        PatternParams {
+            lead_in: Duration::from_millis(700),
+            intro: Duration::from_millis(1000),
+            hold: Duration::from_millis(3000),
+            blink_period: Duration::from_millis(500),
+            blinks: 6,
+            skew: Duration::from_millis(0),
+            jitter: Duration::from_millis(0),
+            glitches: 0,
+            glitch_width: Duration::from_millis(2),
+            dropped_edges: 0,
+            seed: 1,
+        }
+    }
+}
+
+impl PatternParams {
+    /// Fault-free pattern with the timing of `profile`: its
+    /// confirmation delay and blink period, filling its pattern length.
+    pub fn from_profile(profile: &TimingProfile) -> PatternParams {
+        let defaults = PatternParams::default();
+        let hold = profile.pattern_length.saturating_sub(profile.confirmation_delay + defaults.intro);
+        PatternParams {
+            lead_in: profile.confirmation_delay,
+            hold,
+            blink_period: profile.blink_period,
+            blinks: (nanos(hold) / nanos(profile.blink_period).max(1)) as u32,
+            ..defaults
+        }
+    }
+}
+
+/// xorshift64*, good enough for test data and reproducible everywhere.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Uniform value in `0..=max` (nanoseconds).
+    fn below(&mut self, max: u64) -> u64 {
+        if max == 0 {0} else {self.next() % (max + 1)}
+    }
+}
+
+/// Generate the LED events shown after a check press in `state`, with
+/// timestamps relative to the start of the press (as expected by
+/// `Replay::push_capture()`), sorted by timestamp.
+///
+/// The level sequence comes from `parser::PATTERNS`. Without faults
+/// (`glitches`, `dropped_edges`) and with skew plus jitter below the
+/// merge window, the result is classified as `state`.
+pub fn generate(state: CFF3000State, params: PatternParams) -> Vec<LedEvent> {
+    let pattern = state.pattern();
+    let mut rng = Rng(params.seed | 1);
+
+    /* (time, combined level) of every level change */
+    let mut t = nanos(params.lead_in);
+    let mut levels = vec![(t, LED_RED | LED_GREEN)];
+    t += nanos(params.intro);
+    if pattern.blinking {
+        for i in 0..params.blinks as usize {
+            levels.push((t, pattern.levels[i % pattern.levels.len()]));
+            t += nanos(params.blink_period);
+        }
+    } else {
+        levels.push((t, pattern.levels[0]));
+        t += nanos(params.hold);
+    }
+    levels.push((t, 0b00));
+
+    let mut events = Vec::new();
+    let mut previous = 0b00;
+    for &(t, level) in &levels {
+        for &(led, delay) in &[(Led::Red, 0), (Led::Green, nanos(params.skew))] {
+            if (previous ^ level) & led.mask() != 0 {
+                let timestamp = t + delay + rng.below(nanos(params.jitter));
+                events.push(LedEvent {led, on: level & led.mask() != 0, timestamp: Duration::from_nanos(timestamp)});
+            }
+        }
+        previous = level;
+    }
+
+    for _ in 0..params.dropped_edges {
+        if events.is_empty() {
+            break;
+        }
+        let i = (rng.next() % events.len() as u64) as usize;
+        events.remove(i);
+    }
+
+    /* a glitch briefly inverts one LED */
+    let end = t;
+    for _ in 0..params.glitches {
+        let at = rng.below(end);
+        let led = if rng.next() & 1 == 0 {Led::Red} else {Led::Green};
+        let level = levels.iter().take_while(|&&(t, _)| t <= at).last().map_or(0, |&(_, level)| level);
+        let on = level & led.mask() == 0;
+        events.push(LedEvent {led, on, timestamp: Duration::from_nanos(at)});
+        events.push(LedEvent {led, on: !on, timestamp: Duration::from_nanos(at + nanos(params.glitch_width))});
+    }
+
+    events.sort_by_key(|event| event.timestamp);
+    events
+}
+
+/// Recorded capture with the state the device showed, see
+/// `Fixture::parse()` for the file format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fixture {
+    /// Hardware the capture has been recorded on (e.g. "CFF3000 rev. B,
+    /// Raspberry Pi 3")
+    pub device: String,
+    /// Free text about the firmware or anything else noteworthy
+    pub firmware: String,
+    /// Firmware generation, the capture is parsed with its settings
+    pub profile: DeviceProfile,
+    /// State shown by the LEDs, as read by a human
+    pub expected: CFF3000State,
+    /// LED events, timestamps after the start of the press
+    pub events: Vec<LedEvent>,
+}
+
+fn invalid(line: usize, msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("line {}: {}", line, msg))
+}
+
+impl Fixture {
+    /// Parse a fixture file:
+    ///
+    /// ```text
+    /// # device: CFF3000 rev. B, Raspberry Pi 3
+    /// # firmware: unknown
+    /// # profile: classic
+    /// # expected: out-of-range
+    /// led,edge,timestamp_ns
+    /// red,rising,700172000
+    /// green,rising,700194000
+    /// ```
+    ///
+    /// The `# key: value` header needs `expected` (one of `locked`,
+    /// `unlocked`, `manual` and `out-of-range`); `device`, `firmware`
+    /// and `profile` (a `DeviceProfile` name, default `classic`) are
+    /// optional, unknown keys and other comments are ignored. Rows
+    /// follow the column header, edges are `rising` (LED on) or
+    /// `falling`. Empty lines are ignored.
+    pub fn parse(text: &str) -> std::io::Result<Fixture> {
+        let mut device = String::new();
+        let mut firmware = String::new();
+        let mut profile = DeviceProfile::Classic;
+        let mut expected = None;
+        let mut events = Vec::new();
+        let mut columns = false;
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            let n = i + 1;
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(comment) = line.strip_prefix('#') {
+                if let Some((key, value)) = comment.split_once(':') {
+                    let value = value.trim().to_string();
+                    match key.trim() {
+                        "device" => device = value,
+                        "firmware" => firmware = value,
+                        "profile" => profile = DeviceProfile::from_name(&value).ok_or_else(|| invalid(n, "unknown profile"))?,
+                        "expected" => {
+                            expected = Some(CFF3000State::from_name(&value).ok_or_else(|| invalid(n, "unknown expected state"))?);
+                        },
+                        _ => (),
+                    }
+                }
+                continue;
+            }
+            if !columns {
+                if line != "led,edge,timestamp_ns" {
+                    return Err(invalid(n, "expected column header \"led,edge,timestamp_ns\""));
+                }
+                columns = true;
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+            let (led, edge, timestamp) = match *fields.as_slice() {
+                [led, edge, timestamp] => (led, edge, timestamp),
+                _ => return Err(invalid(n, "expected 3 columns")),
+            };
+            let led = match led {
+                "red" => Led::Red,
+                "green" => Led::Green,
+                _ => return Err(invalid(n, "unknown LED")),
+            };
+            let on = match edge {
+                "rising" => true,
+                "falling" => false,
+                _ => return Err(invalid(n, "unknown edge")),
+            };
+            let timestamp = timestamp.parse().map_err(|_| invalid(n, "invalid timestamp"))?;
+            events.push(LedEvent {led, on, timestamp: Duration::from_nanos(timestamp)});
+        }
+
+        match expected {
+            Some(expected) => Ok(Fixture {device, firmware, profile, expected, events}),
+            None => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "missing \"# expected:\" header")),
+        }
+    }
+
+    /// Read and parse the fixture file at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Fixture> {
+        let path = path.as_ref();
+        let text = (std::fs::read_to_string(path)
+            .map_err(|err| std::io::Error::new(err.kind(), format!("{}: {}", path.display(), err))))?;
+        Fixture::parse(&text).map_err(|err| std::io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))
+    }
+
+    /// Fixture from the events of a live capture (e.g.
+    /// `StateReport::events`), with timestamps made relative to
+    /// `press_start` (the event timestamp at which the press started,
+    /// or the first event's timestamp if unknown). The profile
+    /// is `DeviceProfile::Classic`, set `profile` for other devices.
+    pub fn from_capture(device: &str, firmware: &str, expected: CFF3000State, events: &[LedEvent], press_start: Duration) -> Fixture {
+        let mut events: Vec<LedEvent> = events.iter()
+            .map(|event| LedEvent {timestamp: event.timestamp.saturating_sub(press_start), ..*event})
+            .collect();
+        events.sort_by_key(|event| event.timestamp);
+        Fixture {device: device.to_string(), firmware: firmware.to_string(), profile: DeviceProfile::Classic, expected, events}
+    }
+}
+
+/// Writes the file format read by `Fixture::parse()`. The profile line
+/// is left out for `DeviceProfile::Classic`, custom profiles cannot be
+/// written and are left out as well.
+impl std::fmt::Display for Fixture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "# device: {}", self.device)?;
+        writeln!(f, "# firmware: {}", self.firmware)?;
+        if let Some(name) = self.profile.name().filter(|_| self.profile != DeviceProfile::Classic) {
+            writeln!(f, "# profile: {}", name)?;
+        }
+        writeln!(f, "# expected: {}", self.expected.name())?;
+        writeln!(f, "led,edge,timestamp_ns")?;
+        for event in &self.events {
+            let led = match event.led {Led::Red => "red", Led::Green => "green"};
+            let edge = if event.on {"rising"} else {"falling"};
+            writeln!(f, "{},{},{}", led, edge, event.timestamp.as_nanos())?;
+        }
+        Ok(())
+    }
+}
+
+/// Load all fixtures (`*.csv`) in `dir`, sorted by file name.
+pub fn load_fixtures<P: AsRef<Path>>(dir: P) -> std::io::Result<Vec<(PathBuf, Fixture)>> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "csv") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut fixtures = Vec::with_capacity(paths.len());
+    for path in paths {
+        let fixture = Fixture::load(&path)?;
+        fixtures.push((path, fixture));
+    }
+    Ok(fixtures)
+}
+
+struct FakeInner {
+    state: CFF3000State,
+    failure: Option<std::io::ErrorKind>,
+    commands: Vec<(Command, String)>,
+}
+
+/// `LockControl` keeping its state in memory, see module
+/// documentation.
+///
+/// Locking and unlocking succeed at once and change the state, unless a
+/// failure is set with `fail()`. Clones share their state.
+#[derive(Clone)]
+pub struct FakeLock {
+    inner: Arc<Mutex<FakeInner>>,
+}
+
+impl FakeLock {
+    /// Lock showing `state`.
+    pub fn new(state: CFF3000State) -> FakeLock {
+        FakeLock {inner: Arc::new(Mutex::new(FakeInner {state, failure: None, commands: Vec::new()}))}
+    }
+
+    fn inner(&self) -> MutexGuard<'_, FakeInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Change the state as if the door was operated by hand.
+    pub fn set_state(&self, state: CFF3000State) {
+        self.inner().state = state;
+    }
+
+    /// Fail every command with `kind` from now on, `None` to succeed
+    /// again. Failed commands leave the state alone.
+    pub fn fail(&self, kind: Option<std::io::ErrorKind>) {
+        self.inner().failure = kind;
+    }
+
+    /// Commands executed so far with their initiators, including the
+    /// failed ones.
+    pub fn commands(&self) -> Vec<(Command, String)> {
+        self.inner().commands.clone()
+    }
+}
+
+impl LockControl for FakeLock {
+    fn state(&self) -> std::io::Result<CFF3000State> {
+        self.execute_as(Command::Check, "")
+    }
+
+    fn lock_and_verify(&self) -> std::io::Result<CFF3000State> {
+        self.execute_as(Command::Lock, "")
+    }
+
+    fn unlock_and_verify(&self) -> std::io::Result<CFF3000State> {
+        self.execute_as(Command::Unlock, "")
+    }
+
+    fn execute_as(&self, command: Command, initiator: &str) -> std::io::Result<CFF3000State> {
+        let mut inner = self.inner();
+        inner.commands.push((command, initiator.to_string()));
+        if let Some(kind) = inner.failure {
+            return Err(std::io::Error::new(kind, "fake lock failure"));
+        }
+        match command {
+            Command::Lock => inner.state = CFF3000State::Locked,
+            Command::Unlock => inner.state = CFF3000State::Unlocked,
+            Command::Check => {},
+        }
+        Ok(inner.state)
+    }
+}
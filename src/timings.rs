@@ -0,0 +1,215 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Press durations, capture windows and other default timings.
+//!
+//! The constants are the defaults used by this crate, so applications
+//! can refer to them instead of repeating the numbers. They match the
+//! classic firmware, `DeviceProfile` bundles the timings of the known
+//! firmware generations.
+//!
+//! # Example
+//! ```no_run
+//! use cff3000::{timings, CFF3000};
+//!
+//! fn main() {
+//!     let cff3000 = CFF3000::new("/dev/gpiochip2", [2, 3, 4, 5]).unwrap();
+//!     cff3000.lock().unwrap();
+//!     cff3000.show_leds(timings::SUGGESTED_FEEDBACK_DISPLAY.as_secs() as u8).unwrap();
+//! }
+//! ```
+
+use std::time::Duration;
+
+#[cfg(feature = "config")]
+use serde::{Deserialize, Serialize};
+
+use crate::{Command, ParseOptions};
+
+pub use crate::parser::DEFAULT_MERGE_WINDOW;
+
+/// Minimum time a button is held down for the CFF3000 to register it.
+pub const DEFAULT_PRESS: Duration = Duration::from_millis(500);
+/// Time the LED pattern is captured after a check press.
+pub const DEFAULT_CAPTURE_WINDOW: Duration = Duration::from_secs(8);
+/// Time the LED pattern is captured after a lock or unlock press.
+pub const DEFAULT_COMMAND_CAPTURE_WINDOW: Duration = Duration::from_secs(10);
+/// Time to show the LEDs with `CFF3000::show_leds()` after a lock or
+/// unlock press, until the confirmation is over.
+pub const SUGGESTED_FEEDBACK_DISPLAY: Duration = Duration::from_secs(10);
+/// Time to show the LEDs with `CFF3000::show_leds()` after a check
+/// press.
+pub const SUGGESTED_CHECK_FEEDBACK_DISPLAY: Duration = Duration::from_secs(8);
+/// Default `WatchOptions::min_interval`.
+pub const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(10);
+/// Default `WatchOptions::auto_lock_cooldown`.
+pub const DEFAULT_AUTO_LOCK_COOLDOWN: Duration = Duration::from_secs(15 * 60);
+/// Default `schedule::ScheduleEntry::grace`.
+pub const DEFAULT_SCHEDULE_GRACE: Duration = Duration::from_secs(15 * 60);
+
+/// Timing of the button presses and LED captures of a `CFF3000`, set
+/// with `CFF3000Builder::timings()`.
+///
+/// The per-operation values override the shared ones if set, e.g. for
+/// a remote control whose unlock button needs a longer press.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Timings {
+    /// Minimum time a button is held down (default: `DEFAULT_PRESS`)
+    pub press: Duration,
+    /// Time the LED pattern is captured after a check press (default:
+    /// `DEFAULT_CAPTURE_WINDOW`)
+    pub check_capture: Duration,
+    /// Time the LED pattern is captured after a lock or unlock press
+    /// (default: `DEFAULT_COMMAND_CAPTURE_WINDOW`)
+    pub command_capture: Duration,
+    /// Press duration of the lock button (default: `press`)
+    pub lock_press: Option<Duration>,
+    /// Press duration of the unlock button (default: `press`)
+    pub unlock_press: Option<Duration>,
+    /// Press duration of both buttons for a check (default: `press`)
+    pub check_press: Option<Duration>,
+    /// Capture window of `lock_and_verify()` (default:
+    /// `command_capture`)
+    pub lock_capture: Option<Duration>,
+    /// Capture window of `unlock_and_verify()` (default:
+    /// `command_capture`)
+    pub unlock_capture: Option<Duration>,
+}
+
+impl Timings {
+    /// Press duration used for `command`.
+    pub fn press_for(&self, command: Command) -> Duration {
+        let press = match command {
+            Command::Lock => self.lock_press,
+            Command::Unlock => self.unlock_press,
+            Command::Check => self.check_press,
+        };
+        press.unwrap_or(self.press)
+    }
+
+    /// Capture window used for `command`.
+    pub fn capture_for(&self, command: Command) -> Duration {
+        match command {
+            Command::Lock => self.lock_capture.unwrap_or(self.command_capture),
+            Command::Unlock => self.unlock_capture.unwrap_or(self.command_capture),
+            Command::Check => self.check_capture,
+        }
+    }
+}
+
+impl Default for Timings {
+    fn default() -> Timings {
+        TimingProfile::CLASSIC.timings
+    }
+}
+
+/// Timing of one CFF3000 firmware generation, see `DeviceProfile`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TimingProfile {
+    /// Press durations and capture windows
+    pub timings: Timings,
+    /// Time from the start of a press to both LEDs switching on
+    pub confirmation_delay: Duration,
+    /// Time the CFF3000 shows each level of a blinking pattern
+    pub blink_period: Duration,
+    /// Time from the start of a press to the end of the longest
+    /// pattern
+    pub pattern_length: Duration,
+    /// LED changes closer together than this are one change (the
+    /// default `ParseOptions::merge_window`)
+    pub merge_window: Duration,
+}
+
+impl TimingProfile {
+    /// Timing of the classic firmware, the defaults of this crate.
+    pub const CLASSIC: TimingProfile = TimingProfile {
+        timings: Timings {
+            press: DEFAULT_PRESS,
+            check_capture: DEFAULT_CAPTURE_WINDOW,
+            command_capture: DEFAULT_COMMAND_CAPTURE_WINDOW,
+            lock_press: None,
+            unlock_press: None,
+            check_press: None,
+            lock_capture: None,
+            unlock_capture: None,
+        },
+        confirmation_delay: Duration::from_millis(700),
+        blink_period: Duration::from_millis(500),
+        pattern_length: Duration::from_millis(4700),
+        merge_window: DEFAULT_MERGE_WINDOW,
+    };
+
+    /// Timing reported for the second firmware generation: twice the
+    /// blink rate, a later confirmation and a longer press. Not yet
+    /// backed by recorded fixtures, see `tests/fixtures/README.md`.
+    pub const REV2: TimingProfile = TimingProfile {
+        timings: Timings {
+            press: Duration::from_millis(800),
+            check_capture: Duration::from_secs(9),
+            command_capture: Duration::from_secs(12),
+            lock_press: None,
+            unlock_press: None,
+            check_press: None,
+            lock_capture: None,
+            unlock_capture: None,
+        },
+        confirmation_delay: Duration::from_millis(1200),
+        blink_period: Duration::from_millis(250),
+        pattern_length: Duration::from_millis(5200),
+        merge_window: DEFAULT_MERGE_WINDOW,
+    };
+
+    /// Parser settings with this profile's merge window.
+    pub fn parse_options(&self) -> ParseOptions {
+        ParseOptions {merge_window: self.merge_window, ..ParseOptions::default()}
+    }
+}
+
+/// Known CFF3000 firmware generations, selected with
+/// `CFF3000Builder::profile()` or the `profile` configuration key.
+///
+/// Both show the same LED levels for each state (`parser::PATTERNS`),
+/// they differ in press duration, confirmation delay and blink cadence.
+/// `Custom` is for devices matching neither; it cannot be given in a
+/// configuration file, use a named profile with `[timings]` overrides
+/// there.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "config", derive(Serialize, Deserialize), serde(rename_all = "kebab-case"))]
+pub enum DeviceProfile {
+    /// `TimingProfile::CLASSIC`
+    #[default]
+    Classic,
+    /// `TimingProfile::REV2`
+    Rev2,
+    #[cfg_attr(feature = "config", serde(skip))]
+    Custom(TimingProfile),
+}
+
+impl DeviceProfile {
+    /// Timing of this profile.
+    pub fn timing(&self) -> TimingProfile {
+        match *self {
+            DeviceProfile::Classic => TimingProfile::CLASSIC,
+            DeviceProfile::Rev2 => TimingProfile::REV2,
+            DeviceProfile::Custom(timing) => timing,
+        }
+    }
+
+    /// Profile named `name` ("classic" or "rev2").
+    pub fn from_name(name: &str) -> Option<DeviceProfile> {
+        match name {
+            "classic" => Some(DeviceProfile::Classic),
+            "rev2" => Some(DeviceProfile::Rev2),
+            _ => None,
+        }
+    }
+
+    /// Name accepted by `from_name()`, `None` for `Custom`.
+    pub fn name(&self) -> Option<&'static str> {
+        match *self {
+            DeviceProfile::Classic => Some("classic"),
+            DeviceProfile::Rev2 => Some("rev2"),
+            DeviceProfile::Custom(_) => None,
+        }
+    }
+}
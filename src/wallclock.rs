@@ -0,0 +1,156 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Wall-clock times of the LED events.
+//!
+//! The timestamps of the LED events are monotonic durations in the
+//! time base of the backend, e.g. `CLOCK_MONOTONIC` for the edge events
+//! of the kernel, so they tell the spacing of the events but not when
+//! they happened. A state query reads the wall clock (`SystemTime`)
+//! once, together with the device clock, when it presses the buttons.
+//! While capturing it notes the device clock at every read of an LED
+//! event, which ties the event timestamps to the device clock: a
+//! [`WallAnchor`] pairs the timestamp of the event read with the least
+//! delay with its wall-clock time. `event.wall_time(&anchor)` of
+//! [`WallTime`] converts the timestamps of the capture, as exact as the
+//! delay of that read: a few milliseconds with edge events, up to the
+//! poll period of polling backends.
+//!
+//! The wall clock is read only at the start of a query. If NTP or an
+//! administrator steps it during a capture, the times of that capture
+//! stay relative to the wall-clock time before the step and spaced
+//! like the events, the next query starts from the new time. A capture
+//! converted later gives the same times, conversions never read the
+//! wall clock again.
+//!
+//! `StateReport::anchor` and `Capture::anchor` are the anchor of a
+//! query, `StateChange::at` the wall-clock time of the state it
+//! reports. With serde (`config` feature) the wall-clock time of an
+//! anchor is in integer milliseconds since the Unix epoch, with the
+//! `chrono` feature an RFC 3339 string in UTC like
+//! "2025-10-09T08:53:20.123Z", which devices with `chrono` read back as
+//! well as the milliseconds:
+//!
+//! ```text
+//! {"wall":1760000000123,"timestamp_ns":700000000}
+//! ```
+
+#[cfg(feature = "config")]
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime};
+#[cfg(feature = "config")]
+use std::time::UNIX_EPOCH;
+
+#[cfg(feature = "config")]
+use serde::de::{self, Visitor};
+#[cfg(feature = "config")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::LedEvent;
+
+/// Wall-clock time of an event timestamp, see the module documentation.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "config", derive(Serialize, Deserialize), serde(deny_unknown_fields))]
+pub struct WallAnchor {
+    /// Wall-clock time at `timestamp`
+    #[cfg_attr(feature = "config", serde(serialize_with = "serialize_wall", deserialize_with = "deserialize_wall"))]
+    pub wall: SystemTime,
+    /// Event timestamp, in the time base of the backend
+    #[cfg_attr(feature = "config", serde(rename = "timestamp_ns", with = "cff3000_parser::nanos"))]
+    pub timestamp: Duration,
+}
+
+impl WallAnchor {
+    /// Wall-clock time of the event timestamp `timestamp` of the same
+    /// capture. Times beyond the range of `SystemTime` are `wall`.
+    pub fn wall_time(&self, timestamp: Duration) -> SystemTime {
+        let time = match timestamp.checked_sub(self.timestamp) {
+            Some(after) => self.wall.checked_add(after),
+            None => self.wall.checked_sub(self.timestamp.saturating_sub(timestamp)),
+        };
+        time.unwrap_or(self.wall)
+    }
+}
+
+/// Wall-clock time of an LED event.
+pub trait WallTime {
+    /// Wall-clock time by `anchor`, which must be the one of the capture
+    /// of the event.
+    fn wall_time(&self, anchor: &WallAnchor) -> SystemTime;
+}
+
+impl WallTime for LedEvent {
+    fn wall_time(&self, anchor: &WallAnchor) -> SystemTime {
+        anchor.wall_time(self.timestamp)
+    }
+}
+
+/// Anchor of a capture in the making.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Anchoring {
+    /// Wall-clock time at `start`
+    wall: SystemTime,
+    start: Instant,
+    /// Timestamp of the event read with the least delay so far and the
+    /// time of the read since `start`
+    best: Option<(Duration, Duration)>,
+}
+
+impl Anchoring {
+    pub(crate) fn new(wall: SystemTime, start: Instant) -> Anchoring {
+        Anchoring {wall, start, best: None}
+    }
+
+    /// Note an event with `timestamp` read at `read`.
+    pub(crate) fn note(&mut self, timestamp: Duration, read: Instant) {
+        let elapsed = read.saturating_duration_since(self.start);
+        /* the timestamp the start would have had, later with less delay */
+        let start = |timestamp: Duration, elapsed: Duration| timestamp.as_nanos() as i128 - elapsed.as_nanos() as i128;
+        if self.best.is_none_or(|(best, best_elapsed)| start(timestamp, elapsed) > start(best, best_elapsed)) {
+            self.best = Some((timestamp, elapsed));
+        }
+    }
+
+    /// The anchor, `None` without events.
+    pub(crate) fn anchor(&self) -> Option<WallAnchor> {
+        self.best.map(|(timestamp, elapsed)| WallAnchor {wall: self.wall + elapsed, timestamp})
+    }
+}
+
+#[cfg(all(feature = "config", not(feature = "chrono")))]
+fn serialize_wall<S: Serializer>(wall: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    let since_epoch = wall.duration_since(UNIX_EPOCH).unwrap_or_default();
+    serializer.serialize_u64(since_epoch.as_millis() as u64)
+}
+
+#[cfg(feature = "chrono")]
+fn serialize_wall<S: Serializer>(wall: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    let wall: chrono::DateTime<chrono::Utc> = (*wall).into();
+    serializer.serialize_str(&wall.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+}
+
+#[cfg(feature = "config")]
+struct WallVisitor;
+
+#[cfg(feature = "config")]
+impl<'de> Visitor<'de> for WallVisitor {
+    type Value = SystemTime;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("milliseconds since the Unix epoch or an RFC 3339 time")
+    }
+
+    fn visit_u64<E: de::Error>(self, millis: u64) -> Result<SystemTime, E> {
+        Ok(UNIX_EPOCH + Duration::from_millis(millis))
+    }
+
+    #[cfg(feature = "chrono")]
+    fn visit_str<E: de::Error>(self, text: &str) -> Result<SystemTime, E> {
+        chrono::DateTime::parse_from_rfc3339(text).map(SystemTime::from).map_err(E::custom)
+    }
+}
+
+#[cfg(feature = "config")]
+fn deserialize_wall<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+    deserializer.deserialize_any(WallVisitor)
+}
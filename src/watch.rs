@@ -0,0 +1,489 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Continuous state monitoring.
+
+use std::fmt;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::task::{Poll, Waker};
+use std::time::{Duration, Instant, SystemTime};
+#[cfg(any(feature = "cli", feature = "config", feature = "http", feature = "mqtt", feature = "webhook"))]
+use std::time::UNIX_EPOCH;
+
+#[cfg(feature = "config")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::clock::deadline;
+#[cfg(any(feature = "cli", feature = "http", feature = "mqtt", feature = "webhook"))]
+use crate::json::json_string;
+use crate::{Clock, CFF3000, CFF3000State, Command, StateQuery, StateReport};
+
+/// Cancellation flag shared between a watch loop and its controller.
+#[derive(Clone, Default)]
+pub struct StopToken {
+    inner: Arc<(Mutex<bool>, Condvar)>,
+    /* woken by `stop()`, see `wake_on_stop()` */
+    wakers: Arc<Mutex<Vec<Waker>>>,
+}
+
+impl StopToken {
+    /// Create a token which has not been stopped.
+    pub fn new() -> StopToken {
+        StopToken::default()
+    }
+
+    /// Request all loops using this token to stop.
+    pub fn stop(&self) {
+        let (ref flag, ref cond) = *self.inner;
+        *flag.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        cond.notify_all();
+        for waker in self.wakers.lock().unwrap_or_else(|e| e.into_inner()).drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Returns true once `stop()` has been called.
+    pub fn is_stopped(&self) -> bool {
+        *self.inner.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Wake `waker` on `stop()`, right away if stopped already. Loops
+    /// blocking in something else than `wait_timeout()` use this to
+    /// stop promptly, e.g. with the `waker()` of a `GpioBackend`
+    /// interrupting its wait for LED events.
+    pub fn wake_on_stop(&self, waker: &Waker) {
+        {
+            let mut wakers = self.wakers.lock().unwrap_or_else(|e| e.into_inner());
+            if !wakers.iter().any(|registered| registered.will_wake(waker)) {
+                wakers.push(waker.clone());
+            }
+        }
+        if self.is_stopped() {
+            waker.wake_by_ref();
+        }
+    }
+
+    /// Sleep for `timeout` or until stopped, returning true if stopped.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let (ref flag, ref cond) = *self.inner;
+        let deadline = deadline(Instant::now(), timeout);
+        let mut stopped = flag.lock().unwrap_or_else(|e| e.into_inner());
+
+        while !*stopped {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            stopped = match cond.wait_timeout(stopped, deadline - now) {
+                Ok((guard, _)) => guard,
+                Err(poisoned) => poisoned.into_inner().0,
+            };
+        }
+
+        *stopped
+    }
+}
+
+/// Reason a `StateChange` has been reported.
+///
+/// Its name is the `trigger` of the JSON of the integrations, see
+/// `Trigger::name()`. More reasons may be added.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "config", derive(Serialize, Deserialize), serde(rename_all = "kebab-case"))]
+#[non_exhaustive]
+pub enum Trigger {
+    /// Periodic state query
+    Poll,
+    /// Lock issued by the auto-lock policy. Reported even if the door
+    /// remained unlocked, so that failed attempts are visible.
+    AutoLock,
+    /// Action of a `schedule::ScheduleEntry`, reported like `AutoLock`
+    Schedule,
+    /// Verified command issued through this crate, e.g. by the client of
+    /// an integration, reported like `AutoLock`
+    Command,
+    /// Pattern the device has not asked for, e.g. after a press on the
+    /// key remote (see `CFF3000::listen_for_activity()`)
+    External,
+}
+
+impl Trigger {
+    /// Stable name, e.g. "auto-lock".
+    pub fn name(self) -> &'static str {
+        match self {
+            Trigger::Poll => "poll",
+            Trigger::AutoLock => "auto-lock",
+            Trigger::Schedule => "schedule",
+            Trigger::Command => "command",
+            Trigger::External => "external",
+        }
+    }
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A change of the interpreted CFF3000 state, the one record of a
+/// change all integrations report.
+///
+/// With serde (`config` feature) and in the JSON of the integrations it
+/// is an object with the `previous` state, `null` for the first
+/// observation, and the time in milliseconds since the Unix epoch:
+///
+/// ```text
+/// {"state":"locked","previous":"unlocked","trigger":"auto-lock","at_ms":1760000000123}
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "config", derive(Serialize, Deserialize), serde(deny_unknown_fields))]
+#[non_exhaustive]
+pub struct StateChange {
+    /// Newly observed state
+    #[cfg_attr(feature = "config", serde(rename = "state"))]
+    pub current: CFF3000State,
+    /// Previously observed state, `None` for the first observation
+    pub previous: Option<CFF3000State>,
+    /// What caused the observation
+    pub trigger: Trigger,
+    /// Wall-clock time of the observation: when the LED pattern of a
+    /// query started (`StateReport::shown_at()`), when a verified
+    /// command read the state otherwise
+    #[cfg_attr(feature = "config", serde(rename = "at_ms", serialize_with = "serialize_millis", deserialize_with = "deserialize_millis"))]
+    pub at: SystemTime,
+}
+
+impl StateChange {
+    /// Change from `previous` to `current` observed at `at`.
+    pub fn new(previous: Option<CFF3000State>, current: CFF3000State, trigger: Trigger, at: SystemTime) -> StateChange {
+        StateChange {current, previous, trigger, at}
+    }
+
+    /// Append the change as a JSON object, see above.
+    #[cfg(any(feature = "cli", feature = "http", feature = "mqtt", feature = "webhook"))]
+    pub(crate) fn write_json(&self, out: &mut String) {
+        out.push_str("{\"state\":");
+        json_string(out, self.current.name());
+        out.push_str(",\"previous\":");
+        match self.previous {
+            Some(previous) => json_string(out, previous.name()),
+            None => out.push_str("null"),
+        }
+        out.push_str(",\"trigger\":");
+        json_string(out, self.trigger.name());
+        let since = self.at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        out.push_str(&format!(",\"at_ms\":{}}}", since.as_millis()));
+    }
+}
+
+/// One line for logs, e.g. "unlocked -> locked (auto-lock)".
+impl fmt::Display for StateChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(previous) = self.previous {
+            write!(f, "{} -> ", previous.name())?;
+        }
+        write!(f, "{} ({})", self.current.name(), self.trigger)
+    }
+}
+
+#[cfg(feature = "config")]
+fn serialize_millis<S: Serializer>(at: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    let since = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    serializer.serialize_u64(since.as_millis() as u64)
+}
+
+#[cfg(feature = "config")]
+fn deserialize_millis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+    u64::deserialize(deserializer).map(|millis| UNIX_EPOCH + Duration::from_millis(millis))
+}
+
+/// Fan-out of state changes to any number of subscribers, e.g. one per
+/// client of a server, each receiving every change sent after it has
+/// subscribed. Clones share the subscribers.
+#[derive(Clone, Default)]
+pub struct ChangeBroadcast {
+    subscribers: Arc<Mutex<Vec<mpsc::Sender<StateChange>>>>,
+}
+
+impl ChangeBroadcast {
+    pub fn new() -> ChangeBroadcast {
+        ChangeBroadcast::default()
+    }
+
+    /// Receive all further changes. Dropping the receiver unsubscribes,
+    /// effective with the next `send()`.
+    pub fn subscribe(&self) -> mpsc::Receiver<StateChange> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap_or_else(|e| e.into_inner()).push(tx);
+        rx
+    }
+
+    /// Deliver `change` to every subscriber, forgetting the dropped ones.
+    pub fn send(&self, change: StateChange) {
+        self.subscribers.lock().unwrap_or_else(|e| e.into_inner()).retain(|tx| tx.send(change).is_ok());
+    }
+
+    /// Forget all subscribers, their receivers disconnect.
+    pub fn close(&self) {
+        self.subscribers.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+
+    /// Number of subscribers, including dropped ones not yet forgotten.
+    pub fn subscribers(&self) -> usize {
+        self.subscribers.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+}
+
+/// Configuration of the watch loop.
+#[derive(Debug, Copy, Clone)]
+pub struct WatchOptions {
+    /// Time between the start of two state queries
+    pub poll_interval: Duration,
+    /// Maximum random delay added to every interval, so that several
+    /// devices polled at the same interval do not stay in lockstep
+    pub jitter: Duration,
+    /// Minimum time between the start of two state queries, applied
+    /// after jitter and error handling (rate limit)
+    pub min_interval: Duration,
+    /// Number of consecutive failed queries after which the watch loop
+    /// gives up and returns the last error (0 = never give up)
+    pub max_consecutive_errors: u32,
+    /// Lock the door once it has been observed `Unlocked` continuously
+    /// for this long (`None` = disabled)
+    pub auto_lock_after: Option<Duration>,
+    /// Minimum time between an automatic lock and the next one, so a
+    /// person unlocking again right away is not overruled
+    pub auto_lock_cooldown: Duration,
+    /// Longest time between two heartbeats of `watch_with_heartbeat()`
+    /// while waiting for the next query (`None` = only after queries)
+    pub heartbeat: Option<Duration>,
+}
+
+impl WatchOptions {
+    /// Options polling every `poll_interval` without jitter, at most
+    /// once every 10 seconds, giving up after 5 failures in a row.
+    pub fn new(poll_interval: Duration) -> WatchOptions {
+        WatchOptions {
+            poll_interval,
+            jitter: Duration::from_millis(0),
+            min_interval: crate::timings::DEFAULT_MIN_INTERVAL,
+            max_consecutive_errors: 5,
+            auto_lock_after: None,
+            auto_lock_cooldown: crate::timings::DEFAULT_AUTO_LOCK_COOLDOWN,
+            heartbeat: None,
+        }
+    }
+
+    /// Enable the auto-lock policy with `threshold`.
+    pub fn auto_lock_after(mut self, threshold: Duration) -> WatchOptions {
+        self.auto_lock_after = Some(threshold);
+        self
+    }
+
+    /// Set the cool-down time following an automatic lock.
+    pub fn auto_lock_cooldown(mut self, cooldown: Duration) -> WatchOptions {
+        self.auto_lock_cooldown = cooldown;
+        self
+    }
+
+    /// Call the heartbeat at least every `every` between queries.
+    pub fn heartbeat(mut self, every: Duration) -> WatchOptions {
+        self.heartbeat = Some(every);
+        self
+    }
+}
+
+/// Bookkeeping for the auto-lock policy.
+struct AutoLock {
+    /// First observation of the current unlocked period
+    unlocked_since: Option<Instant>,
+    /// Time of the last automatic lock
+    last_lock: Option<Instant>,
+}
+
+impl AutoLock {
+    /// Update with the latest observation (`None` = query failed).
+    fn observe(&mut self, state: Option<CFF3000State>, now: Instant) {
+        match state {
+            Some(CFF3000State::Unlocked) => {
+                if self.unlocked_since.is_none() {
+                    self.unlocked_since = Some(now);
+                }
+            },
+            /* Manual, OutOfRange and unknown break the unlocked period */
+            _ => self.unlocked_since = None,
+        }
+    }
+
+    /// Point in time at which the door should be locked.
+    fn due(&self, options: &WatchOptions) -> Option<Instant> {
+        let due = deadline(self.unlocked_since?, options.auto_lock_after?);
+        match self.last_lock {
+            Some(last) => Some(std::cmp::max(due, deadline(last, options.auto_lock_cooldown))),
+            None => Some(due),
+        }
+    }
+}
+
+/// Small xorshift generator, good enough to spread out polling.
+struct Jitter(u64);
+
+impl Jitter {
+    fn new() -> Jitter {
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() as u64 ^ d.as_secs())
+            .unwrap_or(0);
+        Jitter(seed | 1)
+    }
+
+    fn next(&mut self, max: Duration) -> Duration {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        let max_ms = max.as_secs().saturating_mul(1000).saturating_add(max.subsec_millis() as u64);
+        if max_ms == 0 {
+            return Duration::from_millis(0);
+        }
+        Duration::from_millis(self.0 % max_ms.saturating_add(1))
+    }
+}
+
+impl CFF3000 {
+    /// Query the state every `poll_interval` and invoke `f` for every
+    /// change until `stop` is stopped.
+    ///
+    /// See `watch_with_options()` for details.
+    pub fn watch<F>(&self, poll_interval: Duration, stop: &StopToken, f: F) -> std::io::Result<()>
+        where F: FnMut(StateChange)
+    {
+        self.watch_with_options(&WatchOptions::new(poll_interval), stop, f)
+    }
+
+    /// Query the state periodically and invoke `f` for every change
+    /// until `stop` is stopped.
+    ///
+    /// The first successfully queried state is reported with `previous`
+    /// set to `None`. Failed queries never produce a change; the next
+    /// successful query is compared against the last known state. After
+    /// `max_consecutive_errors` failed queries in a row the last error
+    /// is returned. Stopping interrupts a running query (releasing the
+    /// buttons) and makes this function return `Ok(())`.
+    ///
+    /// With `auto_lock_after` set, the door is locked once it has been
+    /// seen `Unlocked` in every query for longer than the threshold and
+    /// the cool-down since the previous automatic lock has passed. Any
+    /// other result (including a failed query) restarts the countdown.
+    /// The outcome is reported with `Trigger::AutoLock`.
+    pub fn watch_with_options<F>(&self, options: &WatchOptions, stop: &StopToken, f: F) -> std::io::Result<()>
+        where F: FnMut(StateChange)
+    {
+        self.watch_with_heartbeat(options, stop, f, || {})
+    }
+
+    /// Like `watch_with_options()`, also calling `heartbeat` after every
+    /// query, failed or not, and at least every `options.heartbeat`
+    /// while waiting for the next one. A loop stuck in a query, e.g.
+    /// waiting for a GPIO line which never answers, stops calling it,
+    /// which is what a watchdog wants to know.
+    pub fn watch_with_heartbeat<F, H>(&self, options: &WatchOptions, stop: &StopToken, mut f: F, mut heartbeat: H) -> std::io::Result<()>
+        where F: FnMut(StateChange), H: FnMut()
+    {
+        let mut jitter = Jitter::new();
+        let mut previous: Option<CFF3000State> = None;
+        let mut errors = 0u32;
+        let mut last_start: Option<Instant> = None;
+        let mut auto_lock = AutoLock {unlocked_since: None, last_lock: None};
+
+        'watch: while !stop.is_stopped() {
+            if let Some(last) = last_start {
+                let mut interval = options.poll_interval.saturating_add(jitter.next(options.jitter));
+                if let Some(due) = auto_lock.due(options) {
+                    /* re-check right when the auto-lock becomes due */
+                    let until_due = if due > last {due - last} else {Duration::from_millis(0)};
+                    interval = std::cmp::min(interval, until_due);
+                }
+                let interval = std::cmp::max(interval, options.min_interval);
+                let mut remaining = interval.saturating_sub(self.clock.now().saturating_duration_since(last));
+                while remaining > Duration::from_millis(0) {
+                    let chunk = options.heartbeat.map_or(remaining, |every| std::cmp::min(every, remaining));
+                    if self.clock.sleep_or_stop(chunk, stop) {
+                        break 'watch;
+                    }
+                    remaining -= chunk;
+                    if remaining > Duration::from_millis(0) {
+                        heartbeat();
+                    }
+                }
+            }
+            last_start = Some(self.clock.now());
+
+            let result = self.query_until_stopped(stop);
+            if !matches!(result, Ok(None)) {
+                heartbeat();
+            }
+            match result {
+                Ok(None) => break,
+                Ok(Some(report)) => {
+                    let current = report.state;
+                    errors = 0;
+                    auto_lock.observe(Some(current), self.clock.now());
+                    if previous != Some(current) {
+                        let at = report.shown_at().unwrap_or_else(SystemTime::now);
+                        f(StateChange {previous, current, trigger: Trigger::Poll, at});
+                        previous = Some(current);
+                    }
+                },
+                Err(err) => {
+                    auto_lock.observe(None, self.clock.now());
+                    errors += 1;
+                    if options.max_consecutive_errors != 0 && errors >= options.max_consecutive_errors {
+                        return Err(err);
+                    }
+                    continue;
+                },
+            }
+
+            let now = self.clock.now();
+            match auto_lock.due(options) {
+                Some(due) if due <= now && !stop.is_stopped() => {},
+                _ => continue,
+            }
+
+            auto_lock.last_lock = Some(now);
+            match self.execute_as(Command::Lock, "auto-lock") {
+                Ok(current) => {
+                    errors = 0;
+                    auto_lock.observe(Some(current), self.clock.now());
+                    f(StateChange {previous, current, trigger: Trigger::AutoLock, at: SystemTime::now()});
+                    previous = Some(current);
+                },
+                Err(err) => {
+                    auto_lock.observe(None, self.clock.now());
+                    errors += 1;
+                    if options.max_consecutive_errors != 0 && errors >= options.max_consecutive_errors {
+                        return Err(err);
+                    }
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run a state query, returning `None` if `stop` is stopped first.
+    fn query_until_stopped(&self, stop: &StopToken) -> std::io::Result<Option<StateReport>> {
+        let mut query = StateQuery::start(self)?;
+
+        loop {
+            if let Poll::Ready(result) = query.poll_report() {
+                return result.map(Some);
+            }
+            if stop.is_stopped() {
+                return Ok(None);
+            }
+            query.wait_or_stop(stop)?;
+        }
+    }
+}
@@ -0,0 +1,240 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! HTTP POST notifications of state changes (`webhook` feature).
+//!
+//! A [`WebhookNotifier`] posts one request per change given to
+//! `notify()`, e.g. from `publish_changes()`, to a URL like an ntfy
+//! topic or a serverless function. The body is rendered from
+//! `WebhookOptions::template`, by default
+//!
+//! ```json
+//! {"device":"frontdoor","previous":"unlocked","state":"locked","trigger":"poll","timestamp":"2026-01-02T03:04:05.678Z"}
+//! ```
+//!
+//! The placeholders `{{device}}`, `{{previous}}`, `{{state}}`,
+//! `{{trigger}}`, `{{timestamp}}` (RFC 3339, UTC) and
+//! `{{timestamp_ms}}` (milliseconds since the Unix epoch) are replaced
+//! with JSON values, strings quoted and `previous` being `null` for the
+//! first observation. `{{change}}` is the whole change as the object
+//! the other integrations send, see `StateChange`, e.g. for a template
+//! `{"device":{{device}},"change":{{change}}}`.
+//!
+//! # Failures
+//!
+//! The requests are sent from a thread of the notifier, `notify()`
+//! never blocks the watch loop. Transport errors and the statuses 408,
+//! 429 and 5xx are retried `retries` times, waiting `min_backoff` and
+//! doubling the wait up to `max_backoff`. Other statuses are not
+//! retried. Undelivered changes are logged as warnings and dropped, as
+//! are changes arriving while `queue_depth` are waiting.
+
+use std::io::{Error, ErrorKind};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::clock::rfc3339;
+use crate::json::json_string;
+use crate::{CFF3000, StateChange, StopToken, WatchOptions};
+
+/// Default body, see the module documentation.
+pub const DEFAULT_TEMPLATE: &str =
+    r#"{"device":{{device}},"previous":{{previous}},"state":{{state}},"trigger":{{trigger}},"timestamp":{{timestamp}}}"#;
+
+const PLACEHOLDERS: [&str; 7] = ["device", "previous", "state", "trigger", "timestamp", "timestamp_ms", "change"];
+
+/// Notifier configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookOptions {
+    /// `http://` or `https://` URL the changes are posted to
+    pub url: String,
+    /// Additional request headers, e.g. `("Title", "Front door")` for
+    /// ntfy. `Content-Type` defaults to `application/json`.
+    pub headers: Vec<(String, String)>,
+    /// Sent as `Authorization: Bearer <token>`
+    pub token: Option<String>,
+    /// Body template, see the module documentation
+    pub template: String,
+    /// Value of `{{device}}`
+    pub device_name: String,
+    /// Timeout of one request including the connect
+    pub timeout: Duration,
+    /// Attempts after the first failed one
+    pub retries: u32,
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Maximum number of changes waiting to be sent
+    pub queue_depth: usize,
+}
+
+impl WebhookOptions {
+    /// Options posting to `url` with the default template.
+    pub fn new(url: &str) -> WebhookOptions {
+        WebhookOptions {
+            url: url.to_string(),
+            headers: Vec::new(),
+            token: None,
+            template: DEFAULT_TEMPLATE.to_string(),
+            device_name: "frontdoor".to_string(),
+            timeout: Duration::from_secs(10),
+            retries: 4,
+            min_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+            queue_depth: 16,
+        }
+    }
+}
+
+/// Body of the notification about `change` of `device` at `time`.
+pub fn render(template: &str, device: &str, change: &StateChange, time: SystemTime) -> String {
+    let quoted = |text: &str| {
+        let mut out = String::new();
+        json_string(&mut out, text);
+        out
+    };
+    let previous = change.previous.map_or("null".to_string(), |previous| quoted(previous.name()));
+    let mut json = String::new();
+    change.write_json(&mut json);
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    template
+        .replace("{{device}}", &quoted(device))
+        .replace("{{previous}}", &previous)
+        .replace("{{state}}", &quoted(change.current.name()))
+        .replace("{{trigger}}", &quoted(change.trigger.name()))
+        .replace("{{timestamp_ms}}", &since.as_millis().to_string())
+        .replace("{{timestamp}}", &quoted(&rfc3339(time)))
+        .replace("{{change}}", &json)
+}
+
+/// Check `options`, failing with `ErrorKind::InvalidInput`.
+fn validate(options: &WebhookOptions) -> std::io::Result<()> {
+    if !options.url.starts_with("http://") && !options.url.starts_with("https://") {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("webhook URL {} is not http:// or https://", options.url)));
+    }
+    for part in options.template.split("{{").skip(1) {
+        let name = part.split("}}").next().unwrap_or(part);
+        if !PLACEHOLDERS.contains(&name) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("unknown webhook template placeholder {{{{{}}}}}", name)));
+        }
+    }
+    if options.min_backoff > options.max_backoff {
+        return Err(Error::new(ErrorKind::InvalidInput, "webhook min_backoff exceeds max_backoff"));
+    }
+    Ok(())
+}
+
+/// Outcome of one attempt.
+enum Attempt {
+    Delivered,
+    /// Worth another attempt
+    Failed(String),
+    /// Not worth another attempt, e.g. 404
+    Rejected(String),
+}
+
+fn post(agent: &ureq::Agent, options: &WebhookOptions, body: &str) -> Attempt {
+    let mut request = agent.post(&options.url);
+    if !options.headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("content-type")) {
+        request = request.set("Content-Type", "application/json");
+    }
+    for (name, value) in &options.headers {
+        request = request.set(name, value);
+    }
+    if let Some(ref token) = options.token {
+        request = request.set("Authorization", &format!("Bearer {}", token));
+    }
+    match request.send_string(body) {
+        Ok(_) => Attempt::Delivered,
+        Err(ureq::Error::Status(status, _)) if status == 408 || status == 429 || status >= 500 => {
+            Attempt::Failed(format!("status {}", status))
+        },
+        Err(ureq::Error::Status(status, _)) => Attempt::Rejected(format!("status {}", status)),
+        Err(ureq::Error::Transport(err)) => Attempt::Failed(err.to_string()),
+    }
+}
+
+/// Send the queued notifications until the notifier is dropped.
+fn deliver(options: &WebhookOptions, changes: mpsc::Receiver<(StateChange, SystemTime)>, stop: &StopToken) {
+    let agent = ureq::AgentBuilder::new().timeout(options.timeout).build();
+    for (change, time) in changes {
+        let body = render(&options.template, &options.device_name, &change, time);
+        let mut backoff = options.min_backoff;
+        let mut attempt = 0;
+        loop {
+            let reason = match post(&agent, options, &body) {
+                Attempt::Delivered => break,
+                Attempt::Rejected(reason) => {
+                    log::warn!("webhook {} rejected the {} notification: {}", options.url, change.current.name(), reason);
+                    break;
+                },
+                Attempt::Failed(reason) => reason,
+            };
+            if attempt == options.retries {
+                log::warn!("dropping the {} notification after {} attempts, last error: {}", change.current.name(), attempt + 1, reason);
+                break;
+            }
+            if stop.wait_timeout(backoff) {
+                return;
+            }
+            attempt += 1;
+            backoff = std::cmp::min(backoff * 2, options.max_backoff);
+        }
+    }
+}
+
+/// Posts state changes to a webhook, see the module documentation.
+///
+/// Dropping the notifier sends the waiting changes, but abandons those
+/// waiting for another attempt.
+pub struct WebhookNotifier {
+    changes: Option<SyncSender<(StateChange, SystemTime)>>,
+    stop: StopToken,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl WebhookNotifier {
+    /// Start a notifier posting to `options.url`. Fails with
+    /// `ErrorKind::InvalidInput` for URLs which are not HTTP(S),
+    /// unknown template placeholders and a backoff range ending before
+    /// it starts.
+    pub fn new(options: WebhookOptions) -> std::io::Result<WebhookNotifier> {
+        validate(&options)?;
+        let (changes, rx) = mpsc::sync_channel(options.queue_depth);
+        let stop = StopToken::new();
+        let worker_stop = stop.clone();
+        let worker = (std::thread::Builder::new()
+            .name("cff3000-webhook".to_string())
+            .spawn(move || deliver(&options, rx, &worker_stop)))?;
+        Ok(WebhookNotifier {changes: Some(changes), stop, worker: Some(worker)})
+    }
+
+    /// Queue `change` for delivery with its `at` as the timestamp.
+    /// Never blocks; the change is logged and dropped if the queue is
+    /// full.
+    pub fn notify(&self, change: StateChange) {
+        let changes = match self.changes {
+            Some(ref changes) => changes,
+            None => return,
+        };
+        if let Err(TrySendError::Full(_)) = changes.try_send((change, change.at)) {
+            log::warn!("dropping the {} notification, the webhook queue is full", change.current.name());
+        }
+    }
+}
+
+impl Drop for WebhookNotifier {
+    fn drop(&mut self) {
+        self.changes = None;
+        self.stop.stop();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Watch `cff3000` like `CFF3000::watch_with_options()` and notify
+/// `notifier` of every state change until `stop` is stopped.
+pub fn publish_changes(cff3000: &CFF3000, options: &WatchOptions, stop: &StopToken, notifier: &WebhookNotifier) -> std::io::Result<()> {
+    cff3000.watch_with_options(options, stop, |change| notifier.notify(change))
+}
@@ -0,0 +1,100 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Servers on sockets passed like systemd does (`LISTEN_FDS`).
+
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+
+use cff3000::socket::{serve, UnixClient};
+use cff3000::testing::{self, Replay};
+use cff3000::{CFF3000State, LockControl};
+
+/// Keep descriptors 3 and 4 open while they are not passed, so the
+/// sockets of the test get others.
+fn reserve() {
+    for fd in 3..5 {
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+            let null = std::fs::File::open("/dev/null").unwrap();
+            /* the file got the lowest free descriptor, maybe this one */
+            if null.as_raw_fd() != fd {
+                assert_eq!(unsafe { libc::dup2(null.as_raw_fd(), fd) }, fd);
+            } else {
+                std::mem::forget(null);
+            }
+        }
+    }
+}
+
+/// Pass `sockets` to this process as descriptors 3 and up.
+fn pass<S: AsRawFd>(sockets: &[&S]) {
+    for (i, socket) in sockets.iter().enumerate() {
+        assert_eq!(unsafe { libc::dup2(socket.as_raw_fd(), 3 + i as libc::c_int) }, 3 + i as libc::c_int);
+    }
+    std::env::set_var("LISTEN_FDS", sockets.len().to_string());
+    std::env::set_var("LISTEN_PID", std::process::id().to_string());
+}
+
+/// One test, the passed sockets belong to the whole process.
+#[test]
+fn passed_sockets() {
+    /* keep whatever the harness has at 3 and 4 */
+    let saved: Vec<_> = (3..5).map(|fd| unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 10) }).collect();
+    reserve();
+    let device = Arc::new(testing::device(&Replay::with_captures(&[Some(CFF3000State::Locked)])).build().unwrap());
+
+    let path = std::env::temp_dir().join(format!("cff3000-{}-activated.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    pass(&[&listener]);
+    let server = serve(device.clone(), "/nonexistent/cff3000.sock").unwrap();
+    assert_eq!(server.path(), path.as_path());
+    assert!(std::env::var_os("LISTEN_FDS").is_none());
+    assert_eq!(UnixClient::connect(&path).unwrap().state().unwrap(), CFF3000State::Locked);
+    /* the file belongs to systemd */
+    drop(server);
+    assert!(path.exists());
+    std::fs::remove_file(&path).unwrap();
+
+    #[cfg(feature = "http")]
+    {
+        use std::io::{Read, Write};
+        reserve();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        pass(&[&listener]);
+        let server = cff3000::http::serve(device.clone(), "192.0.2.1:80").unwrap();
+        assert_eq!(server.local_addr(), listener.local_addr().unwrap());
+        let mut stream = std::net::TcpStream::connect(server.local_addr()).unwrap();
+        stream.write_all(b"GET /healthz HTTP/1.1\r\nHost: door\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    }
+
+    /* without passed sockets, the server binds its own */
+    let server = serve(device.clone(), &path).unwrap();
+    drop(server);
+    assert!(!path.exists());
+
+    /* a connected pair is no listener */
+    reserve();
+    let (pair, _other) = UnixStream::pair().unwrap();
+    pass(&[&pair]);
+    assert_eq!(serve(device.clone(), &path).err().unwrap().kind(), std::io::ErrorKind::InvalidInput);
+
+    reserve();
+    let (first, second) = (UnixListener::bind(&path).unwrap(), UnixListener::bind(path.with_extension("2")).unwrap());
+    pass(&[&first, &second]);
+    let err = serve(device, &path).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("2 Unix stream sockets"), "{}", err);
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(path.with_extension("2")).unwrap();
+
+    for (fd, saved) in (3..5).zip(saved) {
+        if saved >= 0 {
+            unsafe { libc::dup2(saved, fd) };
+        }
+    }
+}
@@ -0,0 +1,99 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! `activity` of the key remote on the replay backend.
+
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use cff3000::testing::{self, generate, PatternParams, Replay};
+use cff3000::{CFF3000, CFF3000Builder, CFF3000State, LedEvent, Notice, StopToken};
+
+/// `events` shown `after` the start of a press, for the same capture.
+fn later(events: Vec<LedEvent>, after: Duration) -> Vec<LedEvent> {
+    events.into_iter().map(|event| LedEvent {timestamp: event.timestamp + after, ..event}).collect()
+}
+
+/// Device on `replay` with a monitor stopping `stop` on the first
+/// external activity, which ends up in the returned list.
+fn device(replay: &Replay, stop: &StopToken) -> (CFF3000, Arc<Mutex<Vec<Notice>>>) {
+    let notices = Arc::new(Mutex::new(Vec::new()));
+    let (seen, stop) = (notices.clone(), stop.clone());
+    let device = testing::device(replay)
+        .monitor(move |notice| {
+            if let Notice::ExternalActivity {..} = *notice {
+                seen.lock().unwrap().push(notice.clone());
+                stop.stop();
+            }
+        })
+        .build()
+        .unwrap();
+    (device, notices)
+}
+
+#[test]
+fn key_remote_is_classified() {
+    let replay = Replay::new();
+    let remote = later(generate(CFF3000State::Unlocked, PatternParams::default()), Duration::from_secs(40));
+    let mut capture = generate(CFF3000State::Locked, PatternParams::default());
+    capture.extend(remote.iter().cloned());
+    replay.push_capture(capture);
+
+    let stop = StopToken::new();
+    let (device, notices) = device(&replay, &stop);
+    assert_eq!(device.state().unwrap(), CFF3000State::Locked);
+    device.listen_for_activity(&stop).unwrap();
+
+    let notices = notices.lock().unwrap();
+    /* replayed from the start of the press */
+    assert_eq!(*notices, vec![Notice::ExternalActivity {classified: Some(CFF3000State::Unlocked), events: remote.clone()}]);
+    assert_eq!(notices[0].to_string(), format!("external activity: unlocked ({} LED events)", remote.len()));
+}
+
+#[test]
+fn own_patterns_are_not_reported() {
+    let replay = Replay::new();
+    /* a plain lock does not read its pattern */
+    let mut capture = generate(CFF3000State::Locked, PatternParams::default());
+    capture.extend(later(generate(CFF3000State::Unlocked, PatternParams::default()), Duration::from_secs(60)));
+    replay.push_capture(capture);
+
+    let stop = StopToken::new();
+    let (device, notices) = device(&replay, &stop);
+    device.lock().unwrap();
+    device.listen_for_activity(&stop).unwrap();
+
+    let notices = notices.lock().unwrap();
+    assert_eq!(notices.len(), 1);
+    match notices[0] {
+        Notice::ExternalActivity {classified, ..} => assert_eq!(classified, Some(CFF3000State::Unlocked)),
+        ref notice => panic!("unexpected {:?}", notice),
+    }
+    assert!(replay.elapsed() >= Duration::from_secs(60));
+}
+
+#[test]
+fn unknown_patterns_are_reported() {
+    let replay = Replay::new();
+    let mut capture = generate(CFF3000State::Locked, PatternParams::default());
+    let flash = generate(CFF3000State::Locked, PatternParams::default());
+    capture.extend(later(flash[..2].to_vec(), Duration::from_secs(30)));
+    replay.push_capture(capture);
+
+    let stop = StopToken::new();
+    let (device, notices) = device(&replay, &stop);
+    assert_eq!(device.state().unwrap(), CFF3000State::Locked);
+    device.listen_for_activity(&stop).unwrap();
+
+    let notices = notices.lock().unwrap();
+    assert_eq!(notices.len(), 1);
+    assert_eq!(notices[0].to_string(), "external activity: 2 LED events, no known pattern");
+}
+
+#[test]
+fn listening_needs_a_monitor() {
+    let device = CFF3000Builder::with_backend(Replay::new()).build().unwrap();
+    let err = device.listen_for_activity(&StopToken::new()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
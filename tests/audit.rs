@@ -0,0 +1,161 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! `audit::AuditLog` against local datagram sockets.
+
+use std::collections::HashMap;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use cff3000::audit::{AuditError, AuditLog, AuditRecord, AuditTarget};
+use cff3000::testing::{self, Replay};
+use cff3000::{CFF3000State, Command, CommandQueue, CFF3000};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Listening socket at a fresh path, removed on drop.
+struct Listener {
+    path: PathBuf,
+    socket: UnixDatagram,
+}
+
+impl Listener {
+    fn new(name: &str) -> Listener {
+        let path = std::env::temp_dir().join(format!("cff3000-audit-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+        Listener {path, socket}
+    }
+
+    fn recv(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; 4096];
+        let len = self.socket.recv(&mut buf).expect("no audit record");
+        buf.truncate(len);
+        buf
+    }
+
+    /// Fields of the next journal datagram, only simple `KEY=value` lines.
+    fn fields(&self) -> HashMap<String, String> {
+        String::from_utf8(self.recv()).unwrap().lines()
+            .map(|line| {
+                let (key, value) = line.split_at(line.find('=').unwrap());
+                (key.to_string(), value[1..].to_string())
+            })
+            .collect()
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn device(log: Arc<AuditLog>, captures: &[Option<CFF3000State>]) -> CFF3000 {
+    testing::device(&Replay::with_captures(captures)).audit(log).build().unwrap()
+}
+
+#[test]
+fn journal_fields() {
+    let listener = Listener::new("journal");
+    let log = Arc::new(AuditLog::with_socket(AuditTarget::Journal, &listener.path).unwrap());
+    let cff3000 = device(log.clone(), &[Some(CFF3000State::Locked), None]);
+
+    let before = std::time::SystemTime::now();
+    assert_eq!(cff3000.execute_as(Command::Lock, "cli:uid=1000").unwrap(), CFF3000State::Locked);
+    let fields = listener.fields();
+    assert_eq!(fields["PRIORITY"], "5");
+    assert_eq!(fields["SYSLOG_IDENTIFIER"], "cff3000");
+    assert_eq!(fields["CFF3000_COMMAND"], "lock");
+    assert_eq!(fields["CFF3000_INITIATOR"], "cli:uid=1000");
+    assert_eq!(fields["CFF3000_STATE"], "locked");
+    assert!(!fields.contains_key("CFF3000_ERROR_CODE"));
+    /* on the virtual clock of the replay */
+    let duration: u64 = fields["CFF3000_DURATION_MS"].parse().unwrap();
+    assert!(duration >= 10_000, "{}", duration);
+    let started: u128 = fields["CFF3000_TIMESTAMP_USEC"].parse().unwrap();
+    assert!(started >= before.duration_since(UNIX_EPOCH).unwrap().as_micros() - 1);
+    assert!(fields["MESSAGE"].starts_with("lock by cli:uid=1000: locked after 1"), "{}", fields["MESSAGE"]);
+
+    assert!(cff3000.execute_as(Command::Check, "").is_err());
+    let fields = listener.fields();
+    assert_eq!(fields["PRIORITY"], "4");
+    assert_eq!(fields["CFF3000_COMMAND"], "check");
+    assert_eq!(fields["CFF3000_INITIATOR"], "");
+    assert_eq!(fields["CFF3000_ERROR_CODE"], "no-response");
+    assert!(!fields.contains_key("CFF3000_STATE"));
+    assert!(fields["MESSAGE"].starts_with("check by unknown initiator: failed after"), "{}", fields["MESSAGE"]);
+
+    /* plain presses have no state, unattributed calls no record */
+    cff3000.press_as(Command::Unlock, "python").unwrap();
+    let fields = listener.fields();
+    assert_eq!(fields["CFF3000_COMMAND"], "unlock");
+    assert!(!fields.contains_key("CFF3000_STATE"));
+    cff3000.lock().unwrap();
+    drop(cff3000);
+    drop(log);
+    listener.socket.set_nonblocking(true).unwrap();
+    assert!(listener.socket.recv(&mut [0u8; 16]).is_err());
+}
+
+#[test]
+fn multi_line_values() {
+    let record = AuditRecord {
+        timestamp: UNIX_EPOCH,
+        command: Command::Unlock,
+        initiator: "a\nb".to_string(),
+        result: Err(AuditError {code: "io", message: "broken".to_string()}),
+        duration: Duration::from_millis(1500),
+    };
+    let journal = record.to_journal();
+    let needle = b"CFF3000_INITIATOR\n\x03\0\0\0\0\0\0\0a\nb\n";
+    assert!(journal.windows(needle.len()).any(|window| window == needle));
+    assert_eq!(record.message(), "unlock by a\nb: failed after 1.5 s: broken");
+    assert!(record.to_journal().starts_with(b"MESSAGE\n"));
+    assert_eq!(record.to_syslog(42), concat!(
+        r#"<84>cff3000[42]: command=unlock initiator="a b" code=io error="broken" "#,
+        "duration_ms=1500 timestamp=1970-01-01T00:00:00.000Z",
+    ));
+}
+
+#[test]
+fn syslog_lines() {
+    let listener = Listener::new("syslog");
+    let log = Arc::new(AuditLog::with_socket(AuditTarget::Syslog, &listener.path).unwrap());
+    let cff3000 = device(log, &[Some(CFF3000State::Unlocked)]);
+
+    cff3000.execute_as(Command::Unlock, "http:192.0.2.7:4711 \"x\"").unwrap();
+    let line = String::from_utf8(listener.recv()).unwrap();
+    let prefix = format!("<85>cff3000[{}]: command=unlock initiator=\"http:192.0.2.7:4711 \\\"x\\\"\" state=unlocked duration_ms=", std::process::id());
+    assert!(line.starts_with(&prefix), "{}", line);
+    assert!(line.contains(" timestamp=20"), "{}", line);
+}
+
+#[test]
+fn queue_records_the_initiators() {
+    let listener = Listener::new("queue");
+    let log = Arc::new(AuditLog::with_socket(AuditTarget::Journal, &listener.path).unwrap());
+    let queue = CommandQueue::new(device(log, &[Some(CFF3000State::Manual)])).unwrap();
+
+    assert_eq!(queue.sender().send_as(Command::Check, "mqtt:broker:1883/door/set").recv().unwrap().unwrap(), CFF3000State::Manual);
+    let fields = listener.fields();
+    assert_eq!(fields["CFF3000_INITIATOR"], "mqtt:broker:1883/door/set");
+    assert_eq!(fields["CFF3000_STATE"], "manual");
+}
+
+#[test]
+fn failures_do_not_affect_the_operation() {
+    let path = std::env::temp_dir().join(format!("cff3000-audit-missing-{}", std::process::id()));
+    let log = Arc::new(AuditLog::with_socket(AuditTarget::Journal, &path).unwrap());
+    let cff3000 = device(log.clone(), &[Some(CFF3000State::Locked)]);
+
+    assert_eq!(cff3000.execute_as(Command::Lock, "test").unwrap(), CFF3000State::Locked);
+    let deadline = Instant::now() + TIMEOUT;
+    while log.lost() == 0 && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    assert_eq!(log.lost(), 1);
+}
@@ -0,0 +1,132 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Batched reads of the LED events, see "Batched reads" of
+//! `cff3000::backend`.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use cff3000::mock::MockBackend;
+use cff3000::testing::{generate, PatternParams, Replay};
+use cff3000::{Button, CFF3000Builder, CFF3000State, EventBuffer, GpioBackend, Led, LedEvent, StateQuery, Timings, MAX_EVENTS};
+
+#[derive(Default)]
+struct Calls {
+    waits: AtomicUsize,
+    reads: AtomicUsize,
+}
+
+impl Calls {
+    fn take(&self) -> (usize, usize) {
+        (self.waits.swap(0, Ordering::SeqCst), self.reads.swap(0, Ordering::SeqCst))
+    }
+}
+
+/// Counts the calls standing for system calls of a kernel backend, with
+/// the reads of one event at a time unless `batched`.
+struct Counting<B> {
+    inner: B,
+    batched: bool,
+    calls: Arc<Calls>,
+}
+
+impl<B: GpioBackend> GpioBackend for Counting<B> {
+    fn set_button(&self, button: Button, pressed: bool) -> std::io::Result<()> {
+        self.inner.set_button(button, pressed)
+    }
+
+    fn wait_for_led_events(&self, timeout: Duration) -> std::io::Result<u8> {
+        self.calls.waits.fetch_add(1, Ordering::SeqCst);
+        self.inner.wait_for_led_events(timeout)
+    }
+
+    fn read_led_event(&self, led: Led) -> std::io::Result<LedEvent> {
+        self.calls.reads.fetch_add(1, Ordering::SeqCst);
+        self.inner.read_led_event(led)
+    }
+
+    fn read_led_events(&self, led: Led, events: &mut EventBuffer) -> std::io::Result<usize> {
+        if !self.batched {
+            let event = self.read_led_event(led)?;
+            return Ok(events.push(event) as usize);
+        }
+        self.calls.reads.fetch_add(1, Ordering::SeqCst);
+        self.inner.read_led_events(led, events)
+    }
+
+    fn flush_led_events(&self) -> std::io::Result<()> {
+        self.inner.flush_led_events()
+    }
+}
+
+/// Calls reading an out-of-range pattern which is all due before the
+/// capture reads the first event, and the events read.
+fn read_late(batched: bool) -> ((usize, usize), usize) {
+    let replay = Replay::new();
+    let pattern = generate(CFF3000State::OutOfRange, PatternParams::default());
+    let last = pattern[pattern.len() - 1].timestamp;
+    replay.push_capture(pattern.clone());
+    let calls = Arc::new(Calls::default());
+    let backend = Counting {inner: replay.clone(), batched, calls: calls.clone()};
+    let device = CFF3000Builder::with_backend(backend).clock(replay.clock()).build().unwrap();
+
+    let mut query = StateQuery::start(&device).unwrap();
+    replay.advance(last);
+    calls.take();
+    assert!(query.poll_report().is_pending());
+    let late = calls.take();
+
+    replay.advance(Duration::from_secs(60));
+    let report = match query.poll_report() {
+        std::task::Poll::Ready(result) => result.unwrap(),
+        std::task::Poll::Pending => panic!("capture not complete"),
+    };
+    assert_eq!(report.state, CFF3000State::OutOfRange);
+    assert_eq!(report.events, pattern);
+    (late, pattern.len())
+}
+
+#[test]
+fn late_events_are_read_at_once() {
+    let (batched, events) = read_late(true);
+    /* one wait finding the events, a read per LED and a wait finding none */
+    assert_eq!(batched, (2, 2));
+    /* a wait for every event of the LED with the most and a read per event */
+    let (single, _) = read_late(false);
+    assert_eq!((single, events), ((9, 14), 14));
+}
+
+#[test]
+fn bursts_are_captured_in_order() {
+    const BURST: usize = 64;
+    /* as many as fit into the capture */
+    const BURSTS: usize = MAX_EVENTS / BURST;
+
+    let mock = MockBackend::new();
+    let calls = Arc::new(Calls::default());
+    let backend = Counting {inner: mock.clone(), batched: true, calls: calls.clone()};
+    let timings = Timings {press: Duration::from_millis(10), ..Timings::default()};
+    let device = CFF3000Builder::with_backend(backend).timings(timings).build().unwrap();
+
+    let bursts = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        for burst in 0..BURSTS {
+            /* the LEDs alternating every millisecond */
+            let events: Vec<LedEvent> = (0..BURST).map(|i| {
+                let n = (burst * BURST + i) as u64;
+                LedEvent {led: [Led::Red, Led::Green][n as usize % 2], on: n % 4 < 2, timestamp: Duration::from_millis(1_000 + n)}
+            }).collect();
+            mock.push_events(&events);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    });
+    let capture = device.capture(Duration::from_millis(600)).unwrap();
+    bursts.join().unwrap();
+
+    assert_eq!(capture.events.len(), BURSTS * BURST);
+    assert!(capture.events.windows(2).all(|pair| pair[0].timestamp < pair[1].timestamp));
+    let (_, reads) = calls.take();
+    assert!(reads <= 2 * BURSTS, "{} reads for {} bursts", reads, BURSTS);
+}
@@ -0,0 +1,95 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! `CFF3000::battery_usage()` on the replay backend and the estimate of
+//! `BatteryUsage`.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use cff3000::battery::{BatteryMarker, BatteryOptions, BatteryUsage};
+use cff3000::testing::{self, Replay};
+use cff3000::CFF3000State;
+
+const DAY: u64 = 24 * 60 * 60;
+
+fn marker(commands: u64) -> BatteryMarker {
+    BatteryMarker {since: UNIX_EPOCH + Duration::from_secs(1_750_000_000), replaced: true, commands}
+}
+
+fn usage(commands: u64, days: u64, options: BatteryOptions) -> BatteryUsage {
+    BatteryUsage::new(marker(commands), options, marker(0).since + Duration::from_secs(days * DAY))
+}
+
+#[test]
+fn presses_are_counted() {
+    /* one capture per press */
+    let replay = Replay::with_captures(&[None, None, Some(CFF3000State::Locked)]);
+    let device = testing::device(&replay).build().unwrap();
+    let usage = device.battery_usage();
+    assert_eq!((usage.commands, usage.replaced, usage.budget), (0, false, 5000));
+
+    device.lock().unwrap();
+    device.unlock().unwrap();
+    assert_eq!(device.state().unwrap(), CFF3000State::Locked);
+    assert_eq!(device.battery_usage().commands, 3);
+    assert_eq!(device.battery_marker().commands, 3);
+}
+
+#[test]
+fn dry_runs_are_not_counted() {
+    let replay = Replay::new();
+    let device = testing::device(&replay).dry_run(true).build().unwrap();
+    device.lock().unwrap();
+    device.check().unwrap();
+    assert_eq!(device.battery_usage().commands, 0);
+}
+
+#[test]
+fn markers_are_restored_and_reset() {
+    let replay = Replay::new();
+    let options = BatteryOptions {budget: 100, warn_below_percent: 10};
+    let device = testing::device(&replay).battery(options).build().unwrap();
+    device.lock().unwrap();
+    device.restore_battery(marker(89));
+    assert_eq!(device.battery_marker(), marker(90));
+    let usage = device.battery_usage();
+    assert_eq!((usage.remaining(), usage.warning), (10, false));
+    device.unlock().unwrap();
+    assert!(device.battery_usage().warning);
+
+    let before = SystemTime::now();
+    device.reset_battery();
+    let marker = device.battery_marker();
+    assert!(marker.since >= before && marker.replaced);
+    assert_eq!(marker.commands, 0);
+    assert!(!device.battery_usage().warning);
+}
+
+#[test]
+fn usage_is_estimated() {
+    let options = BatteryOptions::default();
+    let fresh = usage(30, 0, options);
+    assert_eq!((fresh.per_day(), fresh.days_left(), fresh.remaining()), (None, None, 4970));
+
+    let usage = self::usage(1210, 100, options);
+    assert_eq!(usage.elapsed, Duration::from_secs(100 * DAY));
+    assert_eq!(usage.per_day(), Some(12.1));
+    assert_eq!(usage.remaining(), 3790);
+    assert!((usage.days_left().unwrap() - 313.22).abs() < 0.01);
+
+    let idle = self::usage(0, 10, options);
+    assert_eq!((idle.per_day(), idle.days_left()), (Some(0.0), None));
+
+    let spent = self::usage(6000, 300, options);
+    assert_eq!((spent.remaining(), spent.days_left(), spent.warning), (0, Some(0.0), true));
+}
+
+#[test]
+fn warning_follows_the_threshold() {
+    let options = BatteryOptions {budget: 1000, warn_below_percent: 20};
+    assert!(!usage(800, 1, options).warning);
+    assert!(usage(801, 1, options).warning);
+    assert!(!usage(999, 1, BatteryOptions {warn_below_percent: 0, ..options}).warning);
+    assert!(!usage(0, 1, BatteryOptions {warn_below_percent: 100, ..options}).warning);
+    assert!(usage(1, 1, BatteryOptions {warn_below_percent: 100, ..options}).warning);
+}
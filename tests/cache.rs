@@ -0,0 +1,99 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! `CFF3000Builder::state_cache()` on the replay backend.
+
+use std::time::Duration;
+
+use cff3000::testing::{self, generate, PatternParams, Replay};
+use cff3000::{CFF3000, CFF3000State, Led, LedEvent};
+
+const TTL: Duration = Duration::from_secs(60);
+
+fn device(replay: &Replay) -> CFF3000 {
+    testing::device(replay).state_cache(TTL).build().unwrap()
+}
+
+fn presses(replay: &Replay) -> usize {
+    replay.transitions().iter().filter(|transition| transition.pressed).count()
+}
+
+#[test]
+fn states_within_the_ttl_are_cached() {
+    let replay = Replay::with_captures(&[Some(CFF3000State::Locked), Some(CFF3000State::Unlocked)]);
+    let device = device(&replay);
+    assert_eq!(device.last_state(), None);
+
+    let queried = device.cached_state().unwrap();
+    assert_eq!((queried.state, queried.age, queried.cached), (CFF3000State::Locked, Duration::from_secs(0), false));
+    let pressed = presses(&replay);
+
+    replay.advance(Duration::from_secs(20));
+    let cached = device.cached_state().unwrap();
+    assert!(cached.cached);
+    assert_eq!(cached.state, CFF3000State::Locked);
+    assert!(cached.age >= Duration::from_secs(20) && cached.age < TTL);
+    assert!(device.is_locked().unwrap());
+    assert_eq!(presses(&replay), pressed);
+
+    replay.advance(TTL);
+    assert_eq!(device.last_state().unwrap().state, CFF3000State::Locked);
+    assert_eq!(device.state().unwrap(), CFF3000State::Unlocked);
+    assert!(presses(&replay) > pressed);
+}
+
+#[test]
+fn presses_invalidate_the_cache() {
+    let replay = Replay::with_captures(&[Some(CFF3000State::Locked), Some(CFF3000State::Unlocked), Some(CFF3000State::Locked), Some(CFF3000State::Locked)]);
+    let device = device(&replay);
+    assert_eq!(device.state().unwrap(), CFF3000State::Locked);
+
+    assert_eq!(device.unlock_and_verify().unwrap(), CFF3000State::Unlocked);
+    assert!(device.cached_state().unwrap().cached);
+
+    device.lock().unwrap();
+    assert_eq!(device.last_state(), None);
+    assert!(!device.cached_state().unwrap().cached);
+
+    device.invalidate_cached_state();
+    assert_eq!(device.last_state(), None);
+}
+
+#[test]
+fn led_activity_invalidates_the_cache() {
+    let replay = Replay::new();
+    /* the LEDs light up long after the capture, as if the remote
+     * control has been used */
+    let mut events = generate(CFF3000State::Locked, PatternParams::default());
+    events.push(LedEvent {led: Led::Green, on: true, timestamp: Duration::from_secs(30)});
+    replay.push_capture(events);
+    replay.push_capture(generate(CFF3000State::Unlocked, PatternParams::default()));
+    let device = device(&replay);
+
+    assert_eq!(device.state().unwrap(), CFF3000State::Locked);
+    assert!(device.cached_state().unwrap().cached);
+    replay.advance(Duration::from_secs(30));
+    let queried = device.cached_state().unwrap();
+    assert_eq!((queried.state, queried.cached), (CFF3000State::Unlocked, false));
+}
+
+#[test]
+fn without_a_cache_every_call_queries() {
+    let device = testing::device(&Replay::with_captures(&[Some(CFF3000State::Locked), Some(CFF3000State::Unlocked)])).build().unwrap();
+
+    assert_eq!(device.state().unwrap(), CFF3000State::Locked);
+    assert!(!device.is_locked().unwrap());
+    assert_eq!(device.last_state(), None);
+}
+
+#[test]
+fn restored_states_prime_an_empty_cache() {
+    let replay = Replay::new();
+    let device = device(&replay);
+
+    device.restore_cached_state(CFF3000State::Unlocked, Duration::from_secs(10));
+    let cached = device.cached_state().unwrap();
+    assert_eq!((cached.state, cached.age, cached.cached), (CFF3000State::Unlocked, Duration::from_secs(10), true));
+    device.restore_cached_state(CFF3000State::Locked, Duration::from_secs(5));
+    assert_eq!(device.state().unwrap(), CFF3000State::Unlocked);
+}
@@ -0,0 +1,98 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Fixed capacity of the captures, see `cff3000::EventBuffer`.
+
+use std::time::Duration;
+
+use cff3000::testing::{self, generate, PatternParams, Replay};
+use cff3000::{CaptureError, CFF3000State, EventBuffer, Led, LedEvent, ParseError, TimingProfile, MAX_EVENTS};
+
+/// Out-of-range pattern with more events than fit, all shown within
+/// the capture window of a state query.
+fn long_pattern() -> Vec<LedEvent> {
+    let pattern = generate(CFF3000State::OutOfRange, PatternParams {blinks: 100, blink_period: Duration::from_millis(50), ..PatternParams::default()});
+    assert!(pattern.len() > MAX_EVENTS);
+    pattern
+}
+
+#[test]
+fn buffers_do_not_grow() {
+    let event = LedEvent {led: Led::Green, on: true, timestamp: Duration::from_millis(700)};
+    let mut events = EventBuffer::new();
+    assert!(events.is_empty());
+    for _ in 0..MAX_EVENTS {
+        assert!(events.push(event));
+    }
+    assert!(events.is_full());
+    assert!(!events.push(event));
+    assert_eq!((events.len(), events.remaining()), (MAX_EVENTS, 0));
+
+    assert!(EventBuffer::from_slice(&[event; MAX_EVENTS + 1]).is_none());
+    let mut events = EventBuffer::from_slice(&[event; 100]).unwrap();
+    assert_eq!(events.extend_from_slice(&[event; 100]), MAX_EVENTS - 100);
+    events.clear();
+    assert_eq!(events, Vec::new());
+}
+
+#[test]
+fn full_captures_stop_early() {
+    let replay = Replay::new();
+    replay.push_capture(long_pattern());
+    let device = testing::device(&replay).build().unwrap();
+
+    let window = Duration::from_secs(10);
+    let started = replay.elapsed();
+    let capture = device.capture(window).unwrap();
+    assert!(capture.truncated);
+    assert_eq!(capture.events.len(), MAX_EVENTS);
+    assert!(capture.events.windows(2).all(|pair| pair[0].timestamp <= pair[1].timestamp));
+    /* when the first event without room arrived */
+    assert!(replay.elapsed() - started < window);
+}
+
+#[test]
+fn truncated_reports_say_so() {
+    let replay = Replay::new();
+    replay.push_capture(long_pattern());
+    replay.push_capture(generate(CFF3000State::Locked, PatternParams::default()));
+    let device = testing::device(&replay).build().unwrap();
+
+    /* the blinking up to the stop still reads as out of range */
+    let report = device.state_report().unwrap();
+    assert_eq!((report.state, report.truncated), (CFF3000State::OutOfRange, true));
+    assert_eq!(report.diagnostics(), vec![format!("capture stopped after {} events", MAX_EVENTS)]);
+    /* the rest of the pattern does not reach the next query */
+    let report = device.state_report().unwrap();
+    assert_eq!((report.state, report.truncated), (CFF3000State::Locked, false));
+    assert!(report.diagnostics().is_empty());
+}
+
+#[test]
+fn truncated_errors_say_so() {
+    let replay = Replay::new();
+    let mut pattern = long_pattern();
+    /* only the green LED switching on first */
+    pattern.remove(0);
+    replay.push_capture(pattern);
+    let device = testing::device(&replay).build().unwrap();
+
+    let err = device.state_report().unwrap_err();
+    assert!(err.to_string().ends_with(&format!("(capture stopped after {} events)", MAX_EVENTS)), "{}", err);
+    /* the note does not hide the pattern error from the classification */
+    let capture = err.get_ref().and_then(|inner| inner.downcast_ref::<CaptureError>()).unwrap();
+    assert_eq!(capture.error, ParseError::InvalidFirst);
+    let source = std::error::Error::source(capture).and_then(|source| source.downcast_ref::<ParseError>());
+    assert_eq!(source, Some(&capture.error));
+}
+
+/// The capacity is four times the longest pattern, see `MAX_EVENTS`.
+#[test]
+fn patterns_fit() {
+    for &state in &[CFF3000State::Locked, CFF3000State::Unlocked, CFF3000State::Manual, CFF3000State::OutOfRange] {
+        for profile in &[TimingProfile::CLASSIC, TimingProfile::REV2] {
+            let pattern = generate(state, PatternParams::from_profile(profile));
+            assert!(pattern.len() * 4 <= MAX_EVENTS, "{} events for {:?}", pattern.len(), state);
+        }
+    }
+}
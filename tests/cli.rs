@@ -0,0 +1,435 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Snapshots of the JSON output of the `cff3000` binary, see
+//! `cff3000::cli`. A failing snapshot means the documented schema
+//! changed.
+
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap_complete::Shell;
+
+use cff3000::battery::{BatteryMarker, BatteryOptions, BatteryUsage};
+use cff3000::cli::{command, exit_code_help, format_battery, format_config, format_history, log_level, replay_csv_diagnostics, replay_diagnostics, write_completions, write_man_page};
+use cff3000::cli::{CachedState, CliError, ErrorCode, Report, StateCache, EXIT_USAGE};
+use cff3000::config::{ConfigEntry, ConfigSource};
+use cff3000::history::{HistoryEntry, HistoryError};
+use cff3000::mock::{fixtures, MockBackend};
+use cff3000::testing::{generate, Fixture, PatternParams, Replay};
+use cff3000::{AlreadyInUse, Button, CaptureError, CFF3000, CFF3000Builder, CFF3000State, Command, DeviceProfile, EventBuffer, GpioBackend, Led, LedEvent,
+              ParseError, PinAssignment, StateChange, Trigger};
+
+#[test]
+fn success_snapshots() {
+    let mut report = Report::new("status", Duration::from_millis(3120));
+    report.state = Some(CFF3000State::Locked);
+    assert_eq!(report.to_json(), r#"{"command":"status","state":"locked","duration_ms":3120}"#);
+
+    report.state = Some(CFF3000State::OutOfRange);
+    assert_eq!(report.to_json(), r#"{"command":"status","state":"out-of-range","duration_ms":3120}"#);
+
+    let report = Report::new("lock", Duration::from_micros(502_900));
+    assert_eq!(report.to_json(), r#"{"command":"lock","duration_ms":502}"#);
+
+    let mut report = Report::new("status", Duration::from_millis(1));
+    report.state = Some(CFF3000State::Locked);
+    report.cached = true;
+    assert_eq!(report.to_json(), r#"{"command":"status","state":"locked","cached":true,"duration_ms":1}"#);
+}
+
+#[test]
+fn watch_snapshots() {
+    let first = StateChange::new(None, CFF3000State::Locked, Trigger::Poll, SystemTime::now());
+    let report = Report::change(first, Duration::from_millis(3120));
+    assert_eq!(report.to_json(), r#"{"command":"watch","state":"locked","trigger":"poll","duration_ms":3120}"#);
+    assert_eq!(report.summary().unwrap(), "locked");
+    assert_eq!(first.to_string(), "locked (poll)");
+    assert_eq!(report.exit_code(), 0);
+
+    let change = StateChange::new(Some(CFF3000State::Locked), CFF3000State::Unlocked, Trigger::Poll, SystemTime::now());
+    let report = Report::change(change, Duration::from_millis(600412));
+    assert_eq!(report.to_json(), r#"{"command":"watch","state":"unlocked","previous":"locked","trigger":"poll","duration_ms":600412}"#);
+    assert_eq!(report.summary().unwrap(), "locked -> unlocked");
+
+    let change = StateChange::new(Some(CFF3000State::Unlocked), CFF3000State::Locked, Trigger::AutoLock, SystemTime::now());
+    let report = Report::change(change, Duration::from_millis(900000));
+    assert_eq!(report.to_json(), r#"{"command":"watch","state":"locked","previous":"unlocked","trigger":"auto-lock","duration_ms":900000}"#);
+    assert_eq!(report.summary().unwrap(), "unlocked -> locked (auto-lock)");
+    assert_eq!(change.to_string(), "unlocked -> locked (auto-lock)");
+
+    assert_eq!(Report::new("watch", Duration::from_secs(1)).summary(), None);
+}
+
+#[test]
+fn error_snapshots() {
+    let mut report = Report::new("lock", Duration::from_millis(10498));
+    report.state = Some(CFF3000State::Unlocked);
+    report.error = Some(CliError {code: ErrorCode::NotConfirmed, message: "not confirmed, the device shows unlocked".to_string()});
+    assert_eq!(report.to_json(),
+        r#"{"command":"lock","state":"unlocked","error":{"code":"not-confirmed","message":"not confirmed, the device shows unlocked"},"duration_ms":10498}"#);
+
+    let mut report = Report::new("status", Duration::from_millis(3));
+    report.error = Some(CliError::from(Error::new(ErrorKind::NotFound, "/dev/gpiochip9: \"missing\"\n\ttab\u{1}")));
+    assert_eq!(report.to_json(),
+        r#"{"command":"status","error":{"code":"not-found","message":"/dev/gpiochip9: \"missing\"\n\ttab\u0001"},"duration_ms":3}"#);
+}
+
+#[test]
+fn history_snapshots() {
+    let entries = vec![
+        HistoryEntry {
+            timestamp: UNIX_EPOCH + Duration::from_millis(1760000000123),
+            command: Command::Lock,
+            result: Ok(Some(CFF3000State::Locked)),
+            duration: Duration::from_millis(10204),
+        },
+        HistoryEntry {
+            timestamp: UNIX_EPOCH + Duration::from_millis(1760000300456),
+            command: Command::Check,
+            result: Err(HistoryError {code: "no-response", message: "did not receive enough LED change events".to_string()}),
+            duration: Duration::from_millis(8012),
+        },
+        HistoryEntry {timestamp: UNIX_EPOCH + Duration::from_secs(1760000400), command: Command::Unlock, result: Ok(None), duration: Duration::from_millis(502)},
+    ];
+    let mut report = Report::new("history", Duration::from_millis(4));
+    assert_eq!(report.to_json(), r#"{"command":"history","history":[],"duration_ms":4}"#);
+    report.history = entries.clone();
+    assert_eq!(report.to_json(), concat!(
+        r#"{"command":"history","history":["#,
+        r#"{"timestamp_ms":1760000000123,"command":"lock","state":"locked","duration_ms":10204},"#,
+        r#"{"timestamp_ms":1760000300456,"command":"check","error":{"code":"no-response","message":"did not receive enough LED change events"},"duration_ms":8012},"#,
+        r#"{"timestamp_ms":1760000400000,"command":"unlock","duration_ms":502}"#,
+        r#"],"duration_ms":4}"#));
+    assert_eq!(format_history(&entries), concat!(
+        "2025-10-09T08:53:20.123Z  lock    locked  10204 ms\n",
+        "2025-10-09T08:58:20.456Z  check   no-response: did not receive enough LED change events  8012 ms\n",
+        "2025-10-09T09:00:00.000Z  unlock  pressed  502 ms\n"));
+}
+
+#[test]
+fn battery_snapshots() {
+    let since = UNIX_EPOCH + Duration::from_millis(1750000000456);
+    let options = BatteryOptions {budget: 1500, warn_below_percent: 20};
+    let usage = BatteryUsage::new(BatteryMarker {since, replaced: true, commands: 1210}, options, since + Duration::from_secs(100 * 24 * 60 * 60));
+    let mut report = Report::new("battery", Duration::from_millis(2));
+    report.battery = Some(usage);
+    assert_eq!(report.to_json(), concat!(
+        r#"{"command":"battery","battery":{"since_ms":1750000000456,"replaced":true,"commands":1210,"elapsed_ms":8640000000,"#,
+        r#""budget":1500,"warning":true,"per_day":12.1,"remaining":290,"days_left":24.0},"duration_ms":2}"#));
+    assert_eq!(format_battery(&usage), concat!(
+        "1210 commands since 2025-06-15T15:06:40.456Z (batteries replaced)\n",
+        "12.1 per day, 290 of 1500 left, about 24 days\n",
+        "warning: replace the batteries soon\n"));
+
+    let first = BatteryUsage::new(BatteryMarker {since, replaced: false, commands: 3}, BatteryOptions::default(), since + Duration::from_secs(60));
+    assert_eq!(format_battery(&first), "3 commands since 2025-06-15T15:06:40.456Z (first start, no battery change recorded)\n4997 of 5000 left\n");
+}
+
+#[test]
+fn errors_are_classified() {
+    let cases = [
+        (Error::new(ErrorKind::InvalidInput, "chip is not configured"), ErrorCode::Config),
+        (Error::new(ErrorKind::InvalidData, "invalid TOML"), ErrorCode::Config),
+        (Error::new(ErrorKind::InvalidData, ParseError::NotEnoughEvents), ErrorCode::NoResponse),
+        (Error::new(ErrorKind::InvalidData, ParseError::InvalidState), ErrorCode::InvalidPattern),
+        (Error::new(ErrorKind::InvalidData, CaptureError {error: ParseError::NotEnoughEvents, notes: vec!["1 event lost during capture".to_string()]}), ErrorCode::NoResponse),
+        (Error::new(ErrorKind::InvalidData, CaptureError {error: ParseError::InvalidState, notes: vec!["1 event lost during capture".to_string()]}), ErrorCode::InvalidPattern),
+        (Error::from(ErrorKind::NotFound), ErrorCode::NotFound),
+        (Error::from(ErrorKind::PermissionDenied), ErrorCode::PermissionDenied),
+        (Error::from(ErrorKind::WouldBlock), ErrorCode::Busy),
+        (Error::from_raw_os_error(16), ErrorCode::Busy),
+        (Error::other(AlreadyInUse {path: PathBuf::from("/run/lock/cff3000.lock"), pid: Some(42)}), ErrorCode::Busy),
+        (Error::from(ErrorKind::Unsupported), ErrorCode::Unsupported),
+        (Error::from(ErrorKind::TimedOut), ErrorCode::Io),
+    ];
+    for &(ref err, code) in &cases {
+        assert_eq!(ErrorCode::of(err), code, "{:?}", err);
+    }
+}
+
+/// Replay whose backend reports lost LED events on every capture.
+struct Lossy(Replay);
+
+impl GpioBackend for Lossy {
+    fn set_button(&self, button: Button, pressed: bool) -> std::io::Result<()> {
+        self.0.set_button(button, pressed)
+    }
+
+    fn wait_for_led_events(&self, timeout: Duration) -> std::io::Result<u8> {
+        self.0.wait_for_led_events(timeout)
+    }
+
+    fn read_led_event(&self, led: Led) -> std::io::Result<LedEvent> {
+        self.0.read_led_event(led)
+    }
+
+    fn read_led_events(&self, led: Led, events: &mut EventBuffer) -> std::io::Result<usize> {
+        self.0.read_led_events(led, events)
+    }
+
+    fn flush_led_events(&self) -> std::io::Result<()> {
+        self.0.flush_led_events()
+    }
+
+    fn lost_led_events(&self) -> u32 {
+        3
+    }
+}
+
+#[test]
+fn lossy_captures_keep_their_code() {
+    let replay = Replay::new();
+    replay.push_capture(Vec::new());
+    let mut pattern = generate(CFF3000State::Manual, PatternParams::default());
+    /* the red LED misses a blink */
+    pattern.remove(3);
+    replay.push_capture(pattern);
+    let device = CFF3000Builder::with_backend(Lossy(replay.clone())).clock(replay.clock()).build().unwrap();
+
+    for code in [ErrorCode::NoResponse, ErrorCode::InvalidPattern] {
+        let err = device.state().unwrap_err();
+        assert!(err.to_string().ends_with("(3 events lost during capture)"), "{}", err);
+        assert_eq!(ErrorCode::of(&err), code, "{}", err);
+    }
+}
+
+/// The names are part of the JSON schema.
+#[test]
+fn error_code_names() {
+    let codes = [
+        (ErrorCode::Config, "config"),
+        (ErrorCode::NotFound, "not-found"),
+        (ErrorCode::PermissionDenied, "permission-denied"),
+        (ErrorCode::Busy, "busy"),
+        (ErrorCode::NoResponse, "no-response"),
+        (ErrorCode::InvalidPattern, "invalid-pattern"),
+        (ErrorCode::NotConfirmed, "not-confirmed"),
+        (ErrorCode::Unsupported, "unsupported"),
+        (ErrorCode::Io, "io"),
+    ];
+    for &(code, name) in &codes {
+        assert_eq!(code.name(), name);
+    }
+}
+
+/// Scripts rely on these, see the `cff3000::cli` documentation.
+#[test]
+fn exit_codes_are_stable() {
+    let states = [(CFF3000State::Locked, 0), (CFF3000State::Unlocked, 1), (CFF3000State::Manual, 2), (CFF3000State::OutOfRange, 3)];
+    for &(state, code) in &states {
+        let mut report = Report::new("status", Duration::from_secs(8));
+        report.state = Some(state);
+        assert_eq!(report.exit_code(), code);
+        /* other commands only fail or succeed */
+        report.command = "lock".to_string();
+        assert_eq!(report.exit_code(), 0);
+    }
+
+    let errors = [
+        (ErrorCode::NoResponse, 4),
+        (ErrorCode::Config, 11),
+        (ErrorCode::NotFound, 12),
+        (ErrorCode::PermissionDenied, 13),
+        (ErrorCode::Busy, 14),
+        (ErrorCode::InvalidPattern, 15),
+        (ErrorCode::NotConfirmed, 16),
+        (ErrorCode::Unsupported, 17),
+        (ErrorCode::Io, 18),
+    ];
+    assert_eq!(EXIT_USAGE, 10);
+    assert_eq!(errors.len(), ErrorCode::ALL.len());
+    for &(code, exit) in &errors {
+        let mut report = Report::new("status", Duration::from_secs(8));
+        report.error = Some(CliError {code, message: String::new()});
+        assert_eq!(report.exit_code(), exit, "{:?}", code);
+        assert!(exit_code_help().contains(&format!("  {:<3} {}: ", exit, code.name())), "{}", exit_code_help());
+    }
+}
+
+const SUBCOMMANDS: [&str; 13] = ["lock", "unlock", "check", "status", "leds", "watch", "rpc", "record", "replay", "history", "battery", "config", "completions"];
+
+#[test]
+fn verbosity_selects_log_level() {
+    let levels: Vec<String> = [(0, 2), (0, 1), (0, 0), (1, 0), (2, 0), (3, 0)].iter()
+        .map(|&(verbose, quiet)| log_level(verbose, quiet).to_string()).collect();
+    assert_eq!(levels, ["OFF", "ERROR", "WARN", "DEBUG", "TRACE", "TRACE"]);
+    assert_eq!(log_level(1, 1), log_level(0, 0));
+
+    let args = command().try_get_matches_from(["cff3000", "-vv", "status", "-q"]).unwrap();
+    assert_eq!((args.get_count("verbose"), args.get_count("quiet")), (2, 1));
+}
+
+#[test]
+fn capture_formats() {
+    let args = command().try_get_matches_from(["cff3000", "record", "-o", "capture.vcd", "--format", "vcd"]).unwrap();
+    assert_eq!(args.subcommand_matches("record").unwrap().get_one::<String>("format").unwrap(), "vcd");
+    let args = command().try_get_matches_from(["cff3000", "replay", "capture.csv"]).unwrap();
+    assert_eq!(args.subcommand_matches("replay").unwrap().get_one::<String>("format").unwrap(), "text");
+    assert!(command().try_get_matches_from(["cff3000", "replay", "capture.csv", "--format", "fixture"]).is_err());
+    for format in &["fixture", "vcd", "csv"] {
+        assert!(command().try_get_matches_from(["cff3000", "record", "-o", "capture", "--format", format]).is_ok());
+    }
+    assert!(command().try_get_matches_from(["cff3000", "replay", "capture.csv", "--format", "csv"]).is_ok());
+}
+
+#[test]
+fn subcommands_are_listed() {
+    let names: Vec<String> = command().get_subcommands().map(|sub| sub.get_name().to_string()).collect();
+    assert_eq!(names, SUBCOMMANDS);
+}
+
+#[test]
+fn completions_mention_everything() {
+    for &shell in &[Shell::Bash, Shell::Zsh, Shell::Fish] {
+        let mut script = Vec::new();
+        write_completions(shell, &mut script);
+        let script = String::from_utf8(script).unwrap();
+        assert!(!script.is_empty(), "{}", shell);
+        for word in SUBCOMMANDS.iter().chain(&["--json", "--verify", "--interval", "--max-events"]) {
+            let word = if shell == Shell::Fish {word.trim_start_matches('-')} else {word};
+            assert!(script.contains(word), "{} completion lacks {}", shell, word);
+        }
+        /* values of the completions argument, fish does not complete positional values */
+        if shell == Shell::Fish {
+            continue;
+        }
+        for name in &["bash", "zsh", "fish"] {
+            assert!(script.contains(name), "{} completion lacks {}", shell, name);
+        }
+    }
+}
+
+#[test]
+fn man_page_mentions_everything() {
+    let mut page = Vec::new();
+    write_man_page(&mut page).unwrap();
+    let page = String::from_utf8(page).unwrap();
+    assert!(page.starts_with(".ie"));
+    for sub in &SUBCOMMANDS {
+        assert!(page.contains(&format!("cff3000\\-{}(1)", sub)), "man page lacks {}", sub);
+    }
+    assert!(page.contains("Exit status:"));
+}
+
+#[test]
+fn config_show_snapshot() {
+    let entry = |key: &str, value: &str, source| ConfigEntry {key: key.to_string(), value: value.to_string(), source};
+    let entries = [
+        entry("chip", "\"/dev/gpiochip2\"", ConfigSource::File(PathBuf::from("/etc/cff3000.toml"))),
+        entry("pins.led_red", "2", ConfigSource::CommandLine),
+        entry("timings.press_ms", "500", ConfigSource::Default),
+        entry("timings.unlock_press_ms", "1200", ConfigSource::Env),
+    ];
+    assert_eq!(format_config(&entries), concat!(
+        "chip = \"/dev/gpiochip2\"         # /etc/cff3000.toml\n",
+        "pins.led_red = 2                # command line\n",
+        "timings.press_ms = 500          # default\n",
+        "timings.unlock_press_ms = 1200  # environment\n"));
+}
+
+fn temp_cache(name: &str) -> StateCache {
+    StateCache::new(std::env::temp_dir().join(format!("cff3000-cache-{}-{}", std::process::id(), name)).join("test.state"))
+}
+
+#[test]
+fn cache_round_trip() {
+    let cache = temp_cache("round-trip");
+    assert_eq!(cache.load(), None);
+    cache.invalidate().unwrap();
+
+    let cached = CachedState::now(CFF3000State::OutOfRange);
+    cache.store(&cached).unwrap();
+    let loaded = cache.load().unwrap();
+    assert_eq!(loaded.state, CFF3000State::OutOfRange);
+    /* stored with millisecond resolution */
+    assert!(cached.timestamp.duration_since(loaded.timestamp).unwrap() < Duration::from_millis(1));
+    assert!(loaded.is_fresh(Duration::from_secs(300)));
+    assert!(!loaded.is_fresh(Duration::from_secs(0)));
+
+    let old = CachedState {state: CFF3000State::Locked, timestamp: SystemTime::now() - Duration::from_secs(600)};
+    cache.store(&old).unwrap();
+    assert!(!cache.load().unwrap().is_fresh(Duration::from_secs(300)));
+    let future = CachedState {state: CFF3000State::Locked, timestamp: SystemTime::now() + Duration::from_secs(600)};
+    assert!(!future.is_fresh(Duration::from_secs(300)));
+
+    for corrupt in &["", "locked", "locked x", "opened 1760000000000", "locked 1760000000000 1"] {
+        std::fs::write(cache.path(), corrupt).unwrap();
+        assert_eq!(cache.load(), None, "{:?}", corrupt);
+    }
+
+    cache.invalidate().unwrap();
+    assert_eq!(cache.load(), None);
+    std::fs::remove_dir(cache.path().parent().unwrap()).unwrap();
+}
+
+#[test]
+fn cache_survives_concurrent_writers() {
+    let cache = temp_cache("concurrent");
+    cache.store(&CachedState::now(CFF3000State::Locked)).unwrap();
+    let writers: Vec<_> = [CFF3000State::Unlocked, CFF3000State::Manual].iter().map(|&state| {
+        let cache = cache.clone();
+        std::thread::spawn(move || for _ in 0..200 {
+            cache.store(&CachedState::now(state)).unwrap();
+        })
+    }).collect();
+    for _ in 0..200 {
+        assert!(cache.load().is_some());
+    }
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    let dir = cache.path().parent().unwrap().to_path_buf();
+    let files: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+    assert_eq!(files, ["test.state"]);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn cache_file_per_device() {
+    if let Some(cache) = StateCache::for_device("/dev/gpiochip2", PinAssignment::from([2, 3, 4, 5])) {
+        assert_eq!(cache.path().file_name().unwrap(), "dev-gpiochip2_2-3-4-5.state");
+        assert_eq!(cache.path().parent().unwrap().file_name().unwrap(), "cff3000");
+    }
+}
+
+#[test]
+fn replay_snapshot() {
+    let fixture = Fixture::load(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/generated-locked.csv")).unwrap();
+    let lines = replay_diagnostics(&fixture, &fixture.profile.timing().parse_options());
+    assert_eq!(&lines[..3], &[
+        "6 LED events over 4002 ms".to_string(),
+        "profile classic, merge window 50 ms".to_string(),
+        "expected locked".to_string(),
+    ]);
+    assert_eq!(lines.last().unwrap(), &format!("{:>6} ms  {}", 4002, "off"));
+}
+
+#[test]
+fn csv_replay_snapshot() {
+    let fixture = Fixture::load(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/generated-locked.csv")).unwrap();
+    let lines = replay_csv_diagnostics(&fixture.events, &fixture.profile.timing().parse_options());
+    /* no expected state without the fixture header */
+    assert_eq!(lines, replay_diagnostics(&fixture, &fixture.profile.timing().parse_options()).into_iter()
+        .filter(|line| !line.starts_with("expected ")).collect::<Vec<String>>());
+}
+
+#[test]
+fn record_round_trip() {
+    /* an invalid pattern is captured anyway, so it can be recorded */
+    let mock = MockBackend::new();
+    mock.respond(Command::Check, fixtures::invalid_first());
+    let cff3000 = CFF3000::with_backend(mock).unwrap();
+    let capture = cff3000.capture(Duration::from_secs(5)).unwrap();
+    assert_eq!(capture.events.len(), 4);
+    assert_eq!(capture.lost_events, 0);
+
+    let start = capture.events.iter().map(|event| event.timestamp).min().unwrap();
+    let mut fixture = Fixture::from_capture("mock", "n/a", CFF3000State::Locked, &capture.events, start);
+    fixture.profile = DeviceProfile::Rev2;
+    let parsed = Fixture::parse(&fixture.to_string()).unwrap();
+    assert_eq!(parsed, fixture);
+    assert_eq!(parsed.events[0].timestamp, Duration::ZERO);
+}
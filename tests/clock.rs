@@ -0,0 +1,146 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Timing behavior on virtual time, see `testing::TestClock`.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use cff3000::testing::{self, generate, PatternParams, Replay};
+use cff3000::{Button, CFF3000State, LedEvent, LineRole, Notice, Polarities, Polarity, StopToken, Timings, WatchOptions};
+
+/// Virtual times (ms) at which `button` has been pressed.
+fn presses(replay: &Replay, button: Button) -> Vec<u128> {
+    replay.transitions().iter().filter(|t| t.button == button && t.pressed).map(|t| t.at.as_millis()).collect()
+}
+
+#[test]
+fn press_lasts_press_duration() {
+    let replay = Replay::new();
+    let cff3000 = testing::device(&replay).build().unwrap();
+    cff3000.lock().unwrap();
+
+    let transitions = replay.transitions();
+    assert_eq!(transitions.len(), 2);
+    assert!(transitions[0].button == Button::Lock && transitions[0].pressed);
+    assert!(transitions[1].button == Button::Lock && !transitions[1].pressed);
+    assert_eq!(transitions[1].at - transitions[0].at, Duration::from_millis(500));
+    assert_eq!(replay.elapsed(), Duration::from_millis(500));
+}
+
+#[test]
+fn dry_run_only_reports_presses() {
+    let replay = Replay::new();
+    let notices = Arc::new(Mutex::new(Vec::new()));
+    let log = notices.clone();
+    let cff3000 = testing::device(&replay)
+        .polarities(Polarities {button_lock: Polarity::ActiveLow, ..Polarities::default()})
+        .dry_run(true)
+        .monitor(move |notice| log.lock().unwrap().push(notice.clone()))
+        .build()
+        .unwrap();
+    assert!(cff3000.is_dry_run());
+    cff3000.lock().unwrap();
+    cff3000.check().unwrap();
+
+    assert_eq!(replay.transitions(), vec![]);
+    let write = |role, level, held: Option<u64>| Notice::DryRun {chipdev: None, role, offset: None, level, held: held.map(Duration::from_millis)};
+    assert_eq!(*notices.lock().unwrap(), vec![
+        /* releasing the active-low button on build() */
+        write(LineRole::ButtonLock, true, None),
+        write(LineRole::ButtonLock, false, None),
+        write(LineRole::ButtonLock, true, Some(500)),
+        write(LineRole::ButtonLock, false, None),
+        write(LineRole::ButtonUnlock, true, None),
+        write(LineRole::ButtonUnlock, false, Some(500)),
+        write(LineRole::ButtonLock, true, Some(500)),
+    ]);
+    assert_eq!(notices.lock().unwrap()[2].to_string(), "dry run: button lock high after 500 ms");
+}
+
+#[test]
+fn capture_window_cuts_off_late_events() {
+    let replay = Replay::new();
+    let mut events = generate(CFF3000State::Locked, PatternParams::default());
+    let in_window = events.len();
+    /* the capture ends 8 s after the release, 8.5 s after the press */
+    events.push(LedEvent {led: cff3000::Led::Red, on: true, timestamp: Duration::from_millis(8_600)});
+    replay.push_capture(events);
+
+    let report = testing::device(&replay).build().unwrap().state_report().unwrap();
+    assert_eq!(report.state, CFF3000State::Locked);
+    assert_eq!(report.events.len(), in_window);
+    assert_eq!(replay.elapsed(), Duration::from_millis(8_500));
+}
+
+#[test]
+fn huge_durations_do_not_overflow() {
+    let stop = StopToken::new();
+    stop.stop();
+    assert!(stop.wait_timeout(Duration::MAX));
+
+    /* the release is due at the end of the virtual time */
+    let replay = Replay::with_captures(&[Some(CFF3000State::Locked)]);
+    let timings = Timings {check_press: Some(Duration::MAX), ..Timings::default()};
+    let cff3000 = testing::device(&replay).timings(timings).build().unwrap();
+    assert_eq!(cff3000.state().unwrap(), CFF3000State::Locked);
+    assert!(replay.elapsed() > Duration::from_secs(365 * 86400));
+}
+
+#[test]
+fn stopping_ends_watch_early() {
+    let replay = Replay::with_captures(&[Some(CFF3000State::Unlocked)]);
+    let cff3000 = testing::device(&replay).build().unwrap();
+    let stop = StopToken::new();
+
+    let mut changes = Vec::new();
+    cff3000.watch(Duration::from_secs(60), &stop, |change| {
+        changes.push(change.current);
+        stop.stop();
+    }).unwrap();
+
+    /* no interval is waited for after the stop */
+    assert_eq!(changes, vec![CFF3000State::Unlocked]);
+    assert_eq!(replay.elapsed(), Duration::from_millis(8_500));
+}
+
+#[test]
+fn watch_gives_up_after_consecutive_errors() {
+    /* without captures, every query fails */
+    let replay = Replay::new();
+    let cff3000 = testing::device(&replay).build().unwrap();
+    let options = WatchOptions {max_consecutive_errors: 3, ..WatchOptions::new(Duration::from_secs(30))};
+
+    assert!(cff3000.watch_with_options(&options, &StopToken::new(), |_| panic!("no change expected")).is_err());
+    assert_eq!(presses(&replay, Button::Lock), vec![0, 30_000, 60_000]);
+    assert_eq!(replay.elapsed(), Duration::from_millis(68_500));
+}
+
+#[test]
+fn watch_is_rate_limited() {
+    let replay = Replay::with_captures(&[Some(CFF3000State::Locked); 3]);
+    let cff3000 = testing::device(&replay).build().unwrap();
+    let options = WatchOptions {
+        min_interval: Duration::from_secs(12),
+        max_consecutive_errors: 1,
+        ..WatchOptions::new(Duration::from_secs(1))
+    };
+
+    /* the fourth query fails for lack of a capture and ends the watch */
+    let mut changes = 0;
+    assert!(cff3000.watch_with_options(&options, &StopToken::new(), |_| changes += 1).is_err());
+    assert_eq!(changes, 1);
+    assert_eq!(presses(&replay, Button::Lock), vec![0, 12_000, 24_000, 36_000]);
+}
+
+#[test]
+fn watch_heartbeat_between_queries() {
+    let replay = Replay::with_captures(&[Some(CFF3000State::Locked); 2]);
+    let cff3000 = testing::device(&replay).build().unwrap();
+    let options = WatchOptions {max_consecutive_errors: 1, ..WatchOptions::new(Duration::from_secs(30)).heartbeat(Duration::from_secs(10))};
+
+    /* after every query, the failed third one included, and every 10 s in between */
+    let mut beats = Vec::new();
+    assert!(cff3000.watch_with_heartbeat(&options, &StopToken::new(), |_| {}, || beats.push(replay.elapsed().as_millis())).is_err());
+    assert_eq!(beats, vec![8_500, 18_500, 28_500, 38_500, 48_500, 58_500, 68_500]);
+}
@@ -0,0 +1,606 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! TOML configuration files and environment variables: round trips,
+//! rejected input, layering and how a configuration is applied to a
+//! device.
+
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use cff3000::config::{AuditConfig, AuditTarget, BatteryConfig, CFF3000Config, ConfigLayer, ConfigSource, ConfigSources, GroupConfig, HealthConfig, MqttConfig, MqttTlsConfig, RateLimitConfig, RetryConfig,
+                      ScheduleConfig, Severity, TimingConfig, WebhookConfig};
+use cff3000::mock::MockBackend;
+use cff3000::schedule::{ScheduleAction, TimeOfDay, Weekday};
+use cff3000::timings::DEFAULT_SCHEDULE_GRACE;
+use cff3000::{BusyPolicy, Button, CFF3000Builder, Command, DeviceProfile, LineRole, PinAssignment, Polarities, Polarity, TimingProfile};
+
+const MINIMAL: &str = r#"
+chip = "/dev/gpiochip2"
+
+[pins]
+led_red = 2
+led_green = 3
+button_unlock = 4
+button_lock = 5
+"#;
+
+fn full() -> CFF3000Config {
+    CFF3000Config {
+        polarities: Polarities {led_green: Polarity::ActiveLow, button_lock: Polarity::ActiveLow, ..Polarities::default()},
+        timings: TimingConfig {
+            press_ms: Some(800),
+            command_capture_ms: Some(12000),
+            unlock_press_ms: Some(1200),
+            lock_capture_ms: Some(6000),
+            merge_window_ms: Some(70),
+            ..TimingConfig::default()
+        },
+        retry: RetryConfig {auto_reopen_ms: Some(30000), max_consecutive_errors: Some(2)},
+        rate_limit: RateLimitConfig {min_interval_ms: Some(60000), jitter_ms: Some(5000)},
+        busy_policy: BusyPolicy::FailFast,
+        lockfile: Some(PathBuf::from("/run/lock/cff3000.lock")),
+        state_file: Some(PathBuf::from("/var/lib/cff3000/state.toml")),
+        ..CFF3000Config::new("/dev/gpiochip0", PinAssignment::from([17, 27, 22, 23]))
+    }
+}
+
+#[test]
+fn pin_arrays_follow_line_roles() {
+    let pins = PinAssignment {led_red: 2, led_green: 3, button_unlock: 4, button_lock: 5};
+    assert_eq!(pins.to_array(), [2, 3, 4, 5]);
+    assert_eq!(PinAssignment::from(pins.to_array()), pins);
+    assert_eq!(LineRole::ALL.iter().map(|&role| pins.get(role)).collect::<Vec<_>>(), vec![2, 3, 4, 5]);
+}
+
+#[test]
+fn minimal_config_uses_defaults() {
+    let config = CFF3000Config::from_toml_str(MINIMAL).unwrap();
+    assert_eq!(config, CFF3000Config::new("/dev/gpiochip2", PinAssignment::from([2, 3, 4, 5])));
+    assert_eq!(config.timings(), cff3000::Timings::default());
+    assert_eq!(config.parse_options(), None);
+
+    let watch = config.watch_options(Duration::from_secs(60));
+    let defaults = cff3000::WatchOptions::new(Duration::from_secs(60));
+    assert_eq!((watch.min_interval, watch.jitter, watch.max_consecutive_errors), (defaults.min_interval, defaults.jitter, defaults.max_consecutive_errors));
+}
+
+#[test]
+fn config_round_trips_through_toml() {
+    for config in &[full(), CFF3000Config::from_toml_str(MINIMAL).unwrap()] {
+        let text = config.to_toml_string().unwrap();
+        assert_eq!(&CFF3000Config::from_toml_str(&text).unwrap(), config, "{}", text);
+    }
+
+    /* defaults are left out */
+    let text = CFF3000Config::from_toml_str(MINIMAL).unwrap().to_toml_string().unwrap();
+    assert!(!text.contains("timings") && !text.contains("polarities") && !text.contains("busy_policy"), "{}", text);
+}
+
+#[test]
+fn config_values_are_applied() {
+    let config = full();
+    let timings = config.timings();
+    assert_eq!(timings.press, Duration::from_millis(800));
+    assert_eq!(timings.check_capture, cff3000::Timings::default().check_capture);
+    assert_eq!(timings.command_capture, Duration::from_secs(12));
+    assert_eq!(timings.press_for(Command::Unlock), Duration::from_millis(1200));
+    assert_eq!(timings.press_for(Command::Lock), Duration::from_millis(800));
+    assert_eq!(timings.capture_for(Command::Lock), Duration::from_secs(6));
+    assert_eq!(timings.capture_for(Command::Unlock), Duration::from_secs(12));
+    assert_eq!(config.parse_options().unwrap().merge_window, Duration::from_millis(70));
+
+    let watch = config.watch_options(Duration::from_secs(300));
+    assert_eq!(watch.min_interval, Duration::from_secs(60));
+    assert_eq!(watch.jitter, Duration::from_secs(5));
+    assert_eq!(watch.max_consecutive_errors, 2);
+}
+
+#[test]
+fn unknown_keys_are_rejected() {
+    let typos = [
+        (MINIMAL.replace("button_lock", "buton_lock"), "buton_lock"),
+        (format!("chips = 1\n{}", MINIMAL), "chips"),
+        (format!("{}\n[timings]\npress = 500\n", MINIMAL), "press"),
+        (format!("{}\n[polarities]\nled_red = \"low\"\n", MINIMAL), "low"),
+        (format!("{}\n[watch]\njitter_ms = 5\n", MINIMAL), "watch"),
+    ];
+    for &(ref text, key) in &typos {
+        let err = CFF3000Config::from_toml_str(text).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains(key), "{}", err);
+    }
+}
+
+#[test]
+fn missing_keys_are_rejected() {
+    let without_pin: String = MINIMAL.lines().filter(|line| !line.starts_with("led_green")).collect::<Vec<_>>().join("\n");
+    let err = CFF3000Config::from_toml_str(&without_pin).unwrap_err();
+    assert!(err.to_string().contains("led_green"), "{}", err);
+
+    let err = CFF3000Config::from_toml_str("[pins]\nled_red = 1\nled_green = 2\nbutton_unlock = 3\nbutton_lock = 4\n").unwrap_err();
+    assert!(err.to_string().contains("chip"), "{}", err);
+}
+
+#[test]
+fn file_errors_name_the_file() {
+    let path = std::env::temp_dir().join(format!("cff3000-config-{}.toml", std::process::id()));
+    std::fs::write(&path, MINIMAL.replace("led_red", "led-red")).unwrap();
+    let err = CFF3000Config::from_toml_file(&path).unwrap_err();
+    std::fs::write(&path, MINIMAL).unwrap();
+    let config = CFF3000Config::from_toml_file(&path);
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(err.to_string().starts_with(&path.display().to_string()), "{}", err);
+    assert_eq!(config.unwrap().pins.led_red, 2);
+    assert_eq!(CFF3000Config::from_toml_file(&path).unwrap_err().kind(), ErrorKind::NotFound);
+}
+
+#[test]
+fn active_low_buttons_are_driven_inverted() {
+    let mock = MockBackend::new();
+    let cff3000 = CFF3000Builder::with_backend(mock.clone())
+        .polarities(Polarities {button_lock: Polarity::ActiveLow, ..Polarities::default()})
+        .build()
+        .unwrap();
+
+    cff3000.lock().unwrap();
+    cff3000.unlock().unwrap();
+    let transitions: Vec<(Button, bool)> = mock.transitions().iter().map(|t| (t.button, t.pressed)).collect();
+    /* the mock records electrical levels, the lock line is released by build() */
+    assert_eq!(transitions, vec![
+        (Button::Lock, true),
+        (Button::Lock, false), (Button::Lock, true),
+        (Button::Unlock, true), (Button::Unlock, false),
+    ]);
+}
+
+fn vars(vars: &[(&str, &str)]) -> std::io::Result<ConfigLayer> {
+    ConfigLayer::from_vars(vars.iter().map(|&(name, value)| (name.to_string(), value.to_string())))
+}
+
+#[test]
+fn env_variables_are_parsed() {
+    let layer = vars(&[
+        ("CFF3000_CHIP", "/dev/gpiochip0"),
+        ("CFF3000_PINS", "17, 27,22 ,23"),
+        ("CFF3000_ACTIVE_LOW", "led_green,button_lock"),
+        ("CFF3000_PRESS_MS", "800"),
+        ("CFF3000_COMMAND_CAPTURE_MS", "12000"),
+        ("CFF3000_UNLOCK_PRESS_MS", "1200"),
+        ("CFF3000_LOCK_CAPTURE_MS", "6000"),
+        ("CFF3000_MERGE_WINDOW_MS", "70"),
+        ("CFF3000_AUTO_REOPEN_MS", "30000"),
+        ("CFF3000_MAX_CONSECUTIVE_ERRORS", "2"),
+        ("CFF3000_MIN_INTERVAL_MS", "60000"),
+        ("CFF3000_JITTER_MS", "5000"),
+        ("CFF3000_BUSY_POLICY", "fail-fast"),
+        ("CFF3000_LOCKFILE", "/run/lock/cff3000.lock"),
+        ("CFF3000_STATE_FILE", "/var/lib/cff3000/state.toml"),
+        /* not configuration, ignored */
+        ("CFF3000_AGENT_TOKEN", "secret"),
+        ("CFF3000_CHECK_CAPTURE_MS", ""),
+    ]).unwrap();
+    assert_eq!(layer.into_config().unwrap(), full());
+}
+
+#[test]
+fn malformed_pin_lists_are_rejected() {
+    for &value in &["2,3,4", "2,3,4,5,6", "2,3,x,5", "2,,4,5", "2;3;4;5", "-1,2,3,4", "2,3,4,5,"] {
+        let err = vars(&[("CFF3000_PINS", value)]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let message = err.to_string();
+        assert!(message.starts_with("CFF3000_PINS: ") && message.contains(&format!("{:?}", value)), "{}", message);
+    }
+}
+
+#[test]
+fn invalid_env_values_name_the_variable() {
+    let cases = [
+        ("CFF3000_PRESS_MS", "0.5"),
+        ("CFF3000_MAX_CONSECUTIVE_ERRORS", "-1"),
+        ("CFF3000_ACTIVE_LOW", "led_red,buton_lock"),
+        ("CFF3000_BUSY_POLICY", "failfast"),
+    ];
+    for &(name, value) in &cases {
+        let err = vars(&[("CFF3000_CHIP", "/dev/gpiochip0"), (name, value)]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let message = err.to_string();
+        assert!(message.starts_with(&format!("{}: invalid value {:?}", name, value)), "{}", message);
+    }
+}
+
+#[test]
+fn missing_required_variables_are_reported() {
+    let err = vars(&[("CFF3000_PINS", "2,3,4,5")]).unwrap().into_config().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("CFF3000_CHIP"), "{}", err);
+
+    let err = vars(&[("CFF3000_CHIP", "/dev/gpiochip0"), ("CFF3000_PINS", "")]).unwrap().into_config().unwrap_err();
+    assert!(err.to_string().contains("CFF3000_PINS"), "{}", err);
+}
+
+#[test]
+fn layers_take_precedence_in_order() {
+    let file = CFF3000Config::from_toml_str(&format!("{}\n[timings]\npress_ms = 600\nmerge_window_ms = 60\n", MINIMAL)).unwrap();
+    let env = vars(&[("CFF3000_PINS", "10,11,12,13"), ("CFF3000_PRESS_MS", "700"), ("CFF3000_BUSY_POLICY", "fail-fast")]).unwrap();
+    let overrides = ConfigLayer {
+        timings: TimingConfig {press_ms: Some(900), ..TimingConfig::default()},
+        ..ConfigLayer::default()
+    };
+
+    let config = CFF3000Config::merged(Some(file.clone()), env.clone(), overrides.clone()).unwrap();
+    assert_eq!(config.chip, "/dev/gpiochip2");
+    assert_eq!(config.pins, PinAssignment::from([10, 11, 12, 13]));
+    assert_eq!(config.timings.press_ms, Some(900));
+    assert_eq!(config.timings.merge_window_ms, Some(60));
+    assert_eq!(config.busy_policy, BusyPolicy::FailFast);
+
+    let config = CFF3000Config::merged(Some(file.clone()), env, ConfigLayer::default()).unwrap();
+    assert_eq!(config.timings.press_ms, Some(700));
+    assert_eq!(CFF3000Config::merged(Some(file.clone()), ConfigLayer::default(), ConfigLayer::default()).unwrap(), file);
+
+    /* without a file, the environment has to provide chip and pins */
+    assert!(CFF3000Config::merged(None, vars(&[("CFF3000_PINS", "1,2,3,4")]).unwrap(), overrides).is_err());
+}
+
+#[test]
+fn errors_name_line_and_key() {
+    let cases = [
+        (MINIMAL.replace("button_lock", "buton_lock"), "line 8: pins.buton_lock: unknown field `buton_lock`"),
+        (format!("chips = 1\n{}", MINIMAL), "line 1: chips: unknown field `chips`"),
+        (format!("{}\n[timings]\n# comment\npress_ms = \"x\"\n", MINIMAL), "line 12: timings.press_ms: invalid type: string \"x\""),
+        (format!("{}\n[polarities]\nled_red = \"low\"\n", MINIMAL), "line 11: polarities.led_red: unknown variant `low`"),
+        (MINIMAL.replace("led_green = 3\n", ""), "line 4: pins: missing field `led_green`"),
+        (format!("{}\n[timings\n", MINIMAL), "line 10: timings: invalid table header"),
+    ];
+    for &(ref text, expected) in &cases {
+        let err = CFF3000Config::from_toml_str(text).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with(expected), "{}", err);
+    }
+
+    /* a missing required key has no location */
+    let err = CFF3000Config::from_toml_str("[pins]\nled_red = 1\nled_green = 2\nbutton_unlock = 3\nbutton_lock = 4\n").unwrap_err();
+    assert_eq!(err.to_string(), "missing field `chip`");
+
+    let path = std::env::temp_dir().join(format!("cff3000-layer-{}.toml", std::process::id()));
+    std::fs::write(&path, "[timings]\npress_ms = -1\n").unwrap();
+    let err = ConfigLayer::from_toml_file(&path).unwrap_err();
+    std::fs::remove_file(&path).unwrap();
+    assert!(err.to_string().starts_with(&format!("{}:2: timings.press_ms: ", path.display())), "{}", err);
+}
+
+#[test]
+fn layers_read_partial_files() {
+    let layer = ConfigLayer::from_toml_str("[timings]\npress_ms = 600\n").unwrap();
+    assert_eq!(layer, ConfigLayer {timings: TimingConfig {press_ms: Some(600), ..TimingConfig::default()}, ..ConfigLayer::default()});
+    assert_eq!(ConfigLayer::from_toml_str(MINIMAL).unwrap().into_config().unwrap(), CFF3000Config::from_toml_str(MINIMAL).unwrap());
+    assert!(ConfigLayer::from_toml_str("[timings]\npress = 600\n").is_err());
+}
+
+#[test]
+fn sources_track_the_origin() {
+    let system = PathBuf::from("/etc/cff3000.toml");
+    let mut sources = ConfigSources::new();
+    sources.push(ConfigSource::File(system.clone()), ConfigLayer::from_toml_str(&format!("{}\n[polarities]\nled_red = \"active-low\"\n", MINIMAL)).unwrap());
+    sources.push(ConfigSource::Env, vars(&[("CFF3000_PRESS_MS", "700"), ("CFF3000_ACTIVE_LOW", "led_green")]).unwrap());
+    sources.push(ConfigSource::CommandLine, ConfigLayer {chip: Some("/dev/gpiochip5".to_string()), ..ConfigLayer::default()});
+    assert!(!sources.push_file_if_exists("/nonexistent/cff3000.toml").unwrap());
+
+    let config = sources.config().unwrap();
+    assert_eq!(config.chip, "/dev/gpiochip5");
+    assert_eq!(config.timings.press_ms, Some(700));
+    assert_eq!(config.polarities, Polarities {led_green: Polarity::ActiveLow, ..Polarities::default()});
+
+    let entries = sources.entries().unwrap();
+    let entry = |key: &str| {
+        let entry = entries.iter().find(|entry| entry.key == key).unwrap_or_else(|| panic!("{} missing", key));
+        (entry.value.as_str(), entry.source.clone())
+    };
+    assert_eq!(entry("chip"), ("\"/dev/gpiochip5\"", ConfigSource::CommandLine));
+    assert_eq!(entry("pins.led_red"), ("2", ConfigSource::File(system)));
+    assert_eq!(entry("timings.press_ms"), ("700", ConfigSource::Env));
+    /* polarities are replaced as a whole */
+    assert_eq!(entry("polarities.led_red"), ("\"active-high\"", ConfigSource::Env));
+    assert_eq!(entry("timings.check_capture_ms"), ("8000", ConfigSource::Default));
+    assert_eq!(entry("profile"), ("\"classic\"", ConfigSource::Default));
+    assert!(entries.windows(2).all(|pair| pair[0].key < pair[1].key));
+
+    /* defaults follow the configured profile, chip and pins are required */
+    let mut sources = ConfigSources::new();
+    sources.push(ConfigSource::Env, vars(&[("CFF3000_PROFILE", "rev2")]).unwrap());
+    let entries = sources.entries().unwrap();
+    assert!(entries.iter().any(|entry| entry.key == "timings.check_capture_ms" && entry.value == "9000"));
+    assert!(!entries.iter().any(|entry| entry.key == "chip"));
+    assert_eq!(sources.config().unwrap_err().kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn profile_provides_defaults() {
+    let config = CFF3000Config::from_toml_str(&format!("profile = \"rev2\"\n{}\n[timings]\npress_ms = 600\n", MINIMAL)).unwrap();
+    assert_eq!(config.profile, DeviceProfile::Rev2);
+    let timings = config.timings();
+    assert_eq!(timings.press, Duration::from_millis(600));
+    assert_eq!(timings.command_capture, TimingProfile::REV2.timings.command_capture);
+    let text = config.to_toml_string().unwrap();
+    assert_eq!(CFF3000Config::from_toml_str(&text).unwrap(), config, "{}", text);
+
+    assert_eq!(vars(&[("CFF3000_PROFILE", "rev2")]).unwrap().profile, Some(DeviceProfile::Rev2));
+    let err = vars(&[("CFF3000_PROFILE", "rev3")]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(CFF3000Config::from_toml_str(&format!("profile = \"custom\"\n{}", MINIMAL)).is_err());
+
+    /* the blink period of the profile limits the merge window */
+    let mut config = CFF3000Config::from_toml_str(MINIMAL).unwrap();
+    config.timings.merge_window_ms = Some(300);
+    assert!(config.validate_offline().is_empty());
+    config.profile = DeviceProfile::Rev2;
+    assert_eq!(issues(&config), vec![("timings.merge_window_ms".to_string(), Severity::Error)]);
+}
+
+fn issues(config: &CFF3000Config) -> Vec<(String, Severity)> {
+    config.validate_offline().into_iter().map(|issue| (issue.field, issue.severity)).collect()
+}
+
+#[test]
+fn valid_configs_have_no_issues() {
+    assert!(full().validate_offline().is_empty());
+    assert!(CFF3000Config::from_toml_str(MINIMAL).unwrap().validate_offline().is_empty());
+}
+
+#[test]
+fn validation_reports_every_issue() {
+    let mut config = CFF3000Config::new("/dev/gpiochip0", PinAssignment::from([2, 3, 2, 3]));
+    config.timings = TimingConfig {
+        press_ms: Some(0),
+        check_capture_ms: Some(3000),
+        unlock_press_ms: Some(50),
+        merge_window_ms: Some(500),
+        ..TimingConfig::default()
+    };
+    config.retry.auto_reopen_ms = Some(0);
+
+    assert_eq!(issues(&config), vec![
+        ("pins.button_unlock".to_string(), Severity::Error),
+        ("pins.button_lock".to_string(), Severity::Error),
+        ("timings.press_ms".to_string(), Severity::Error),
+        ("timings.unlock_press_ms".to_string(), Severity::Warning),
+        ("timings.check_capture_ms".to_string(), Severity::Warning),
+        ("timings.merge_window_ms".to_string(), Severity::Error),
+        ("retry.auto_reopen_ms".to_string(), Severity::Warning),
+    ]);
+    let messages: Vec<String> = config.validate_offline().iter().map(|issue| issue.to_string()).collect();
+    assert_eq!(messages[0], "error: pins.button_unlock: line 2 is already used for pins.led_red");
+}
+
+#[test]
+fn merge_window_includes_poll_period() {
+    let mut config = CFF3000Config::from_toml_str(MINIMAL).unwrap();
+    config.timings.poll_period_ms = Some(400);
+    assert_eq!(issues(&config), vec![]);
+    config.timings.poll_period_ms = Some(450);
+    assert_eq!(issues(&config), vec![("timings.poll_period_ms".to_string(), Severity::Error)]);
+}
+
+#[test]
+fn validation_checks_the_chip() {
+    let mut config = CFF3000Config::new("/dev/cff3000-missing-chip", PinAssignment::from([2, 3, 4, 5]));
+    config.lockfile = Some(PathBuf::from("/cff3000-missing-dir/cff3000.lock"));
+    config.state_file = Some(PathBuf::from("/cff3000-missing-dir/state.toml"));
+    let fields: Vec<String> = config.validate().into_iter().filter(|issue| issue.severity == Severity::Error).map(|issue| issue.field).collect();
+    assert_eq!(fields, vec!["lockfile".to_string(), "state_file".to_string(), "chip".to_string()]);
+}
+
+const MQTT: &str = r#"
+[mqtt]
+host = "mqtt.example.org"
+client_id = "frontdoor"
+username = "cff3000"
+password = "secret"
+keep_alive_ms = 60000
+change_topic = "cff3000/frontdoor/change"
+
+[mqtt.tls]
+ca_cert = "/etc/cff3000/ca.pem"
+"#;
+
+#[test]
+fn mqtt_section_is_parsed() {
+    let config = CFF3000Config::from_toml_str(&format!("{}{}", MINIMAL, MQTT)).unwrap();
+    let mqtt = config.mqtt.clone().unwrap();
+    assert_eq!(mqtt, MqttConfig {
+        client_id: Some("frontdoor".to_string()),
+        username: Some("cff3000".to_string()),
+        password: Some("secret".to_string()),
+        keep_alive_ms: Some(60000),
+        change_topic: Some("cff3000/frontdoor/change".to_string()),
+        tls: Some(MqttTlsConfig {ca_cert: Some(PathBuf::from("/etc/cff3000/ca.pem")), ..MqttTlsConfig::default()}),
+        ..MqttConfig::new("mqtt.example.org")
+    });
+    assert!(config.validate_offline().is_empty());
+    assert_eq!(CFF3000Config::from_toml_str(&config.to_toml_string().unwrap()).unwrap(), config);
+
+    /* the password is not shown with the other entries */
+    let mut sources = ConfigSources::new();
+    sources.push(ConfigSource::Env, ConfigLayer::from(config));
+    let entries = sources.entries().unwrap();
+    let value = |key: &str| entries.iter().find(|entry| entry.key == key).map(|entry| entry.value.clone());
+    assert_eq!(value("mqtt.host").unwrap(), "\"mqtt.example.org\"");
+    assert_eq!(value("mqtt.password").unwrap(), "\"<redacted>\"");
+
+    let err = CFF3000Config::from_toml_str(&format!("{}\n[mqtt]\nhost = \"x\"\nqos = 2\n", MINIMAL)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn mqtt_validation() {
+    let mut config = CFF3000Config::from_toml_str(MINIMAL).unwrap();
+    config.mqtt = Some(MqttConfig {
+        password: Some("secret".to_string()),
+        keep_alive_ms: Some(1500),
+        tls: Some(MqttTlsConfig {client_cert: Some(PathBuf::from("client.pem")), ..MqttTlsConfig::default()}),
+        ..MqttConfig::new("")
+    });
+    assert_eq!(issues(&config), vec![
+        ("mqtt.host".to_string(), Severity::Error),
+        ("mqtt.password".to_string(), Severity::Error),
+        ("mqtt.keep_alive_ms".to_string(), Severity::Error),
+        ("mqtt.tls.ca_cert".to_string(), Severity::Error),
+        ("mqtt.tls.client_key".to_string(), Severity::Error),
+    ]);
+
+    config.mqtt = Some(MqttConfig {
+        tls: Some(MqttTlsConfig {insecure_skip_verify: true, ..MqttTlsConfig::default()}),
+        ..MqttConfig::new("broker")
+    });
+    assert_eq!(issues(&config), vec![("mqtt.tls.insecure_skip_verify".to_string(), Severity::Warning)]);
+}
+
+#[cfg(feature = "mqtt")]
+#[test]
+fn mqtt_options_follow_the_config() {
+    let config = CFF3000Config::from_toml_str(&format!("{}{}", MINIMAL, MQTT)).unwrap();
+    let options = config.mqtt.unwrap().options();
+    assert_eq!(options.host, "mqtt.example.org");
+    assert_eq!(options.port, cff3000::mqtt::DEFAULT_TLS_PORT);
+    assert_eq!(options.client_id, "frontdoor");
+    assert_eq!(options.credentials, Some(("cff3000".to_string(), "secret".to_string())));
+    assert_eq!(options.keep_alive, Duration::from_secs(60));
+    assert_eq!(options.change_topic, Some("cff3000/frontdoor/change".to_string()));
+    assert_eq!(options.tls.unwrap().ca_cert, Some(PathBuf::from("/etc/cff3000/ca.pem")));
+
+    let options = MqttConfig::new("broker").options();
+    assert_eq!(options, cff3000::mqtt::MqttOptions::new("broker"));
+}
+
+#[test]
+fn webhook_section_is_parsed() {
+    let text = format!("{}{}", MINIMAL, r#"
+[webhook]
+url = "https://ntfy.example.org/frontdoor"
+token = "secret"
+retries = 2
+
+[webhook.headers]
+Title = "Front door"
+"#);
+    let config = CFF3000Config::from_toml_str(&text).unwrap();
+    let webhook = config.webhook.clone().unwrap();
+    assert_eq!(webhook.headers.get("Title").map(String::as_str), Some("Front door"));
+    assert_eq!(webhook, WebhookConfig {token: Some("secret".to_string()), retries: Some(2), headers: webhook.headers.clone(),
+                                       ..WebhookConfig::new("https://ntfy.example.org/frontdoor")});
+    assert!(config.validate_offline().is_empty());
+    assert_eq!(CFF3000Config::from_toml_str(&config.to_toml_string().unwrap()).unwrap(), config);
+
+    let mut sources = ConfigSources::new();
+    sources.push(ConfigSource::Env, ConfigLayer::from(config.clone()));
+    let entries = sources.entries().unwrap();
+    assert_eq!(entries.iter().find(|entry| entry.key == "webhook.token").unwrap().value, "\"<redacted>\"");
+
+    #[cfg(feature = "webhook")]
+    {
+        let options = webhook.options();
+        assert_eq!(options.retries, 2);
+        assert_eq!(options.headers, vec![("Title".to_string(), "Front door".to_string())]);
+        assert_eq!(options.token, Some("secret".to_string()));
+    }
+
+    let mut config = config;
+    config.webhook = Some(WebhookConfig {token: Some("secret".to_string()), ..WebhookConfig::new("http://hooks.local/door")});
+    assert_eq!(issues(&config), vec![("webhook.token".to_string(), Severity::Warning)]);
+    config.webhook = Some(WebhookConfig {timeout_ms: Some(0), ..WebhookConfig::new("ftp://hooks.local/door")});
+    assert_eq!(issues(&config), vec![("webhook.url".to_string(), Severity::Error), ("webhook.timeout_ms".to_string(), Severity::Error)]);
+}
+
+#[test]
+fn audit_section_is_parsed() {
+    let config = CFF3000Config::from_toml_str(&format!("{}\n[audit]\ntarget = \"syslog\"\n", MINIMAL)).unwrap();
+    assert_eq!(config.audit, Some(AuditConfig::new(AuditTarget::Syslog)));
+    assert!(config.validate_offline().is_empty());
+    assert_eq!(CFF3000Config::from_toml_str(&config.to_toml_string().unwrap()).unwrap(), config);
+
+    let text = format!("{}\n[audit]\ntarget = \"journal\"\nsocket = \"/tmp/journal\"\n", MINIMAL);
+    let audit = CFF3000Config::from_toml_str(&text).unwrap().audit.unwrap();
+    assert_eq!(audit, AuditConfig {socket: Some(PathBuf::from("/tmp/journal")), ..AuditConfig::new(AuditTarget::Journal)});
+    assert!(CFF3000Config::from_toml_str(&format!("{}\n[audit]\ntarget = \"file\"\n", MINIMAL)).is_err());
+}
+
+#[test]
+fn battery_section_is_parsed() {
+    let config = CFF3000Config::from_toml_str(&format!("{}\n[battery]\nbudget = 8000\n", MINIMAL)).unwrap();
+    assert_eq!(config.battery, BatteryConfig {budget: Some(8000), warn_below_percent: None});
+    let options = config.battery.options();
+    assert_eq!((options.budget, options.warn_below_percent), (8000, cff3000::battery::DEFAULT_WARN_BELOW_PERCENT));
+    assert!(config.validate_offline().is_empty());
+    assert_eq!(CFF3000Config::from_toml_str(&config.to_toml_string().unwrap()).unwrap(), config);
+    assert!(!CFF3000Config::from_toml_str(MINIMAL).unwrap().to_toml_string().unwrap().contains("[battery]"));
+
+    let mut config = config;
+    config.battery = BatteryConfig {budget: Some(0), warn_below_percent: Some(120)};
+    assert_eq!(issues(&config), vec![("battery.budget".to_string(), Severity::Error), ("battery.warn_below_percent".to_string(), Severity::Error)]);
+    assert!(CFF3000Config::from_toml_str(&format!("{}\n[battery]\nwarn_below = 10\n", MINIMAL)).is_err());
+}
+
+#[test]
+fn health_section_is_parsed() {
+    let config = CFF3000Config::from_toml_str(&format!("{}\n[health]\n", MINIMAL)).unwrap();
+    assert_eq!(config.health, Some(HealthConfig::default()));
+    assert_eq!(config.health.unwrap().options(), cff3000::health::HealthOptions::default());
+    assert_eq!(CFF3000Config::from_toml_str(&config.to_toml_string().unwrap()).unwrap(), config);
+
+    let config = CFF3000Config::from_toml_str(&format!("{}\n[health]\ninterval_ms = 30000\nreopen = true\n", MINIMAL)).unwrap();
+    let options = config.health.unwrap().options();
+    assert_eq!((options.interval, options.stuck_after, options.reopen), (Duration::from_secs(30), cff3000::health::DEFAULT_STUCK_AFTER, true));
+    assert_eq!(issues(&config), vec![("health.reopen".to_string(), Severity::Warning)]);
+
+    let mut config = config;
+    config.retry.auto_reopen_ms = Some(30000);
+    config.health = Some(HealthConfig {interval_ms: Some(0), stuck_after_ms: Some(0), reopen: Some(true)});
+    assert_eq!(issues(&config), vec![("health.interval_ms".to_string(), Severity::Error), ("health.stuck_after_ms".to_string(), Severity::Error)]);
+    assert!(CFF3000Config::from_toml_str(&format!("{}\n[health]\nintervall_ms = 10\n", MINIMAL)).is_err());
+}
+
+#[test]
+fn schedule_sections_are_parsed() {
+    let text = format!("{}\n[[schedule]]\nat = \"22:30\"\naction = \"ensure_locked\"\ndays = [\"fri\", \"sat\"]\n\n[[schedule]]\nat = \"7:00\"\naction = \"check\"\ngrace_ms = 60000\n", MINIMAL);
+    let config = CFF3000Config::from_toml_str(&text).unwrap();
+    let night = ScheduleConfig {days: vec![Weekday::Fri, Weekday::Sat], ..ScheduleConfig::new(TimeOfDay::new(22, 30).unwrap(), ScheduleAction::EnsureLocked)};
+    let morning = ScheduleConfig {grace_ms: Some(60000), ..ScheduleConfig::new(TimeOfDay::new(7, 0).unwrap(), ScheduleAction::Check)};
+    assert_eq!(config.schedule, vec![night, morning]);
+    assert_eq!(config.schedule[0].entry().grace, DEFAULT_SCHEDULE_GRACE);
+    assert_eq!(config.schedule[1].entry().grace, Duration::from_secs(60));
+    assert_eq!(config.schedule[1].entry().days, vec![]);
+    assert_eq!(CFF3000Config::from_toml_str(&config.to_toml_string().unwrap()).unwrap(), config);
+
+    let err = CFF3000Config::from_toml_str(&format!("{}\n[[schedule]]\nat = \"25:00\"\naction = \"check\"\n", MINIMAL)).unwrap_err();
+    assert!(err.to_string().contains("schedule.at: 25:00 is no time of the day"), "{}", err);
+    assert!(CFF3000Config::from_toml_str(&format!("{}\n[[schedule]]\nat = \"8:00\"\naction = \"unlock\"\n", MINIMAL)).is_err());
+    assert!(CFF3000Config::from_toml_str(&format!("{}\n[[schedule]]\nat = \"8:00\"\naction = \"check\"\ndays = [\"monday\"]\n", MINIMAL)).is_err());
+}
+
+#[test]
+fn group_configs_are_checked() {
+    let text = format!("spacing_ms = 250\n\n[devices.frontdoor]\n{}\n[devices.garage]\n{}", MINIMAL.replace("[pins]", "[devices.frontdoor.pins]"),
+        MINIMAL.replace("[pins]", "[devices.garage.pins]").replace("led_red = 2", "led_red = 6").replace("led_green = 3", "led_green = 7"));
+    let mut config = GroupConfig::from_toml_str(&text).unwrap();
+    assert_eq!(config.options().spacing, Duration::from_millis(250));
+    assert_eq!(GroupConfig::from_toml_str(&config.to_toml_string().unwrap()).unwrap(), config);
+    /* the buttons are shared */
+    let fields: Vec<(String, Severity)> = config.validate_offline().into_iter().map(|issue| (issue.field, issue.severity)).collect();
+    assert_eq!(fields, vec![
+        ("devices.garage.pins.button_unlock".to_string(), Severity::Error),
+        ("devices.garage.pins.button_lock".to_string(), Severity::Error),
+    ]);
+    assert!(config.validate_offline()[0].message.contains("devices.frontdoor.pins.button_unlock"), "{}", config.validate_offline()[0]);
+
+    let mut garage = config.devices.remove("garage").unwrap();
+    garage.chip = "/dev/gpiochip3".to_string();
+    config.devices.insert("garage".to_string(), garage.clone());
+    assert!(config.validate_offline().is_empty());
+    config.devices.insert("back door".to_string(), garage);
+    assert!(config.validate_offline().iter().any(|issue| issue.field == "devices.back door"));
+    assert!(GroupConfig::from_toml_str("spacing_ms = 250\ndevice = {}").is_err());
+    assert_eq!(GroupConfig::default().validate_offline()[0].field, "devices");
+}
@@ -0,0 +1,154 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! `daemon::CFF3000Daemon` with the Unix socket and HTTP servers on the
+//! replay backend.
+
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+use cff3000::config::CFF3000Config;
+use cff3000::daemon::{CFF3000Daemon, DaemonConfig};
+use cff3000::http::HttpOptions;
+use cff3000::persist::{LastState, Snapshot, StateFile};
+use cff3000::socket::{SocketOptions, UnixClient};
+use cff3000::testing::{self, Replay, TestClock};
+use cff3000::{CFF3000State, Clock, LockControl, StopToken};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const MINIMAL: &str = r#"
+chip = "/dev/gpiochip2"
+
+[pins]
+led_red = 2
+led_green = 3
+button_unlock = 4
+button_lock = 5
+"#;
+
+/// Virtual time of the replay, except that the watch loop really waits
+/// for the next query, so it stays there until stopped.
+struct WaitingClock(TestClock);
+
+impl Clock for WaitingClock {
+    fn now(&self) -> Instant {
+        self.0.now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.0.sleep(duration)
+    }
+
+    fn sleep_or_stop(&self, duration: Duration, stop: &StopToken) -> bool {
+        if duration >= Duration::from_secs(60) {
+            stop.wait_timeout(duration)
+        } else {
+            self.0.sleep_or_stop(duration, stop)
+        }
+    }
+}
+
+fn daemon(captures: &[Option<CFF3000State>], configure: fn(&mut DaemonConfig)) -> CFF3000Daemon {
+    let replay = Replay::with_captures(captures);
+    let device = testing::device(&replay).clock(WaitingClock(replay.clock())).build().unwrap();
+    let mut config = DaemonConfig::new(CFF3000Config::from_toml_str(MINIMAL).unwrap());
+    config.signals = false;
+    configure(&mut config);
+    CFF3000Daemon::new(device, config)
+}
+
+fn socket_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("cff3000-daemon-{}-{}.sock", std::process::id(), name))
+}
+
+#[test]
+fn serves_until_stopped() {
+    let path = socket_path("serve");
+    let daemon = daemon(&[Some(CFF3000State::Locked), Some(CFF3000State::Locked)], |config| {
+        config.http = Some(("127.0.0.1:0".to_string(), HttpOptions::default()));
+        config.socket = Some((socket_path("serve"), SocketOptions::default()));
+    });
+    let stop = daemon.stop_token();
+    let running = std::thread::spawn(move || daemon.run());
+
+    let deadline = Instant::now() + TIMEOUT;
+    while !path.exists() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    let client = UnixClient::connect(&path).unwrap();
+    assert_eq!(client.lock_and_verify().unwrap(), CFF3000State::Locked);
+
+    stop.stop();
+    running.join().unwrap().unwrap();
+    assert!(!path.exists());
+    assert!(UnixClient::connect(&path).is_err());
+}
+
+#[test]
+fn watch_errors_end_the_daemon() {
+    let path = socket_path("errors");
+    let daemon = daemon(&[None], |config| {
+        config.watch.max_consecutive_errors = 1;
+        config.socket = Some((socket_path("errors"), SocketOptions::default()));
+    });
+
+    let err = daemon.run().unwrap_err();
+    assert_eq!(err.to_string(), "did not receive enough LED change events");
+    assert!(!path.exists());
+}
+
+#[test]
+fn failing_integrations_end_the_daemon() {
+    let daemon = daemon(&[], |config| {
+        config.http = Some(("127.0.0.1:0".to_string(), HttpOptions::default()));
+        config.socket = Some((std::env::temp_dir().join("cff3000-missing").join("daemon.sock"), SocketOptions::default()));
+    });
+
+    assert_eq!(daemon.run().unwrap_err().kind(), ErrorKind::NotFound);
+}
+
+fn state_file() -> StateFile {
+    StateFile::new(std::env::temp_dir().join(format!("cff3000-daemon-{}-state.toml", std::process::id())))
+}
+
+#[test]
+fn state_file_is_kept_across_runs() {
+    let file = state_file();
+    let mut stored = Snapshot {last: Some(LastState {state: CFF3000State::Locked, timestamp: SystemTime::now() - Duration::from_secs(60)}), ..Snapshot::default()};
+    stored.counters.commands.insert("check".to_string(), 7);
+    file.store(&stored).unwrap();
+    let stored = file.load().unwrap();
+
+    /* nothing verified, the loaded snapshot is written back with the press counted */
+    let failing = daemon(&[None], |config| {
+        config.watch.max_consecutive_errors = 1;
+        config.state_file = Some(state_file().path().to_path_buf());
+    });
+    assert!(failing.run().is_err());
+    let written = file.load().unwrap();
+    assert_eq!((written.last, &written.counters), (stored.last, &stored.counters));
+    let battery = written.battery.unwrap();
+    assert_eq!((battery.replaced, battery.commands), (false, 1));
+
+    let watching = daemon(&[Some(CFF3000State::Unlocked)], |config| config.state_file = Some(state_file().path().to_path_buf()));
+    let stop = watching.stop_token();
+    let running = std::thread::spawn(move || watching.run());
+    let deadline = Instant::now() + TIMEOUT;
+    while file.load().and_then(|snapshot| snapshot.last) == stored.last && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    stop.stop();
+    running.join().unwrap().unwrap();
+
+    let snapshot = file.load().unwrap();
+    let last = snapshot.last.unwrap();
+    assert_eq!(last.state, CFF3000State::Unlocked);
+    assert!(last.age().unwrap() < Duration::from_secs(60));
+    assert_eq!(snapshot.counters, stored.counters);
+    let restored = snapshot.battery.unwrap();
+    assert_eq!(restored.since, battery.since);
+    assert!(restored.commands >= 2);
+    std::fs::remove_file(file.path()).unwrap();
+}
@@ -0,0 +1,101 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! `DbusService` over a peer-to-peer connection.
+
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use cff3000::dbus::{DbusService, ERROR_NOT_RESPONDING, ERROR_OUT_OF_RANGE, INTERFACE, INTERFACE_XML, OBJECT_PATH};
+use cff3000::testing::{self, Replay};
+use cff3000::{CFF3000State, CommandQueue};
+use zbus::blocking::connection::Builder;
+use zbus::blocking::{Connection, MessageIterator};
+use zbus::message::Type as MessageType;
+use zbus::names::BusName;
+use zbus::zvariant::OwnedValue;
+use zbus::Guid;
+
+/// Service on the device replaying `captures` and a client connected
+/// to it.
+fn service(captures: &[Option<CFF3000State>]) -> (DbusService, Connection, CommandQueue, Replay) {
+    let replay = Replay::with_captures(captures);
+    let device = testing::device(&replay).build().unwrap();
+    let queue = CommandQueue::new(device).unwrap();
+
+    let (server, client) = UnixStream::pair().unwrap();
+    let server = std::thread::spawn(move || Builder::unix_stream(server).server(Guid::generate()).unwrap().p2p().build().unwrap());
+    let client = Builder::unix_stream(client).p2p().build().unwrap();
+    let service = DbusService::new(server.join().unwrap(), queue.sender()).unwrap();
+    (service, client, queue, replay)
+}
+
+fn call(client: &Connection, interface: &str, method: &str) -> zbus::Result<zbus::Message> {
+    client.call_method(None::<BusName>, OBJECT_PATH, Some(interface), method, &())
+}
+
+fn error_name(result: zbus::Result<zbus::Message>) -> String {
+    match result {
+        Err(zbus::Error::MethodError(name, _, _)) => name.to_string(),
+        other => panic!("expected a method error, got {:?}", other),
+    }
+}
+
+fn state_property(client: &Connection) -> String {
+    let reply = client.call_method(None::<BusName>, OBJECT_PATH, Some("org.freedesktop.DBus.Properties"), "Get", &(INTERFACE, "State")).unwrap();
+    let value: OwnedValue = reply.body().deserialize().unwrap();
+    std::convert::TryFrom::try_from(value).unwrap()
+}
+
+#[test]
+fn lock_announces_the_state() {
+    let (service, client, _queue, _replay) = service(&[Some(CFF3000State::Locked)]);
+    let signals = MessageIterator::from(&client);
+    assert_eq!(state_property(&client), "unknown");
+
+    call(&client, INTERFACE, "Lock").unwrap();
+    assert_eq!(service.state(), Some(CFF3000State::Locked));
+    assert_eq!(state_property(&client), "locked");
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for message in signals.flatten() {
+            let header = message.header();
+            if message.message_type() == MessageType::Signal && header.member().map(|m| m.as_str()) == Some("StateChanged") {
+                let _ = tx.send(message.body().deserialize::<String>().unwrap());
+                return;
+            }
+        }
+    });
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), "locked");
+}
+
+#[test]
+fn check_returns_the_state() {
+    let (_service, client, _queue, _replay) = service(&[Some(CFF3000State::Manual)]);
+    let reply = call(&client, INTERFACE, "Check").unwrap();
+    assert_eq!(reply.body().deserialize::<String>().unwrap(), "manual");
+}
+
+#[test]
+fn failures_have_distinct_error_names() {
+    let (_service, client, _queue, _replay) = service(&[Some(CFF3000State::OutOfRange)]);
+    assert_eq!(error_name(call(&client, INTERFACE, "Check")), ERROR_OUT_OF_RANGE);
+    /* no capture left, the remote shows nothing */
+    assert_eq!(error_name(call(&client, INTERFACE, "Unlock")), ERROR_NOT_RESPONDING);
+    assert_eq!(error_name(call(&client, INTERFACE, "Open")), "org.freedesktop.DBus.Error.UnknownMethod");
+}
+
+#[test]
+fn introspection_describes_the_interface() {
+    let (_service, client, _queue, _replay) = service(&[]);
+    let reply = call(&client, "org.freedesktop.DBus.Introspectable", "Introspect").unwrap();
+    let xml: String = reply.body().deserialize().unwrap();
+    for name in INTERFACE_XML.split("name=\"").skip(1).map(|rest| &rest[..rest.find('"').unwrap()]) {
+        assert!(xml.contains(&format!("name=\"{}\"", name)), "{} missing in {}", name, xml);
+    }
+    assert!(xml.contains("org.freedesktop.DBus.Properties"));
+
+    let reply = client.call_method(None::<BusName>, "/org/cff3000", Some("org.freedesktop.DBus.Introspectable"), "Introspect", &()).unwrap();
+    assert!(reply.body().deserialize::<String>().unwrap().contains("<node name=\"Lock1\"/>"));
+}
@@ -0,0 +1,76 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! `CFF3000Builder::watch_device_node`: removals of a temporary device
+//! node are reported as `Notice::DeviceLost`.
+
+#![cfg(target_os = "linux")]
+
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use cff3000::mock::MockBackend;
+use cff3000::{CFF3000, CFF3000Builder, Notice};
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Empty directory holding only the node, so other tests cannot
+/// trigger the watch.
+fn node(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cff3000-devwatch-{}-{}", std::process::id(), name));
+    std::fs::create_dir_all(&dir).unwrap();
+    let node = dir.join("gpiochip0");
+    std::fs::write(&node, b"").unwrap();
+    node
+}
+
+/// Device watching `node`, with the notices it reports.
+fn watch(node: &Path) -> (CFF3000, Receiver<Notice>) {
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    let cff3000 = CFF3000Builder::with_backend(MockBackend::new())
+        .watch_device_node(node)
+        .monitor(move |notice| { let _ = tx.lock().unwrap().send(notice.clone()); })
+        .build()
+        .unwrap();
+    (cff3000, rx)
+}
+
+fn lost(node: &Path) -> Notice {
+    Notice::DeviceLost {chipdev: node.to_string_lossy().into_owned()}
+}
+
+#[test]
+fn removal_is_reported() {
+    let node = node("removed");
+    let (_cff3000, rx) = watch(&node);
+
+    std::fs::remove_file(&node).unwrap();
+    assert_eq!(rx.recv_timeout(TIMEOUT), Ok(lost(&node)));
+    std::fs::remove_dir(node.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn moving_away_is_reported_again() {
+    let node = node("moved");
+    let dir = node.parent().unwrap().to_path_buf();
+    let (_cff3000, rx) = watch(&node);
+
+    /* other entries of the directory are ignored */
+    let other = dir.join("gpiochip1");
+    std::fs::write(&other, b"").unwrap();
+    std::fs::remove_file(&other).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_millis(300)), Err(RecvTimeoutError::Timeout));
+
+    std::fs::rename(&node, &other).unwrap();
+    assert_eq!(rx.recv_timeout(TIMEOUT), Ok(lost(&node)));
+
+    /* the node comes back and disappears again */
+    std::fs::rename(&other, &node).unwrap();
+    std::fs::remove_file(&node).unwrap();
+    assert_eq!(rx.recv_timeout(TIMEOUT), Ok(lost(&node)));
+    assert_eq!(rx.recv_timeout(Duration::from_millis(300)), Err(RecvTimeoutError::Timeout));
+    std::fs::remove_dir(&dir).unwrap();
+}
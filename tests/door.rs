@@ -0,0 +1,172 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! `door` sensors on the replay backend.
+
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use cff3000::door::{CompositeChange, CompositeStatus, CompositeTrigger, DoorInput, DoorOpen, DoorSensorOptions, OpenDoorPolicy};
+use cff3000::testing::{self, Replay, TestClock};
+use cff3000::{CFF3000, CFF3000State, Clock, Notice, Polarity, StopToken, Trigger, WatchOptions};
+
+/// Reed switch high while the door is closed.
+#[derive(Clone)]
+struct Reed(Arc<AtomicBool>);
+
+impl Reed {
+    fn new(closed: bool) -> Reed {
+        Reed(Arc::new(AtomicBool::new(closed)))
+    }
+
+    fn set(&self, closed: bool) {
+        self.0.store(closed, Ordering::SeqCst);
+    }
+}
+
+impl DoorInput for Reed {
+    fn level(&self) -> std::io::Result<bool> {
+        Ok(self.0.load(Ordering::SeqCst))
+    }
+}
+
+/// Line returning `levels` one after another, then the last one.
+struct Bouncing(Mutex<VecDeque<bool>>);
+
+impl DoorInput for Bouncing {
+    fn level(&self) -> std::io::Result<bool> {
+        let mut levels = self.0.lock().unwrap();
+        Ok(if levels.len() > 1 {levels.pop_front().unwrap()} else {levels[0]})
+    }
+}
+
+fn device(replay: &Replay, reed: &Reed, open_door: OpenDoorPolicy) -> CFF3000 {
+    let options = DoorSensorOptions {open_door, ..DoorSensorOptions::default()};
+    testing::device(replay).door_input(reed.clone(), options).build().unwrap()
+}
+
+fn presses(replay: &Replay) -> usize {
+    replay.transitions().iter().filter(|transition| transition.pressed).count()
+}
+
+#[test]
+fn door_is_read_with_its_polarity() {
+    let replay = Replay::with_captures(&[Some(CFF3000State::Locked)]);
+    let reed = Reed::new(true);
+    let device = device(&replay, &reed, OpenDoorPolicy::Allow);
+    assert!(device.door_closed().unwrap());
+    assert_eq!(device.composite_status().unwrap(), CompositeStatus {lock: CFF3000State::Locked, door_open: false});
+    reed.set(false);
+    assert!(!device.door_closed().unwrap());
+
+    let options = DoorSensorOptions {polarity: Polarity::ActiveLow, ..DoorSensorOptions::default()};
+    let pulled_down = testing::device(&Replay::new()).door_input(reed.clone(), options).build().unwrap();
+    assert!(pulled_down.door_closed().unwrap());
+
+    let without = testing::device(&Replay::new()).build().unwrap();
+    assert_eq!(without.door_closed().unwrap_err().kind(), ErrorKind::Unsupported);
+    /* lines need a chip */
+    let err = testing::device(&Replay::new()).door_sensor(6, DoorSensorOptions::default()).build().err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn bouncing_contacts_are_debounced() {
+    let replay = Replay::new();
+    let bouncing = Bouncing(Mutex::new(vec![true, false, true, false, false].into_iter().collect()));
+    let device = testing::device(&replay).door_input(bouncing, DoorSensorOptions::default()).build().unwrap();
+    let start = replay.elapsed();
+    assert!(!device.door_closed().unwrap());
+    assert_eq!(replay.elapsed() - start, 4 * cff3000::door::DEFAULT_DEBOUNCE);
+
+    let chattering = Bouncing(Mutex::new((0..100).map(|i| i % 2 == 0).collect()));
+    let device = testing::device(&replay).door_input(chattering, DoorSensorOptions::default()).build().unwrap();
+    assert_eq!(device.door_closed().unwrap_err().kind(), ErrorKind::InvalidData);
+
+    let first = Bouncing(Mutex::new(vec![false, true].into_iter().collect()));
+    let options = DoorSensorOptions {debounce: Duration::from_millis(0), ..DoorSensorOptions::default()};
+    let device = testing::device(&replay).door_input(first, options).build().unwrap();
+    assert!(!device.door_closed().unwrap());
+}
+
+#[test]
+fn open_doors_are_not_locked() {
+    let replay = Replay::with_captures(&[Some(CFF3000State::Unlocked), Some(CFF3000State::Locked)]);
+    let reed = Reed::new(false);
+    let device = device(&replay, &reed, OpenDoorPolicy::Refuse);
+    let err = device.lock().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    assert_eq!(err.get_ref().and_then(|inner| inner.downcast_ref::<DoorOpen>()), Some(&DoorOpen));
+    assert_eq!(device.lock_and_verify().unwrap_err().kind(), ErrorKind::PermissionDenied);
+    assert_eq!(presses(&replay), 0);
+    /* unlocking and checking are fine */
+    assert_eq!(device.unlock_and_verify().unwrap(), CFF3000State::Unlocked);
+
+    reed.set(true);
+    assert_eq!(device.lock_and_verify().unwrap(), CFF3000State::Locked);
+}
+
+#[test]
+fn open_doors_are_reported() {
+    let replay = Replay::with_captures(&[Some(CFF3000State::Locked), Some(CFF3000State::Locked)]);
+    let reed = Reed::new(false);
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    let options = DoorSensorOptions {open_door: OpenDoorPolicy::Warn, ..DoorSensorOptions::default()};
+    let device = testing::device(&replay)
+        .door_input(reed.clone(), options)
+        .monitor(move |notice| { let _ = tx.lock().unwrap().send(notice.clone()); })
+        .build()
+        .unwrap();
+    assert_eq!(device.lock_and_verify().unwrap(), CFF3000State::Locked);
+    assert_eq!(rx.try_recv().unwrap(), Notice::DoorOpen);
+    assert_eq!(Notice::DoorOpen.to_string(), "locking while the door is open");
+
+    reed.set(true);
+    device.lock_and_verify().unwrap();
+    assert!(rx.try_recv().is_err());
+}
+
+/// Reed switch open from `from` to `until` after `start`.
+struct Opened {
+    clock: TestClock,
+    start: Instant,
+    from: Duration,
+    until: Duration,
+}
+
+impl DoorInput for Opened {
+    fn level(&self) -> std::io::Result<bool> {
+        let elapsed = self.clock.now() - self.start;
+        Ok(elapsed < self.from || elapsed >= self.until)
+    }
+}
+
+#[test]
+fn composite_changes() {
+    let replay = Replay::with_captures(&[Some(CFF3000State::Locked)]);
+    let opened = Opened {clock: replay.clock(), start: replay.clock().now(), from: Duration::from_secs(100), until: Duration::from_secs(150)};
+    let device = testing::device(&replay).door_input(opened, DoorSensorOptions::default()).build().unwrap();
+
+    let stop = StopToken::new();
+    let mut changes: Vec<CompositeChange> = Vec::new();
+    device.watch_composite(&WatchOptions::new(Duration::from_secs(300)), Duration::from_secs(10), &stop, |change| {
+        changes.push(change);
+        if changes.len() == 3 {
+            stop.stop();
+        }
+    }).unwrap();
+
+    let closed = CompositeStatus {lock: CFF3000State::Locked, door_open: false};
+    let open = CompositeStatus {lock: CFF3000State::Locked, door_open: true};
+    assert_eq!(changes, vec![
+        CompositeChange {previous: None, current: closed, trigger: CompositeTrigger::Lock(Trigger::Poll)},
+        CompositeChange {previous: Some(closed), current: open, trigger: CompositeTrigger::Door},
+        CompositeChange {previous: Some(open), current: closed, trigger: CompositeTrigger::Door},
+    ]);
+    /* the door is read every 10 s */
+    assert!(replay.elapsed() >= Duration::from_secs(150) && replay.elapsed() < Duration::from_secs(170), "{:?}", replay.elapsed());
+}
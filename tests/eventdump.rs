@@ -0,0 +1,74 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Bounded dump of the LED events of a capture.
+
+use std::time::Duration;
+
+use cff3000::eventdump::{format_event, EventDump, Suppressed};
+use cff3000::testing::{generate, PatternParams};
+use cff3000::{CFF3000State, Led, LedEvent};
+
+fn event(led: Led, on: bool, millis: u64) -> LedEvent {
+    LedEvent {led, on, timestamp: Duration::from_millis(millis)}
+}
+
+#[test]
+fn events_are_formatted() {
+    let first = Duration::from_millis(700);
+    assert_eq!(format_event(1, &event(Led::Red, true, 700), first), "event 1: LED red on at 700000000 ns (+0ns)");
+    assert_eq!(format_event(3, &event(Led::Green, false, 1_950), first), "event 3: LED green off at 1950000000 ns (+1.25s)");
+}
+
+#[test]
+fn summaries_are_formatted() {
+    let suppressed = Suppressed {first: 9, red: 90, green: 90, span: Duration::from_millis(8990)};
+    assert_eq!(suppressed.count(), 180);
+    assert_eq!(suppressed.to_string(), "events 9 to 188 suppressed (90 red, 90 green over 8.99s)");
+    let single = Suppressed {first: 5, red: 0, green: 1, span: Duration::from_millis(0)};
+    assert_eq!(single.to_string(), "event 5 suppressed (0 red, 1 green over 0ns)");
+}
+
+#[test]
+fn short_captures_are_dumped_verbatim() {
+    let events = generate(CFF3000State::Locked, PatternParams::default());
+    let mut dump = EventDump::new(events.len());
+    let head: Vec<String> = events.iter().filter_map(|&event| dump.push(event)).collect();
+    assert_eq!(head.len(), events.len());
+    assert_eq!(dump.suppressed(), None);
+    assert!(dump.finish().is_empty());
+}
+
+#[test]
+fn long_captures_are_bounded() {
+    let events = generate(CFF3000State::OutOfRange, PatternParams::default());
+    assert!(events.len() > 8, "{} events", events.len());
+    let mut dump = EventDump::new(4);
+    let head: Vec<String> = events.iter().filter_map(|&event| dump.push(event)).collect();
+    let first = events[0].timestamp;
+    let expected: Vec<String> = events[..4].iter().enumerate().map(|(i, event)| format_event(i + 1, event, first)).collect();
+    assert_eq!(head, expected);
+
+    let middle = &events[4..events.len() - 4];
+    let suppressed = dump.suppressed().unwrap();
+    assert_eq!(suppressed.first, 5);
+    assert_eq!(suppressed.count(), middle.len());
+    assert_eq!(suppressed.red, middle.iter().filter(|event| event.led == Led::Red).count());
+    assert_eq!(suppressed.span, middle[middle.len() - 1].timestamp - middle[0].timestamp);
+
+    let tail = dump.finish();
+    assert_eq!(tail[0], suppressed.to_string());
+    let start = events.len() - 4;
+    let expected: Vec<String> = events[start..].iter().enumerate().map(|(i, event)| format_event(start + i + 1, event, first)).collect();
+    assert_eq!(&tail[1..], &expected[..]);
+}
+
+#[test]
+fn zero_limits_only_summarize() {
+    let events = generate(CFF3000State::Manual, PatternParams::default());
+    let mut dump = EventDump::new(0);
+    assert!(events.iter().all(|&event| dump.push(event).is_none()));
+    let lines = dump.finish();
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].starts_with(&format!("events 1 to {} suppressed", events.len())), "{:?}", lines);
+}
@@ -0,0 +1,101 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Value Change Dumps and CSV files of LED event logs.
+
+use std::time::Duration;
+
+use cff3000::export::{to_csv, to_vcd};
+use cff3000::import::{from_csv, is_csv};
+use cff3000::testing::Fixture;
+use cff3000::{Led, LedEvent};
+
+fn vcd(events: &[LedEvent]) -> String {
+    let mut out = Vec::new();
+    to_vcd(events, &mut out).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+const HEADER: &str = "\
+$version cff3000 LED events $end
+$timescale 1ns $end
+$scope module cff3000 $end
+$var wire 1 r red $end
+$var wire 1 g green $end
+$upscope $end
+$enddefinitions $end
+";
+
+#[test]
+fn fixtures_match_the_golden_file() {
+    let fixture = Fixture::load(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/generated-locked.csv")).unwrap();
+    assert_eq!(vcd(&fixture.events), include_str!("golden/generated-locked.vcd"));
+}
+
+#[test]
+fn empty_logs_have_the_definitions_only() {
+    assert_eq!(vcd(&[]), HEADER);
+}
+
+#[test]
+fn timestamps_start_at_zero() {
+    let events = [
+        LedEvent {led: Led::Green, on: false, timestamp: Duration::from_millis(5_300)},
+        LedEvent {led: Led::Red, on: true, timestamp: Duration::from_millis(5_000)},
+        LedEvent {led: Led::Red, on: false, timestamp: Duration::from_millis(5_300)},
+    ];
+    /* green starts on, the opposite of its first event */
+    assert_eq!(vcd(&events), format!("{}#0\n$dumpvars\n1r\n1g\n$end\n#300000000\n0g\n0r\n", HEADER));
+}
+
+fn csv(events: &[LedEvent]) -> String {
+    let mut out = Vec::new();
+    to_csv(events, &mut out).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn csv_files_start_at_zero() {
+    let fixture = Fixture::load(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/generated-locked.csv")).unwrap();
+    assert_eq!(csv(&fixture.events), "\
+timestamp_us,led,state
+0,green,on
+1890,red,on
+309042,red,off
+310042,red,on
+1000790,red,off
+4002354,green,off
+");
+    assert_eq!(csv(&[]), "timestamp_us,led,state\n");
+}
+
+#[test]
+fn csv_files_are_read_back() {
+    let fixture = Fixture::load(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/generated-out-of-range.csv")).unwrap();
+    let text = csv(&fixture.events);
+    assert!(is_csv(&text));
+    assert!(!is_csv(&fixture.to_string()));
+
+    let events = from_csv(text.as_bytes()).unwrap();
+    assert_eq!(events.len(), fixture.events.len());
+    let start = fixture.events[0].timestamp;
+    for (read, written) in events.iter().zip(&fixture.events) {
+        assert_eq!((read.led, read.on), (written.led, written.on));
+        assert_eq!(read.timestamp, Duration::from_micros((written.timestamp - start).as_micros() as u64));
+    }
+    /* spreadsheets like to add line ends and spaces */
+    assert_eq!(from_csv("timestamp_us,led,state\r\n0, red ,on\r\n\r\n".as_bytes()).unwrap(), vec![LedEvent {led: Led::Red, on: true, timestamp: Duration::ZERO}]);
+    assert_eq!(from_csv("timestamp_us,led,state\n".as_bytes()).unwrap(), vec![]);
+}
+
+#[test]
+fn invalid_csv_rows_are_numbered() {
+    let error = |text: &str| from_csv(text.as_bytes()).unwrap_err().to_string();
+    assert_eq!(error(""), "empty file, expected a column header");
+    assert_eq!(error("led,edge,timestamp_ns\n"), "line 1: expected column header \"timestamp_us,led,state\"");
+    assert_eq!(error("timestamp_us,led,state\n0,red\n"), "line 2: expected 3 columns");
+    assert_eq!(error("timestamp_us,led,state\n0,red,on\n-1,red,off\n"), "line 3: invalid timestamp");
+    assert_eq!(error("timestamp_us,led,state\n0,blue,on\n"), "line 2: unknown LED \"blue\"");
+    assert_eq!(error("timestamp_us,led,state\n0,red,rising\n"), "line 2: unknown state \"rising\", expected on or off");
+    assert_eq!(error("timestamp_us,led,state\n\n500,red,on\n500,green,on\n400,red,off\n"), "line 5: timestamp 400 before the one of the previous row");
+}
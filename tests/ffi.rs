@@ -0,0 +1,54 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! The C interface called like C code would, on the replay backend.
+
+use std::ffi::{CStr, CString};
+use std::ptr;
+
+use cff3000::ffi::*;
+use cff3000::testing::{self, Replay};
+use cff3000::CFF3000State;
+
+fn message(handle: *const Handle) -> String {
+    unsafe { CStr::from_ptr(cff3000_last_error_message(handle)) }.to_str().unwrap().to_string()
+}
+
+#[test]
+fn calls() {
+    let replay = Replay::with_captures(&[Some(CFF3000State::Manual)]);
+    /* the next press shows nothing */
+    replay.push_capture(Vec::new());
+    let handle = into_handle(testing::device(&replay).build().unwrap());
+
+    unsafe {
+        assert_eq!(message(handle), "");
+        let mut state = -1;
+        assert_eq!(cff3000_state(handle, &mut state), CFF3000_OK);
+        assert_eq!(state, CFF3000_STATE_MANUAL);
+        state = -1;
+        assert_eq!(cff3000_state(handle, &mut state), CFF3000_ERROR_NO_RESPONSE);
+        assert_eq!(state, -1);
+        assert_eq!(message(handle), "did not receive enough LED change events");
+        assert_eq!(cff3000_state(handle, ptr::null_mut()), CFF3000_ERROR_CONFIG);
+        assert_eq!(cff3000_lock(handle), CFF3000_OK);
+        assert_eq!(cff3000_unlock(handle), CFF3000_OK);
+        assert_eq!(cff3000_check(handle), CFF3000_OK);
+        cff3000_free(handle);
+
+        assert_eq!(cff3000_lock(ptr::null_mut()), CFF3000_ERROR_CONFIG);
+        cff3000_free(ptr::null_mut());
+    }
+}
+
+#[test]
+fn failed_open() {
+    let chip = CString::new("/dev/nonexistent-gpiochip").unwrap();
+    let pins = [1, 2, 3, 4];
+    unsafe {
+        assert!(cff3000_new(chip.as_ptr(), pins.as_ptr()).is_null());
+        assert!(!message(ptr::null()).is_empty());
+        assert!(cff3000_new(ptr::null(), pins.as_ptr()).is_null());
+        assert_eq!(message(ptr::null()), "chip and pins must not be NULL");
+    }
+}
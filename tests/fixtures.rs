@@ -0,0 +1,68 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Every capture in `tests/fixtures/` must classify as the state it is
+//! labeled with, see `tests/fixtures/README.md`, and the single pass
+//! of `parse_led_events()` must agree with the two parser stages.
+
+use std::time::Duration;
+
+use cff3000::parser::{classify, merge_events};
+use cff3000::testing::{generate, load_fixtures, Fixture, PatternParams};
+use cff3000::{parse_led_events, CFF3000State, LedEvent, ParseOptions};
+
+/// Both parser paths give the same result for `events`.
+fn assert_paths_agree(events: &[LedEvent], options: &ParseOptions) {
+    assert_eq!(parse_led_events(events, options), classify(&merge_events(events, options)), "{:?}", events);
+}
+
+#[test]
+fn fixtures_classify_as_expected() {
+    let fixtures = load_fixtures(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures")).unwrap();
+    assert!(!fixtures.is_empty());
+
+    let mut failures = Vec::new();
+    for (path, fixture) in &fixtures {
+        assert_eq!(&Fixture::parse(&fixture.to_string()).unwrap(), fixture);
+        let result = parse_led_events(&fixture.events, &fixture.profile.timing().parse_options());
+        if result != Ok(fixture.expected) {
+            failures.push(format!("{} ({}, {:?}): expected {:?}, got {:?}", path.display(), fixture.device, fixture.profile, fixture.expected, result));
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[test]
+fn fixtures_parse_in_one_pass() {
+    let fixtures = load_fixtures(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures")).unwrap();
+    for (_, fixture) in &fixtures {
+        let options = fixture.profile.timing().parse_options();
+        assert_paths_agree(&fixture.events, &options);
+        /* and the broken captures of the same patterns */
+        for i in 0..fixture.events.len() {
+            let mut events = fixture.events.clone();
+            events.remove(i);
+            assert_paths_agree(&events, &options);
+        }
+        assert_paths_agree(&fixture.events[..fixture.events.len() / 2], &options);
+    }
+}
+
+#[test]
+fn generated_patterns_parse_in_one_pass() {
+    for &state in &[CFF3000State::Locked, CFF3000State::Unlocked, CFF3000State::Manual, CFF3000State::OutOfRange] {
+        for seed in 0..50 {
+            /* glitches and lost edges, some longer than the merge window */
+            let params = PatternParams {
+                blinks: 2 + seed as u32 % 10,
+                jitter: Duration::from_millis(10),
+                glitches: seed as u32 % 4,
+                glitch_width: Duration::from_millis(10 * (seed % 8)),
+                dropped_edges: seed as u32 % 3,
+                seed,
+                ..PatternParams::default()
+            };
+            assert_paths_agree(&generate(state, params), &ParseOptions::default());
+        }
+    }
+}
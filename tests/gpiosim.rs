@@ -0,0 +1,305 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! End to end tests through the kernel's GPIO uAPI, using a `gpio-sim`
+//! chip and a fake CFF3000 driving its LED lines.
+//!
+//! Needs a kernel with `CONFIG_GPIO_SIM` (`modprobe gpio-sim`), configfs
+//! mounted at `/sys/kernel/config` and root permissions:
+//!
+//! ```text
+//! sudo modprobe gpio-sim
+//! sudo -E cargo test --features gpiosim-tests --test gpiosim
+//! ```
+//!
+//! Every test creates its own chip with four lines (LED red, LED green,
+//! button unlock, button lock) and removes it afterwards.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use cff3000::config::{CFF3000Config, Severity};
+use cff3000::testing::{generate, PatternParams};
+use cff3000::{CFF3000, CFF3000Builder, CFF3000State, Led, LineRole, PinAssignment};
+
+const CONFIGFS: &str = "/sys/kernel/config/gpio-sim";
+const GPIOS: [u32; 4] = [0, 1, 2, 3];
+
+/// Simulated gpiochip, removed on drop.
+struct SimChip {
+    config: PathBuf,
+    /// `/dev/gpiochipN`
+    chipdev: String,
+    /// sysfs directory with the `sim_gpioN` line attributes
+    lines: PathBuf,
+}
+
+fn write(path: PathBuf, value: &str) {
+    fs::write(&path, value).unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+}
+
+fn read(path: PathBuf) -> String {
+    fs::read_to_string(&path).unwrap_or_else(|err| panic!("{}: {}", path.display(), err)).trim().to_string()
+}
+
+impl SimChip {
+    fn new() -> SimChip {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!("cff3000-{}-{}", std::process::id(), COUNT.fetch_add(1, Ordering::SeqCst));
+        let config = PathBuf::from(CONFIGFS).join(name);
+        fs::create_dir(&config).unwrap_or_else(|err| {
+            panic!("{}: {} (is gpio-sim loaded and are you root?)", config.display(), err)
+        });
+        fs::create_dir(config.join("gpio-bank0")).unwrap();
+        write(config.join("gpio-bank0/num_lines"), "4");
+        write(config.join("live"), "1");
+
+        let device = read(config.join("dev_name"));
+        let chip = read(config.join("gpio-bank0/chip_name"));
+        SimChip {
+            chipdev: format!("/dev/{}", chip),
+            lines: PathBuf::from("/sys/devices/platform").join(device).join(chip),
+            config,
+        }
+    }
+
+    /// Drive input line `offset` (the "hardware" side).
+    fn set(&self, offset: u32, high: bool) {
+        write(self.lines.join(format!("sim_gpio{}/pull", offset)), if high {"pull-up"} else {"pull-down"});
+    }
+
+    /// Level of line `offset` as seen by the hardware.
+    fn get(&self, offset: u32) -> bool {
+        read(self.lines.join(format!("sim_gpio{}/value", offset))) == "1"
+    }
+}
+
+impl Drop for SimChip {
+    fn drop(&mut self) {
+        let _ = fs::write(self.config.join("live"), "0");
+        let _ = fs::remove_dir(self.config.join("gpio-bank0"));
+        let _ = fs::remove_dir(&self.config);
+    }
+}
+
+/// Press seen by the fake CFF3000.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Press {
+    Check,
+    Lock,
+    Unlock,
+}
+
+/// Fake CFF3000 watching the button lines of a `SimChip` and answering
+/// every press with the LED pattern of its state.
+struct FakeDevice {
+    state: Arc<Mutex<CFF3000State>>,
+    presses: Arc<Mutex<Vec<(Press, Duration)>>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FakeDevice {
+    fn start(chip: &Arc<SimChip>, state: CFF3000State) -> FakeDevice {
+        let state = Arc::new(Mutex::new(state));
+        let presses = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        chip.set(GPIOS[0], false);
+        chip.set(GPIOS[1], false);
+
+        let thread = {
+            let (chip, state, presses, stop) = (chip.clone(), state.clone(), presses.clone(), stop.clone());
+            std::thread::spawn(move || FakeDevice::run(&chip, &state, &presses, &stop))
+        };
+        FakeDevice {state, presses, stop, thread: Some(thread)}
+    }
+
+    fn run(chip: &SimChip, state: &Mutex<CFF3000State>, presses: &Mutex<Vec<(Press, Duration)>>, stop: &AtomicBool) {
+        let mut start: Option<Instant> = None;
+        let mut combo = [false; 2];
+        let mut pending: VecDeque<(Instant, u32, bool)> = VecDeque::new();
+        let mut seed = 1;
+
+        while !stop.load(Ordering::SeqCst) {
+            while pending.front().is_some_and(|&(due, _, _)| due <= Instant::now()) {
+                let (_, offset, high) = pending.pop_front().unwrap();
+                chip.set(offset, high);
+            }
+
+            let pressed = [chip.get(GPIOS[2]), chip.get(GPIOS[3])];
+            if pressed[0] || pressed[1] {
+                start = start.or_else(|| Some(Instant::now()));
+                combo = [combo[0] || pressed[0], combo[1] || pressed[1]];
+            } else if let Some(at) = start.take() {
+                let press = match combo {
+                    [true, true] => Press::Check,
+                    [true, false] => Press::Unlock,
+                    _ => Press::Lock,
+                };
+                combo = [false; 2];
+                presses.lock().unwrap().push((press, at.elapsed()));
+
+                let current = {
+                    let mut state = state.lock().unwrap();
+                    match press {
+                        Press::Lock => *state = CFF3000State::Locked,
+                        Press::Unlock => *state = CFF3000State::Unlocked,
+                        Press::Check => {},
+                    }
+                    *state
+                };
+
+                /* a new press restarts the pattern, timed from the start of the press */
+                seed += 1;
+                let params = PatternParams {jitter: Duration::from_millis(3), seed, ..PatternParams::default()};
+                pending = generate(current, params).into_iter().map(|event| {
+                    let offset = match event.led {Led::Red => GPIOS[0], Led::Green => GPIOS[1]};
+                    (at + event.timestamp, offset, event.on)
+                }).collect();
+                /* LEDs off until the new pattern starts */
+                chip.set(GPIOS[0], false);
+                chip.set(GPIOS[1], false);
+            }
+            std::thread::sleep(Duration::from_millis(2));
+        }
+    }
+
+    fn set_state(&self, state: CFF3000State) {
+        *self.state.lock().unwrap() = state;
+    }
+
+    /// Presses seen so far with their duration.
+    fn presses(&self) -> Vec<(Press, Duration)> {
+        self.presses.lock().unwrap().clone()
+    }
+}
+
+impl Drop for FakeDevice {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn setup(state: CFF3000State) -> (Arc<SimChip>, FakeDevice, CFF3000) {
+    let chip = Arc::new(SimChip::new());
+    let fake = FakeDevice::start(&chip, state);
+    let pins = PinAssignment {led_red: GPIOS[0], led_green: GPIOS[1], button_unlock: GPIOS[2], button_lock: GPIOS[3]};
+    let cff3000 = CFF3000::new_with_pins(&chip.chipdev, pins).unwrap();
+    (chip, fake, cff3000)
+}
+
+#[test]
+fn state_reads_every_pattern() {
+    let (_chip, fake, cff3000) = setup(CFF3000State::Locked);
+    for &state in &[CFF3000State::Locked, CFF3000State::Unlocked, CFF3000State::Manual, CFF3000State::OutOfRange] {
+        fake.set_state(state);
+        assert_eq!(cff3000.state().unwrap(), state);
+    }
+    assert!(fake.presses().iter().all(|&(press, _)| press == Press::Check));
+}
+
+#[test]
+fn lock_and_unlock_are_confirmed() {
+    let (_chip, fake, cff3000) = setup(CFF3000State::Unlocked);
+    assert_eq!(cff3000.lock_and_verify().unwrap(), CFF3000State::Locked);
+    assert_eq!(cff3000.unlock_and_verify().unwrap(), CFF3000State::Unlocked);
+
+    let presses = fake.presses();
+    assert_eq!(presses.iter().map(|&(press, _)| press).collect::<Vec<_>>(), vec![Press::Lock, Press::Unlock]);
+    assert!(presses.iter().all(|&(_, held)| held >= Duration::from_millis(500)));
+}
+
+#[test]
+fn plain_presses_release_buttons() {
+    let (chip, fake, cff3000) = setup(CFF3000State::Locked);
+    cff3000.lock().unwrap();
+    cff3000.unlock().unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(!chip.get(GPIOS[2]) && !chip.get(GPIOS[3]));
+    assert_eq!(fake.presses().len(), 2);
+}
+
+#[test]
+fn stale_events_are_drained() {
+    let (chip, fake, cff3000) = setup(CFF3000State::Manual);
+
+    /* LED activity before the query must not end up in the capture */
+    for i in 0..20 {
+        chip.set(GPIOS[i % 2], i % 4 < 2);
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    chip.set(GPIOS[0], false);
+    chip.set(GPIOS[1], false);
+    std::thread::sleep(Duration::from_millis(50));
+
+    assert_eq!(cff3000.state().unwrap(), CFF3000State::Manual);
+    assert_eq!(fake.presses().len(), 1);
+}
+
+#[test]
+fn event_timestamps_follow_pattern_timing() {
+    let (_chip, _fake, cff3000) = setup(CFF3000State::OutOfRange);
+    let report = cff3000.state_report().unwrap();
+    assert_eq!(report.state, CFF3000State::OutOfRange);
+    assert!(report.events.windows(2).all(|pair| pair[0].timestamp <= pair[1].timestamp));
+
+    /* after the intro, red changes with every level (500 ms apart) */
+    let red: Vec<u128> = report.events.iter().filter(|e| e.led == Led::Red).map(|e| e.timestamp.as_millis()).collect();
+    let gaps: Vec<u128> = red.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    assert!(gaps[1..gaps.len() - 1].iter().all(|&gap| gap > 450 && gap < 550), "{:?}", gaps);
+}
+
+#[test]
+fn line_info_reports_levels() {
+    let (chip, _fake, cff3000) = setup(CFF3000State::Locked);
+    chip.set(GPIOS[1], true);
+    let lines = match cff3000.line_info() {
+        Ok(lines) => lines,
+        Err(ref err) if err.kind() == std::io::ErrorKind::Unsupported => return,
+        Err(err) => panic!("{}", err),
+    };
+    chip.set(GPIOS[1], false);
+
+    let level = |role: LineRole| lines.iter().find(|line| line.role == role).unwrap().level;
+    assert!(!level(LineRole::LedRed) && level(LineRole::LedGreen));
+    assert!(!level(LineRole::ButtonUnlock) && !level(LineRole::ButtonLock));
+}
+
+#[test]
+fn config_validation_checks_lines() {
+    let chip = SimChip::new();
+    let config = CFF3000Config::new(&chip.chipdev, PinAssignment::from([0, 1, 2, 7]));
+    let issues = config.validate();
+    assert_eq!(issues.len(), 1, "{:?}", issues);
+    assert_eq!((issues[0].field.as_str(), issues[0].severity), ("pins.button_lock", Severity::Error));
+
+    let config = CFF3000Config::new(&chip.chipdev, PinAssignment::from(GPIOS));
+    assert_eq!(config.validate(), vec![]);
+    let _cff3000 = CFF3000::new(&chip.chipdev, GPIOS).unwrap();
+    let fields: Vec<String> = config.validate().into_iter().map(|issue| issue.field).collect();
+    assert_eq!(fields, vec!["pins.led_red", "pins.led_green", "pins.button_unlock", "pins.button_lock"]);
+}
+
+#[test]
+fn dry_run_leaves_buttons_alone() {
+    let chip = Arc::new(SimChip::new());
+    let fake = FakeDevice::start(&chip, CFF3000State::Locked);
+    let cff3000 = CFF3000Builder::new(&chip.chipdev, GPIOS).dry_run(true).build().unwrap();
+    cff3000.lock().unwrap();
+    cff3000.check().unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+    assert_eq!(fake.presses(), vec![]);
+
+    /* only the LED lines are in use */
+    let config = CFF3000Config::new(&chip.chipdev, PinAssignment::from(GPIOS));
+    let fields: Vec<String> = config.validate().into_iter().map(|issue| issue.field).collect();
+    assert_eq!(fields, vec!["pins.led_red", "pins.led_green"]);
+}
@@ -0,0 +1,162 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! `CFF3000Group` against the replay backend: per-device results, the
+//! spacing between the devices and the namespaced integrations.
+
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use cff3000::group::DEFAULT_SPACING;
+use cff3000::testing::{self, Replay};
+use cff3000::{CFF3000Group, CFF3000State, GroupOptions, Notice};
+
+fn presses(replay: &Replay) -> usize {
+    replay.transitions().iter().filter(|transition| transition.pressed).count()
+}
+
+fn spacing(ms: u64) -> GroupOptions {
+    GroupOptions {spacing: Duration::from_millis(ms)}
+}
+
+#[test]
+fn devices_report_on_their_own() {
+    assert_eq!(GroupOptions::default().spacing, DEFAULT_SPACING);
+    let mut group = CFF3000Group::new(spacing(0));
+    assert!(group.is_empty());
+    group.add("garage", testing::device(&Replay::with_captures(&[Some(CFF3000State::Unlocked)]))).unwrap();
+    group.add("frontdoor", testing::device(&Replay::with_captures(&[None]))).unwrap();
+    group.add("back_door-2", testing::device(&Replay::with_captures(&[Some(CFF3000State::Locked)]))).unwrap();
+    assert_eq!(group.len(), 3);
+    assert_eq!(group.names().collect::<Vec<_>>(), vec!["back_door-2", "frontdoor", "garage"]);
+    assert!(group.get("garage").is_some());
+    assert!(group.get("cellar").is_none());
+
+    /* in name order, the failing device does not stop the others */
+    let results = group.state_all();
+    let names: Vec<&str> = results.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, vec!["back_door-2", "frontdoor", "garage"]);
+    assert_eq!(results[0].1.as_ref().unwrap(), &CFF3000State::Locked);
+    assert!(results[1].1.is_err());
+    assert_eq!(results[2].1.as_ref().unwrap(), &CFF3000State::Unlocked);
+}
+
+#[test]
+fn names_are_checked() {
+    let mut group = CFF3000Group::new(spacing(0));
+    for name in &["", "front door", "garage/1", "tür", "a+b"] {
+        let err = group.add(name, testing::device(&Replay::new())).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput, "{:?}", name);
+    }
+    group.add("garage", testing::device(&Replay::new())).unwrap();
+    assert_eq!(group.add("garage", testing::device(&Replay::new())).err().unwrap().kind(), ErrorKind::AlreadyExists);
+    assert_eq!(group.len(), 1);
+}
+
+#[test]
+fn operations_are_spaced() {
+    let mut group = CFF3000Group::new(spacing(100));
+    for name in &["a", "b", "c"] {
+        group.add(name, testing::device(&Replay::with_captures(&[Some(CFF3000State::Locked), Some(CFF3000State::Locked)]))).unwrap();
+    }
+    let start = Instant::now();
+    assert!(group.state_all().iter().all(|(_, result)| result.is_ok()));
+    assert!(start.elapsed() >= Duration::from_millis(200), "{:?}", start.elapsed());
+
+    /* also between the devices used on their own */
+    let start = Instant::now();
+    let (a, b) = (group.get("a").unwrap().clone(), group.get("b").unwrap().clone());
+    let other = std::thread::spawn(move || b.state().unwrap());
+    a.state().unwrap();
+    other.join().unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(100), "{:?}", start.elapsed());
+}
+
+#[test]
+fn unlocked_devices_are_locked() {
+    let (locked, unlocked, unknown) = (
+        Replay::with_captures(&[Some(CFF3000State::Locked)]),
+        Replay::with_captures(&[Some(CFF3000State::Unlocked), Some(CFF3000State::Locked)]),
+        Replay::with_captures(&[None, Some(CFF3000State::Locked)]),
+    );
+    let mut group = CFF3000Group::new(spacing(0));
+    group.add("locked", testing::device(&locked)).unwrap();
+    group.add("unlocked", testing::device(&unlocked)).unwrap();
+    group.add("unknown", testing::device(&unknown)).unwrap();
+
+    let results = group.ensure_all_locked();
+    assert!(results.iter().all(|(_, result)| result.as_ref().ok() == Some(&CFF3000State::Locked)), "{:?}", results);
+    /* a query presses both buttons, the lock one more */
+    assert_eq!((presses(&locked), presses(&unlocked), presses(&unknown)), (2, 3, 3));
+}
+
+#[test]
+fn notices_carry_the_name() {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let own = Arc::new(Mutex::new(0));
+    let mut group = {
+        let seen = seen.clone();
+        CFF3000Group::new(spacing(0)).monitor(move |name, notice| {
+            if let Notice::DryRun {..} = *notice {
+                seen.lock().unwrap().push(name.to_string());
+            }
+        })
+    };
+    let counter = own.clone();
+    let garage = testing::device(&Replay::new()).dry_run(true).monitor(move |_| *counter.lock().unwrap() += 1);
+    group.add("garage", garage).unwrap();
+    group.add("frontdoor", testing::device(&Replay::new()).dry_run(true)).unwrap();
+
+    group.get("garage").unwrap().lock().unwrap();
+    group.get("frontdoor").unwrap().lock().unwrap();
+    let seen = seen.lock().unwrap().clone();
+    assert!(!seen.is_empty());
+    /* the same writes for each device */
+    assert_eq!(seen.iter().filter(|name| *name == "frontdoor").count(), seen.len() / 2);
+    assert_eq!(seen.iter().filter(|name| *name == "garage").count(), seen.len() / 2);
+    /* the own monitor of the device still sees its notices */
+    assert_eq!(*own.lock().unwrap(), seen.len() / 2);
+}
+
+#[cfg(feature = "http")]
+#[test]
+fn http_routes_are_namespaced() {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::time::UNIX_EPOCH;
+
+    use cff3000::http::{serve_group, HttpOptions};
+    use cff3000::{StateChange, Trigger};
+
+    fn get(addr: SocketAddr, path: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(format!("GET {} HTTP/1.1\r\nHost: door\r\n\r\n", path).as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        (status, response[response.find("\r\n\r\n").unwrap() + 4..].trim_end().to_string())
+    }
+
+    let mut group = CFF3000Group::new(spacing(0));
+    group.add("garage", testing::device(&Replay::with_captures(&[Some(CFF3000State::Unlocked)]))).unwrap();
+    group.add("frontdoor", testing::device(&Replay::with_captures(&[Some(CFF3000State::Locked)]))).unwrap();
+    let server = serve_group(&group, "127.0.0.1:0", HttpOptions::default()).unwrap();
+    let addr = server.local_addr();
+
+    assert_eq!(get(addr, "/devices"), (200, r#"{"devices":["frontdoor","garage"]}"#.to_string()));
+    assert_eq!(get(addr, "/garage/state").1, r#"{"state":"unlocked","cached":false,"age_ms":0}"#);
+    assert_eq!(get(addr, "/frontdoor/state").1, r#"{"state":"locked","cached":false,"age_ms":0}"#);
+    assert_eq!(get(addr, "/cellar/state").0, 404);
+    assert_eq!(get(addr, "/state").0, 404);
+    /* targets without a leading slash, the server keeps running */
+    assert_eq!(get(addr, "").0, 400);
+    assert_eq!(get(addr, "ä/state").0, 400);
+    assert_eq!(get(addr, "/ä/state").0, 404);
+    assert_eq!(get(addr, "/devices").0, 200);
+
+    let change = StateChange::new(None, CFF3000State::Locked, Trigger::Poll, UNIX_EPOCH);
+    assert!(server.publish_to("garage", change));
+    assert!(!server.publish_to("cellar", change));
+}
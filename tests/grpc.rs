@@ -0,0 +1,89 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! `grpc::serve()` against the replay backend, called with the
+//! generated client.
+
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, SystemTime};
+
+use cff3000::grpc::proto::{self, GetStateRequest, LockRequest, State, UnlockRequest, WatchStateRequest};
+use cff3000::grpc::{from_proto, serve, serve_with_options, to_proto, Code, DoorLockClient, GrpcOptions};
+use cff3000::testing::{self, Replay};
+use cff3000::{CFF3000State, StateChange, Trigger};
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap()
+}
+
+#[test]
+fn states_map_one_to_one() {
+    for &state in &[CFF3000State::Locked, CFF3000State::Unlocked, CFF3000State::Manual, CFF3000State::OutOfRange] {
+        assert_eq!(from_proto(to_proto(state)), Some(state));
+    }
+    assert_eq!(from_proto(State::Unknown), None);
+}
+
+#[test]
+fn calls() {
+    let device = Arc::new(testing::device(&Replay::with_captures(&[Some(CFF3000State::Locked), Some(CFF3000State::Locked), None, Some(CFF3000State::Manual)])).build().unwrap());
+    let server = serve(device, "127.0.0.1:0").unwrap();
+    let runtime = runtime();
+    let mut client = runtime.block_on(DoorLockClient::connect(format!("http://{}", server.local_addr()))).unwrap();
+
+    assert_eq!(runtime.block_on(client.lock(LockRequest {})).unwrap().into_inner().state(), State::Locked);
+    let status = runtime.block_on(client.unlock(UnlockRequest {})).unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition, "{}", status);
+    assert_eq!(status.message(), "the door is locked instead of unlocked");
+    let status = runtime.block_on(client.get_state(GetStateRequest {})).unwrap_err();
+    assert_eq!(status.code(), Code::DeadlineExceeded, "{}", status);
+    assert_eq!(runtime.block_on(client.get_state(GetStateRequest {})).unwrap().into_inner().state(), State::Manual);
+}
+
+#[test]
+fn watch_state() {
+    let device = Arc::new(testing::device(&Replay::new()).build().unwrap());
+    let server = serve_with_options(device, "127.0.0.1:0", GrpcOptions {stream_buffer: 1, ..GrpcOptions::default()}).unwrap();
+    let url = format!("http://{}", server.local_addr());
+    let (subscribed, subscription) = mpsc::channel();
+    let (done, received) = mpsc::channel();
+    /* reads all the time, so only the server's buffer limits the stream */
+    let reader = std::thread::spawn(move || {
+        let runtime = runtime();
+        let mut client = runtime.block_on(DoorLockClient::connect(url)).unwrap();
+        let mut stream = runtime.block_on(client.watch_state(WatchStateRequest {})).unwrap().into_inner();
+        subscribed.send(()).unwrap();
+        let mut changes = Vec::new();
+        while let Some(change) = runtime.block_on(stream.message()).unwrap() {
+            let end = change.trigger() == proto::Trigger::AutoLock;
+            changes.push(change);
+            if end {
+                let _ = done.send(std::mem::take(&mut changes));
+            }
+        }
+    });
+    subscription.recv().unwrap();
+    assert_eq!(server.watch_clients(), 1);
+
+    server.publish(StateChange::new(None, CFF3000State::Unlocked, Trigger::Poll, SystemTime::now()));
+    /* far more than the buffer holds, the server drops most */
+    const FLOOD: usize = 10_000;
+    for _ in 0..FLOOD {
+        server.publish(StateChange::new(Some(CFF3000State::Unlocked), CFF3000State::Locked, Trigger::Poll, SystemTime::now()));
+    }
+    /* the end marker is dropped as well while the buffer is full */
+    let changes = loop {
+        server.publish(StateChange::new(Some(CFF3000State::Locked), CFF3000State::Locked, Trigger::AutoLock, SystemTime::now()));
+        if let Ok(changes) = received.recv_timeout(Duration::from_millis(100)) {
+            break changes;
+        }
+    };
+    let first = &changes[0];
+    assert_eq!((first.previous(), first.current(), first.missed_changes), (State::Unknown, State::Unlocked, false));
+    assert!(changes.len() < FLOOD + 2, "{} changes received", changes.len());
+    assert!(changes.iter().any(|change| change.missed_changes));
+
+    /* the stream ends with the server */
+    drop(server);
+    reader.join().unwrap();
+}
@@ -0,0 +1,319 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! `CFF3000Builder::health_check()` against an adapter whose lines are
+//! changed by the tests.
+
+use std::io::Error;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use cff3000::discover::LineFlags;
+use cff3000::health::{HealthOptions, HealthProblem, HealthReport, HealthVerdict};
+use cff3000::history::HistoryError;
+use cff3000::testing::{self, Replay, TestClock};
+use cff3000::{Button, CFF3000, CFF3000Builder, CFF3000State, CommandQueue, GpioBackend, Led, LedEvent, LineInfo, LineRole, Notice, QueueOptions, LED_RED};
+
+const INTERVAL: Duration = Duration::from_millis(10);
+
+/// Longest real wait for a notice.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Electrical levels, flags and pending events of the four lines.
+struct Lines {
+    levels: [bool; 4],
+    flags: [LineFlags; 4],
+    pending: u8,
+    broken: bool,
+}
+
+#[derive(Clone)]
+struct Adapter(Arc<Mutex<Lines>>);
+
+impl Adapter {
+    fn new() -> Adapter {
+        let flags = |output| LineFlags {used: true, output, ..LineFlags::default()};
+        Adapter(Arc::new(Mutex::new(Lines {levels: [false; 4], flags: [flags(false), flags(false), flags(true), flags(true)], pending: 0, broken: false})))
+    }
+
+    fn lines(&self) -> std::sync::MutexGuard<'_, Lines> {
+        self.0.lock().unwrap()
+    }
+}
+
+impl GpioBackend for Adapter {
+    fn set_button(&self, _button: Button, _pressed: bool) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn wait_for_led_events(&self, _timeout: Duration) -> std::io::Result<u8> {
+        Ok(self.lines().pending)
+    }
+
+    fn read_led_event(&self, led: Led) -> std::io::Result<LedEvent> {
+        let mut lines = self.lines();
+        lines.pending &= !led.mask();
+        Ok(LedEvent {led, on: lines.levels[if led == Led::Red {0} else {1}], timestamp: Duration::ZERO})
+    }
+
+    fn flush_led_events(&self) -> std::io::Result<()> {
+        self.lines().pending = 0;
+        Ok(())
+    }
+
+    fn line_info(&self) -> std::io::Result<[LineInfo; 4]> {
+        let lines = self.lines();
+        if lines.broken {
+            return Err(Error::other("adapter does not answer"));
+        }
+        let line = |i: usize| LineInfo {role: LineRole::ALL[i], offset: i as u32, name: None, flags: lines.flags[i], level: lines.levels[i]};
+        Ok([line(0), line(1), line(2), line(3)])
+    }
+}
+
+/// Device probing `adapter` with `options` and the health notices it
+/// reports.
+fn device(adapter: &Adapter, clock: &TestClock, options: HealthOptions, dry_run: bool) -> (CFF3000, Receiver<Notice>) {
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    let device = CFF3000Builder::with_backend(adapter.clone())
+        .clock(clock.clone())
+        .dry_run(dry_run)
+        .health_check(HealthOptions {interval: INTERVAL, ..options})
+        .monitor(move |notice| match *notice {
+            Notice::HealthDegraded {..} | Notice::HealthRestored => {
+                let _ = tx.lock().unwrap().send(notice.clone());
+            },
+            _ => {},
+        })
+        .build()
+        .unwrap();
+    (device, rx)
+}
+
+fn problems(notice: Notice) -> Vec<HealthProblem> {
+    match notice {
+        Notice::HealthDegraded {problems} => problems,
+        notice => panic!("unexpected {:?}", notice),
+    }
+}
+
+/// Wait for the next probe to store its result.
+fn next_probe(device: &CFF3000) {
+    let last = device.health().map(|status| status.checked);
+    let deadline = std::time::Instant::now() + TIMEOUT;
+    while device.health().map(|status| status.checked) == last && std::time::Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn healthy_lines_pass() {
+    let adapter = Adapter::new();
+    let (device, notices) = device(&adapter, &TestClock::new(), HealthOptions::default(), false);
+    next_probe(&device);
+    next_probe(&device);
+    assert!(device.health().unwrap().healthy());
+    assert!(notices.try_recv().is_err());
+
+    let unchecked = CFF3000Builder::with_backend(Adapter::new()).build().unwrap();
+    assert_eq!(unchecked.health(), None);
+}
+
+#[test]
+fn changes_without_events_are_reported() {
+    let adapter = Adapter::new();
+    let (device, notices) = device(&adapter, &TestClock::new(), HealthOptions::default(), false);
+    next_probe(&device);
+    adapter.lines().levels[0] = true;
+
+    let problems = problems(notices.recv_timeout(TIMEOUT).unwrap());
+    assert_eq!(problems, vec![HealthProblem::MissedEvent {role: LineRole::LedRed}]);
+    assert_eq!(problems[0].to_string(), "LED red changed without an event");
+    assert_eq!(notices.recv_timeout(TIMEOUT).unwrap(), Notice::HealthRestored);
+    assert_eq!(Notice::HealthRestored.to_string(), "health check passed again");
+
+    /* a pending event explains the change, also once the device read it */
+    {
+        let mut lines = adapter.lines();
+        lines.levels[0] = false;
+        lines.pending = LED_RED;
+    }
+    next_probe(&device);
+    next_probe(&device);
+    device.flush_led_events().unwrap();
+    next_probe(&device);
+    next_probe(&device);
+    assert!(device.health().unwrap().healthy());
+    assert!(notices.try_recv().is_err());
+}
+
+#[test]
+fn stuck_leds_are_reported_once() {
+    let adapter = Adapter::new();
+    adapter.lines().levels[1] = true;
+    let clock = TestClock::new();
+    let (device, notices) = device(&adapter, &clock, HealthOptions {stuck_after: Duration::from_secs(120), ..HealthOptions::default()}, false);
+    next_probe(&device);
+    clock.advance(Duration::from_secs(130));
+
+    let problems = problems(notices.recv_timeout(TIMEOUT).unwrap());
+    match problems[..] {
+        [HealthProblem::Stuck {role: LineRole::LedGreen, lit}] => assert!(lit >= Duration::from_secs(130), "{:?}", lit),
+        ref problems => panic!("unexpected {:?}", problems),
+    }
+    assert_eq!(HealthProblem::Stuck {role: LineRole::LedGreen, lit: Duration::from_millis(130500)}.to_string(), "LED green lit for 130 s without an operation");
+
+    /* lasting problems are not reported again */
+    clock.advance(Duration::from_secs(60));
+    next_probe(&device);
+    next_probe(&device);
+    assert!(!device.health().unwrap().healthy());
+    assert!(notices.try_recv().is_err());
+}
+
+#[test]
+fn lines_are_checked() {
+    let adapter = Adapter::new();
+    adapter.lines().flags[3].used = false;
+    adapter.lines().flags[0].output = true;
+    let (device, notices) = device(&adapter, &TestClock::new(), HealthOptions::default(), false);
+    let problems = problems(notices.recv_timeout(TIMEOUT).unwrap());
+    let messages: Vec<String> = problems.iter().map(|problem| problem.to_string()).collect();
+    assert_eq!(messages, vec!["LED red is requested as an output", "button lock is not requested"]);
+    let notice = Notice::HealthDegraded {problems};
+    assert_eq!(notice.to_string(), "health check failed: LED red is requested as an output; button lock is not requested");
+    drop(device);
+
+    /* the buttons are not requested in a dry run */
+    adapter.lines().flags[0].output = false;
+    let (device, _notices) = self::device(&adapter, &TestClock::new(), HealthOptions::default(), true);
+    next_probe(&device);
+    assert!(device.health().unwrap().healthy());
+
+    adapter.lines().broken = true;
+    next_probe(&device);
+    assert_eq!(device.health().unwrap().problems, vec![HealthProblem::Unreadable {message: "adapter does not answer".to_string()}]);
+}
+
+#[test]
+fn reports_check_the_lines() {
+    let adapter = Adapter::new();
+    let device = CFF3000Builder::with_backend(adapter.clone()).build().unwrap();
+    let report = device.health_check();
+    assert_eq!(report, HealthReport {
+        verdict: HealthVerdict::Healthy,
+        reasons: Vec::new(),
+        chip_reachable: None,
+        lines_held: Some(true),
+        last_state_age: None,
+        last_error: None,
+        watchdog_alive: None,
+        queue_depth: None,
+    });
+
+    adapter.lines().flags[2].used = false;
+    let report = device.health_check();
+    assert_eq!((report.verdict, report.lines_held), (HealthVerdict::Unhealthy, Some(false)));
+    assert_eq!(report.reasons, vec![HealthProblem::LineFlags {role: LineRole::ButtonUnlock, flags: adapter.lines().flags[2]}]);
+
+    adapter.lines().broken = true;
+    let report = device.health_check();
+    assert_eq!((report.verdict, report.lines_held), (HealthVerdict::Unhealthy, Some(false)));
+    assert_eq!(report.reasons[0].to_string(), "cannot read the lines: adapter does not answer");
+
+    /* the stuck LED found by the probe is added, the lines are checked again */
+    adapter.lines().broken = false;
+    adapter.lines().flags[2].used = true;
+    adapter.lines().levels[0] = true;
+    let clock = TestClock::new();
+    let (device, notices) = self::device(&adapter, &clock, HealthOptions {stuck_after: Duration::from_secs(1), ..HealthOptions::default()}, false);
+    next_probe(&device);
+    clock.advance(Duration::from_secs(2));
+    notices.recv_timeout(TIMEOUT).unwrap();
+    let report = device.health_check();
+    assert_eq!((report.verdict, report.lines_held, report.watchdog_alive), (HealthVerdict::Unhealthy, Some(true), Some(true)));
+    assert!(matches!(report.reasons[..], [HealthProblem::Stuck {role: LineRole::LedRed, ..}]), "{:?}", report.reasons);
+}
+
+#[test]
+fn reports_follow_the_operations() {
+    let replay = Replay::with_captures(&[None, Some(CFF3000State::Locked)]);
+    let device = testing::device(&replay).build().unwrap();
+    let report = device.health_check();
+    assert_eq!((report.verdict, report.lines_held, report.last_state_age), (HealthVerdict::Healthy, None, None));
+
+    /* state queries without a state fail, the last one counts */
+    assert!(device.state().is_err());
+    let report = device.health_check();
+    let error = HistoryError {code: "no-response", message: "did not receive enough LED change events".to_string()};
+    assert_eq!(report.verdict, HealthVerdict::Degraded);
+    assert_eq!(report.reasons, vec![HealthProblem::OperationFailed {error: error.clone()}]);
+    assert_eq!(report.reasons[0].to_string(), "the last operation failed: did not receive enough LED change events");
+    assert_eq!((report.last_state_age, report.last_error.as_ref()), (None, Some(&error)));
+
+    assert_eq!(device.state().unwrap(), CFF3000State::Locked);
+    let report = device.health_check();
+    assert_eq!((report.verdict, report.last_error), (HealthVerdict::Healthy, Some(error)));
+    assert!(report.last_state_age.unwrap() < TIMEOUT);
+}
+
+#[test]
+fn full_queues_degrade_reports() {
+    let replay = Replay::new();
+    let device = std::sync::Arc::new(testing::device(&replay).build().unwrap());
+    let queue = CommandQueue::with_options(device.clone(), QueueOptions {depth: 0, coalesce: false}).unwrap();
+    let report = device.health_check().with_queue(&queue.sender());
+    assert_eq!((report.verdict, report.queue_depth), (HealthVerdict::Degraded, Some(0)));
+    assert_eq!(report.reasons, vec![HealthProblem::QueueFull {depth: 0}]);
+    assert_eq!(HealthProblem::QueueFull {depth: 16}.to_string(), "the command queue is full with 16 commands");
+}
+
+#[cfg(feature = "config")]
+#[test]
+fn reports_serialize() {
+    let report = HealthReport {
+        verdict: HealthVerdict::Degraded,
+        reasons: vec![HealthProblem::WatchdogStopped],
+        chip_reachable: Some(true),
+        lines_held: None,
+        last_state_age: Some(Duration::from_millis(1500)),
+        last_error: Some(HistoryError {code: "io", message: "gone".to_string()}),
+        watchdog_alive: Some(false),
+        queue_depth: None,
+    };
+    assert_eq!(toml::to_string(&report).unwrap(), "verdict = \"degraded\"\n\
+        reasons = [\"the health check is not running\"]\n\
+        chip_reachable = true\n\
+        last_state_age_ms = 1500\n\
+        watchdog_alive = false\n\
+        \n\
+        [last_error]\n\
+        code = \"io\"\n\
+        message = \"gone\"\n");
+}
+
+#[cfg(feature = "http")]
+#[test]
+fn healthz_reports_degraded_lines() {
+    use std::io::{Read, Write};
+
+    let adapter = Adapter::new();
+    let (device, _notices) = device(&adapter, &TestClock::new(), HealthOptions::default(), false);
+    let device = Arc::new(device);
+    let server = cff3000::http::serve(device.clone(), "127.0.0.1:0").unwrap();
+    let healthz = || {
+        let mut stream = std::net::TcpStream::connect(server.local_addr()).unwrap();
+        stream.write_all(b"GET /healthz HTTP/1.1\r\nHost: door\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        (response[9..12].to_string(), response[response.find("\r\n\r\n").unwrap() + 4..].trim_end().to_string())
+    };
+    let healthy = r#"{"verdict":"healthy","reasons":[],"lines_held":true,"watchdog_alive":true,"queue_depth":0}"#;
+    assert_eq!(healthz(), ("200".to_string(), healthy.to_string()));
+
+    adapter.lines().flags[2].used = false;
+    let unhealthy = r#"{"verdict":"unhealthy","reasons":["button unlock is not requested"],"lines_held":false,"watchdog_alive":true,"queue_depth":0}"#;
+    assert_eq!(healthz(), ("503".to_string(), unhealthy.to_string()));
+}
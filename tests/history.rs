@@ -0,0 +1,60 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! `CFF3000::history()` on the replay backend.
+
+use cff3000::testing::{self, Replay};
+use cff3000::{BusyPolicy, CFF3000, CFF3000State, Command};
+
+/// Device on captures showing `captures`, an empty capture for `None`.
+fn device(captures: &[Option<CFF3000State>], capacity: usize) -> CFF3000 {
+    testing::device(&Replay::with_captures(captures)).history(capacity).build().unwrap()
+}
+
+#[test]
+fn operations_are_recorded() {
+    let device = device(&[Some(CFF3000State::Unlocked), Some(CFF3000State::Locked), Some(CFF3000State::Unlocked), None], 10);
+    assert_eq!(device.history(), vec![]);
+    device.lock().unwrap();
+    assert_eq!(device.lock_and_verify().unwrap(), CFF3000State::Locked);
+    assert_eq!(device.state().unwrap(), CFF3000State::Unlocked);
+    device.state().unwrap_err();
+
+    let history = device.history();
+    let commands: Vec<Command> = history.iter().map(|entry| entry.command).collect();
+    assert_eq!(commands, vec![Command::Lock, Command::Lock, Command::Check, Command::Check]);
+    assert_eq!(history[0].result, Ok(None));
+    assert_eq!(history[1].result, Ok(Some(CFF3000State::Locked)));
+    assert_eq!(history[2].result, Ok(Some(CFF3000State::Unlocked)));
+    let error = history[3].result.clone().unwrap_err();
+    assert_eq!((error.code, error.message.as_str()), ("no-response", "did not receive enough LED change events"));
+    assert!(history.windows(2).all(|pair| pair[0].timestamp <= pair[1].timestamp));
+    assert!(!history[1].duration.is_zero());
+}
+
+#[test]
+fn refused_commands_are_recorded() {
+    let device = testing::device(&Replay::new()).busy_policy(BusyPolicy::FailFast).build().unwrap();
+    let press = device.begin_lock_press().unwrap();
+    assert!(device.state().is_err());
+    drop(press);
+
+    let history = device.history();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].command, Command::Check);
+    assert_eq!(history[0].result.as_ref().unwrap_err().code, "busy");
+}
+
+#[test]
+fn only_the_last_operations_are_kept() {
+    let device = device(&[Some(CFF3000State::Locked), Some(CFF3000State::Unlocked), Some(CFF3000State::Manual)], 2);
+    for _ in 0..3 {
+        device.state().unwrap();
+    }
+    let states: Vec<_> = device.history().into_iter().map(|entry| entry.result.unwrap()).collect();
+    assert_eq!(states, vec![Some(CFF3000State::Unlocked), Some(CFF3000State::Manual)]);
+
+    let ignoring = self::device(&[Some(CFF3000State::Locked)], 0);
+    ignoring.state().unwrap();
+    assert_eq!(ignoring.history(), vec![]);
+}
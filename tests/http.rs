@@ -0,0 +1,210 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! `HttpServer` against the replay backend.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use cff3000::http::{serve, serve_with_options, HttpOptions, HttpServer};
+use cff3000::testing::{self, Replay};
+use cff3000::{Button, CFF3000State, StateChange, Trigger};
+
+fn server(captures: &[Option<CFF3000State>], options: HttpOptions) -> (HttpServer, Replay) {
+    let replay = Replay::with_captures(captures);
+    let device = Arc::new(testing::device(&replay).build().unwrap());
+    (serve_with_options(device, "127.0.0.1:0", options).unwrap(), replay)
+}
+
+/// Send `request` and return the status and the body.
+fn request(addr: SocketAddr, request: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response[9..12].parse().unwrap();
+    let body = response[response.find("\r\n\r\n").unwrap() + 4..].trim_end().to_string();
+    (status, body)
+}
+
+fn get(addr: SocketAddr, path: &str) -> (u16, String) {
+    request(addr, &format!("GET {} HTTP/1.1\r\nHost: door\r\n\r\n", path))
+}
+
+fn post(addr: SocketAddr, path: &str) -> (u16, String) {
+    request(addr, &format!("POST {} HTTP/1.1\r\nHost: door\r\nContent-Length: 2\r\n\r\n{{}}", path))
+}
+
+#[test]
+fn state_is_queried_and_cached() {
+    let options = HttpOptions {cache_ttl: Some(Duration::from_secs(60)), ..HttpOptions::default()};
+    let (server, _replay) = server(&[Some(CFF3000State::Unlocked)], options);
+    assert_eq!(get(server.local_addr(), "/state"), (200, r#"{"state":"unlocked","cached":false,"age_ms":0}"#.to_string()));
+    let (status, body) = get(server.local_addr(), "/state");
+    assert_eq!(status, 200);
+    assert!(body.starts_with(r#"{"state":"unlocked","cached":true,"age_ms":"#), "{}", body);
+}
+
+#[test]
+fn lock_and_unlock() {
+    let (server, replay) = server(&[Some(CFF3000State::Locked), Some(CFF3000State::Locked)], HttpOptions::default());
+    assert_eq!(post(server.local_addr(), "/lock?verify=true"), (200, r#"{"state":"locked","cached":false,"age_ms":0}"#.to_string()));
+    let (status, body) = post(server.local_addr(), "/unlock?verify=1");
+    assert_eq!(status, 502);
+    assert!(body.starts_with(r#"{"error":{"code":"not-confirmed","#), "{}", body);
+    let (status, body) = post(server.local_addr(), "/unlock?verify=true");
+    assert_eq!(status, 504, "{}", body);
+    assert_eq!(post(server.local_addr(), "/lock"), (202, r#"{"queued":true}"#.to_string()));
+
+    drop(server);
+    let presses: Vec<Button> = replay.transitions().iter().filter(|t| t.pressed).map(|t| t.button).collect();
+    assert_eq!(presses, vec![Button::Lock, Button::Unlock, Button::Unlock, Button::Lock]);
+}
+
+#[test]
+fn history_is_served() {
+    let (server, _replay) = server(&[Some(CFF3000State::Unlocked)], HttpOptions::default());
+    assert_eq!(get(server.local_addr(), "/history"), (200, r#"{"history":[]}"#.to_string()));
+    assert_eq!(get(server.local_addr(), "/state").0, 200);
+    let (status, body) = get(server.local_addr(), "/history");
+    assert_eq!(status, 200);
+    assert!(body.starts_with(r#"{"history":[{"timestamp_ms":"#), "{}", body);
+    assert!(body.contains(r#","command":"check","state":"unlocked","duration_ms":"#), "{}", body);
+    assert_eq!(post(server.local_addr(), "/history").0, 405);
+}
+
+#[test]
+fn bearer_token_is_required() {
+    let options = HttpOptions {token: Some("secret".to_string()), ..HttpOptions::default()};
+    let (server, replay) = server(&[Some(CFF3000State::Locked)], options);
+    let addr = server.local_addr();
+
+    assert_eq!(post(addr, "/lock?verify=true").0, 401);
+    let wrong = request(addr, "POST /lock HTTP/1.1\r\nAuthorization: Bearer secreT\r\n\r\n");
+    assert_eq!(wrong.0, 401);
+    assert!(replay.transitions().is_empty());
+
+    let right = request(addr, "POST /lock?verify=true HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n");
+    assert_eq!(right.0, 200);
+    let (status, body) = get(addr, "/healthz");
+    assert_eq!(status, 200);
+    assert!(body.starts_with(r#"{"verdict":"healthy","reasons":[],"last_state_age_ms":"#), "{}", body);
+    assert!(body.ends_with(r#","queue_depth":0}"#), "{}", body);
+}
+
+#[test]
+fn unknown_requests_are_rejected() {
+    let device = Arc::new(testing::device(&Replay::new()).build().unwrap());
+    let server = serve(device, "127.0.0.1:0").unwrap();
+    let addr = server.local_addr();
+    assert_eq!(get(addr, "/open").0, 404);
+    assert_eq!(get(addr, "/lock").0, 405);
+    assert_eq!(post(addr, "/state").0, 405);
+    assert_eq!(post(addr, "/lock?verify=maybe").0, 400);
+    assert_eq!(request(addr, "garbage\r\n\r\n").0, 400);
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn metrics_are_served() {
+    use cff3000::metrics::Metrics;
+
+    let replay = Replay::with_captures(&[Some(CFF3000State::Locked)]);
+    let metrics = Arc::new(Metrics::new());
+    let measured = testing::device(&replay).metrics(metrics).build().unwrap();
+    let server = serve(Arc::new(measured), "127.0.0.1:0").unwrap();
+    assert_eq!(get(server.local_addr(), "/state").0, 200);
+
+    let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n"), "{}", response);
+    assert!(response.contains("\ncff3000_commands_total{command=\"check\"} 1\n"), "{}", response);
+    assert!(response.contains("\ncff3000_state 0\n"), "{}", response);
+
+    /* only served for devices with metrics */
+    let device = Arc::new(testing::device(&Replay::new()).build().unwrap());
+    assert_eq!(get(serve(device, "127.0.0.1:0").unwrap().local_addr(), "/metrics").0, 404);
+}
+
+/// Open `/events` and skip the response head.
+fn events(addr: SocketAddr, query: &str) -> BufReader<TcpStream> {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(format!("GET /events{} HTTP/1.1\r\n\r\n", query).as_bytes()).unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "HTTP/1.1 200 OK\r\n");
+    while line != "\r\n" {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+    }
+    reader
+}
+
+/// Next event or comment, without the blank line ending it.
+fn next_message(reader: &mut BufReader<TcpStream>) -> String {
+    let mut message = String::new();
+    loop {
+        let mut line = String::new();
+        assert!(reader.read_line(&mut line).unwrap() > 0, "stream ended");
+        if line == "\n" {
+            return message;
+        }
+        message.push_str(&line);
+    }
+}
+
+fn change(previous: Option<CFF3000State>, current: CFF3000State) -> StateChange {
+    StateChange::new(previous, current, Trigger::Poll, UNIX_EPOCH + Duration::from_millis(1_760_000_000_123))
+}
+
+#[test]
+fn events_are_streamed_to_every_client() {
+    let options = HttpOptions {keep_alive: Duration::from_millis(50), cache_ttl: Some(Duration::from_secs(60)), ..HttpOptions::default()};
+    let (server, _replay) = server(&[], options);
+    server.publish(change(None, CFF3000State::Locked));
+
+    let mut first = events(server.local_addr(), "");
+    assert_eq!(next_message(&mut first), "retry: 5000\n");
+    assert_eq!(next_message(&mut first), "event: state\ndata: {\"state\":\"locked\",\"previous\":null,\"trigger\":\"poll\",\"at_ms\":1760000000123}\n");
+    let mut second = events(server.local_addr(), "");
+    next_message(&mut second);
+    next_message(&mut second);
+
+    server.publish(change(Some(CFF3000State::Locked), CFF3000State::Unlocked));
+    let unlocked = "event: state\ndata: {\"state\":\"unlocked\",\"previous\":\"locked\",\"trigger\":\"poll\",\"at_ms\":1760000000123}\n";
+    for reader in [&mut first, &mut second] {
+        let mut message = next_message(reader);
+        while message == ": keep-alive\n" {
+            message = next_message(reader);
+        }
+        assert_eq!(message, unlocked);
+    }
+    assert_eq!(next_message(&mut first), ": keep-alive\n");
+    assert_eq!(server.event_clients(), 2);
+
+    /* the keep-alive notices the closed connection, the next change forgets it */
+    drop(first);
+    std::thread::sleep(Duration::from_millis(300));
+    server.publish(change(Some(CFF3000State::Unlocked), CFF3000State::Locked));
+    assert_eq!(server.event_clients(), 1);
+    let (status, body) = get(server.local_addr(), "/state");
+    assert_eq!(status, 200);
+    assert!(body.starts_with(r#"{"state":"locked""#), "{}", body);
+}
+
+#[test]
+fn events_accept_the_token_as_query() {
+    let options = HttpOptions {token: Some("secret".to_string()), ..HttpOptions::default()};
+    let (server, _replay) = server(&[], options);
+    assert_eq!(get(server.local_addr(), "/events").0, 401);
+    assert_eq!(get(server.local_addr(), "/state?access_token=secret").0, 401);
+    let mut reader = events(server.local_addr(), "?access_token=secret");
+    assert_eq!(next_message(&mut reader), "retry: 5000\n");
+}
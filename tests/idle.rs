@@ -0,0 +1,78 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Idle waits of the activity listener, see "Idle waits" of
+//! `cff3000::backend`.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::Waker;
+use std::time::{Duration, Instant};
+
+use cff3000::activity::ActivityOptions;
+use cff3000::mock::MockBackend;
+use cff3000::{Button, CFF3000Builder, GpioBackend, Led, LedEvent, StopToken};
+
+/// Counts the waits for LED events of `inner` which may block, leaving
+/// out the ones reading what is pending.
+struct Counting {
+    inner: MockBackend,
+    waits: Arc<AtomicUsize>,
+}
+
+impl GpioBackend for Counting {
+    fn set_button(&self, button: Button, pressed: bool) -> std::io::Result<()> {
+        self.inner.set_button(button, pressed)
+    }
+
+    fn wait_for_led_events(&self, timeout: Duration) -> std::io::Result<u8> {
+        if timeout != Duration::from_millis(0) {
+            self.waits.fetch_add(1, Ordering::SeqCst);
+        }
+        self.inner.wait_for_led_events(timeout)
+    }
+
+    fn read_led_event(&self, led: Led) -> std::io::Result<LedEvent> {
+        self.inner.read_led_event(led)
+    }
+
+    fn flush_led_events(&self) -> std::io::Result<()> {
+        self.inner.flush_led_events()
+    }
+
+    fn waker(&self) -> Option<Waker> {
+        self.inner.waker()
+    }
+}
+
+/// Waits of a listener stopped after `idle`, and how long the stop took.
+fn listen(options: ActivityOptions, idle: Duration) -> (usize, Duration) {
+    let waits = Arc::new(AtomicUsize::new(0));
+    let backend = Counting {inner: MockBackend::new(), waits: waits.clone()};
+    let device = CFF3000Builder::with_backend(backend).monitor(|_| {}).build().unwrap();
+    let stop = StopToken::new();
+    let stopper = stop.clone();
+    let stopped = std::thread::spawn(move || {
+        std::thread::sleep(idle);
+        stopper.stop();
+        Instant::now()
+    });
+    device.listen_for_activity_with(&stop, &options).unwrap();
+    let returned = Instant::now();
+    (waits.load(Ordering::SeqCst), returned - stopped.join().unwrap())
+}
+
+#[test]
+fn idle_listeners_wait_once() {
+    let (waits, latency) = listen(ActivityOptions::default(), Duration::from_millis(1500));
+    assert_eq!(waits, 1);
+    assert!(latency < Duration::from_millis(100), "stopped after {:?}", latency);
+}
+
+#[test]
+fn keep_alive_bounds_the_wait() {
+    let options = ActivityOptions {keep_alive: Some(Duration::from_millis(300))};
+    let (waits, latency) = listen(options, Duration::from_millis(1500));
+    assert!((4..=6).contains(&waits), "{} waits", waits);
+    assert!(latency < Duration::from_millis(100), "stopped after {:?}", latency);
+}
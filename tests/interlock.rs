@@ -0,0 +1,63 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Concurrent operations on one device, see `BusyPolicy`, on the mock
+//! backend.
+
+use std::io::ErrorKind;
+use std::sync::{Arc, Barrier};
+use std::time::Duration;
+
+use cff3000::mock::{MockBackend, Script};
+use cff3000::{BusyPolicy, Button, CFF3000, CFF3000Builder, CFF3000State, Command, Timings};
+
+const CAPTURE: Duration = Duration::from_millis(300);
+
+/// Device answering checks with a short locked pattern.
+fn device(mock: &MockBackend, policy: BusyPolicy) -> CFF3000 {
+    mock.respond(Command::Check, Script::from_levels(&[(10, 0b11), (110, 0b10), (210, 0b00)]));
+    let timings = Timings {press: Duration::from_millis(50), check_capture: CAPTURE, ..Timings::default()};
+    CFF3000Builder::with_backend(mock.clone()).timings(timings).busy_policy(policy).build().unwrap()
+}
+
+/// Results of `check()` started by two threads at the same time.
+fn race(device: CFF3000) -> Vec<std::io::Result<CFF3000State>> {
+    let device = Arc::new(device);
+    let start = Arc::new(Barrier::new(2));
+    let threads: Vec<_> = (0..2).map(|_| {
+        let (device, start) = (device.clone(), start.clone());
+        std::thread::spawn(move || {
+            start.wait();
+            device.state()
+        })
+    }).collect();
+    threads.into_iter().map(|thread| thread.join().unwrap()).collect()
+}
+
+/// Transitions of one check press.
+const CHECK: [(Button, bool); 4] = [(Button::Lock, true), (Button::Unlock, true), (Button::Unlock, false), (Button::Lock, false)];
+
+#[test]
+fn waiting_operations_are_serialized() {
+    let mock = MockBackend::new();
+    for result in race(device(&mock, BusyPolicy::Wait)) {
+        assert_eq!(result.unwrap(), CFF3000State::Locked);
+    }
+
+    let transitions = mock.transitions();
+    let levels: Vec<(Button, bool)> = transitions.iter().map(|t| (t.button, t.pressed)).collect();
+    assert_eq!(levels, [CHECK, CHECK].concat());
+    /* the second press waits for the capture of the first */
+    assert!(transitions[4].at - transitions[3].at >= CAPTURE, "{:?}", transitions);
+}
+
+#[test]
+fn fail_fast_operations_are_refused() {
+    let mock = MockBackend::new();
+    let results = race(device(&mock, BusyPolicy::FailFast));
+    assert_eq!(results.iter().filter(|result| matches!(result, Ok(CFF3000State::Locked))).count(), 1, "{:?}", results);
+    assert_eq!(results.iter().filter(|result| matches!(result, Err(err) if err.kind() == ErrorKind::WouldBlock)).count(), 1, "{:?}", results);
+
+    let levels: Vec<(Button, bool)> = mock.transitions().iter().map(|t| (t.button, t.pressed)).collect();
+    assert_eq!(levels, CHECK);
+}
@@ -0,0 +1,110 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! `journald::Journal` entries of a device on the replay backend.
+
+use std::collections::HashMap;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use cff3000::journald::Journal;
+use cff3000::testing::{self, Replay};
+use cff3000::{BusyPolicy, CFF3000State, CFF3000};
+
+/// Listening socket at a fresh path, removed on drop.
+struct Listener {
+    path: PathBuf,
+    socket: UnixDatagram,
+}
+
+impl Listener {
+    fn new(name: &str) -> Listener {
+        let path = std::env::temp_dir().join(format!("cff3000-journald-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket.set_nonblocking(true).unwrap();
+        Listener {path, socket}
+    }
+
+    /// Fields of the entries sent so far.
+    fn entries(&self) -> Vec<HashMap<String, String>> {
+        let mut entries = Vec::new();
+        let mut buf = vec![0u8; 4096];
+        while let Ok(len) = self.socket.recv(&mut buf) {
+            entries.push(String::from_utf8(buf[..len].to_vec()).unwrap().lines()
+                .map(|line| {
+                    let (key, value) = line.split_at(line.find('=').unwrap());
+                    (key.to_string(), value[1..].to_string())
+                })
+                .collect());
+        }
+        entries
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn device(journal: Arc<Journal>, captures: &[Option<CFF3000State>]) -> CFF3000 {
+    testing::device(&Replay::with_captures(captures)).busy_policy(BusyPolicy::FailFast).journal(journal).build().unwrap()
+}
+
+fn field<'a>(entry: &'a HashMap<String, String>, key: &str) -> Option<&'a str> {
+    entry.get(key).map(String::as_str)
+}
+
+#[test]
+fn events_have_structured_fields() {
+    let listener = Listener::new("events");
+    let journal = Arc::new(Journal::with_socket(&listener.path).unwrap());
+    let cff3000 = device(journal.clone(), &[Some(CFF3000State::Locked), Some(CFF3000State::OutOfRange), None]);
+
+    assert_eq!(cff3000.lock_and_verify().unwrap(), CFF3000State::Locked);
+    assert_eq!(cff3000.state().unwrap(), CFF3000State::OutOfRange);
+    assert!(cff3000.unlock_and_verify().is_err());
+    let entries = listener.entries();
+    let events: Vec<(&str, &str)> = entries.iter().map(|entry| (field(entry, "CFF3000_EVENT").unwrap(), field(entry, "CFF3000_COMMAND").unwrap())).collect();
+    assert_eq!(events, vec![("command", "lock"), ("state", "lock"), ("command", "check"), ("state", "check"), ("command", "unlock"), ("failure", "unlock")]);
+
+    assert_eq!(field(&entries[0], "MESSAGE"), Some("lock: buttons pressed"));
+    assert_eq!(field(&entries[0], "PRIORITY"), Some("6"));
+    assert_eq!(field(&entries[0], "SYSLOG_IDENTIFIER"), Some("cff3000"));
+    assert_eq!(field(&entries[0], "CFF3000_STATE"), None);
+    assert_eq!(field(&entries[3], "CFF3000_STATE"), Some("out-of-range"));
+    assert_eq!(field(&entries[3], "MESSAGE"), Some("check: out-of-range"));
+    assert_eq!(field(&entries[5], "PRIORITY"), Some("4"));
+    assert_eq!(field(&entries[5], "CFF3000_ERROR_CODE"), Some("no-response"));
+    assert_eq!(field(&entries[5], "MESSAGE"), Some("unlock failed: did not receive enough LED change events"));
+    assert_eq!(journal.dropped(), 0);
+}
+
+#[test]
+fn refused_operations_are_failures_only() {
+    let listener = Listener::new("refused");
+    let cff3000 = device(Arc::new(Journal::with_socket(&listener.path).unwrap()), &[]);
+
+    let press = cff3000.begin_lock_press().unwrap();
+    assert!(cff3000.state().is_err());
+    assert!(cff3000.unlock().is_err());
+    drop(press);
+    let entries = listener.entries();
+    let events: Vec<(&str, &str, Option<&str>)> = entries.iter()
+        .map(|entry| (field(entry, "CFF3000_EVENT").unwrap(), field(entry, "CFF3000_COMMAND").unwrap(), field(entry, "CFF3000_ERROR_CODE")))
+        .collect();
+    assert_eq!(events, vec![("command", "lock", None), ("failure", "check", Some("busy")), ("failure", "unlock", Some("busy"))]);
+}
+
+#[test]
+fn missing_journal_is_ignored() {
+    let path = std::env::temp_dir().join(format!("cff3000-journald-missing-{}", std::process::id()));
+    let journal = Arc::new(Journal::with_socket(&path).unwrap());
+    let cff3000 = device(journal.clone(), &[Some(CFF3000State::Unlocked)]);
+
+    assert_eq!(cff3000.unlock_and_verify().unwrap(), CFF3000State::Unlocked);
+    cff3000.lock().unwrap();
+    assert_eq!(journal.dropped(), 3);
+}
@@ -0,0 +1,98 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Code written against `LockControl` driving a `CFF3000` and
+//! `testing::FakeLock` alike.
+
+use std::io::ErrorKind;
+use std::sync::Arc;
+
+use cff3000::testing::{self, FakeLock, Replay};
+use cff3000::{CFF3000State, Command, CommandQueue, LockControl, QueueOptions};
+
+/// Automation code knowing only the trait.
+fn lock_if_unlocked<L: LockControl>(door: &L) -> std::io::Result<bool> {
+    match door.state()? {
+        CFF3000State::Unlocked => Ok(door.lock_and_verify()? == CFF3000State::Locked),
+        _ => Ok(false),
+    }
+}
+
+#[test]
+fn locks_share_the_automation() {
+    let replay = Replay::with_captures(&[Some(CFF3000State::Unlocked), Some(CFF3000State::Locked)]);
+    let device = testing::device(&replay).build().unwrap();
+    assert!(lock_if_unlocked(&device).unwrap());
+
+    let fake = FakeLock::new(CFF3000State::Unlocked);
+    assert!(lock_if_unlocked(&fake).unwrap());
+    assert!(!lock_if_unlocked(&fake).unwrap());
+    assert_eq!(fake.commands(), vec![
+        (Command::Check, String::new()),
+        (Command::Lock, String::new()),
+        (Command::Check, String::new()),
+    ]);
+}
+
+#[test]
+fn fake_locks_fail_on_request() {
+    let fake = FakeLock::new(CFF3000State::Locked);
+    fake.fail(Some(ErrorKind::TimedOut));
+    assert_eq!(fake.unlock_and_verify().unwrap_err().kind(), ErrorKind::TimedOut);
+    fake.fail(None);
+    assert_eq!(fake.state().unwrap(), CFF3000State::Locked);
+    fake.set_state(CFF3000State::Manual);
+    assert_eq!(fake.execute(Command::Check).unwrap(), CFF3000State::Manual);
+    assert_eq!(fake.execute(Command::Unlock).unwrap(), CFF3000State::Unlocked);
+}
+
+#[test]
+fn queues_drive_any_lock() {
+    let fake = FakeLock::new(CFF3000State::Unlocked);
+    let queue = CommandQueue::for_lock(Arc::new(fake.clone()), QueueOptions::default()).unwrap();
+    let sender = queue.sender();
+    assert_eq!(sender.send_as(Command::Lock, "kitchen").recv().unwrap().unwrap(), CFF3000State::Locked);
+    fake.fail(Some(ErrorKind::WouldBlock));
+    assert_eq!(sender.send(Command::Unlock).recv().unwrap().unwrap_err().kind(), ErrorKind::WouldBlock);
+    assert_eq!(fake.commands(), vec![(Command::Lock, "kitchen".to_string()), (Command::Unlock, String::new())]);
+
+    /* also behind a trait object */
+    let door: Arc<dyn LockControl + Send + Sync> = Arc::new(FakeLock::new(CFF3000State::Locked));
+    let queue = CommandQueue::for_lock(door, QueueOptions::default()).unwrap();
+    assert_eq!(queue.sender().send(Command::Check).recv().unwrap().unwrap(), CFF3000State::Locked);
+}
+
+#[cfg(feature = "http")]
+#[test]
+fn http_serves_any_lock() {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::time::Duration;
+
+    use cff3000::http::{serve_lock, HttpOptions};
+
+    fn request(addr: SocketAddr, head: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(format!("{} HTTP/1.1\r\nHost: door\r\nContent-Length: 0\r\n\r\n", head).as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        (status, response[response.find("\r\n\r\n").unwrap() + 4..].trim_end().to_string())
+    }
+
+    let fake = FakeLock::new(CFF3000State::Unlocked);
+    let server = serve_lock(Arc::new(fake.clone()), "127.0.0.1:0", HttpOptions::default()).unwrap();
+    let addr = server.local_addr();
+    assert_eq!(request(addr, "GET /state"), (200, r#"{"state":"unlocked","cached":false,"age_ms":0}"#.to_string()));
+    assert_eq!(request(addr, "POST /lock?verify=true"), (200, r#"{"state":"locked","cached":false,"age_ms":0}"#.to_string()));
+    assert_eq!(fake.commands().last().map(|&(command, _)| command), Some(Command::Lock));
+    assert_eq!(request(addr, "GET /healthz"), (200, r#"{"verdict":"healthy","reasons":[],"queue_depth":0}"#.to_string()));
+    assert_eq!(request(addr, "GET /history").0, 404);
+    assert_eq!(request(addr, "GET /metrics").0, 404);
+
+    fake.fail(Some(ErrorKind::WouldBlock));
+    assert_eq!(request(addr, "POST /unlock?verify=true").0, 503);
+    fake.fail(Some(ErrorKind::Other));
+    assert_eq!(request(addr, "GET /state").0, 500);
+}
@@ -0,0 +1,61 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! `CFF3000Builder::exclusive_lockfile`: exclusion, release on drop and
+//! the takeover of stale lock files.
+
+#![cfg(unix)]
+
+use std::io;
+use std::path::PathBuf;
+
+use cff3000::mock::MockBackend;
+use cff3000::{AlreadyInUse, CFF3000, CFF3000Builder};
+
+fn path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("cff3000-lockfile-{}-{}.lock", std::process::id(), name))
+}
+
+fn open(path: &PathBuf) -> io::Result<CFF3000> {
+    CFF3000Builder::with_backend(MockBackend::new()).exclusive_lockfile(path).build()
+}
+
+fn recorded_pid(path: &PathBuf) -> String {
+    std::fs::read_to_string(path).unwrap().trim().to_string()
+}
+
+#[test]
+fn second_device_is_refused() {
+    let path = path("refused");
+    let _first = open(&path).unwrap();
+
+    let err = match open(&path) {
+        Ok(_) => panic!("second device opened"),
+        Err(err) => err,
+    };
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+    let in_use = err.get_ref().and_then(|e| e.downcast_ref::<AlreadyInUse>());
+    assert_eq!(in_use, Some(&AlreadyInUse {path: path.clone(), pid: Some(std::process::id())}));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn lock_is_released_on_drop() {
+    let path = path("released");
+    drop(open(&path).unwrap());
+
+    let _again = open(&path).unwrap();
+    assert_eq!(recorded_pid(&path), std::process::id().to_string());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn stale_lock_file_is_taken_over() {
+    /* left behind by a crashed process, nobody holds the flock */
+    let path = path("stale");
+    std::fs::write(&path, "4294967295\n").unwrap();
+
+    let _device = open(&path).unwrap();
+    assert_eq!(recorded_pid(&path), std::process::id().to_string());
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,79 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Records of the GPIO operations for the `log` facade, on the replay
+//! backend.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use cff3000::testing::{self, generate, PatternParams, Replay};
+use cff3000::CFF3000State;
+
+/// Records of the `cff3000` targets, "LEVEL message".
+static RECORDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+struct Recorder;
+
+impl log::Log for Recorder {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.target().starts_with("cff3000")
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            RECORDS.lock().unwrap().push(format!("{} {}", record.level(), record.args()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Records so far, once one of them starts with `last` or after a few
+/// seconds.
+fn records(last: &str) -> Vec<String> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let records = RECORDS.lock().unwrap().clone();
+        if records.iter().any(|record| record.starts_with(last)) || Instant::now() > deadline {
+            return records;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn gpio_operations_are_logged() {
+    log::set_logger(&Recorder).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+
+    let replay = Replay::with_captures(&[Some(CFF3000State::Locked)]);
+    let device = testing::device(&replay).build().unwrap();
+    assert_eq!(device.state().unwrap(), CFF3000State::Locked);
+
+    /* the buttons are released on the timer thread of the presses */
+    let records = records("DEBUG set button lock to 0");
+    let messages = |prefix: &str| -> Vec<String> { records.iter().filter(|record| record.starts_with(prefix)).cloned().collect() };
+    let sets = messages("DEBUG set ");
+    let lines: Vec<&str> = sets.iter().map(|record| &record[..record.find(" in ").unwrap()]).collect();
+    assert_eq!(lines, vec!["DEBUG set button lock to 1", "DEBUG set button unlock to 1", "DEBUG set button unlock to 0", "DEBUG set button lock to 0"]);
+    assert_eq!(messages("DEBUG waiting"), vec![format!("DEBUG waiting {:?} for LED events", cff3000::Timings::default().capture_for(cff3000::Command::Check))]);
+
+    /* one capture, within the default bound of the dump */
+    let events = messages("TRACE event ");
+    assert_eq!(events.len(), generate(CFF3000State::Locked, PatternParams::default()).len());
+    assert!(events[0].starts_with("TRACE event 1: LED red on at "), "{:?}", events);
+    assert!(events[0].ends_with(" ns (+0ns)"), "{:?}", events);
+    assert!(messages("TRACE events ").is_empty());
+
+    /* the spans of the tracing feature */
+    #[cfg(not(feature = "tracing"))]
+    {
+        let spans = messages("DEBUG state took ");
+        assert_eq!(spans.len(), 1);
+        let press = cff3000::Timings::default().press_for(cff3000::Command::Check).as_millis();
+        assert!(spans[0].ends_with(&format!(" (press_ms={}, events={}, state=locked)", press, events.len())), "{:?}", spans);
+        let parse = messages("DEBUG parse took ");
+        assert!(parse[0].ends_with(&format!(" (events={}, state=locked)", events.len())), "{:?}", parse);
+    }
+}
@@ -0,0 +1,242 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! `Metrics` of a device on the replay backend.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use cff3000::metrics::Metrics;
+use cff3000::testing::{self, Replay};
+use cff3000::{BusyPolicy, Button, CFF3000Builder, CFF3000State, CFF3000, EventBuffer, GpioBackend, Led, LedEvent, ParseOptions};
+
+fn device(captures: &[Option<CFF3000State>]) -> (CFF3000, Arc<Metrics>) {
+    let metrics = Arc::new(Metrics::new());
+    let device = testing::device(&Replay::with_captures(captures))
+        .busy_policy(BusyPolicy::FailFast)
+        .metrics(metrics.clone())
+        .build()
+        .unwrap();
+    (device, metrics)
+}
+
+/// `encode()` without the wall clock dependent timestamp value.
+fn encode(metrics: &Metrics) -> String {
+    metrics.encode().lines().map(|line| match line.starts_with("cff3000_last_success_timestamp_seconds ") {
+        true => "cff3000_last_success_timestamp_seconds <now>\n".to_string(),
+        false => format!("{}\n", line),
+    }).collect()
+}
+
+#[test]
+fn names_snapshot() {
+    assert_eq!(Metrics::new().encode(), "\
+# HELP cff3000_commands_total Button presses and state queries started.
+# TYPE cff3000_commands_total counter
+cff3000_commands_total{command=\"lock\"} 0
+cff3000_commands_total{command=\"unlock\"} 0
+cff3000_commands_total{command=\"check\"} 0
+# HELP cff3000_command_failures_total Failed button presses and state queries.
+# TYPE cff3000_command_failures_total counter
+# HELP cff3000_state Last state read: 0 locked, 1 unlocked, 2 manual, 3 out-of-range.
+# TYPE cff3000_state gauge
+# HELP cff3000_query_duration_seconds Duration of completed state queries including the press.
+# TYPE cff3000_query_duration_seconds histogram
+# HELP cff3000_led_feedback_latency_seconds Time from releasing the buttons until the first LED event is read.
+# TYPE cff3000_led_feedback_latency_seconds histogram
+cff3000_led_feedback_latency_seconds_bucket{le=\"0.01\"} 0
+cff3000_led_feedback_latency_seconds_bucket{le=\"0.025\"} 0
+cff3000_led_feedback_latency_seconds_bucket{le=\"0.05\"} 0
+cff3000_led_feedback_latency_seconds_bucket{le=\"0.1\"} 0
+cff3000_led_feedback_latency_seconds_bucket{le=\"0.25\"} 0
+cff3000_led_feedback_latency_seconds_bucket{le=\"0.5\"} 0
+cff3000_led_feedback_latency_seconds_bucket{le=\"1\"} 0
+cff3000_led_feedback_latency_seconds_bucket{le=\"2.5\"} 0
+cff3000_led_feedback_latency_seconds_bucket{le=\"5\"} 0
+cff3000_led_feedback_latency_seconds_bucket{le=\"+Inf\"} 0
+cff3000_led_feedback_latency_seconds_sum 0
+cff3000_led_feedback_latency_seconds_count 0
+# HELP cff3000_last_success_timestamp_seconds Unix time of the last successful state query.
+# TYPE cff3000_last_success_timestamp_seconds gauge
+");
+}
+
+#[test]
+fn operations_snapshot() {
+    let (device, metrics) = device(&[Some(CFF3000State::Unlocked), None, Some(CFF3000State::Locked)]);
+    assert_eq!(device.state().unwrap(), CFF3000State::Unlocked);
+    assert!(device.state().is_err());
+    assert_eq!(device.lock_and_verify().unwrap(), CFF3000State::Locked);
+    device.unlock().unwrap();
+    /* the unlock is a plain press, which has no duration sample */
+    assert_eq!(encode(&metrics), "\
+# HELP cff3000_commands_total Button presses and state queries started.
+# TYPE cff3000_commands_total counter
+cff3000_commands_total{command=\"lock\"} 1
+cff3000_commands_total{command=\"unlock\"} 1
+cff3000_commands_total{command=\"check\"} 2
+# HELP cff3000_command_failures_total Failed button presses and state queries.
+# TYPE cff3000_command_failures_total counter
+cff3000_command_failures_total{command=\"check\",code=\"no-response\"} 1
+# HELP cff3000_state Last state read: 0 locked, 1 unlocked, 2 manual, 3 out-of-range.
+# TYPE cff3000_state gauge
+cff3000_state 0
+# HELP cff3000_query_duration_seconds Duration of completed state queries including the press.
+# TYPE cff3000_query_duration_seconds histogram
+cff3000_query_duration_seconds_bucket{command=\"check\",le=\"2\"} 0
+cff3000_query_duration_seconds_bucket{command=\"check\",le=\"4\"} 0
+cff3000_query_duration_seconds_bucket{command=\"check\",le=\"6\"} 0
+cff3000_query_duration_seconds_bucket{command=\"check\",le=\"8\"} 0
+cff3000_query_duration_seconds_bucket{command=\"check\",le=\"9\"} 2
+cff3000_query_duration_seconds_bucket{command=\"check\",le=\"10\"} 2
+cff3000_query_duration_seconds_bucket{command=\"check\",le=\"11\"} 2
+cff3000_query_duration_seconds_bucket{command=\"check\",le=\"12\"} 2
+cff3000_query_duration_seconds_bucket{command=\"check\",le=\"15\"} 2
+cff3000_query_duration_seconds_bucket{command=\"check\",le=\"20\"} 2
+cff3000_query_duration_seconds_bucket{command=\"check\",le=\"+Inf\"} 2
+cff3000_query_duration_seconds_sum{command=\"check\"} 17
+cff3000_query_duration_seconds_count{command=\"check\"} 2
+cff3000_query_duration_seconds_bucket{command=\"lock\",le=\"2\"} 0
+cff3000_query_duration_seconds_bucket{command=\"lock\",le=\"4\"} 0
+cff3000_query_duration_seconds_bucket{command=\"lock\",le=\"6\"} 0
+cff3000_query_duration_seconds_bucket{command=\"lock\",le=\"8\"} 0
+cff3000_query_duration_seconds_bucket{command=\"lock\",le=\"9\"} 0
+cff3000_query_duration_seconds_bucket{command=\"lock\",le=\"10\"} 0
+cff3000_query_duration_seconds_bucket{command=\"lock\",le=\"11\"} 1
+cff3000_query_duration_seconds_bucket{command=\"lock\",le=\"12\"} 1
+cff3000_query_duration_seconds_bucket{command=\"lock\",le=\"15\"} 1
+cff3000_query_duration_seconds_bucket{command=\"lock\",le=\"20\"} 1
+cff3000_query_duration_seconds_bucket{command=\"lock\",le=\"+Inf\"} 1
+cff3000_query_duration_seconds_sum{command=\"lock\"} 10.5
+cff3000_query_duration_seconds_count{command=\"lock\"} 1
+# HELP cff3000_led_feedback_latency_seconds Time from releasing the buttons until the first LED event is read.
+# TYPE cff3000_led_feedback_latency_seconds histogram
+cff3000_led_feedback_latency_seconds_bucket{le=\"0.01\"} 0
+cff3000_led_feedback_latency_seconds_bucket{le=\"0.025\"} 0
+cff3000_led_feedback_latency_seconds_bucket{le=\"0.05\"} 0
+cff3000_led_feedback_latency_seconds_bucket{le=\"0.1\"} 0
+cff3000_led_feedback_latency_seconds_bucket{le=\"0.25\"} 2
+cff3000_led_feedback_latency_seconds_bucket{le=\"0.5\"} 2
+cff3000_led_feedback_latency_seconds_bucket{le=\"1\"} 2
+cff3000_led_feedback_latency_seconds_bucket{le=\"2.5\"} 2
+cff3000_led_feedback_latency_seconds_bucket{le=\"5\"} 2
+cff3000_led_feedback_latency_seconds_bucket{le=\"+Inf\"} 2
+cff3000_led_feedback_latency_seconds_sum 0.4
+cff3000_led_feedback_latency_seconds_count 2
+# HELP cff3000_last_success_timestamp_seconds Unix time of the last successful state query.
+# TYPE cff3000_last_success_timestamp_seconds gauge
+cff3000_last_success_timestamp_seconds <now>
+");
+}
+
+#[test]
+fn refused_queries_are_failures() {
+    let (device, metrics) = device(&[]);
+    let press = device.begin_lock_press().unwrap();
+    assert!(device.state().is_err());
+    assert!(device.unlock().is_err());
+    drop(press);
+    let text = metrics.encode();
+    assert!(text.contains("cff3000_command_failures_total{command=\"check\",code=\"busy\"} 1\n"), "{}", text);
+    assert!(text.contains("cff3000_command_failures_total{command=\"unlock\",code=\"busy\"} 1\n"), "{}", text);
+    /* refused queries took no time worth a sample */
+    assert!(!text.contains("cff3000_query_duration_seconds_count"), "{}", text);
+    assert!(!text.contains("\ncff3000_state "), "{}", text);
+}
+
+#[test]
+fn snapshots_carry_the_numbers() {
+    let (device, metrics) = device(&[Some(CFF3000State::Unlocked), None, Some(CFF3000State::Locked)]);
+    let empty = metrics.snapshot();
+    assert_eq!(empty.commands.values().sum::<u64>(), 0);
+    assert_eq!(empty.state, None);
+    assert_eq!(empty.last_success, None);
+    assert!(empty.query_duration.is_empty());
+
+    assert_eq!(device.state().unwrap(), CFF3000State::Unlocked);
+    assert!(device.state().is_err());
+    assert_eq!(device.lock_and_verify().unwrap(), CFF3000State::Locked);
+    device.unlock().unwrap();
+
+    let snapshot = metrics.snapshot();
+    let counts = |map: &BTreeMap<&'static str, u64>| -> Vec<(&'static str, u64)> { map.iter().map(|(&name, &count)| (name, count)).collect() };
+    assert_eq!(counts(&snapshot.commands), vec![("check", 2), ("lock", 1), ("unlock", 1)]);
+    assert_eq!(counts(&snapshot.successes), vec![("check", 1), ("lock", 1), ("unlock", 1)]);
+    assert_eq!(counts(&snapshot.failures["check"]), vec![("no-response", 1)]);
+    assert_eq!(snapshot.failures.len(), 1);
+    assert_eq!(snapshot.state, Some(CFF3000State::Locked));
+    assert!(snapshot.last_success.is_some());
+    assert_eq!(snapshot.last_durations["lock"], Duration::from_millis(10500));
+    assert_eq!(snapshot.last_durations.get("unlock"), None);
+    assert_eq!(snapshot.query_duration["check"].count, 2);
+    assert_eq!(snapshot.query_duration["check"].counts.iter().sum::<u64>(), 2);
+    assert_eq!(snapshot.feedback_latency.count, 2);
+    assert!(snapshot.uptime >= empty.uptime);
+
+    /* the Prometheus text is rendered from the snapshot */
+    assert_eq!(snapshot.encode(), metrics.encode());
+}
+
+/// Replay whose reads of LED events take `READ` of virtual time.
+struct SlowReads(Replay);
+
+const READ: Duration = Duration::from_millis(300);
+
+impl GpioBackend for SlowReads {
+    fn set_button(&self, button: Button, pressed: bool) -> std::io::Result<()> {
+        self.0.set_button(button, pressed)
+    }
+
+    fn wait_for_led_events(&self, timeout: Duration) -> std::io::Result<u8> {
+        self.0.wait_for_led_events(timeout)
+    }
+
+    fn read_led_event(&self, led: Led) -> std::io::Result<LedEvent> {
+        self.0.advance(READ);
+        self.0.read_led_event(led)
+    }
+
+    fn read_led_events(&self, led: Led, events: &mut EventBuffer) -> std::io::Result<usize> {
+        self.0.advance(READ);
+        self.0.read_led_events(led, events)
+    }
+
+    fn flush_led_events(&self) -> std::io::Result<()> {
+        self.0.flush_led_events()
+    }
+
+    fn parse_options(&self) -> ParseOptions {
+        self.0.parse_options()
+    }
+}
+
+#[test]
+fn latency_includes_the_first_read() {
+    let replay = Replay::with_captures(&[Some(CFF3000State::Locked)]);
+    let metrics = Arc::new(Metrics::new());
+    let device = CFF3000Builder::with_backend(SlowReads(replay.clone())).clock(replay.clock()).metrics(metrics.clone()).build().unwrap();
+    assert_eq!(device.state().unwrap(), CFF3000State::Locked);
+
+    /* the first LED change comes 200 ms after the release, see operations_snapshot() */
+    let latency = metrics.snapshot().feedback_latency;
+    assert_eq!(latency.count, 1);
+    assert!(latency.sum >= 0.2 + READ.as_secs_f64(), "{}", latency.sum);
+}
+
+#[cfg(feature = "config")]
+#[test]
+fn snapshots_serialize() {
+    let (device, metrics) = device(&[Some(CFF3000State::OutOfRange)]);
+    assert_eq!(device.state().unwrap(), CFF3000State::OutOfRange);
+    let text = toml::to_string(&metrics.snapshot()).unwrap();
+    let value: toml::Value = text.parse().unwrap();
+    assert_eq!(value["state"].as_str(), Some("out-of-range"));
+    assert_eq!(value["commands"]["check"].as_integer(), Some(1));
+    assert_eq!(value["successes"]["lock"].as_integer(), Some(0));
+    assert!(value["last_durations_ms"]["check"].as_integer().unwrap() > 0);
+    assert!(value.get("uptime_ms").is_some());
+    assert!(value["last_success_ms"].as_integer().unwrap() > 1_500_000_000_000);
+    assert_eq!(value["query_duration"]["check"]["count"].as_integer(), Some(1));
+    assert_eq!(value["feedback_latency"]["bounds"].as_array().unwrap().len(), cff3000::metrics::FEEDBACK_LATENCY_BUCKETS.len());
+}
@@ -0,0 +1,458 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! `MqttPublisher` against a minimal in-process MQTT 3.1.1 broker.
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use cff3000::mqtt::{discovery_config, parse_command, Convention, Discovery, Homie, MqttOptions, MqttPublisher, TlsConfig, DEFAULT_AVAILABILITY_TOPIC,
+                    DEFAULT_COMMAND_TOPIC, DEFAULT_STATE_TOPIC};
+use cff3000::testing::{self, Replay};
+use cff3000::{Button, CFF3000State, Command, CommandQueue, StateChange, Trigger};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What the broker has seen.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Seen {
+    /// CONNECT with the Last Will (topic, payload, retain) and the
+    /// user name and password
+    Connect {will: Option<(String, String, bool)>, credentials: Option<(String, String)>},
+    /// PUBLISH: topic, payload, retain
+    Publish(String, String, bool),
+    Subscribe(String),
+    Disconnect,
+}
+
+fn publish(topic: &str, payload: &str) -> Seen {
+    Seen::Publish(topic.to_string(), payload.to_string(), true)
+}
+
+/// Read one packet: the first header byte and the body.
+fn read_packet(stream: &mut TcpStream) -> std::io::Result<(u8, Vec<u8>)> {
+    let mut byte = [0u8; 1];
+    stream.read_exact(&mut byte)?;
+    let header = byte[0];
+    let (mut len, mut shift) = (0usize, 0);
+    loop {
+        stream.read_exact(&mut byte)?;
+        len |= ((byte[0] & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body)?;
+    Ok((header, body))
+}
+
+/// Length prefixed field at `pos`.
+fn field(body: &[u8], pos: &mut usize) -> String {
+    let len = (body[*pos] as usize) << 8 | body[*pos + 1] as usize;
+    let text = String::from_utf8(body[*pos + 2..*pos + 2 + len].to_vec()).unwrap();
+    *pos += 2 + len;
+    text
+}
+
+/// Serve one client, closing the connection after `publishes` PUBLISH
+/// packets if given. `commands` (payload, retain) are published to the
+/// client once it subscribes. A non-zero `return_code` refuses the
+/// connection.
+fn serve(mut stream: TcpStream, seen: &mpsc::Sender<Seen>, publishes: Option<usize>, commands: &[(&str, bool)], return_code: u8) {
+    let mut count = 0;
+    while let Ok((header, body)) = read_packet(&mut stream) {
+        match header >> 4 {
+            1 => {
+                /* protocol name, level, flags, keep alive, client id */
+                let mut pos = 0;
+                field(&body, &mut pos);
+                let flags = body[pos + 1];
+                pos += 4;
+                field(&body, &mut pos);
+                let will = match flags & 0x04 != 0 {
+                    true => Some((field(&body, &mut pos), field(&body, &mut pos), flags & 0x20 != 0)),
+                    false => None,
+                };
+                let credentials = match flags & 0x80 != 0 {
+                    true => Some((field(&body, &mut pos), if flags & 0x40 != 0 {field(&body, &mut pos)} else {String::new()})),
+                    false => None,
+                };
+                let _ = seen.send(Seen::Connect {will, credentials});
+                let _ = stream.write_all(&[0x20, 0x02, 0x00, return_code]);
+                if return_code != 0 {
+                    return;
+                }
+            },
+            3 => {
+                let mut pos = 0;
+                let topic = field(&body, &mut pos);
+                if header & 0x06 != 0 {
+                    let _ = stream.write_all(&[0x40, 0x02, body[pos], body[pos + 1]]);
+                    pos += 2;
+                }
+                let payload = String::from_utf8(body[pos..].to_vec()).unwrap();
+                let _ = seen.send(Seen::Publish(topic, payload, header & 0x01 != 0));
+                count += 1;
+                if publishes == Some(count) {
+                    return;
+                }
+            },
+            8 => {
+                /* packet id, one topic filter with its QoS */
+                let mut pos = 2;
+                let _ = seen.send(Seen::Subscribe(field(&body, &mut pos)));
+                let _ = stream.write_all(&[0x90, 0x03, body[0], body[1], 0x01]);
+                for &(payload, retain) in commands {
+                    let topic = DEFAULT_COMMAND_TOPIC.as_bytes();
+                    let mut packet = vec![0x30 | retain as u8, (2 + topic.len() + payload.len()) as u8, 0, topic.len() as u8];
+                    packet.extend_from_slice(topic);
+                    packet.extend_from_slice(payload.as_bytes());
+                    let _ = stream.write_all(&packet);
+                }
+            },
+            12 => {let _ = stream.write_all(&[0xd0, 0x00]);},
+            14 => {
+                let _ = seen.send(Seen::Disconnect);
+                return;
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Broker on a free local port serving one client per entry of
+/// `publishes` in turn, see `serve()`.
+fn broker(publishes: Vec<Option<usize>>, commands: &'static [(&'static str, bool)]) -> (MqttOptions, mpsc::Receiver<Seen>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut options = MqttOptions::new("127.0.0.1");
+    options.port = listener.local_addr().unwrap().port();
+    options.min_backoff = Duration::from_millis(10);
+
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for limit in publishes {
+            let (stream, _) = listener.accept().unwrap();
+            serve(stream, &tx, limit, commands, 0);
+        }
+    });
+    (options, rx)
+}
+
+/// Broker on a free local port refusing every connection with
+/// `return_code`.
+fn refusing_broker(return_code: u8) -> (MqttOptions, mpsc::Receiver<Seen>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut options = MqttOptions::new("127.0.0.1");
+    options.port = listener.local_addr().unwrap().port();
+    options.min_backoff = Duration::from_millis(10);
+
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            serve(stream.unwrap(), &tx, None, &[], return_code);
+        }
+    });
+    (options, rx)
+}
+
+fn next(seen: &mpsc::Receiver<Seen>) -> Seen {
+    seen.recv_timeout(TIMEOUT).expect("broker saw nothing")
+}
+
+#[test]
+fn publishes_retained_states() {
+    let (options, seen) = broker(vec![None], &[]);
+    let publisher = MqttPublisher::new(options).unwrap();
+    publisher.publish(CFF3000State::Locked).unwrap();
+
+    let will = Some((DEFAULT_AVAILABILITY_TOPIC.to_string(), "offline".to_string(), true));
+    assert_eq!(next(&seen), Seen::Connect {will, credentials: None});
+    assert_eq!(next(&seen), publish(DEFAULT_AVAILABILITY_TOPIC, "online"));
+    /* published before the connect, sent once connected */
+    assert_eq!(next(&seen), publish(DEFAULT_STATE_TOPIC, "locked"));
+    assert!(publisher.is_connected());
+
+    publisher.publish(CFF3000State::OutOfRange).unwrap();
+    assert_eq!(next(&seen), publish(DEFAULT_STATE_TOPIC, "out-of-range"));
+
+    drop(publisher);
+    assert_eq!(next(&seen), publish(DEFAULT_AVAILABILITY_TOPIC, "offline"));
+    assert_eq!(next(&seen), Seen::Disconnect);
+}
+
+#[test]
+fn publishes_changes_to_the_change_topic() {
+    let (mut options, seen) = broker(vec![None], &[]);
+    options.change_topic = Some("home/door/change".to_string());
+    let publisher = MqttPublisher::new(options).unwrap();
+    assert!(matches!(next(&seen), Seen::Connect {..}));
+    assert_eq!(next(&seen), publish(DEFAULT_AVAILABILITY_TOPIC, "online"));
+    while !publisher.is_connected() {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    let at = UNIX_EPOCH + Duration::from_millis(1_760_000_000_123);
+    publisher.publish_change(&StateChange::new(Some(CFF3000State::Unlocked), CFF3000State::Locked, Trigger::AutoLock, at)).unwrap();
+    assert_eq!(next(&seen), publish(DEFAULT_STATE_TOPIC, "locked"));
+    assert_eq!(next(&seen), publish("home/door/change", r#"{"state":"locked","previous":"unlocked","trigger":"auto-lock","at_ms":1760000000123}"#));
+}
+
+#[test]
+fn reconnect_republishes_the_state() {
+    let (mut options, seen) = broker(vec![Some(2), None], &[]);
+    options.state_topic = "home/door/state".to_string();
+    let publisher = MqttPublisher::new(options).unwrap();
+    publisher.publish(CFF3000State::Unlocked).unwrap();
+
+    assert!(matches!(next(&seen), Seen::Connect {..}));
+    assert_eq!(next(&seen), publish(DEFAULT_AVAILABILITY_TOPIC, "online"));
+    assert_eq!(next(&seen), publish("home/door/state", "unlocked"));
+
+    /* the broker closed the connection, the state comes again unasked */
+    assert!(matches!(next(&seen), Seen::Connect {..}));
+    assert_eq!(next(&seen), publish(DEFAULT_AVAILABILITY_TOPIC, "online"));
+    assert_eq!(next(&seen), publish("home/door/state", "unlocked"));
+}
+
+#[test]
+fn reconnect_publishes_changes_noticed_while_disconnected() {
+    let (mut options, seen) = broker(vec![Some(2), None], &[]);
+    options.min_backoff = Duration::from_millis(500);
+    let publisher = MqttPublisher::new(options).unwrap();
+    publisher.publish(CFF3000State::Locked).unwrap();
+
+    assert!(matches!(next(&seen), Seen::Connect {..}));
+    assert_eq!(next(&seen), publish(DEFAULT_AVAILABILITY_TOPIC, "online"));
+    assert_eq!(next(&seen), publish(DEFAULT_STATE_TOPIC, "locked"));
+    while publisher.is_connected() {
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    publisher.publish(CFF3000State::Manual).unwrap();
+    publisher.publish(CFF3000State::Unlocked).unwrap();
+
+    /* online first, then only the last state, nothing stale after it */
+    assert!(matches!(next(&seen), Seen::Connect {..}));
+    assert_eq!(next(&seen), publish(DEFAULT_AVAILABILITY_TOPIC, "online"));
+    assert_eq!(next(&seen), publish(DEFAULT_STATE_TOPIC, "unlocked"));
+    publisher.shutdown().unwrap();
+    assert_eq!(next(&seen), publish(DEFAULT_AVAILABILITY_TOPIC, "offline"));
+    assert_eq!(next(&seen), Seen::Disconnect);
+    assert!(seen.recv_timeout(Duration::from_millis(100)).is_err());
+}
+
+#[test]
+fn shutdown_without_connection() {
+    let (options, seen) = refusing_broker(5);
+    let publisher = MqttPublisher::new(options).unwrap();
+    assert!(matches!(next(&seen), Seen::Connect {..}));
+    /* nothing to take back, the broker never saw online */
+    publisher.shutdown().unwrap();
+}
+
+#[test]
+fn devices_of_groups_get_their_topics() {
+    let mut options = MqttOptions::new("localhost");
+    options.change_topic = Some("changes".to_string());
+    let garage = options.for_device("garage");
+    assert_eq!(garage.client_id, "cff3000-garage");
+    assert_eq!(garage.state_topic, "cff3000/garage/state");
+    assert_eq!(garage.command_topic, "cff3000/garage/set");
+    assert_eq!(garage.availability_topic, "cff3000/garage/availability");
+    assert_eq!(garage.change_topic.as_deref(), Some("garage/changes"));
+    assert_eq!(garage.host, options.host);
+
+    options.state_topic = "home/doors/front/state".to_string();
+    assert_eq!(options.for_device("garage").state_topic, "home/doors/garage/state");
+    options.convention = Convention::Homie(Homie::new("frontdoor", "Front door"));
+    assert_eq!(options.for_device("Back_Door").convention, Convention::Homie(Homie::new("back-door", "Back_Door")));
+    options.convention = Convention::HomeAssistant(Discovery::new("cff3000_frontdoor", "Front door"));
+    assert_eq!(options.for_device("garage").convention, Convention::HomeAssistant(Discovery::new("garage", "garage")));
+}
+
+/// A failing snapshot means the entity Home Assistant sees changed.
+#[test]
+fn discovery_config_snapshot() {
+    let mut discovery = Discovery::new("cff3000_frontdoor", "Front door");
+    discovery.suggested_area = Some("Hallway".to_string());
+    assert_eq!(discovery.topic(), "homeassistant/lock/cff3000_frontdoor/config");
+    assert_eq!(discovery_config(&MqttOptions::new("localhost"), &discovery), concat!(
+        r#"{"name":null,"unique_id":"cff3000_frontdoor","#,
+        r#""state_topic":"cff3000/frontdoor/state","command_topic":"cff3000/frontdoor/set","#,
+        r#""availability_topic":"cff3000/frontdoor/availability","#,
+        r#""payload_available":"online","payload_not_available":"offline","#,
+        r#""payload_lock":"LOCK","payload_unlock":"UNLOCK","#,
+        r#""state_locked":"locked","state_unlocked":"unlocked","state_jammed":"out-of-range","#,
+        r#""value_template":"{{ 'None' if value == 'manual' else value }}","#,
+        r#""optimistic":false,"retain":false,"qos":1,"#,
+        r#""device":{"identifiers":["cff3000_frontdoor"],"name":"Front door","#,
+        r#""manufacturer":"ABUS","model":"ABUS CFF3000","suggested_area":"Hallway"}}"#,
+    ));
+
+    let mut options = MqttOptions::new("localhost");
+    options.state_topic = "home/\"door\"/state".to_string();
+    discovery.suggested_area = None;
+    let config = discovery_config(&options, &discovery);
+    assert!(config.contains(r#""state_topic":"home/\"door\"/state""#));
+    assert!(config.ends_with(r#""model":"ABUS CFF3000"}}"#));
+}
+
+#[test]
+fn announces_and_removes_the_discovery() {
+    let (mut options, seen) = broker(vec![None], &[]);
+    let mut discovery = Discovery::new("door", "Door");
+    discovery.remove_on_shutdown = true;
+    let config = discovery_config(&options, &discovery);
+    options.convention = Convention::HomeAssistant(discovery);
+    let publisher = MqttPublisher::new(options).unwrap();
+
+    assert!(matches!(next(&seen), Seen::Connect {..}));
+    assert_eq!(next(&seen), publish("homeassistant/lock/door/config", &config));
+    assert_eq!(next(&seen), publish(DEFAULT_AVAILABILITY_TOPIC, "online"));
+    assert!(publisher.is_connected());
+
+    drop(publisher);
+    assert_eq!(next(&seen), publish("homeassistant/lock/door/config", ""));
+    assert_eq!(next(&seen), publish(DEFAULT_AVAILABILITY_TOPIC, "offline"));
+    assert_eq!(next(&seen), Seen::Disconnect);
+}
+
+#[test]
+fn parses_commands() {
+    assert_eq!(parse_command(b"LOCK"), Some(Command::Lock));
+    assert_eq!(parse_command(b" UNLOCK\n"), Some(Command::Unlock));
+    assert_eq!(parse_command(b"CHECK"), Some(Command::Check));
+    assert_eq!(parse_command(b"lock"), None);
+    assert_eq!(parse_command(b"LOCK NOW"), None);
+    assert_eq!(parse_command(b"\xff"), None);
+}
+
+#[test]
+fn executes_allowed_commands() {
+    /* only the last one is retained-free, well-formed and allowed */
+    let (mut options, seen) = broker(vec![None], &[("LOCK", true), ("OPEN", false), ("UNLOCK", false), ("LOCK", false)]);
+    options.allowed_commands = vec![Command::Lock, Command::Check];
+    let replay = Replay::with_captures(&[Some(CFF3000State::Locked)]);
+    let device = testing::device(&replay).build().unwrap();
+    let queue = CommandQueue::new(device).unwrap();
+    let publisher = MqttPublisher::with_commands(options, queue.sender()).unwrap();
+
+    assert!(matches!(next(&seen), Seen::Connect {..}));
+    assert_eq!(next(&seen), publish(DEFAULT_AVAILABILITY_TOPIC, "online"));
+    assert_eq!(next(&seen), Seen::Subscribe(DEFAULT_COMMAND_TOPIC.to_string()));
+    assert_eq!(next(&seen), publish(DEFAULT_STATE_TOPIC, "locked"));
+
+    let transitions = replay.transitions();
+    assert_eq!(transitions.len(), 2);
+    assert!(transitions.iter().all(|t| t.button == Button::Lock));
+    drop(publisher);
+}
+
+fn homie_publish(path: &str, payload: &str) -> Seen {
+    publish(&format!("homie/frontdoor/{}", path), payload)
+}
+
+#[test]
+fn announces_a_homie_device() {
+    let (mut options, seen) = broker(vec![None], &[]);
+    options.convention = Convention::Homie(Homie::new("frontdoor", "Front door"));
+    let publisher = MqttPublisher::new(options).unwrap();
+    publisher.publish(CFF3000State::Manual).unwrap();
+    publisher.publish_battery_low(false).unwrap();
+
+    let will = Some(("homie/frontdoor/$state".to_string(), "lost".to_string(), true));
+    assert_eq!(next(&seen), Seen::Connect {will, credentials: None});
+    let expected = [
+        ("$state", "init"),
+        ("$homie", "4.0.0"),
+        ("$name", "Front door"),
+        ("$extensions", ""),
+        ("$nodes", "lock"),
+        ("lock/$name", "Lock"),
+        ("lock/$type", "ABUS CFF3000"),
+        ("lock/$properties", "state,battery-low"),
+        ("lock/state/$name", "State"),
+        ("lock/state/$datatype", "enum"),
+        ("lock/state/$format", "locked,unlocked,manual,out-of-range"),
+        ("lock/state/$settable", "true"),
+        ("lock/battery-low/$name", "Battery low"),
+        ("lock/battery-low/$datatype", "boolean"),
+        ("lock/state", "manual"),
+        ("lock/battery-low", "false"),
+        ("$state", "ready"),
+    ];
+    for &(path, payload) in &expected {
+        assert_eq!(next(&seen), homie_publish(path, payload));
+    }
+
+    publisher.publish_battery_low(true).unwrap();
+    assert_eq!(next(&seen), homie_publish("lock/battery-low", "true"));
+    publisher.publish(CFF3000State::Unlocked).unwrap();
+    assert_eq!(next(&seen), homie_publish("lock/state", "unlocked"));
+
+    drop(publisher);
+    assert_eq!(next(&seen), homie_publish("$state", "disconnected"));
+    assert_eq!(next(&seen), Seen::Disconnect);
+}
+
+#[test]
+fn sends_credentials_and_retries_when_refused() {
+    /* bad user name or password */
+    let (mut options, seen) = refusing_broker(4);
+    options.credentials = Some(("door".to_string(), "secret".to_string()));
+    let publisher = MqttPublisher::new(options).unwrap();
+
+    let credentials = Some(("door".to_string(), "secret".to_string()));
+    for _ in 0..2 {
+        assert!(matches!(next(&seen), Seen::Connect {credentials: ref c, ..} if *c == credentials));
+    }
+    assert!(!publisher.is_connected());
+}
+
+#[test]
+fn rejects_unusable_settings() {
+    let mut options = MqttOptions::new("127.0.0.1");
+    options.keep_alive = Duration::from_millis(1500);
+    assert_eq!(MqttPublisher::new(options).err().unwrap().kind(), ErrorKind::InvalidInput);
+}
+
+#[cfg(not(feature = "mqtt-tls"))]
+#[test]
+fn tls_needs_the_feature() {
+    let mut options = MqttOptions::new("127.0.0.1");
+    options.tls = Some(TlsConfig::new("/etc/ssl/certs/ca-certificates.crt"));
+    assert_eq!(MqttPublisher::new(options).err().unwrap().kind(), ErrorKind::Unsupported);
+}
+
+#[cfg(feature = "mqtt-tls")]
+#[test]
+fn tls_files_are_checked_up_front() {
+    let dir = std::env::temp_dir().join(format!("cff3000-mqtt-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let empty = dir.join("empty.pem");
+    std::fs::write(&empty, "no certificate here\n").unwrap();
+    let connect = |tls: TlsConfig| {
+        let mut options = MqttOptions::new("127.0.0.1");
+        options.tls = Some(tls);
+        MqttPublisher::new(options).map(|_| ())
+    };
+
+    let missing = dir.join("missing.pem");
+    let err = connect(TlsConfig::new(&missing)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    assert!(err.to_string().starts_with(&missing.display().to_string()));
+
+    let err = connect(TlsConfig::new(&empty)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert_eq!(err.to_string(), format!("{}: no PEM certificate found", empty.display()));
+
+    assert_eq!(connect(TlsConfig::default()).unwrap_err().kind(), ErrorKind::InvalidInput);
+    let insecure = TlsConfig {insecure_skip_verify: true, ..TlsConfig::default()};
+    let half = TlsConfig {client_cert: Some(empty.clone()), ..insecure.clone()};
+    assert_eq!(connect(half).unwrap_err().kind(), ErrorKind::InvalidInput);
+    connect(insecure).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
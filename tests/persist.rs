@@ -0,0 +1,110 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! `persist::StateFile`: round trips, tolerated files and the metrics
+//! counters.
+
+use std::path::PathBuf;
+use std::time::{Duration, UNIX_EPOCH};
+
+use cff3000::battery::BatteryMarker;
+use cff3000::persist::{Counters, LastState, Snapshot, StateFile};
+use cff3000::CFF3000State;
+
+fn path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("cff3000-persist-{}-{}.toml", std::process::id(), name))
+}
+
+fn snapshot() -> Snapshot {
+    let mut counters = Counters::default();
+    counters.commands.insert("lock".to_string(), 3);
+    counters.commands.insert("check".to_string(), 12);
+    counters.failures.entry("check".to_string()).or_default().insert("no-response".to_string(), 2);
+    Snapshot {
+        last: Some(LastState {state: CFF3000State::Locked, timestamp: UNIX_EPOCH + Duration::from_millis(1_760_000_000_123)}),
+        counters,
+        battery: Some(BatteryMarker {since: UNIX_EPOCH + Duration::from_millis(1_750_000_000_456), replaced: true, commands: 1210}),
+    }
+}
+
+#[test]
+fn snapshots_round_trip() {
+    let file = StateFile::new(path("round-trip"));
+    assert_eq!(file.load(), None);
+    file.store(&snapshot()).unwrap();
+    assert_eq!(file.load(), Some(snapshot()));
+    file.store(&Snapshot::default()).unwrap();
+    assert_eq!(file.load(), Some(Snapshot::default()));
+    std::fs::remove_file(file.path()).unwrap();
+}
+
+#[test]
+fn unusable_files_are_missing() {
+    let file = StateFile::new(path("unusable"));
+    for text in &["", "version = 1\n[last\n", "version = 2\n", "[commands]\nlock = 3\n", "version = 1\n[last]\nstate = \"open\"\ntimestamp_ms = 1\n"] {
+        std::fs::write(file.path(), text).unwrap();
+        assert_eq!(file.load(), None, "{:?}", text);
+    }
+    std::fs::remove_file(file.path()).unwrap();
+}
+
+#[test]
+fn newer_keys_are_ignored() {
+    let file = StateFile::new(path("newer"));
+    std::fs::write(file.path(), "version = 1\nsignal = \"low\"\n\n[last]\nstate = \"locked\"\ntimestamp_ms = 1760000000123\nsource = \"watch\"\n\n[commands]\nlock = 3\ncheck = 12\n\n[failures.check]\nno-response = 2\n\n[battery]\nsince_ms = 1750000000456\nreplaced = true\ncommands = 1210\nlevel = 3\n\n[history]\nlocked = 7\n").unwrap();
+    assert_eq!(file.load(), Some(snapshot()));
+    std::fs::remove_file(file.path()).unwrap();
+}
+
+#[test]
+fn first_start_markers_round_trip() {
+    let file = StateFile::new(path("first-start"));
+    let snapshot = Snapshot {battery: Some(BatteryMarker {since: UNIX_EPOCH + Duration::from_secs(1_750_000_000), replaced: false, commands: 0}), ..Snapshot::default()};
+    file.store(&snapshot).unwrap();
+    let text = std::fs::read_to_string(file.path()).unwrap();
+    assert!(text.contains("[battery]\nsince_ms = 1750000000000\ncommands = 0\n"), "{}", text);
+    assert_eq!(file.load(), Some(snapshot));
+    std::fs::remove_file(file.path()).unwrap();
+}
+
+#[test]
+fn files_carry_their_schema() {
+    let file = StateFile::new(path("schema"));
+    file.store(&snapshot()).unwrap();
+    let text = std::fs::read_to_string(file.path()).unwrap();
+    assert!(text.starts_with("schema = 1\n"), "{}", text);
+    /* older daemons wrote it as version */
+    std::fs::write(file.path(), text.replacen("schema", "version", 1)).unwrap();
+    assert_eq!(file.load(), Some(snapshot()));
+    std::fs::write(file.path(), text.replacen("schema = 1", "schema = 2", 1)).unwrap();
+    assert_eq!(file.load(), None);
+    std::fs::remove_file(file.path()).unwrap();
+}
+
+#[test]
+fn missing_directories_fail_to_store() {
+    let file = StateFile::new(std::env::temp_dir().join("cff3000-missing").join("state.toml"));
+    assert!(file.store(&snapshot()).is_err());
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn metrics_counters_are_restored() {
+    use cff3000::metrics::Metrics;
+
+    let metrics = Metrics::new();
+    let mut counters = snapshot().counters;
+    counters.commands.insert("open".to_string(), 1);
+    counters.failures.entry("lock".to_string()).or_default().insert("jammed".to_string(), 1);
+    metrics.add_counters(&counters);
+    metrics.add_counters(&snapshot().counters);
+
+    let text = metrics.encode();
+    assert!(text.contains("cff3000_commands_total{command=\"lock\"} 6\n"), "{}", text);
+    assert!(text.contains("cff3000_commands_total{command=\"unlock\"} 0\n"), "{}", text);
+    assert!(text.contains("cff3000_command_failures_total{command=\"check\",code=\"no-response\"} 4\n"), "{}", text);
+    assert!(!text.contains("jammed") && !text.contains("open"), "{}", text);
+    let counters = metrics.counters();
+    assert_eq!(counters.commands.get("check"), Some(&24));
+    assert_eq!(counters.failures.len(), 1);
+}
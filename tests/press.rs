@@ -0,0 +1,73 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Two-phase button presses, see `cff3000::PressGuard`, on the mock
+//! backend.
+
+use std::time::{Duration, Instant};
+
+use cff3000::mock::MockBackend;
+use cff3000::{Button, CFF3000, CFF3000Builder, Timings};
+
+const PRESS: Duration = Duration::from_millis(100);
+
+fn device(mock: &MockBackend) -> CFF3000 {
+    CFF3000Builder::with_backend(mock.clone()).timings(Timings {press: PRESS, ..Timings::default()}).build().unwrap()
+}
+
+/// Recorded `(button, pressed)` transitions, in order.
+fn levels(mock: &MockBackend) -> Vec<(Button, bool)> {
+    mock.transitions().iter().map(|t| (t.button, t.pressed)).collect()
+}
+
+/// Wait up to a second for both buttons to be released.
+fn wait_released(mock: &MockBackend) {
+    let start = Instant::now();
+    while mock.is_pressed(Button::Lock) || mock.is_pressed(Button::Unlock) {
+        assert!(start.elapsed() < Duration::from_secs(1), "buttons not released: {:?}", levels(mock));
+        std::thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[test]
+fn dropped_guards_release_after_the_press() {
+    let mock = MockBackend::new();
+    let device = device(&mock);
+    let guard = device.begin_check_press().unwrap();
+    assert_eq!(levels(&mock), vec![(Button::Lock, true), (Button::Unlock, true)]);
+
+    /* the release is left to the timer thread */
+    drop(guard);
+    assert!(mock.is_pressed(Button::Lock) && mock.is_pressed(Button::Unlock));
+    wait_released(&mock);
+    assert_eq!(levels(&mock), vec![(Button::Lock, true), (Button::Unlock, true), (Button::Unlock, false), (Button::Lock, false)]);
+    let transitions = mock.transitions();
+    assert!(transitions[2].at - transitions[1].at >= PRESS, "{:?}", transitions);
+}
+
+#[test]
+fn due_guards_release_at_once() {
+    let mock = MockBackend::new();
+    let device = device(&mock);
+    let guard = device.begin_unlock_press().unwrap();
+    assert!(!guard.is_due());
+    std::thread::sleep(guard.remaining());
+    assert!(guard.is_due());
+    guard.release().unwrap();
+    assert_eq!(levels(&mock), vec![(Button::Unlock, true), (Button::Unlock, false)]);
+}
+
+#[test]
+fn early_releases_keep_the_device_busy() {
+    let mock = MockBackend::new();
+    let device = device(&mock);
+    device.begin_lock_press().unwrap().release().unwrap();
+    assert!(mock.is_pressed(Button::Lock));
+
+    /* the next press waits for the deferred release */
+    let guard = device.begin_unlock_press().unwrap();
+    assert_eq!(levels(&mock), vec![(Button::Lock, true), (Button::Lock, false), (Button::Unlock, true)]);
+    drop(guard);
+    wait_released(&mock);
+    assert_eq!(levels(&mock)[3], (Button::Unlock, false));
+}
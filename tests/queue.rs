@@ -0,0 +1,195 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Coalescing, shutdown and panicking commands of a `CommandQueue`, on
+//! `testing::FakeLock`.
+
+use std::io::ErrorKind;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use cff3000::testing::FakeLock;
+use cff3000::{CFF3000State, Command, CommandQueue, CommandSender, LockControl, QueueOptions, Shutdown};
+
+/// Closed until `open()`.
+#[derive(Default)]
+struct Gate {
+    open: Mutex<bool>,
+    opened: Condvar,
+}
+
+impl Gate {
+    fn pass(&self) {
+        let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        while !*open {
+            open = self.opened.wait(open).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    fn open(&self) {
+        *self.open.lock().unwrap() = true;
+        self.opened.notify_all();
+    }
+}
+
+/// `FakeLock` whose commands wait for `gate`, so commands pile up in
+/// the queue until it is opened.
+struct Gated {
+    fake: FakeLock,
+    gate: Arc<Gate>,
+}
+
+impl LockControl for Gated {
+    fn state(&self) -> std::io::Result<CFF3000State> {
+        self.execute_as(Command::Check, "")
+    }
+
+    fn lock_and_verify(&self) -> std::io::Result<CFF3000State> {
+        self.execute_as(Command::Lock, "")
+    }
+
+    fn unlock_and_verify(&self) -> std::io::Result<CFF3000State> {
+        self.execute_as(Command::Unlock, "")
+    }
+
+    fn execute_as(&self, command: Command, initiator: &str) -> std::io::Result<CFF3000State> {
+        self.gate.pass();
+        self.fake.execute_as(command, initiator)
+    }
+}
+
+/// Queue with a check being executed, waiting for the returned gate.
+fn blocked(fake: &FakeLock, options: QueueOptions) -> (CommandQueue, CommandSender, Arc<Gate>, mpsc::Receiver<std::io::Result<CFF3000State>>) {
+    let gate = Arc::new(Gate::default());
+    let queue = CommandQueue::for_lock(Arc::new(Gated {fake: fake.clone(), gate: gate.clone()}), options).unwrap();
+    let sender = queue.sender();
+    let running = sender.send(Command::Check);
+    /* taken by the worker, which waits for the gate */
+    let start = Instant::now();
+    while sender.pending() > 0 {
+        assert!(start.elapsed() < Duration::from_secs(5), "worker did not take the check");
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    (queue, sender, gate, running)
+}
+
+#[test]
+fn identical_commands_coalesce() {
+    let fake = FakeLock::new(CFF3000State::Unlocked);
+    let (_queue, sender, gate, running) = blocked(&fake, QueueOptions::default());
+    let locks: Vec<_> = (0..3).map(|i| sender.send_as(Command::Lock, &format!("producer {}", i % 2))).collect();
+    assert_eq!(sender.pending(), 1);
+    /* only consecutive commands are merged */
+    let unlock = sender.send(Command::Unlock);
+    let relock = sender.send(Command::Lock);
+    assert_eq!(sender.pending(), 3);
+    gate.open();
+
+    assert_eq!(running.recv().unwrap().unwrap(), CFF3000State::Unlocked);
+    for lock in locks {
+        assert_eq!(lock.recv().unwrap().unwrap(), CFF3000State::Locked);
+    }
+    assert_eq!(unlock.recv().unwrap().unwrap(), CFF3000State::Unlocked);
+    assert_eq!(relock.recv().unwrap().unwrap(), CFF3000State::Locked);
+    assert_eq!(fake.commands(), vec![
+        (Command::Check, String::new()),
+        (Command::Lock, "producer 0, producer 1".to_string()),
+        (Command::Unlock, String::new()),
+        (Command::Lock, String::new()),
+    ]);
+}
+
+#[test]
+fn coalescing_can_be_disabled() {
+    let fake = FakeLock::new(CFF3000State::Unlocked);
+    let (_queue, sender, gate, running) = blocked(&fake, QueueOptions {coalesce: false, ..QueueOptions::default()});
+    let locks: Vec<_> = (0..2).map(|_| sender.send(Command::Lock)).collect();
+    assert_eq!(sender.pending(), 2);
+    gate.open();
+    running.recv().unwrap().unwrap();
+    for lock in locks {
+        assert_eq!(lock.recv().unwrap().unwrap(), CFF3000State::Locked);
+    }
+    assert_eq!(fake.commands().len(), 3);
+}
+
+#[test]
+fn cancel_fails_pending_commands() {
+    let fake = FakeLock::new(CFF3000State::Unlocked);
+    let (queue, sender, gate, running) = blocked(&fake, QueueOptions::default());
+    let lock = sender.send(Command::Lock);
+    let unlock = sender.send(Command::Unlock);
+
+    /* shutdown() waits for the running check, which waits for the gate */
+    let shutdown = std::thread::spawn(move || queue.shutdown(Shutdown::Cancel));
+    assert_eq!(lock.recv().unwrap().unwrap_err().kind(), ErrorKind::BrokenPipe);
+    assert_eq!(unlock.recv().unwrap().unwrap_err().kind(), ErrorKind::BrokenPipe);
+    gate.open();
+    shutdown.join().unwrap();
+
+    /* the running command is completed */
+    assert_eq!(running.recv().unwrap().unwrap(), CFF3000State::Unlocked);
+    assert_eq!(fake.commands(), vec![(Command::Check, String::new())]);
+    assert_eq!(sender.send(Command::Check).recv().unwrap().unwrap_err().kind(), ErrorKind::BrokenPipe);
+}
+
+#[test]
+fn drain_runs_pending_commands() {
+    for drop_queue in [false, true] {
+        let fake = FakeLock::new(CFF3000State::Unlocked);
+        let (queue, sender, gate, running) = blocked(&fake, QueueOptions::default());
+            let lock = sender.send(Command::Lock);
+        let unlock = sender.send(Command::Unlock);
+        gate.open();
+        /* dropping the queue drains as well */
+        match drop_queue {
+            true => drop(queue),
+            false => queue.shutdown(Shutdown::Drain),
+        }
+
+        assert_eq!(fake.commands().len(), 3);
+        assert_eq!(running.recv().unwrap().unwrap(), CFF3000State::Unlocked);
+        assert_eq!(lock.recv().unwrap().unwrap(), CFF3000State::Locked);
+        assert_eq!(unlock.recv().unwrap().unwrap(), CFF3000State::Unlocked);
+        assert_eq!(sender.send(Command::Check).recv().unwrap().unwrap_err().kind(), ErrorKind::BrokenPipe);
+    }
+}
+
+/// `FakeLock` panicking on unlock.
+struct Panicking(FakeLock);
+
+impl LockControl for Panicking {
+    fn state(&self) -> std::io::Result<CFF3000State> {
+        self.execute_as(Command::Check, "")
+    }
+
+    fn lock_and_verify(&self) -> std::io::Result<CFF3000State> {
+        self.execute_as(Command::Lock, "")
+    }
+
+    fn unlock_and_verify(&self) -> std::io::Result<CFF3000State> {
+        self.execute_as(Command::Unlock, "")
+    }
+
+    fn execute_as(&self, command: Command, initiator: &str) -> std::io::Result<CFF3000State> {
+        assert_ne!(command, Command::Unlock, "unlock is broken");
+        self.0.execute_as(command, initiator)
+    }
+}
+
+#[test]
+fn panicking_commands_fail() {
+    let fake = FakeLock::new(CFF3000State::Unlocked);
+    let queue = CommandQueue::for_lock(Arc::new(Panicking(fake.clone())), QueueOptions::default()).unwrap();
+    let sender = queue.sender();
+    let unlock = sender.send(Command::Unlock);
+    let lock = sender.send(Command::Lock);
+
+    let timeout = Duration::from_secs(5);
+    assert_eq!(unlock.recv_timeout(timeout).unwrap().unwrap_err().kind(), ErrorKind::BrokenPipe);
+    /* the worker keeps executing commands */
+    assert_eq!(lock.recv_timeout(timeout).unwrap().unwrap(), CFF3000State::Locked);
+    assert_eq!(sender.send(Command::Check).recv_timeout(timeout).unwrap().unwrap(), CFF3000State::Locked);
+    assert_eq!(fake.commands(), vec![(Command::Lock, String::new()), (Command::Check, String::new())]);
+    queue.shutdown(Shutdown::Drain);
+}
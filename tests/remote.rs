@@ -0,0 +1,44 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! The `remote` agent against malformed frames of a client.
+
+use std::io::{ErrorKind, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+
+use cff3000::remote::Agent;
+use cff3000::testing::Replay;
+
+const HELLO: &[u8] = &[0, 0, 0, 8, 0x01, 1, b's', b'e', b'c', b'r', b'e', b't'];
+
+/// Result of the agent serving a client sending `data` and closing
+/// the connection.
+fn serve(data: &[u8]) -> std::io::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    client.write_all(data).unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    Agent::new(Replay::new(), "secret").handle_client(stream)
+}
+
+#[test]
+fn malformed_frames_are_rejected() {
+    assert!(serve(HELLO).is_ok());
+    /* a truncated frame is the end of the connection */
+    assert!(serve(&[HELLO, &[0, 0, 0, 5, 0x02]].concat()).is_ok());
+
+    for frame in &[
+        &[0, 0, 0, 0][..],
+        &[0xff, 0xff, 0xff, 0xff],
+        &[0, 0, 0, 1, 0x02],
+        &[0, 0, 0, 3, 0x02, 7, 1],
+        &[0, 0, 0, 4, 0x02, 0, 1, 0],
+        &[0, 0, 0, 1, 0x90],
+        &[0, 0, 0, 1, 0x7f],
+    ] {
+        assert_eq!(serve(&[HELLO, frame].concat()).unwrap_err().kind(), ErrorKind::InvalidData, "{:?}", frame);
+    }
+    /* a greeting without version */
+    assert_eq!(serve(&[0, 0, 0, 1, 0x01]).unwrap_err().kind(), ErrorKind::InvalidData);
+}
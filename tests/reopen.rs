@@ -0,0 +1,132 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! `CFF3000Builder::auto_reopen()` on custom backends of an adapter
+//! which is unplugged and plugged in again.
+
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use cff3000::mock::MockBackend;
+use cff3000::{Button, CFF3000, CFF3000Builder, GpioBackend, Led, LedEvent, LineInfo, Notice, Timings};
+
+/// USB adapter with one set of lines, recorded by `mock`.
+struct Adapter {
+    plugged: AtomicBool,
+    opens: AtomicUsize,
+    mock: MockBackend,
+}
+
+impl Adapter {
+    fn open(adapter: &Arc<Adapter>) -> std::io::Result<Handles> {
+        if !adapter.plugged.load(Ordering::SeqCst) {
+            return Err(Error::new(ErrorKind::NotFound, "no such adapter"));
+        }
+        adapter.opens.fetch_add(1, Ordering::SeqCst);
+        Ok(Handles(adapter.clone()))
+    }
+}
+
+/// Lines of one open, failing like a removed chip while unplugged.
+struct Handles(Arc<Adapter>);
+
+impl Handles {
+    fn mock(&self) -> std::io::Result<&MockBackend> {
+        match self.0.plugged.load(Ordering::SeqCst) {
+            true => Ok(&self.0.mock),
+            false => Err(Error::from_raw_os_error(libc::ENODEV)),
+        }
+    }
+}
+
+impl GpioBackend for Handles {
+    fn set_button(&self, button: Button, pressed: bool) -> std::io::Result<()> {
+        self.mock()?.set_button(button, pressed)
+    }
+
+    fn wait_for_led_events(&self, timeout: Duration) -> std::io::Result<u8> {
+        self.mock()?.wait_for_led_events(timeout)
+    }
+
+    fn read_led_event(&self, led: Led) -> std::io::Result<LedEvent> {
+        self.mock()?.read_led_event(led)
+    }
+
+    fn flush_led_events(&self) -> std::io::Result<()> {
+        self.mock()?.flush_led_events()
+    }
+
+    fn line_info(&self) -> std::io::Result<[LineInfo; 4]> {
+        self.mock()?.line_info()
+    }
+}
+
+/// Device on `adapter` and the reconnects it reports.
+fn device(adapter: &Arc<Adapter>) -> (CFF3000, Receiver<Notice>) {
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    let opener = adapter.clone();
+    let device = CFF3000Builder::with_opener("usb0", move || Adapter::open(&opener))
+        .auto_reopen(Duration::from_millis(200))
+        .timings(Timings {press: Duration::from_millis(20), ..Timings::default()})
+        .monitor(move |notice| if let Notice::Reconnected {..} = *notice {
+            let _ = tx.lock().unwrap().send(notice.clone());
+        })
+        .build()
+        .unwrap();
+    (device, rx)
+}
+
+#[test]
+fn operations_reopen_after_an_outage() {
+    let adapter = Arc::new(Adapter {plugged: AtomicBool::new(true), opens: AtomicUsize::new(0), mock: MockBackend::new()});
+    let (device, rx) = device(&adapter);
+    assert_eq!(adapter.opens.load(Ordering::SeqCst), 1);
+
+    /* the adapter does not come back within the timeout */
+    adapter.plugged.store(false, Ordering::SeqCst);
+    assert!(device.lock().is_err());
+    assert!(rx.try_recv().is_err());
+
+    adapter.plugged.store(true, Ordering::SeqCst);
+    device.lock().unwrap();
+    assert_eq!(adapter.opens.load(Ordering::SeqCst), 2);
+    device.unlock().unwrap();
+    assert_eq!(adapter.opens.load(Ordering::SeqCst), 2);
+
+    assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![Notice::Reconnected {chipdev: "usb0".to_string()}]);
+    let levels: Vec<(Button, bool)> = adapter.mock.transitions().iter().map(|t| (t.button, t.pressed)).collect();
+    assert_eq!(levels, vec![(Button::Lock, true), (Button::Lock, false), (Button::Unlock, true), (Button::Unlock, false)]);
+}
+
+#[test]
+fn waiting_callers_do_not_block_each_other() {
+    let adapter = Arc::new(Adapter {plugged: AtomicBool::new(true), opens: AtomicUsize::new(0), mock: MockBackend::new()});
+    let (device, rx) = device(&adapter);
+
+    /* both wait for the same 200 ms, not one after the other */
+    adapter.plugged.store(false, Ordering::SeqCst);
+    let start = Instant::now();
+    std::thread::scope(|scope| {
+        let readers: Vec<_> = (0..2).map(|_| scope.spawn(|| device.line_info())).collect();
+        for reader in readers {
+            assert!(reader.join().unwrap().is_err());
+        }
+    });
+    assert!(start.elapsed() < Duration::from_millis(350), "{:?}", start.elapsed());
+
+    /* a single reopen serves both */
+    std::thread::scope(|scope| {
+        let readers: Vec<_> = (0..2).map(|_| scope.spawn(|| device.line_info())).collect();
+        std::thread::sleep(Duration::from_millis(50));
+        adapter.plugged.store(true, Ordering::SeqCst);
+        for reader in readers {
+            reader.join().unwrap().unwrap();
+        }
+    });
+    assert_eq!(adapter.opens.load(Ordering::SeqCst), 2);
+    assert_eq!(rx.try_iter().count(), 1);
+}
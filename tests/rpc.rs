@@ -0,0 +1,165 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! `cff3000 rpc` (`cli::serve_rpc()`) against the replay backend.
+
+use std::io::{BufReader, Read, Write};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+use cff3000::cli::{serve_rpc, RpcOptions, MAX_FRAME};
+use cff3000::testing::{self, Replay};
+use cff3000::{CFF3000State, CFF3000, WatchOptions};
+
+/// Output shared with the test.
+#[derive(Clone, Default)]
+struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Output {
+    fn lines(&self) -> Vec<String> {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap().lines().map(str::to_string).collect()
+    }
+}
+
+/// Serve `input` completely and return the output lines.
+fn run(device: Arc<CFF3000>, input: &str) -> Vec<String> {
+    let output = Output::default();
+    serve_rpc(device, input.as_bytes(), output.clone(), &RpcOptions::default()).unwrap();
+    output.lines()
+}
+
+#[test]
+fn methods() {
+    let device = Arc::new(testing::device(&Replay::with_captures(&[Some(CFF3000State::Unlocked), Some(CFF3000State::Locked), Some(CFF3000State::Manual), None])).build().unwrap());
+    let lines = run(device, concat!(
+        r#"{"jsonrpc":"2.0","id":1,"method":"state"}"#, "\n",
+        r#"{"jsonrpc":"2.0","id":"two","method":"lock","params":[]}"#, "\n",
+        /* the device shows manual */
+        r#"{"jsonrpc":"2.0","id":3,"method":"unlock","params":{}}"#, "\n",
+        r#"{"jsonrpc":"2.0","id":4,"method":"state"}"#, "\n",
+    ));
+    /* one queue, so the responses come in order */
+    assert_eq!(lines, vec![
+        r#"{"jsonrpc":"2.0","id":1,"result":{"state":"unlocked"}}"#,
+        r#"{"jsonrpc":"2.0","id":"two","result":{"state":"locked"}}"#,
+        r#"{"jsonrpc":"2.0","id":3,"error":{"code":16,"message":"not confirmed, the device shows manual","data":{"code":"not-confirmed","state":"manual"}}}"#,
+        r#"{"jsonrpc":"2.0","id":4,"error":{"code":4,"message":"did not receive enough LED change events","data":{"code":"no-response"}}}"#,
+    ]);
+}
+
+#[test]
+fn malformed_frames() {
+    let too_long = format!("{}\n", "x".repeat(MAX_FRAME + 10));
+    let input = [
+        "{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"state\"\n",
+        "[]\n",
+        "[1]\n",
+        "{\"jsonrpc\":\"1.0\",\"id\":2,\"method\":\"state\"}\n",
+        "{\"jsonrpc\":\"2.0\",\"id\":3,\"method\":\"open\"}\n",
+        "{\"jsonrpc\":\"2.0\",\"id\":4,\"method\":\"lock\",\"params\":[true]}\n",
+        "{\"jsonrpc\":\"2.0\",\"id\":[5],\"method\":\"lock\"}\n",
+        "{\"jsonrpc\":\"2.0\",\"id\":6,\"method\":7}\n",
+        &too_long,
+        "\n",
+        /* notifications get no response, not even an error */
+        "{\"jsonrpc\":\"2.0\",\"method\":\"open\"}\n",
+        "{\"jsonrpc\":\"2.0\",\"id\":8,\"method\":\"subscribe\",\"params\":\"all\"}\n",
+    ].concat();
+    let device = Arc::new(testing::device(&Replay::new()).build().unwrap());
+    let lines = run(device, &input);
+    assert_eq!(lines, vec![
+        r#"{"jsonrpc":"2.0","id":null,"error":{"code":-32700,"message":"invalid JSON"}}"#,
+        r#"{"jsonrpc":"2.0","id":null,"error":{"code":-32600,"message":"empty batch"}}"#,
+        r#"[{"jsonrpc":"2.0","id":null,"error":{"code":-32600,"message":"request is not an object"}}]"#,
+        r#"{"jsonrpc":"2.0","id":2,"error":{"code":-32600,"message":"jsonrpc must be \"2.0\""}}"#,
+        r#"{"jsonrpc":"2.0","id":3,"error":{"code":-32601,"message":"unknown method open"}}"#,
+        r#"{"jsonrpc":"2.0","id":4,"error":{"code":-32602,"message":"lock takes no parameters"}}"#,
+        r#"{"jsonrpc":"2.0","id":null,"error":{"code":-32600,"message":"id must be a string, a number or null"}}"#,
+        r#"{"jsonrpc":"2.0","id":6,"error":{"code":-32600,"message":"method must be a string"}}"#,
+        r#"{"jsonrpc":"2.0","id":null,"error":{"code":-32600,"message":"request longer than 65536 bytes"}}"#,
+        r#"{"jsonrpc":"2.0","id":8,"error":{"code":-32600,"message":"params must be an array or an object"}}"#,
+    ]);
+}
+
+#[test]
+fn batch() {
+    let device = Arc::new(testing::device(&Replay::with_captures(&[Some(CFF3000State::Locked)])).build().unwrap());
+    let lines = run(device, concat!(
+        r#"[{"jsonrpc":"2.0","id":1,"method":"state"},{"jsonrpc":"2.0","method":"check"},{"jsonrpc":"2.0","id":2,"method":"nothing"}]"#, "\n",
+        r#"[{"jsonrpc":"2.0","method":"state"}]"#, "\n",
+    ));
+    assert_eq!(lines, vec![
+        r#"[{"jsonrpc":"2.0","id":1,"result":{"state":"locked"}},{"jsonrpc":"2.0","id":2,"error":{"code":-32601,"message":"unknown method nothing"}}]"#,
+    ]);
+}
+
+/// Input fed by the test while `serve_rpc()` runs.
+struct Input {
+    lines: mpsc::Receiver<String>,
+    rest: Vec<u8>,
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.rest.is_empty() {
+            match self.lines.recv() {
+                Ok(line) => self.rest = line.into_bytes(),
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = std::cmp::min(buf.len(), self.rest.len());
+        buf[..n].copy_from_slice(&self.rest[..n]);
+        self.rest.drain(..n);
+        Ok(n)
+    }
+}
+
+#[test]
+fn subscribe() {
+    let states = [CFF3000State::Locked, CFF3000State::Locked, CFF3000State::Unlocked];
+    let device = Arc::new(testing::device(&Replay::with_captures(&states.iter().cloned().map(Some).collect::<Vec<_>>())).build().unwrap());
+    let (lines, input) = mpsc::channel();
+    let output = Output::default();
+    let options = RpcOptions {watch: WatchOptions::new(Duration::from_secs(60)), ..RpcOptions::default()};
+    let server = {
+        let output = output.clone();
+        std::thread::spawn(move || serve_rpc(device, BufReader::new(Input {lines: input, rest: Vec::new()}), output, &options))
+    };
+
+    lines.send("{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"subscribe\"}\n".to_string()).unwrap();
+    lines.send("{\"jsonrpc\":\"2.0\",\"id\":2,\"method\":\"subscribe\"}\n".to_string()).unwrap();
+    /* on virtual time, the loop runs out of captures right away and gives up */
+    for _ in 0..500 {
+        if output.lines().iter().any(|line| line.contains("subscription_failed")) {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    drop(lines);
+    server.join().unwrap().unwrap();
+
+    let lines = output.lines();
+    let responses: Vec<&String> = lines.iter().filter(|line| line.contains("\"id\"")).collect();
+    assert_eq!(responses, vec![r#"{"jsonrpc":"2.0","id":1,"result":true}"#, r#"{"jsonrpc":"2.0","id":2,"result":true}"#]);
+    /* the times are those of the virtual clock */
+    let notifications: Vec<String> = lines.iter().filter(|line| !line.contains("\"id\"")).map(|line| match line.find(",\"at_ms\":") {
+        Some(at) => format!("{}}}}}", &line[..at]),
+        None => line.clone(),
+    }).collect();
+    assert_eq!(notifications, vec![
+        r#"{"jsonrpc":"2.0","method":"state_changed","params":{"state":"locked","previous":null,"trigger":"poll"}}"#,
+        r#"{"jsonrpc":"2.0","method":"state_changed","params":{"state":"unlocked","previous":"locked","trigger":"poll"}}"#,
+        r#"{"jsonrpc":"2.0","method":"subscription_failed","params":{"code":"no-response","message":"did not receive enough LED change events"}}"#,
+    ]);
+}
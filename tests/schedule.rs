@@ -0,0 +1,133 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! `schedule` times in a time zone with daylight saving time, and the
+//! `Scheduler` on the replay backend.
+
+use std::io::ErrorKind;
+use std::sync::mpsc;
+use std::sync::Once;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use cff3000::schedule::{ScheduleAction, ScheduleEntry, Scheduler, TimeOfDay, Weekday};
+use cff3000::testing::{self, Replay};
+use cff3000::{CFF3000State, CommandQueue};
+
+/// Central European time, switching on the last Sundays of March and
+/// October. Set once, the C library reads it only on the first use.
+fn zone() {
+    static ZONE: Once = Once::new();
+    ZONE.call_once(|| std::env::set_var("TZ", "CET-1CEST,M3.5.0,M10.5.0/3"));
+}
+
+/// UTC time of the date and time.
+fn utc(year: i64, month: i64, day: i64, hour: u64, minute: u64) -> SystemTime {
+    /* days_from_civil of Howard Hinnant */
+    let year = if month <= 2 {year - 1} else {year};
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * (if month > 2 {month - 3} else {month + 9}) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = (era * 146097 + doe - 719468) as u64;
+    UNIX_EPOCH + Duration::from_secs(days * 86400 + hour * 3600 + minute * 60)
+}
+
+fn entry(at: &str, action: ScheduleAction) -> ScheduleEntry {
+    ScheduleEntry::new(at.parse().unwrap(), action)
+}
+
+#[test]
+fn times_of_day() {
+    let at: TimeOfDay = "7:05".parse().unwrap();
+    assert_eq!((at.hour(), at.minute()), (7, 5));
+    assert_eq!(at.to_string(), "07:05");
+    assert_eq!("23:59".parse::<TimeOfDay>().unwrap(), TimeOfDay::new(23, 59).unwrap());
+    for text in &["24:00", "12:60", "7:5", "07-30", "", ":30", "+1:30", "12:30:00"] {
+        let err = text.parse::<TimeOfDay>().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput, "{}", text);
+    }
+    assert_eq!(entry("22:30", ScheduleAction::EnsureLocked).initiator(), "schedule:ensure_locked@22:30");
+}
+
+#[test]
+fn local_times() {
+    zone();
+    /* Saturday, 21:45 in winter */
+    let time = utc(2026, 1, 10, 20, 45);
+    assert_eq!(Weekday::of(time), Weekday::Sat);
+    assert_eq!(TimeOfDay::of(time), TimeOfDay::new(21, 45).unwrap());
+    assert_eq!(Weekday::of(utc(2026, 1, 10, 23, 0)), Weekday::Sun);
+    assert_eq!(TimeOfDay::of(utc(2026, 7, 1, 12, 0)), TimeOfDay::new(14, 0).unwrap());
+}
+
+#[test]
+fn slots_on_the_days() {
+    zone();
+    let every_day = entry("22:30", ScheduleAction::EnsureLocked);
+    assert_eq!(every_day.slots(utc(2026, 1, 10, 0, 0), utc(2026, 1, 12, 0, 0)), vec![utc(2026, 1, 10, 21, 30), utc(2026, 1, 11, 21, 30)]);
+    /* after, until and including */
+    assert_eq!(every_day.slots(utc(2026, 1, 10, 21, 30), utc(2026, 1, 11, 21, 30)), vec![utc(2026, 1, 11, 21, 30)]);
+    assert_eq!(every_day.slots(utc(2026, 1, 11, 0, 0), utc(2026, 1, 10, 0, 0)), vec![]);
+    assert_eq!(every_day.slots(utc(2026, 7, 1, 0, 0), utc(2026, 7, 2, 0, 0)), vec![utc(2026, 7, 1, 20, 30)]);
+
+    let mut weekend = entry("9:00", ScheduleAction::EnsureUnlocked);
+    weekend.days = vec![Weekday::Sat, Weekday::Sun];
+    assert!(weekend.runs_on(Weekday::Sun) && !weekend.runs_on(Weekday::Mon));
+    assert_eq!(weekend.slots(utc(2026, 1, 5, 0, 0), utc(2026, 1, 12, 0, 0)), vec![utc(2026, 1, 10, 8, 0), utc(2026, 1, 11, 8, 0)]);
+}
+
+#[test]
+fn daylight_saving_time() {
+    zone();
+    /* 02:00 to 03:00 is skipped on 2026-03-29 */
+    let skipped = entry("2:30", ScheduleAction::EnsureLocked);
+    assert_eq!(skipped.slots(utc(2026, 3, 28, 12, 0), utc(2026, 3, 30, 12, 0)), vec![utc(2026, 3, 29, 1, 30), utc(2026, 3, 30, 0, 30)]);
+    assert_eq!(TimeOfDay::of(utc(2026, 3, 29, 1, 30)), TimeOfDay::new(3, 30).unwrap());
+    let evening = entry("22:30", ScheduleAction::EnsureLocked);
+    assert_eq!(evening.slots(utc(2026, 3, 28, 12, 0), utc(2026, 3, 30, 12, 0)), vec![utc(2026, 3, 28, 21, 30), utc(2026, 3, 29, 20, 30)]);
+
+    /* 02:00 to 03:00 is repeated on 2026-10-25 */
+    let repeated = entry("2:30", ScheduleAction::EnsureLocked);
+    assert_eq!(repeated.slots(utc(2026, 10, 24, 12, 0), utc(2026, 10, 25, 12, 0)), vec![utc(2026, 10, 25, 0, 30)]);
+    assert_eq!(TimeOfDay::of(utc(2026, 10, 25, 1, 30)), TimeOfDay::new(2, 30).unwrap());
+}
+
+fn queue(captures: &[Option<CFF3000State>]) -> CommandQueue {
+    CommandQueue::new(testing::device(&Replay::with_captures(captures)).build().unwrap()).unwrap()
+}
+
+fn minutes_ago(minutes: u64) -> TimeOfDay {
+    TimeOfDay::of(SystemTime::now() - Duration::from_secs(minutes * 60))
+}
+
+#[test]
+fn scheduler_runs_missed_times_within_the_grace() {
+    zone();
+    let queue = queue(&[Some(CFF3000State::Unlocked), Some(CFF3000State::Locked)]);
+    let missed = ScheduleEntry::new(minutes_ago(2), ScheduleAction::EnsureLocked);
+    let mut too_late = ScheduleEntry::new(minutes_ago(30), ScheduleAction::Check);
+    too_late.grace = Duration::from_secs(10 * 60);
+    let mut other_day = ScheduleEntry::new(minutes_ago(1), ScheduleAction::Check);
+    other_day.days = Weekday::ALL.iter().cloned().filter(|&day| day != Weekday::of(SystemTime::now())).collect();
+
+    let (tx, rx) = mpsc::channel();
+    let scheduler = Scheduler::start(vec![too_late, missed.clone(), other_day], queue.sender(), move |run| tx.send(run).unwrap()).unwrap();
+    let run = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(run.entry, missed);
+    assert!(run.slot <= SystemTime::now() - Duration::from_secs(2 * 60));
+    assert_eq!(run.result.unwrap(), CFF3000State::Locked);
+    assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+    drop(scheduler);
+}
+
+#[test]
+fn ensure_skips_the_press_in_the_wanted_state() {
+    zone();
+    /* a lock press would find no LED pattern */
+    let queue = queue(&[Some(CFF3000State::Locked)]);
+    let (tx, rx) = mpsc::channel();
+    let _scheduler = Scheduler::start(vec![ScheduleEntry::new(minutes_ago(1), ScheduleAction::EnsureLocked)], queue.sender(), move |run| {
+        let _ = tx.send(run);
+    }).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap().result.unwrap(), CFF3000State::Locked);
+}
@@ -0,0 +1,83 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Button read back of `CFF3000::self_test()` on the mock backend, with
+//! both polarities and a stuck line.
+
+use std::time::Duration;
+
+use cff3000::mock::{MockBackend, Script};
+use cff3000::{Button, CFF3000Builder, Command, GpioBackend, Led, LedEvent, LineInfo, LineRole, Polarities, Polarity, SelfTestReport, SelfTestResult, Timings};
+
+fn self_test<B: GpioBackend + 'static>(backend: B, polarities: Polarities) -> SelfTestReport {
+    let timings = Timings {press: Duration::from_millis(50), check_capture: Duration::from_millis(300), ..Timings::default()};
+    CFF3000Builder::with_backend(backend).timings(timings).polarities(polarities).build().unwrap().self_test().unwrap()
+}
+
+fn mock() -> MockBackend {
+    let mock = MockBackend::new();
+    mock.respond(Command::Check, Script::from_levels(&[(10, 0b11), (110, 0b10), (210, 0b00)]));
+    mock
+}
+
+fn readback(report: &SelfTestReport, name: &str) -> SelfTestResult {
+    report.items.iter().find(|item| item.name == name).unwrap().result.clone()
+}
+
+#[test]
+fn readback_passes() {
+    let report = self_test(mock(), Polarities::default());
+    assert_eq!(readback(&report, "button-unlock-readback"), SelfTestResult::Pass);
+    assert_eq!(readback(&report, "button-lock-readback"), SelfTestResult::Pass);
+}
+
+#[test]
+fn readback_passes_on_active_low_buttons() {
+    /* the mock sees the electrical levels, low while pressed */
+    let polarities = Polarities {button_unlock: Polarity::ActiveLow, button_lock: Polarity::ActiveLow, ..Polarities::default()};
+    let report = self_test(mock(), polarities);
+    assert_eq!(readback(&report, "button-unlock-readback"), SelfTestResult::Pass);
+    assert_eq!(readback(&report, "button-lock-readback"), SelfTestResult::Pass);
+}
+
+/// Mock whose lock button line stays low.
+struct Stuck(MockBackend);
+
+impl GpioBackend for Stuck {
+    fn set_button(&self, button: Button, pressed: bool) -> std::io::Result<()> {
+        self.0.set_button(button, pressed)
+    }
+
+    fn wait_for_led_events(&self, timeout: Duration) -> std::io::Result<u8> {
+        self.0.wait_for_led_events(timeout)
+    }
+
+    fn read_led_event(&self, led: Led) -> std::io::Result<LedEvent> {
+        self.0.read_led_event(led)
+    }
+
+    fn flush_led_events(&self) -> std::io::Result<()> {
+        self.0.flush_led_events()
+    }
+
+    fn line_info(&self) -> std::io::Result<[LineInfo; 4]> {
+        let mut lines = self.0.line_info()?;
+        for line in lines.iter_mut().filter(|line| line.role == LineRole::ButtonLock) {
+            line.level = false;
+        }
+        Ok(lines)
+    }
+}
+
+#[test]
+fn stuck_lines_fail() {
+    let report = self_test(Stuck(mock()), Polarities::default());
+    assert_eq!(readback(&report, "button-unlock-readback"), SelfTestResult::Pass);
+    assert_eq!(readback(&report, "button-lock-readback"), SelfTestResult::Fail("line stays low while pressed".to_string()));
+    assert!(!report.passed());
+
+    /* low is released for active-low buttons */
+    let polarities = Polarities {button_lock: Polarity::ActiveLow, ..Polarities::default()};
+    let report = self_test(Stuck(mock()), polarities);
+    assert_eq!(readback(&report, "button-lock-readback"), SelfTestResult::Fail("line stays low while released".to_string()));
+}
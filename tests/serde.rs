@@ -0,0 +1,197 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! JSON snapshots of the serde representations, see `cff3000::schema`.
+
+use std::time::{Duration, UNIX_EPOCH};
+
+use cff3000::health::{HealthProblem, HealthReport, HealthVerdict};
+use cff3000::history::{HistoryEntry, HistoryError};
+use cff3000::{CFF3000State, Command, EventBuffer, Led, LedEvent, StateChange, StateReport, Trigger, WallAnchor, MAX_EVENTS};
+
+fn report() -> StateReport {
+    StateReport {
+        state: CFF3000State::Locked,
+        events: EventBuffer::from_slice(&[
+            LedEvent {led: Led::Red, on: true, timestamp: Duration::from_millis(700)},
+            LedEvent {led: Led::Green, on: false, timestamp: Duration::from_millis(1_950)},
+        ]).unwrap(),
+        lost_events: 0,
+        truncated: false,
+        anchor: None,
+    }
+}
+
+#[test]
+fn names_are_stable() {
+    for &state in &[CFF3000State::Locked, CFF3000State::Unlocked, CFF3000State::Manual, CFF3000State::OutOfRange] {
+        let json = serde_json::to_string(&state).unwrap();
+        assert_eq!(json, format!("\"{}\"", state.name()));
+        assert_eq!(serde_json::from_str::<CFF3000State>(&json).unwrap(), state);
+    }
+    let commands: Vec<String> = [Command::Lock, Command::Unlock, Command::Check].iter().map(|command| serde_json::to_string(command).unwrap()).collect();
+    assert_eq!(commands, vec!["\"lock\"", "\"unlock\"", "\"check\""]);
+    assert_eq!(serde_json::to_string(&[Led::Red, Led::Green]).unwrap(), r#"["red","green"]"#);
+    assert!(serde_json::from_str::<CFF3000State>("\"OutOfRange\"").is_err());
+}
+
+#[test]
+fn led_events_serialize() {
+    let event = LedEvent {led: Led::Green, on: true, timestamp: Duration::from_millis(700)};
+    let json = serde_json::to_string(&event).unwrap();
+    assert_eq!(json, r#"{"led":"green","on":true,"timestamp_ns":700000000}"#);
+    assert_eq!(serde_json::from_str::<LedEvent>(&json).unwrap(), event);
+    assert!(serde_json::from_str::<LedEvent>(r#"{"led":"green","on":true,"timestamp_ns":1,"level":1}"#).is_err());
+    assert!(serde_json::from_str::<LedEvent>(r#"{"led":"green","on":true,"timestamp":1}"#).is_err());
+}
+
+#[test]
+fn timestamps_serialize_in_nanoseconds() {
+    /* zero, below a millisecond and many minutes in, exactly */
+    for &(timestamp, ns) in &[(Duration::ZERO, "0"), (Duration::from_nanos(400_001), "400001"), (Duration::new(600, 1), "600000000001")] {
+        let event = LedEvent {led: Led::Red, on: false, timestamp};
+        let json = serde_json::to_string(&event).unwrap();
+        assert_eq!(json, format!(r#"{{"led":"red","on":false,"timestamp_ns":{}}}"#, ns));
+        assert_eq!(serde_json::from_str::<LedEvent>(&json).unwrap(), event);
+    }
+    /* beyond u64::MAX nanoseconds (584 years) */
+    assert!(serde_json::to_string(&LedEvent {led: Led::Red, on: false, timestamp: Duration::MAX}).is_err());
+    assert!(serde_json::from_str::<LedEvent>(r#"{"led":"red","on":false,"timestamp_ns":-1}"#).is_err());
+}
+
+#[test]
+fn state_reports_serialize() {
+    let json = serde_json::to_string(&report()).unwrap();
+    assert_eq!(json, r#"{"state":"locked","events":[{"led":"red","on":true,"timestamp_ns":700000000},{"led":"green","on":false,"timestamp_ns":1950000000}],"lost_events":0}"#);
+    assert_eq!(serde_json::from_str::<StateReport>(&json).unwrap(), report());
+    assert!(serde_json::from_str::<StateReport>(r#"{"state":"locked","events":[],"lost_events":0,"cached":true}"#).is_err());
+    assert!(serde_json::from_str::<StateReport>(r#"{"state":"locked","events":[]}"#).is_err());
+
+    /* only truncated captures say so */
+    let truncated = StateReport {truncated: true, ..report()};
+    let json = serde_json::to_string(&truncated).unwrap();
+    assert!(json.ends_with(r#""lost_events":0,"truncated":true}"#), "{}", json);
+    assert_eq!(serde_json::from_str::<StateReport>(&json).unwrap(), truncated);
+    let events = format!("[{}]", vec![r#"{"led":"red","on":true,"timestamp_ns":1}"#; MAX_EVENTS + 1].join(","));
+    assert!(serde_json::from_str::<EventBuffer>(&events).is_err());
+}
+
+#[test]
+fn anchored_reports_serialize() {
+    let anchor = WallAnchor {wall: UNIX_EPOCH + Duration::from_millis(1_760_000_000_123), timestamp: Duration::from_millis(700)};
+    let report = StateReport {anchor: Some(anchor), ..report()};
+    let json = serde_json::to_string(&report).unwrap();
+    #[cfg(not(feature = "chrono"))]
+    let wall = "1760000000123";
+    #[cfg(feature = "chrono")]
+    let wall = "\"2025-10-09T08:53:20.123Z\"";
+    assert!(json.ends_with(&format!(r#""lost_events":0,"anchor":{{"wall":{},"timestamp_ns":700000000}}}}"#, wall)), "{}", json);
+    assert_eq!(serde_json::from_str::<StateReport>(&json).unwrap(), report);
+    /* devices with chrono read the milliseconds as well */
+    let millis = serde_json::from_str::<WallAnchor>(r#"{"wall":1760000000123,"timestamp_ns":700000000}"#).unwrap();
+    assert_eq!(millis, anchor);
+    assert!(serde_json::from_str::<WallAnchor>(r#"{"wall":1760000000123,"timestamp_ns":700000000,"zone":"UTC"}"#).is_err());
+}
+
+#[test]
+fn state_changes_serialize() {
+    let at = UNIX_EPOCH + Duration::from_millis(1_760_000_000_123);
+    let change = StateChange::new(Some(CFF3000State::Unlocked), CFF3000State::Locked, Trigger::AutoLock, at);
+    let json = serde_json::to_string(&change).unwrap();
+    assert_eq!(json, r#"{"state":"locked","previous":"unlocked","trigger":"auto-lock","at_ms":1760000000123}"#);
+    assert_eq!(serde_json::from_str::<StateChange>(&json).unwrap(), change);
+
+    let first = StateChange::new(None, CFF3000State::Unlocked, Trigger::External, at);
+    assert_eq!(serde_json::to_string(&first).unwrap(), r#"{"state":"unlocked","previous":null,"trigger":"external","at_ms":1760000000123}"#);
+    let triggers: Vec<String> = [Trigger::Poll, Trigger::AutoLock, Trigger::Schedule, Trigger::Command, Trigger::External].iter().map(|trigger| {
+        assert_eq!(serde_json::to_string(trigger).unwrap(), format!("\"{}\"", trigger));
+        trigger.name().to_string()
+    }).collect();
+    assert_eq!(triggers, vec!["poll", "auto-lock", "schedule", "command", "external"]);
+    assert!(serde_json::from_str::<StateChange>(r#"{"state":"locked","previous":null,"trigger":"poll","at_ms":1,"device":"x"}"#).is_err());
+}
+
+#[test]
+fn history_entries_serialize() {
+    let timestamp = UNIX_EPOCH + Duration::from_millis(1_760_000_000_123);
+    let entry = |command, result| HistoryEntry {timestamp, command, result, duration: Duration::from_millis(10_204)};
+    let entries = [
+        entry(Command::Lock, Ok(Some(CFF3000State::Locked))),
+        entry(Command::Unlock, Ok(None)),
+        entry(Command::Check, Err(HistoryError {code: "no-response", message: "did not receive enough LED change events".to_string()})),
+    ];
+    let lines: Vec<String> = entries.iter().map(|entry| serde_json::to_string(entry).unwrap()).collect();
+    assert_eq!(lines, vec![
+        r#"{"timestamp_ms":1760000000123,"command":"lock","state":"locked","duration_ms":10204}"#,
+        r#"{"timestamp_ms":1760000000123,"command":"unlock","duration_ms":10204}"#,
+        r#"{"timestamp_ms":1760000000123,"command":"check","error":{"code":"no-response","message":"did not receive enough LED change events"},"duration_ms":10204}"#,
+    ]);
+}
+
+#[test]
+fn health_reports_serialize() {
+    let report = HealthReport {
+        verdict: HealthVerdict::Degraded,
+        reasons: vec![HealthProblem::WatchdogStopped],
+        chip_reachable: Some(true),
+        lines_held: None,
+        last_state_age: Some(Duration::from_millis(1500)),
+        last_error: Some(HistoryError {code: "io", message: "gone".to_string()}),
+        watchdog_alive: Some(false),
+        queue_depth: None,
+    };
+    let json = serde_json::to_string(&report).unwrap();
+    let expected = format!(r#"{{"verdict":"degraded","reasons":[{}],"chip_reachable":true,"last_state_age_ms":1500,"last_error":{{"code":"io","message":"gone"}},"watchdog_alive":false}}"#,
+        serde_json::to_string(&HealthProblem::WatchdogStopped.to_string()).unwrap());
+    assert_eq!(json, expected);
+}
+
+/// The serde representations are the ones the HTTP server writes.
+#[cfg(feature = "http")]
+#[test]
+fn integrations_agree() {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::Arc;
+
+    use cff3000::http::{serve_with_options, HttpOptions};
+    use cff3000::testing::{self, Replay};
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(format!("GET {} HTTP/1.1\r\nHost: door\r\n\r\n", path).as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response[response.find("\r\n\r\n").unwrap() + 4..].trim_end().to_string()
+    }
+
+    let replay = Replay::with_captures(&[Some(CFF3000State::Unlocked), None]);
+    let device = Arc::new(testing::device(&replay).build().unwrap());
+    let server = serve_with_options(device.clone(), "127.0.0.1:0", HttpOptions::default()).unwrap();
+    get(server.local_addr(), "/state");
+    get(server.local_addr(), "/state");
+
+    let history = device.history();
+    assert_eq!(history.len(), 2);
+    assert_eq!(get(server.local_addr(), "/history"), format!("{{\"history\":{}}}", serde_json::to_string(&history).unwrap()));
+    /* with the depth of the queue of the server */
+    let mut health = device.health_check();
+    health.queue_depth = Some(0);
+    assert_eq!(get(server.local_addr(), "/healthz"), serde_json::to_string(&health).unwrap());
+
+    /* and the events of a change */
+    let change = StateChange::new(None, CFF3000State::Unlocked, Trigger::Poll, UNIX_EPOCH + Duration::from_millis(1_760_000_000_123));
+    server.publish(change);
+    let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(b"GET /events HTTP/1.1\r\nHost: door\r\n\r\n").unwrap();
+    let expected = format!("event: state\ndata: {}\n\n", serde_json::to_string(&change).unwrap());
+    let mut response = Vec::new();
+    let mut buf = [0; 512];
+    while !String::from_utf8_lossy(&response).ends_with(&expected) {
+        let n = stream.read(&mut buf).unwrap();
+        assert!(n != 0, "stream ended");
+        response.extend_from_slice(&buf[..n]);
+    }
+}
@@ -0,0 +1,230 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! `UnixServer` and `UnixClient` against the replay backend.
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use cff3000::socket::{serve, serve_with_options, PeerCredentials, PeerPolicy, SocketOptions, UnixClient};
+use cff3000::testing::{self, Replay};
+use cff3000::{CFF3000State, Command, LockControl, ParseError};
+
+fn socket_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("cff3000-{}-{}.sock", std::process::id(), name))
+}
+
+/// Send raw request lines and return the response lines.
+fn exchange(path: &PathBuf, requests: &[&str]) -> Vec<String> {
+    let mut stream = UnixStream::connect(path).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    requests.iter().map(|request| {
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        line.trim_end().to_string()
+    }).collect()
+}
+
+/// Application code working with either kind of `LockControl`.
+fn lock_door<L: LockControl>(door: &L) -> std::io::Result<CFF3000State> {
+    door.lock_and_verify()
+}
+
+#[test]
+fn client_controls_the_device() {
+    let device = Arc::new(testing::device(&Replay::with_captures(&[Some(CFF3000State::Locked), Some(CFF3000State::Unlocked), None, Some(CFF3000State::Locked)])).build().unwrap());
+    let path = socket_path("client");
+    let server = serve(device.clone(), &path).unwrap();
+    let client = UnixClient::connect(server.path()).unwrap();
+
+    assert_eq!(lock_door(&client).unwrap(), CFF3000State::Locked);
+    assert_eq!(client.unlock_and_verify().unwrap(), CFF3000State::Unlocked);
+    let err = client.state().unwrap_err();
+    assert_eq!(err.get_ref().and_then(|inner| inner.downcast_ref::<ParseError>()), Some(&ParseError::NotEnoughEvents));
+    assert_eq!(lock_door(&*device).unwrap(), CFF3000State::Locked);
+
+    drop(server);
+    assert!(!path.exists());
+    assert!(client.state().is_err());
+}
+
+#[test]
+fn protocol_lines() {
+    let device = Arc::new(testing::device(&Replay::with_captures(&[Some(CFF3000State::Manual)])).build().unwrap());
+    let path = socket_path("protocol");
+    let _server = serve(device, &path).unwrap();
+    assert_eq!(exchange(&path, &[
+        "{\"cmd\":\"check\"}\n",
+        "{\"cmd\": \"open\"}\n",
+        "[1]\n",
+        "{\"cmd\":\"lock\",\"timeout_ms\":-1}\n",
+        "{\"cmd\":\"check\"}\n",
+    ]), vec![
+        r#"{"ok":true,"state":"manual"}"#,
+        r#"{"ok":false,"error":{"code":"config","message":"unknown cmd open"}}"#,
+        r#"{"ok":false,"error":{"code":"config","message":"request is not a JSON object"}}"#,
+        r#"{"ok":false,"error":{"code":"config","message":"timeout_ms must be a non-negative number"}}"#,
+        r#"{"ok":false,"error":{"code":"no-response","message":"did not receive enough LED change events"}}"#,
+    ]);
+    let long = format!("{{\"cmd\":\"{}\"}}\n", "x".repeat(2000));
+    assert_eq!(exchange(&path, &[&long]), vec![r#"{"ok":false,"error":{"code":"config","message":"request too long"}}"#]);
+}
+
+#[test]
+fn requests_time_out() {
+    let device = Arc::new(testing::device(&Replay::with_captures(&[Some(CFF3000State::Locked)])).build().unwrap());
+    let path = socket_path("timeout");
+    let _server = serve(device.clone(), &path).unwrap();
+    /* the queued check waits for the press to end */
+    let press = device.begin_lock_press().unwrap();
+    assert_eq!(exchange(&path, &["{\"cmd\":\"check\",\"timeout_ms\":50}\n"]),
+               vec![r#"{"ok":false,"error":{"code":"timeout","message":"no result within 50 ms"}}"#]);
+    let client = UnixClient::connect_with_timeout(&path, Duration::from_millis(50)).unwrap();
+    assert_eq!(client.state().unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+    drop(press);
+}
+
+#[test]
+fn socket_file_permissions() {
+    let device = Arc::new(testing::device(&Replay::new()).build().unwrap());
+    let path = socket_path("mode");
+    let options = SocketOptions {mode: 0o600, ..SocketOptions::default()};
+    let server = serve_with_options(device.clone(), &path, options).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+    /* a live server is not replaced, a stale socket and its file are */
+    assert_eq!(serve(device.clone(), &path).err().unwrap().kind(), std::io::ErrorKind::AddrInUse);
+    drop(server);
+    let stale = std::os::unix::net::UnixListener::bind(&path).unwrap();
+    drop(stale);
+    let _server = serve(device.clone(), &path).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o660);
+
+    let file = socket_path("regular");
+    std::fs::write(&file, b"").unwrap();
+    assert_eq!(serve(device, &file).err().unwrap().kind(), std::io::ErrorKind::AddrInUse);
+    std::fs::remove_file(&file).unwrap();
+}
+
+/// User of this process, as the owner of a new file.
+fn own_uid() -> u32 {
+    let file = socket_path("uid");
+    std::fs::write(&file, b"").unwrap();
+    let uid = std::fs::metadata(&file).unwrap().uid();
+    std::fs::remove_file(&file).unwrap();
+    uid
+}
+
+#[test]
+fn peer_credentials() {
+    let (stream, _other) = UnixStream::pair().unwrap();
+    let peer = PeerCredentials::of(&stream).unwrap();
+    assert_eq!(peer.uid, own_uid());
+    assert!(!peer.gids.is_empty());
+
+    let policy = PeerPolicy {control_uids: vec![1000], control_gids: vec![27], state_gids: vec![100], ..PeerPolicy::default()};
+    let user = |uid, gids: &[u32]| PeerCredentials {uid, gids: gids.to_vec()};
+    assert!(policy.allows(&user(1000, &[1000]), Command::Unlock));
+    assert!(policy.allows(&user(1001, &[1001, 27]), Command::Lock));
+    assert!(policy.allows(&user(1002, &[100]), Command::Check));
+    assert!(!policy.allows(&user(1002, &[100]), Command::Unlock));
+    assert!(!policy.allows(&user(0, &[0]), Command::Check));
+}
+
+#[test]
+fn policy_refuses_commands() {
+    let device = Arc::new(testing::device(&Replay::with_captures(&[Some(CFF3000State::Locked), Some(CFF3000State::Unlocked)])).build().unwrap());
+    let path = socket_path("policy");
+    let policy = PeerPolicy {state_uids: vec![own_uid()], ..PeerPolicy::default()};
+    let server = serve_with_options(device.clone(), &path, SocketOptions {policy: Some(policy), ..SocketOptions::default()}).unwrap();
+    let message = format!("uid {} may not unlock", own_uid());
+    assert_eq!(exchange(&path, &["{\"cmd\":\"check\"}\n", "{\"cmd\":\"unlock\"}\n"]), vec![
+        r#"{"ok":true,"state":"locked"}"#.to_string(),
+        format!(r#"{{"ok":false,"error":{{"code":"permission-denied","message":"{}"}}}}"#, message),
+    ]);
+    let err = UnixClient::connect(&path).unwrap().lock_and_verify().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    drop(server);
+
+    let policy = PeerPolicy {control_uids: vec![own_uid()], ..PeerPolicy::default()};
+    let _server = serve_with_options(device, &path, SocketOptions {policy: Some(policy), ..SocketOptions::default()}).unwrap();
+    assert_eq!(UnixClient::connect(&path).unwrap().unlock_and_verify().unwrap(), CFF3000State::Unlocked);
+}
+
+#[test]
+fn history_is_answered() {
+    let device = Arc::new(testing::device(&Replay::with_captures(&[Some(CFF3000State::Locked), None])).build().unwrap());
+    let path = socket_path("history");
+    let server = serve(device.clone(), &path).unwrap();
+    let client = UnixClient::connect(server.path()).unwrap();
+    assert_eq!(client.history().unwrap(), vec![]);
+    assert_eq!(exchange(&path, &["{\"cmd\":\"history\"}\n"]), vec![r#"{"ok":true,"history":[]}"#.to_string()]);
+
+    client.lock_and_verify().unwrap();
+    client.state().unwrap_err();
+    let history = client.history().unwrap();
+    assert_eq!(history.iter().map(|entry| entry.command).collect::<Vec<_>>(), vec![Command::Lock, Command::Check]);
+    assert_eq!(history[0].result, Ok(Some(CFF3000State::Locked)));
+    assert_eq!(history[1].result.as_ref().unwrap_err().code, "no-response");
+    assert!(history[0].timestamp <= history[1].timestamp);
+    drop(server);
+
+    /* like check, refused to peers without any permission */
+    let _server = serve_with_options(device, &path, SocketOptions {policy: Some(PeerPolicy::default()), ..SocketOptions::default()}).unwrap();
+    let err = UnixClient::connect(&path).unwrap().history().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+}
+
+#[test]
+fn battery_is_answered_and_reset() {
+    let device = Arc::new(testing::device(&Replay::with_captures(&[Some(CFF3000State::Locked)])).build().unwrap());
+    let path = socket_path("battery");
+    let server = serve(device.clone(), &path).unwrap();
+    let client = UnixClient::connect(server.path()).unwrap();
+    client.lock_and_verify().unwrap();
+    assert_eq!(client.battery().unwrap().commands, 1);
+    let response = &exchange(&path, &["{\"cmd\":\"battery\"}\n"])[0];
+    assert!(response.starts_with(r#"{"ok":true,"battery":{"since_ms":"#), "{}", response);
+    assert!(response.contains(r#""replaced":false,"commands":1,"#), "{}", response);
+
+    let usage = client.reset_battery().unwrap();
+    assert_eq!((usage.commands, usage.replaced), (0, true));
+    assert_eq!(device.battery_marker().commands, 0);
+    drop(server);
+
+    /* reading the usage is a check, recording new batteries a lock */
+    let policy = PeerPolicy {state_uids: vec![own_uid()], ..PeerPolicy::default()};
+    let _server = serve_with_options(device, &path, SocketOptions {policy: Some(policy), ..SocketOptions::default()}).unwrap();
+    let client = UnixClient::connect(&path).unwrap();
+    assert!(client.battery().unwrap().replaced);
+    assert_eq!(client.reset_battery().unwrap_err().kind(), std::io::ErrorKind::PermissionDenied);
+}
+
+#[cfg(feature = "audit")]
+#[test]
+fn refused_commands_are_audited() {
+    use cff3000::audit::{AuditLog, AuditTarget};
+
+    let journal = socket_path("journal");
+    let listener = std::os::unix::net::UnixDatagram::bind(&journal).unwrap();
+    listener.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let log = Arc::new(AuditLog::with_socket(AuditTarget::Journal, &journal).unwrap());
+    let device = Arc::new(testing::device(&Replay::new()).audit(log).build().unwrap());
+
+    let path = socket_path("audit");
+    let _server = serve_with_options(device, &path, SocketOptions {policy: Some(PeerPolicy::default()), ..SocketOptions::default()}).unwrap();
+    assert!(exchange(&path, &["{\"cmd\":\"lock\"}\n"])[0].contains("permission-denied"));
+    let mut buf = [0u8; 4096];
+    let len = listener.recv(&mut buf).unwrap();
+    let record = String::from_utf8_lossy(&buf[..len]).into_owned();
+    assert!(record.contains(&format!("\nCFF3000_INITIATOR=unix:uid={}\n", own_uid())), "{}", record);
+    assert!(record.contains("\nCFF3000_COMMAND=lock\n"), "{}", record);
+    assert!(record.contains("\nCFF3000_ERROR_CODE=permission-denied\n"), "{}", record);
+    std::fs::remove_file(&journal).unwrap();
+}
@@ -0,0 +1,75 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! `systemd::Notifier` sending to a socket standing in for systemd.
+
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+use cff3000::systemd::Notifier;
+
+fn receive(socket: &UnixDatagram) -> String {
+    let mut buf = [0; 256];
+    let n = socket.recv(&mut buf).unwrap();
+    String::from_utf8(buf[..n].to_vec()).unwrap()
+}
+
+fn listener(name: &str) -> (UnixDatagram, std::path::PathBuf) {
+    let path = std::env::temp_dir().join(format!("cff3000-{}-{}.notify", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    let socket = UnixDatagram::bind(&path).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    (socket, path)
+}
+
+#[test]
+fn notifications() {
+    let (socket, path) = listener("messages");
+    let notifier = Notifier::connect(&path).unwrap();
+    notifier.ready().unwrap();
+    notifier.watchdog().unwrap();
+    notifier.status("door\nlocked").unwrap();
+    notifier.stopping().unwrap();
+    assert_eq!(receive(&socket), "READY=1");
+    assert_eq!(receive(&socket), "WATCHDOG=1");
+    assert_eq!(receive(&socket), "STATUS=door locked");
+    assert_eq!(receive(&socket), "STOPPING=1");
+    assert_eq!(notifier.watchdog_interval(), None);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn abstract_socket() {
+    use std::os::linux::net::SocketAddrExt;
+    let name = format!("cff3000-{}-notify", std::process::id());
+    let socket = UnixDatagram::bind_addr(&std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    Notifier::connect(format!("@{}", name)).unwrap().ready().unwrap();
+    assert_eq!(receive(&socket), "READY=1");
+}
+
+/// The only test touching the environment.
+#[test]
+fn environment() {
+    let (socket, path) = listener("env");
+    std::env::remove_var("NOTIFY_SOCKET");
+    assert!(Notifier::from_env().unwrap().is_none());
+
+    std::env::set_var("NOTIFY_SOCKET", &path);
+    std::env::set_var("WATCHDOG_USEC", "20000000");
+    std::env::set_var("WATCHDOG_PID", std::process::id().to_string());
+    let notifier = Notifier::from_env().unwrap().unwrap();
+    assert_eq!(notifier.watchdog_timeout(), Some(Duration::from_secs(20)));
+    assert_eq!(notifier.watchdog_interval(), Some(Duration::from_secs(10)));
+    notifier.watchdog().unwrap();
+    assert_eq!(receive(&socket), "WATCHDOG=1");
+
+    /* the watchdog of another process, e.g. the parent of a fork */
+    std::env::set_var("WATCHDOG_PID", "1");
+    assert_eq!(Notifier::from_env().unwrap().unwrap().watchdog_interval(), None);
+    std::env::set_var("WATCHDOG_PID", std::process::id().to_string());
+    std::env::set_var("WATCHDOG_USEC", "soon");
+    assert_eq!(Notifier::from_env().unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,127 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Telemetry hooks of the operations, on the replay backend.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use cff3000::telemetry::{Telemetry, TelemetryError};
+use cff3000::testing::{self, Replay};
+use cff3000::{CFF3000State, Command, LedEvent};
+
+/// Calls of one or more hooks, "name: call".
+#[derive(Clone, Default)]
+struct Calls(Arc<Mutex<Vec<String>>>);
+
+impl Calls {
+    fn get(&self) -> Vec<String> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+struct Recorder {
+    name: &'static str,
+    calls: Calls,
+    /// LED events of the last failure
+    events: Arc<Mutex<Vec<LedEvent>>>,
+}
+
+impl Recorder {
+    fn new(name: &'static str, calls: &Calls) -> Recorder {
+        Recorder {name, calls: calls.clone(), events: Arc::new(Mutex::new(Vec::new()))}
+    }
+
+    fn push(&self, call: String) {
+        self.calls.0.lock().unwrap().push(format!("{}: {}", self.name, call));
+    }
+}
+
+impl Telemetry for Recorder {
+    fn on_command(&self, command: Command, duration: Duration) {
+        self.push(format!("command {:?} {:?}", command, duration));
+    }
+
+    fn on_state(&self, command: Command, state: CFF3000State, _duration: Duration) {
+        self.push(format!("state {:?} {}", command, state.name()));
+    }
+
+    fn on_error(&self, error: &TelemetryError) {
+        *self.events.lock().unwrap() = error.events.to_vec();
+        self.push(format!("error {:?} {}", error.command, error.code));
+    }
+}
+
+struct Panicking;
+
+impl Telemetry for Panicking {
+    fn on_command(&self, _command: Command, _duration: Duration) {
+        panic!("broken hook");
+    }
+}
+
+#[test]
+fn commands_are_reported() {
+    let calls = Calls::default();
+    let replay = Replay::with_captures(&[None]);
+    let device = testing::device(&replay)
+        .telemetry(Recorder::new("first", &calls))
+        .telemetry(Recorder::new("second", &calls))
+        .build().unwrap();
+    device.lock().unwrap();
+
+    let press = cff3000::Timings::default().press_for(Command::Lock);
+    assert_eq!(calls.get(), vec![format!("first: command Lock {:?}", press), format!("second: command Lock {:?}", press)]);
+}
+
+#[test]
+fn states_are_reported() {
+    let calls = Calls::default();
+    let replay = Replay::with_captures(&[Some(CFF3000State::Locked)]);
+    let device = testing::device(&replay)
+        .telemetry(Recorder::new("hook", &calls))
+        .build().unwrap();
+    assert_eq!(device.state().unwrap(), CFF3000State::Locked);
+    assert_eq!(calls.get(), vec!["hook: state Check locked"]);
+}
+
+#[test]
+fn errors_carry_the_capture() {
+    let calls = Calls::default();
+    let replay = Replay::with_captures(&[None]);
+    let hook = Recorder::new("hook", &calls);
+    let events = hook.events.clone();
+    let device = testing::device(&replay)
+        .telemetry(hook)
+        .build().unwrap();
+    assert!(device.state().is_err());
+    assert_eq!(calls.get(), vec!["hook: error Check no-response"]);
+    assert!(events.lock().unwrap().is_empty());
+
+    /* a pattern the parser does not know is passed along */
+    let pattern = vec![
+        LedEvent {led: cff3000::Led::Red, on: true, timestamp: Duration::from_millis(700)},
+        LedEvent {led: cff3000::Led::Red, on: false, timestamp: Duration::from_millis(900)},
+    ];
+    replay.push_capture(pattern);
+    assert!(device.state().is_err());
+    assert!(calls.get()[1].starts_with("hook: error Check "), "{:?}", calls.get());
+    /* replayed relative to the press */
+    let captured = events.lock().unwrap().clone();
+    let levels: Vec<(cff3000::Led, bool)> = captured.iter().map(|event| (event.led, event.on)).collect();
+    assert_eq!(levels, vec![(cff3000::Led::Red, true), (cff3000::Led::Red, false)]);
+    assert_eq!(captured[1].timestamp - captured[0].timestamp, Duration::from_millis(200));
+}
+
+#[test]
+fn panicking_hooks_are_contained() {
+    let calls = Calls::default();
+    let replay = Replay::with_captures(&[None]);
+    let device = testing::device(&replay)
+        .telemetry(Panicking)
+        .telemetry(Recorder::new("after", &calls))
+        .build().unwrap();
+    device.lock().unwrap();
+    assert_eq!(calls.get().len(), 1);
+    assert!(calls.get()[0].starts_with("after: command Lock"));
+}
@@ -0,0 +1,149 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! The documented default timings, and that the device actually uses
+//! them (on virtual time, see `testing::TestClock`).
+
+use std::time::Duration;
+
+use cff3000::testing::{self, generate, PatternParams, Replay};
+use cff3000::timings::*;
+use cff3000::{CFF3000State, Command, DeviceProfile, ParseOptions, TimingProfile, Timings, WatchOptions};
+
+/// Changing one of these is an API change.
+#[test]
+fn default_values() {
+    assert_eq!(DEFAULT_PRESS, Duration::from_millis(500));
+    assert_eq!(DEFAULT_CAPTURE_WINDOW, Duration::from_secs(8));
+    assert_eq!(DEFAULT_COMMAND_CAPTURE_WINDOW, Duration::from_secs(10));
+    assert_eq!(DEFAULT_MERGE_WINDOW, Duration::from_millis(50));
+    assert_eq!(SUGGESTED_FEEDBACK_DISPLAY, Duration::from_secs(10));
+    assert_eq!(SUGGESTED_CHECK_FEEDBACK_DISPLAY, Duration::from_secs(8));
+    assert_eq!(DEFAULT_MIN_INTERVAL, Duration::from_secs(10));
+    assert_eq!(DEFAULT_AUTO_LOCK_COOLDOWN, Duration::from_secs(15 * 60));
+    assert_eq!(DEFAULT_SCHEDULE_GRACE, Duration::from_secs(15 * 60));
+}
+
+#[test]
+fn defaults_are_used_by_default() {
+    let timings = Timings::default();
+    assert_eq!((timings.press, timings.check_capture, timings.command_capture), (DEFAULT_PRESS, DEFAULT_CAPTURE_WINDOW, DEFAULT_COMMAND_CAPTURE_WINDOW));
+    for &command in &[Command::Lock, Command::Unlock, Command::Check] {
+        assert_eq!(timings.press_for(command), DEFAULT_PRESS);
+    }
+    assert_eq!(timings.capture_for(Command::Lock), DEFAULT_COMMAND_CAPTURE_WINDOW);
+    assert_eq!(timings.capture_for(Command::Unlock), DEFAULT_COMMAND_CAPTURE_WINDOW);
+    assert_eq!(timings.capture_for(Command::Check), DEFAULT_CAPTURE_WINDOW);
+    assert_eq!(ParseOptions::default().merge_window, DEFAULT_MERGE_WINDOW);
+
+    let watch = WatchOptions::new(Duration::from_secs(60));
+    assert_eq!(watch.min_interval, DEFAULT_MIN_INTERVAL);
+    assert_eq!(watch.auto_lock_cooldown, DEFAULT_AUTO_LOCK_COOLDOWN);
+}
+
+#[test]
+fn press_lasts_default_press() {
+    let replay = Replay::new();
+    testing::device(&replay).build().unwrap().unlock().unwrap();
+    let transitions = replay.transitions();
+    assert_eq!(transitions[1].at - transitions[0].at, DEFAULT_PRESS);
+}
+
+#[test]
+fn state_captures_default_capture_window() {
+    let replay = Replay::with_captures(&[Some(CFF3000State::Locked)]);
+    assert_eq!(testing::device(&replay).build().unwrap().state().unwrap(), CFF3000State::Locked);
+    assert_eq!(replay.elapsed(), DEFAULT_PRESS + DEFAULT_CAPTURE_WINDOW);
+}
+
+#[test]
+fn verify_captures_default_command_capture_window() {
+    let replay = Replay::with_captures(&[Some(CFF3000State::Locked)]);
+    assert_eq!(testing::device(&replay).build().unwrap().lock_and_verify().unwrap(), CFF3000State::Locked);
+    assert_eq!(replay.elapsed(), DEFAULT_PRESS + DEFAULT_COMMAND_CAPTURE_WINDOW);
+}
+
+#[test]
+fn confirmation_fits_into_suggested_display() {
+    /* the whole pattern is over before the LEDs are no longer shown */
+    let events = generate(CFF3000State::OutOfRange, PatternParams::default());
+    let last = events.last().unwrap().timestamp;
+    assert!(last < SUGGESTED_CHECK_FEEDBACK_DISPLAY && SUGGESTED_CHECK_FEEDBACK_DISPLAY <= SUGGESTED_FEEDBACK_DISPLAY);
+
+    let replay = Replay::new();
+    testing::device(&replay).build().unwrap().show_leds(SUGGESTED_FEEDBACK_DISPLAY.as_secs() as u8).unwrap();
+    assert_eq!(replay.elapsed(), SUGGESTED_FEEDBACK_DISPLAY);
+}
+
+#[test]
+fn custom_timings_replace_defaults() {
+    let replay = Replay::with_captures(&[Some(CFF3000State::Unlocked)]);
+    let timings = Timings {press: Duration::from_millis(800), check_capture: Duration::from_secs(6), ..Timings::default()};
+    let cff3000 = testing::device(&replay).timings(timings).build().unwrap();
+
+    assert_eq!(cff3000.state().unwrap(), CFF3000State::Unlocked);
+    assert_eq!(replay.elapsed(), Duration::from_millis(6_800));
+}
+
+#[test]
+fn per_operation_press() {
+    let replay = Replay::new();
+    let cff3000 = testing::device(&replay)
+        .unlock_press(Duration::from_millis(1200)).build().unwrap();
+    cff3000.unlock().unwrap();
+    cff3000.lock().unwrap();
+
+    let transitions = replay.transitions();
+    assert_eq!(transitions[1].at - transitions[0].at, Duration::from_millis(1200));
+    assert_eq!(transitions[3].at - transitions[2].at, DEFAULT_PRESS);
+}
+
+#[test]
+fn per_operation_capture() {
+    let replay = Replay::with_captures(&[Some(CFF3000State::Locked), Some(CFF3000State::Unlocked)]);
+    let cff3000 = testing::device(&replay)
+        .lock_press(Duration::from_millis(700)).lock_capture(Duration::from_secs(6)).build().unwrap();
+
+    assert_eq!(cff3000.lock_and_verify().unwrap(), CFF3000State::Locked);
+    assert_eq!(replay.elapsed(), Duration::from_millis(6_700));
+    /* unset values fall back to the shared ones */
+    assert_eq!(cff3000.unlock_and_verify().unwrap(), CFF3000State::Unlocked);
+    assert_eq!(replay.elapsed(), Duration::from_millis(6_700) + DEFAULT_PRESS + DEFAULT_COMMAND_CAPTURE_WINDOW);
+}
+
+#[test]
+fn classic_profile_is_the_default() {
+    assert_eq!(DeviceProfile::default(), DeviceProfile::Classic);
+    assert_eq!(TimingProfile::CLASSIC.timings, Timings::default());
+    assert_eq!(TimingProfile::CLASSIC.parse_options(), ParseOptions::default());
+    assert_eq!(PatternParams::from_profile(&TimingProfile::CLASSIC), PatternParams::default());
+}
+
+#[test]
+fn profile_sets_timings() {
+    let replay = Replay::new();
+    replay.push_capture(generate(CFF3000State::Manual, PatternParams::from_profile(&TimingProfile::REV2)));
+    let cff3000 = testing::device(&replay).profile(DeviceProfile::Rev2).build().unwrap();
+    assert_eq!(cff3000.state().unwrap(), CFF3000State::Manual);
+    assert_eq!(replay.elapsed(), TimingProfile::REV2.timings.press + TimingProfile::REV2.timings.check_capture);
+
+    /* a custom profile, with single values adjusted afterwards */
+    let timing = TimingProfile {blink_period: Duration::from_millis(300), ..TimingProfile::REV2};
+    let replay = Replay::new();
+    let cff3000 = testing::device(&replay)
+        .profile(DeviceProfile::Custom(timing)).lock_press(Duration::from_millis(1500)).build().unwrap();
+    cff3000.unlock().unwrap();
+    cff3000.lock().unwrap();
+    let transitions = replay.transitions();
+    assert_eq!(transitions[1].at - transitions[0].at, TimingProfile::REV2.timings.press);
+    assert_eq!(transitions[3].at - transitions[2].at, Duration::from_millis(1500));
+}
+
+#[test]
+fn profiles_have_names() {
+    for &profile in &[DeviceProfile::Classic, DeviceProfile::Rev2] {
+        assert_eq!(DeviceProfile::from_name(profile.name().unwrap()), Some(profile));
+    }
+    assert_eq!(DeviceProfile::Custom(TimingProfile::REV2).name(), None);
+    assert_eq!(DeviceProfile::from_name("rev3"), None);
+}
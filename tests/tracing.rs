@@ -0,0 +1,112 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Spans of the operations with the `tracing` feature, on the replay
+//! backend.
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+use cff3000::testing::{self, generate, PatternParams, Replay};
+use cff3000::{CFF3000State, Command, Timings};
+
+/// A span with its parent and fields.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Span {
+    name: &'static str,
+    parent: Option<&'static str>,
+    fields: BTreeMap<&'static str, String>,
+}
+
+struct Fields<'a>(&'a mut BTreeMap<&'static str, String>);
+
+impl<'a> Visit for Fields<'a> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name(), value.to_string());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0.insert(field.name(), format!("{:?}", value));
+    }
+}
+
+/// Subscriber keeping every span, the entered ones on a stack.
+#[derive(Clone, Default)]
+struct Recorder {
+    spans: Arc<Mutex<Vec<Span>>>,
+    entered: Arc<Mutex<Vec<u64>>>,
+}
+
+impl Subscriber for Recorder {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn new_span(&self, attributes: &Attributes) -> Id {
+        let mut spans = self.spans.lock().unwrap();
+        let parent = match attributes.parent() {
+            Some(parent) => Some(parent.into_u64()),
+            None if attributes.is_contextual() => self.entered.lock().unwrap().last().cloned(),
+            None => None,
+        };
+        let mut fields = BTreeMap::new();
+        attributes.record(&mut Fields(&mut fields));
+        let parent = parent.map(|id| spans[id as usize - 1].name);
+        spans.push(Span {name: attributes.metadata().name(), parent, fields});
+        Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, span: &Id, values: &Record) {
+        let mut spans = self.spans.lock().unwrap();
+        values.record(&mut Fields(&mut spans[span.into_u64() as usize - 1].fields));
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, _event: &Event) {}
+
+    fn enter(&self, span: &Id) {
+        self.entered.lock().unwrap().push(span.into_u64());
+    }
+
+    fn exit(&self, _span: &Id) {
+        self.entered.lock().unwrap().pop();
+    }
+}
+
+fn span(name: &'static str, parent: Option<&'static str>, fields: &[(&'static str, &str)]) -> Span {
+    Span {name, parent, fields: fields.iter().map(|&(name, value)| (name, value.to_string())).collect()}
+}
+
+#[test]
+fn operations_are_spans() {
+    let recorder = Recorder::default();
+    let replay = Replay::with_captures(&[None, Some(CFF3000State::Locked), None]);
+    let device = testing::device(&replay).label("door").build().unwrap();
+    let events = generate(CFF3000State::Locked, PatternParams::default()).len().to_string();
+    let press = Timings::default().press_for(Command::Lock).as_millis().to_string();
+
+    tracing::subscriber::with_default(recorder.clone(), || {
+        device.lock().unwrap();
+        assert_eq!(device.state().unwrap(), CFF3000State::Locked);
+        assert!(device.unlock_and_verify().is_err());
+    });
+    assert_eq!(*recorder.spans.lock().unwrap(), vec![
+        span("lock", None, &[("device", "door"), ("press_ms", &press)]),
+        span("state", None, &[("device", "door"), ("press_ms", &press), ("events", &events), ("state", "locked")]),
+        span("capture", Some("state"), &[("device", "door"), ("events", &events)]),
+        span("parse", Some("state"), &[("device", "door"), ("events", &events), ("state", "locked")]),
+        span("unlock_and_verify", None, &[("device", "door"), ("press_ms", &press), ("error", "no-response")]),
+        span("capture", Some("unlock_and_verify"), &[("device", "door"), ("events", "0")]),
+        span("parse", Some("unlock_and_verify"), &[("device", "door"), ("events", "0"), ("error", "no-response")]),
+    ]);
+}
@@ -0,0 +1,74 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! Wall-clock anchoring of the event timestamps, on the replay backend.
+
+use std::task::Poll;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use cff3000::testing::{self, generate, PatternParams, Replay};
+use cff3000::{CFF3000State, Led, LedEvent, StateQuery, WallAnchor, WallTime};
+
+#[test]
+fn timestamps_are_converted() {
+    let wall = UNIX_EPOCH + Duration::from_millis(1_760_000_000_123);
+    let anchor = WallAnchor {wall, timestamp: Duration::from_secs(5)};
+    assert_eq!(anchor.wall_time(Duration::from_secs(5)), wall);
+    assert_eq!(anchor.wall_time(Duration::from_millis(6_250)), wall + Duration::from_millis(1250));
+    assert_eq!(anchor.wall_time(Duration::from_millis(4_999)), wall - Duration::from_millis(1));
+    let event = LedEvent {led: Led::Green, on: true, timestamp: Duration::from_millis(5_700)};
+    assert_eq!(event.wall_time(&anchor), wall + Duration::from_millis(700));
+}
+
+#[test]
+fn reports_are_anchored() {
+    let replay = Replay::with_captures(&[Some(CFF3000State::Unlocked)]);
+    replay.advance(Duration::from_secs(3600));
+    let device = testing::device(&replay).build().unwrap();
+
+    let before = SystemTime::now();
+    let pressed = replay.elapsed();
+    let report = device.state_report().unwrap();
+    let after = SystemTime::now();
+
+    /* the replay reads every event when it is due */
+    let anchor = report.anchor.unwrap();
+    for event in &report.events {
+        let since_press = event.timestamp - pressed;
+        let press = event.wall_time(&anchor) - since_press;
+        assert!(before <= press && press <= after, "{:?} not in {:?} to {:?}", press, before, after);
+    }
+    let first = report.events.iter().map(|event| event.timestamp).min().unwrap();
+    assert_eq!(report.shown_at(), Some(anchor.wall_time(first)));
+}
+
+#[test]
+fn events_read_late_are_not_the_anchor() {
+    let replay = Replay::new();
+    let pattern = generate(CFF3000State::Locked, PatternParams::default());
+    let last = pattern[pattern.len() - 1].timestamp;
+    replay.push_capture(pattern);
+    let device = testing::device(&replay).build().unwrap();
+
+    let mut query = StateQuery::start(&device).unwrap();
+    /* all but the last event are read long after they were due */
+    replay.advance(last - Duration::from_millis(1));
+    assert!(query.poll_report().is_pending());
+    replay.advance(Duration::from_millis(1));
+    assert!(query.poll_report().is_pending());
+    replay.advance(Duration::from_secs(60));
+    let report = match query.poll_report() {
+        Poll::Ready(result) => result.unwrap(),
+        Poll::Pending => panic!("capture not complete"),
+    };
+    assert_eq!(report.anchor.unwrap().timestamp, report.events[report.events.len() - 1].timestamp);
+}
+
+#[test]
+fn empty_captures_have_no_anchor() {
+    let replay = Replay::with_captures(&[None]);
+    let device = testing::device(&replay).build().unwrap();
+    let capture = device.capture(Duration::from_secs(1)).unwrap();
+    assert!(capture.events.is_empty());
+    assert_eq!(capture.anchor, None);
+}
@@ -0,0 +1,108 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! The watch loop: stopping it while a query runs, and the auto-lock
+//! policy on virtual time (see `testing::TestClock`).
+
+use std::time::{Duration, Instant};
+
+use cff3000::mock::MockBackend;
+use cff3000::testing::{self, Replay};
+use cff3000::{Button, CFF3000Builder, CFF3000State, StopToken, Trigger, WatchOptions};
+
+/// Stop a watch on a mock without responses `after` its start and
+/// return how long it took to return from then.
+fn stop_watch_after(after: Duration) -> Duration {
+    let cff3000 = CFF3000Builder::with_backend(MockBackend::new()).build().unwrap();
+    let stop = StopToken::new();
+    let stopper = {
+        let stop = stop.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(after);
+            stop.stop();
+            Instant::now()
+        })
+    };
+    cff3000.watch(Duration::from_secs(60), &stop, |change| panic!("unexpected change {:?}", change)).unwrap();
+    let returned = Instant::now();
+    returned.saturating_duration_since(stopper.join().unwrap())
+}
+
+#[test]
+fn stopping_interrupts_the_press() {
+    let delay = stop_watch_after(Duration::from_millis(100));
+    assert!(delay < Duration::from_millis(300), "{:?}", delay);
+}
+
+#[test]
+fn stopping_interrupts_the_capture() {
+    /* the capture waits 8 s for LED events which never come */
+    let delay = stop_watch_after(Duration::from_millis(800));
+    assert!(delay < Duration::from_millis(300), "{:?}", delay);
+}
+
+/// Watch with `options` on a replay showing `captures` one after
+/// another (lock presses included), until two queries in a row fail
+/// after the last one. Returns the changes with the virtual time they
+/// were reported at.
+fn watch_captures(captures: &[Option<CFF3000State>], options: WatchOptions) -> (Replay, Vec<(Trigger, CFF3000State, Duration)>) {
+    let replay = Replay::with_captures(captures);
+    let cff3000 = testing::device(&replay).build().unwrap();
+    let options = WatchOptions {max_consecutive_errors: 2, ..options};
+    let mut changes = Vec::new();
+    let result = cff3000.watch_with_options(&options, &StopToken::new(), |change| changes.push((change.trigger, change.current, replay.elapsed())));
+    assert!(result.is_err());
+    (replay, changes)
+}
+
+/// Start of every press of the lock button alone.
+fn lock_presses(replay: &Replay) -> Vec<Duration> {
+    let transitions = replay.transitions();
+    let alone = |at: Duration| !transitions.iter().any(|t| t.button == Button::Unlock && t.pressed && t.at == at);
+    transitions.iter().filter(|t| t.button == Button::Lock && t.pressed && alone(t.at)).map(|t| t.at).collect()
+}
+
+const UNLOCKED: Option<CFF3000State> = Some(CFF3000State::Unlocked);
+const LOCKED: Option<CFF3000State> = Some(CFF3000State::Locked);
+
+#[test]
+fn auto_lock_locks_after_the_delay() {
+    let after = Duration::from_secs(100);
+    let (replay, changes) = watch_captures(&[UNLOCKED, UNLOCKED, UNLOCKED, LOCKED, LOCKED], WatchOptions::new(Duration::from_secs(60)).auto_lock_after(after));
+    assert_eq!(changes.iter().map(|&(trigger, state, _)| (trigger, state)).collect::<Vec<_>>(),
+               vec![(Trigger::Poll, CFF3000State::Unlocked), (Trigger::AutoLock, CFF3000State::Locked)]);
+
+    /* the first query saw the door unlocked at its end */
+    let lock = lock_presses(&replay);
+    assert_eq!(lock.len(), 1);
+    assert!(lock[0] >= changes[0].2 + after, "locked at {:?}", lock[0]);
+    /* and it is locked right when due, not at the next poll */
+    assert!(lock[0] < changes[0].2 + after + Duration::from_secs(10), "locked at {:?}", lock[0]);
+}
+
+#[test]
+fn auto_lock_waits_for_the_cooldown() {
+    /* a person unlocks again right after the automatic lock */
+    let cooldown = Duration::from_secs(300);
+    let mut captures = vec![UNLOCKED, UNLOCKED, LOCKED];
+    captures.extend(std::iter::repeat_n(UNLOCKED, 6));
+    captures.push(LOCKED);
+    let options = WatchOptions::new(Duration::from_secs(60)).auto_lock_after(Duration::from_secs(30)).auto_lock_cooldown(cooldown);
+    let (replay, changes) = watch_captures(&captures, options);
+
+    let lock = lock_presses(&replay);
+    assert_eq!(lock.len(), 2, "{:?}", changes);
+    assert!(lock[1] - lock[0] >= cooldown, "locked at {:?}", lock);
+    assert_eq!(changes.iter().filter(|&&(trigger, _, _)| trigger == Trigger::AutoLock).count(), 2);
+}
+
+#[test]
+fn auto_lock_restarts_on_other_states() {
+    /* unlocked for much longer than the delay, but not continuously */
+    for &interruption in &[Some(CFF3000State::Manual), Some(CFF3000State::OutOfRange), None] {
+        let captures = [UNLOCKED, UNLOCKED, interruption, UNLOCKED, UNLOCKED];
+        let (replay, changes) = watch_captures(&captures, WatchOptions::new(Duration::from_secs(60)).auto_lock_after(Duration::from_secs(100)));
+        assert_eq!(lock_presses(&replay), vec![], "after {:?}", interruption);
+        assert!(changes.iter().all(|&(trigger, _, _)| trigger == Trigger::Poll));
+    }
+}
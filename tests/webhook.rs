@@ -0,0 +1,170 @@
+// © 2018 Sebastian Reichel
+// SPDX-License-Identifier: ISC
+
+//! `WebhookNotifier` against a minimal in-process HTTP server.
+
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use cff3000::webhook::{render, WebhookNotifier, WebhookOptions, DEFAULT_TEMPLATE};
+use cff3000::{CFF3000State, StateChange, Trigger};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A received request: headers (lowercase names) and body.
+#[derive(Debug)]
+struct Received {
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Received {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+}
+
+/// Server answering every request with the next of `statuses` (200
+/// once they are used up), returning the options for it and the
+/// requests.
+fn server(statuses: Vec<u16>) -> (WebhookOptions, mpsc::Receiver<Received>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut statuses = statuses.into_iter();
+        for stream in listener.incoming() {
+            let mut reader = BufReader::new(stream.unwrap());
+            let mut headers = Vec::new();
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert!(line.starts_with("POST /hook HTTP/1.1"), "{}", line);
+            loop {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                let colon = line.find(':').unwrap();
+                headers.push((line[..colon].to_ascii_lowercase(), line[colon + 1..].trim().to_string()));
+            }
+            let length: usize = headers.iter().find(|(key, _)| key == "content-length").unwrap().1.parse().unwrap();
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let status = statuses.next().unwrap_or(200);
+            let response = format!("HTTP/1.1 {} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+            if tx.send(Received {headers, body: String::from_utf8(body).unwrap()}).is_err() {
+                return;
+            }
+        }
+    });
+    let mut options = WebhookOptions::new(&url);
+    options.min_backoff = Duration::from_millis(10);
+    options.max_backoff = Duration::from_millis(40);
+    (options, rx)
+}
+
+fn change(previous: Option<CFF3000State>, current: CFF3000State) -> StateChange {
+    StateChange::new(previous, current, Trigger::Poll, SystemTime::now())
+}
+
+#[test]
+fn body_snapshot() {
+    let time = UNIX_EPOCH + Duration::from_millis(1_767_323_045_678);
+    let unlocked = change(Some(CFF3000State::Unlocked), CFF3000State::Locked);
+    assert_eq!(render(DEFAULT_TEMPLATE, "front \"door\"", &unlocked, time),
+               r#"{"device":"front \"door\"","previous":"unlocked","state":"locked","trigger":"poll","timestamp":"2026-01-02T03:04:05.678Z"}"#);
+    let mut first = change(None, CFF3000State::OutOfRange);
+    first.trigger = Trigger::AutoLock;
+    assert_eq!(render("{{previous}} {{trigger}} {{timestamp_ms}} {{timestamp}}", "x", &first, UNIX_EPOCH + Duration::from_secs(951_868_799)),
+               r#"null "auto-lock" 951868799000 "2000-02-29T23:59:59.000Z""#);
+    assert_eq!(render("{\"device\":{{device}},\"change\":{{change}}}", "x", &first, UNIX_EPOCH),
+               format!(r#"{{"device":"x","change":{{"state":"out-of-range","previous":null,"trigger":"auto-lock","at_ms":{}}}}}"#, first.at.duration_since(UNIX_EPOCH).unwrap().as_millis()));
+}
+
+#[test]
+fn posts_with_headers_and_token() {
+    let (mut options, requests) = server(vec![]);
+    options.token = Some("secret".to_string());
+    options.headers = vec![("Title".to_string(), "Front door".to_string())];
+    options.device_name = "front".to_string();
+    let notifier = WebhookNotifier::new(options).unwrap();
+    notifier.notify(change(None, CFF3000State::Locked));
+
+    let request = requests.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!(request.header("authorization"), Some("Bearer secret"));
+    assert_eq!(request.header("title"), Some("Front door"));
+    assert_eq!(request.header("content-type"), Some("application/json"));
+    assert!(request.body.starts_with(r#"{"device":"front","previous":null,"state":"locked","trigger":"poll","timestamp":"#), "{}", request.body);
+}
+
+#[test]
+fn timestamps_are_the_times_of_the_changes() {
+    let (options, requests) = server(vec![]);
+    let notifier = WebhookNotifier::new(options).unwrap();
+    let at = UNIX_EPOCH + Duration::from_millis(1_767_323_045_678);
+    notifier.notify(StateChange::new(None, CFF3000State::Locked, Trigger::Poll, at));
+
+    let request = requests.recv_timeout(TIMEOUT).unwrap();
+    assert!(request.body.ends_with(r#""timestamp":"2026-01-02T03:04:05.678Z"}"#), "{}", request.body);
+}
+
+#[test]
+fn retries_with_backoff_until_delivered() {
+    let (options, requests) = server(vec![503, 429, 500]);
+    let notifier = WebhookNotifier::new(options).unwrap();
+    notifier.notify(change(None, CFF3000State::Unlocked));
+    notifier.notify(change(Some(CFF3000State::Unlocked), CFF3000State::Locked));
+
+    let states: Vec<String> = (0..5).map(|_| requests.recv_timeout(TIMEOUT).unwrap().body).collect();
+    assert!(states[..4].iter().all(|body| body.contains(r#""state":"unlocked""#)), "{:?}", states);
+    assert!(states[4].contains(r#""state":"locked""#));
+    assert!(requests.recv_timeout(Duration::from_millis(200)).is_err());
+}
+
+#[test]
+fn gives_up_after_the_retry_budget_and_on_client_errors() {
+    let (mut options, requests) = server(vec![500, 500, 404]);
+    options.retries = 1;
+    let notifier = WebhookNotifier::new(options).unwrap();
+    for &state in &[CFF3000State::Unlocked, CFF3000State::Locked, CFF3000State::Manual] {
+        notifier.notify(change(None, state));
+    }
+
+    let states: Vec<String> = (0..4).map(|_| requests.recv_timeout(TIMEOUT).unwrap().body).collect();
+    /* two attempts for unlocked, one for the rejected locked */
+    assert!(states[0].contains("unlocked") && states[1].contains("unlocked"));
+    assert!(states[2].contains(r#""state":"locked""#));
+    assert!(states[3].contains("manual"));
+}
+
+#[test]
+fn unreachable_webhooks_do_not_stall() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut options = WebhookOptions::new(&format!("http://{}/hook", listener.local_addr().unwrap()));
+    drop(listener);
+    options.min_backoff = Duration::from_secs(60);
+    options.max_backoff = Duration::from_secs(60);
+    options.queue_depth = 1;
+    let notifier = WebhookNotifier::new(options).unwrap();
+    for _ in 0..10 {
+        notifier.notify(change(None, CFF3000State::Locked));
+    }
+    /* dropping abandons the waiting retry */
+    drop(notifier);
+}
+
+#[test]
+fn rejects_unusable_options() {
+    let err = WebhookNotifier::new(WebhookOptions::new("ftp://hooks.local")).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    let mut options = WebhookOptions::new("https://hooks.local");
+    options.template = "{{state}} {{door}}".to_string();
+    let err = WebhookNotifier::new(options).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("{{door}}"), "{}", err);
+}